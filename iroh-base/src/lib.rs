@@ -7,6 +7,9 @@
 #[cfg(feature = "ticket")]
 pub mod ticket;
 
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
 #[cfg(feature = "key")]
 mod key;
 #[cfg(feature = "key")]
@@ -20,3 +23,23 @@ pub use self::key::{KeyParsingError, NodeId, PublicKey, SecretKey, Signature};
 pub use self::node_addr::NodeAddr;
 #[cfg(feature = "relay")]
 pub use self::relay_url::{RelayUrl, RelayUrlParseError};
+
+/// Increments a counter of a metric group by one.
+#[cfg(not(feature = "metrics"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! inc {
+    ($m:ty, $f:ident) => {{
+        ::iroh_metrics::inc!($m, $f);
+    }};
+}
+
+/// Increments a counter of a metric group.
+#[cfg(not(feature = "metrics"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! inc_by {
+    ($m:ty, $f:ident, $n:expr) => {{
+        ::iroh_metrics::inc_by!($m, $f, $n);
+    }};
+}
@@ -0,0 +1,177 @@
+//! Reporting metrics to the metrics system of the embedding application.
+//!
+//! The iroh crates record their metrics in the metric groups of `iroh_metrics`, using the
+//! [`inc!`] and [`inc_by!`] macros of this crate.  Besides recording them in the
+//! `iroh_metrics` registry, if the application set it up, the macros report every increment
+//! of a counter to the process-wide [`MetricsBackend`] installed with [`set_backend`].
+//!
+//! A backend lets applications which already have a metrics system forward the metrics to
+//! it without running the registry and an exporter of `iroh_metrics`.  There is no built-in
+//! OTLP exporter: an OpenTelemetry pipeline is connected by implementing [`MetricsBackend`]
+//! on top of its meter.
+//!
+//! The backend is installed once for the process, it receives the increments of all relay
+//! servers and endpoints in the process.
+//!
+//! [`inc!`]: crate::inc
+//! [`inc_by!`]: crate::inc_by
+
+use std::{
+    fmt,
+    sync::{Arc, OnceLock},
+};
+
+/// The backend the increments of counters are reported to, see [`set_backend`].
+static BACKEND: OnceLock<Arc<dyn MetricsBackend>> = OnceLock::new();
+
+/// A metrics system to report metrics to.
+pub trait MetricsBackend: fmt::Debug + Send + Sync + 'static {
+    /// Records an increment of a counter.
+    ///
+    /// The `group` is the name of the metric group, e.g. `relayserver`, `name` is the name of
+    /// the counter within the group and `help` describes it.  The counter was incremented by
+    /// `by`, counters never decrease.  Called for every increment, so this should not block.
+    fn increment_counter(
+        &self,
+        group: &'static str,
+        name: &'static str,
+        help: &'static str,
+        by: u64,
+    );
+}
+
+/// Installs the backend the increments of counters are reported to.
+///
+/// The backend stays installed for the lifetime of the process.  Installing the same backend
+/// again succeeds, installing a different one fails and returns the installed backend.
+pub fn set_backend(backend: Arc<dyn MetricsBackend>) -> Result<(), Arc<dyn MetricsBackend>> {
+    let installed = BACKEND.get_or_init(|| backend.clone());
+    match Arc::ptr_eq(installed, &backend) {
+        true => Ok(()),
+        false => Err(installed.clone()),
+    }
+}
+
+/// Returns the installed backend, if any.
+///
+/// Used by the [`inc_by!`] macro.
+///
+/// [`inc_by!`]: crate::inc_by
+#[doc(hidden)]
+pub fn backend() -> Option<&'static Arc<dyn MetricsBackend>> {
+    BACKEND.get()
+}
+
+/// Increments a counter of a metric group by one, see [`inc_by!`].
+///
+/// [`inc_by!`]: crate::inc_by
+#[doc(hidden)]
+#[macro_export]
+macro_rules! inc {
+    ($m:ty, $f:ident) => {
+        $crate::inc_by!($m, $f, 1)
+    };
+}
+
+/// Increments a counter of a metric group.
+///
+/// Like `iroh_metrics::inc_by`, and also reports the increment to the installed
+/// [`MetricsBackend`].  The description of the counter is looked up once per call site.
+///
+/// [`MetricsBackend`]: crate::metrics::MetricsBackend
+#[doc(hidden)]
+#[macro_export]
+macro_rules! inc_by {
+    ($m:ty, $f:ident, $n:expr) => {{
+        let by: u64 = $n;
+        ::iroh_metrics::inc_by!($m, $f, by);
+        if let Some(backend) = $crate::metrics::backend() {
+            static HELP: ::std::sync::OnceLock<&'static str> = ::std::sync::OnceLock::new();
+            let help =
+                HELP.get_or_init(|| <$m as ::std::default::Default>::default().$f.description);
+            backend.increment_counter(
+                <$m as ::iroh_metrics::core::Metric>::name(),
+                stringify!($f),
+                help,
+                by,
+            );
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use iroh_metrics::{
+        core::{Counter, Metric},
+        struct_iterable::Iterable,
+    };
+
+    use super::*;
+
+    #[derive(Debug, Clone, Iterable)]
+    struct TestMetrics {
+        sent: Counter,
+    }
+
+    impl Default for TestMetrics {
+        fn default() -> Self {
+            Self {
+                sent: Counter::new("Packets sent"),
+            }
+        }
+    }
+
+    impl Metric for TestMetrics {
+        fn name() -> &'static str {
+            "test"
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingBackend {
+        records: Mutex<Vec<(&'static str, &'static str, &'static str, u64)>>,
+    }
+
+    impl MetricsBackend for RecordingBackend {
+        fn increment_counter(
+            &self,
+            group: &'static str,
+            name: &'static str,
+            help: &'static str,
+            by: u64,
+        ) {
+            self.records
+                .lock()
+                .expect("poisoned")
+                .push((group, name, help, by));
+        }
+    }
+
+    #[test]
+    fn test_backend() {
+        // Without a backend the increments are only recorded in the registry.
+        crate::inc!(TestMetrics, sent);
+
+        let backend = Arc::new(RecordingBackend::default());
+        set_backend(backend.clone()).unwrap();
+        set_backend(backend.clone()).unwrap();
+        let other = set_backend(Arc::new(RecordingBackend::default())).unwrap_err();
+        assert!(Arc::ptr_eq(
+            &other,
+            &(backend.clone() as Arc<dyn MetricsBackend>)
+        ));
+
+        // The registry of `iroh_metrics` is not set up, the backend still gets the increments.
+        crate::inc!(TestMetrics, sent);
+        crate::inc_by!(TestMetrics, sent, 3);
+        assert_eq!(
+            *backend.records.lock().unwrap(),
+            vec![
+                ("test", "sent", "Packets sent", 1),
+                ("test", "sent", "Packets sent", 3),
+            ]
+        );
+    }
+}
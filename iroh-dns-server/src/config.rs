@@ -3,6 +3,7 @@
 use std::{
     env,
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    num::NonZeroU32,
     path::{Path, PathBuf},
     time::Duration,
 };
@@ -10,11 +11,13 @@ use std::{
 use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
 use tracing::info;
+use url::Url;
 
 use crate::{
     dns::DnsConfig,
     http::{CertMode, HttpConfig, HttpsConfig, RateLimitConfig},
     store::ZoneStoreOptions,
+    validation::PublishPolicy,
 };
 
 const DEFAULT_METRICS_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9117);
@@ -25,7 +28,7 @@ const DEFAULT_METRICS_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LO
 ///
 /// The struct also implements [`Default`] which creates a config suitable for local development
 /// and testing.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
 pub struct Config {
     /// Config for the HTTP server
     ///
@@ -49,28 +52,93 @@ pub struct Config {
     /// Config for the zone store.
     pub zone_store: Option<StoreConfig>,
 
+    /// Config for warming the cache from the zone store on startup.
+    ///
+    /// If set to `None` the cache starts out empty.
+    pub cache_warming: Option<CacheWarmingConfig>,
+
     /// Config for pkarr rate limit
     #[serde(default)]
     pub pkarr_put_rate_limit: RateLimitConfig,
+
+    /// Policy for packets published to the pkarr relay.
+    ///
+    /// If set to `None` all packets with a valid signature are accepted.
+    pub publish_policy: Option<PublishPolicy>,
+
+    /// Config for running as a read-only replica of a primary server.
+    ///
+    /// If set, publishes are refused and packets are fetched from the primary instead.
+    pub replica: Option<ReplicaConfig>,
+
+    /// Config for the admin API of the HTTP(S) server.
+    ///
+    /// If set to `None` the admin routes are not served.
+    pub admin: Option<AdminConfig>,
+}
+
+/// The config for the admin API.
+///
+/// The admin API is served below `/admin`:
+/// * `POST /admin/selftest` runs a publish and resolve round trip against the server itself
+///   and returns a JSON report, see [`SelfTest`].
+///
+/// [`SelfTest`]: crate::selftest::SelfTest
+#[derive(Serialize, Deserialize, Clone, derive_more::Debug, schemars::JsonSchema)]
+pub struct AdminConfig {
+    /// The bearer token authenticating requests to the admin API.
+    #[debug("..")]
+    pub bearer_token: String,
+}
+
+/// The config for a read-only replica.
+#[derive(Debug, Serialize, Deserialize, Clone, schemars::JsonSchema)]
+pub struct ReplicaConfig {
+    /// The pkarr relay URL of the primary server, e.g. `https://dns.example/pkarr`.
+    ///
+    /// Publishes are refused with a pointer to this URL.
+    pub primary_url: Url,
+    /// How long to serve a packet fetched from the primary before fetching it again.
+    #[serde(
+        with = "humantime_serde",
+        default = "ReplicaConfig::default_refresh_interval"
+    )]
+    #[schemars(with = "String")]
+    pub refresh_interval: Duration,
+}
+
+impl ReplicaConfig {
+    fn default_refresh_interval() -> Duration {
+        Duration::from_secs(60)
+    }
 }
 
 /// The config for the store.
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, schemars::JsonSchema)]
 pub struct StoreConfig {
     /// Maximum number of packets to process in a single write transaction.
     max_batch_size: usize,
 
     /// Maximum time to keep a write transaction open.
     #[serde(with = "humantime_serde")]
+    #[schemars(with = "String")]
     max_batch_time: Duration,
 
     /// Time to keep packets in the store before eviction.
     #[serde(with = "humantime_serde")]
+    #[schemars(with = "String")]
     eviction: Duration,
 
     /// Pause between eviction checks.
     #[serde(with = "humantime_serde")]
+    #[schemars(with = "String")]
     eviction_interval: Duration,
+
+    /// Maximum number of packets to keep, the least recently used ones are evicted beyond.
+    ///
+    /// Unbounded if not set.
+    #[serde(default)]
+    max_entries: Option<usize>,
 }
 
 impl Default for StoreConfig {
@@ -86,6 +154,7 @@ impl From<ZoneStoreOptions> for StoreConfig {
             max_batch_time: value.max_batch_time,
             eviction: value.eviction,
             eviction_interval: value.eviction_interval,
+            max_entries: value.max_entries,
         }
     }
 }
@@ -97,12 +166,47 @@ impl From<StoreConfig> for ZoneStoreOptions {
             max_batch_time: value.max_batch_time,
             eviction: value.eviction,
             eviction_interval: value.eviction_interval,
+            max_entries: value.max_entries,
+            on_evict: None,
+        }
+    }
+}
+
+/// The config for warming the cache on startup.
+///
+/// The most recently published packets are loaded from the zone store into the cache in the
+/// background, so the first queries after a restart do not all have to read the store.
+#[derive(Debug, Serialize, Deserialize, Clone, schemars::JsonSchema)]
+pub struct CacheWarmingConfig {
+    /// Number of packets to load, the most recently published first.
+    #[serde(default = "CacheWarmingConfig::default_records")]
+    pub records: usize,
+    /// Maximum number of packets to load per second.
+    #[serde(default = "CacheWarmingConfig::default_rate")]
+    pub rate: NonZeroU32,
+}
+
+impl Default for CacheWarmingConfig {
+    fn default() -> Self {
+        Self {
+            records: Self::default_records(),
+            rate: Self::default_rate(),
         }
     }
 }
 
+impl CacheWarmingConfig {
+    fn default_records() -> usize {
+        100_000
+    }
+
+    fn default_rate() -> NonZeroU32 {
+        NonZeroU32::new(10_000).expect("non-zero")
+    }
+}
+
 /// The config for the metrics server.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
 pub struct MetricsConfig {
     /// Set to true to disable the metrics server.
     pub disabled: bool,
@@ -121,7 +225,7 @@ impl MetricsConfig {
 }
 
 /// The config for the metrics server.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
 pub struct MainlineConfig {
     /// Set to true to enable the mainline lookup.
     pub enabled: bool,
@@ -154,6 +258,13 @@ impl Default for MainlineConfig {
 }
 
 impl Config {
+    /// Returns the JSON Schema of the configuration file.
+    pub fn json_schema() -> serde_json::Value {
+        let mut schema = schemars::schema_for!(Config);
+        schema.insert("title".into(), "iroh-dns-server configuration".into());
+        schema.to_value()
+    }
+
     /// Load the config from a file.
     pub async fn load(path: impl AsRef<Path>) -> Result<Config> {
         info!(
@@ -238,11 +349,20 @@ impl Default for Config {
                 rr_a: Some(Ipv4Addr::LOCALHOST),
                 rr_aaaa: None,
                 rr_ns: Some("ns1.irohdns.example.".to_string()),
+                query_tracing: Default::default(),
+                udp: Default::default(),
+                tcp: Default::default(),
+                tls: Default::default(),
+                rrl: None,
             },
             zone_store: None,
+            cache_warming: None,
             metrics: None,
             mainline: None,
             pkarr_put_rate_limit: RateLimitConfig::default(),
+            publish_policy: None,
+            replica: None,
+            admin: None,
         }
     }
 }
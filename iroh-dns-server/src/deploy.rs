@@ -0,0 +1,374 @@
+//! Generating the files to deploy the server.
+//!
+//! A [`Deployment`] describes the hostname and ports of a server and renders a hardened
+//! systemd unit, nginx and caddy reverse proxy configs and a config file which fit together.
+//! The `gen` subcommand of the `iroh-dns-server` binary prints these.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    config::{Config, MainlineConfig, MetricsConfig},
+    dns::{DnsConfig, DnsTlsConfig, QueryTracingConfig},
+    http::{CertMode, HttpConfig, HttpsConfig, RateLimitConfig},
+};
+
+/// The state directory of the systemd unit, used as the data directory of the server.
+const STATE_DIR: &str = "/var/lib/iroh-dns-server";
+
+/// The default TTL of the records in the generated config, in seconds.
+const DEFAULT_TTL: u32 = 30;
+
+/// The hostname, ports and paths of a deployment.
+#[derive(Debug, Clone)]
+pub struct Deployment {
+    /// The public hostname of the server, which is also the origin of the served zones.
+    pub hostname: String,
+    /// The port of the HTTP server.
+    pub http_port: u16,
+    /// The port of the HTTPS server.
+    pub https_port: u16,
+    /// The port of the DNS server, for UDP and TCP.
+    pub dns_port: u16,
+    /// The port of the metrics server, which is only bound on localhost.
+    pub metrics_port: u16,
+    /// Whether TLS is terminated by a reverse proxy instead of the server.
+    ///
+    /// The server then serves plain HTTP on localhost on the [`Self::upstream_port`], the
+    /// nginx and caddy configs always proxy to it.
+    pub behind_proxy: bool,
+    /// The localhost port the server serves plain HTTP on behind a proxy.
+    pub upstream_port: u16,
+    /// The path of the server binary in the systemd unit.
+    pub binary_path: PathBuf,
+    /// The path of the config file in the systemd unit.
+    pub config_path: PathBuf,
+}
+
+impl Deployment {
+    /// Creates a deployment for `hostname` using the default ports.
+    pub fn new(hostname: impl Into<String>) -> Self {
+        Self {
+            hostname: hostname.into(),
+            http_port: 80,
+            https_port: 443,
+            dns_port: 53,
+            metrics_port: 9117,
+            behind_proxy: false,
+            upstream_port: 8080,
+            binary_path: PathBuf::from("/usr/local/bin/iroh-dns-server"),
+            config_path: PathBuf::from("/etc/iroh-dns-server/config.toml"),
+        }
+    }
+
+    /// The config file for this deployment.
+    ///
+    /// The `rr_a` and `rr_aaaa` records of the origins are left unset, they need the public
+    /// addresses of the server.
+    pub fn config(&self) -> Config {
+        let hostname = self.hostname.trim_end_matches('.');
+        let (http, https, rate_limit) = if self.behind_proxy {
+            let http = HttpConfig {
+                port: self.upstream_port,
+                bind_addr: Some(Ipv4Addr::LOCALHOST.into()),
+            };
+            // Behind a proxy all connections come from localhost.
+            (http, None, RateLimitConfig::Smart)
+        } else {
+            let http = HttpConfig {
+                port: self.http_port,
+                bind_addr: None,
+            };
+            let https = HttpsConfig {
+                port: self.https_port,
+                bind_addr: None,
+                domains: vec![hostname.to_string()],
+                cert_mode: CertMode::LetsEncrypt,
+                letsencrypt_contact: None,
+                letsencrypt_prod: Some(true),
+            };
+            (http, Some(https), RateLimitConfig::Simple)
+        };
+        let dns = DnsConfig {
+            port: self.dns_port,
+            bind_addr: None,
+            default_soa: format!("ns1.{hostname} hostmaster.{hostname} 0 10800 3600 604800 3600"),
+            default_ttl: DEFAULT_TTL,
+            origins: vec![format!("{hostname}."), ".".to_string()],
+            rr_a: None,
+            rr_aaaa: None,
+            rr_ns: Some(format!("ns1.{hostname}.")),
+            query_tracing: QueryTracingConfig {
+                anonymize_client_addr: true,
+                ..Default::default()
+            },
+            udp: Default::default(),
+            tcp: Default::default(),
+            // DNS over TLS uses the certificates of the HTTPS server.
+            tls: DnsTlsConfig {
+                enabled: https.is_some(),
+                ..Default::default()
+            },
+            rrl: None,
+        };
+        Config {
+            http: Some(http),
+            https,
+            dns,
+            metrics: Some(MetricsConfig {
+                disabled: false,
+                bind_addr: Some(SocketAddr::from((Ipv4Addr::LOCALHOST, self.metrics_port))),
+            }),
+            mainline: Some(MainlineConfig::default()),
+            zone_store: None,
+            cache_warming: None,
+            pkarr_put_rate_limit: rate_limit,
+            publish_policy: None,
+            replica: None,
+            admin: None,
+        }
+    }
+
+    /// The config file for this deployment, as TOML.
+    pub fn config_toml(&self) -> Result<String> {
+        let config = toml::to_string(&self.config()).context("failed to serialize config")?;
+        let mode = if self.behind_proxy {
+            "TLS is terminated by a reverse proxy, see `iroh-dns-server gen nginx` and `iroh-dns-server gen caddy`."
+        } else {
+            "Certificates are obtained from LetsEncrypt, which must be able to reach the HTTPS port."
+        };
+        Ok(format!(
+            "# iroh-dns-server config for {hostname}, generated by `iroh-dns-server gen config`.\n\
+             #\n\
+             # {mode}\n\
+             # Set `dns.rr_a` and `dns.rr_aaaa` to the public addresses of the server.\n\
+             \n\
+             {config}",
+            hostname = self.hostname,
+        ))
+    }
+
+    /// A systemd unit running the server with a restricted set of privileges.
+    pub fn systemd_unit(&self) -> String {
+        let privileged = self.public_ports().iter().any(|port| *port < 1024);
+        let capabilities = if privileged {
+            "AmbientCapabilities=CAP_NET_BIND_SERVICE\nCapabilityBoundingSet=CAP_NET_BIND_SERVICE\n"
+        } else {
+            "CapabilityBoundingSet=\n"
+        };
+        format!(
+            r#"[Unit]
+Description=iroh DNS server for {hostname}
+Documentation=https://github.com/n0-computer/iroh
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+Type=simple
+ExecStart={binary} --config {config}
+Restart=on-failure
+RestartSec=5s
+Environment=RUST_LOG=info
+Environment=IROH_DNS_DATA_DIR={state_dir}
+DynamicUser=yes
+StateDirectory=iroh-dns-server
+WorkingDirectory={state_dir}
+{capabilities}NoNewPrivileges=yes
+ProtectSystem=strict
+ProtectHome=yes
+PrivateTmp=yes
+PrivateDevices=yes
+ProtectClock=yes
+ProtectHostname=yes
+ProtectKernelLogs=yes
+ProtectKernelModules=yes
+ProtectKernelTunables=yes
+ProtectControlGroups=yes
+RestrictAddressFamilies=AF_INET AF_INET6 AF_UNIX
+RestrictNamespaces=yes
+RestrictRealtime=yes
+RestrictSUIDSGID=yes
+LockPersonality=yes
+MemoryDenyWriteExecute=yes
+SystemCallArchitectures=native
+SystemCallFilter=@system-service
+LimitNOFILE=65536
+
+[Install]
+WantedBy=multi-user.target
+"#,
+            hostname = self.hostname,
+            binary = self.binary_path.display(),
+            config = self.config_path.display(),
+            state_dir = STATE_DIR,
+        )
+    }
+
+    /// An nginx config terminating TLS and proxying the pkarr relay to the server.
+    pub fn nginx_config(&self) -> String {
+        format!(
+            r#"# nginx reverse proxy for the iroh DNS server at {hostname}.
+#
+# The server must serve plain HTTP on 127.0.0.1:{upstream}, see
+# `iroh-dns-server gen config --behind-proxy`.  DNS on port {dns} can not be proxied and
+# must be reachable directly.
+
+map $http_upgrade $connection_upgrade {{
+    default upgrade;
+    ''      close;
+}}
+
+server {{
+    listen {https} ssl;
+    listen [::]:{https} ssl;
+    server_name {hostname};
+
+    ssl_certificate     /etc/letsencrypt/live/{hostname}/fullchain.pem;
+    ssl_certificate_key /etc/letsencrypt/live/{hostname}/privkey.pem;
+    ssl_protocols       TLSv1.2 TLSv1.3;
+
+    location / {{
+        proxy_pass http://127.0.0.1:{upstream};
+        proxy_http_version 1.1;
+        proxy_set_header Upgrade $http_upgrade;
+        proxy_set_header Connection $connection_upgrade;
+        proxy_set_header Host $host;
+        # The pkarr relay rate limits by the client address in these headers, they are
+        # overwritten so clients can not pick their own.
+        proxy_set_header X-Forwarded-For $remote_addr;
+        proxy_set_header X-Real-IP $remote_addr;
+        proxy_set_header X-Forwarded-Proto $scheme;
+    }}
+}}
+
+server {{
+    listen {http};
+    listen [::]:{http};
+    server_name {hostname};
+
+    location / {{
+        return 301 https://$host{https_suffix}$request_uri;
+    }}
+}}
+"#,
+            hostname = self.hostname,
+            upstream = self.upstream_port,
+            dns = self.dns_port,
+            http = self.http_port,
+            https = self.https_port,
+            https_suffix = self.https_suffix(),
+        )
+    }
+
+    /// A caddy config terminating TLS and proxying the pkarr relay to the server.
+    pub fn caddy_config(&self) -> String {
+        format!(
+            r#"# caddy reverse proxy for the iroh DNS server at {hostname}.
+#
+# The server must serve plain HTTP on 127.0.0.1:{upstream}, see
+# `iroh-dns-server gen config --behind-proxy`.  DNS on port {dns} can not be proxied and
+# must be reachable directly.
+
+{hostname}:{https} {{
+    # caddy passes the Upgrade header and sets X-Forwarded-For, which the pkarr relay
+    # rate limits by.
+    reverse_proxy 127.0.0.1:{upstream}
+}}
+
+http://{hostname}:{http} {{
+    redir https://{hostname}{https_suffix}{{uri}} permanent
+}}
+"#,
+            hostname = self.hostname,
+            upstream = self.upstream_port,
+            dns = self.dns_port,
+            http = self.http_port,
+            https = self.https_port,
+            https_suffix = self.https_suffix(),
+        )
+    }
+
+    /// The ports the server itself binds on all interfaces.
+    fn public_ports(&self) -> Vec<u16> {
+        let mut ports = vec![self.dns_port];
+        if !self.behind_proxy {
+            ports.extend([
+                self.http_port,
+                self.https_port,
+                DnsTlsConfig::default().port,
+            ]);
+        }
+        ports
+    }
+
+    /// The port to add to redirect URLs, empty for the default HTTPS port.
+    fn https_suffix(&self) -> String {
+        match self.https_port {
+            443 => String::new(),
+            port => format!(":{port}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_roundtrip() -> Result<()> {
+        let deployment = Deployment::new("dns.example.org");
+        let config: Config = toml::from_str(&deployment.config_toml()?)?;
+        let https = config.https.as_ref().context("https")?;
+        assert_eq!(https.domains, ["dns.example.org"]);
+        assert_eq!(https.cert_mode, CertMode::LetsEncrypt);
+        assert_eq!(config.dns.origins, ["dns.example.org.", "."]);
+        assert!(config.dns.tls.enabled);
+        assert!(matches!(
+            config.pkarr_put_rate_limit,
+            RateLimitConfig::Simple
+        ));
+        assert!(config.metrics_addr().context("metrics")?.ip().is_loopback());
+
+        let deployment = Deployment {
+            behind_proxy: true,
+            ..deployment
+        };
+        let config: Config = toml::from_str(&deployment.config_toml()?)?;
+        assert!(config.https.is_none());
+        assert!(!config.dns.tls.enabled);
+        let http = config.http.context("http")?;
+        assert_eq!(http.port, 8080);
+        assert_eq!(http.bind_addr, Some(Ipv4Addr::LOCALHOST.into()));
+        assert!(matches!(
+            config.pkarr_put_rate_limit,
+            RateLimitConfig::Smart
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_artifacts() {
+        let deployment = Deployment {
+            https_port: 8443,
+            ..Deployment::new("dns.example.org")
+        };
+        let unit = deployment.systemd_unit();
+        assert!(unit.contains(
+            "ExecStart=/usr/local/bin/iroh-dns-server --config /etc/iroh-dns-server/config.toml"
+        ));
+        assert!(unit.contains("AmbientCapabilities=CAP_NET_BIND_SERVICE"));
+
+        let nginx = deployment.nginx_config();
+        assert!(nginx.contains("proxy_pass http://127.0.0.1:8080;"));
+        assert!(nginx.contains("proxy_set_header Upgrade $http_upgrade;"));
+        assert!(nginx.contains("return 301 https://$host:8443$request_uri;"));
+
+        let caddy = deployment.caddy_config();
+        assert!(caddy.contains("dns.example.org:8443 {"));
+        assert!(caddy.contains("reverse_proxy 127.0.0.1:8080"));
+    }
+}
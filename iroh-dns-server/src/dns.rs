@@ -4,18 +4,21 @@ use std::{
     collections::BTreeMap,
     io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, ensure, Context, Result};
 use async_trait::async_trait;
 use bytes::Bytes;
 use hickory_server::{
-    authority::{Catalog, MessageResponse, ZoneType},
+    authority::{Catalog, MessageResponse, MessageResponseBuilder, ZoneType},
     proto::{
         self,
-        op::ResponseCode,
+        op::{Header, OpCode, ResponseCode},
         rr::{
             rdata::{self},
             LowerName, Name, RData, Record, RecordSet, RecordType, RrKey,
@@ -28,27 +31,38 @@ use hickory_server::{
 };
 use iroh_metrics::inc;
 use serde::{Deserialize, Serialize};
-use tokio::{
-    net::{TcpListener, UdpSocket},
-    sync::broadcast,
-};
-use tracing::{debug, info};
+use tokio::{sync::broadcast, task::JoinSet};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, field, info, info_span, warn, Instrument};
 
-use self::node_authority::NodeAuthority;
-use crate::{metrics::Metrics, store::ZoneStore};
+use self::{
+    node_authority::NodeAuthority,
+    rrl::{ResponseRateLimiter, RrlAction},
+};
+pub use self::{
+    rrl::{RrlConfig, RrlSubnet},
+    udp::UdpConfig,
+};
+use crate::{
+    listeners::{DnsListeners, DEFAULT_DNS_TLS_PORT},
+    metrics::Metrics,
+    store::ZoneStore,
+};
 
 mod node_authority;
+mod rrl;
+pub(crate) mod udp;
 
 const DEFAULT_NS_TTL: u32 = 60 * 60 * 12; // 12h
 const DEFAULT_SOA_TTL: u32 = 60 * 60 * 24 * 14; // 14d
 const DEFAULT_A_TTL: u32 = 60 * 60; // 1h
 
 /// DNS server settings
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, schemars::JsonSchema)]
 pub struct DnsConfig {
-    /// The port to serve a local UDP DNS server at
+    /// The port to serve a local UDP and TCP DNS server at
     pub port: u16,
-    /// The IPv4 or IPv6 address to bind the UDP DNS server.
+    /// The IPv4 or IPv6 address to bind the UDP and TCP DNS server.
     /// Uses `0.0.0.0` if unspecified.
     pub bind_addr: Option<IpAddr>,
     /// SOA record data for any authoritative DNS records
@@ -64,47 +78,181 @@ pub struct DnsConfig {
     pub rr_aaaa: Option<Ipv6Addr>,
     /// `NS` record to set for all origins
     pub rr_ns: Option<String>,
+
+    /// Tracing of DNS queries
+    #[serde(default)]
+    pub query_tracing: QueryTracingConfig,
+
+    /// Tuning of the UDP sockets
+    #[serde(default)]
+    pub udp: UdpConfig,
+
+    /// DNS over TCP
+    #[serde(default)]
+    pub tcp: TcpConfig,
+
+    /// DNS over TLS
+    #[serde(default)]
+    pub tls: DnsTlsConfig,
+
+    /// Response rate limiting of DNS over UDP
+    ///
+    /// Responses are not limited if unset.
+    pub rrl: Option<RrlConfig>,
+}
+
+/// Configuration of DNS over TCP.
+#[derive(Clone, Debug, Serialize, Deserialize, schemars::JsonSchema)]
+#[serde(default)]
+pub struct TcpConfig {
+    /// Whether to serve DNS over TCP on the DNS port.
+    pub enabled: bool,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+/// Configuration of DNS over TLS.
+///
+/// The server uses the certificates of the HTTPS server, so `https` needs to be configured.
+#[derive(Clone, Debug, Serialize, Deserialize, schemars::JsonSchema)]
+#[serde(default)]
+pub struct DnsTlsConfig {
+    /// Whether to serve DNS over TLS.
+    pub enabled: bool,
+    /// The port to serve DNS over TLS at.
+    pub port: u16,
+    /// The IPv4 or IPv6 address to bind the DNS over TLS server.
+    /// Uses the `bind_addr` of the DNS server if unspecified.
+    pub bind_addr: Option<IpAddr>,
+}
+
+impl Default for DnsTlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: DEFAULT_DNS_TLS_PORT,
+            bind_addr: None,
+        }
+    }
+}
+
+/// Configuration for tracing DNS queries.
+///
+/// Every query is handled in a `dns_query` span carrying a query ID, the client address, the
+/// queried name and type, the time spent in the zone store and the response code.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, schemars::JsonSchema)]
+#[serde(default)]
+pub struct QueryTracingConfig {
+    /// Log only the network of client addresses, their /24 for IPv4 and /48 for IPv6.
+    pub anonymize_client_addr: bool,
+    /// Log queries taking longer than this threshold at warn level.
+    #[serde(with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub slow_query_threshold: Option<Duration>,
+    /// Log why queries are answered with `REFUSED` or `NOTIMP` at info level.
+    ///
+    /// The reasons are logged at debug level otherwise.
+    pub log_refused: bool,
 }
 
 /// A DNS server that serves pkarr signed packets.
 pub struct DnsServer {
     local_addr: SocketAddr,
     server: hickory_server::ServerFuture<DnsHandler>,
+    /// The UDP listeners receiving in batches, not served by `server`.
+    batch_listeners: JoinSet<Result<()>>,
+    cancel: CancellationToken,
 }
 
 impl DnsServer {
     /// Spawn the server.
+    ///
+    /// DNS over TLS needs the certificates of the HTTPS server, it is only supported when
+    /// spawning the complete [`Server`].
+    ///
+    /// [`Server`]: crate::server::Server
     pub async fn spawn(config: DnsConfig, dns_handler: DnsHandler) -> Result<Self> {
-        const TCP_TIMEOUT: Duration = Duration::from_millis(1000);
-        let mut server = hickory_server::ServerFuture::new(dns_handler);
-
-        let bind_addr = SocketAddr::new(
-            config.bind_addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
-            config.port,
+        ensure!(
+            !config.tls.enabled,
+            "DNS over TLS is only supported together with the HTTPS server"
         );
+        let listeners = DnsListeners::bind(&config).await?;
+        Self::spawn_with_listeners(&config, listeners, None, dns_handler)
+    }
 
-        let socket = UdpSocket::bind(bind_addr).await?;
-
-        let socket_addr = socket.local_addr()?;
-
-        server.register_socket(socket);
-        server.register_listener(TcpListener::bind(bind_addr).await?, TCP_TIMEOUT);
-        info!("DNS server listening on {}", bind_addr);
+    /// Spawns the server on bound listeners.
+    ///
+    /// The `tls_config` is required if the DNS over TLS listener is bound.
+    pub(crate) fn spawn_with_listeners(
+        config: &DnsConfig,
+        listeners: DnsListeners,
+        tls_config: Option<Arc<rustls::ServerConfig>>,
+        dns_handler: DnsHandler,
+    ) -> Result<Self> {
+        const TCP_TIMEOUT: Duration = Duration::from_millis(1000);
+        const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+        let mut server = hickory_server::ServerFuture::new(dns_handler.clone());
+        let mut batch_listeners = JoinSet::new();
+        let cancel = CancellationToken::new();
+
+        let local_addr = listeners.local_addr()?;
+        let DnsListeners { udp, tcp, tls } = listeners;
+
+        if let Some(addr) = udp.first().map(|socket| socket.local_addr()).transpose()? {
+            info!(
+                listeners = config.udp.listeners,
+                batch_recv = config.udp.batch_recv,
+                "DNS server listening on UDP {addr}",
+            );
+        }
+        for socket in udp {
+            match config.udp.batch_recv {
+                true => {
+                    let listener =
+                        udp::run_batch_listener(socket, dns_handler.clone(), cancel.clone());
+                    batch_listeners.spawn(listener.instrument(info_span!("dns-udp")));
+                }
+                false => server.register_socket(socket),
+            }
+        }
+        if let Some(listener) = tcp {
+            info!("DNS server listening on TCP {}", listener.local_addr()?);
+            server.register_listener(listener, TCP_TIMEOUT);
+        }
+        if let Some(listener) = tls {
+            let tls_config = tls_config.context("DNS over TLS requires a TLS configuration")?;
+            info!("DNS server listening on TLS {}", listener.local_addr()?);
+            server.register_tls_listener_with_tls_config(
+                listener,
+                TLS_HANDSHAKE_TIMEOUT,
+                tls_config,
+            )?;
+        }
 
         Ok(Self {
             server,
-            local_addr: socket_addr,
+            local_addr,
+            batch_listeners,
+            cancel,
         })
     }
 
-    /// Get the local address of the UDP/TCP socket.
+    /// Get the local address of the UDP socket, or of the TCP socket if UDP is disabled.
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
     }
 
     /// Shutdown the server an wait for all tasks to complete.
     pub async fn shutdown(mut self) -> Result<()> {
+        self.cancel.cancel();
         self.server.shutdown_gracefully().await?;
+        while let Some(res) = self.batch_listeners.join_next().await {
+            res??;
+        }
         Ok(())
     }
 
@@ -112,7 +260,10 @@ impl DnsServer {
     ///
     /// Runs forever unless tasks fail.
     pub async fn run_until_done(mut self) -> Result<()> {
-        self.server.block_until_done().await?;
+        tokio::select! {
+            res = self.server.block_until_done() => res?,
+            Some(res) = self.batch_listeners.join_next() => res??,
+        }
         Ok(())
     }
 }
@@ -122,6 +273,10 @@ impl DnsServer {
 pub struct DnsHandler {
     #[debug("Catalog")]
     catalog: Arc<Catalog>,
+    origins: Arc<[Name]>,
+    query_tracing: QueryTracingConfig,
+    next_query_id: Arc<AtomicU64>,
+    rrl: Option<Arc<ResponseRateLimiter>>,
 }
 
 impl DnsHandler {
@@ -134,11 +289,17 @@ impl DnsHandler {
             .map(Name::from_utf8)
             .collect::<Result<Vec<_>, _>>()?;
 
+        let rrl = config
+            .rrl
+            .clone()
+            .map(ResponseRateLimiter::new)
+            .transpose()?
+            .map(Arc::new);
         let (static_authority, serial) = create_static_authority(&origins, config)?;
         let authority = Arc::new(NodeAuthority::new(
             zone_store,
             static_authority,
-            origins,
+            origins.clone(),
             serial,
         )?);
 
@@ -149,6 +310,10 @@ impl DnsHandler {
 
         Ok(Self {
             catalog: Arc::new(catalog),
+            origins: origins.into(),
+            query_tracing: config.query_tracing.clone(),
+            next_query_id: Default::default(),
+            rrl,
         })
     }
 
@@ -159,6 +324,82 @@ impl DnsHandler {
         self.handle_request(&request, response_handle).await;
         Ok(rx.recv().await?)
     }
+
+    /// Applies the response rate limit to a query over UDP.
+    ///
+    /// Returns the info of the response if the query is not answered normally.
+    async fn rate_limit<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        response_handle: &mut R,
+    ) -> Option<ResponseInfo> {
+        let rrl = self
+            .rrl
+            .as_ref()
+            .filter(|_| request.protocol() == Protocol::Udp)?;
+        let mut header = Header::response_from_request(request.header());
+        match rrl.check(request.src().ip(), Instant::now()) {
+            RrlAction::Respond => None,
+            RrlAction::Drop => {
+                inc!(Metrics, dns_rrl_dropped);
+                debug!("DNS response dropped by the rate limit");
+                Some(header.into())
+            }
+            RrlAction::Slip => {
+                inc!(Metrics, dns_rrl_slipped);
+                debug!("DNS response truncated by the rate limit");
+                header.set_truncated(true);
+                let response =
+                    MessageResponseBuilder::from_message_request(request).build_no_records(header);
+                match response_handle.send_response(response).await {
+                    Ok(info) => Some(info),
+                    Err(err) => {
+                        warn!("failed to send truncated DNS response: {err}");
+                        Some(header.into())
+                    }
+                }
+            }
+        }
+    }
+
+    /// Explains why a request was answered with `REFUSED` or `NOTIMP`.
+    fn refusal_reason(&self, request: &Request) -> RefusalReason {
+        if request.op_code() == OpCode::Update {
+            return RefusalReason::Update;
+        }
+        if request.op_code() != OpCode::Query {
+            return RefusalReason::UnsupportedOpcode(request.op_code());
+        }
+        let query = request.query();
+        if self.catalog.find(query.name()).is_none() {
+            let origins = self.origins.iter().map(ToString::to_string).collect();
+            return RefusalReason::WrongZone(origins);
+        }
+        match query.query_type() {
+            RecordType::AXFR | RecordType::IXFR => RefusalReason::ZoneTransfer(query.query_type()),
+            _ => RefusalReason::LookupFailed,
+        }
+    }
+}
+
+/// Why a query was answered with `REFUSED` or `NOTIMP`.
+#[derive(Debug, Clone, PartialEq, Eq, derive_more::Display)]
+enum RefusalReason {
+    /// Only standard queries and updates are handled.
+    #[display("unsupported opcode {_0:?}")]
+    UnsupportedOpcode(OpCode),
+    /// Records are published to the pkarr relay, not with dynamic updates.
+    #[display("dynamic updates are not supported, signed packets are published over HTTP")]
+    Update,
+    /// The name is not below any of the origins.
+    #[display("wrong zone, the name is not below any of the origins {}", _0.join(", "))]
+    WrongZone(Vec<String>),
+    /// Zone transfers are refused.
+    #[display("zone transfers with {_0} are not allowed")]
+    ZoneTransfer(RecordType),
+    /// The zone store failed to look up the name.
+    #[display("the lookup in the zone store failed")]
+    LookupFailed,
 }
 
 #[async_trait::async_trait]
@@ -166,7 +407,7 @@ impl RequestHandler for DnsHandler {
     async fn handle_request<R: ResponseHandler>(
         &self,
         request: &Request,
-        response_handle: R,
+        mut response_handle: R,
     ) -> ResponseInfo {
         inc!(Metrics, dns_requests);
         match request.protocol() {
@@ -174,9 +415,55 @@ impl RequestHandler for DnsHandler {
             Protocol::Https => inc!(Metrics, dns_requests_https),
             _ => {}
         }
-        debug!(protocol=%request.protocol(), query=%request.query(), "incoming DNS request");
+        let query = request.query();
+        let client = match self.query_tracing.anonymize_client_addr {
+            true => anonymize_ip(request.src().ip()).to_string(),
+            false => request.src().to_string(),
+        };
+        let span = info_span!(
+            "dns_query",
+            id = self.next_query_id.fetch_add(1, Ordering::Relaxed),
+            %client,
+            qname = %query.name(),
+            qtype = %query.query_type(),
+            store_time = field::Empty,
+            rcode = field::Empty,
+        );
+        if let Some(info) = self
+            .rate_limit(request, &mut response_handle)
+            .instrument(span.clone())
+            .await
+        {
+            return info;
+        }
+        let start = Instant::now();
+        let res = async {
+            debug!(protocol=%request.protocol(), "incoming DNS request");
+            self.catalog.handle_request(request, response_handle).await
+        }
+        .instrument(span.clone())
+        .await;
+        let elapsed = start.elapsed();
+        let rcode = res.response_code();
+        span.record("rcode", field::display(rcode));
+        span.in_scope(|| {
+            debug!(?elapsed, "DNS response");
+            if matches!(rcode, ResponseCode::Refused | ResponseCode::NotImp) {
+                let reason = self.refusal_reason(request);
+                match self.query_tracing.log_refused {
+                    true => info!(%reason, "DNS query not answered"),
+                    false => debug!(%reason, "DNS query not answered"),
+                }
+            }
+            if self
+                .query_tracing
+                .slow_query_threshold
+                .is_some_and(|threshold| elapsed >= threshold)
+            {
+                warn!(?elapsed, "slow DNS query");
+            }
+        });
 
-        let res = self.catalog.handle_request(request, response_handle).await;
         match &res.response_code() {
             ResponseCode::NoError => match res.answer_count() {
                 0 => inc!(Metrics, dns_lookup_notfound),
@@ -219,6 +506,20 @@ impl ResponseHandler for Handle {
     }
 }
 
+/// Truncates an IP address to its /24 (IPv4) or /48 (IPv6) network.
+fn anonymize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            Ipv4Addr::new(a, b, c, 0).into()
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0).into()
+        }
+    }
+}
+
 fn create_static_authority(
     origins: &[Name],
     config: &DnsConfig,
@@ -274,3 +575,224 @@ fn push_record(records: &mut BTreeMap<RrKey, RecordSet>, serial: u32, record: Re
     record_set.insert(record, serial);
     records.insert(key, record_set);
 }
+
+#[cfg(test)]
+mod tests {
+    use hickory_server::{
+        authority::MessageRequest,
+        proto::{
+            op::{Message, Query},
+            serialize::binary::BinDecodable,
+        },
+    };
+    use tracing_test::traced_test;
+
+    use super::*;
+    use crate::{config::Config, store::PacketSource};
+
+    #[test]
+    fn test_anonymize_ip() {
+        assert_eq!(
+            anonymize_ip("192.0.2.77".parse().unwrap()),
+            "192.0.2.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            anonymize_ip("2001:db8:1:2:3:4:5:6".parse().unwrap()),
+            "2001:db8:1::".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_slow_query_log() -> Result<()> {
+        let mut config = Config::default().dns;
+        config.query_tracing = QueryTracingConfig {
+            anonymize_client_addr: true,
+            slow_query_threshold: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let store = ZoneStore::in_memory(Default::default())?;
+        let handler = DnsHandler::new(store, &config)?;
+
+        let mut query = Message::new();
+        query.add_query(Query::query(
+            Name::from_utf8("irohdns.example.")?,
+            RecordType::A,
+        ));
+        let request = Request::new(
+            MessageRequest::from_bytes(&query.to_vec()?)?,
+            "192.0.2.77:5353".parse()?,
+            Protocol::Udp,
+        );
+        handler.answer_request(request).await?;
+
+        assert!(logs_contain("slow DNS query"));
+        assert!(logs_contain("client=192.0.2.0"));
+        assert!(logs_contain("qname=irohdns.example."));
+        assert!(logs_contain("rcode=No Error"));
+        assert!(!logs_contain("192.0.2.77"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_response_rate_limit() -> Result<()> {
+        let mut config = Config::default().dns;
+        config.rrl = Some(RrlConfig {
+            responses_per_second: 1,
+            slip: 2,
+            subnets: vec![RrlSubnet {
+                network: "198.51.100.0/24".parse()?,
+                responses_per_second: None,
+                slip: None,
+                leak: None,
+            }],
+            ..Default::default()
+        });
+        let store = ZoneStore::in_memory(Default::default())?;
+        let handler = DnsHandler::new(store, &config)?;
+
+        let answer = |src: &str, protocol| {
+            let mut query = Message::new();
+            query.add_query(Query::query(
+                Name::from_utf8("irohdns.example.").unwrap(),
+                RecordType::A,
+            ));
+            let request = Request::new(
+                MessageRequest::from_bytes(&query.to_vec().unwrap()).unwrap(),
+                src.parse().unwrap(),
+                protocol,
+            );
+            let handler = handler.clone();
+            async move {
+                let response = handler.answer_request(request).await.ok()?;
+                Some(Message::from_vec(&response).unwrap())
+            }
+        };
+
+        let response = answer("192.0.2.1:5353", Protocol::Udp).await.unwrap();
+        assert!(!response.truncated());
+        assert!(!response.answers().is_empty());
+        // Dropped responses are not sent.
+        assert!(answer("192.0.2.2:5353", Protocol::Udp).await.is_none());
+        let response = answer("192.0.2.3:5353", Protocol::Udp).await.unwrap();
+        assert!(response.truncated());
+        assert!(response.answers().is_empty());
+        assert_eq!(response.queries().len(), 1);
+
+        // Only UDP is limited, and exempt subnets are not.
+        let response = answer("192.0.2.1:5353", Protocol::Https).await.unwrap();
+        assert!(!response.answers().is_empty());
+        for _ in 0..3 {
+            let response = answer("198.51.100.1:5353", Protocol::Udp).await.unwrap();
+            assert!(!response.answers().is_empty());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_refusal_reason() -> Result<()> {
+        let mut config = Config::default().dns;
+        config.origins = vec!["irohdns.example.".to_string()];
+        config.query_tracing.log_refused = true;
+        let store = ZoneStore::in_memory(Default::default())?;
+        let handler = DnsHandler::new(store, &config)?;
+
+        let answer = |name: &str, record_type, op_code| {
+            let mut query = Message::new();
+            query
+                .set_op_code(op_code)
+                .add_query(Query::query(Name::from_utf8(name).unwrap(), record_type));
+            let request = Request::new(
+                MessageRequest::from_bytes(&query.to_vec().unwrap()).unwrap(),
+                "192.0.2.77:5353".parse().unwrap(),
+                Protocol::Udp,
+            );
+            let handler = handler.clone();
+            async move {
+                let response = handler.answer_request(request).await.unwrap();
+                Message::from_vec(&response).unwrap().response_code()
+            }
+        };
+
+        let rcode = answer("other.example.", RecordType::A, OpCode::Query).await;
+        assert_eq!(rcode, ResponseCode::Refused);
+        assert!(logs_contain(
+            "wrong zone, the name is not below any of the origins irohdns.example."
+        ));
+
+        let rcode = answer("irohdns.example.", RecordType::AXFR, OpCode::Query).await;
+        assert_eq!(rcode, ResponseCode::Refused);
+        assert!(logs_contain("zone transfers with AXFR are not allowed"));
+
+        let rcode = answer("irohdns.example.", RecordType::SOA, OpCode::Update).await;
+        assert_eq!(rcode, ResponseCode::NotImp);
+        assert!(logs_contain("dynamic updates are not supported"));
+
+        let rcode = answer("irohdns.example.", RecordType::A, OpCode::Notify).await;
+        assert_eq!(rcode, ResponseCode::NotImp);
+        assert!(logs_contain("unsupported opcode Notify"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_https_query() -> Result<()> {
+        use pkarr::{dns, Keypair, SignedPacket};
+
+        let mut config = Config::default().dns;
+        config.origins = vec!["irohdns.example.".to_string()];
+        let store = ZoneStore::in_memory(Default::default())?;
+        let keypair = Keypair::random();
+        let mut packet = dns::Packet::new_reply(0);
+        packet.answers.push(dns::ResourceRecord::new(
+            dns::Name::new("")?,
+            dns::CLASS::IN,
+            30,
+            dns::rdata::RData::A(Ipv4Addr::new(192, 0, 2, 1).into()),
+        ));
+        packet.answers.push(dns::ResourceRecord::new(
+            dns::Name::new("_iroh")?,
+            dns::CLASS::IN,
+            30,
+            dns::rdata::RData::TXT("relay=https://relay.example".try_into()?),
+        ));
+        let packet = SignedPacket::from_packet(&keypair, &packet)?;
+        store.insert(packet, PacketSource::PkarrPublish).await?;
+        let handler = DnsHandler::new(store, &config)?;
+
+        let answer = |name: String, record_type| {
+            let mut query = Message::new();
+            query.add_query(Query::query(Name::from_utf8(name).unwrap(), record_type));
+            let request = Request::new(
+                MessageRequest::from_bytes(&query.to_vec().unwrap()).unwrap(),
+                "192.0.2.77:5353".parse().unwrap(),
+                Protocol::Udp,
+            );
+            let handler = handler.clone();
+            async move {
+                let response = handler.answer_request(request).await.unwrap();
+                Message::from_vec(&response).unwrap()
+            }
+        };
+
+        // The HTTPS record is derived from the A record.
+        let node = format!("{}.irohdns.example.", keypair.public_key().to_z32());
+        let response = answer(node.clone(), RecordType::HTTPS).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(response.answers()[0].record_type(), RecordType::HTTPS);
+
+        // Existing names without the record type are answered with NODATA and the SOA
+        // record for negative caching, names which do not exist with NXDOMAIN.
+        let response = answer(format!("_iroh.{node}"), RecordType::HTTPS).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+        assert!(response
+            .name_servers()
+            .iter()
+            .any(|record| record.record_type() == RecordType::SOA));
+        let response = answer(format!("other.{node}"), RecordType::HTTPS).await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        Ok(())
+    }
+}
@@ -17,7 +17,7 @@ use hickory_server::{
 use tracing::{debug, trace};
 
 use crate::{
-    store::ZoneStore,
+    store::{Resolved, ZoneStore},
     util::{record_set_append_origin, PublicKeyBytes},
 };
 
@@ -74,7 +74,7 @@ impl NodeAuthority {
             .await
             .map_err(err_refused)?
         {
-            Some(pkarr_set) => {
+            Some(Resolved::Records(pkarr_set)) => {
                 debug!(%origin, %pubkey, %name, "found {} records in pkarr zone", pkarr_set.records_without_rrsigs().count());
                 let new_origin =
                     Name::parse(&pubkey.to_z32(), Some(&origin)).map_err(err_refused)?;
@@ -84,6 +84,9 @@ impl NodeAuthority {
                 let answers = AuthLookup::answers(records, None);
                 Ok(answers)
             }
+            // Answering NODATA rather than NXDOMAIN tells resolvers that other record types
+            // exist at the name, browsers wait on the HTTPS record of names for instance.
+            Some(Resolved::NoData) => Err(LookupError::NameExists),
             None => Err(err_nx_domain("not found")),
         }
     }
@@ -0,0 +1,269 @@
+//! Response rate limiting of DNS over UDP.
+//!
+//! UDP queries can carry a spoofed source address, so the server can be abused to reflect
+//! responses onto a victim.  Response rate limiting (RRL) caps the responses sent to each
+//! client network per second.  Responses over the limit are dropped, except that every
+//! `slip`th is sent truncated, so legitimate clients retry over TCP, and every `leak`th is
+//! sent in full.
+
+use std::{
+    net::IpAddr,
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{ensure, Context, Result};
+use ipnet::IpNet;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+
+/// The period the responses to a client network are counted over.
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Configuration of the response rate limiting of DNS over UDP.
+#[derive(Clone, Debug, Serialize, Deserialize, schemars::JsonSchema)]
+#[serde(default)]
+pub struct RrlConfig {
+    /// The number of responses per second sent to a single client network.
+    pub responses_per_second: u32,
+    /// Every `slip`th response over the limit is sent truncated instead of dropped.
+    ///
+    /// A truncated response has no records, it asks the client to retry over TCP.  `0`
+    /// drops all responses over the limit, `1` truncates all of them.
+    pub slip: u32,
+    /// Every `leak`th response over the limit is sent in full.
+    ///
+    /// `0` sends none of them.
+    pub leak: u32,
+    /// The length of the IPv4 prefixes counted as a single client network.
+    pub ipv4_prefix_len: u8,
+    /// The length of the IPv6 prefixes counted as a single client network.
+    pub ipv6_prefix_len: u8,
+    /// The number of client networks tracked, the least recently seen are forgotten.
+    pub table_size: usize,
+    /// Limits of subnets, overriding the ones above.
+    ///
+    /// The most specific subnet containing the client address applies.
+    pub subnets: Vec<RrlSubnet>,
+}
+
+impl Default for RrlConfig {
+    fn default() -> Self {
+        Self {
+            responses_per_second: 50,
+            slip: 2,
+            leak: 0,
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 56,
+            table_size: 100_000,
+            subnets: Vec::new(),
+        }
+    }
+}
+
+/// The response rate limit of client addresses in a subnet, see [`RrlConfig::subnets`].
+///
+/// Unset fields are unlimited, or take the values of the [`RrlConfig`].
+#[derive(Clone, Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct RrlSubnet {
+    /// The subnet, in CIDR notation like `192.0.2.0/24`.
+    #[schemars(with = "String")]
+    pub network: IpNet,
+    /// The number of responses per second sent to a single client network in the subnet.
+    ///
+    /// Responses to the subnet are not limited if unset, e.g. for trusted resolvers.
+    pub responses_per_second: Option<u32>,
+    /// Overrides [`RrlConfig::slip`].
+    pub slip: Option<u32>,
+    /// Overrides [`RrlConfig::leak`].
+    pub leak: Option<u32>,
+}
+
+/// What to do with the response to a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RrlAction {
+    /// Send the response.
+    Respond,
+    /// Send a truncated response instead.
+    Slip,
+    /// Send no response.
+    Drop,
+}
+
+/// The limit applying to a client address.
+#[derive(Debug, Clone, Copy)]
+struct Limit {
+    responses_per_second: Option<u32>,
+    slip: u32,
+    leak: u32,
+}
+
+/// The responses sent to a client network in the current window.
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    responses: u32,
+    limited: u32,
+}
+
+/// Counts the responses to each client network to enforce a [`RrlConfig`].
+#[derive(Debug)]
+pub(crate) struct ResponseRateLimiter {
+    config: RrlConfig,
+    networks: Mutex<LruCache<IpAddr, Window>>,
+}
+
+impl ResponseRateLimiter {
+    pub(crate) fn new(config: RrlConfig) -> Result<Self> {
+        ensure!(
+            config.ipv4_prefix_len <= 32,
+            "invalid RRL IPv4 prefix length {}",
+            config.ipv4_prefix_len
+        );
+        ensure!(
+            config.ipv6_prefix_len <= 128,
+            "invalid RRL IPv6 prefix length {}",
+            config.ipv6_prefix_len
+        );
+        let table_size =
+            NonZeroUsize::new(config.table_size).context("the RRL table size must not be zero")?;
+        Ok(Self {
+            config,
+            networks: Mutex::new(LruCache::new(table_size)),
+        })
+    }
+
+    /// Counts a response to `addr`, returning what to do with it.
+    pub(crate) fn check(&self, addr: IpAddr, now: Instant) -> RrlAction {
+        let limit = self.limit(addr);
+        let Some(responses_per_second) = limit.responses_per_second else {
+            return RrlAction::Respond;
+        };
+        let mut networks = self.networks.lock().expect("poisoned");
+        let window = networks.get_or_insert_mut(self.network(addr), || Window {
+            start: now,
+            responses: 0,
+            limited: 0,
+        });
+        if now.saturating_duration_since(window.start) >= WINDOW {
+            *window = Window {
+                start: now,
+                responses: 0,
+                limited: 0,
+            };
+        }
+        if window.responses < responses_per_second {
+            window.responses += 1;
+            return RrlAction::Respond;
+        }
+        window.limited += 1;
+        if window.limited.checked_rem(limit.leak) == Some(0) {
+            RrlAction::Respond
+        } else if window.limited.checked_rem(limit.slip) == Some(0) {
+            RrlAction::Slip
+        } else {
+            RrlAction::Drop
+        }
+    }
+
+    fn limit(&self, addr: IpAddr) -> Limit {
+        let subnet = self
+            .config
+            .subnets
+            .iter()
+            .filter(|subnet| subnet.network.contains(&addr))
+            .max_by_key(|subnet| subnet.network.prefix_len());
+        match subnet {
+            Some(subnet) => Limit {
+                responses_per_second: subnet.responses_per_second,
+                slip: subnet.slip.unwrap_or(self.config.slip),
+                leak: subnet.leak.unwrap_or(self.config.leak),
+            },
+            None => Limit {
+                responses_per_second: Some(self.config.responses_per_second),
+                slip: self.config.slip,
+                leak: self.config.leak,
+            },
+        }
+    }
+
+    /// Returns the client network of the address.
+    fn network(&self, addr: IpAddr) -> IpAddr {
+        let prefix_len = match addr {
+            IpAddr::V4(_) => self.config.ipv4_prefix_len,
+            IpAddr::V6(_) => self.config.ipv6_prefix_len,
+        };
+        IpNet::new(addr, prefix_len)
+            .expect("valid prefix length")
+            .network()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slip_and_leak() -> Result<()> {
+        let rrl = ResponseRateLimiter::new(RrlConfig {
+            responses_per_second: 2,
+            slip: 2,
+            leak: 3,
+            ..Default::default()
+        })?;
+        let now = Instant::now();
+        let addr: IpAddr = "192.0.2.1".parse()?;
+        let actions: Vec<_> = (0..8).map(|_| rrl.check(addr, now)).collect();
+        use RrlAction::*;
+        assert_eq!(
+            actions,
+            [Respond, Respond, Drop, Slip, Respond, Slip, Drop, Respond]
+        );
+
+        // The network of the client is limited, other networks are not.
+        assert_eq!(rrl.check("192.0.2.200".parse()?, now), Drop);
+        assert_eq!(rrl.check("198.51.100.1".parse()?, now), Respond);
+
+        // The limit starts over in the next window.
+        let later = now + WINDOW;
+        assert_eq!(rrl.check(addr, later), Respond);
+        assert_eq!(rrl.check(addr, later), Respond);
+        assert_eq!(rrl.check(addr, later), Drop);
+        Ok(())
+    }
+
+    #[test]
+    fn test_subnets() -> Result<()> {
+        let rrl = ResponseRateLimiter::new(RrlConfig {
+            responses_per_second: 1,
+            slip: 0,
+            subnets: vec![
+                RrlSubnet {
+                    network: "10.0.0.0/8".parse()?,
+                    responses_per_second: Some(3),
+                    slip: Some(1),
+                    leak: None,
+                },
+                RrlSubnet {
+                    network: "10.1.0.0/16".parse()?,
+                    responses_per_second: None,
+                    slip: None,
+                    leak: None,
+                },
+            ],
+            ..Default::default()
+        })?;
+        let now = Instant::now();
+        let count = |addr: &str, action| {
+            let addr = addr.parse().unwrap();
+            (0..10).filter(|_| rrl.check(addr, now) == action).count()
+        };
+        assert_eq!(count("192.0.2.1", RrlAction::Drop), 9);
+        assert_eq!(count("10.2.0.1", RrlAction::Respond), 3);
+        assert_eq!(count("10.2.0.1", RrlAction::Slip), 10);
+        assert_eq!(count("10.1.0.1", RrlAction::Respond), 10);
+        assert_eq!(count("2001:db8::1", RrlAction::Respond), 1);
+        Ok(())
+    }
+}
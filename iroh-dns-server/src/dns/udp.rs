@@ -0,0 +1,291 @@
+//! UDP listeners of the DNS server.
+//!
+//! By default a single socket is served by [`hickory_server::ServerFuture`].  For high query
+//! rates the DNS port can be sharded over several sockets bound with `SO_REUSEPORT`, and
+//! queries can be received in batches, using `recvmmsg` (and GRO) where supported.
+
+use std::{
+    io::{self, IoSliceMut},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use anyhow::{bail, ensure, Result};
+use async_trait::async_trait;
+use hickory_server::{
+    authority::{MessageRequest, MessageResponse},
+    proto::{
+        self,
+        op::MessageType,
+        serialize::binary::{BinDecodable, BinEncoder},
+        xfer::Protocol,
+    },
+    server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
+};
+use quinn_udp::{RecvMeta, UdpSocketState, BATCH_SIZE};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
+use tokio::{io::Interest, net::UdpSocket, sync::Semaphore, task::JoinSet};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use super::DnsHandler;
+
+/// The size of a receive buffer, large enough for datagrams coalesced by GRO.
+const RECV_BUFFER_SIZE: usize = u16::MAX as usize;
+
+/// The max number of queries of a batch listener handled concurrently.
+///
+/// Once reached the listener stops receiving until a query is answered, further queries
+/// queue in the receive buffer of the socket and are dropped by the kernel once it is full.
+const MAX_CONCURRENT_QUERIES: usize = 1024;
+
+/// UDP socket settings of the DNS server.
+#[derive(Clone, Debug, Serialize, Deserialize, schemars::JsonSchema)]
+#[serde(default)]
+pub struct UdpConfig {
+    /// Whether to serve DNS over UDP on the DNS port.
+    pub enabled: bool,
+    /// The number of UDP sockets bound to the DNS port.
+    ///
+    /// With more than one listener the sockets are bound with `SO_REUSEPORT` and the
+    /// kernel distributes the queries between them.  Only supported on Unix.
+    pub listeners: usize,
+    /// The receive buffer size to request for each socket, in bytes.
+    ///
+    /// The kernel might cap it, e.g. at `net.core.rmem_max` on Linux.  Uses the system
+    /// default if unset.
+    pub recv_buffer_size: Option<usize>,
+    /// Whether to receive queries in batches, using `recvmmsg` where supported.
+    pub batch_recv: bool,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            listeners: 1,
+            recv_buffer_size: None,
+            batch_recv: false,
+        }
+    }
+}
+
+/// Binds the UDP sockets for the DNS server.
+///
+/// All sockets are bound to the port of the first one, in case the port in `addr` is zero.
+pub(crate) fn bind_sockets(addr: SocketAddr, config: &UdpConfig) -> Result<Vec<UdpSocket>> {
+    ensure!(
+        config.listeners > 0,
+        "at least one UDP listener is required"
+    );
+    let mut addr = addr;
+    let mut sockets = Vec::with_capacity(config.listeners);
+    for _ in 0..config.listeners {
+        let socket = bind_socket(addr, config)?;
+        addr = socket.local_addr()?;
+        sockets.push(socket);
+    }
+    if let Some(size) = config.recv_buffer_size {
+        let actual = socket2::SockRef::from(&sockets[0]).recv_buffer_size()?;
+        info!(requested = size, actual, "DNS UDP receive buffer size");
+    }
+    Ok(sockets)
+}
+
+fn bind_socket(addr: SocketAddr, config: &UdpConfig) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    if config.listeners > 1 {
+        set_reuse_port(&socket)?;
+    }
+    if let Some(size) = config.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> Result<()> {
+    socket.set_reuse_port(true)?;
+    Ok(())
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &Socket) -> Result<()> {
+    bail!("multiple UDP listeners are not supported on this platform")
+}
+
+/// Serves DNS queries on the socket, receiving them in batches.
+///
+/// Runs until cancelled or the socket fails.  Malformed queries are dropped without a
+/// response.  At most [`MAX_CONCURRENT_QUERIES`] queries are handled at a time.
+pub(super) async fn run_batch_listener(
+    socket: UdpSocket,
+    handler: DnsHandler,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let socket = Arc::new(socket);
+    let state = UdpSocketState::new((&*socket).into())?;
+    let mut bufs = vec![vec![0u8; RECV_BUFFER_SIZE]; BATCH_SIZE];
+    let mut meta = [RecvMeta::default(); BATCH_SIZE];
+    let mut tasks = JoinSet::new();
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_QUERIES));
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            res = socket.readable() => res?,
+        }
+        let mut slices: Vec<_> = bufs.iter_mut().map(|buf| IoSliceMut::new(buf)).collect();
+        let count = match socket.try_io(Interest::READABLE, || {
+            state.recv((&*socket).into(), &mut slices, &mut meta)
+        }) {
+            Ok(count) => count,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            // Caused by ICMP errors of earlier responses on some platforms.
+            Err(err) if err.kind() == io::ErrorKind::ConnectionReset => continue,
+            Err(err) => bail!("error receiving on UDP socket: {err}"),
+        };
+        for (meta, buf) in meta.iter().zip(slices.iter()).take(count) {
+            if meta.addr.port() == 0 || meta.addr.ip().is_unspecified() {
+                warn!(src = %meta.addr, "cannot respond to DNS query");
+                continue;
+            }
+            let stride = meta.stride.max(1);
+            for datagram in buf[..meta.len].chunks(stride) {
+                let handle = UdpResponseHandle {
+                    socket: socket.clone(),
+                    dst: meta.addr,
+                };
+                let handler = handler.clone();
+                let datagram = datagram.to_vec();
+                let permit = tokio::select! {
+                    _ = cancel.cancelled() => return Ok(()),
+                    permit = permits.clone().acquire_owned() => permit?,
+                };
+                tasks.spawn(async move {
+                    handle_datagram(handler, datagram, handle).await;
+                    drop(permit);
+                });
+            }
+        }
+        while tasks.try_join_next().is_some() {}
+    }
+    Ok(())
+}
+
+async fn handle_datagram(handler: DnsHandler, datagram: Vec<u8>, handle: UdpResponseHandle) {
+    let message = match MessageRequest::from_bytes(&datagram) {
+        Ok(message) => message,
+        Err(err) => {
+            debug!(src = %handle.dst, "invalid DNS query: {err}");
+            return;
+        }
+    };
+    if message.message_type() != MessageType::Query {
+        debug!(src = %handle.dst, "DNS message is not a query");
+        return;
+    }
+    let request = Request::new(message, handle.dst, Protocol::Udp);
+    handler.handle_request(&request, handle).await;
+}
+
+/// Sends a response directly on the socket the query was received on.
+#[derive(Debug, Clone)]
+struct UdpResponseHandle {
+    socket: Arc<UdpSocket>,
+    dst: SocketAddr,
+}
+
+#[async_trait]
+impl ResponseHandler for UdpResponseHandle {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a proto::rr::Record> + Send + 'a,
+            impl Iterator<Item = &'a proto::rr::Record> + Send + 'a,
+            impl Iterator<Item = &'a proto::rr::Record> + Send + 'a,
+            impl Iterator<Item = &'a proto::rr::Record> + Send + 'a,
+        >,
+    ) -> io::Result<ResponseInfo> {
+        // The same limit as used for responses sent by hickory.
+        let max_size = match response.get_edns() {
+            Some(edns) => edns.max_payload(),
+            None => proto::udp::MAX_RECEIVE_BUFFER_SIZE as u16,
+        };
+        let mut bytes = Vec::with_capacity(512);
+        let info = {
+            let mut encoder = BinEncoder::new(&mut bytes);
+            encoder.set_max_size(max_size);
+            response
+                .destructive_emit(&mut encoder)
+                .map_err(io::Error::other)?
+        };
+        self.socket.send_to(&bytes, self.dst).await?;
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+
+    use hickory_server::proto::{
+        op::{Message, Query, ResponseCode},
+        rr::{Name, RecordType},
+    };
+
+    use super::*;
+    use crate::{config::Config, dns::DnsServer, store::ZoneStore};
+
+    #[tokio::test]
+    async fn test_sharded_batch_listeners() -> Result<()> {
+        let mut config = Config::default().dns;
+        config.port = 0;
+        config.bind_addr = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        config.udp = UdpConfig {
+            enabled: true,
+            listeners: if cfg!(unix) { 2 } else { 1 },
+            recv_buffer_size: Some(1 << 20),
+            batch_recv: true,
+        };
+        let store = ZoneStore::in_memory(Default::default())?;
+        let handler = DnsHandler::new(store, &config)?;
+        let server = DnsServer::spawn(config, handler).await?;
+
+        // Burst a number of queries before reading any response.
+        const QUERIES: u16 = 64;
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        for id in 0..QUERIES {
+            let mut query = Message::new();
+            query.set_id(id);
+            query.add_query(Query::query(
+                Name::from_utf8("irohdns.example.")?,
+                RecordType::A,
+            ));
+            client
+                .send_to(&query.to_vec()?, server.local_addr())
+                .await?;
+        }
+        let mut ids = Vec::new();
+        let mut buf = [0u8; 4096];
+        while ids.len() < QUERIES as usize {
+            let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf)).await??;
+            let response = Message::from_vec(&buf[..len])?;
+            assert_eq!(response.response_code(), ResponseCode::NoError);
+            assert!(!response.answers().is_empty());
+            ids.push(response.id());
+        }
+        ids.sort_unstable();
+        assert_eq!(ids, (0..QUERIES).collect::<Vec<_>>());
+
+        server.shutdown().await?;
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 //! HTTP server part of iroh-dns-server
 
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::{IpAddr, SocketAddr},
     time::Instant,
 };
 
@@ -12,29 +12,31 @@ use axum::{
     http::Method,
     middleware::{self, Next},
     response::IntoResponse,
-    routing::get,
+    routing::{get, post},
     Router,
 };
 use iroh_metrics::{inc, inc_by};
 use serde::{Deserialize, Serialize};
-use tokio::{net::TcpListener, task::JoinSet};
+use tokio::task::JoinSet;
 use tower_http::{
     cors::{self, CorsLayer},
     trace::TraceLayer,
 };
 use tracing::{info, span, warn, Level};
 
+mod admin;
 mod doh;
 mod error;
 mod pkarr;
 mod rate_limiting;
 mod tls;
 
+pub(crate) use self::tls::TlsAcceptor;
 pub use self::{rate_limiting::RateLimitConfig, tls::CertMode};
-use crate::{config::Config, metrics::Metrics, state::AppState};
+use crate::{config::Config, listeners::HttpListeners, metrics::Metrics, state::AppState};
 
 /// Config for the HTTP server
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, schemars::JsonSchema)]
 pub struct HttpConfig {
     /// Port to bind to
     pub port: u16,
@@ -43,7 +45,7 @@ pub struct HttpConfig {
 }
 
 /// Config for the HTTPS server
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, schemars::JsonSchema)]
 pub struct HttpsConfig {
     /// Port to bind to
     pub port: u16,
@@ -77,23 +79,35 @@ impl HttpServer {
         if http_config.is_none() && https_config.is_none() {
             bail!("Either http or https config is required");
         }
+        let listeners = HttpListeners::bind(http_config.as_ref(), https_config.as_ref()).await?;
+        let acceptor = match https_config {
+            Some(config) => Some(tls_acceptor(config).await?),
+            None => None,
+        };
+        Self::spawn_with_listeners(listeners, acceptor, rate_limit_config, state)
+    }
 
+    /// Spawns the server on bound listeners.
+    ///
+    /// The `acceptor` is required if the HTTPS listener is bound.
+    pub(crate) fn spawn_with_listeners(
+        listeners: HttpListeners,
+        acceptor: Option<TlsAcceptor>,
+        rate_limit_config: RateLimitConfig,
+        state: AppState,
+    ) -> Result<HttpServer> {
         let app = create_app(state, &rate_limit_config);
 
         let mut tasks = JoinSet::new();
 
         // launch http
-        let http_addr = if let Some(config) = http_config {
-            let bind_addr = SocketAddr::new(
-                config.bind_addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
-                config.port,
-            );
-            let app = app.clone();
-            let listener = TcpListener::bind(bind_addr).await?.into_std()?;
+        let http_addr = if let Some(listener) = listeners.http {
             let bound_addr = listener.local_addr()?;
-            let fut = axum_server::from_tcp(listener)
-                .serve(app.into_make_service_with_connect_info::<SocketAddr>());
-            info!("HTTP server listening on {bind_addr}");
+            let fut = axum_server::from_tcp(listener).serve(
+                app.clone()
+                    .into_make_service_with_connect_info::<SocketAddr>(),
+            );
+            info!("HTTP server listening on {bound_addr}");
             tasks.spawn(fut);
             Some(bound_addr)
         } else {
@@ -101,36 +115,13 @@ impl HttpServer {
         };
 
         // launch https
-        let https_addr = if let Some(config) = https_config {
-            let bind_addr = SocketAddr::new(
-                config.bind_addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
-                config.port,
-            );
-            let acceptor = {
-                let cache_path = Config::data_dir()?
-                    .join("cert_cache")
-                    .join(config.cert_mode.to_string());
-                tokio::fs::create_dir_all(&cache_path)
-                    .await
-                    .with_context(|| {
-                        format!("failed to create cert cache dir at {cache_path:?}")
-                    })?;
-                config
-                    .cert_mode
-                    .build(
-                        config.domains,
-                        cache_path,
-                        config.letsencrypt_contact,
-                        config.letsencrypt_prod.unwrap_or(false),
-                    )
-                    .await?
-            };
-            let listener = TcpListener::bind(bind_addr).await?.into_std()?;
+        let https_addr = if let Some(listener) = listeners.https {
+            let acceptor = acceptor.context("HTTPS requires a TLS acceptor")?;
             let bound_addr = listener.local_addr()?;
             let fut = axum_server::from_tcp(listener)
                 .acceptor(acceptor)
                 .serve(app.into_make_service_with_connect_info::<SocketAddr>());
-            info!("HTTPS server listening on {bind_addr}");
+            info!("HTTPS server listening on {bound_addr}");
             tasks.spawn(fut);
             Some(bound_addr)
         } else {
@@ -185,6 +176,25 @@ impl HttpServer {
     }
 }
 
+/// Builds the acceptor for the certificates of the HTTPS server.
+pub(crate) async fn tls_acceptor(config: HttpsConfig) -> Result<TlsAcceptor> {
+    let cache_path = Config::data_dir()?
+        .join("cert_cache")
+        .join(config.cert_mode.to_string());
+    tokio::fs::create_dir_all(&cache_path)
+        .await
+        .with_context(|| format!("failed to create cert cache dir at {cache_path:?}"))?;
+    config
+        .cert_mode
+        .build(
+            config.domains,
+            cache_path,
+            config.letsencrypt_contact,
+            config.letsencrypt_prod.unwrap_or(false),
+        )
+        .await
+}
+
 pub(crate) fn create_app(state: AppState, rate_limit_config: &RateLimitConfig) -> Router {
     // configure cors middleware
     let cors = CorsLayer::new()
@@ -215,7 +225,7 @@ pub(crate) fn create_app(state: AppState, rate_limit_config: &RateLimitConfig) -
     // configure routes
     //
     // only the pkarr::put route gets a rate limit
-    let router = Router::new()
+    let mut router = Router::new()
         .route("/dns-query", get(doh::get).post(doh::post))
         .route(
             "/pkarr/:key",
@@ -226,8 +236,17 @@ pub(crate) fn create_app(state: AppState, rate_limit_config: &RateLimitConfig) -
             },
         )
         .route("/healthcheck", get(|| async { "OK" }))
-        .route("/", get(|| async { "Hi!" }))
-        .with_state(state);
+        .route("/", get(|| async { "Hi!" }));
+    if state.admin.is_some() {
+        let admin = Router::new()
+            .route("/selftest", post(admin::selftest))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                admin::authorize,
+            ));
+        router = router.nest("/admin", admin);
+    }
+    let router = router.with_state(state);
 
     // configure app
     router
@@ -0,0 +1,65 @@
+//! The admin API, see [`AdminConfig`].
+//!
+//! [`AdminConfig`]: crate::config::AdminConfig
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::{header, StatusCode};
+use tracing::{info, warn};
+
+use super::error::AppError;
+use crate::state::AppState;
+
+/// Rejects requests without the bearer token of the admin API.
+pub async fn authorize(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let Some(admin) = &state.admin else {
+        return Err(AppError::with_status(StatusCode::NOT_FOUND));
+    };
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "))
+        .is_some_and(|token| constant_time_eq(token, admin.bearer_token.as_bytes()));
+    if !authorized {
+        let headers = [(header::WWW_AUTHENTICATE, "Bearer")];
+        let err = AppError::with_status(StatusCode::UNAUTHORIZED);
+        return Ok((headers, err).into_response());
+    }
+    Ok(next.run(req).await)
+}
+
+/// Runs a publish and resolve round trip against the server.
+///
+/// Answers with the report, with `500 Internal Server Error` if the round trip failed.
+pub async fn selftest(State(state): State<AppState>) -> Result<Response, AppError> {
+    let Some(selftest) = &state.selftest else {
+        return Err(AppError::new(
+            StatusCode::NOT_IMPLEMENTED,
+            Some("the self-test needs the HTTP server and DNS over UDP"),
+        ));
+    };
+    let report = selftest.run().await;
+    match report.success {
+        true => {
+            info!(key = %report.key, "self-test passed");
+            Ok(Json(report).into_response())
+        }
+        false => {
+            warn!(key = %report.key, "self-test failed");
+            Ok((StatusCode::INTERNAL_SERVER_ERROR, Json(report)).into_response())
+        }
+    }
+}
+
+/// Compares two byte strings in a time independent of their content.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
@@ -6,6 +6,8 @@ use axum::{
 };
 use serde::{Deserialize, Serialize};
 
+use crate::validation::PublishRejection;
+
 pub type AppResult<T> = Result<T, AppError>;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -13,6 +15,9 @@ pub struct AppError {
     #[serde(with = "serde_status_code")]
     status: StatusCode,
     detail: Option<String>,
+    /// Why a published packet was rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rejection: Option<PublishRejection>,
 }
 
 impl Default for AppError {
@@ -20,6 +25,7 @@ impl Default for AppError {
         Self {
             status: StatusCode::INTERNAL_SERVER_ERROR,
             detail: None,
+            rejection: None,
         }
     }
 }
@@ -29,6 +35,7 @@ impl AppError {
         Self {
             status,
             detail: None,
+            rejection: None,
         }
     }
 
@@ -38,6 +45,7 @@ impl AppError {
             status: status_code,
             // title: Self::canonical_reason_to_string(&status_code),
             detail: message.map(|m| m.to_string()),
+            rejection: None,
         }
     }
 }
@@ -54,6 +62,17 @@ impl From<anyhow::Error> for AppError {
         Self {
             status: StatusCode::INTERNAL_SERVER_ERROR,
             detail: Some(value.to_string()),
+            rejection: None,
+        }
+    }
+}
+
+impl From<PublishRejection> for AppError {
+    fn from(value: PublishRejection) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            detail: Some(value.to_string()),
+            rejection: Some(value),
         }
     }
 }
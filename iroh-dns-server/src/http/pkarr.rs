@@ -1,7 +1,7 @@
 use anyhow::Result;
 use axum::{
     extract::{Path, State},
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
 use bytes::Bytes;
 use http::{header, StatusCode};
@@ -14,9 +14,24 @@ pub async fn put(
     State(state): State<AppState>,
     Path(key): Path<String>,
     body: Bytes,
-) -> Result<impl IntoResponse, AppError> {
+) -> Result<Response, AppError> {
     let key = pkarr::PublicKey::try_from(key.as_str())
         .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, Some(format!("invalid key: {e}"))))?;
+    if let Some(primary_url) = state.store.primary_url() {
+        // Read-only replica: point the publisher to the primary.
+        let location = format!("{}/{}", primary_url.as_str().trim_end_matches('/'), key);
+        let err = AppError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            Some(format!(
+                "this server is a read-only replica, publish to {location}"
+            )),
+        );
+        let headers = [
+            (header::ALLOW, "GET".to_string()),
+            (header::LOCATION, location),
+        ];
+        return Ok((headers, err).into_response());
+    }
     let label = &key.to_z32()[..10];
     let signed_packet = pkarr::SignedPacket::from_relay_payload(&key, &body).map_err(|e| {
         AppError::new(
@@ -24,13 +39,14 @@ pub async fn put(
             Some(format!("invalid body payload: {e}")),
         )
     })?;
+    state.store.validate(&signed_packet)?;
 
     let updated = state
         .store
         .insert(signed_packet, PacketSource::PkarrPublish)
         .await?;
     info!(key = %label, ?updated, "pkarr upsert");
-    Ok(StatusCode::NO_CONTENT)
+    Ok(StatusCode::NO_CONTENT.into_response())
 }
 
 pub async fn get(
@@ -9,7 +9,7 @@ use tower_governor::{
 };
 
 /// Config for http server rate limit.
-#[derive(Debug, Deserialize, Default, Serialize, Clone)]
+#[derive(Debug, Deserialize, Default, Serialize, Clone, schemars::JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum RateLimitConfig {
     /// Disable rate limit.
@@ -18,7 +18,9 @@ use tokio_stream::StreamExt;
 use tracing::{debug, error, info_span, Instrument};
 
 /// The mode how SSL certificates should be created.
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, strum::Display)]
+#[derive(
+    Serialize, Deserialize, Debug, Clone, PartialEq, Eq, strum::Display, schemars::JsonSchema,
+)]
 #[serde(rename_all = "snake_case")]
 pub enum CertMode {
     /// Certs are loaded from a the `cert_cache` path
@@ -53,8 +55,8 @@ impl CertMode {
 /// TLS Certificate Authority acceptor.
 #[derive(Clone)]
 pub enum TlsAcceptor {
-    LetsEncrypt(AxumAcceptor),
-    Manual(RustlsAcceptor),
+    LetsEncrypt(AxumAcceptor, Arc<rustls::ServerConfig>),
+    Manual(RustlsAcceptor, RustlsConfig),
 }
 
 impl<I: AsyncRead + AsyncWrite + Unpin + Send + 'static, S: Send + 'static> Accept<I, S>
@@ -66,19 +68,27 @@ impl<I: AsyncRead + AsyncWrite + Unpin + Send + 'static, S: Send + 'static> Acce
 
     fn accept(&self, stream: I, service: S) -> Self::Future {
         match self {
-            Self::LetsEncrypt(a) => a.accept(stream, service).boxed(),
-            Self::Manual(a) => a.accept(stream, service).boxed(),
+            Self::LetsEncrypt(a, _) => a.accept(stream, service).boxed(),
+            Self::Manual(a, _) => a.accept(stream, service).boxed(),
         }
     }
 }
 
 impl TlsAcceptor {
+    /// Returns the TLS configuration serving the certificates, for other TLS listeners.
+    pub(crate) fn server_config(&self) -> Arc<rustls::ServerConfig> {
+        match self {
+            Self::LetsEncrypt(_, config) => config.clone(),
+            Self::Manual(_, config) => config.get_inner(),
+        }
+    }
+
     async fn self_signed(domains: Vec<String>) -> Result<Self> {
         let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(domains)?;
         let config =
             RustlsConfig::from_der(vec![cert.der().to_vec()], key_pair.serialize_der()).await?;
-        let acceptor = RustlsAcceptor::new(config);
-        Ok(Self::Manual(acceptor))
+        let acceptor = RustlsAcceptor::new(config.clone());
+        Ok(Self::Manual(acceptor, config))
     }
 
     async fn manual(domains: Vec<String>, dir: PathBuf) -> Result<Self> {
@@ -95,8 +105,8 @@ impl TlsAcceptor {
 
         let config = config.with_single_cert(certs, secret_key)?;
         let config = RustlsConfig::from_config(Arc::new(config));
-        let acceptor = RustlsAcceptor::new(config);
-        Ok(Self::Manual(acceptor))
+        let acceptor = RustlsAcceptor::new(config.clone());
+        Ok(Self::Manual(acceptor, config))
     }
 
     fn letsencrypt(
@@ -127,8 +137,8 @@ impl TlsAcceptor {
             .instrument(info_span!("acme")),
         );
         let config = Arc::new(config);
-        let acceptor = AxumAcceptor::new(acceptor, config);
-        Ok(Self::LetsEncrypt(acceptor))
+        let acceptor = AxumAcceptor::new(acceptor, config.clone());
+        Ok(Self::LetsEncrypt(acceptor, config))
     }
 }
 
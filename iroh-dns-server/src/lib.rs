@@ -3,16 +3,20 @@
 #![deny(missing_docs, rustdoc::broken_intra_doc_links)]
 
 pub mod config;
+pub mod deploy;
 pub mod dns;
 pub mod http;
+mod listeners;
 pub mod metrics;
+pub mod selftest;
 pub mod server;
 pub mod state;
 mod store;
 mod util;
+pub mod validation;
 
 // Re-export to be able to construct your own dns-server
-pub use store::ZoneStore;
+pub use store::{EvictionCallback, ZoneStore, ZoneStoreOptions};
 
 #[cfg(test)]
 mod tests {
@@ -37,6 +41,7 @@ mod tests {
         server::Server,
         store::{PacketSource, ZoneStoreOptions},
         util::PublicKeyBytes,
+        validation::PublishPolicy,
         ZoneStore,
     };
 
@@ -187,6 +192,116 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn pkarr_publish_rejected() -> Result<()> {
+        let store =
+            ZoneStore::in_memory(Default::default())?.with_publish_validator(PublishPolicy {
+                max_ttl: Some(10),
+                ..Default::default()
+            });
+        let (server, _nameserver, http_url) = Server::spawn_for_tests_with_store(store).await?;
+        let pkarr_relay = http_url.join("/pkarr")?;
+
+        // The node info is published with a TTL of 30 seconds.
+        let signed_packet = random_signed_packet()?;
+        let pkarr = PkarrRelayClient::new(pkarr_relay);
+        let err = pkarr.publish(&signed_packet).await.unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains("400"), "{err}");
+        assert!(err.contains(r#""reason":"ttl_too_large""#), "{err}");
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn pkarr_replica() -> Result<()> {
+        let (primary, _nameserver, primary_url) = Server::spawn_for_tests().await?;
+        let primary_relay = primary_url.join("/pkarr")?;
+        let store = ZoneStore::in_memory(Default::default())?
+            .with_primary(primary_relay.clone(), Duration::from_millis(200));
+        let (replica, replica_nameserver, replica_url) =
+            Server::spawn_for_tests_with_store(store.clone()).await?;
+
+        let origin = "irohdns.example.";
+        let secret_key = SecretKey::generate(rand::thread_rng());
+        let node_id = secret_key.public();
+        let relay_url: Url = "https://relay.example.".parse()?;
+        let node_info = NodeInfo::new(node_id, Some(relay_url.clone()), Default::default());
+        let signed_packet = node_info.to_pkarr_signed_packet(&secret_key, 30)?;
+        PkarrRelayClient::new(primary_relay.clone())
+            .publish(&signed_packet)
+            .await?;
+
+        // The replica serves packets published to the primary.
+        let resolver = test_resolver(replica_nameserver);
+        let res = resolver.lookup_node_by_id(&node_id, origin).await?;
+        assert_eq!(res.relay_url.map(Url::from), Some(relay_url));
+
+        // The replica refuses publishes.
+        let err = PkarrRelayClient::new(replica_url.join("/pkarr")?)
+            .publish(&signed_packet)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("405"), "{err}");
+        assert!(err.contains(primary_relay.as_str()), "{err}");
+
+        // Updates on the primary are fetched once the refresh interval passed.
+        let relay_url: Url = "https://relay2.example.".parse()?;
+        let node_info = NodeInfo::new(node_id, Some(relay_url.clone()), Default::default());
+        let signed_packet = node_info.to_pkarr_signed_packet(&secret_key, 30)?;
+        PkarrRelayClient::new(primary_relay)
+            .publish(&signed_packet)
+            .await?;
+        let key = PublicKeyBytes::from_signed_packet(&signed_packet);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        // The stored packet is served while the update is fetched in the background.
+        store.get_signed_packet(&key).await?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let packet = store.get_signed_packet(&key).await?;
+                if packet.expect("packet is stored").timestamp() == signed_packet.timestamp() {
+                    return anyhow::Ok(());
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await??;
+
+        replica.shutdown().await?;
+        primary.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn pkarr_replica_unresponsive_primary() -> Result<()> {
+        // Accepts connections but never answers.
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let primary_relay: Url = format!("http://{}/pkarr", listener.local_addr()?).parse()?;
+        let store = ZoneStore::in_memory(Default::default())?
+            .with_primary(primary_relay, Duration::from_millis(200));
+
+        let secret_key = SecretKey::generate(rand::thread_rng());
+        let node_info = NodeInfo::new(secret_key.public(), None, Default::default());
+        let signed_packet = node_info.to_pkarr_signed_packet(&secret_key, 30)?;
+        let key = PublicKeyBytes::from_signed_packet(&signed_packet);
+        store
+            .insert(signed_packet.clone(), PacketSource::PkarrPublish)
+            .await?;
+
+        // The stored packet is served without waiting for the primary.
+        let packet = tokio::time::timeout(Duration::from_secs(1), store.get_signed_packet(&key))
+            .await??
+            .expect("packet is stored");
+        assert_eq!(packet.timestamp(), signed_packet.timestamp());
+        drop(listener);
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn store_eviction() -> TestResult<()> {
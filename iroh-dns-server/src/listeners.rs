@@ -0,0 +1,432 @@
+//! Binding of the sockets of the DNS and HTTP(S) servers.
+//!
+//! All enabled listeners are bound before any server starts.  A listener which cannot be
+//! bound does not stop the others from being tried, so the error lists every listener that
+//! failed, with the setting to change for each of them.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+use anyhow::{bail, Result};
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::{
+    config::Config,
+    dns::{udp, DnsConfig},
+    http::{HttpConfig, HttpsConfig},
+};
+
+/// The DNS port served over TLS by default.
+pub(crate) const DEFAULT_DNS_TLS_PORT: u16 = 853;
+
+/// A listener of the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Listener {
+    DnsUdp,
+    DnsTcp,
+    DnsTls,
+    Http,
+    Https,
+}
+
+impl Listener {
+    /// The setting holding the port of the listener.
+    fn port_setting(self) -> &'static str {
+        match self {
+            Self::DnsUdp | Self::DnsTcp => "dns.port",
+            Self::DnsTls => "dns.tls.port",
+            Self::Http => "http.port",
+            Self::Https => "https.port",
+        }
+    }
+
+    /// How to disable the listener in the configuration.
+    fn disable_hint(self) -> &'static str {
+        match self {
+            Self::DnsUdp => "set `dns.udp.enabled = false`",
+            Self::DnsTcp => "set `dns.tcp.enabled = false`",
+            Self::DnsTls => "set `dns.tls.enabled = false`",
+            Self::Http => "remove the `[http]` section",
+            Self::Https => "remove the `[https]` section",
+        }
+    }
+
+    fn is_udp(self) -> bool {
+        self == Self::DnsUdp
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::DnsUdp => "DNS over UDP",
+            Self::DnsTcp => "DNS over TCP",
+            Self::DnsTls => "DNS over TLS",
+            Self::Http => "HTTP",
+            Self::Https => "HTTPS",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The bound sockets of the DNS server.
+#[derive(Debug, Default)]
+pub(crate) struct DnsListeners {
+    pub(crate) udp: Vec<UdpSocket>,
+    pub(crate) tcp: Option<TcpListener>,
+    pub(crate) tls: Option<TcpListener>,
+}
+
+impl DnsListeners {
+    /// Binds the enabled listeners of the DNS server.
+    pub(crate) async fn bind(config: &DnsConfig) -> Result<Self> {
+        let mut binder = Binder::new(dns_addrs(config));
+        let listeners = binder.bind_dns(config).await;
+        binder.finish()?;
+        Ok(listeners)
+    }
+
+    /// Returns the address of the first bound listener, preferring UDP.
+    pub(crate) fn local_addr(&self) -> Result<SocketAddr> {
+        if let Some(socket) = self.udp.first() {
+            return Ok(socket.local_addr()?);
+        }
+        match self.tcp.as_ref().or(self.tls.as_ref()) {
+            Some(listener) => Ok(listener.local_addr()?),
+            None => bail!("at least one DNS listener must be enabled"),
+        }
+    }
+}
+
+/// The bound sockets of the HTTP server.
+#[derive(Debug, Default)]
+pub(crate) struct HttpListeners {
+    pub(crate) http: Option<std::net::TcpListener>,
+    pub(crate) https: Option<std::net::TcpListener>,
+}
+
+impl HttpListeners {
+    /// Binds the enabled listeners of the HTTP server.
+    pub(crate) async fn bind(
+        http: Option<&HttpConfig>,
+        https: Option<&HttpsConfig>,
+    ) -> Result<Self> {
+        let mut binder = Binder::new(http_addrs(http, https));
+        let listeners = binder.bind_http(http, https).await;
+        binder.finish()?;
+        Ok(listeners)
+    }
+}
+
+/// The bound sockets of all servers.
+#[derive(Debug)]
+pub(crate) struct Listeners {
+    pub(crate) dns: DnsListeners,
+    pub(crate) http: HttpListeners,
+}
+
+impl Listeners {
+    /// Binds the enabled listeners of all servers.
+    pub(crate) async fn bind(config: &Config) -> Result<Self> {
+        let mut addrs = dns_addrs(&config.dns);
+        addrs.extend(http_addrs(config.http.as_ref(), config.https.as_ref()));
+        let mut binder = Binder::new(addrs);
+        let dns = binder.bind_dns(&config.dns).await;
+        let http = binder
+            .bind_http(config.http.as_ref(), config.https.as_ref())
+            .await;
+        binder.finish()?;
+        Ok(Self { dns, http })
+    }
+}
+
+fn socket_addr(bind_addr: Option<IpAddr>, port: u16) -> SocketAddr {
+    SocketAddr::new(bind_addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into()), port)
+}
+
+/// Returns the addresses of the enabled DNS listeners.
+fn dns_addrs(config: &DnsConfig) -> Vec<(Listener, SocketAddr)> {
+    let addr = socket_addr(config.bind_addr, config.port);
+    let mut addrs = Vec::new();
+    if config.udp.enabled {
+        addrs.push((Listener::DnsUdp, addr));
+    }
+    if config.tcp.enabled {
+        addrs.push((Listener::DnsTcp, addr));
+    }
+    if config.tls.enabled {
+        let bind_addr = config.tls.bind_addr.or(config.bind_addr);
+        addrs.push((Listener::DnsTls, socket_addr(bind_addr, config.tls.port)));
+    }
+    addrs
+}
+
+/// Returns the addresses of the enabled HTTP listeners.
+fn http_addrs(
+    http: Option<&HttpConfig>,
+    https: Option<&HttpsConfig>,
+) -> Vec<(Listener, SocketAddr)> {
+    let http = http.map(|config| (Listener::Http, socket_addr(config.bind_addr, config.port)));
+    let https = https.map(|config| (Listener::Https, socket_addr(config.bind_addr, config.port)));
+    http.into_iter().chain(https).collect()
+}
+
+/// Whether two listeners cannot be bound at the same time.
+///
+/// Listeners conflict if they use the same transport and port on overlapping addresses.
+/// Port zero picks a free port, so it never conflicts.
+fn conflicts((a, a_addr): (Listener, SocketAddr), (b, b_addr): (Listener, SocketAddr)) -> bool {
+    let overlapping = a_addr.ip() == b_addr.ip()
+        || (a_addr.is_ipv4() == b_addr.is_ipv4()
+            && (a_addr.ip().is_unspecified() || b_addr.ip().is_unspecified()));
+    a.is_udp() == b.is_udp() && a_addr.port() != 0 && a_addr.port() == b_addr.port() && overlapping
+}
+
+/// Binds listeners, collecting the errors of all of them.
+#[derive(Debug)]
+struct Binder {
+    addrs: Vec<(Listener, SocketAddr)>,
+    errors: Vec<String>,
+}
+
+impl Binder {
+    /// Creates a binder for the listeners, checking them for conflicts among each other.
+    fn new(addrs: Vec<(Listener, SocketAddr)>) -> Self {
+        let mut errors = Vec::new();
+        for (i, &a) in addrs.iter().enumerate() {
+            for &b in &addrs[i + 1..] {
+                if conflicts(a, b) {
+                    errors.push(format!(
+                        "the {} listener on {} conflicts with the {} listener on {}: change `{}` or {}",
+                        b.0,
+                        b.1,
+                        a.0,
+                        a.1,
+                        b.0.port_setting(),
+                        b.0.disable_hint(),
+                    ));
+                }
+            }
+        }
+        Self { addrs, errors }
+    }
+
+    fn addr(&self, listener: Listener) -> Option<SocketAddr> {
+        self.addrs
+            .iter()
+            .find(|(l, _)| *l == listener)
+            .map(|(_, addr)| *addr)
+    }
+
+    /// Whether the listener is enabled and does not conflict with another listener.
+    fn should_bind(&self, listener: Listener) -> Option<SocketAddr> {
+        let addr = self.addr(listener)?;
+        let conflicting = self
+            .addrs
+            .iter()
+            .any(|&other| other.0 != listener && conflicts((listener, addr), other));
+        (!conflicting).then_some(addr)
+    }
+
+    fn record<T>(&mut self, listener: Listener, addr: SocketAddr, res: Result<T>) -> Option<T> {
+        match res {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.errors.push(describe_bind_error(listener, addr, &err));
+                None
+            }
+        }
+    }
+
+    async fn bind_tcp(&mut self, listener: Listener) -> Option<TcpListener> {
+        let addr = self.should_bind(listener)?;
+        let res = TcpListener::bind(addr).await.map_err(Into::into);
+        self.record(listener, addr, res)
+    }
+
+    async fn bind_dns(&mut self, config: &DnsConfig) -> DnsListeners {
+        let udp = match self.should_bind(Listener::DnsUdp) {
+            Some(addr) => {
+                let res = udp::bind_sockets(addr, &config.udp);
+                self.record(Listener::DnsUdp, addr, res).unwrap_or_default()
+            }
+            None => Vec::new(),
+        };
+        DnsListeners {
+            udp,
+            tcp: self.bind_tcp(Listener::DnsTcp).await,
+            tls: self.bind_tcp(Listener::DnsTls).await,
+        }
+    }
+
+    async fn bind_http(
+        &mut self,
+        http: Option<&HttpConfig>,
+        https: Option<&HttpsConfig>,
+    ) -> HttpListeners {
+        let mut listeners = HttpListeners::default();
+        if http.is_some() {
+            listeners.http = self.bind_std_tcp(Listener::Http).await;
+        }
+        if https.is_some() {
+            listeners.https = self.bind_std_tcp(Listener::Https).await;
+        }
+        listeners
+    }
+
+    async fn bind_std_tcp(&mut self, listener: Listener) -> Option<std::net::TcpListener> {
+        let addr = self.should_bind(listener)?;
+        let res = async { Ok(TcpListener::bind(addr).await?.into_std()?) }.await;
+        self.record(listener, addr, res)
+    }
+
+    /// Fails with all errors encountered while binding.
+    fn finish(self) -> Result<()> {
+        match self.errors.len() {
+            0 => Ok(()),
+            1 => bail!("{}", self.errors[0]),
+            n => bail!(
+                "failed to bind {n} listeners:\n  - {}",
+                self.errors.join("\n  - ")
+            ),
+        }
+    }
+}
+
+/// Describes why a listener could not be bound, and what to change about it.
+fn describe_bind_error(listener: Listener, addr: SocketAddr, err: &anyhow::Error) -> String {
+    let setting = listener.port_setting();
+    let hint = listener.disable_hint();
+    let io_kind = err
+        .chain()
+        .find_map(|err| err.downcast_ref::<std::io::Error>())
+        .map(|err| err.kind());
+    match io_kind {
+        Some(std::io::ErrorKind::AddrInUse) => format!(
+            "the {listener} listener cannot bind {addr}, the address is already in use by another \
+             process: stop it, change `{setting}` or {hint}"
+        ),
+        Some(std::io::ErrorKind::PermissionDenied) => format!(
+            "the {listener} listener is not permitted to bind {addr}, ports below 1024 require \
+             privileges (e.g. `CAP_NET_BIND_SERVICE` on Linux): change `{setting}` or {hint}"
+        ),
+        _ => format!("the {listener} listener failed to bind {addr}: {err:#}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn localhost(port: u16) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let any = |port| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port);
+        // UDP and TCP share the DNS port.
+        assert!(!conflicts(
+            (Listener::DnsUdp, any(53)),
+            (Listener::DnsTcp, any(53))
+        ));
+        assert!(conflicts(
+            (Listener::DnsTls, any(443)),
+            (Listener::Https, localhost(443))
+        ));
+        assert!(!conflicts(
+            (Listener::DnsTls, localhost(443)),
+            (
+                Listener::Https,
+                SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 443)
+            )
+        ));
+        assert!(!conflicts(
+            (Listener::Http, localhost(0)),
+            (Listener::Https, localhost(0))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_bind_reports_all_listeners() -> Result<()> {
+        let taken_http = TcpListener::bind(localhost(0)).await?;
+        let taken_dns = TcpListener::bind(localhost(0)).await?;
+        let mut config = Config {
+            http: Some(HttpConfig {
+                port: taken_http.local_addr()?.port(),
+                bind_addr: Some(Ipv4Addr::LOCALHOST.into()),
+            }),
+            https: None,
+            ..Config::default()
+        };
+        config.dns.bind_addr = Some(Ipv4Addr::LOCALHOST.into());
+        config.dns.port = taken_dns.local_addr()?.port();
+        config.dns.udp.enabled = false;
+
+        let err = Listeners::bind(&config).await.unwrap_err().to_string();
+        assert!(err.starts_with("failed to bind 2 listeners:"), "{err}");
+        assert!(
+            err.contains("the DNS over TCP listener cannot bind"),
+            "{err}"
+        );
+        assert!(err.contains("the HTTP listener cannot bind"), "{err}");
+
+        // Once the other process is gone all listeners are bound.
+        drop((taken_http, taken_dns));
+        let listeners = Listeners::bind(&config).await?;
+        assert!(listeners.dns.udp.is_empty());
+        assert!(listeners.dns.tcp.is_some());
+        assert!(listeners.dns.tls.is_none());
+        assert!(listeners.http.http.is_some());
+        assert!(listeners.http.https.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bind_conflicting_listeners() -> Result<()> {
+        let mut config = Config {
+            https: None,
+            ..Config::default()
+        };
+        config.dns.bind_addr = Some(Ipv4Addr::LOCALHOST.into());
+        config.dns.port = 0;
+        config.dns.tls.enabled = true;
+        config.dns.tls.port = 8080;
+        config.http.as_mut().unwrap().port = 8080;
+
+        let err = Listeners::bind(&config).await.unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "the HTTP listener on 0.0.0.0:8080 conflicts with the DNS over TLS listener on \
+             127.0.0.1:8080: change `http.port` or remove the `[http]` section"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bind_addr_in_use() -> Result<()> {
+        let taken = TcpListener::bind(localhost(0)).await?;
+        let port = taken.local_addr()?.port();
+        let mut config = Config::default().dns;
+        config.bind_addr = Some(Ipv4Addr::LOCALHOST.into());
+        config.port = port;
+        config.udp.enabled = false;
+
+        let err = DnsListeners::bind(&config).await.unwrap_err().to_string();
+        assert_eq!(
+            err,
+            format!(
+                "the DNS over TCP listener cannot bind 127.0.0.1:{port}, the address is already \
+                 in use by another process: stop it, change `dns.port` or set \
+                 `dns.tcp.enabled = false`"
+            )
+        );
+
+        config.tcp.enabled = false;
+        let err = DnsListeners::bind(&config).await?.local_addr().unwrap_err();
+        assert_eq!(err.to_string(), "at least one DNS listener must be enabled");
+        Ok(())
+    }
+}
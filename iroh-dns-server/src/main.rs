@@ -1,9 +1,10 @@
 use std::path::PathBuf;
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use clap::Parser;
 use iroh_dns_server::{
-    config::Config, metrics::init_metrics, server::run_with_config_until_ctrl_c,
+    config::Config, deploy::Deployment, metrics::init_metrics, selftest::SelfTest,
+    server::run_with_config_until_ctrl_c,
 };
 use tracing::debug;
 
@@ -12,12 +13,102 @@ struct Cli {
     /// Path to config file
     #[clap(short, long)]
     config: Option<PathBuf>,
+    /// Print the JSON Schema of the config file and exit.
+    #[clap(long)]
+    dump_config_schema: bool,
+    /// Publish a packet to the running server of the config file, resolve it and exit.
+    ///
+    /// Exits with an error if the round trip fails.
+    #[clap(long)]
+    selftest: bool,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Generate the files to deploy the server.
+    ///
+    /// Prints a systemd unit, an nginx or caddy reverse proxy config or a hardened config
+    /// file for the given hostname and ports.  The systemd unit reads the config from
+    /// `--config`.
+    Gen(GenArgs),
+}
+
+/// The arguments of the `gen` subcommand.
+#[derive(clap::Args, Debug)]
+struct GenArgs {
+    /// The artifact to print.
+    #[clap(value_enum)]
+    artifact: Artifact,
+    /// The public hostname of the server.
+    #[clap(long)]
+    hostname: String,
+    /// The port of the HTTP server.
+    #[clap(long, default_value_t = 80)]
+    http_port: u16,
+    /// The port of the HTTPS server.
+    #[clap(long, default_value_t = 443)]
+    https_port: u16,
+    /// The port of the DNS server.
+    #[clap(long, default_value_t = 53)]
+    dns_port: u16,
+    /// The port of the metrics server, which is only bound on localhost.
+    #[clap(long, default_value_t = 9117)]
+    metrics_port: u16,
+    /// Terminate TLS in a reverse proxy instead of the server.
+    #[clap(long)]
+    behind_proxy: bool,
+    /// The localhost port the server serves plain HTTP on behind a proxy.
+    #[clap(long, default_value_t = 8080)]
+    upstream_port: u16,
+    /// The path of the server binary in the systemd unit.
+    #[clap(long, default_value = "/usr/local/bin/iroh-dns-server")]
+    binary_path: PathBuf,
+}
+
+/// An artifact generated by the `gen` subcommand.
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum Artifact {
+    /// A hardened systemd service unit.
+    Systemd,
+    /// An nginx reverse proxy config.
+    Nginx,
+    /// A caddy reverse proxy config.
+    Caddy,
+    /// A hardened config file.
+    Config,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     let args = Cli::parse();
+    if args.dump_config_schema {
+        println!("{}", serde_json::to_string_pretty(&Config::json_schema())?);
+        return Ok(());
+    }
+    if let Some(Command::Gen(gen)) = args.command {
+        let mut deployment = Deployment::new(gen.hostname);
+        deployment.http_port = gen.http_port;
+        deployment.https_port = gen.https_port;
+        deployment.dns_port = gen.dns_port;
+        deployment.metrics_port = gen.metrics_port;
+        deployment.behind_proxy = gen.behind_proxy;
+        deployment.upstream_port = gen.upstream_port;
+        deployment.binary_path = gen.binary_path;
+        if let Some(config) = args.config {
+            deployment.config_path = config;
+        }
+        let artifact = match gen.artifact {
+            Artifact::Systemd => deployment.systemd_unit(),
+            Artifact::Nginx => deployment.nginx_config(),
+            Artifact::Caddy => deployment.caddy_config(),
+            Artifact::Config => deployment.config_toml()?,
+        };
+        print!("{artifact}");
+        return Ok(());
+    }
 
     let config = if let Some(path) = args.config {
         debug!("loading config from {:?}", path);
@@ -26,6 +117,12 @@ async fn main() -> Result<()> {
         debug!("using default config");
         Config::default()
     };
+    if args.selftest {
+        let report = SelfTest::from_config(&config)?.run().await;
+        println!("{report}");
+        ensure!(report.success, "self-test failed");
+        return Ok(());
+    }
 
     init_metrics();
     run_with_config_until_ctrl_c(config).await
@@ -9,12 +9,19 @@ use struct_iterable::Iterable;
 pub struct Metrics {
     pub pkarr_publish_update: Counter,
     pub pkarr_publish_noop: Counter,
+    pub pkarr_publish_rejected: Counter,
+    pub replica_fetch_update: Counter,
+    pub replica_fetch_noop: Counter,
+    pub replica_fetch_error: Counter,
     pub dns_requests: Counter,
     pub dns_requests_udp: Counter,
     pub dns_requests_https: Counter,
     pub dns_lookup_success: Counter,
     pub dns_lookup_notfound: Counter,
     pub dns_lookup_error: Counter,
+    pub dns_service_binding_derived: Counter,
+    pub dns_rrl_dropped: Counter,
+    pub dns_rrl_slipped: Counter,
     pub http_requests: Counter,
     pub http_requests_success: Counter,
     pub http_requests_error: Counter,
@@ -23,6 +30,8 @@ pub struct Metrics {
     pub store_packets_removed: Counter,
     pub store_packets_updated: Counter,
     pub store_packets_expired: Counter,
+    pub store_packets_evicted: Counter,
+    pub store_cache_warmed: Counter,
 }
 
 impl Default for Metrics {
@@ -32,12 +41,29 @@ impl Default for Metrics {
             pkarr_publish_noop: Counter::new(
                 "Number of pkarr relay puts that did not update the state",
             ),
+            pkarr_publish_rejected: Counter::new(
+                "Number of pkarr relay puts rejected by a publish validator",
+            ),
+            replica_fetch_update: Counter::new(
+                "Number of packets fetched from the primary that updated the state",
+            ),
+            replica_fetch_noop: Counter::new(
+                "Number of packets fetched from the primary that did not update the state",
+            ),
+            replica_fetch_error: Counter::new("Number of failed fetches from the primary"),
             dns_requests: Counter::new("DNS requests (total)"),
             dns_requests_udp: Counter::new("DNS requests via UDP"),
             dns_requests_https: Counter::new("DNS requests via HTTPS (DoH)"),
             dns_lookup_success: Counter::new("DNS lookup responses with at least one answer"),
             dns_lookup_notfound: Counter::new("DNS lookup responses with no answers"),
             dns_lookup_error: Counter::new("DNS lookup responses which failed"),
+            dns_service_binding_derived: Counter::new(
+                "HTTPS and SVCB records derived from the published records of a node",
+            ),
+            dns_rrl_dropped: Counter::new("DNS responses over UDP dropped by the rate limit"),
+            dns_rrl_slipped: Counter::new(
+                "Truncated DNS responses over UDP sent instead of responses over the rate limit",
+            ),
             http_requests: Counter::new("Number of HTTP requests"),
             http_requests_success: Counter::new("Number of HTTP requests with a 2xx status code"),
             http_requests_error: Counter::new("Number of HTTP requests with a non-2xx status code"),
@@ -46,6 +72,10 @@ impl Default for Metrics {
             store_packets_removed: Counter::new("Signed packets removed from the store"),
             store_packets_updated: Counter::new("Number of updates to existing packets"),
             store_packets_expired: Counter::new("Number of expired packets"),
+            store_packets_evicted: Counter::new(
+                "Packets evicted as the store reached its maximum number of packets",
+            ),
+            store_cache_warmed: Counter::new("Packets loaded into the cache on startup"),
         }
     }
 }
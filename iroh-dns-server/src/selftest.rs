@@ -0,0 +1,285 @@
+//! Publish and resolve round trip against a running server.
+//!
+//! The self-test publishes a signed packet with a random key to the pkarr relay over HTTP,
+//! then resolves its TXT record from the DNS server over UDP.  It is run with `--selftest`
+//! against the server of a config file, or through `POST /admin/selftest` of the server
+//! itself, see [`AdminConfig`].
+//!
+//! [`AdminConfig`]: crate::config::AdminConfig
+
+use std::{
+    fmt,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use hickory_server::proto::{
+    op::{Message, MessageType, Query, ResponseCode},
+    rr::{Name, RData, RecordType},
+};
+use n0_future::time::{timeout, Instant};
+use serde::Serialize;
+use tokio::net::UdpSocket;
+use url::Url;
+
+use crate::config::Config;
+
+/// The name of the TXT record published by the self-test, below the key of the packet.
+const RECORD_NAME: &str = "_selftest";
+
+/// The TTL of the published record.
+const RECORD_TTL: u32 = 30;
+
+/// How long each step may take.
+const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A publish and resolve round trip against a server.
+#[derive(Debug, Clone)]
+pub struct SelfTest {
+    pkarr_url: Url,
+    dns_addr: SocketAddr,
+    origin: Name,
+}
+
+impl SelfTest {
+    /// Creates a self-test publishing to the pkarr relay at `pkarr_url` and resolving from
+    /// the DNS server at `dns_addr`, below `origin`.
+    ///
+    /// Unspecified addresses are replaced with localhost.
+    pub fn new(pkarr_url: Url, dns_addr: SocketAddr, origin: &str) -> Result<Self> {
+        Ok(Self {
+            pkarr_url,
+            dns_addr: connect_addr(dns_addr),
+            origin: Name::from_utf8(origin)?,
+        })
+    }
+
+    /// Creates a self-test against the server of a config.
+    ///
+    /// The server needs to serve HTTP and DNS over UDP.  The packet is resolved below the
+    /// first origin.
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let Some(http) = &config.http else {
+            bail!("the self-test publishes over HTTP, which is not configured");
+        };
+        ensure!(
+            config.dns.udp.enabled,
+            "the self-test resolves over UDP, which is disabled with `dns.udp.enabled`"
+        );
+        let origin = config
+            .dns
+            .origins
+            .first()
+            .context("at least one origin is required")?;
+        let http_addr = SocketAddr::new(
+            http.bind_addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
+            http.port,
+        );
+        let dns_addr = SocketAddr::new(
+            config.dns.bind_addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
+            config.dns.port,
+        );
+        Self::new(pkarr_url(http_addr)?, dns_addr, origin)
+    }
+
+    /// Runs the round trip.
+    ///
+    /// The steps after a failed one are skipped.
+    pub async fn run(&self) -> SelfTestReport {
+        let keypair = pkarr::Keypair::random();
+        let token = format!(
+            "selftest={}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis()
+        );
+        let mut report = SelfTestReport {
+            success: false,
+            key: keypair.to_z32(),
+            steps: Vec::new(),
+        };
+        if report.step("publish", self.publish(&keypair, &token)).await {
+            report.success = report.step("resolve", self.resolve(&keypair, &token)).await;
+        }
+        report
+    }
+
+    async fn publish(&self, keypair: &pkarr::Keypair, token: &str) -> Result<()> {
+        use pkarr::dns;
+
+        let mut packet = dns::Packet::new_reply(0);
+        packet.answers.push(dns::ResourceRecord::new(
+            dns::Name::new(RECORD_NAME)?,
+            dns::CLASS::IN,
+            RECORD_TTL,
+            dns::rdata::RData::TXT(token.try_into()?),
+        ));
+        let signed_packet = pkarr::SignedPacket::from_packet(keypair, &packet)?;
+        let client = pkarr::PkarrRelayClient::new(pkarr::RelaySettings {
+            relays: vec![self.pkarr_url.to_string()],
+            ..Default::default()
+        })?;
+        client
+            .as_async()
+            .publish(&signed_packet)
+            .await
+            .with_context(|| format!("failed to publish to {}", self.pkarr_url))
+    }
+
+    async fn resolve(&self, keypair: &pkarr::Keypair, token: &str) -> Result<()> {
+        let name = Name::from_utf8(format!("{RECORD_NAME}.{}", keypair.to_z32()))?
+            .append_domain(&self.origin)?;
+        // The key is random, which makes for a random query ID.
+        let key = keypair.public_key().to_bytes();
+        let id = u16::from_be_bytes([key[0], key[1]]);
+        let mut query = Message::new();
+        query
+            .set_id(id)
+            .set_message_type(MessageType::Query)
+            .add_query(Query::query(name.clone(), RecordType::TXT));
+        let bind_addr: IpAddr = match self.dns_addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind((bind_addr, 0)).await?;
+        socket.send_to(&query.to_vec()?, self.dns_addr).await?;
+        let mut buf = vec![0u8; u16::MAX as usize];
+        let response = loop {
+            let (len, from) = socket.recv_from(&mut buf).await?;
+            if from != self.dns_addr {
+                continue;
+            }
+            let response = Message::from_vec(&buf[..len])?;
+            if response.id() == id {
+                break response;
+            }
+        };
+        ensure!(
+            response.response_code() == ResponseCode::NoError,
+            "resolving {name} from {} failed with {}, the server logs the reason of the query",
+            self.dns_addr,
+            response.response_code()
+        );
+        let found = response.answers().iter().any(|record| match record.data() {
+            RData::TXT(txt) => txt
+                .txt_data()
+                .iter()
+                .any(|data| **data == *token.as_bytes()),
+            _ => false,
+        });
+        ensure!(
+            found,
+            "resolving {name} from {} returned {} answers without the published record",
+            self.dns_addr,
+            response.answer_count()
+        );
+        Ok(())
+    }
+}
+
+/// The outcome of a [`SelfTest`].
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestReport {
+    /// Whether all steps succeeded.
+    pub success: bool,
+    /// The z-base-32 encoded key of the published packet.
+    pub key: String,
+    /// The steps which were run.
+    pub steps: Vec<SelfTestStep>,
+}
+
+impl SelfTestReport {
+    async fn step(&mut self, name: &'static str, fut: impl Future<Output = Result<()>>) -> bool {
+        let start = Instant::now();
+        let res = match timeout(STEP_TIMEOUT, fut).await {
+            Ok(res) => res,
+            Err(_) => Err(anyhow::anyhow!("timed out after {STEP_TIMEOUT:?}")),
+        };
+        let step = SelfTestStep {
+            name,
+            duration: start.elapsed(),
+            error: res.err().map(|err| format!("{err:#}")),
+        };
+        let success = step.error.is_none();
+        self.steps.push(step);
+        success
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "self-test with key {}", self.key)?;
+        for step in &self.steps {
+            match &step.error {
+                None => writeln!(f, "  {}: ok in {:?}", step.name, step.duration)?,
+                Some(err) => writeln!(
+                    f,
+                    "  {}: failed after {:?}: {err}",
+                    step.name, step.duration
+                )?,
+            }
+        }
+        match self.success {
+            true => write!(f, "passed"),
+            false => write!(f, "failed"),
+        }
+    }
+}
+
+/// A step of a [`SelfTest`].
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestStep {
+    /// The name of the step, `publish` or `resolve`.
+    pub name: &'static str,
+    /// How long the step took.
+    #[serde(with = "humantime_serde")]
+    pub duration: Duration,
+    /// Why the step failed, if it did.
+    pub error: Option<String>,
+}
+
+/// Returns the pkarr relay URL of an HTTP server.
+pub(crate) fn pkarr_url(http_addr: SocketAddr) -> Result<Url> {
+    Ok(format!("http://{}/pkarr", connect_addr(http_addr)).parse()?)
+}
+
+/// Replaces an unspecified address with localhost.
+fn connect_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+        _ => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+
+    #[tokio::test]
+    async fn test_selftest() -> Result<()> {
+        let (server, dns_addr, http_url) = Server::spawn_for_tests().await?;
+        let pkarr_url = http_url.join("/pkarr")?;
+        let report = SelfTest::new(pkarr_url.clone(), dns_addr, "irohdns.example.")?
+            .run()
+            .await;
+        assert!(report.success, "{report}");
+        assert_eq!(report.steps.len(), 2);
+
+        // The packet is published, but not served below an unknown name.
+        let report = SelfTest::new(pkarr_url, dns_addr, "other.example.")?
+            .run()
+            .await;
+        assert!(!report.success);
+        assert_eq!(report.steps.len(), 2);
+        let err = report.steps[1].error.as_deref().unwrap();
+        assert!(err.contains("Non-Existent Domain"), "{err}");
+
+        server.shutdown().await?;
+        Ok(())
+    }
+}
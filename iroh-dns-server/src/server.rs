@@ -1,13 +1,15 @@
 //! The main server which combines the DNS and HTTP(S) servers.
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use iroh_metrics::metrics::start_metrics_server;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{
     config::Config,
     dns::{DnsHandler, DnsServer},
-    http::HttpServer,
+    http::{tls_acceptor, HttpServer, TlsAcceptor},
+    listeners::Listeners,
+    selftest::{self, SelfTest},
     state::AppState,
     store::ZoneStore,
 };
@@ -35,6 +37,7 @@ pub struct Server {
     http_server: HttpServer,
     dns_server: DnsServer,
     metrics_task: tokio::task::JoinHandle<anyhow::Result<()>>,
+    cache_warming_task: Option<tokio::task::JoinHandle<()>>,
 }
 
 impl Server {
@@ -44,10 +47,36 @@ impl Server {
     /// * A DNS server task
     /// * A HTTP server task, if `config.http` is not empty
     /// * A HTTPS server task, if `config.https` is not empty
-    pub async fn spawn(config: Config, store: ZoneStore) -> Result<Self> {
+    /// * A cache warming task, if `config.cache_warming` is not empty
+    ///
+    /// All listeners are bound before any task is spawned.  If some cannot be bound, the
+    /// error describes each of them.
+    pub async fn spawn(config: Config, mut store: ZoneStore) -> Result<Self> {
+        if config.http.is_none() && config.https.is_none() {
+            bail!("Either http or https config is required");
+        }
+        if config.dns.tls.enabled && config.https.is_none() {
+            bail!(
+                "DNS over TLS uses the certificates of the HTTPS server, which is not configured"
+            );
+        }
+        if let Some(policy) = config.publish_policy.clone() {
+            store = store.with_publish_validator(policy);
+        }
+        if let Some(replica) = config.replica.clone() {
+            info!(primary = %replica.primary_url, "running as read-only replica");
+            store = store.with_primary(replica.primary_url, replica.refresh_interval);
+        }
         let dns_handler = DnsHandler::new(store.clone(), &config.dns)?;
-
-        let state = AppState { store, dns_handler };
+        let cache_warming_task = config.cache_warming.clone().map(|warming| {
+            let store = store.clone();
+            tokio::task::spawn(async move {
+                match store.warm_cache(warming.records, warming.rate).await {
+                    Ok(warmed) => info!(warmed, "warmed cache from the zone store"),
+                    Err(err) => warn!("failed to warm cache: {err:#}"),
+                }
+            })
+        });
 
         let metrics_addr = config.metrics_addr();
         let metrics_task = tokio::task::spawn(async move {
@@ -56,24 +85,62 @@ impl Server {
             }
             Ok(())
         });
-        let http_server = HttpServer::spawn(
-            config.http,
-            config.https,
+        let listeners = Listeners::bind(&config).await?;
+        let selftest = match (&listeners.http.http, listeners.dns.udp.first()) {
+            (Some(http), Some(udp)) => {
+                let origin = config.dns.origins.first().context("no origin")?;
+                let pkarr_url = selftest::pkarr_url(http.local_addr()?)?;
+                Some(SelfTest::new(pkarr_url, udp.local_addr()?, origin)?)
+            }
+            _ => None,
+        };
+        let state = AppState {
+            store,
+            dns_handler,
+            admin: config.admin.clone(),
+            selftest,
+        };
+        let acceptor = match config.https {
+            Some(https) => Some(tls_acceptor(https).await?),
+            None => None,
+        };
+        let tls_config = acceptor.as_ref().map(TlsAcceptor::server_config);
+        let http_server = HttpServer::spawn_with_listeners(
+            listeners.http,
+            acceptor,
             config.pkarr_put_rate_limit,
             state.clone(),
-        )
-        .await?;
-        let dns_server = DnsServer::spawn(config.dns, state.dns_handler.clone()).await?;
+        )?;
+        let dns_server = DnsServer::spawn_with_listeners(
+            &config.dns,
+            listeners.dns,
+            tls_config,
+            state.dns_handler.clone(),
+        )?;
         Ok(Self {
             http_server,
             dns_server,
             metrics_task,
+            cache_warming_task,
         })
     }
 
+    /// Returns the address the DNS server is bound to.
+    pub fn dns_addr(&self) -> std::net::SocketAddr {
+        self.dns_server.local_addr()
+    }
+
+    /// Returns the address the HTTP server is bound to, if it is running.
+    pub fn http_addr(&self) -> Option<std::net::SocketAddr> {
+        self.http_server.http_addr()
+    }
+
     /// Cancel the server tasks and wait for all tasks to complete.
     pub async fn shutdown(self) -> Result<()> {
         self.metrics_task.abort();
+        if let Some(task) = &self.cache_warming_task {
+            task.abort();
+        }
         let (res1, res2) = tokio::join!(self.dns_server.shutdown(), self.http_server.shutdown(),);
         res1?;
         res2?;
@@ -89,6 +156,9 @@ impl Server {
             res = self.http_server.run_until_done() => res?,
         }
         self.metrics_task.abort();
+        if let Some(task) = &self.cache_warming_task {
+            task.abort();
+        }
         Ok(())
     }
 
@@ -109,6 +179,19 @@ impl Server {
     pub async fn spawn_for_tests_with_options(
         mainline: Option<crate::config::BootstrapOption>,
         options: Option<crate::store::ZoneStoreOptions>,
+    ) -> Result<(Self, std::net::SocketAddr, url::Url)> {
+        let mut store = ZoneStore::in_memory(options.unwrap_or_default())?;
+        if let Some(bootstrap) = mainline {
+            info!("mainline fallback enabled");
+            store = store.with_mainline_fallback(bootstrap);
+        }
+        Self::spawn_for_tests_with_store(store).await
+    }
+
+    /// Spawn a server suitable for testing, using the given store.
+    #[cfg(test)]
+    pub async fn spawn_for_tests_with_store(
+        store: ZoneStore,
     ) -> Result<(Self, std::net::SocketAddr, url::Url)> {
         use std::net::{IpAddr, Ipv4Addr};
 
@@ -122,14 +205,9 @@ impl Server {
         config.https = None;
         config.metrics = Some(MetricsConfig::disabled());
 
-        let mut store = ZoneStore::in_memory(options.unwrap_or_default())?;
-        if let Some(bootstrap) = mainline {
-            info!("mainline fallback enabled");
-            store = store.with_mainline_fallback(bootstrap);
-        }
         let server = Self::spawn(config, store).await?;
-        let dns_addr = server.dns_server.local_addr();
-        let http_addr = server.http_server.http_addr().expect("http is set");
+        let dns_addr = server.dns_addr();
+        let http_addr = server.http_addr().expect("http is set");
         let http_url = format!("http://{http_addr}").parse()?;
         Ok((server, dns_addr, http_url))
     }
@@ -1,6 +1,6 @@
 //! Shared state and store for the iroh-dns-server
 
-use crate::{dns::DnsHandler, store::ZoneStore};
+use crate::{config::AdminConfig, dns::DnsHandler, selftest::SelfTest, store::ZoneStore};
 
 /// The shared app state.
 #[derive(Clone)]
@@ -9,4 +9,8 @@ pub struct AppState {
     pub store: ZoneStore,
     /// Handler for DNS requests
     pub dns_handler: DnsHandler,
+    /// The config of the admin API, which is served if set
+    pub admin: Option<AdminConfig>,
+    /// The self-test of the admin API, if the server serves HTTP and DNS over UDP
+    pub selftest: Option<SelfTest>,
 }
@@ -1,35 +1,59 @@
 //! Pkarr packet store used to resolve DNS queries.
 
-use std::{collections::BTreeMap, num::NonZeroUsize, path::Path, sync::Arc, time::Duration};
+use std::{
+    collections::BTreeMap,
+    num::{NonZeroU32, NonZeroUsize},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
-use hickory_server::proto::rr::{Name, RecordSet, RecordType, RrKey};
-use iroh_metrics::inc;
+use hickory_server::proto::rr::{LowerName, Name, RecordSet, RecordType, RrKey};
+use iroh_metrics::{inc, inc_by};
 use lru::LruCache;
-use pkarr::{mainline::dht::DhtSettings, PkarrClient, SignedPacket};
+use pkarr::{
+    mainline::dht::DhtSettings, PkarrClient, PkarrRelayClient, RelaySettings, SignedPacket,
+};
 use tokio::sync::Mutex;
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 use ttl_cache::TtlCache;
+use url::Url;
 
-use self::signed_packets::SignedPacketStore;
+use self::signed_packets::{SignedPacketStore, Upsert};
 use crate::{
     config::BootstrapOption,
     metrics::Metrics,
     util::{signed_packet_to_hickory_records_without_origin, PublicKeyBytes},
+    validation::{PublishRejection, PublishValidator},
 };
 
+mod service_binding;
 mod signed_packets;
-pub use signed_packets::Options as ZoneStoreOptions;
+pub use signed_packets::{EvictionCallback, Options as ZoneStoreOptions};
 
 /// Cache up to 1 million pkarr zones by default
 pub const DEFAULT_CACHE_CAPACITY: usize = 1024 * 1024;
 /// Default TTL for DHT cache entries
 pub const DHT_CACHE_TTL: Duration = Duration::from_secs(300);
+/// Timeout for fetching a packet from the primary server of a read-only replica
+pub const REPLICA_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Where a new pkarr packet comes from
 pub enum PacketSource {
     /// Received via HTTPS relay PUT
     PkarrPublish,
+    /// Fetched from the primary server of a read-only replica
+    Replication,
+}
+
+/// The result of resolving a name in a pkarr zone.
+#[derive(Debug, Clone)]
+pub enum Resolved {
+    /// The records of the queried type.
+    Records(Arc<RecordSet>),
+    /// The name exists in the zone, but has no records of the queried type.
+    NoData,
 }
 
 /// A store for pkarr signed packets.
@@ -41,6 +65,8 @@ pub struct ZoneStore {
     cache: Arc<Mutex<ZoneCache>>,
     store: Arc<SignedPacketStore>,
     pkarr: Option<Arc<PkarrClient>>,
+    primary: Option<Arc<Primary>>,
+    validators: Vec<Arc<dyn PublishValidator>>,
 }
 
 impl ZoneStore {
@@ -79,6 +105,48 @@ impl ZoneStore {
         }
     }
 
+    /// Make this the store of a read-only replica of a primary server.
+    ///
+    /// `primary_url` is the pkarr relay URL of the primary, e.g. `https://dns.example/pkarr`.
+    /// Packets are fetched from the primary when they are resolved, and fetched again once
+    /// `refresh_interval` has passed.  Stored packets are served right away and refreshed in
+    /// the background, only unknown packets wait for the primary, for at most
+    /// [`REPLICA_FETCH_TIMEOUT`].  If the primary cannot be reached the stored packet is
+    /// served.
+    pub fn with_primary(self, primary_url: Url, refresh_interval: Duration) -> Self {
+        let client = PkarrRelayClient::new(RelaySettings {
+            relays: vec![primary_url.as_str().trim_end_matches('/').to_string()],
+            cache_size: NonZeroUsize::MIN,
+            // Always ask the primary, we keep track of refreshes ourselves.
+            minimum_ttl: 0,
+            maximum_ttl: 0,
+            ..Default::default()
+        })
+        .expect("one relay is set");
+        Self {
+            primary: Some(Arc::new(Primary {
+                url: primary_url,
+                client,
+                refresh_interval,
+                refreshed: Mutex::new(TtlCache::new(DEFAULT_CACHE_CAPACITY)),
+            })),
+            ..self
+        }
+    }
+
+    /// The pkarr relay URL of the primary server, if this is the store of a read-only replica.
+    pub fn primary_url(&self) -> Option<&Url> {
+        self.primary.as_ref().map(|primary| &primary.url)
+    }
+
+    /// Add a validator for published packets.
+    ///
+    /// Packets are only stored if they pass all validators, see [`Self::validate`].
+    pub fn with_publish_validator(mut self, validator: impl PublishValidator) -> Self {
+        self.validators.push(Arc::new(validator));
+        self
+    }
+
     /// Create a new zone store.
     pub fn new(store: SignedPacketStore) -> Self {
         let zone_cache = ZoneCache::new(DEFAULT_CACHE_CAPACITY);
@@ -86,18 +154,49 @@ impl ZoneStore {
             store: Arc::new(store),
             cache: Arc::new(Mutex::new(zone_cache)),
             pkarr: None,
+            primary: None,
+            validators: Vec::new(),
+        }
+    }
+
+    /// Validate a published signed packet with all validators of the store.
+    ///
+    /// Returns the rejection of the first validator rejecting the packet.
+    pub fn validate(&self, signed_packet: &SignedPacket) -> Result<(), PublishRejection> {
+        let res = self
+            .validators
+            .iter()
+            .try_for_each(|validator| validator.validate(signed_packet));
+        if let Err(rejection) = &res {
+            debug!(%rejection, "publish rejected");
+            inc!(Metrics, pkarr_publish_rejected);
         }
+        res
     }
 
     /// Resolve a DNS query.
-    #[allow(clippy::unused_async)]
+    ///
+    /// The time spent is recorded as `store_time` on the current span.
     pub async fn resolve(
         &self,
         pubkey: &PublicKeyBytes,
         name: &Name,
         record_type: RecordType,
-    ) -> Result<Option<Arc<RecordSet>>> {
+    ) -> Result<Option<Resolved>> {
+        let start = Instant::now();
+        let res = self.resolve_inner(pubkey, name, record_type).await;
+        tracing::Span::current().record("store_time", tracing::field::debug(start.elapsed()));
+        res
+    }
+
+    async fn resolve_inner(
+        &self,
+        pubkey: &PublicKeyBytes,
+        name: &Name,
+        record_type: RecordType,
+    ) -> Result<Option<Resolved>> {
         tracing::info!("{} {}", name, record_type);
+        self.refresh_from_primary(pubkey).await;
         if let Some(rset) = self.cache.lock().await.resolve(pubkey, name, record_type) {
             return Ok(Some(rset));
         }
@@ -135,28 +234,150 @@ impl ZoneStore {
     // allow unused async: this will be async soon.
     #[allow(clippy::unused_async)]
     pub async fn get_signed_packet(&self, pubkey: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+        self.refresh_from_primary(pubkey).await;
         self.store.get(pubkey).await
     }
 
+    /// Fetch the packet for a pubkey from the primary server, if it is due for a refresh.
+    async fn refresh_from_primary(&self, pubkey: &PublicKeyBytes) {
+        let Some(primary) = self.primary.as_ref() else {
+            return;
+        };
+        {
+            let mut refreshed = primary.refreshed.lock().await;
+            if refreshed.contains_key(pubkey) {
+                return;
+            }
+            refreshed.insert(*pubkey, (), primary.refresh_interval);
+        }
+        // The packet is only stored if no other update was stored while fetching it.
+        let expected = match self.store.get(pubkey).await {
+            Ok(packet) => packet.map(|packet| packet.timestamp()),
+            Err(err) => {
+                warn!("failed to read packet before fetching from primary: {err:#}");
+                return;
+            }
+        };
+        if expected.is_some() {
+            // Serve the stored packet, later queries see the update.
+            let this = self.clone();
+            let primary = primary.clone();
+            let pubkey = *pubkey;
+            tokio::spawn(async move { this.fetch_from_primary(&primary, &pubkey, expected).await });
+        } else {
+            self.fetch_from_primary(primary, pubkey, expected).await;
+        }
+    }
+
+    /// Fetch the packet for a pubkey from the primary server and store it.
+    async fn fetch_from_primary(
+        &self,
+        primary: &Primary,
+        pubkey: &PublicKeyBytes,
+        expected: Option<u64>,
+    ) {
+        let key = pkarr::PublicKey::try_from(pubkey.as_bytes()).expect("valid public key");
+        debug!("replica fetch {}", key.to_z32());
+        let client = primary.client.clone().as_async();
+        let res = match tokio::time::timeout(REPLICA_FETCH_TIMEOUT, client.resolve(&key)).await {
+            Ok(res) => res.map_err(anyhow::Error::from),
+            Err(elapsed) => Err(elapsed.into()),
+        };
+        match res {
+            Ok(Some(packet)) => {
+                let res = self.store.compare_and_swap(packet, expected).await;
+                if let Err(err) = self
+                    .record_insert(pubkey, PacketSource::Replication, res)
+                    .await
+                {
+                    warn!("failed to store packet from primary: {err:#}");
+                }
+            }
+            Ok(None) => debug!("replica fetch: not found on primary"),
+            Err(err) => {
+                inc!(Metrics, replica_fetch_error);
+                warn!("failed to fetch packet from primary: {err:#}");
+            }
+        }
+    }
+
+    /// Load the `records` most recently published packets from the store into the cache.
+    ///
+    /// The packets are inserted in small batches, at most `rate` per second, so the warming
+    /// does not hold the cache lock for long while queries are already being served.
+    /// Packets which are cached already are skipped.  Returns the number of packets loaded.
+    pub async fn warm_cache(&self, records: usize, rate: NonZeroU32) -> Result<usize> {
+        let packets = self
+            .store
+            .most_recent(records.min(DEFAULT_CACHE_CAPACITY))
+            .await?;
+        // Ten batches per second, unless the rate is lower than that.
+        let batch = rate.get().div_ceil(10) as usize;
+        let mut interval =
+            tokio::time::interval(Duration::from_secs_f64(batch as f64 / rate.get() as f64));
+        let mut warmed = 0;
+        // The oldest packets go first, so the most recent ones are the last to be evicted.
+        for chunk in packets.rchunks(batch) {
+            interval.tick().await;
+            let mut cache = self.cache.lock().await;
+            for packet in chunk.iter().rev() {
+                match cache.warm(packet) {
+                    Ok(true) => warmed += 1,
+                    Ok(false) => {}
+                    Err(err) => debug!("failed to warm cache with packet: {err:#}"),
+                }
+            }
+        }
+        inc_by!(Metrics, store_cache_warmed, warmed as u64);
+        Ok(warmed)
+    }
+
     /// Insert a signed packet into the cache and the store.
     ///
     /// Returns whether this produced an update, i.e. whether the packet is the newest for its
     /// pubkey.
     // allow unused async: this will be async soon.
     #[allow(clippy::unused_async)]
-    pub async fn insert(&self, signed_packet: SignedPacket, _source: PacketSource) -> Result<bool> {
+    pub async fn insert(&self, signed_packet: SignedPacket, source: PacketSource) -> Result<bool> {
         let pubkey = PublicKeyBytes::from_signed_packet(&signed_packet);
-        if self.store.upsert(signed_packet).await? {
-            inc!(Metrics, pkarr_publish_update);
-            self.cache.lock().await.remove(&pubkey);
-            Ok(true)
-        } else {
-            inc!(Metrics, pkarr_publish_noop);
-            Ok(false)
+        let res = self.store.upsert_if_newer(signed_packet).await;
+        self.record_insert(&pubkey, source, res).await
+    }
+
+    /// Counts the outcome of an upsert, and drops the cached zone if it was updated.
+    async fn record_insert(
+        &self,
+        pubkey: &PublicKeyBytes,
+        source: PacketSource,
+        res: Result<Upsert>,
+    ) -> Result<bool> {
+        let updated = res?.stored;
+        match (source, updated) {
+            (PacketSource::PkarrPublish, true) => inc!(Metrics, pkarr_publish_update),
+            (PacketSource::PkarrPublish, false) => inc!(Metrics, pkarr_publish_noop),
+            (PacketSource::Replication, true) => inc!(Metrics, replica_fetch_update),
+            (PacketSource::Replication, false) => inc!(Metrics, replica_fetch_noop),
+        }
+        if updated {
+            self.cache.lock().await.remove(pubkey);
         }
+        Ok(updated)
     }
 }
 
+/// The primary server of a read-only replica.
+#[derive(derive_more::Debug)]
+struct Primary {
+    /// The pkarr relay URL of the primary.
+    url: Url,
+    client: PkarrRelayClient,
+    /// How long to serve a fetched packet before fetching it again.
+    refresh_interval: Duration,
+    /// Pubkeys fetched within the last `refresh_interval`.
+    #[debug("refreshed")]
+    refreshed: Mutex<TtlCache<PublicKeyBytes, ()>>,
+}
+
 #[derive(derive_more::Debug)]
 struct ZoneCache {
     /// Cache for explicitly added entries
@@ -179,7 +400,7 @@ impl ZoneCache {
         pubkey: &PublicKeyBytes,
         name: &Name,
         record_type: RecordType,
-    ) -> Option<Arc<RecordSet>> {
+    ) -> Option<Resolved> {
         let zone = if let Some(zone) = self.cache.get(pubkey) {
             trace!("cache hit {}", pubkey.to_z32());
             zone
@@ -197,7 +418,7 @@ impl ZoneCache {
         signed_packet: &SignedPacket,
         name: &Name,
         record_type: RecordType,
-    ) -> Result<Option<Arc<RecordSet>>> {
+    ) -> Result<Option<Resolved>> {
         let pubkey = PublicKeyBytes::from_signed_packet(signed_packet);
         self.insert(signed_packet)?;
         Ok(self.resolve(&pubkey, name, record_type))
@@ -208,7 +429,7 @@ impl ZoneCache {
         signed_packet: &SignedPacket,
         name: &Name,
         record_type: RecordType,
-    ) -> Result<Option<Arc<RecordSet>>> {
+    ) -> Result<Option<Resolved>> {
         let pubkey = PublicKeyBytes::from_signed_packet(signed_packet);
         let zone = CachedZone::from_signed_packet(signed_packet)?;
         let res = zone.resolve(name, record_type);
@@ -231,6 +452,17 @@ impl ZoneCache {
         Ok(())
     }
 
+    /// Inserts the packet unless there is a cached zone for its key already.
+    fn warm(&mut self, signed_packet: &SignedPacket) -> Result<bool> {
+        let pubkey = PublicKeyBytes::from_signed_packet(signed_packet);
+        if self.cache.contains(&pubkey) {
+            return Ok(false);
+        }
+        self.cache
+            .put(pubkey, CachedZone::from_signed_packet(signed_packet)?);
+        Ok(true)
+    }
+
     fn remove(&mut self, pubkey: &PublicKeyBytes) {
         self.cache.pop(pubkey);
         self.dht_cache.remove(pubkey);
@@ -257,11 +489,158 @@ impl CachedZone {
         self.timestamp > signed_packet.timestamp()
     }
 
-    fn resolve(&self, name: &Name, record_type: RecordType) -> Option<Arc<RecordSet>> {
+    /// Resolves the records of a type at a name of the zone.
+    ///
+    /// HTTPS and SVCB records are derived from the other records at the name, unless they
+    /// were published.  Returns `None` if the name does not exist in the zone.
+    fn resolve(&self, name: &Name, record_type: RecordType) -> Option<Resolved> {
         let key = RrKey::new(name.into(), record_type);
         for record in self.records.keys() {
             tracing::info!("record {:?}", record);
         }
-        self.records.get(&key).cloned()
+        if let Some(records) = self.records.get(&key) {
+            return Some(Resolved::Records(records.clone()));
+        }
+        if matches!(record_type, RecordType::HTTPS | RecordType::SVCB) {
+            if let Some(records) = service_binding::derive(&self.records, name, record_type) {
+                inc!(Metrics, dns_service_binding_derived);
+                return Some(Resolved::Records(Arc::new(records)));
+            }
+        }
+        // Names with records below them exist as well.
+        let name = LowerName::from(name);
+        self.records
+            .keys()
+            .any(|key| name.zone_of(&key.name))
+            .then_some(Resolved::NoData)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pkarr::{dns, Keypair};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_warm_cache() -> Result<()> {
+        let store = ZoneStore::in_memory(ZoneStoreOptions {
+            max_batch_time: Duration::from_millis(10),
+            ..Default::default()
+        })?;
+        let mut keys = Vec::new();
+        for _ in 0..3 {
+            let mut packet = dns::Packet::new_reply(0);
+            packet.answers.push(dns::ResourceRecord::new(
+                dns::Name::new("_iroh").unwrap(),
+                dns::CLASS::IN,
+                30,
+                dns::rdata::RData::TXT("hello".try_into()?),
+            ));
+            let packet = SignedPacket::from_packet(&Keypair::random(), &packet)?;
+            keys.push(PublicKeyBytes::from_signed_packet(&packet));
+            store.store.upsert_if_newer(packet).await?;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // Wait for the write transaction to be committed.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // One packet every 100ms.
+        let start = Instant::now();
+        let rate = NonZeroU32::new(10).unwrap();
+        assert_eq!(store.warm_cache(2, rate).await?, 2);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        {
+            let cache = store.cache.lock().await;
+            assert!(!cache.cache.contains(&keys[0]));
+            assert!(cache.cache.contains(&keys[1]));
+            assert!(cache.cache.contains(&keys[2]));
+        }
+
+        // Cached packets are skipped.
+        assert_eq!(store.warm_cache(3, rate).await?, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_service_binding() -> Result<()> {
+        use hickory_server::proto::rr::{
+            rdata::svcb::{Alpn, SvcParamKey, SvcParamValue},
+            RData,
+        };
+
+        let store = ZoneStore::in_memory(Default::default())?;
+        let mut packet = dns::Packet::new_reply(0);
+        let mut push = |name: &'static str, ttl, rdata| {
+            packet.answers.push(dns::ResourceRecord::new(
+                dns::Name::new(name).unwrap(),
+                dns::CLASS::IN,
+                ttl,
+                rdata,
+            ))
+        };
+        push(
+            "",
+            60,
+            dns::rdata::RData::A("192.0.2.1".parse::<std::net::Ipv4Addr>()?.into()),
+        );
+        push("", 30, dns::rdata::RData::TXT("alpn=h3,h2".try_into()?));
+        push("", 30, dns::rdata::RData::TXT("port=8443".try_into()?));
+        push(
+            "_iroh",
+            30,
+            dns::rdata::RData::TXT("relay=https://relay.example".try_into()?),
+        );
+        push("www.sub", 30, dns::rdata::RData::TXT("hello".try_into()?));
+        let packet = SignedPacket::from_packet(&Keypair::random(), &packet)?;
+        let pubkey = PublicKeyBytes::from_signed_packet(&packet);
+        store.store.upsert_if_newer(packet).await?;
+
+        let resolve = |name: &str, record_type| {
+            let store = store.clone();
+            let name = Name::from_utf8(name).unwrap();
+            async move { store.resolve(&pubkey, &name, record_type).await.unwrap() }
+        };
+        let Some(Resolved::Records(records)) = resolve("", RecordType::HTTPS).await else {
+            panic!("no HTTPS record derived");
+        };
+        let record = records.records_without_rrsigs().next().unwrap();
+        assert_eq!(record.ttl(), 30);
+        let RData::HTTPS(https) = record.data() else {
+            panic!("not an HTTPS record: {record:?}");
+        };
+        assert_eq!(https.svc_priority(), 1);
+        let params: Vec<_> = https.svc_params().iter().map(|(key, _)| *key).collect();
+        assert_eq!(
+            params,
+            [SvcParamKey::Alpn, SvcParamKey::Port, SvcParamKey::Ipv4Hint]
+        );
+        assert_eq!(https.svc_params()[1].1, SvcParamValue::Port(8443));
+        assert_eq!(
+            https.svc_params()[0].1,
+            SvcParamValue::Alpn(Alpn(vec!["h3".into(), "h2".into()]))
+        );
+        assert!(matches!(
+            resolve("", RecordType::SVCB).await,
+            Some(Resolved::Records(records)) if records.record_type() == RecordType::SVCB
+        ));
+
+        // Names without anything to derive the record from, or without any records of the
+        // queried type, have no data.  Names which do not exist are not found.
+        assert!(matches!(
+            resolve("_iroh", RecordType::HTTPS).await,
+            Some(Resolved::NoData)
+        ));
+        assert!(matches!(
+            resolve("", RecordType::AAAA).await,
+            Some(Resolved::NoData)
+        ));
+        assert!(matches!(
+            resolve("sub", RecordType::HTTPS).await,
+            Some(Resolved::NoData)
+        ));
+        assert!(resolve("other", RecordType::HTTPS).await.is_none());
+        Ok(())
     }
 }
@@ -0,0 +1,98 @@
+//! HTTPS and SVCB records derived from the records published in a pkarr zone.
+//!
+//! Browsers query the HTTPS record of a name alongside its addresses and hold off
+//! connecting until it is answered.  Unless a node published HTTPS or SVCB records itself,
+//! a ServiceMode record is derived from the records it published at the queried name: the
+//! `ipv4hint` and `ipv6hint` from its A and AAAA records, and the `alpn` and `port` from
+//! `alpn=` and `port=` attributes in its TXT records, e.g. `alpn=h3,h2`.  Names without
+//! any of these have no HTTPS record and are answered with NODATA.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use hickory_server::proto::rr::{
+    rdata::{
+        svcb::{Alpn, IpHint, SvcParamKey, SvcParamValue, SVCB},
+        HTTPS,
+    },
+    Name, RData, Record, RecordSet, RecordType, RrKey,
+};
+
+/// The priority of the derived records, any non-zero priority makes it a ServiceMode record.
+const PRIORITY: u16 = 1;
+
+/// Derives an HTTPS or SVCB record for `name` from the `records` published at it.
+///
+/// Returns `None` if nothing is published to derive the record from.  The TTL of the
+/// record is the lowest TTL of the records it is derived from.
+pub(super) fn derive(
+    records: &BTreeMap<RrKey, Arc<RecordSet>>,
+    name: &Name,
+    record_type: RecordType,
+) -> Option<RecordSet> {
+    let mut alpn: Vec<String> = Vec::new();
+    let mut port = None;
+    let mut ipv4 = Vec::new();
+    let mut ipv6 = Vec::new();
+    let mut ttl: Option<u32> = None;
+    let published = [RecordType::A, RecordType::AAAA, RecordType::TXT]
+        .into_iter()
+        .filter_map(|record_type| records.get(&RrKey::new(name.into(), record_type)));
+    for set in published {
+        for record in set.records_without_rrsigs() {
+            match record.data() {
+                RData::A(addr) => ipv4.push(*addr),
+                RData::AAAA(addr) => ipv6.push(*addr),
+                RData::TXT(txt) => {
+                    let mut hints = false;
+                    for attr in txt.iter().filter_map(|data| std::str::from_utf8(data).ok()) {
+                        match attr.split_once('=') {
+                            Some(("alpn", ids)) => {
+                                for id in ids.split(',').filter(|id| !id.is_empty()) {
+                                    if !alpn.iter().any(|known| known == id) {
+                                        alpn.push(id.to_string());
+                                    }
+                                }
+                                hints = true;
+                            }
+                            Some(("port", value)) => {
+                                if let Ok(value) = value.parse() {
+                                    port = Some(value);
+                                    hints = true;
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                    if !hints {
+                        continue;
+                    }
+                }
+                _ => continue,
+            }
+            ttl = Some(ttl.map_or(record.ttl(), |ttl| ttl.min(record.ttl())));
+        }
+    }
+    let ttl = ttl?;
+
+    // The parameters must be in the order of their keys.
+    let mut params = Vec::new();
+    if !alpn.is_empty() {
+        params.push((SvcParamKey::Alpn, SvcParamValue::Alpn(Alpn(alpn))));
+    }
+    if let Some(port) = port {
+        params.push((SvcParamKey::Port, SvcParamValue::Port(port)));
+    }
+    if !ipv4.is_empty() {
+        params.push((SvcParamKey::Ipv4Hint, SvcParamValue::Ipv4Hint(IpHint(ipv4))));
+    }
+    if !ipv6.is_empty() {
+        params.push((SvcParamKey::Ipv6Hint, SvcParamValue::Ipv6Hint(IpHint(ipv6))));
+    }
+    // The target `.` is the owner name itself.
+    let svcb = SVCB::new(PRIORITY, Name::root(), params);
+    let rdata = match record_type {
+        RecordType::HTTPS => RData::HTTPS(HTTPS(svcb)),
+        _ => RData::SVCB(svcb),
+    };
+    Some(Record::from_rdata(name.clone(), ttl, rdata).into())
+}
@@ -1,11 +1,13 @@
-use std::{future::Future, path::Path, result, time::Duration};
+use std::{future::Future, path::Path, result, sync::Arc, time::Duration};
 
 use anyhow::{Context, Result};
 use bytes::Bytes;
 use iroh_metrics::inc;
+use lru::LruCache;
 use pkarr::{system_time, SignedPacket};
 use redb::{
-    backends::InMemoryBackend, Database, MultimapTableDefinition, ReadableTable, TableDefinition,
+    backends::InMemoryBackend, Database, MultimapTableDefinition, ReadableMultimapTable,
+    ReadableTable, TableDefinition,
 };
 use tokio::sync::{mpsc, oneshot};
 use tokio_util::sync::CancellationToken;
@@ -35,11 +37,31 @@ impl Drop for SignedPacketStore {
     }
 }
 
+/// The outcome of a conditional upsert into the [`SignedPacketStore`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Upsert {
+    /// The timestamp of the packet stored before, if any.
+    pub previous: Option<u64>,
+    /// Whether the packet was stored.
+    pub stored: bool,
+}
+
+/// When an upsert stores the packet.
+#[derive(Debug, Clone, Copy)]
+enum Condition {
+    /// The packet is more recent than the stored one, if any.
+    Newer,
+    /// The packet is more recent than the stored one, and the stored one has the expected
+    /// timestamp, or there is none if `None` is expected.
+    Timestamp(Option<u64>),
+}
+
 #[derive(derive_more::Debug)]
 enum Message {
     Upsert {
         packet: SignedPacket,
-        res: oneshot::Sender<bool>,
+        condition: Condition,
+        res: oneshot::Sender<Upsert>,
     },
     Get {
         key: PublicKeyBytes,
@@ -64,9 +86,28 @@ struct Actor {
     recv: PeekableReceiver<Message>,
     cancel: CancellationToken,
     options: Options,
+    /// The recency of the packets, `None` if the number of packets is not bounded.
+    bound: Option<Bound>,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Called with a packet before it is evicted to keep the store within its bound.
+///
+/// Returns whether the packet may be dropped.  Returning `false` keeps the packet as if it
+/// was just used, e.g. after failing to write it to a persistent layer.  The callback runs
+/// within the write transaction of the store, so it should return quickly.
+#[derive(Clone, derive_more::Debug)]
+#[debug("EvictionCallback")]
+pub struct EvictionCallback(Arc<dyn Fn(&SignedPacket) -> bool + Send + Sync>);
+
+impl EvictionCallback {
+    /// Creates a callback from a closure.
+    pub fn new(f: impl Fn(&SignedPacket) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+/// The options of the packet store.
+#[derive(Debug, Clone)]
 pub struct Options {
     /// Maximum number of packets to process in a single write transaction.
     pub max_batch_size: usize,
@@ -76,6 +117,12 @@ pub struct Options {
     pub eviction: Duration,
     /// Pause between eviction checks.
     pub eviction_interval: Duration,
+    /// Maximum number of packets to keep, the least recently used ones are evicted beyond.
+    ///
+    /// Packets are used when they are stored or read.  Unbounded if `None`.
+    pub max_entries: Option<usize>,
+    /// Consulted before a packet is evicted to keep the store within `max_entries`.
+    pub on_evict: Option<EvictionCallback>,
 }
 
 impl Default for Options {
@@ -89,6 +136,8 @@ impl Default for Options {
             eviction: Duration::from_secs(3600 * 24 * 7),
             // eviction can run frequently since it does not do a full scan
             eviction_interval: Duration::from_secs(10),
+            max_entries: None,
+            on_evict: None,
         }
     }
 }
@@ -135,32 +184,19 @@ impl Actor {
                             Message::Get { key, res } => {
                                 trace!("get {}", key);
                                 let packet = get_packet(&tables.signed_packets, &key)?;
+                                if let (Some(bound), Some(_)) = (&mut self.bound, &packet) {
+                                    bound.touch(key);
+                                }
                                 res.send(packet).ok();
                             }
-                            Message::Upsert { packet, res } => {
+                            Message::Upsert { packet, condition, res } => {
                                 let key = PublicKeyBytes::from_signed_packet(&packet);
-                                trace!("upsert {}", key);
-                                let replaced = if let Some(existing) = get_packet(&tables.signed_packets, &key)? {
-                                    if existing.more_recent_than(&packet) {
-                                        res.send(false).ok();
-                                        continue;
-                                    } else {
-                                        // remove the packet from the update time index
-                                        tables.update_time.remove(&packet.timestamp().to_be_bytes(), key.as_bytes())?;
-                                        true
-                                    }
-                                } else {
-                                    false
-                                };
-                                let value = packet.as_bytes();
-                                tables.signed_packets.insert(key.as_bytes(), &value[..])?;
-                                tables.update_time.insert(&packet.timestamp().to_be_bytes(), key.as_bytes())?;
-                                if replaced {
-                                    inc!(Metrics, store_packets_updated);
-                                } else {
-                                    inc!(Metrics, store_packets_inserted);
+                                let upsert = upsert(&mut tables, packet, condition)?;
+                                if let (Some(bound), true) = (&mut self.bound, upsert.stored) {
+                                    bound.touch(key);
+                                    bound.evict(&mut tables)?;
                                 }
-                                res.send(true).ok();
+                                res.send(upsert).ok();
                             }
                             Message::Remove { key, res } => {
                                 trace!("remove {}", key);
@@ -175,6 +211,9 @@ impl Actor {
                                 if updated {
                                     inc!(Metrics, store_packets_removed);
                                 }
+                                if let Some(bound) = &mut self.bound {
+                                    bound.remove(&key);
+                                }
                                 res.send(updated).ok();
                             }
                             Message::Snapshot { res } => {
@@ -187,6 +226,9 @@ impl Actor {
                                     if packet.timestamp() < expired {
                                         tables.update_time.remove(&time, key.as_bytes())?;
                                         let _ = tables.signed_packets.remove(key.as_bytes())?;
+                                        if let Some(bound) = &mut self.bound {
+                                            bound.remove(&key);
+                                        }
                                         inc!(Metrics, store_packets_expired);
                                     }
                                 }
@@ -202,6 +244,108 @@ impl Actor {
     }
 }
 
+/// Stores the packet if the condition holds, within the write transaction of `tables`.
+fn upsert(tables: &mut Tables, packet: SignedPacket, condition: Condition) -> Result<Upsert> {
+    let key = PublicKeyBytes::from_signed_packet(&packet);
+    trace!("upsert {} if {:?}", key, condition);
+    let existing = get_packet(&tables.signed_packets, &key)?;
+    let previous = existing.as_ref().map(SignedPacket::timestamp);
+    let newer = existing
+        .as_ref()
+        .is_none_or(|existing| !existing.more_recent_than(&packet));
+    let stored = match condition {
+        Condition::Newer => newer,
+        Condition::Timestamp(expected) => newer && previous == expected,
+    };
+    if !stored {
+        return Ok(Upsert { previous, stored });
+    }
+    if let Some(previous) = previous {
+        // remove the previous packet from the update time index
+        tables
+            .update_time
+            .remove(&previous.to_be_bytes(), key.as_bytes())?;
+    }
+    let value = packet.as_bytes();
+    tables.signed_packets.insert(key.as_bytes(), &value[..])?;
+    tables
+        .update_time
+        .insert(&packet.timestamp().to_be_bytes(), key.as_bytes())?;
+    if previous.is_some() {
+        inc!(Metrics, store_packets_updated);
+    } else {
+        inc!(Metrics, store_packets_inserted);
+    }
+    Ok(Upsert { previous, stored })
+}
+
+/// Keeps the number of packets within `max_entries`, by evicting the least recently used
+/// ones.
+///
+/// The recency is only tracked in memory.  When a store is opened its packets start out
+/// used in the order they were published.
+struct Bound {
+    max_entries: usize,
+    recency: LruCache<PublicKeyBytes, ()>,
+    on_evict: Option<EvictionCallback>,
+}
+
+impl Bound {
+    /// Reads the stored packets, the least recently published first.
+    fn load(db: &Database, max_entries: usize, on_evict: Option<EvictionCallback>) -> Result<Self> {
+        let snapshot = Snapshot::new(db)?;
+        let mut recency = LruCache::unbounded();
+        for item in snapshot.update_time.iter()? {
+            let (_, keys) = item?;
+            for key in keys {
+                recency.put(PublicKeyBytes::new(key?.value()), ());
+            }
+        }
+        Ok(Self {
+            max_entries,
+            recency,
+            on_evict,
+        })
+    }
+
+    /// Marks the packet of `key` as the most recently used.
+    fn touch(&mut self, key: PublicKeyBytes) {
+        self.recency.put(key, ());
+    }
+
+    fn remove(&mut self, key: &PublicKeyBytes) {
+        self.recency.pop(key);
+    }
+
+    /// Evicts the least recently used packets beyond `max_entries`.
+    ///
+    /// Packets kept by the eviction callback are tried again on a later eviction, the store
+    /// exceeds its bound while the callback keeps all of them.
+    fn evict(&mut self, tables: &mut Tables) -> Result<()> {
+        let mut kept = 0;
+        while self.recency.len() > self.max_entries && kept < self.recency.len() {
+            let (key, ()) = self.recency.pop_lru().expect("not empty");
+            let Some(packet) = get_packet(&tables.signed_packets, &key)? else {
+                continue;
+            };
+            if let Some(on_evict) = &self.on_evict {
+                if !(on_evict.0)(&packet) {
+                    self.recency.put(key, ());
+                    kept += 1;
+                    continue;
+                }
+            }
+            debug!("evicting least recently used packet {}", key);
+            tables
+                .update_time
+                .remove(&packet.timestamp().to_be_bytes(), key.as_bytes())?;
+            tables.signed_packets.remove(key.as_bytes())?;
+            inc!(Metrics, store_packets_evicted);
+        }
+        Ok(())
+    }
+}
+
 /// A struct similar to [`redb::Table`] but for all tables that make up the
 /// signed packet store.
 pub(super) struct Tables<'a> {
@@ -219,7 +363,6 @@ impl<'txn> Tables<'txn> {
 }
 
 pub(super) struct Snapshot {
-    #[allow(dead_code)]
     pub signed_packets: redb::ReadOnlyTable<&'static SignedPacketsKey, &'static [u8]>,
     pub update_time: redb::ReadOnlyMultimapTable<[u8; 8], SignedPacketsKey>,
 }
@@ -232,6 +375,24 @@ impl Snapshot {
             update_time: tx.open_multimap_table(UPDATE_TIME_TABLE)?,
         })
     }
+
+    /// Reads up to `limit` packets, the most recently published first.
+    fn most_recent(&self, limit: usize) -> Result<Vec<SignedPacket>> {
+        let mut packets = Vec::new();
+        for item in self.update_time.iter()?.rev() {
+            let (_, keys) = item?;
+            for key in keys {
+                if packets.len() >= limit {
+                    return Ok(packets);
+                }
+                let key = PublicKeyBytes::new(key?.value());
+                if let Some(packet) = get_packet(&self.signed_packets, &key)? {
+                    packets.push(packet);
+                }
+            }
+        }
+        Ok(packets)
+    }
 }
 
 impl SignedPacketStore {
@@ -263,22 +424,28 @@ impl SignedPacketStore {
         let write_tx = db.begin_write()?;
         let _ = Tables::new(&write_tx)?;
         write_tx.commit()?;
+        let bound = match options.max_entries {
+            Some(max_entries) => Some(Bound::load(&db, max_entries, options.on_evict.clone())?),
+            None => None,
+        };
         let (send, recv) = mpsc::channel(1024);
         let send2 = send.clone();
         let cancel = CancellationToken::new();
         let cancel2 = cancel.clone();
         let cancel3 = cancel.clone();
+        let evict_options = options.clone();
         let actor = Actor {
             db,
             recv: PeekableReceiver::new(recv),
             cancel: cancel2,
             options,
+            bound,
         };
         // start an io thread and donate it to the tokio runtime so we can do blocking IO
         // inside the thread despite being in a tokio runtime
         let _write_thread = IoThread::new("packet-store-actor", move || actor.run())?;
         let _evict_thread = IoThread::new("packet-store-evict", move || {
-            evict_task(send2, options, cancel3)
+            evict_task(send2, evict_options, cancel3)
         })?;
         Ok(Self {
             send,
@@ -288,9 +455,36 @@ impl SignedPacketStore {
         })
     }
 
-    pub async fn upsert(&self, packet: SignedPacket) -> Result<bool> {
+    /// Stores the packet if it is more recent than the stored one, if any.
+    ///
+    /// The check and the insert happen in the same write transaction, so concurrent upserts
+    /// cannot replace a packet with an older one.
+    pub async fn upsert_if_newer(&self, packet: SignedPacket) -> Result<Upsert> {
+        self.upsert(packet, Condition::Newer).await
+    }
+
+    /// Stores the packet if the stored one still has the `expected` timestamp.
+    ///
+    /// Expecting `None` only stores the packet if there is none for its key yet.  The packet
+    /// also needs to be more recent than the stored one.  Used by replication, so a packet
+    /// fetched from the primary does not overwrite an update stored while it was fetched.
+    pub async fn compare_and_swap(
+        &self,
+        packet: SignedPacket,
+        expected: Option<u64>,
+    ) -> Result<Upsert> {
+        self.upsert(packet, Condition::Timestamp(expected)).await
+    }
+
+    async fn upsert(&self, packet: SignedPacket, condition: Condition) -> Result<Upsert> {
         let (tx, rx) = oneshot::channel();
-        self.send.send(Message::Upsert { packet, res: tx }).await?;
+        self.send
+            .send(Message::Upsert {
+                packet,
+                condition,
+                res: tx,
+            })
+            .await?;
         Ok(rx.await?)
     }
 
@@ -300,6 +494,17 @@ impl SignedPacketStore {
         Ok(rx.await?)
     }
 
+    /// Reads up to `limit` packets, the most recently published first.
+    ///
+    /// The packets are read from a snapshot on a blocking thread, so this does not hold up
+    /// writes.  Packets of the write transaction which is still open are not included.
+    pub async fn most_recent(&self, limit: usize) -> Result<Vec<SignedPacket>> {
+        let (tx, rx) = oneshot::channel();
+        self.send.send(Message::Snapshot { res: tx }).await?;
+        let snapshot = rx.await?;
+        tokio::task::spawn_blocking(move || snapshot.most_recent(limit)).await?
+    }
+
     pub async fn remove(&self, key: &PublicKeyBytes) -> Result<bool> {
         let (tx, rx) = oneshot::channel();
         self.send
@@ -458,3 +663,163 @@ impl<T> PeekableReceiver<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use pkarr::{dns, Keypair};
+
+    use super::*;
+
+    fn signed_packet(keypair: &Keypair, ttl: u32) -> Result<SignedPacket> {
+        let mut packet = dns::Packet::new_reply(0);
+        packet.answers.push(dns::ResourceRecord::new(
+            dns::Name::new("_iroh").unwrap(),
+            dns::CLASS::IN,
+            ttl,
+            dns::rdata::RData::TXT("hello".try_into()?),
+        ));
+        Ok(SignedPacket::from_packet(keypair, &packet)?)
+    }
+
+    #[tokio::test]
+    async fn test_upsert_if_newer() -> Result<()> {
+        let store = SignedPacketStore::in_memory(Options::default())?;
+        let keypair = Keypair::random();
+        let older = signed_packet(&keypair, 30)?;
+        let newer = signed_packet(&keypair, 60)?;
+        assert!(newer.more_recent_than(&older));
+        let key = PublicKeyBytes::from_signed_packet(&older);
+
+        let res = store.upsert_if_newer(newer.clone()).await?;
+        assert_eq!(
+            res,
+            Upsert {
+                previous: None,
+                stored: true
+            }
+        );
+        // An older packet is not stored, but the stored timestamp is returned.
+        let res = store.upsert_if_newer(older).await?;
+        assert_eq!(
+            res,
+            Upsert {
+                previous: Some(newer.timestamp()),
+                stored: false
+            }
+        );
+        let stored = store.get(&key).await?.expect("packet is stored");
+        assert_eq!(stored.timestamp(), newer.timestamp());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_most_recent() -> Result<()> {
+        let options = Options {
+            max_batch_time: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let store = SignedPacketStore::in_memory(options)?;
+        let keypairs: Vec<_> = (0..3).map(|_| Keypair::random()).collect();
+        let keys: Vec<_> = keypairs
+            .iter()
+            .map(|keypair| PublicKeyBytes::new(keypair.public_key().to_bytes()))
+            .collect();
+        for keypair in keypairs.iter().chain([&keypairs[0]]) {
+            store.upsert_if_newer(signed_packet(keypair, 30)?).await?;
+            // Distinct timestamps, they have microsecond resolution.
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // Wait for the write transaction to be committed.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Updating the first packet made it the most recent one.
+        let recent = store.most_recent(2).await?;
+        let recent: Vec<_> = recent
+            .iter()
+            .map(PublicKeyBytes::from_signed_packet)
+            .collect();
+        assert_eq!(recent, vec![keys[0], keys[2]]);
+        assert_eq!(store.most_recent(10).await?.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_entries() -> Result<()> {
+        let keypairs: Vec<_> = (0..4).map(|_| Keypair::random()).collect();
+        let packets = keypairs
+            .iter()
+            .map(|keypair| signed_packet(keypair, 30))
+            .collect::<Result<Vec<_>>>()?;
+        let keys: Vec<_> = packets
+            .iter()
+            .map(PublicKeyBytes::from_signed_packet)
+            .collect();
+        // The callback keeps the first packet.
+        let consulted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let on_evict = {
+            let consulted = consulted.clone();
+            let keep = keys[0];
+            EvictionCallback::new(move |packet| {
+                let key = PublicKeyBytes::from_signed_packet(packet);
+                consulted.lock().unwrap().push(key);
+                key != keep
+            })
+        };
+        let store = SignedPacketStore::in_memory(Options {
+            max_entries: Some(2),
+            on_evict: Some(on_evict),
+            ..Default::default()
+        })?;
+
+        store.upsert_if_newer(packets[0].clone()).await?;
+        store.upsert_if_newer(packets[1].clone()).await?;
+        // Reading the first packet makes the second one the least recently used.
+        assert!(store.get(&keys[0]).await?.is_some());
+        store.upsert_if_newer(packets[2].clone()).await?;
+        assert!(store.get(&keys[1]).await?.is_none());
+        // The first packet is the least recently used one now, but it is kept.
+        store.upsert_if_newer(packets[3].clone()).await?;
+        assert_eq!(*consulted.lock().unwrap(), vec![keys[1], keys[0], keys[2]]);
+        assert!(store.get(&keys[0]).await?.is_some());
+        assert!(store.get(&keys[2]).await?.is_none());
+        assert!(store.get(&keys[3]).await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compare_and_swap() -> Result<()> {
+        let store = SignedPacketStore::in_memory(Options::default())?;
+        let keypair = Keypair::random();
+        let first = signed_packet(&keypair, 30)?;
+        let second = signed_packet(&keypair, 60)?;
+        let third = signed_packet(&keypair, 90)?;
+        let key = PublicKeyBytes::from_signed_packet(&first);
+
+        assert!(store.compare_and_swap(first.clone(), None).await?.stored);
+        // Stored while the third packet was fetched, which expected the first one.
+        store.upsert_if_newer(second.clone()).await?;
+        let res = store
+            .compare_and_swap(third.clone(), Some(first.timestamp()))
+            .await?;
+        assert_eq!(
+            res,
+            Upsert {
+                previous: Some(second.timestamp()),
+                stored: false
+            }
+        );
+        let res = store
+            .compare_and_swap(third.clone(), Some(second.timestamp()))
+            .await?;
+        assert!(res.stored);
+        // A matching timestamp does not allow going back to an older packet.
+        let res = store
+            .compare_and_swap(first, Some(third.timestamp()))
+            .await?;
+        assert!(!res.stored);
+        let stored = store.get(&key).await?.expect("packet is stored");
+        assert_eq!(stored.timestamp(), third.timestamp());
+        Ok(())
+    }
+}
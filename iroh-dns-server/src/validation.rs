@@ -0,0 +1,221 @@
+//! Validation of signed packets published to the pkarr relay.
+//!
+//! Signatures are always checked when a packet is published, a [`PublishValidator`] can
+//! additionally enforce operator policies on the content of the packet, e.g. with a
+//! [`PublishPolicy`].
+
+use hickory_server::proto::rr::{RData, RecordType};
+use pkarr::SignedPacket;
+use serde::{Deserialize, Serialize};
+
+use crate::util::signed_packet_to_hickory_message;
+
+/// Validates signed packets published to the pkarr relay, beyond their signature.
+///
+/// Validators are added to a [`ZoneStore`] with [`ZoneStore::with_publish_validator`].  They
+/// are invoked on every published packet before it is stored, a rejection is returned to the
+/// publisher.
+///
+/// [`ZoneStore`]: crate::ZoneStore
+/// [`ZoneStore::with_publish_validator`]: crate::ZoneStore::with_publish_validator
+pub trait PublishValidator: std::fmt::Debug + Send + Sync + 'static {
+    /// Validates a signed packet, returning why it is rejected if it is not allowed.
+    fn validate(&self, signed_packet: &SignedPacket) -> Result<(), PublishRejection>;
+}
+
+/// Why a published signed packet was rejected.
+///
+/// This is returned to the publisher as the `rejection` of the JSON error body, tagged by its
+/// `reason`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, derive_more::Display)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum PublishRejection {
+    /// The DNS packet could not be parsed.
+    #[display("invalid DNS packet: {detail}")]
+    InvalidPacket {
+        /// Why parsing failed.
+        detail: String,
+    },
+    /// The packet contains a record of a type which is not allowed.
+    #[display("record type {record_type} of {name} is not allowed")]
+    RecordTypeNotAllowed {
+        /// The name of the record.
+        name: String,
+        /// The type of the record.
+        record_type: String,
+    },
+    /// The packet contains a record with a TTL above the maximum.
+    #[display("TTL {ttl} of {name} exceeds the maximum of {max_ttl}")]
+    TtlTooLarge {
+        /// The name of the record.
+        name: String,
+        /// The TTL of the record.
+        ttl: u32,
+        /// The maximum TTL allowed.
+        max_ttl: u32,
+    },
+    /// The packet contains a TXT record with forbidden content.
+    #[display("TXT record of {name} contains forbidden content")]
+    ForbiddenContent {
+        /// The name of the record.
+        name: String,
+    },
+    /// The packet was rejected by a custom policy.
+    #[display("{detail}")]
+    Policy {
+        /// Why the packet was rejected.
+        detail: String,
+    },
+}
+
+impl std::error::Error for PublishRejection {}
+
+/// A [`PublishValidator`] enforcing a configurable operator policy.
+///
+/// The defaults allow everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, schemars::JsonSchema)]
+#[serde(default)]
+pub struct PublishPolicy {
+    /// The record types allowed in packets, e.g. `["TXT", "A", "AAAA"]`.
+    ///
+    /// All record types are allowed if not set.
+    pub allowed_record_types: Option<Vec<String>>,
+    /// The maximum TTL of records, in seconds.
+    pub max_ttl: Option<u32>,
+    /// Substrings which must not appear in TXT values.
+    pub forbidden_txt_content: Vec<String>,
+}
+
+impl PublishValidator for PublishPolicy {
+    fn validate(&self, signed_packet: &SignedPacket) -> Result<(), PublishRejection> {
+        let message = signed_packet_to_hickory_message(signed_packet).map_err(|err| {
+            PublishRejection::InvalidPacket {
+                detail: err.to_string(),
+            }
+        })?;
+        for record in message.answers() {
+            let name = record.name().to_string();
+            if let Some(allowed) = &self.allowed_record_types {
+                let record_type = record.record_type();
+                if !allowed
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(&record_type.to_string()))
+                {
+                    return Err(PublishRejection::RecordTypeNotAllowed {
+                        name,
+                        record_type: record_type.to_string(),
+                    });
+                }
+            }
+            if let Some(max_ttl) = self.max_ttl {
+                if record.ttl() > max_ttl {
+                    return Err(PublishRejection::TtlTooLarge {
+                        name,
+                        ttl: record.ttl(),
+                        max_ttl,
+                    });
+                }
+            }
+            if let (RecordType::TXT, RData::TXT(txt)) = (record.record_type(), record.data()) {
+                let forbidden = txt.txt_data().iter().any(|data| {
+                    let value = String::from_utf8_lossy(data);
+                    self.forbidden_txt_content
+                        .iter()
+                        .any(|content| value.contains(content.as_str()))
+                });
+                if forbidden {
+                    return Err(PublishRejection::ForbiddenContent { name });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use pkarr::{dns, Keypair};
+
+    use super::*;
+
+    fn signed_packet(records: &[(&str, u32, dns::rdata::RData)]) -> SignedPacket {
+        let mut packet = dns::Packet::new_reply(0);
+        for (name, ttl, rdata) in records {
+            packet.answers.push(dns::ResourceRecord::new(
+                dns::Name::new(name).unwrap(),
+                dns::CLASS::IN,
+                *ttl,
+                rdata.clone(),
+            ));
+        }
+        SignedPacket::from_packet(&Keypair::random(), &packet).unwrap()
+    }
+
+    #[test]
+    fn test_publish_policy() {
+        let policy = PublishPolicy {
+            allowed_record_types: Some(vec!["txt".to_string()]),
+            max_ttl: Some(60),
+            forbidden_txt_content: vec!["evil".to_string()],
+        };
+
+        let packet = signed_packet(&[(
+            "_iroh",
+            30,
+            dns::rdata::RData::TXT("hi".try_into().unwrap()),
+        )]);
+        assert_eq!(policy.validate(&packet), Ok(()));
+        assert_eq!(PublishPolicy::default().validate(&packet), Ok(()));
+
+        let packet = signed_packet(&[("", 30, dns::rdata::RData::A(Ipv4Addr::LOCALHOST.into()))]);
+        assert!(matches!(
+            policy.validate(&packet),
+            Err(PublishRejection::RecordTypeNotAllowed { record_type, .. }) if record_type == "A"
+        ));
+
+        let packet = signed_packet(&[(
+            "_iroh",
+            3600,
+            dns::rdata::RData::TXT("hi".try_into().unwrap()),
+        )]);
+        assert!(matches!(
+            policy.validate(&packet),
+            Err(PublishRejection::TtlTooLarge {
+                ttl: 3600,
+                max_ttl: 60,
+                ..
+            })
+        ));
+
+        let packet = signed_packet(&[(
+            "_iroh",
+            30,
+            dns::rdata::RData::TXT("very evil".try_into().unwrap()),
+        )]);
+        assert!(matches!(
+            policy.validate(&packet),
+            Err(PublishRejection::ForbiddenContent { name }) if name.starts_with("_iroh.")
+        ));
+    }
+
+    #[test]
+    fn test_publish_rejection_json() {
+        let rejection = PublishRejection::TtlTooLarge {
+            name: "_iroh.example.".to_string(),
+            ttl: 3600,
+            max_ttl: 60,
+        };
+        let json = serde_json::to_value(&rejection).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "reason": "ttl_too_large",
+                "name": "_iroh.example.",
+                "ttl": 3600,
+                "max_ttl": 60,
+            })
+        );
+    }
+}
@@ -18,9 +18,9 @@ use std::{
 
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
-use iroh_base::RelayUrl;
 #[cfg(feature = "metrics")]
-use iroh_metrics::inc;
+use iroh_base::inc;
+use iroh_base::RelayUrl;
 use iroh_relay::{dns::DnsResolver, protos::stun, RelayMap};
 use n0_future::{
     task::{self, AbortOnDropHandle},
@@ -166,6 +166,16 @@ impl RelayLatencies {
     }
 }
 
+impl FromIterator<(RelayUrl, Duration)> for RelayLatencies {
+    fn from_iter<T: IntoIterator<Item = (RelayUrl, Duration)>>(iter: T) -> Self {
+        let mut latencies = Self::new();
+        for (url, latency) in iter {
+            latencies.update_relay(url, latency);
+        }
+        latencies
+    }
+}
+
 /// Client to run net_reports.
 ///
 /// Creating this creates a net_report actor which runs in the background.  Most of the time
@@ -431,6 +441,16 @@ impl Client {
         }
     }
 
+    /// Seeds the actor with a report from a previous run, e.g. one persisted across restarts.
+    ///
+    /// The seeded report is used like the result of the last run: the next run only
+    /// probes incrementally, and its preferred relay is kept unless another relay is
+    /// considerably faster.  It is ignored once a report was produced.
+    pub async fn seed_report(&self, report: Arc<Report>) -> Result<()> {
+        self.addr.send(Message::SeedReport(report)).await?;
+        Ok(())
+    }
+
     /// Get report with channel
     ///
     /// Look at [`Options`] for the different configuration options.
@@ -476,6 +496,8 @@ pub(crate) enum Message {
         /// Channel to receive the response.
         response_tx: oneshot::Sender<Result<Arc<Report>>>,
     },
+    /// A report from a previous run to use as the last report.
+    SeedReport(Arc<Report>),
     /// A report produced by the [`reportgen`] actor.
     ReportReady { report: Box<Report> },
     /// The [`reportgen`] actor failed to produce a report.
@@ -623,6 +645,9 @@ impl Actor {
                 } => {
                     self.handle_run_check(relay_map, opts, response_tx);
                 }
+                Message::SeedReport(report) => {
+                    self.handle_seed_report(report);
+                }
                 Message::ReportReady { report } => {
                     self.handle_report_ready(*report);
                 }
@@ -709,6 +734,16 @@ impl Actor {
         });
     }
 
+    /// Handles [`Message::SeedReport`].
+    fn handle_seed_report(&mut self, report: Arc<Report>) {
+        if self.reports.last.is_some() || self.current_report_run.is_some() {
+            debug!("ignoring seed report, already have a report");
+            return;
+        }
+        self.reports.prev.insert(Instant::now(), report.clone());
+        self.reports.last = Some(report);
+    }
+
     fn handle_report_ready(&mut self, report: Report) {
         let report = self.finish_and_store_report(report);
         self.in_flight_stun_requests.clear();
@@ -1418,6 +1453,45 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn test_seed_report() -> Result<()> {
+        let url_1: RelayUrl = "http://1.com".parse().unwrap();
+        let url_2: RelayUrl = "http://2.com".parse().unwrap();
+        let mut actor = Actor::new(None, crate::dns::tests::resolver(), None)?;
+
+        let seed = Arc::new(Report {
+            relay_latency: [
+                (url_1.clone(), Duration::from_millis(20)),
+                (url_2.clone(), Duration::from_millis(30)),
+            ]
+            .into_iter()
+            .collect(),
+            preferred_relay: Some(url_1.clone()),
+            ..Default::default()
+        });
+        actor.handle_seed_report(seed.clone());
+        assert_eq!(actor.reports.last, Some(seed));
+
+        // The seeded preferred relay is kept if the other one is not much faster.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let report = Report {
+            relay_latency: [
+                (url_1.clone(), Duration::from_millis(20)),
+                (url_2.clone(), Duration::from_millis(18)),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        let report = actor.add_report_history_and_set_preferred_relay(report);
+        assert_eq!(report.preferred_relay, Some(url_1));
+
+        // Later seeds are ignored.
+        actor.handle_seed_report(Arc::new(Report::default()));
+        assert_eq!(actor.reports.last, Some(report));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_hairpin() -> Result<()> {
         // Hairpinning is initiated after we discover our own IPv4 socket address (IP +
@@ -26,9 +26,9 @@ use std::{
 };
 
 use anyhow::{anyhow, bail, Context as _, Result};
-use iroh_base::RelayUrl;
 #[cfg(feature = "metrics")]
-use iroh_metrics::inc;
+use iroh_base::inc;
+use iroh_base::RelayUrl;
 use iroh_relay::{
     defaults::{DEFAULT_RELAY_QUIC_PORT, DEFAULT_STUN_PORT},
     dns::DnsResolver,
@@ -1130,6 +1130,29 @@ async fn relay_lookup_ipv4_staggered(
     }
 }
 
+/// Synthesizes a NAT64 address from the IPv4 addresses of an IPv4-only relay.
+///
+/// Only used if the DNS resolver does not synthesize them itself, see
+/// [`iroh_relay::dns::Nat64`].
+async fn relay_lookup_nat64(
+    dns_resolver: &DnsResolver,
+    hostname: &str,
+    port: u16,
+) -> Result<SocketAddr> {
+    let mut addrs = dns_resolver
+        .lookup_ipv4_staggered(hostname, DNS_TIMEOUT, DNS_STAGGERING_MS)
+        .await?;
+    let Some(IpAddr::V4(addr)) = addrs.next().map(|ip| ip.to_canonical()) else {
+        bail!("no IPv4 addr");
+    };
+    let addr = dns_resolver
+        .synthesize_ipv6(addr)
+        .await
+        .context("no NAT64 prefix")?;
+    debug!(%hostname, %addr, "synthesized NAT64 relay addr");
+    Ok(SocketAddr::new(addr.into(), port))
+}
+
 /// Do a staggared ipv6 DNS lookup based on [`RelayNode`]
 ///
 /// `port` is combined with the resolved [`std::net::Ipv6Addr`] to return a [`SocketAddr`]
@@ -1145,15 +1168,21 @@ async fn relay_lookup_ipv6_staggered(
                 .lookup_ipv6_staggered(hostname, DNS_TIMEOUT, DNS_STAGGERING_MS)
                 .await
             {
-                Ok(mut addrs) => addrs
-                    .next()
-                    .map(|ip| ip.to_canonical())
-                    .map(|addr| SocketAddr::new(addr, port))
-                    .ok_or(anyhow!("No suitable relay addr found")),
-                Err(err) => Err(err.context("No suitable relay addr found")),
+                Ok(mut addrs) => match addrs.next() {
+                    Some(addr) => Ok(SocketAddr::new(addr.to_canonical(), port)),
+                    None => relay_lookup_nat64(dns_resolver, hostname, port)
+                        .await
+                        .context("No suitable relay addr found"),
+                },
+                Err(err) => relay_lookup_nat64(dns_resolver, hostname, port)
+                    .await
+                    .map_err(|_| err.context("No suitable relay addr found")),
             }
         }
-        Some(url::Host::Ipv4(_addr)) => Err(anyhow!("No suitable relay addr found")),
+        Some(url::Host::Ipv4(addr)) => match dns_resolver.synthesize_ipv6(addr).await {
+            Some(addr) => Ok(SocketAddr::new(addr.into(), port)),
+            None => Err(anyhow!("No suitable relay addr found")),
+        },
         Some(url::Host::Ipv6(addr)) => Ok(SocketAddr::new(addr.into(), port)),
         None => Err(anyhow!("No valid hostname in RelayUrl")),
     }
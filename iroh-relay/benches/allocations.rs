@@ -0,0 +1,166 @@
+//! Counts the heap allocations needed to forward packets through a relay server.
+//!
+//! Runs a relay server and two clients in-process, forwards packets from one client to
+//! the other and reports the number of allocations and the allocated bytes per packet, for
+//! each relay protocol.  Fails if these exceed [`MAX_ALLOCATIONS_PER_PACKET`] or
+//! [`MAX_ALLOCATED_PACKETS_PER_PACKET`], to catch regressions on the forwarding hot path.
+//! Copies of the packet content show up as allocated bytes.
+//!
+//! Run with `cargo bench -p iroh-relay --features server --bench allocations`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    net::Ipv4Addr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use iroh_base::{NodeId, RelayUrl, SecretKey};
+use iroh_relay::{
+    client::{Client, ClientBuilder, ReceivedMessage, SendMessage},
+    dns::DnsResolver,
+    http::Protocol,
+    server::{RelayConfig, Server, ServerConfig},
+};
+use n0_future::{SinkExt, StreamExt};
+
+/// Allocations allowed per forwarded packet, for both clients and the server together.
+const MAX_ALLOCATIONS_PER_PACKET: [(Protocol, f64); 2] =
+    [(Protocol::Relay, 0.5), (Protocol::Websocket, 5.5)];
+
+/// Bytes allowed to be allocated per forwarded packet, in multiples of the packet size.
+///
+/// Over websockets the clients copy the packets into the messages they send and receive,
+/// the server forwards them without copying.
+const MAX_ALLOCATED_PACKETS_PER_PACKET: [(Protocol, f64); 2] =
+    [(Protocol::Relay, 0.5), (Protocol::Websocket, 5.5)];
+
+const WARMUP_PACKETS: usize = 1_000;
+const PACKETS: usize = 10_000;
+const PACKET_SIZE: usize = 1_200;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// The allocations and the allocated bytes while forwarding the packets.
+struct Allocations {
+    count: usize,
+    bytes: usize,
+}
+
+async fn run(protocol: Protocol) -> Result<Allocations> {
+    let mut relay = RelayConfig::new((Ipv4Addr::LOCALHOST, 0).into());
+    relay.key_cache_capacity = Some(1024);
+    let server = Server::spawn(ServerConfig::<(), ()> {
+        relay: Some(relay),
+        stun: None,
+        quic: None,
+        #[cfg(feature = "metrics")]
+        metrics: Default::default(),
+        listeners: Default::default(),
+    })
+    .await?;
+    let url: RelayUrl = format!("http://{}", server.http_addr().context("http addr")?).parse()?;
+
+    let a_key = SecretKey::generate(rand::thread_rng());
+    let b_key = SecretKey::generate(rand::thread_rng());
+    let b_node_id = b_key.public();
+    let mut client_a = ClientBuilder::new(url.clone(), a_key, DnsResolver::new())
+        .protocol(protocol)
+        .connect()
+        .await?;
+    let mut client_b = ClientBuilder::new(url, b_key, DnsResolver::new())
+        .protocol(protocol)
+        .connect()
+        .await?;
+
+    let payload = Bytes::from(vec![42u8; PACKET_SIZE]);
+    forward(
+        &mut client_a,
+        &mut client_b,
+        b_node_id,
+        &payload,
+        WARMUP_PACKETS,
+    )
+    .await?;
+    let start = ALLOCATIONS.load(Ordering::Relaxed);
+    let start_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    forward(&mut client_a, &mut client_b, b_node_id, &payload, PACKETS).await?;
+    let allocations = Allocations {
+        count: ALLOCATIONS.load(Ordering::Relaxed) - start,
+        bytes: ALLOCATED_BYTES.load(Ordering::Relaxed) - start_bytes,
+    };
+
+    server.shutdown().await?;
+    Ok(allocations)
+}
+
+/// Sends `count` packets from `sender` to `receiver`, one at a time.
+async fn forward(
+    sender: &mut Client,
+    receiver: &mut Client,
+    dst: NodeId,
+    payload: &Bytes,
+    count: usize,
+) -> Result<()> {
+    for _ in 0..count {
+        sender
+            .send(SendMessage::SendPacket(dst, payload.clone()))
+            .await?;
+        match receiver.next().await.context("eos")?? {
+            ReceivedMessage::ReceivedPacket { data, .. } if data.len() == payload.len() => {}
+            msg => bail!("unexpected message: {msg:?}"),
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    for ((protocol, max), (_, max_bytes)) in MAX_ALLOCATIONS_PER_PACKET
+        .into_iter()
+        .zip(MAX_ALLOCATED_PACKETS_PER_PACKET)
+    {
+        let allocations = rt.block_on(run(protocol))?;
+        let per_packet = allocations.count as f64 / PACKETS as f64;
+        let bytes_per_packet = allocations.bytes as f64 / PACKETS as f64 / PACKET_SIZE as f64;
+        println!(
+            "{protocol:?}: {} allocations of {} bytes for {PACKETS} packets, \
+             {per_packet:.2} per packet of {bytes_per_packet:.2} times the packet size",
+            allocations.count, allocations.bytes,
+        );
+        if per_packet > max {
+            bail!("{protocol:?}: more than {max} allocations per packet");
+        }
+        if bytes_per_packet > max_bytes {
+            bail!("{protocol:?}: more than {max_bytes} times the packet size allocated per packet");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,141 @@
+//! Measures the throughput of packets forwarded through a relay server.
+//!
+//! Runs a relay server and two clients in-process, forwards packets of several sizes from
+//! one client to the other and reports the throughput, for each relay protocol.  Large
+//! packets show the cost of copying their content on the forwarding path.
+//!
+//! Run with `cargo bench -p iroh-relay --features server --bench forwarding`.
+
+use std::{
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use iroh_base::{NodeId, RelayUrl, SecretKey};
+use iroh_relay::{
+    client::{Client, ClientBuilder, ReceivedMessage, SendMessage},
+    dns::DnsResolver,
+    http::Protocol,
+    server::{KeepAliveConfig, RelayConfig, Server, ServerConfig},
+    MAX_PACKET_SIZE,
+};
+use n0_future::{SinkExt, StreamExt};
+
+const PROTOCOLS: [Protocol; 2] = [Protocol::Relay, Protocol::Websocket];
+const PACKET_SIZES: [usize; 3] = [1_200, 16 * 1024, MAX_PACKET_SIZE];
+
+/// The bytes forwarded per protocol and packet size, after the warmup.
+const BYTES: usize = 256 * 1024 * 1024;
+const WARMUP_BYTES: usize = 32 * 1024 * 1024;
+/// The packets sent before waiting for them to arrive.
+///
+/// Small enough for the send queue of the receiver on the server to never overflow.
+const WINDOW: usize = 64;
+/// The interval the server pings the clients at, longer than the benchmark runs.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(3600);
+
+async fn run(protocol: Protocol, packet_size: usize) -> Result<f64> {
+    let mut relay = RelayConfig::new((Ipv4Addr::LOCALHOST, 0).into());
+    relay.key_cache_capacity = Some(1024);
+    // The clients do not answer pings while forwarding.
+    relay.keep_alive = Some(KeepAliveConfig {
+        default_interval: KEEP_ALIVE_INTERVAL,
+        min_interval: KEEP_ALIVE_INTERVAL,
+        max_interval: KEEP_ALIVE_INTERVAL,
+    });
+    let server = Server::spawn(ServerConfig::<(), ()> {
+        relay: Some(relay),
+        stun: None,
+        quic: None,
+        #[cfg(feature = "metrics")]
+        metrics: Default::default(),
+        listeners: Default::default(),
+    })
+    .await?;
+    let url: RelayUrl = format!("http://{}", server.http_addr().context("http addr")?).parse()?;
+
+    let a_key = SecretKey::generate(rand::thread_rng());
+    let b_key = SecretKey::generate(rand::thread_rng());
+    let b_node_id = b_key.public();
+    let mut client_a = connect(&url, a_key, protocol).await?;
+    let mut client_b = connect(&url, b_key, protocol).await?;
+
+    let payload = Bytes::from(vec![42u8; packet_size]);
+    let warmup_packets = WARMUP_BYTES / packet_size;
+    forward(
+        &mut client_a,
+        &mut client_b,
+        b_node_id,
+        &payload,
+        warmup_packets,
+    )
+    .await?;
+    let packets = BYTES / packet_size;
+    let start = Instant::now();
+    forward(&mut client_a, &mut client_b, b_node_id, &payload, packets).await?;
+    let elapsed = start.elapsed();
+
+    server.shutdown().await?;
+    Ok((packets * packet_size) as f64 / elapsed.as_secs_f64())
+}
+
+/// Connects a client, returning once the server registered it.
+async fn connect(url: &RelayUrl, key: SecretKey, protocol: Protocol) -> Result<Client> {
+    let mut client = ClientBuilder::new(url.clone(), key, DnsResolver::new())
+        .protocol(protocol)
+        .connect()
+        .await?;
+    // The server answers pings once the client is registered, packets sent to it before
+    // are dropped.
+    client.send(SendMessage::Ping([1u8; 8])).await?;
+    match client.next().await.context("eos")?? {
+        ReceivedMessage::Pong(_) => Ok(client),
+        msg => bail!("unexpected message: {msg:?}"),
+    }
+}
+
+/// Sends `count` packets from `sender` to `receiver`, in windows of [`WINDOW`] packets.
+async fn forward(
+    sender: &mut Client,
+    receiver: &mut Client,
+    dst: NodeId,
+    payload: &Bytes,
+    count: usize,
+) -> Result<()> {
+    let mut remaining = count;
+    while remaining > 0 {
+        let window = remaining.min(WINDOW);
+        for _ in 0..window {
+            sender
+                .feed(SendMessage::SendPacket(dst, payload.clone()))
+                .await?;
+        }
+        sender.flush().await?;
+        for _ in 0..window {
+            match receiver.next().await.context("eos")?? {
+                ReceivedMessage::ReceivedPacket { data, .. } if data.len() == payload.len() => {}
+                msg => bail!("unexpected message: {msg:?}"),
+            }
+        }
+        remaining -= window;
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    for protocol in PROTOCOLS {
+        for packet_size in PACKET_SIZES {
+            let throughput = rt.block_on(run(protocol, packet_size))?;
+            println!(
+                "{protocol:?}: {packet_size} byte packets, {:.1} MiB/s",
+                throughput / (1024.0 * 1024.0)
+            );
+        }
+    }
+    Ok(())
+}
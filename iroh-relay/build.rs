@@ -5,5 +5,6 @@ fn main() {
     cfg_aliases! {
         // Convenience aliases
         wasm_browser: { all(target_family = "wasm", target_os = "unknown") },
+        zstd: { all(feature = "payload-compression", not(wasm_browser)) },
     }
 }
@@ -0,0 +1,151 @@
+//! Estimating the offset of the local clock from the clock of the relay server.
+//!
+//! The client sends its time with the handshake and the server answers with its own in its
+//! capabilities.  Assuming the server answered halfway through the round trip, the offset
+//! is known to within half the round-trip time.  Clocks which are far off break the
+//! validation of certificates and of timestamped records, such as those of the node
+//! discovery, which otherwise shows up as baffling failures.
+
+use std::sync::{Arc, Mutex};
+
+use n0_future::time::{Duration, Instant, SystemTime};
+use tracing::{debug, warn};
+
+/// Clock skews larger than this are logged as warnings.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// The offset of the clock of the relay server from the local clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSkew {
+    /// How many milliseconds the server clock is ahead of the local clock, negative if it
+    /// is behind.
+    pub offset_ms: i64,
+    /// The error bound of the offset, half the round-trip time of the handshake.
+    pub uncertainty: Duration,
+}
+
+impl ClockSkew {
+    /// Estimates the skew from the local times the request was sent at and the answer was
+    /// received at, and the server time in the answer.
+    fn estimate(sent_ms: u64, rtt: Duration, server_ms: u64) -> Self {
+        let uncertainty = rtt / 2;
+        let midpoint_ms = i128::from(sent_ms) + uncertainty.as_millis() as i128;
+        let offset_ms =
+            (i128::from(server_ms) - midpoint_ms).clamp(i64::MIN.into(), i64::MAX.into()) as i64;
+        Self {
+            offset_ms,
+            uncertainty,
+        }
+    }
+
+    /// The absolute offset between the clocks.
+    pub fn magnitude(&self) -> Duration {
+        Duration::from_millis(self.offset_ms.unsigned_abs())
+    }
+
+    /// Whether the clocks are off by more than `max`, even allowing for the uncertainty.
+    pub fn exceeds(&self, max: Duration) -> bool {
+        self.magnitude().saturating_sub(self.uncertainty) > max
+    }
+}
+
+/// The latest clock skew of a connection, shared by the connection and the client halves.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ClockSkewSlot(Arc<Mutex<Option<ClockSkew>>>);
+
+impl ClockSkewSlot {
+    /// Returns the skew, `None` until the server answered or if it did not send its time.
+    pub(crate) fn get(&self) -> Option<ClockSkew> {
+        *self.0.lock().expect("poisoned")
+    }
+}
+
+/// The connection side of the clock skew estimation.
+#[derive(Debug, Default)]
+pub(super) struct ClockProbe {
+    /// The local times the request was sent at, `None` until it is and once answered.
+    sent: Option<(u64, Instant)>,
+    skew: ClockSkewSlot,
+}
+
+impl ClockProbe {
+    /// The skew shared with the client.
+    pub(super) fn skew(&self) -> &ClockSkewSlot {
+        &self.skew
+    }
+
+    /// Records sending the request, returning the local time to send, in milliseconds
+    /// since the Unix epoch.
+    pub(super) fn start(&mut self) -> u64 {
+        let now_ms = unix_ms(SystemTime::now());
+        self.sent = Some((now_ms, Instant::now()));
+        now_ms
+    }
+
+    /// Records the time the server answered with.
+    pub(super) fn on_server_time(&mut self, server_ms: u64) {
+        let Some((sent_ms, sent)) = self.sent.take() else {
+            return;
+        };
+        let skew = ClockSkew::estimate(sent_ms, sent.elapsed(), server_ms);
+        if skew.exceeds(MAX_CLOCK_SKEW) {
+            warn!(
+                offset_ms = skew.offset_ms,
+                "the local clock is off by {:?} from the relay server, validating certificates \
+                 and timestamped records may fail",
+                skew.magnitude(),
+            );
+        } else {
+            debug!(offset_ms = skew.offset_ms, uncertainty = ?skew.uncertainty, "clock skew");
+        }
+        *self.skew.0.lock().expect("poisoned") = Some(skew);
+    }
+}
+
+/// Milliseconds since the Unix epoch, zero for earlier times.
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate() {
+        let skew = ClockSkew::estimate(10_000, Duration::from_millis(200), 10_100);
+        assert_eq!(skew.offset_ms, 0);
+        assert_eq!(skew.uncertainty, Duration::from_millis(100));
+        assert!(!skew.exceeds(Duration::ZERO));
+
+        let skew = ClockSkew::estimate(10_000, Duration::from_millis(200), 7_100);
+        assert_eq!(skew.offset_ms, -3_000);
+        assert_eq!(skew.magnitude(), Duration::from_secs(3));
+        assert!(skew.exceeds(Duration::from_secs(2)));
+        assert!(!skew.exceeds(Duration::from_millis(2_900)));
+
+        let skew = ClockSkew::estimate(u64::MAX, Duration::ZERO, 0);
+        assert_eq!(skew.offset_ms, i64::MIN);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clock_probe() {
+        let mut probe = ClockProbe::default();
+        let skew = probe.skew().clone();
+        // Answers without a request are ignored.
+        probe.on_server_time(0);
+        assert_eq!(skew.get(), None);
+
+        let sent_ms = probe.start();
+        n0_future::time::sleep(Duration::from_millis(40)).await;
+        probe.on_server_time(sent_ms + 60_020);
+        assert_eq!(
+            skew.get(),
+            Some(ClockSkew {
+                offset_ms: 60_000,
+                uncertainty: Duration::from_millis(20),
+            })
+        );
+    }
+}
@@ -5,7 +5,8 @@
 use std::{
     io,
     pin::Pin,
-    task::{Context, Poll},
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll, Waker},
 };
 
 use anyhow::{bail, Result};
@@ -17,8 +18,22 @@ use tokio_tungstenite_wasm::WebSocketStream;
 use tokio_util::codec::Framed;
 use tracing::debug;
 
-use super::KeyCache;
-use crate::protos::relay::{ClientInfo, Frame, MAX_PACKET_SIZE, PROTOCOL_VERSION};
+use super::{
+    clock_skew::{ClockProbe, ClockSkewSlot},
+    dropped::{DropReason, DroppedFrames},
+    fragments::Fragments,
+    keepalive::{PingKeepalive, PingKeepaliveConfig, Tick},
+    latency::{LatencyProber, LatencyProbes},
+    KeyCache,
+};
+use crate::protos::{
+    disco,
+    relay::{
+        Channel, ClientCapabilities, ClientInfo, ClientSoftware, Frame, KeepAliveInterval,
+        RejectReason, SendStatus, SessionToken, CHANNEL_WINDOW, MAX_PACKET_SIZE,
+        MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+};
 #[cfg(not(wasm_browser))]
 use crate::{client::streams::MaybeTlsStreamChained, protos::relay::RelayCodec};
 
@@ -33,6 +48,47 @@ pub enum ConnSendError {
     Protocol(&'static str),
 }
 
+/// Error returned when the relay server rejected the connection.
+///
+/// The server will reject any further connection attempts for the same reason, so
+/// reconnecting is pointless.  This is returned as the error of the [`Client`] stream and
+/// can be obtained using [`anyhow::Error::downcast_ref`].
+///
+/// [`Client`]: super::Client
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("connection rejected by relay server: {reason}")]
+pub struct ConnectionRejected {
+    reason: RejectReason,
+}
+
+impl ConnectionRejected {
+    /// The reason the relay server gave for rejecting the connection.
+    pub fn reason(&self) -> &RejectReason {
+        &self.reason
+    }
+}
+
+/// The keep-alive interval picked by the relay server, see [`ReceivedMessage::KeepAliveNegotiated`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NegotiatedKeepAlive {
+    /// The interval the server pings this client at while the connection is idle.
+    pub interval: Duration,
+    /// The shortest interval the server accepts.
+    pub min_interval: Duration,
+    /// The longest interval the server accepts.
+    pub max_interval: Duration,
+}
+
+impl From<KeepAliveInterval> for NegotiatedKeepAlive {
+    fn from(picked: KeepAliveInterval) -> Self {
+        Self {
+            interval: picked.interval(),
+            min_interval: Duration::from_millis(picked.min_ms.into()),
+            max_interval: Duration::from_millis(picked.max_ms.into()),
+        }
+    }
+}
+
 impl From<tokio_tungstenite_wasm::Error> for ConnSendError {
     fn from(source: tokio_tungstenite_wasm::Error) -> Self {
         let io_err = match source {
@@ -43,6 +99,101 @@ impl From<tokio_tungstenite_wasm::Error> for ConnSendError {
     }
 }
 
+/// Keeps the token of the latest session issued by the server, for the next connection.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SessionSlot(Arc<Mutex<Option<SessionToken>>>);
+
+impl SessionSlot {
+    /// Returns the token of the latest session, if any.
+    pub(crate) fn get(&self) -> Option<SessionToken> {
+        *self.0.lock().expect("poisoned")
+    }
+
+    fn set(&self, token: SessionToken) {
+        *self.0.lock().expect("poisoned") = Some(token);
+    }
+}
+
+/// The pings and window updates sent by the receiving half of a connection.
+#[derive(Debug, Default)]
+pub(crate) struct Pings {
+    keepalive: Option<PingKeepalive>,
+    latency: LatencyProber,
+    clock: ClockProbe,
+    /// The credit to grant the server, `None` unless the connection is multiplexed.
+    windows: Option<Windows>,
+    /// Whether a ping was sent which is not flushed yet.
+    flushing: bool,
+    /// The waker of a sink half waiting for the connection to accept or flush frames.
+    ///
+    /// Writing the pings replaces the waker registered with the connection, so the sink
+    /// half is woken to register it again.
+    sink_waker: Option<Waker>,
+}
+
+impl Pings {
+    /// Remembers the waker of the sink half, which waits for the connection.
+    fn sink_waiting(&mut self, waker: &Waker) {
+        match self.sink_waker {
+            Some(ref w) if w.will_wake(waker) => {}
+            _ => self.sink_waker = Some(waker.clone()),
+        }
+    }
+
+    /// Wakes the sink half after the connection was written to by the pings.
+    fn wake_sink(&mut self) {
+        if let Some(waker) = self.sink_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The packet bytes received on the flow controlled [`Channel`]s, which are not granted
+/// back to the server yet.
+///
+/// The credit is granted back once half of the window was received, so the server can
+/// keep sending while the update is in flight.
+#[derive(Debug, Default)]
+struct Windows {
+    disco: u32,
+    data: u32,
+}
+
+impl Windows {
+    fn get_mut(&mut self, channel: Channel) -> Option<&mut u32> {
+        match channel {
+            Channel::Control => None,
+            Channel::Disco => Some(&mut self.disco),
+            Channel::Data => Some(&mut self.data),
+        }
+    }
+
+    /// Records a frame received from the server.
+    fn on_received(&mut self, frame: &Frame) {
+        let (channel, len) = Channel::of(frame);
+        if let Some(received) = self.get_mut(channel) {
+            *received = received.saturating_add(len.try_into().unwrap_or(u32::MAX));
+        }
+    }
+
+    /// Returns the next window update which is due.
+    fn due(&self) -> Option<Frame> {
+        [(Channel::Disco, self.disco), (Channel::Data, self.data)]
+            .into_iter()
+            .find(|(_, received)| *received >= CHANNEL_WINDOW / 2)
+            .map(|(channel, credit)| Frame::WindowUpdate { channel, credit })
+    }
+
+    /// Records that the credit of a window update was granted.
+    fn granted(&mut self, frame: &Frame) {
+        if let Frame::WindowUpdate { channel, credit } = frame {
+            if let Some(received) = self.get_mut(*channel) {
+                *received -= credit;
+            }
+        }
+    }
+}
+
 /// A connection to a relay server.
 ///
 /// This holds a connection to a relay server.  It is:
@@ -53,18 +204,37 @@ impl From<tokio_tungstenite_wasm::Error> for ConnSendError {
 ///
 /// The [`Frame`] sink is a more internal interface, it allows performing the handshake.
 /// The [`SendMessage`] and [`ReceivedMessage`] are safer wrappers enforcing some protocol
-/// invariants.
+/// invariants.  They also take care of fragmenting and reassembling packets larger than
+/// [`MAX_PACKET_SIZE`], once the server accepted fragmentation.
 #[derive(derive_more::Debug)]
 pub(crate) enum Conn {
     #[cfg(not(wasm_browser))]
     Relay {
         #[debug("Framed<MaybeTlsStreamChained, RelayCodec>")]
         conn: Framed<MaybeTlsStreamChained, RelayCodec>,
+        /// The capabilities accepted by the server.
+        accepted: ClientCapabilities,
+        fragments: Fragments,
+        /// Where the token of the session issued by the server is kept.
+        session: Option<SessionSlot>,
+        /// Taken while the pings are sent.
+        pings: Option<Pings>,
     },
     Ws {
         #[debug("WebSocketStream")]
         conn: WebSocketStream,
         key_cache: KeyCache,
+        /// Whether sent frames are checksummed.
+        checksums: bool,
+        /// Whether the server sent a checksummed frame.
+        peer_checksums: bool,
+        /// The capabilities accepted by the server.
+        accepted: ClientCapabilities,
+        fragments: Fragments,
+        /// Where the token of the session issued by the server is kept.
+        session: Option<SessionSlot>,
+        /// Taken while the pings are sent.
+        pings: Option<Pings>,
     },
 }
 
@@ -74,11 +244,24 @@ impl Conn {
         conn: WebSocketStream,
         key_cache: KeyCache,
         secret_key: &SecretKey,
+        capabilities: ClientCapabilities,
+        session: Option<SessionSlot>,
+        software: Option<&ClientSoftware>,
+        multiplexed: bool,
     ) -> Result<Self> {
-        let mut conn = Self::Ws { conn, key_cache };
+        let mut conn = Self::Ws {
+            conn,
+            key_cache,
+            checksums: false,
+            peer_checksums: false,
+            accepted: ClientCapabilities::default(),
+            fragments: Fragments::default(),
+            session,
+            pings: Some(Pings::default()),
+        };
 
         // exchange information with the server
-        server_handshake(&mut conn, secret_key).await?;
+        server_handshake(&mut conn, secret_key, capabilities, software, multiplexed).await?;
 
         Ok(conn)
     }
@@ -89,63 +272,369 @@ impl Conn {
         conn: MaybeTlsStreamChained,
         key_cache: KeyCache,
         secret_key: &SecretKey,
+        capabilities: ClientCapabilities,
+        session: Option<SessionSlot>,
+        software: Option<&ClientSoftware>,
+        multiplexed: bool,
     ) -> Result<Self> {
         let conn = Framed::new(conn, RelayCodec::new(key_cache));
 
-        let mut conn = Self::Relay { conn };
+        let mut conn = Self::Relay {
+            conn,
+            accepted: ClientCapabilities::default(),
+            fragments: Fragments::default(),
+            session,
+            pings: Some(Pings::default()),
+        };
 
         // exchange information with the server
-        server_handshake(&mut conn, secret_key).await?;
+        server_handshake(&mut conn, secret_key, capabilities, software, multiplexed).await?;
 
         Ok(conn)
     }
+
+    /// Records the frames dropped by the connection in `dropped`.
+    pub(crate) fn set_dropped_frames(&mut self, dropped: DroppedFrames) {
+        self.fragments().set_dropped(dropped);
+    }
+
+    /// Starts pinging the server to detect when the connection is lost.
+    ///
+    /// See [`ReceivedMessage::ConnectionLost`].
+    pub(crate) fn set_ping_keepalive(&mut self, config: PingKeepaliveConfig) {
+        self.pings().keepalive = Some(PingKeepalive::new(config));
+    }
+
+    /// Starts probing the latency to the server every `interval`.
+    pub(crate) fn set_latency_probing(&mut self, interval: Duration) {
+        self.pings().latency.set_interval(interval);
+    }
+
+    /// The latency probes of the connection.
+    pub(crate) fn latency_probes(&mut self) -> LatencyProbes {
+        self.pings().latency.probes().clone()
+    }
+
+    /// The clock skew to the server, estimated from the time in its capabilities.
+    pub(crate) fn clock_skew(&mut self) -> ClockSkewSlot {
+        self.pings().clock.skew().clone()
+    }
 }
 
 /// Sends the server handshake message.
-async fn server_handshake(writer: &mut Conn, secret_key: &SecretKey) -> Result<()> {
+///
+/// With `multiplexed` the latest protocol version is requested, and the received packets
+/// are granted back to the server as credit.
+async fn server_handshake(
+    writer: &mut Conn,
+    secret_key: &SecretKey,
+    mut capabilities: ClientCapabilities,
+    software: Option<&ClientSoftware>,
+    multiplexed: bool,
+) -> Result<()> {
     debug!("server_handshake: started");
     let client_info = ClientInfo {
-        version: PROTOCOL_VERSION,
+        version: if multiplexed {
+            PROTOCOL_VERSION
+        } else {
+            MIN_PROTOCOL_VERSION
+        },
     };
-    debug!("server_handshake: sending client_key: {:?}", &client_info);
-    crate::protos::relay::send_client_key(&mut *writer, secret_key, &client_info).await?;
+    if multiplexed {
+        writer.pings().windows = Some(Windows::default());
+    }
+    capabilities.time = Some(writer.pings().clock.start());
+    debug!(
+        ?capabilities,
+        ?software,
+        "server_handshake: sending client_key: {:?}",
+        &client_info
+    );
+    crate::protos::relay::send_client_key(
+        &mut *writer,
+        secret_key,
+        &client_info,
+        &capabilities,
+        software,
+    )
+    .await?;
 
     debug!("server_handshake: done");
     Ok(())
 }
 
-impl Stream for Conn {
-    type Item = Result<ReceivedMessage>;
+impl Conn {
+    /// The capabilities accepted by the server.
+    fn accepted(&mut self) -> &mut ClientCapabilities {
+        match self {
+            #[cfg(not(wasm_browser))]
+            Self::Relay { accepted, .. } => accepted,
+            Self::Ws { accepted, .. } => accepted,
+        }
+    }
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match *self {
+    /// Where the token of the session issued by the server is kept, if sessions are used.
+    fn session(&self) -> Option<&SessionSlot> {
+        match self {
+            #[cfg(not(wasm_browser))]
+            Self::Relay { session, .. } => session.as_ref(),
+            Self::Ws { session, .. } => session.as_ref(),
+        }
+    }
+
+    /// The fragmentation state of the connection.
+    fn fragments(&mut self) -> &mut Fragments {
+        match self {
+            #[cfg(not(wasm_browser))]
+            Self::Relay { fragments, .. } => fragments,
+            Self::Ws { fragments, .. } => fragments,
+        }
+    }
+
+    /// The pings sent by the receiving half of the connection.
+    fn pings(&mut self) -> &mut Pings {
+        self.pings_slot().as_mut().expect("not sending pings")
+    }
+
+    fn pings_slot(&mut self) -> &mut Option<Pings> {
+        match self {
             #[cfg(not(wasm_browser))]
-            Self::Relay { ref mut conn } => match Pin::new(conn).poll_next(cx) {
-                Poll::Pending => Poll::Pending,
-                Poll::Ready(Some(Ok(frame))) => {
-                    let message = ReceivedMessage::try_from(frame);
-                    Poll::Ready(Some(message))
+            Self::Relay { pings, .. } => pings,
+            Self::Ws { pings, .. } => pings,
+        }
+    }
+
+    /// Sends the keep-alive pings, latency probes and window updates which are due.
+    ///
+    /// Returns the [`ReceivedMessage::ConnectionLost`] once too many pings went unanswered.
+    fn poll_pings(&mut self, cx: &mut Context<'_>) -> Option<ReceivedMessage> {
+        let mut pings = self.pings_slot().take().expect("not sending pings");
+        let mut lost = None;
+        let mut written = false;
+        if let Some(keepalive) = &mut pings.keepalive {
+            while let Poll::Ready(tick) = keepalive.poll_tick(cx) {
+                match tick {
+                    Tick::Ping(data) => {
+                        // A connection not accepting the ping is as good as one not answering.
+                        if let Poll::Ready(Ok(())) = self.poll_ready_writer(cx) {
+                            if self.start_send_frame(Frame::Ping { data }).is_ok() {
+                                pings.flushing = true;
+                            }
+                        }
+                        written = true;
+                    }
+                    Tick::Lost(unanswered_pings) => {
+                        debug!(unanswered_pings, "relay connection lost");
+                        lost = Some(ReceivedMessage::ConnectionLost { unanswered_pings });
+                    }
+                }
+            }
+        }
+        // Probes wait for the connection to accept them, to not measure the local queue.
+        while pings.latency.poll_due(cx) {
+            written = true;
+            if !matches!(self.poll_ready_writer(cx), Poll::Ready(Ok(()))) {
+                break;
+            }
+            let data = pings.latency.start().expect("due");
+            if self.start_send_frame(Frame::Ping { data }).is_err() {
+                break;
+            }
+            pings.flushing = true;
+        }
+        if let Some(windows) = &mut pings.windows {
+            while let Some(update) = windows.due() {
+                written = true;
+                if !matches!(self.poll_ready_writer(cx), Poll::Ready(Ok(()))) {
+                    break;
+                }
+                windows.granted(&update);
+                if self.start_send_frame(update).is_err() {
+                    break;
+                }
+                pings.flushing = true;
+            }
+        }
+        if pings.flushing {
+            if self.poll_flush_writer(cx).is_ready() {
+                pings.flushing = false;
+            }
+            written = true;
+        }
+        if written {
+            pings.wake_sink();
+        }
+        *self.pings_slot() = Some(pings);
+        lost
+    }
+
+    /// Polls the connection to accept a frame, without recording the waker of a sink half.
+    fn poll_ready_writer(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ConnSendError>> {
+        match self {
+            #[cfg(not(wasm_browser))]
+            Self::Relay { conn, .. } => {
+                <_ as Sink<Frame>>::poll_ready(Pin::new(conn), cx).map_err(Into::into)
+            }
+            Self::Ws { conn, .. } => Pin::new(conn).poll_ready(cx).map_err(Into::into),
+        }
+    }
+
+    /// Polls the connection to flush the frames, without recording the waker of a sink half.
+    fn poll_flush_writer(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ConnSendError>> {
+        match self {
+            #[cfg(not(wasm_browser))]
+            Self::Relay { conn, .. } => {
+                <_ as Sink<Frame>>::poll_flush(Pin::new(conn), cx).map_err(Into::into)
+            }
+            Self::Ws { conn, .. } => Pin::new(conn).poll_flush(cx).map_err(Into::into),
+        }
+    }
+
+    /// Records the waker of a sink half waiting for the connection.
+    fn sink_waiting<T>(&mut self, res: Poll<T>, cx: &mut Context<'_>) -> Poll<T> {
+        if res.is_pending() {
+            self.pings().sink_waiting(cx.waker());
+        }
+        res
+    }
+
+    /// Polls the next frame received from the server.
+    fn poll_next_frame(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Frame>>> {
+        match self {
+            #[cfg(not(wasm_browser))]
+            Self::Relay { conn, .. } => Pin::new(conn).poll_next(cx),
+            Self::Ws {
+                conn,
+                key_cache,
+                checksums,
+                peer_checksums,
+                ..
+            } => loop {
+                match ready!(Pin::new(&mut *conn).poll_next(cx)) {
+                    Some(Ok(tokio_tungstenite_wasm::Message::Binary(vec))) => {
+                        let frame = Frame::decode_from_ws_msg(vec, key_cache, peer_checksums);
+                        // The server supports checksums, protect our frames as well.
+                        *checksums |= *peer_checksums;
+                        return Poll::Ready(Some(frame));
+                    }
+                    Some(Ok(msg)) => {
+                        tracing::warn!(
+                            ?msg,
+                            "Got websocket message of unsupported type, skipping."
+                        );
+                    }
+                    Some(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                    None => return Poll::Ready(None),
                 }
-                Poll::Ready(Some(Err(err))) => Poll::Ready(Some(Err(err))),
-                Poll::Ready(None) => Poll::Ready(None),
             },
+        }
+    }
+
+    /// Starts sending a frame, without any validation.
+    fn start_send_frame(&mut self, frame: Frame) -> Result<(), ConnSendError> {
+        match self {
+            #[cfg(not(wasm_browser))]
+            Self::Relay { conn, .. } => Pin::new(conn).start_send(frame).map_err(Into::into),
             Self::Ws {
-                ref mut conn,
-                ref key_cache,
-            } => match Pin::new(conn).poll_next(cx) {
-                Poll::Ready(Some(Ok(tokio_tungstenite_wasm::Message::Binary(vec)))) => {
-                    let frame = Frame::decode_from_ws_msg(vec, key_cache);
-                    let message = frame.and_then(ReceivedMessage::try_from);
-                    Poll::Ready(Some(message))
+                conn, checksums, ..
+            } => Pin::new(conn)
+                .start_send(tokio_tungstenite_wasm::Message::binary(
+                    frame.encode_for_ws_msg(*checksums),
+                ))
+                .map_err(Into::into),
+        }
+    }
+}
+
+impl Stream for Conn {
+    type Item = Result<ReceivedMessage>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(lost) = self.poll_pings(cx) {
+                self.pings().latency.close();
+                return Poll::Ready(Some(Ok(lost)));
+            }
+            if self.pings().keepalive.as_ref().is_some_and(|k| k.is_lost()) {
+                return Poll::Ready(None);
+            }
+            let frame = match ready!(self.poll_next_frame(cx)) {
+                Some(Ok(frame)) => frame,
+                Some(Err(err)) => {
+                    self.pings().latency.close();
+                    return Poll::Ready(Some(Err(err)));
                 }
-                Poll::Ready(Some(Ok(msg))) => {
-                    tracing::warn!(?msg, "Got websocket message of unsupported type, skipping.");
-                    Poll::Pending
+                None => {
+                    self.pings().latency.close();
+                    return Poll::Ready(None);
                 }
-                Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
-                Poll::Ready(None) => Poll::Ready(None),
-                Poll::Pending => Poll::Pending,
-            },
+            };
+            let pings = self.pings();
+            let keepalive_pong = pings
+                .keepalive
+                .as_mut()
+                .is_some_and(|k| k.on_received(&frame));
+            if keepalive_pong || pings.latency.on_received(&frame) {
+                continue;
+            }
+            if let Some(windows) = &mut pings.windows {
+                windows.on_received(&frame);
+            }
+            match frame {
+                Frame::Capabilities { capabilities } => {
+                    debug!(?capabilities, "server accepted capabilities");
+                    if let (Some(slot), Some(token)) = (self.session(), capabilities.session_token)
+                    {
+                        slot.set(token);
+                    }
+                    if let Some(time) = capabilities.time {
+                        self.pings().clock.on_server_time(time);
+                    }
+                    let keep_alive = capabilities.keep_alive;
+                    *self.accepted() = capabilities;
+                    if let Some(keep_alive) = keep_alive {
+                        return Poll::Ready(Some(Ok(ReceivedMessage::KeepAliveNegotiated(
+                            keep_alive.into(),
+                        ))));
+                    }
+                }
+                Frame::RecvFragment { src_key, fragment } => {
+                    match self.fragments().reassemble(src_key, fragment) {
+                        Ok(Some(data)) => {
+                            return Poll::Ready(Some(Ok(ReceivedMessage::ReceivedPacket {
+                                remote_node_id: src_key,
+                                data,
+                            })));
+                        }
+                        Ok(None) => {}
+                        Err(err) => {
+                            debug!(src = %src_key.fmt_short(), "dropping invalid fragment: {err:#}");
+                            self.fragments()
+                                .dropped()
+                                .record(Some(src_key), DropReason::InvalidFragment);
+                        }
+                    }
+                }
+                Frame::RecvCompressedPacket {
+                    src_key,
+                    compression,
+                    packet,
+                } => match compression.decompress(&packet) {
+                    Ok(data) => {
+                        return Poll::Ready(Some(Ok(ReceivedMessage::ReceivedPacket {
+                            remote_node_id: src_key,
+                            data,
+                        })));
+                    }
+                    Err(err) => {
+                        debug!(src = %src_key.fmt_short(), "dropping invalid compressed packet: {err:#}");
+                        self.fragments()
+                            .dropped()
+                            .record(Some(src_key), DropReason::InvalidCompression);
+                    }
+                },
+                frame => return Poll::Ready(Some(ReceivedMessage::try_from(frame))),
+            }
         }
     }
 }
@@ -154,42 +643,31 @@ impl Sink<Frame> for Conn {
     type Error = ConnSendError;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        match *self {
-            #[cfg(not(wasm_browser))]
-            Self::Relay { ref mut conn } => Pin::new(conn).poll_ready(cx).map_err(Into::into),
-            Self::Ws { ref mut conn, .. } => Pin::new(conn).poll_ready(cx).map_err(Into::into),
-        }
+        let res = self.poll_ready_writer(cx);
+        self.sink_waiting(res, cx)
     }
 
     fn start_send(mut self: Pin<&mut Self>, frame: Frame) -> Result<(), Self::Error> {
-        if let Frame::SendPacket { dst_key: _, packet } = &frame {
+        if let Frame::SendPacket { packet, .. }
+        | Frame::SendAckedPacket { packet, .. }
+        | Frame::ForwardPacket { packet, .. } = &frame
+        {
             if packet.len() > MAX_PACKET_SIZE {
                 return Err(ConnSendError::Protocol("Packet exceeds MAX_PACKET_SIZE"));
             }
         }
-        match *self {
-            #[cfg(not(wasm_browser))]
-            Self::Relay { ref mut conn } => Pin::new(conn).start_send(frame).map_err(Into::into),
-            Self::Ws { ref mut conn, .. } => Pin::new(conn)
-                .start_send(tokio_tungstenite_wasm::Message::binary(
-                    frame.encode_for_ws_msg(),
-                ))
-                .map_err(Into::into),
-        }
+        self.start_send_frame(frame)
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        match *self {
-            #[cfg(not(wasm_browser))]
-            Self::Relay { ref mut conn } => Pin::new(conn).poll_flush(cx).map_err(Into::into),
-            Self::Ws { ref mut conn, .. } => Pin::new(conn).poll_flush(cx).map_err(Into::into),
-        }
+        let res = self.poll_flush_writer(cx);
+        self.sink_waiting(res, cx)
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         match *self {
             #[cfg(not(wasm_browser))]
-            Self::Relay { ref mut conn } => Pin::new(conn).poll_close(cx).map_err(Into::into),
+            Self::Relay { ref mut conn, .. } => Pin::new(conn).poll_close(cx).map_err(Into::into),
             Self::Ws { ref mut conn, .. } => Pin::new(conn).poll_close(cx).map_err(Into::into),
         }
     }
@@ -199,43 +677,74 @@ impl Sink<SendMessage> for Conn {
     type Error = ConnSendError;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        match *self {
-            #[cfg(not(wasm_browser))]
-            Self::Relay { ref mut conn } => Pin::new(conn).poll_ready(cx).map_err(Into::into),
-            Self::Ws { ref mut conn, .. } => Pin::new(conn).poll_ready(cx).map_err(Into::into),
-        }
+        let res = self.poll_ready_writer(cx);
+        self.sink_waiting(res, cx)
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: SendMessage) -> Result<(), Self::Error> {
-        if let SendMessage::SendPacket(_, bytes) = &item {
-            if bytes.len() > MAX_PACKET_SIZE {
-                return Err(ConnSendError::Protocol("Packet exceeds MAX_PACKET_SIZE"));
+        match item {
+            SendMessage::SendPacket(dst_key, bytes) if bytes.len() > MAX_PACKET_SIZE => {
+                if !self.accepted().fragments {
+                    return Err(ConnSendError::Protocol("Packet exceeds MAX_PACKET_SIZE"));
+                }
+                // Both sinks buffer the frames, so all fragments can be sent at once.
+                for fragment in self.fragments().split(&bytes)? {
+                    self.start_send_frame(Frame::SendFragment { dst_key, fragment })?;
+                }
+                Ok(())
             }
-        }
-        let frame = Frame::from(item);
-        match *self {
-            #[cfg(not(wasm_browser))]
-            Self::Relay { ref mut conn } => Pin::new(conn).start_send(frame).map_err(Into::into),
-            Self::Ws { ref mut conn, .. } => Pin::new(conn)
-                .start_send(tokio_tungstenite_wasm::Message::binary(
-                    frame.encode_for_ws_msg(),
-                ))
-                .map_err(Into::into),
+            SendMessage::SendPacket(dst_key, packet) => {
+                let compressed = self
+                    .accepted()
+                    .compression
+                    .filter(|_| !disco::looks_like_disco_wrapper(&packet))
+                    .and_then(|c| Some((c, c.compress(&packet)?)));
+                match compressed {
+                    Some((compression, packet)) => {
+                        self.start_send_frame(Frame::SendCompressedPacket {
+                            dst_key,
+                            compression,
+                            packet,
+                        })
+                    }
+                    None => self.start_send_frame(Frame::SendPacket { dst_key, packet }),
+                }
+            }
+            SendMessage::SendAckedPacket { ref packet, .. } => {
+                if !self.accepted().send_acks {
+                    return Err(ConnSendError::Protocol(
+                        "Server does not acknowledge packets",
+                    ));
+                }
+                if packet.len() > MAX_PACKET_SIZE {
+                    return Err(ConnSendError::Protocol("Packet exceeds MAX_PACKET_SIZE"));
+                }
+                self.start_send_frame(Frame::from(item))
+            }
+            SendMessage::WatchConns | SendMessage::ForwardPacket { .. } => {
+                if self.accepted().mesh_proof.is_none() {
+                    return Err(ConnSendError::Protocol("Not a trusted client"));
+                }
+                if let SendMessage::ForwardPacket { ref packet, .. } = item {
+                    if packet.len() > MAX_PACKET_SIZE {
+                        return Err(ConnSendError::Protocol("Packet exceeds MAX_PACKET_SIZE"));
+                    }
+                }
+                self.start_send_frame(Frame::from(item))
+            }
+            item => self.start_send_frame(Frame::from(item)),
         }
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        match *self {
-            #[cfg(not(wasm_browser))]
-            Self::Relay { ref mut conn } => Pin::new(conn).poll_flush(cx).map_err(Into::into),
-            Self::Ws { ref mut conn, .. } => Pin::new(conn).poll_flush(cx).map_err(Into::into),
-        }
+        let res = self.poll_flush_writer(cx);
+        self.sink_waiting(res, cx)
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         match *self {
             #[cfg(not(wasm_browser))]
-            Self::Relay { ref mut conn } => Pin::new(conn).poll_close(cx).map_err(Into::into),
+            Self::Relay { ref mut conn, .. } => Pin::new(conn).poll_close(cx).map_err(Into::into),
             Self::Ws { ref mut conn, .. } => Pin::new(conn).poll_close(cx).map_err(Into::into),
         }
     }
@@ -257,6 +766,11 @@ pub enum ReceivedMessage {
     /// Indicates that the client identified by the underlying public key had previously sent you a
     /// packet but has now disconnected from the server.
     NodeGone(NodeId),
+    /// Indicates that the client identified by the underlying public key connected to the
+    /// server.
+    ///
+    /// Only sent to trusted clients after a [`SendMessage::WatchConns`].
+    PeerPresent(NodeId),
     /// Request from a client or server to reply to the
     /// other side with a [`ReceivedMessage::Pong`] with the given payload.
     Ping([u8; 8]),
@@ -277,6 +791,50 @@ pub enum ReceivedMessage {
         /// until a problem exists.
         problem: Option<String>,
     },
+    /// The server's acknowledgement of a [`SendMessage::SendAckedPacket`].
+    SendAck {
+        /// The id of the acknowledged packet.
+        id: u32,
+        /// What the server did with the packet.
+        status: SendStatus,
+    },
+    /// The send queue of a destination this client sends to crossed a watermark on the server.
+    ///
+    /// Only sent if the server accepted [`ClientBuilder::send_queue_status`].  Once a
+    /// destination is congested, further packets to it are likely dropped by the server
+    /// until it is ready again.
+    ///
+    /// [`ClientBuilder::send_queue_status`]: crate::client::ClientBuilder::send_queue_status
+    SendQueueStatus {
+        /// The destination, as addressed by this client.
+        dst: NodeId,
+        /// Whether the destination is ready to receive packets, `false` if it is congested.
+        ready: bool,
+    },
+    /// A packet of this client was dropped as its destination is not connected to the server.
+    ///
+    /// Only sent if the server accepted [`ClientBuilder::unknown_peer_notifications`].
+    ///
+    /// [`ClientBuilder::unknown_peer_notifications`]: crate::client::ClientBuilder::unknown_peer_notifications
+    UnknownPeer(NodeId),
+    /// The server picked the interval it pings this client at.
+    ///
+    /// Only sent if the client requested an interval with
+    /// [`ClientBuilder::keep_alive_interval`] and the server supports it, right after the
+    /// handshake.
+    ///
+    /// [`ClientBuilder::keep_alive_interval`]: crate::client::ClientBuilder::keep_alive_interval
+    KeepAliveNegotiated(NegotiatedKeepAlive),
+    /// The connection is considered lost, as the server did not answer the keep-alive pings.
+    ///
+    /// Only yielded if enabled with [`ClientBuilder::ping_keepalive`].  The stream ends
+    /// after this, the client should be closed and a new connection established.
+    ///
+    /// [`ClientBuilder::ping_keepalive`]: crate::client::ClientBuilder::ping_keepalive
+    ConnectionLost {
+        /// The number of consecutive pings which went unanswered.
+        unanswered_pings: u32,
+    },
     /// A one-way message from server to client, advertising that the server is restarting.
     ServerRestarting {
         /// An advisory duration that the client should wait before attempting to reconnect.
@@ -301,6 +859,7 @@ impl TryFrom<Frame> for ReceivedMessage {
                 Ok(ReceivedMessage::KeepAlive)
             }
             Frame::NodeGone { node_id } => Ok(ReceivedMessage::NodeGone(node_id)),
+            Frame::PeerPresent { node_id } => Ok(ReceivedMessage::PeerPresent(node_id)),
             Frame::RecvPacket { src_key, content } => {
                 let packet = ReceivedMessage::ReceivedPacket {
                     remote_node_id: src_key,
@@ -326,6 +885,13 @@ impl TryFrom<Frame> for ReceivedMessage {
                     try_for,
                 })
             }
+            Frame::SendAck { id, status } => Ok(ReceivedMessage::SendAck { id, status }),
+            Frame::SendQueueStatus { dst_key, ready } => Ok(ReceivedMessage::SendQueueStatus {
+                dst: dst_key,
+                ready,
+            }),
+            Frame::UnknownPeer { dst_key } => Ok(ReceivedMessage::UnknownPeer(dst_key)),
+            Frame::Error { reason } => Err(ConnectionRejected { reason }.into()),
             _ => bail!("unexpected packet: {:?}", frame.typ()),
         }
     }
@@ -336,18 +902,89 @@ impl TryFrom<Frame> for ReceivedMessage {
 pub enum SendMessage {
     /// Send a packet of data to the [`NodeId`].
     SendPacket(NodeId, Bytes),
+    /// Send a packet of data to the [`NodeId`], asking the server to acknowledge it.
+    ///
+    /// The server responds with a [`ReceivedMessage::SendAck`] carrying the same `id`.  Only
+    /// supported if the server accepted acknowledged sends, see
+    /// [`ClientBuilder::send_acks`].
+    ///
+    /// [`ClientBuilder::send_acks`]: crate::client::ClientBuilder::send_acks
+    SendAckedPacket {
+        /// Identifies the packet in the acknowledgement.
+        id: u32,
+        /// The destination of the packet.
+        dst: NodeId,
+        /// The packet bytes.
+        packet: Bytes,
+    },
+    /// Subscribes to the connections of all nodes.
+    ///
+    /// The server responds with a [`ReceivedMessage::PeerPresent`] for every connected
+    /// node, and keeps sending [`ReceivedMessage::PeerPresent`] and
+    /// [`ReceivedMessage::NodeGone`] when nodes connect and disconnect.  Only supported for
+    /// trusted clients, see [`ClientBuilder::mesh_key`].
+    ///
+    /// [`ClientBuilder::mesh_key`]: crate::client::ClientBuilder::mesh_key
+    WatchConns,
+    /// Send a packet of data to `dst` on behalf of `src`.
+    ///
+    /// Used to relay packets between relay servers.  Only supported for trusted clients,
+    /// see [`ClientBuilder::mesh_key`].
+    ///
+    /// [`ClientBuilder::mesh_key`]: crate::client::ClientBuilder::mesh_key
+    ForwardPacket {
+        /// The node the packet originates from.
+        src: NodeId,
+        /// The destination of the packet.
+        dst: NodeId,
+        /// How many times the packet was forwarded between relay servers, including this
+        /// time.
+        ///
+        /// Starts at 1 when forwarding a packet received from a node, the server drops
+        /// packets with more than [`MAX_FORWARD_HOPS`] hops.
+        ///
+        /// [`MAX_FORWARD_HOPS`]: crate::protos::relay::MAX_FORWARD_HOPS
+        hops: u8,
+        /// The packet bytes.
+        packet: Bytes,
+    },
     /// Sends a ping message to the connected relay server.
     Ping([u8; 8]),
     /// Sends a pong message to the connected relay server.
     Pong([u8; 8]),
+    /// Tells the relay server that the client is about to disconnect.
+    ///
+    /// This is sent automatically when a [`Client`] or [`ClientSink`] is closed.
+    ///
+    /// [`Client`]: crate::client::Client
+    /// [`ClientSink`]: crate::client::ClientSink
+    Closing,
 }
 
 impl From<SendMessage> for Frame {
     fn from(source: SendMessage) -> Self {
         match source {
             SendMessage::SendPacket(dst_key, packet) => Frame::SendPacket { dst_key, packet },
+            SendMessage::SendAckedPacket { id, dst, packet } => Frame::SendAckedPacket {
+                dst_key: dst,
+                id,
+                packet,
+            },
+            SendMessage::WatchConns => Frame::WatchConns,
+            SendMessage::ForwardPacket {
+                src,
+                dst,
+                hops,
+                packet,
+            } => Frame::ForwardPacket {
+                src_key: src,
+                dst_key: dst,
+                hops,
+                packet,
+            },
             SendMessage::Ping(data) => Frame::Ping { data },
             SendMessage::Pong(data) => Frame::Pong { data },
+            SendMessage::Closing => Frame::Closing,
         }
     }
 }
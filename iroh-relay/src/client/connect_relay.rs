@@ -4,64 +4,67 @@
 //! (2) doesn't work in the browser - thus separated into its own file.
 //!
 //! `connect_relay` uses a custom HTTP upgrade header value (see [`HTTP_UPGRADE_PROTOCOL`]),
-//! as opposed to [`WEBSOCKET_UPGRADE_PROTOCOL`].
+//! as opposed to [`WEBSOCKET_UPGRADE_PROTOCOL`], or skips HTTP entirely when the server
+//! negotiates the [`RELAY_ALPN`].
 //! However, this code path also contains support for HTTP(s) proxies, which is
 //! why it still remains the default code path as of now.
 //!
 //! [`HTTP_UPGRADE_PROTOCOL`]: crate::http::HTTP_UPGRADE_PROTOCOL
 //! [`WEBSOCKET_UPGRADE_PROTOCOL`]: crate::http::WEBSOCKET_UPGRADE_PROTOCOL
+//! [`RELAY_ALPN`]: crate::http::RELAY_ALPN
 
 // Based on tailscale/derp/derphttp/derphttp_client.go
 
-use anyhow::Context;
+use anyhow::{ensure, Context};
 use bytes::Bytes;
-use data_encoding::BASE64URL;
+use data_encoding::BASE64;
 use http_body_util::Empty;
 use hyper::{
     body::Incoming,
     header::{HOST, UPGRADE},
     upgrade::Parts,
-    Request,
+    Method, Request,
 };
 use n0_future::{task, time};
-use rustls::client::Resumption;
+use rustls::client::{EchConfig, EchMode, Resumption};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tracing::{error, info_span, Instrument};
+use tracing::{error, info_span, warn, Instrument};
 
 use super::{
-    streams::{downcast_upgrade, MaybeTlsStream, ProxyStream},
+    socks::{self, TargetAddr},
+    streams::{downcast_upgrade, MaybeTlsStream, MaybeTlsStreamChained, ProxyStream},
     *,
 };
-use crate::defaults::timeouts::*;
+use crate::{
+    defaults::timeouts::*,
+    http::{HTTP_1_1_ALPN, RELAY_ALPN},
+};
 
 impl ClientBuilder {
     /// Connects to configured relay using HTTP(S) with an upgrade header
     /// set to [`HTTP_UPGRADE_PROTOCOL`].
     ///
+    /// When [`ClientBuilder::http_connect_tunnel`] is enabled a `CONNECT` request is used
+    /// instead of the upgrade.  If the server negotiates the [`RELAY_ALPN`] during the TLS
+    /// handshake no HTTP request is sent at all, the relay protocol starts right away.
+    ///
+    /// The durations of the steps are recorded in `timing`.
+    ///
     /// [`HTTP_UPGRADE_PROTOCOL`]: crate::http::HTTP_UPGRADE_PROTOCOL
-    pub(super) async fn connect_relay(&self) -> Result<(Conn, SocketAddr)> {
-        let roots = rustls::RootCertStore {
-            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
-        };
-        let mut config = rustls::client::ClientConfig::builder_with_provider(Arc::new(
-            rustls::crypto::ring::default_provider(),
-        ))
-        .with_safe_default_protocol_versions()
-        .expect("protocols supported by ring")
-        .with_root_certificates(roots)
-        .with_no_client_auth();
-        #[cfg(any(test, feature = "test-utils"))]
-        if self.insecure_skip_cert_verify {
-            warn!("Insecure config: SSL certificates from relay servers not verified");
-            config
-                .dangerous()
-                .set_certificate_verifier(Arc::new(NoCertVerifier));
-        }
-        config.resumption = Resumption::default();
-        let tls_connector: tokio_rustls::TlsConnector = Arc::new(config).into();
+    pub(super) async fn connect_relay(
+        &self,
+        timing: &mut ConnectTiming,
+    ) -> Result<(Conn, SocketAddr)> {
+        let dial_target = self.dial_target()?;
+        let tls_connector: tokio_rustls::TlsConnector = self.rustls_client_config(None).into();
+        // ECH configs and the relay ALPN are specific to the relay server, so the TLS
+        // connection to a proxy must not use them.
+        let ech = self.ech_config(&dial_target).await;
+        let relay_tls_connector: tokio_rustls::TlsConnector =
+            with_relay_alpns(self.rustls_client_config(ech)).into();
 
         let url = self.url.clone();
-        let tcp_stream = self.dial_url(&tls_connector).await?;
+        let tcp_stream = self.dial_url(&dial_target, &tls_connector, timing).await?;
 
         let local_addr = tcp_stream
             .local_addr()
@@ -69,24 +72,140 @@ impl ClientBuilder {
 
         debug!(server_addr = ?tcp_stream.peer_addr(), %local_addr, "TCP stream connected");
 
-        let response = if self.use_tls() {
+        let conn = if self.use_tls() {
             debug!("Starting TLS handshake");
-            let hostname = self
-                .tls_servername()
-                .ok_or_else(|| anyhow!("No tls servername"))?;
+            let hostname =
+                tls_servername(&dial_target).ok_or_else(|| anyhow!("No tls servername"))?;
             let hostname = hostname.to_owned();
-            let tls_stream = tls_connector.connect(hostname, tcp_stream).await?;
-            debug!("tls_connector connect success");
-            Self::start_upgrade(tls_stream, url).await?
+            let start = Instant::now();
+            let tls_stream = relay_tls_connector.connect(hostname, tcp_stream).await?;
+            timing.tls = Some(start.elapsed());
+            let alpn = tls_stream.get_ref().1.alpn_protocol();
+            debug!(alpn = ?alpn.map(String::from_utf8_lossy), "tls_connector connect success");
+            if alpn == Some(RELAY_ALPN) {
+                // The server speaks the relay protocol right away, skip the HTTP upgrade.
+                debug!("using direct framing");
+                MaybeTlsStreamChained::Tls(util::chain(
+                    std::io::Cursor::new(Bytes::new()),
+                    tls_stream,
+                ))
+            } else {
+                let start = Instant::now();
+                let response =
+                    Self::start_upgrade(tls_stream, url, self.http_connect_tunnel).await?;
+                let conn = self.finish_upgrade(response).await?;
+                timing.upgrade = Some(start.elapsed());
+                conn
+            }
         } else {
             debug!("Starting handshake");
-            Self::start_upgrade(tcp_stream, url).await?
+            let start = Instant::now();
+            let response = Self::start_upgrade(tcp_stream, url, self.http_connect_tunnel).await?;
+            let conn = self.finish_upgrade(response).await?;
+            timing.upgrade = Some(start.elapsed());
+            conn
+        };
+        #[cfg(any(test, feature = "test-utils"))]
+        let conn = match &self.faults {
+            Some(faults) => MaybeTlsStreamChained::Faulty(Box::new(
+                crate::faults::FaultyStream::new(conn, faults.clone()),
+            )),
+            None => conn,
+        };
+
+        let start = Instant::now();
+        let conn = Conn::new_relay(
+            conn,
+            self.key_cache.clone(),
+            &self.secret_key,
+            self.capabilities(),
+            self.session.clone(),
+            self.software.as_ref(),
+            self.multiplexed_channels,
+        )
+        .await?;
+        timing.handshake = start.elapsed();
+
+        Ok((conn, local_addr))
+    }
+
+    /// Returns the ECH config for the relay server, if ECH is enabled and available.
+    ///
+    /// Failing to find a usable ECH config is not an error, we fall back to plain TLS.
+    async fn ech_config(&self, dial_target: &Url) -> Option<EchConfig> {
+        let hpke_suites = self.ech_hpke_suites?;
+        if self.rustls_config.is_some() || !self.use_tls() {
+            return None;
+        }
+        let host = dial_target.host_str()?;
+        let ech_config_list = match self
+            .dns_resolver
+            .lookup_ech_config_list(host, DNS_TIMEOUT)
+            .await
+        {
+            Ok(Some(ech_config_list)) => ech_config_list,
+            Ok(None) => {
+                debug!(%host, "no ECH config published, using plain TLS");
+                return None;
+            }
+            Err(err) => {
+                debug!(%host, "failed to lookup ECH config, using plain TLS: {err:#}");
+                return None;
+            }
+        };
+        match EchConfig::new(ech_config_list.into(), hpke_suites) {
+            Ok(ech) => Some(ech),
+            Err(err) => {
+                warn!(%host, "unusable ECH config, using plain TLS: {err:#}");
+                None
+            }
+        }
+    }
+
+    /// Returns the TLS config to use, either the custom one or our default.
+    pub(super) fn rustls_client_config(&self, ech: Option<EchConfig>) -> Arc<rustls::ClientConfig> {
+        if let Some(ref config) = self.rustls_config {
+            return config.clone();
+        }
+        let roots = rustls::RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let builder = rustls::client::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ));
+        let builder = match ech {
+            Some(ech) => builder.with_ech(EchMode::Enable(ech)),
+            None => builder.with_safe_default_protocol_versions(),
         };
+        let mut config = builder
+            .expect("protocols supported by ring")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        #[cfg(any(test, feature = "test-utils"))]
+        if self.insecure_skip_cert_verify {
+            warn!("Insecure config: SSL certificates from relay servers not verified");
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoCertVerifier));
+        }
+        config.resumption = Resumption::default();
+        Arc::new(config)
+    }
 
-        if response.status() != hyper::StatusCode::SWITCHING_PROTOCOLS {
+    /// Checks the response to the HTTP upgrade request and takes over the connection.
+    async fn finish_upgrade(
+        &self,
+        response: hyper::Response<Incoming>,
+    ) -> Result<MaybeTlsStreamChained> {
+        let expected_status = if self.http_connect_tunnel {
+            hyper::StatusCode::OK
+        } else {
+            hyper::StatusCode::SWITCHING_PROTOCOLS
+        };
+        if response.status() != expected_status {
             bail!(
                 "Unexpected status code: expected {}, actual: {}",
-                hyper::StatusCode::SWITCHING_PROTOCOLS,
+                expected_status,
                 response.status(),
             );
         }
@@ -97,19 +216,26 @@ impl ClientBuilder {
             .context("Upgrade failed")?;
 
         debug!("connection upgraded");
-        let conn = downcast_upgrade(upgraded)?;
-
-        let conn = Conn::new_relay(conn, self.key_cache.clone(), &self.secret_key).await?;
-
-        Ok((conn, local_addr))
+        downcast_upgrade(upgraded)
     }
 
     /// Sends the HTTP upgrade request to the relay server.
-    async fn start_upgrade<T>(io: T, relay_url: RelayUrl) -> Result<hyper::Response<Incoming>>
+    ///
+    /// With `connect_tunnel` a `CONNECT` request for the relay's authority is sent instead.
+    async fn start_upgrade<T>(
+        io: T,
+        relay_url: RelayUrl,
+        connect_tunnel: bool,
+    ) -> Result<hyper::Response<Incoming>>
     where
         T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
     {
         use hyper_util::rt::TokioIo;
+        let connect_authority = if connect_tunnel {
+            Some(connect_authority(&relay_url)?)
+        } else {
+            None
+        };
         let host_header_value = host_header_value(relay_url)?;
 
         let io = TokioIo::new(io);
@@ -127,6 +253,15 @@ impl ClientBuilder {
             }
             .instrument(info_span!("http-driver")),
         );
+        if let Some(authority) = connect_authority {
+            debug!("Sending CONNECT request");
+            let req = Request::builder()
+                .method(Method::CONNECT)
+                .uri(authority)
+                .header(HOST, host_header_value)
+                .body(http_body_util::Empty::<hyper::body::Bytes>::new())?;
+            return request_sender.send_request(req).await.map_err(From::from);
+        }
         debug!("Sending upgrade request");
         let req = Request::builder()
             .uri(RELAY_PATH)
@@ -139,68 +274,136 @@ impl ClientBuilder {
         request_sender.send_request(req).await.map_err(From::from)
     }
 
-    fn tls_servername(&self) -> Option<rustls::pki_types::ServerName> {
-        self.url
-            .host_str()
-            .and_then(|s| rustls::pki_types::ServerName::try_from(s).ok())
+    /// Returns the URL to establish the TCP and TLS connection to.
+    ///
+    /// This is the relay URL, unless a fronting domain is configured in which case its host
+    /// is replaced by the fronting domain.
+    fn dial_target(&self) -> Result<Url> {
+        let mut url: Url = (*self.url).clone();
+        if let Some(ref front) = self.fronting_domain {
+            url.set_host(Some(front))
+                .with_context(|| format!("Invalid fronting domain: {front}"))?;
+        }
+        Ok(url)
     }
 
-    async fn dial_url(&self, tls_connector: &tokio_rustls::TlsConnector) -> Result<ProxyStream> {
+    async fn dial_url(
+        &self,
+        dial_target: &Url,
+        tls_connector: &tokio_rustls::TlsConnector,
+        timing: &mut ConnectTiming,
+    ) -> Result<ProxyStream> {
         if let Some(ref proxy) = self.proxy_url {
-            let stream = self.dial_url_proxy(proxy.clone(), tls_connector).await?;
+            let stream = self
+                .dial_url_proxy(proxy.clone(), dial_target, tls_connector, timing)
+                .await?;
             Ok(ProxyStream::Proxied(stream))
         } else {
-            let stream = self.dial_url_direct().await?;
+            let stream = self.dial_url_direct(dial_target, timing).await?;
             Ok(ProxyStream::Raw(stream))
         }
     }
 
-    async fn dial_url_direct(&self) -> Result<tokio::net::TcpStream> {
+    async fn dial_url_direct(
+        &self,
+        dial_target: &Url,
+        timing: &mut ConnectTiming,
+    ) -> Result<tokio::net::TcpStream> {
         use tokio::net::TcpStream;
-        debug!(%self.url, "dial url");
+        debug!(%self.url, %dial_target, "dial url");
         let prefer_ipv6 = self.prefer_ipv6();
-        let dst_ip = self
+        let start = Instant::now();
+        let dst_ips = self
             .dns_resolver
-            .resolve_host(&self.url, prefer_ipv6, DNS_TIMEOUT)
+            .resolve_host_addrs(dial_target, prefer_ipv6, DNS_TIMEOUT)
             .await?;
+        timing.dns = Some(start.elapsed());
+        let port = url_port(dial_target).ok_or_else(|| anyhow!("Missing URL port"))?;
+
+        // Try the first address of each family, in the order given by the resolver.  If
+        // IPv6 fails but IPv4 works the resolver learns that IPv6 is broken.
+        let (mut seen_v4, mut seen_v6) = (false, false);
+        let candidates = dst_ips.into_iter().filter(|ip| {
+            let seen = if ip.is_ipv4() {
+                &mut seen_v4
+            } else {
+                &mut seen_v6
+            };
+            !std::mem::replace(seen, true)
+        });
 
-        let port = url_port(&self.url).ok_or_else(|| anyhow!("Missing URL port"))?;
-        let addr = SocketAddr::new(dst_ip, port);
-
-        debug!("connecting to {}", addr);
-        let tcp_stream = time::timeout(
-            DIAL_NODE_TIMEOUT,
-            async move { TcpStream::connect(addr).await },
-        )
-        .await
-        .context("Timeout connecting")?
-        .context("Failed connecting")?;
-        tcp_stream.set_nodelay(true)?;
-
-        Ok(tcp_stream)
+        let mut ipv6_failed = false;
+        let mut last_err = None;
+        let start = Instant::now();
+        for dst_ip in candidates {
+            let addr = SocketAddr::new(dst_ip, port);
+            debug!("connecting to {}", addr);
+            let res = time::timeout(DIAL_NODE_TIMEOUT, TcpStream::connect(addr))
+                .await
+                .context("Timeout connecting")
+                .and_then(|res| res.context("Failed connecting"));
+            match res {
+                Ok(tcp_stream) => {
+                    if dst_ip.is_ipv6() {
+                        self.dns_resolver.report_ipv6_connectivity(true);
+                    } else if ipv6_failed {
+                        self.dns_resolver.report_ipv6_connectivity(false);
+                    }
+                    tcp_stream.set_nodelay(true)?;
+                    timing.tcp = Some(start.elapsed());
+                    return Ok(tcp_stream);
+                }
+                Err(err) => {
+                    debug!(%addr, "failed to connect: {err:#}");
+                    ipv6_failed |= dst_ip.is_ipv6();
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow!("No addresses to connect to")))
     }
 
     async fn dial_url_proxy(
         &self,
         proxy_url: Url,
+        dial_target: &Url,
         tls_connector: &tokio_rustls::TlsConnector,
+        timing: &mut ConnectTiming,
     ) -> Result<util::Chain<std::io::Cursor<Bytes>, MaybeTlsStream>> {
         use hyper_util::rt::TokioIo;
         use tokio::net::TcpStream;
-        debug!(%self.url, %proxy_url, "dial url via proxy");
+        debug!(%self.url, %dial_target, %proxy_url, "dial url via proxy");
+        ensure!(
+            matches!(proxy_url.scheme(), "http" | "https" | "socks5" | "socks5h"),
+            "Unsupported proxy scheme: {}",
+            proxy_url.scheme()
+        );
 
         // Resolve proxy DNS
         let prefer_ipv6 = self.prefer_ipv6();
-        let proxy_ip = self
-            .dns_resolver
-            .resolve_host(&proxy_url, prefer_ipv6, DNS_TIMEOUT)
-            .await?;
+        let start = Instant::now();
+        // The host of a URL with a non-special scheme, like `socks5`, is never parsed as
+        // an IP address.
+        let literal_ip = proxy_url
+            .host_str()
+            .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+            .and_then(|host| host.parse::<std::net::IpAddr>().ok());
+        let proxy_ip = match literal_ip {
+            Some(ip) => ip,
+            None => {
+                self.dns_resolver
+                    .resolve_host(&proxy_url, prefer_ipv6, DNS_TIMEOUT)
+                    .await?
+            }
+        };
+        timing.dns = Some(start.elapsed());
 
         let proxy_port = url_port(&proxy_url).ok_or_else(|| anyhow!("Missing proxy url port"))?;
         let proxy_addr = SocketAddr::new(proxy_ip, proxy_port);
 
         debug!(%proxy_addr, "connecting to proxy");
 
+        let start = Instant::now();
         let tcp_stream = time::timeout(DIAL_NODE_TIMEOUT, async move {
             TcpStream::connect(proxy_addr).await
         })
@@ -210,6 +413,15 @@ impl ClientBuilder {
 
         tcp_stream.set_nodelay(true)?;
 
+        if let "socks5" | "socks5h" = proxy_url.scheme() {
+            let mut tcp_stream = tcp_stream;
+            self.socks5_tunnel(&mut tcp_stream, &proxy_url, dial_target)
+                .await?;
+            timing.tcp = Some(start.elapsed());
+            let empty = std::io::Cursor::new(Bytes::new());
+            return Ok(util::chain(empty, MaybeTlsStream::Raw(tcp_stream)));
+        }
+
         // Setup TLS if necessary
         let io = if proxy_url.scheme() == "http" {
             MaybeTlsStream::Raw(tcp_stream)
@@ -221,12 +433,11 @@ impl ClientBuilder {
         };
         let io = TokioIo::new(io);
 
-        let target_host = self
-            .url
+        let target_host = dial_target
             .host_str()
             .ok_or_else(|| anyhow!("Missing proxy host"))?;
 
-        let port = url_port(&self.url).ok_or_else(|| anyhow!("invalid target port"))?;
+        let port = url_port(dial_target).ok_or_else(|| anyhow!("invalid target port"))?;
 
         // Establish Proxy Tunnel
         let mut req_builder = Request::builder()
@@ -234,19 +445,12 @@ impl ClientBuilder {
             .method("CONNECT")
             .header("Host", target_host)
             .header("Proxy-Connection", "Keep-Alive");
-        if !proxy_url.username().is_empty() {
+        if let Some((username, password)) = proxy_credentials(&proxy_url) {
             // Passthrough authorization
             // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Proxy-Authorization
-            debug!(
-                "setting proxy-authorization: username={}",
-                proxy_url.username()
-            );
-            let to_encode = format!(
-                "{}:{}",
-                proxy_url.username(),
-                proxy_url.password().unwrap_or_default()
-            );
-            let encoded = BASE64URL.encode(to_encode.as_bytes());
+            debug!("setting proxy-authorization: username={username}");
+            let to_encode = format!("{username}:{password}");
+            let encoded = BASE64.encode(to_encode.as_bytes());
             req_builder = req_builder.header("Proxy-Authorization", format!("Basic {}", encoded));
         }
         let req = req_builder.body(Empty::<Bytes>::new())?;
@@ -271,16 +475,54 @@ impl ClientBuilder {
         };
 
         let res = util::chain(std::io::Cursor::new(read_buf), io.into_inner());
+        timing.tcp = Some(start.elapsed());
 
         Ok(res)
     }
 
+    /// Establishes a tunnel to the relay server through the SOCKS5 proxy at `proxy_url`.
+    ///
+    /// With the `socks5h` scheme the proxy resolves the host name of the relay server,
+    /// otherwise it is resolved locally.
+    async fn socks5_tunnel(
+        &self,
+        stream: &mut tokio::net::TcpStream,
+        proxy_url: &Url,
+        dial_target: &Url,
+    ) -> Result<()> {
+        let port = url_port(dial_target).ok_or_else(|| anyhow!("invalid target port"))?;
+        let host = dial_target
+            .host_str()
+            .ok_or_else(|| anyhow!("Missing target host"))?;
+        let host = host.strip_suffix('.').unwrap_or(host);
+        let target = match dial_target.host() {
+            Some(url::Host::Ipv4(ip)) => TargetAddr::Ip(SocketAddr::new(ip.into(), port)),
+            Some(url::Host::Ipv6(ip)) => TargetAddr::Ip(SocketAddr::new(ip.into(), port)),
+            _ if proxy_url.scheme() == "socks5h" => TargetAddr::Domain(host, port),
+            _ => {
+                let ip = self
+                    .dns_resolver
+                    .resolve_host(dial_target, self.prefer_ipv6(), DNS_TIMEOUT)
+                    .await?;
+                TargetAddr::Ip(SocketAddr::new(ip, port))
+            }
+        };
+        debug!(?target, "establishing SOCKS5 tunnel");
+        let credentials = proxy_credentials(proxy_url);
+        let auth = credentials
+            .as_ref()
+            .map(|(username, password)| (username.as_str(), password.as_str()));
+        time::timeout(DIAL_NODE_TIMEOUT, socks::connect(stream, target, auth))
+            .await
+            .context("Timeout establishing SOCKS5 tunnel")?
+    }
+
     /// Reports whether IPv4 dials should be slightly
     /// delayed to give IPv6 a better chance of winning dial races.
     /// Implementations should only return true if IPv6 is expected
     /// to succeed. (otherwise delaying IPv4 will delay the connection
     /// overall)
-    fn prefer_ipv6(&self) -> bool {
+    pub(super) fn prefer_ipv6(&self) -> bool {
         match self.address_family_selector {
             Some(ref selector) => selector(),
             None => false,
@@ -303,7 +545,20 @@ fn host_header_value(relay_url: RelayUrl) -> Result<String> {
     Ok(host_header_value)
 }
 
-fn url_port(url: &Url) -> Option<u16> {
+/// Returns the `host:port` authority of the relay, as used in the target of a `CONNECT` request.
+pub(super) fn connect_authority(relay_url: &RelayUrl) -> Result<String> {
+    let host = relay_url.host_str().context("Invalid URL")?;
+    let host = host.strip_suffix('.').unwrap_or(host);
+    let port = url_port(relay_url).context("Invalid URL, missing port")?;
+    Ok(format!("{host}:{port}"))
+}
+
+pub(super) fn tls_servername(url: &Url) -> Option<rustls::pki_types::ServerName<'_>> {
+    url.host_str()
+        .and_then(|s| rustls::pki_types::ServerName::try_from(s).ok())
+}
+
+pub(super) fn url_port(url: &Url) -> Option<u16> {
     if let Some(port) = url.port() {
         return Some(port);
     }
@@ -311,15 +566,56 @@ fn url_port(url: &Url) -> Option<u16> {
     match url.scheme() {
         "http" => Some(80),
         "https" => Some(443),
+        "socks5" | "socks5h" => Some(1080),
         _ => None,
     }
 }
 
+/// Returns the percent-decoded username and password of a proxy URL, if it has a username.
+fn proxy_credentials(proxy_url: &Url) -> Option<(String, String)> {
+    if proxy_url.username().is_empty() {
+        return None;
+    }
+    let decode = |s: &str| {
+        percent_encoding::percent_decode_str(s)
+            .decode_utf8_lossy()
+            .into_owned()
+    };
+    Some((
+        decode(proxy_url.username()),
+        decode(proxy_url.password().unwrap_or_default()),
+    ))
+}
+
+/// Offers the relay ALPN protocols, unless the TLS config already offers protocols.
+///
+/// [`RELAY_ALPN`] is preferred, [`HTTP_1_1_ALPN`] allows connecting to servers which
+/// predate it.
+fn with_relay_alpns(config: Arc<rustls::ClientConfig>) -> Arc<rustls::ClientConfig> {
+    if !config.alpn_protocols.is_empty() {
+        return config;
+    }
+    let mut config = Arc::unwrap_or_clone(config);
+    config.alpn_protocols = vec![RELAY_ALPN.to_vec(), HTTP_1_1_ALPN.to_vec()];
+    Arc::new(config)
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
 
     use anyhow::Result;
+    use rustls::{
+        client::EchStatus,
+        crypto::hpke::{
+            EncapsulatedSecret, Hpke, HpkeOpener, HpkePrivateKey, HpkePublicKey, HpkeSealer,
+            HpkeSuite,
+        },
+        internal::msgs::{
+            enums::{HpkeAead, HpkeKdf, HpkeKem},
+            handshake::HpkeSymmetricCipherSuite,
+        },
+    };
     use tracing_test::traced_test;
 
     use super::*;
@@ -343,4 +639,202 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_with_relay_alpns() {
+        let config = Arc::new(crate::client::make_dangerous_client_config());
+        let config = with_relay_alpns(config);
+        assert_eq!(
+            config.alpn_protocols,
+            [RELAY_ALPN.to_vec(), HTTP_1_1_ALPN.to_vec()]
+        );
+
+        // Protocols configured in a custom config are kept.
+        let mut config = crate::client::make_dangerous_client_config();
+        config.alpn_protocols = vec![HTTP_1_1_ALPN.to_vec()];
+        let config = with_relay_alpns(Arc::new(config));
+        assert_eq!(config.alpn_protocols, [HTTP_1_1_ALPN.to_vec()]);
+    }
+
+    #[test]
+    fn test_dial_target_fronting() -> Result<()> {
+        let secret_key = SecretKey::generate(rand::thread_rng());
+        let relay_url = RelayUrl::from_str("https://relay.example.com:8443")?;
+        let builder = ClientBuilder::new(relay_url.clone(), secret_key, DnsResolver::new());
+        assert_eq!(builder.dial_target()?, *relay_url);
+
+        let builder = builder.fronting_domain("cdn.example.net");
+        let dial_target = builder.dial_target()?;
+        assert_eq!(dial_target.as_str(), "https://cdn.example.net:8443/");
+        assert_eq!(
+            tls_servername(&dial_target),
+            rustls::pki_types::ServerName::try_from("cdn.example.net").ok()
+        );
+        // The Host header still names the relay.
+        assert_eq!(host_header_value(relay_url)?, "relay.example.com:8443");
+
+        let builder = builder.fronting_domain("not a domain");
+        assert!(builder.dial_target().is_err());
+
+        Ok(())
+    }
+
+    /// An HPKE suite which only produces ECH offers, nothing can be decrypted.
+    ///
+    /// The *ring* provider has no HPKE implementation, this is enough to build a client hello.
+    #[derive(Debug)]
+    struct TestHpke;
+
+    static TEST_HPKE_SUITES: &[&dyn Hpke] = &[&TestHpke];
+
+    impl Hpke for TestHpke {
+        fn seal(
+            &self,
+            _info: &[u8],
+            _aad: &[u8],
+            plaintext: &[u8],
+            _pub_key: &HpkePublicKey,
+        ) -> Result<(EncapsulatedSecret, Vec<u8>), rustls::Error> {
+            Ok((
+                EncapsulatedSecret(vec![0; 32]),
+                TestSealer.seal(&[], plaintext)?,
+            ))
+        }
+
+        fn setup_sealer(
+            &self,
+            _info: &[u8],
+            _pub_key: &HpkePublicKey,
+        ) -> Result<(EncapsulatedSecret, Box<dyn HpkeSealer>), rustls::Error> {
+            Ok((EncapsulatedSecret(vec![0; 32]), Box::new(TestSealer)))
+        }
+
+        fn open(
+            &self,
+            _enc: &EncapsulatedSecret,
+            _info: &[u8],
+            _aad: &[u8],
+            _ciphertext: &[u8],
+            _secret_key: &HpkePrivateKey,
+        ) -> Result<Vec<u8>, rustls::Error> {
+            Err(rustls::Error::General("not supported".into()))
+        }
+
+        fn setup_opener(
+            &self,
+            _enc: &EncapsulatedSecret,
+            _info: &[u8],
+            _secret_key: &HpkePrivateKey,
+        ) -> Result<Box<dyn HpkeOpener>, rustls::Error> {
+            Err(rustls::Error::General("not supported".into()))
+        }
+
+        fn generate_key_pair(&self) -> Result<(HpkePublicKey, HpkePrivateKey), rustls::Error> {
+            Ok((
+                HpkePublicKey(vec![1; 32]),
+                HpkePrivateKey::from(vec![2; 32]),
+            ))
+        }
+
+        fn suite(&self) -> HpkeSuite {
+            HpkeSuite {
+                kem: HpkeKem::DHKEM_X25519_HKDF_SHA256,
+                sym: HpkeSymmetricCipherSuite {
+                    kdf_id: HpkeKdf::HKDF_SHA256,
+                    aead_id: HpkeAead::AES_128_GCM,
+                },
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct TestSealer;
+
+    impl HpkeSealer for TestSealer {
+        fn seal(&mut self, _aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, rustls::Error> {
+            // Same length as the AES-128-GCM ciphertext.
+            let mut ciphertext = plaintext.to_vec();
+            ciphertext.extend_from_slice(&[0; 16]);
+            Ok(ciphertext)
+        }
+    }
+
+    /// Encodes an `ECHConfigList` with a single config for the [`TestHpke`] suite.
+    fn ech_config_list(public_name: &str) -> Vec<u8> {
+        let mut contents = vec![1]; // config_id
+        contents.extend_from_slice(&0x0020u16.to_be_bytes()); // DHKEM(X25519, HKDF-SHA256)
+        contents.extend_from_slice(&32u16.to_be_bytes());
+        contents.extend_from_slice(&[1; 32]); // public_key
+        contents.extend_from_slice(&4u16.to_be_bytes());
+        contents.extend_from_slice(&[0, 1, 0, 1]); // HKDF-SHA256, AES-128-GCM
+        contents.push(0); // maximum_name_length
+        contents.push(public_name.len() as u8);
+        contents.extend_from_slice(public_name.as_bytes());
+        contents.extend_from_slice(&0u16.to_be_bytes()); // extensions
+
+        let mut config = 0xfe0du16.to_be_bytes().to_vec();
+        config.extend_from_slice(&(contents.len() as u16).to_be_bytes());
+        config.extend_from_slice(&contents);
+
+        let mut list = (config.len() as u16).to_be_bytes().to_vec();
+        list.extend_from_slice(&config);
+        list
+    }
+
+    fn ech_status(config: Arc<rustls::ClientConfig>) -> Result<EchStatus> {
+        let server_name = rustls::pki_types::ServerName::try_from("relay.example.com")?;
+        let conn = rustls::ClientConnection::new(config, server_name)?;
+        Ok(conn.ech_status())
+    }
+
+    #[test]
+    fn test_rustls_client_config_ech() -> Result<()> {
+        let secret_key = SecretKey::generate(rand::thread_rng());
+        let relay_url = RelayUrl::from_str("https://relay.example.com")?;
+        let builder =
+            ClientBuilder::new(relay_url, secret_key, DnsResolver::new()).ech(TEST_HPKE_SUITES);
+
+        let ech = EchConfig::new(
+            ech_config_list("public.example.com").into(),
+            TEST_HPKE_SUITES,
+        )?;
+        let config = builder.rustls_client_config(Some(ech));
+        assert_eq!(ech_status(config)?, EchStatus::Offered);
+
+        // Without an ECH config we fall back to plain TLS.
+        let config = builder.rustls_client_config(None);
+        assert_eq!(ech_status(config)?, EchStatus::NotOffered);
+
+        // Config lists without a supported suite are not usable.
+        assert!(EchConfig::new(ech_config_list("public.example.com").into(), &[]).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ech_config_disabled() -> Result<()> {
+        let secret_key = SecretKey::generate(rand::thread_rng());
+        let relay_url = RelayUrl::from_str("https://relay.example.com")?;
+        let builder = ClientBuilder::new(relay_url.clone(), secret_key, DnsResolver::new());
+        let dial_target = builder.dial_target()?;
+
+        // ECH is not enabled.
+        assert!(builder.ech_config(&dial_target).await.is_none());
+
+        // ECH has no effect with a custom TLS config.
+        let custom = Arc::new(crate::client::make_dangerous_client_config());
+        let builder = builder.ech(TEST_HPKE_SUITES).rustls_config(custom.clone());
+        assert!(builder.ech_config(&dial_target).await.is_none());
+        assert!(Arc::ptr_eq(&builder.rustls_client_config(None), &custom));
+
+        // Nor without TLS.
+        let secret_key = SecretKey::generate(rand::thread_rng());
+        let relay_url = RelayUrl::from_str("http://relay.example.com")?;
+        let builder =
+            ClientBuilder::new(relay_url, secret_key, DnsResolver::new()).ech(TEST_HPKE_SUITES);
+        let dial_target = builder.dial_target()?;
+        assert!(builder.ech_config(&dial_target).await.is_none());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,89 @@
+//! Functionality related to `ClientBuilder::connect_webtransport`.
+//!
+//! The relay connection is a stream of a WebTransport session over HTTP/3, see
+//! [`crate::protos::webtransport`].  This exists for parity with browsers, which this code
+//! does not run in: they use the WebTransport API of the browser instead.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use anyhow::Context;
+use n0_future::time;
+use quinn::crypto::rustls::QuicClientConfig;
+
+use super::{
+    connect_relay::{connect_authority, tls_servername, url_port},
+    streams::MaybeTlsStreamChained,
+    *,
+};
+use crate::{defaults::timeouts::*, http::H3_ALPN, protos::webtransport};
+
+impl ClientBuilder {
+    /// Connects to the relay server with a WebTransport session.
+    ///
+    /// The QUIC connection goes to the UDP port of the relay URL, which is the port of the
+    /// HTTPS server.  Proxies, domain fronting and ECH are not supported.
+    ///
+    /// The durations of the steps are recorded in `timing`, the QUIC handshake counting as
+    /// the TLS handshake and establishing the session as the upgrade.
+    pub(super) async fn connect_webtransport(
+        &self,
+        timing: &mut ConnectTiming,
+    ) -> Result<(Conn, SocketAddr)> {
+        if !self.use_tls() {
+            bail!("WebTransport needs TLS, can not connect to {}", self.url);
+        }
+        if self.proxy_url.is_some() {
+            bail!("WebTransport can not be used through a proxy");
+        }
+        let start = Instant::now();
+        let addrs = self
+            .dns_resolver
+            .resolve_host_addrs(&self.url, self.prefer_ipv6(), DNS_TIMEOUT)
+            .await?;
+        timing.dns = Some(start.elapsed());
+        let ip = *addrs.first().context("no addresses for the relay")?;
+        let port = url_port(&self.url).context("Missing URL port")?;
+        let server_addr = SocketAddr::new(ip, port);
+        let server_name = tls_servername(&self.url).context("No tls servername")?;
+
+        let mut tls_config = Arc::unwrap_or_clone(self.rustls_client_config(None));
+        tls_config.alpn_protocols = vec![H3_ALPN.to_vec()];
+        let client_config = quinn::ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(tls_config).context("TLS 1.3 is required for QUIC")?,
+        ));
+        let bind_addr: SocketAddr = match server_addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let endpoint = quinn::Endpoint::client(bind_addr)?;
+        let local_addr = endpoint.local_addr()?;
+
+        debug!(%server_addr, %local_addr, "Dialing relay by WebTransport");
+        let start = Instant::now();
+        let connecting =
+            endpoint.connect_with(client_config, server_addr, &server_name.to_str())?;
+        let conn = time::timeout(DIAL_NODE_TIMEOUT, connecting)
+            .await
+            .context("Timeout connecting")??;
+        timing.tls = Some(start.elapsed());
+
+        let start = Instant::now();
+        let stream = webtransport::connect(conn, &connect_authority(&self.url)?).await?;
+        timing.upgrade = Some(start.elapsed());
+
+        let start = Instant::now();
+        let conn = Conn::new_relay(
+            MaybeTlsStreamChained::WebTransport(Box::new(stream)),
+            self.key_cache.clone(),
+            &self.secret_key,
+            self.capabilities(),
+            self.session.clone(),
+            self.software.as_ref(),
+            self.multiplexed_channels,
+        )
+        .await?;
+        timing.handshake = start.elapsed();
+
+        Ok((conn, local_addr))
+    }
+}
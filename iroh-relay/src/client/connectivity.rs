@@ -0,0 +1,592 @@
+//! A relay client checking its connectivity in the background.
+//!
+//! Middleboxes silently drop idle connections, which otherwise goes unnoticed until the
+//! next send fails.  A [`CheckedClient`] pings the relay server periodically and
+//! reconnects after too many consecutive pings went unanswered, or as soon as the
+//! connection fails.  The changes of the connectivity are reported as
+//! [`ConnectivityEvent`]s.
+//!
+//! With [`ClientBuilder::failover_urls`] the client connects to the most preferred relay
+//! server which is reachable.  A server which can not be connected to is retried after a
+//! backoff, doubled with every failed attempt.  While connected to a less preferred server,
+//! the more preferred ones are probed once their backoff expired, and the client switches
+//! back as soon as one is reachable again.
+
+use std::{
+    num::NonZeroU32,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{self, Poll},
+};
+
+use anyhow::{anyhow, Result};
+use iroh_base::RelayUrl;
+use n0_future::{
+    boxed::{BoxFuture, BoxStream},
+    task::AbortOnDropHandle,
+    time::{self, Duration, Instant},
+    SinkExt, Stream, StreamExt,
+};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace, warn, Instrument};
+
+use super::{
+    Client, ClientBuilder, ConnSendError, ConnectTiming, DropReason, DroppedFrames,
+    ReceivedMessage, SendMessage,
+};
+
+/// The capacity of the send queue between a [`CheckedClient`] and its background task.
+const QUEUE_CAPACITY: usize = 32;
+
+/// The capacity of the queue of received messages, further messages are dropped.
+const RECEIVED_CAPACITY: usize = 256;
+
+/// The capacity of the [`ConnectivityEvent`] channel.
+const EVENTS_CAPACITY: usize = 16;
+
+/// Configuration of the connectivity checks of a [`CheckedClient`].
+#[derive(Debug, Clone)]
+pub struct ConnectivityCheckConfig {
+    /// The time between two pings, and between two reconnection attempts.
+    pub interval: Duration,
+    /// How long to wait for the pong, and for sends to complete.
+    pub timeout: Duration,
+    /// The number of consecutive unanswered pings after which the client reconnects.
+    pub max_failures: NonZeroU32,
+    /// The maximum time to wait before retrying a relay server which could not be
+    /// connected to.
+    ///
+    /// The first retry is after [`ConnectivityCheckConfig::interval`], the backoff is
+    /// doubled with every failed attempt up to this.
+    pub max_backoff: Duration,
+}
+
+impl Default for ConnectivityCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(5),
+            max_failures: NonZeroU32::new(2).expect("non-zero"),
+            max_backoff: Duration::from_secs(300),
+        }
+    }
+}
+
+/// A change of the connectivity of a [`CheckedClient`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectivityEvent {
+    /// A ping went unanswered or the connection failed.
+    Degraded {
+        /// The number of consecutive unanswered pings, zero if the connection failed.
+        failures: u32,
+    },
+    /// A ping was answered again, or the client reconnected, after being degraded.
+    Recovered {
+        /// Whether the client had to reconnect.
+        reconnected: bool,
+    },
+    /// The client connected to another relay server.
+    ///
+    /// Either failing over to a less preferred server, or switching back to a more
+    /// preferred one, see [`CheckedClient::relay_url`].
+    Switched {
+        /// The preference of the server, 0 for the URL passed to [`ClientBuilder::new`],
+        /// followed by the [`ClientBuilder::failover_urls`].
+        index: usize,
+    },
+}
+
+/// A relay client checking its connectivity in the background.
+///
+/// Created with [`ClientBuilder::connect_checked`].  The client owns a background task,
+/// which is aborted when the client is dropped, see [`CheckedClient::close`] for a
+/// graceful shutdown.
+///
+/// Received messages are yielded by the [`Stream`] implementation.  Messages received
+/// while the stream is not polled fast enough to keep up are dropped, see
+/// [`CheckedClient::dropped_frames`].  Messages sent while the client reconnects are
+/// queued, messages in flight when the connection fails are lost.
+#[derive(Debug)]
+pub struct CheckedClient {
+    send_queue: mpsc::Sender<SendMessage>,
+    received: mpsc::Receiver<ReceivedMessage>,
+    events: broadcast::Sender<ConnectivityEvent>,
+    /// The timing of the latest connection.
+    connect_timing: Arc<Mutex<ConnectTiming>>,
+    /// The relay server of the latest connection.
+    relay_url: Arc<Mutex<RelayUrl>>,
+    dropped_frames: DroppedFrames,
+    cancel: CancellationToken,
+    task: AbortOnDropHandle<()>,
+}
+
+impl CheckedClient {
+    /// Connects to the most preferred reachable relay server and starts checking the
+    /// connectivity.
+    pub(super) async fn connect(
+        builder: ClientBuilder,
+        config: ConnectivityCheckConfig,
+    ) -> Result<Self> {
+        let mut relays = Relays::new(&builder, &config);
+        let mut last_err = None;
+        for index in 0..relays.servers.len() {
+            match relays.connect(index, config.timeout).await {
+                Ok(client) => return Ok(Self::new(relays, client, config)),
+                Err(err) => {
+                    debug!(url = %relays.servers[index].builder.url, "failed to connect: {err:#}");
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("at least one relay server"))
+    }
+
+    /// Starts checking the connectivity of the connected client.
+    fn new(relays: Relays, client: Client, config: ConnectivityCheckConfig) -> Self {
+        let (send_queue_s, send_queue_r) = mpsc::channel(QUEUE_CAPACITY);
+        let (received_s, received_r) = mpsc::channel(RECEIVED_CAPACITY);
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        let cancel = CancellationToken::new();
+        let connect_timing = Arc::new(Mutex::new(client.connect_timing()));
+        let relay_url = Arc::new(Mutex::new(relays.current_url().clone()));
+        // Shared by the connections of the builder.
+        let dropped_frames = client.dropped_frames().clone();
+        let actor = Actor {
+            relays,
+            config,
+            send_queue: send_queue_r,
+            received: received_s,
+            events: events.clone(),
+            connect_timing: connect_timing.clone(),
+            relay_url: relay_url.clone(),
+            dropped_frames: dropped_frames.clone(),
+            cancel: cancel.clone(),
+            failures: 0,
+            degraded: false,
+        };
+        let task = n0_future::task::spawn(
+            actor
+                .run(client)
+                .instrument(tracing::info_span!("relay-connectivity")),
+        );
+        Self {
+            send_queue: send_queue_s,
+            received: received_r,
+            events,
+            connect_timing,
+            relay_url,
+            dropped_frames,
+            cancel,
+            task: AbortOnDropHandle::new(task),
+        }
+    }
+
+    /// Queues a message to be sent to the relay server.
+    ///
+    /// Fails only if the background task stopped.
+    pub async fn send(&self, msg: SendMessage) -> Result<(), ConnSendError> {
+        self.send_queue
+            .send(msg)
+            .await
+            .map_err(|_| ConnSendError::Protocol("Connectivity checker stopped"))
+    }
+
+    /// Returns a stream of the connectivity changes from now on.
+    pub fn events(&self) -> BoxStream<ConnectivityEvent> {
+        let events = self.events.subscribe();
+        Box::pin(n0_future::stream::unfold(events, |mut events| async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => return Some((event, events)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }))
+    }
+
+    /// Returns how long the steps of establishing the latest connection took.
+    ///
+    /// This is updated whenever the client reconnects.
+    pub fn connect_timing(&self) -> ConnectTiming {
+        *self.connect_timing.lock().expect("poisoned")
+    }
+
+    /// Returns the relay server of the latest connection.
+    ///
+    /// This changes when the client fails over, see [`ConnectivityEvent::Switched`].
+    pub fn relay_url(&self) -> RelayUrl {
+        self.relay_url.lock().expect("poisoned").clone()
+    }
+
+    /// Returns the frames received but dropped, across all connections.
+    ///
+    /// Includes the messages dropped because the stream was not polled fast enough, as
+    /// [`DropReason::QueueFull`].
+    pub fn dropped_frames(&self) -> &DroppedFrames {
+        &self.dropped_frames
+    }
+
+    /// Stops the background task and closes the connection gracefully.
+    pub async fn close(self) {
+        self.cancel.cancel();
+        if let Err(err) = self.task.await {
+            warn!("connectivity checker failed: {err:#}");
+        }
+    }
+}
+
+impl Stream for CheckedClient {
+    type Item = ReceivedMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        self.received.poll_recv(cx)
+    }
+}
+
+/// The relay servers of a [`CheckedClient`], in order of preference.
+#[derive(Debug)]
+struct Relays {
+    servers: Vec<RelayServer>,
+    /// The index of the server connected to.
+    current: usize,
+    /// The backoff after the first failed attempt.
+    min_backoff: Duration,
+    max_backoff: Duration,
+}
+
+/// A relay server a [`CheckedClient`] can connect to.
+#[derive(Debug)]
+struct RelayServer {
+    /// Connects to the server.
+    builder: ClientBuilder,
+    /// The time to wait after the next failed attempt.
+    backoff: Duration,
+    /// When the server may be tried again, `None` unless the last attempt failed.
+    retry_at: Option<Instant>,
+}
+
+impl Relays {
+    fn new(builder: &ClientBuilder, config: &ConnectivityCheckConfig) -> Self {
+        let servers = builder
+            .relay_urls()
+            .enumerate()
+            .map(|(index, url)| RelayServer {
+                builder: match index {
+                    0 => builder.clone(),
+                    _ => builder.with_url(url.clone()),
+                },
+                backoff: config.interval,
+                retry_at: None,
+            })
+            .collect();
+        Self {
+            servers,
+            current: 0,
+            min_backoff: config.interval,
+            max_backoff: config.max_backoff.max(config.interval),
+        }
+    }
+
+    fn current_url(&self) -> &RelayUrl {
+        &self.servers[self.current].builder.url
+    }
+
+    /// Connects to the server at `index`, becoming the current server on success.
+    async fn connect(&mut self, index: usize, timeout: Duration) -> Result<Client> {
+        let res = connect(self.servers[index].builder.clone(), timeout).await;
+        self.record(index, res)
+    }
+
+    /// Records the outcome of an attempt to connect to the server at `index`.
+    fn record(&mut self, index: usize, res: Result<Client>) -> Result<Client> {
+        let server = &mut self.servers[index];
+        match res {
+            Ok(client) => {
+                server.backoff = self.min_backoff;
+                server.retry_at = None;
+                self.current = index;
+                Ok(client)
+            }
+            Err(err) => {
+                server.retry_at = Some(Instant::now() + server.backoff);
+                server.backoff = (server.backoff * 2).min(self.max_backoff);
+                Err(err)
+            }
+        }
+    }
+
+    /// Returns the most preferred server which may be tried, before the server at `before`.
+    fn next_due(&self, before: usize) -> Option<usize> {
+        let now = Instant::now();
+        self.servers[..before]
+            .iter()
+            .position(|server| !server.retry_at.is_some_and(|retry_at| retry_at > now))
+    }
+
+    /// Returns when the first of the servers before `before` may be tried again.
+    fn next_retry(&self, before: usize) -> Option<Instant> {
+        self.servers[..before]
+            .iter()
+            .map(|server| server.retry_at.unwrap_or_else(Instant::now))
+            .min()
+    }
+}
+
+/// Connects a client, failing after `timeout`.
+async fn connect(builder: ClientBuilder, timeout: Duration) -> Result<Client> {
+    time::timeout(timeout, builder.connect())
+        .await
+        .map_err(|_| anyhow!("connecting timed out"))?
+}
+
+/// The background task of a [`CheckedClient`].
+#[derive(Debug)]
+struct Actor {
+    relays: Relays,
+    config: ConnectivityCheckConfig,
+    send_queue: mpsc::Receiver<SendMessage>,
+    received: mpsc::Sender<ReceivedMessage>,
+    events: broadcast::Sender<ConnectivityEvent>,
+    connect_timing: Arc<Mutex<ConnectTiming>>,
+    relay_url: Arc<Mutex<RelayUrl>>,
+    dropped_frames: DroppedFrames,
+    cancel: CancellationToken,
+    /// The number of consecutive unanswered pings.
+    failures: u32,
+    /// Whether a [`ConnectivityEvent::Degraded`] was emitted without recovering since.
+    degraded: bool,
+}
+
+/// Why the connection to a server is given up.
+#[derive(Debug)]
+enum Exit {
+    /// The connection failed.
+    Failed,
+    /// Too many pings went unanswered.
+    Unanswered,
+    /// A more preferred server is reachable again, the client connected to it.
+    SwitchedBack(Box<Client>),
+}
+
+impl Actor {
+    async fn run(mut self, mut client: Client) {
+        loop {
+            let previous = self.relays.current;
+            match self.run_connected(&mut client).await {
+                Some(Exit::SwitchedBack(new_client)) => {
+                    debug!(url = %self.relays.current_url(), "switching back to preferred relay");
+                    if let Err(err) = client.close().await {
+                        debug!("failed to close client: {err:#}");
+                    }
+                    client = *new_client;
+                    self.connected(&client, previous);
+                    continue;
+                }
+                Some(lost) => {
+                    debug!(?lost, "connection lost, reconnecting");
+                    if let Exit::Failed = lost {
+                        self.degrade(0);
+                    }
+                }
+                None => {
+                    if let Err(err) = client.close().await {
+                        debug!("failed to close client: {err:#}");
+                    }
+                    return;
+                }
+            }
+            drop(client);
+            let Some(new_client) = self.reconnect().await else {
+                return;
+            };
+            client = new_client;
+            self.connected(&client, previous);
+            self.recover(true);
+        }
+    }
+
+    /// Serves the connected client.
+    ///
+    /// Returns why the connection was given up, or `None` if cancelled.
+    async fn run_connected(&mut self, client: &mut Client) -> Option<Exit> {
+        let mut interval = time::interval(self.config.interval);
+        // The first tick completes immediately.
+        interval.tick().await;
+        let mut ping: Option<([u8; 8], Instant)> = None;
+        // Connecting to a more preferred server.
+        let mut probe: Option<(usize, BoxFuture<Result<Client>>)> = None;
+        loop {
+            let deadline = ping.map(|(_, deadline)| deadline);
+            let probe_at = match probe {
+                Some(_) => None,
+                None => self.relays.next_retry(self.relays.current),
+            };
+            tokio::select! {
+                biased;
+
+                _ = self.cancel.cancelled() => return None,
+                res = async { (&mut probe.as_mut().expect("checked").1).await }, if probe.is_some() => {
+                    let (index, _) = probe.take().expect("checked");
+                    match self.relays.record(index, res) {
+                        Ok(client) => return Some(Exit::SwitchedBack(Box::new(client))),
+                        Err(err) => {
+                            let url = &self.relays.servers[index].builder.url;
+                            debug!(%url, "preferred relay still unreachable: {err:#}");
+                        }
+                    }
+                }
+                _ = time::sleep_until(probe_at.unwrap_or_else(Instant::now)), if probe_at.is_some() => {
+                    if let Some(index) = self.relays.next_due(self.relays.current) {
+                        let builder = self.relays.servers[index].builder.clone();
+                        trace!(url = %builder.url, "probing preferred relay");
+                        probe = Some((index, Box::pin(connect(builder, self.config.timeout))));
+                    }
+                }
+                _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    ping = None;
+                    self.failures += 1;
+                    debug!(failures = self.failures, "ping unanswered");
+                    self.degrade(self.failures);
+                    if self.failures >= self.config.max_failures.get() {
+                        return Some(Exit::Unanswered);
+                    }
+                }
+                _ = interval.tick(), if ping.is_none() => {
+                    let data: [u8; 8] = rand::random();
+                    trace!("connectivity ping");
+                    if !self.send(client, SendMessage::Ping(data)).await {
+                        return Some(Exit::Failed);
+                    }
+                    ping = Some((data, Instant::now() + self.config.timeout));
+                }
+                msg = client.next() => match msg {
+                    Some(Ok(ReceivedMessage::Pong(data))) if ping.is_some_and(|(ping, _)| ping == data) => {
+                        ping = None;
+                        self.failures = 0;
+                        self.recover(false);
+                    }
+                    Some(Ok(msg)) => self.deliver(msg),
+                    Some(Err(err)) => {
+                        debug!("connection failed: {err:#}");
+                        return Some(Exit::Failed);
+                    }
+                    None => {
+                        debug!("connection closed");
+                        return Some(Exit::Failed);
+                    }
+                },
+                msg = self.send_queue.recv() => {
+                    // The client was dropped, close the connection.
+                    let msg = msg?;
+                    if !self.send(client, msg).await {
+                        return Some(Exit::Failed);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends a message, returns whether it was sent within the timeout.
+    async fn send(&self, client: &mut Client, msg: SendMessage) -> bool {
+        match time::timeout(self.config.timeout, client.send(msg)).await {
+            Ok(Ok(())) => true,
+            Ok(Err(err)) => {
+                debug!("send failed: {err:#}");
+                false
+            }
+            Err(_) => {
+                debug!("send timed out");
+                false
+            }
+        }
+    }
+
+    /// Connects again, to the most preferred server which may be tried.
+    ///
+    /// Servers which can not be connected to are retried after their backoff.  Returns
+    /// `None` if cancelled.
+    async fn reconnect(&mut self) -> Option<Client> {
+        let servers = self.relays.servers.len();
+        loop {
+            while let Some(index) = self.relays.next_due(servers) {
+                let res = tokio::select! {
+                    biased;
+
+                    _ = self.cancel.cancelled() => return None,
+                    res = self.relays.connect(index, self.config.timeout) => res,
+                };
+                match res {
+                    Ok(client) => {
+                        debug!(url = %self.relays.current_url(), "reconnected");
+                        return Some(client);
+                    }
+                    Err(err) => {
+                        let url = &self.relays.servers[index].builder.url;
+                        debug!(%url, "failed to reconnect: {err:#}");
+                    }
+                }
+            }
+            let retry_at = self
+                .relays
+                .next_retry(servers)
+                .expect("at least one server");
+            tokio::select! {
+                biased;
+
+                _ = self.cancel.cancelled() => return None,
+                _ = time::sleep_until(retry_at) => {}
+            }
+        }
+    }
+
+    /// Updates the state after connecting to the current server.
+    fn connected(&mut self, client: &Client, previous: usize) {
+        self.failures = 0;
+        *self.connect_timing.lock().expect("poisoned") = client.connect_timing();
+        if self.relays.current != previous {
+            *self.relay_url.lock().expect("poisoned") = self.relays.current_url().clone();
+            self.events
+                .send(ConnectivityEvent::Switched {
+                    index: self.relays.current,
+                })
+                .ok();
+        }
+    }
+
+    fn degrade(&mut self, failures: u32) {
+        self.degraded = true;
+        // There might be no subscribers.
+        self.events
+            .send(ConnectivityEvent::Degraded { failures })
+            .ok();
+    }
+
+    /// Queues a received message for the [`CheckedClient`], dropping it if the queue is full.
+    fn deliver(&self, msg: ReceivedMessage) {
+        match self.received.try_send(msg) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(msg)) => {
+                let src = match msg {
+                    ReceivedMessage::ReceivedPacket { remote_node_id, .. } => Some(remote_node_id),
+                    _ => None,
+                };
+                trace!("receive queue full, dropping message");
+                self.dropped_frames.record(src, DropReason::QueueFull);
+            }
+            // Nobody is listening once the client is dropped, the task is aborted then.
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+    }
+
+    fn recover(&mut self, reconnected: bool) {
+        if !self.degraded {
+            return;
+        }
+        self.degraded = false;
+        self.events
+            .send(ConnectivityEvent::Recovered { reconnected })
+            .ok();
+    }
+}
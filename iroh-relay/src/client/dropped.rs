@@ -0,0 +1,186 @@
+//! Accounting of the frames a relay client received but dropped.
+//!
+//! Frames are dropped when the application does not keep up with receiving them, or when a
+//! fragmented packet can not be reassembled.  Without accounting this looks like packet
+//! loss on the network.  The drops are counted by [`DropReason`] and by sender, and
+//! reported as [`DroppedFrame`]s to anyone listening.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use iroh_base::NodeId;
+use n0_future::boxed::BoxStream;
+use tokio::sync::broadcast;
+
+/// The capacity of the [`DroppedFrame`] channel.
+const DROPS_CAPACITY: usize = 64;
+
+/// The max number of senders drops are counted for, further senders are only counted in
+/// the totals.
+const MAX_SENDERS: usize = 1024;
+
+/// Why a received frame was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    /// The queue of received messages was full, the application did not keep up.
+    QueueFull,
+    /// A fragment of a packet was invalid.
+    InvalidFragment,
+    /// Not all fragments of a packet were received in time.
+    ReassemblyTimeout,
+    /// A packet was still incomplete when too many packets were being reassembled.
+    TooManyIncomplete,
+    /// A compressed packet did not decompress.
+    InvalidCompression,
+}
+
+/// A frame dropped by a relay client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DroppedFrame {
+    /// The node which sent the frame, `None` for frames sent by the server itself.
+    pub src: Option<NodeId>,
+    /// Why the frame was dropped.
+    pub reason: DropReason,
+}
+
+/// Handle to the frames dropped by the connections of a relay client.
+///
+/// Obtained from [`Client::dropped_frames`] or [`CheckedClient::dropped_frames`], this is
+/// cheap to clone and keeps working after the client was split.  All connections made by
+/// a [`ClientBuilder`] and its clones share the same counts.
+///
+/// [`Client::dropped_frames`]: super::Client::dropped_frames
+/// [`CheckedClient::dropped_frames`]: super::CheckedClient::dropped_frames
+/// [`ClientBuilder`]: super::ClientBuilder
+#[derive(Debug, Clone)]
+pub struct DroppedFrames(Arc<Shared>);
+
+#[derive(Debug)]
+struct Shared {
+    inner: Mutex<Inner>,
+    drops: broadcast::Sender<DroppedFrame>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    total: u64,
+    by_reason: HashMap<DropReason, u64>,
+    by_sender: HashMap<NodeId, u64>,
+}
+
+impl Default for DroppedFrames {
+    fn default() -> Self {
+        let (drops, _) = broadcast::channel(DROPS_CAPACITY);
+        Self(Arc::new(Shared {
+            inner: Default::default(),
+            drops,
+        }))
+    }
+}
+
+impl DroppedFrames {
+    /// Returns the number of dropped frames.
+    pub fn total(&self) -> u64 {
+        self.0.inner.lock().expect("poisoned").total
+    }
+
+    /// Returns the number of frames dropped for `reason`.
+    pub fn count(&self, reason: DropReason) -> u64 {
+        let inner = self.0.inner.lock().expect("poisoned");
+        inner.by_reason.get(&reason).copied().unwrap_or_default()
+    }
+
+    /// Returns the number of dropped frames by sender, the most dropped first.
+    ///
+    /// Only the first 1024 senders are counted, frames of the server itself are not
+    /// included.
+    pub fn by_sender(&self) -> Vec<(NodeId, u64)> {
+        let inner = self.0.inner.lock().expect("poisoned");
+        let mut senders: Vec<_> = inner.by_sender.iter().map(|(k, v)| (*k, *v)).collect();
+        senders.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        senders
+    }
+
+    /// Returns a stream of the frames dropped from now on.
+    ///
+    /// Drops are not reported if the stream is not polled fast enough to keep up, they
+    /// are still counted.
+    pub fn drops(&self) -> BoxStream<DroppedFrame> {
+        let drops = self.0.drops.subscribe();
+        Box::pin(n0_future::stream::unfold(drops, |mut drops| async move {
+            loop {
+                match drops.recv().await {
+                    Ok(drop) => return Some((drop, drops)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }))
+    }
+
+    /// Records a dropped frame.
+    ///
+    /// The client records the frames it drops itself.  Applications can record the
+    /// packets they drop after receiving them from the client, e.g. because their own
+    /// queue is full, to have all drops accounted in one place.
+    pub fn record(&self, src: Option<NodeId>, reason: DropReason) {
+        {
+            let mut inner = self.0.inner.lock().expect("poisoned");
+            inner.total += 1;
+            *inner.by_reason.entry(reason).or_default() += 1;
+            if let Some(src) = src {
+                let senders = inner.by_sender.len();
+                match inner.by_sender.get_mut(&src) {
+                    Some(count) => *count += 1,
+                    None if senders < MAX_SENDERS => {
+                        inner.by_sender.insert(src, 1);
+                    }
+                    None => {}
+                }
+            }
+        }
+        // Nobody may be listening.
+        self.0.drops.send(DroppedFrame { src, reason }).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::SecretKey;
+    use n0_future::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dropped_frames() {
+        let dropped = DroppedFrames::default();
+        let mut drops = dropped.drops();
+        let a = SecretKey::generate(rand::thread_rng()).public();
+        let b = SecretKey::generate(rand::thread_rng()).public();
+
+        dropped.record(Some(a), DropReason::QueueFull);
+        dropped.record(Some(b), DropReason::ReassemblyTimeout);
+        dropped.record(Some(b), DropReason::QueueFull);
+        dropped.record(None, DropReason::QueueFull);
+
+        assert_eq!(dropped.total(), 4);
+        assert_eq!(dropped.count(DropReason::QueueFull), 3);
+        assert_eq!(dropped.count(DropReason::ReassemblyTimeout), 1);
+        assert_eq!(dropped.count(DropReason::InvalidFragment), 0);
+        assert_eq!(dropped.by_sender(), vec![(b, 2), (a, 1)]);
+
+        let drop = drops.next().await.unwrap();
+        assert_eq!(
+            drop,
+            DroppedFrame {
+                src: Some(a),
+                reason: DropReason::QueueFull
+            }
+        );
+        // Clones share the counts.
+        dropped.clone().record(Some(a), DropReason::InvalidFragment);
+        assert_eq!(dropped.total(), 5);
+    }
+}
@@ -0,0 +1,285 @@
+//! Fragmentation of packets larger than [`MAX_PACKET_SIZE`].
+//!
+//! Such packets are split into numbered fragments, each sent in its own
+//! `FrameType::SendFragment` frame.  The receiving client collects the fragments of every
+//! packet and yields the packet once all of them arrived.  Packets which are not complete
+//! within [`REASSEMBLY_TIMEOUT`] are dropped, like any other lost packet.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, ensure, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use iroh_base::NodeId;
+use n0_future::time::{Duration, Instant};
+use tracing::debug;
+
+use super::{ConnSendError, DropReason, DroppedFrames};
+use crate::protos::relay::{
+    FragmentHeader, FRAGMENT_HEADER_LEN, MAX_FRAGMENTED_PACKET_SIZE, MAX_PACKET_SIZE,
+};
+
+/// The number of packet bytes in every fragment but the last.
+const FRAGMENT_DATA_LEN: usize = MAX_PACKET_SIZE - FRAGMENT_HEADER_LEN;
+
+/// The maximum number of fragments of a packet.
+const MAX_FRAGMENTS: usize = MAX_FRAGMENTED_PACKET_SIZE.div_ceil(FRAGMENT_DATA_LEN);
+
+/// How long to wait for all fragments of a packet after receiving the first one.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The maximum number of packets reassembled at the same time.
+///
+/// Bounds the memory used by incomplete packets, the oldest one is dropped to make room.
+const MAX_PENDING_PACKETS: usize = 16;
+
+/// The fragmentation state of a relay connection.
+#[derive(Debug, Default)]
+pub(crate) struct Fragments {
+    /// The id of the next fragmented packet.
+    next_id: u32,
+    /// The packets of which not all fragments were received yet, by sender and id.
+    pending: HashMap<(NodeId, u32), PendingPacket>,
+    /// Where the dropped packets are recorded.
+    dropped: DroppedFrames,
+}
+
+/// A packet of which not all fragments were received yet.
+#[derive(Debug)]
+struct PendingPacket {
+    /// When the first fragment was received.
+    started: Instant,
+    /// The fragments received so far, by index.
+    fragments: Vec<Option<Bytes>>,
+    /// The number of fragments not received yet.
+    missing: usize,
+}
+
+impl Fragments {
+    /// Records the packets dropped from now on in `dropped`.
+    pub(crate) fn set_dropped(&mut self, dropped: DroppedFrames) {
+        self.dropped = dropped;
+    }
+
+    /// Where the dropped packets are recorded.
+    pub(crate) fn dropped(&self) -> &DroppedFrames {
+        &self.dropped
+    }
+
+    /// Splits a packet larger than [`MAX_PACKET_SIZE`] into fragments.
+    ///
+    /// The fragments start with their [`FragmentHeader`] and are each at most
+    /// [`MAX_PACKET_SIZE`] long.
+    pub(crate) fn split(&mut self, packet: &[u8]) -> Result<Vec<Bytes>, ConnSendError> {
+        if packet.len() > MAX_FRAGMENTED_PACKET_SIZE {
+            return Err(ConnSendError::Protocol(
+                "Packet exceeds MAX_FRAGMENTED_PACKET_SIZE",
+            ));
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let count = u16::try_from(packet.len().div_ceil(FRAGMENT_DATA_LEN))
+            .expect("MAX_FRAGMENTS fits into a u16");
+        let fragments = packet
+            .chunks(FRAGMENT_DATA_LEN)
+            .zip(0..)
+            .map(|(data, index)| {
+                let header = FragmentHeader { id, index, count };
+                let mut fragment = BytesMut::with_capacity(FRAGMENT_HEADER_LEN + data.len());
+                fragment.put_slice(&header.to_bytes());
+                fragment.put_slice(data);
+                fragment.freeze()
+            })
+            .collect();
+        Ok(fragments)
+    }
+
+    /// Adds a fragment received from `src`.
+    ///
+    /// Returns the packet once all of its fragments were received.
+    pub(crate) fn reassemble(&mut self, src: NodeId, fragment: Bytes) -> Result<Option<Bytes>> {
+        let (header, data) = FragmentHeader::decode(fragment)?;
+        let count = usize::from(header.count);
+        ensure!(count <= MAX_FRAGMENTS, "too many fragments: {count}");
+
+        let now = Instant::now();
+        let dropped = &self.dropped;
+        self.pending.retain(|(src, id), packet| {
+            let expired = now.duration_since(packet.started) >= REASSEMBLY_TIMEOUT;
+            if expired {
+                debug!(src = %src.fmt_short(), id, "reassembly timed out, dropping packet");
+                dropped.record(Some(*src), DropReason::ReassemblyTimeout);
+            }
+            !expired
+        });
+
+        let key = (src, header.id);
+        if !self.pending.contains_key(&key) && self.pending.len() >= MAX_PENDING_PACKETS {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|(_, packet)| packet.started)
+                .map(|(key, _)| *key);
+            if let Some((src, id)) = oldest {
+                debug!(src = %src.fmt_short(), id, "too many incomplete packets, dropping packet");
+                self.pending.remove(&(src, id));
+                self.dropped
+                    .record(Some(src), DropReason::TooManyIncomplete);
+            }
+        }
+
+        let packet = self.pending.entry(key).or_insert_with(|| PendingPacket {
+            started: now,
+            fragments: vec![None; count],
+            missing: count,
+        });
+        if packet.fragments.len() != count {
+            self.pending.remove(&key);
+            bail!("inconsistent fragment count {count}");
+        }
+        let slot = &mut packet.fragments[usize::from(header.index)];
+        if slot.is_none() {
+            *slot = Some(data);
+            packet.missing -= 1;
+        }
+        if packet.missing > 0 {
+            return Ok(None);
+        }
+
+        let packet = self.pending.remove(&key).expect("just accessed");
+        let len = packet.fragments.iter().flatten().map(Bytes::len).sum();
+        let mut buf = BytesMut::with_capacity(len);
+        for fragment in packet.fragments.into_iter().flatten() {
+            buf.put(fragment);
+        }
+        Ok(Some(buf.freeze()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::SecretKey;
+
+    use super::*;
+
+    fn packet(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn test_split_reassemble() -> Result<()> {
+        let src_a = SecretKey::generate(rand::thread_rng()).public();
+        let src_b = SecretKey::generate(rand::thread_rng()).public();
+        let mut sender = Fragments::default();
+        let mut receiver = Fragments::default();
+
+        let packet_a = packet(3 * MAX_PACKET_SIZE);
+        let packet_b = packet(MAX_PACKET_SIZE + 1);
+        let fragments_a = sender.split(&packet_a)?;
+        let fragments_b = sender.split(&packet_b)?;
+        assert_eq!(fragments_a.len(), 4);
+        assert_eq!(fragments_b.len(), 2);
+        assert!(fragments_a.iter().all(|f| f.len() <= MAX_PACKET_SIZE));
+
+        // Fragments of different packets and senders interleave and arrive out of order.
+        let (last_a, rest_a) = fragments_a.split_last().unwrap();
+        for fragment in rest_a.iter().rev() {
+            assert_eq!(receiver.reassemble(src_a, fragment.clone())?, None);
+        }
+        assert_eq!(receiver.reassemble(src_b, fragments_b[1].clone())?, None);
+        // The same packet id of another sender is a different packet.
+        assert_eq!(receiver.reassemble(src_b, fragments_a[0].clone())?, None);
+        // Duplicates are ignored.
+        assert_eq!(receiver.reassemble(src_a, rest_a[0].clone())?, None);
+        assert_eq!(
+            receiver.reassemble(src_a, last_a.clone())?.as_deref(),
+            Some(&packet_a[..])
+        );
+        assert_eq!(
+            receiver
+                .reassemble(src_b, fragments_b[0].clone())?
+                .as_deref(),
+            Some(&packet_b[..])
+        );
+        assert_eq!(receiver.pending.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_limits() -> Result<()> {
+        let mut fragments = Fragments::default();
+        let max = fragments.split(&packet(MAX_FRAGMENTED_PACKET_SIZE))?;
+        assert_eq!(max.len(), MAX_FRAGMENTS);
+        assert!(fragments
+            .split(&packet(MAX_FRAGMENTED_PACKET_SIZE + 1))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_fragments() -> Result<()> {
+        let src = SecretKey::generate(rand::thread_rng()).public();
+        let mut receiver = Fragments::default();
+
+        let too_many = FragmentHeader {
+            id: 0,
+            index: 0,
+            count: MAX_FRAGMENTS as u16 + 1,
+        };
+        let fragment = Bytes::copy_from_slice(&too_many.to_bytes());
+        assert!(receiver.reassemble(src, fragment).is_err());
+
+        let first = FragmentHeader {
+            id: 1,
+            index: 0,
+            count: 3,
+        };
+        let inconsistent = FragmentHeader {
+            id: 1,
+            index: 1,
+            count: 2,
+        };
+        assert_eq!(
+            receiver.reassemble(src, Bytes::copy_from_slice(&first.to_bytes()))?,
+            None
+        );
+        let fragment = Bytes::copy_from_slice(&inconsistent.to_bytes());
+        assert!(receiver.reassemble(src, fragment).is_err());
+        assert!(receiver.pending.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reassembly_timeout() -> Result<()> {
+        let src = SecretKey::generate(rand::thread_rng()).public();
+        let mut sender = Fragments::default();
+        let mut receiver = Fragments::default();
+
+        let fragments = sender.split(&packet(2 * MAX_PACKET_SIZE))?;
+        assert_eq!(receiver.reassemble(src, fragments[0].clone())?, None);
+        tokio::time::advance(REASSEMBLY_TIMEOUT).await;
+        // The first fragment was dropped, the packet starts over.
+        assert_eq!(receiver.reassemble(src, fragments[1].clone())?, None);
+        assert_eq!(receiver.reassemble(src, fragments[2].clone())?, None);
+        assert_eq!(receiver.pending.len(), 1);
+        assert_eq!(receiver.dropped().count(DropReason::ReassemblyTimeout), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_pending_packets() -> Result<()> {
+        let src = SecretKey::generate(rand::thread_rng()).public();
+        let mut sender = Fragments::default();
+        let mut receiver = Fragments::default();
+
+        let packets: Vec<_> = (0..=MAX_PENDING_PACKETS)
+            .map(|_| sender.split(&packet(MAX_PACKET_SIZE + 1)))
+            .collect::<Result<_, _>>()?;
+        for fragments in &packets {
+            assert_eq!(receiver.reassemble(src, fragments[0].clone())?, None);
+        }
+        assert_eq!(receiver.pending.len(), MAX_PENDING_PACKETS);
+        assert_eq!(receiver.dropped().count(DropReason::TooManyIncomplete), 1);
+        assert_eq!(receiver.dropped().by_sender(), vec![(src, 1)]);
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+//! Opt-in detection of dead relay connections by pinging the server.
+//!
+//! A connection whose server vanished, e.g. because the network of a mobile device
+//! changed, is only detected by the TCP stack once its retransmissions time out, which can
+//! take many minutes.  Instead the client pings the server whenever nothing was received
+//! for the ping interval, and reports the connection as lost once a number of pings in a
+//! row went unanswered.
+//!
+//! The pings are sent by the receiving half of the connection, which also works once the
+//! [`Client`] is split: the halves share the connection.
+//!
+//! [`Client`]: super::Client
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    num::NonZeroU32,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use n0_future::time::{self, Duration, Instant, Sleep};
+
+use crate::protos::relay::Frame;
+
+/// Configuration for pinging the relay server to detect dead connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingKeepaliveConfig {
+    /// How long the connection may be idle before the server is pinged.
+    ///
+    /// This is also the time the server has to answer a ping.
+    pub interval: Duration,
+    /// The number of consecutive unanswered pings after which the connection is lost.
+    pub max_unanswered: NonZeroU32,
+}
+
+impl Default for PingKeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            max_unanswered: NonZeroU32::new(3).expect("non-zero"),
+        }
+    }
+}
+
+/// What the connection needs to do after the ping interval elapsed.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Tick {
+    /// Send a ping with this payload.
+    Ping([u8; 8]),
+    /// The connection is lost after this many unanswered pings.
+    Lost(u32),
+}
+
+/// The keep-alive state of a connection.
+#[derive(Debug)]
+pub(crate) struct PingKeepalive {
+    config: PingKeepaliveConfig,
+    timer: Pin<Box<Sleep>>,
+    /// Whether anything was received since the timer last fired.
+    received: bool,
+    /// The number of pings sent since anything was last received.
+    unanswered: u32,
+    /// The payloads of the unanswered pings, their pongs are not delivered.
+    outstanding: VecDeque<[u8; 8]>,
+    /// Whether the connection was reported as lost.
+    lost: bool,
+}
+
+impl PingKeepalive {
+    pub(super) fn new(config: PingKeepaliveConfig) -> Self {
+        Self {
+            config,
+            timer: Box::pin(time::sleep(config.interval)),
+            received: false,
+            unanswered: 0,
+            outstanding: VecDeque::new(),
+            lost: false,
+        }
+    }
+
+    /// Whether the connection was reported as lost.
+    pub(super) fn is_lost(&self) -> bool {
+        self.lost
+    }
+
+    /// Records a frame received from the server.
+    ///
+    /// Returns `true` if the frame is the pong of a keep-alive ping, which is not delivered.
+    pub(super) fn on_received(&mut self, frame: &Frame) -> bool {
+        self.received = true;
+        self.unanswered = 0;
+        match frame {
+            Frame::Pong { data } => match self.outstanding.iter().position(|d| d == data) {
+                Some(pos) => {
+                    self.outstanding.remove(pos);
+                    true
+                }
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Polls the ping interval.
+    ///
+    /// Once the interval elapsed without anything received, a ping is due, unless too many
+    /// pings went unanswered already.
+    pub(super) fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<Tick> {
+        if self.lost {
+            return Poll::Pending;
+        }
+        loop {
+            if self.timer.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.timer
+                .as_mut()
+                .reset(Instant::now() + self.config.interval);
+            if std::mem::take(&mut self.received) {
+                continue;
+            }
+            if self.unanswered >= self.config.max_unanswered.get() {
+                self.lost = true;
+                return Poll::Ready(Tick::Lost(self.unanswered));
+            }
+            self.unanswered += 1;
+            let data: [u8; 8] = rand::random();
+            if self.outstanding.len() >= self.config.max_unanswered.get() as usize {
+                self.outstanding.pop_front();
+            }
+            self.outstanding.push_back(data);
+            return Poll::Ready(Tick::Ping(data));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn tick(keepalive: &mut PingKeepalive) -> Poll<Tick> {
+        std::future::poll_fn(|cx| Poll::Ready(keepalive.poll_tick(cx))).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ping_keepalive() {
+        let interval = Duration::from_secs(1);
+        let mut keepalive = PingKeepalive::new(PingKeepaliveConfig {
+            interval,
+            max_unanswered: NonZeroU32::new(2).unwrap(),
+        });
+        assert_eq!(tick(&mut keepalive).await, Poll::Pending);
+
+        // Received frames postpone the pings.
+        time::sleep(interval / 2).await;
+        assert!(!keepalive.on_received(&Frame::KeepAlive));
+        time::sleep(interval / 2).await;
+        assert_eq!(tick(&mut keepalive).await, Poll::Pending);
+
+        time::sleep(interval).await;
+        let Poll::Ready(Tick::Ping(first)) = tick(&mut keepalive).await else {
+            panic!("expected ping");
+        };
+        assert_eq!(tick(&mut keepalive).await, Poll::Pending);
+        // The pong of a keep-alive ping is not delivered, other pongs are.
+        assert!(keepalive.on_received(&Frame::Pong { data: first }));
+        assert!(!keepalive.on_received(&Frame::Pong { data: first }));
+        assert!(!keepalive.on_received(&Frame::Pong { data: [1; 8] }));
+
+        time::sleep(interval).await;
+        assert_eq!(tick(&mut keepalive).await, Poll::Pending);
+        time::sleep(interval).await;
+        assert!(matches!(
+            tick(&mut keepalive).await,
+            Poll::Ready(Tick::Ping(_))
+        ));
+        time::sleep(interval).await;
+        assert!(matches!(
+            tick(&mut keepalive).await,
+            Poll::Ready(Tick::Ping(_))
+        ));
+        time::sleep(interval).await;
+        assert_eq!(tick(&mut keepalive).await, Poll::Ready(Tick::Lost(2)));
+        assert!(keepalive.is_lost());
+        time::sleep(interval).await;
+        assert_eq!(tick(&mut keepalive).await, Poll::Pending);
+    }
+}
@@ -0,0 +1,267 @@
+//! Measuring the round-trip time to the relay server.
+//!
+//! The latency is measured by pinging the server, on request or periodically.  Like the
+//! keep-alive pings, the probes are sent by the receiving half of the connection, which
+//! also matches the pongs to the probes and does not deliver them.  The receiving half
+//! needs to be polled for measurements to complete.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Waker},
+};
+
+use n0_future::{
+    boxed::BoxStream,
+    time::{self, Duration, Instant, Sleep},
+};
+use tokio::sync::{broadcast, oneshot};
+
+use crate::protos::relay::Frame;
+
+/// The capacity of the sample channel.
+const SAMPLES_CAPACITY: usize = 64;
+
+/// The max number of probes awaiting their pong, older probes are given up.
+const MAX_OUTSTANDING: usize = 16;
+
+/// The latency probes of a connection, shared by the connection and the client halves.
+#[derive(Debug, Clone)]
+pub(crate) struct LatencyProbes(Arc<Shared>);
+
+#[derive(Debug)]
+struct Shared {
+    inner: Mutex<Inner>,
+    samples: broadcast::Sender<Duration>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// The probes in the order they were requested.
+    probes: VecDeque<Probe>,
+    /// The latest measured round-trip time.
+    last: Option<Duration>,
+    /// The waker of the receiving half, to send requested probes.
+    waker: Option<Waker>,
+    /// Whether the connection is closed, probes can not complete anymore.
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct Probe {
+    data: [u8; 8],
+    /// When the ping was sent, `None` until it is.
+    sent: Option<Instant>,
+    /// Where to report the round-trip time, `None` for periodic probes.
+    reply: Option<oneshot::Sender<Duration>>,
+}
+
+impl Default for LatencyProbes {
+    fn default() -> Self {
+        let (samples, _) = broadcast::channel(SAMPLES_CAPACITY);
+        Self(Arc::new(Shared {
+            inner: Default::default(),
+            samples,
+        }))
+    }
+}
+
+impl LatencyProbes {
+    /// Requests a probe, the receiver completes with its round-trip time.
+    ///
+    /// The receiver fails if the connection closes before the pong is received.
+    pub(crate) fn request(&self) -> oneshot::Receiver<Duration> {
+        let (tx, rx) = oneshot::channel();
+        self.push(Some(tx));
+        rx
+    }
+
+    /// Returns the latest measured round-trip time.
+    pub(crate) fn last(&self) -> Option<Duration> {
+        self.0.inner.lock().expect("poisoned").last
+    }
+
+    /// Returns a stream of the round-trip times measured from now on.
+    ///
+    /// Samples are skipped if the stream is not polled fast enough to keep up.
+    pub(crate) fn samples(&self) -> BoxStream<Duration> {
+        let samples = self.0.samples.subscribe();
+        Box::pin(n0_future::stream::unfold(
+            samples,
+            |mut samples| async move {
+                loop {
+                    match samples.recv().await {
+                        Ok(rtt) => return Some((rtt, samples)),
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            },
+        ))
+    }
+
+    fn push(&self, reply: Option<oneshot::Sender<Duration>>) {
+        let mut inner = self.0.inner.lock().expect("poisoned");
+        if inner.closed {
+            // Dropping the reply fails the request.
+            return;
+        }
+        if inner.probes.len() >= MAX_OUTSTANDING {
+            inner.probes.pop_front();
+        }
+        inner.probes.push_back(Probe {
+            data: rand::random(),
+            sent: None,
+            reply,
+        });
+        if let Some(waker) = inner.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The connection side of the latency probes.
+#[derive(Debug, Default)]
+pub(super) struct LatencyProber {
+    probes: LatencyProbes,
+    /// The interval of the periodic probes and its timer, disabled when `None`.
+    periodic: Option<(Duration, Pin<Box<Sleep>>)>,
+}
+
+impl LatencyProber {
+    /// The probes shared with the client.
+    pub(super) fn probes(&self) -> &LatencyProbes {
+        &self.probes
+    }
+
+    /// Starts probing the latency every `interval`.
+    pub(super) fn set_interval(&mut self, interval: Duration) {
+        self.periodic = Some((interval, Box::pin(time::sleep(interval))));
+    }
+
+    /// Polls whether a probe is waiting to be sent.
+    pub(super) fn poll_due(&mut self, cx: &mut Context<'_>) -> bool {
+        if let Some((interval, timer)) = &mut self.periodic {
+            if timer.as_mut().poll(cx).is_ready() {
+                timer.as_mut().reset(Instant::now() + *interval);
+                // Registers the waker with the timer again.
+                let _ = timer.as_mut().poll(cx);
+                self.probes.push(None);
+            }
+        }
+        let mut inner = self.probes.0.inner.lock().expect("poisoned");
+        let due = inner.probes.iter().any(|p| p.sent.is_none());
+        if !due {
+            match inner.waker {
+                Some(ref w) if w.will_wake(cx.waker()) => {}
+                _ => inner.waker = Some(cx.waker().clone()),
+            }
+        }
+        due
+    }
+
+    /// Marks the next waiting probe as sent, returning the payload of its ping.
+    pub(super) fn start(&mut self) -> Option<[u8; 8]> {
+        let mut inner = self.probes.0.inner.lock().expect("poisoned");
+        let probe = inner.probes.iter_mut().find(|p| p.sent.is_none())?;
+        probe.sent = Some(Instant::now());
+        Some(probe.data)
+    }
+
+    /// Records a frame received from the server.
+    ///
+    /// Returns `true` if the frame is the pong of a probe, which is not delivered.
+    pub(super) fn on_received(&mut self, frame: &Frame) -> bool {
+        let Frame::Pong { data } = frame else {
+            return false;
+        };
+        let rtt = {
+            let mut inner = self.probes.0.inner.lock().expect("poisoned");
+            let Some(pos) = inner
+                .probes
+                .iter()
+                .position(|p| p.sent.is_some() && p.data == *data)
+            else {
+                return false;
+            };
+            let probe = inner.probes.remove(pos).expect("exists");
+            let rtt = probe.sent.expect("sent").elapsed();
+            inner.last = Some(rtt);
+            if let Some(reply) = probe.reply {
+                // The requester may be gone.
+                reply.send(rtt).ok();
+            }
+            rtt
+        };
+        // Nobody may be listening.
+        self.probes.0.samples.send(rtt).ok();
+        true
+    }
+
+    /// Fails the outstanding and future probes, once the connection is closed.
+    pub(super) fn close(&mut self) {
+        let mut inner = self.probes.0.inner.lock().expect("poisoned");
+        inner.closed = true;
+        inner.probes.clear();
+    }
+}
+
+impl Drop for LatencyProber {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::task::Poll;
+
+    use n0_future::StreamExt;
+
+    use super::*;
+
+    async fn due(prober: &mut LatencyProber) -> bool {
+        std::future::poll_fn(|cx| Poll::Ready(prober.poll_due(cx))).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_latency_probes() {
+        let mut prober = LatencyProber::default();
+        let probes = prober.probes().clone();
+        let mut samples = probes.samples();
+        assert!(!due(&mut prober).await);
+        assert_eq!(prober.start(), None);
+
+        let rtt = probes.request();
+        assert!(due(&mut prober).await);
+        let data = prober.start().unwrap();
+        assert!(!due(&mut prober).await);
+        time::sleep(Duration::from_millis(20)).await;
+        // Only the pongs of probes are swallowed.
+        assert!(!prober.on_received(&Frame::Pong { data: [1; 8] }));
+        assert!(!prober.on_received(&Frame::Ping { data }));
+        assert!(prober.on_received(&Frame::Pong { data }));
+        assert!(!prober.on_received(&Frame::Pong { data }));
+        assert_eq!(rtt.await.unwrap(), Duration::from_millis(20));
+        assert_eq!(probes.last(), Some(Duration::from_millis(20)));
+        assert_eq!(samples.next().await, Some(Duration::from_millis(20)));
+
+        // Periodic probes.
+        prober.set_interval(Duration::from_secs(1));
+        assert!(!due(&mut prober).await);
+        time::sleep(Duration::from_secs(1)).await;
+        assert!(due(&mut prober).await);
+        let data = prober.start().unwrap();
+        time::sleep(Duration::from_millis(30)).await;
+        assert!(prober.on_received(&Frame::Pong { data }));
+        assert_eq!(samples.next().await, Some(Duration::from_millis(30)));
+
+        // Closing fails the outstanding and later probes.
+        let rtt = probes.request();
+        prober.close();
+        assert!(rtt.await.is_err());
+        assert!(probes.request().await.is_err());
+    }
+}
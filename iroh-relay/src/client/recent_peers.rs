@@ -0,0 +1,291 @@
+//! Opt-in tracking of the nodes a relay [`Client`] recently received packets from.
+//!
+//! The nodes are tracked for the current connection only: a new connection starts with an
+//! empty set.  A node is evicted when the server reports it as gone, when nothing was
+//! received from it for the idle timeout, or to make room for a new node once the
+//! capacity is reached.  Evictions are reported as [`PeerEviction`]s, which helps with
+//! debugging and with presence heuristics.
+//!
+//! [`Client`]: super::Client
+
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+
+use iroh_base::NodeId;
+use n0_future::{
+    boxed::BoxStream,
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+
+use super::ReceivedMessage;
+
+/// The capacity of the [`PeerEviction`] channel.
+const EVICTIONS_CAPACITY: usize = 64;
+
+/// The minimum time between two scans for idle nodes.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Configuration for tracking the nodes a relay client recently received packets from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecentPeersConfig {
+    /// The max number of tracked nodes, the least recently seen node is evicted to make
+    /// room for a new one.
+    pub capacity: NonZeroUsize,
+    /// How long a node is tracked after the last packet received from it.
+    pub idle_timeout: Duration,
+}
+
+impl Default for RecentPeersConfig {
+    fn default() -> Self {
+        Self {
+            capacity: NonZeroUsize::new(1024).expect("non-zero"),
+            idle_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// A node a relay client recently received packets from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecentPeer {
+    /// The node the packets were received from.
+    pub node_id: NodeId,
+    /// When the first packet of the node was received on this connection.
+    pub first_seen: Instant,
+    /// When the last packet of the node was received.
+    pub last_seen: Instant,
+    /// The number of packets received from the node.
+    pub packets: u64,
+}
+
+/// Why a node is no longer tracked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// The server reported that the node disconnected, see [`ReceivedMessage::NodeGone`].
+    Gone,
+    /// Nothing was received from the node for [`RecentPeersConfig::idle_timeout`].
+    Idle,
+    /// The node was the least recently seen one when a new node had to be tracked.
+    Capacity,
+}
+
+/// A node which is no longer tracked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerEviction {
+    /// The node as it was last tracked.
+    pub peer: RecentPeer,
+    /// Why the node is no longer tracked.
+    pub reason: EvictionReason,
+}
+
+/// Handle to the nodes a relay client recently received packets from.
+///
+/// Obtained from [`Client::recent_peers`], this is cheap to clone and keeps working after
+/// the client was split.  The nodes are recorded while the client is polled for received
+/// messages.
+///
+/// [`Client::recent_peers`]: super::Client::recent_peers
+#[derive(Debug, Clone)]
+pub struct RecentPeers(Arc<Shared>);
+
+#[derive(Debug)]
+struct Shared {
+    config: RecentPeersConfig,
+    inner: Mutex<Inner>,
+    evictions: broadcast::Sender<PeerEviction>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    peers: HashMap<NodeId, RecentPeer>,
+    /// When the tracked nodes were last scanned for idle ones.
+    last_sweep: Instant,
+}
+
+impl RecentPeers {
+    pub(super) fn new(config: RecentPeersConfig) -> Self {
+        let (evictions, _) = broadcast::channel(EVICTIONS_CAPACITY);
+        Self(Arc::new(Shared {
+            config,
+            inner: Mutex::new(Inner {
+                peers: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+            evictions,
+        }))
+    }
+
+    /// Returns the tracked nodes, the most recently seen first.
+    pub fn list(&self) -> Vec<RecentPeer> {
+        let mut inner = self.0.inner.lock().expect("poisoned");
+        self.sweep(&mut inner, Instant::now());
+        let mut peers: Vec<_> = inner.peers.values().cloned().collect();
+        peers.sort_by_key(|peer| std::cmp::Reverse(peer.last_seen));
+        peers
+    }
+
+    /// Returns the tracked node, if a packet was received from it recently.
+    pub fn get(&self, node_id: &NodeId) -> Option<RecentPeer> {
+        let mut inner = self.0.inner.lock().expect("poisoned");
+        self.sweep(&mut inner, Instant::now());
+        inner.peers.get(node_id).cloned()
+    }
+
+    /// Returns a stream of the nodes evicted from now on.
+    ///
+    /// Evictions are dropped if the stream is not polled fast enough to keep up.
+    pub fn evictions(&self) -> BoxStream<PeerEviction> {
+        let evictions = self.0.evictions.subscribe();
+        Box::pin(n0_future::stream::unfold(
+            evictions,
+            |mut evictions| async move {
+                loop {
+                    match evictions.recv().await {
+                        Ok(eviction) => return Some((eviction, evictions)),
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            },
+        ))
+    }
+
+    /// Records a message received by the client.
+    pub(super) fn on_received(&self, msg: &ReceivedMessage) {
+        let now = Instant::now();
+        match msg {
+            ReceivedMessage::ReceivedPacket { remote_node_id, .. } => {
+                let mut inner = self.0.inner.lock().expect("poisoned");
+                self.sweep(&mut inner, now);
+                if let Some(peer) = inner.peers.get_mut(remote_node_id) {
+                    peer.last_seen = now;
+                    peer.packets += 1;
+                    return;
+                }
+                if inner.peers.len() >= self.0.config.capacity.get() {
+                    let oldest = inner
+                        .peers
+                        .values()
+                        .min_by_key(|peer| peer.last_seen)
+                        .map(|peer| peer.node_id);
+                    if let Some(peer) = oldest.and_then(|node_id| inner.peers.remove(&node_id)) {
+                        self.evicted(peer, EvictionReason::Capacity);
+                    }
+                }
+                inner.peers.insert(
+                    *remote_node_id,
+                    RecentPeer {
+                        node_id: *remote_node_id,
+                        first_seen: now,
+                        last_seen: now,
+                        packets: 1,
+                    },
+                );
+            }
+            ReceivedMessage::NodeGone(node_id) => {
+                let peer = self.0.inner.lock().expect("poisoned").peers.remove(node_id);
+                if let Some(peer) = peer {
+                    self.evicted(peer, EvictionReason::Gone);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Evicts the idle nodes, at most once per [`SWEEP_INTERVAL`].
+    fn sweep(&self, inner: &mut Inner, now: Instant) {
+        if now.duration_since(inner.last_sweep) < SWEEP_INTERVAL {
+            return;
+        }
+        inner.last_sweep = now;
+        let idle_timeout = self.0.config.idle_timeout;
+        let idle: Vec<_> = inner
+            .peers
+            .values()
+            .filter(|peer| now.duration_since(peer.last_seen) >= idle_timeout)
+            .map(|peer| peer.node_id)
+            .collect();
+        for node_id in idle {
+            if let Some(peer) = inner.peers.remove(&node_id) {
+                self.evicted(peer, EvictionReason::Idle);
+            }
+        }
+    }
+
+    fn evicted(&self, peer: RecentPeer, reason: EvictionReason) {
+        // Nobody may be listening.
+        self.0.evictions.send(PeerEviction { peer, reason }).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use iroh_base::SecretKey;
+    use n0_future::StreamExt;
+
+    use super::*;
+
+    fn packet(from: NodeId) -> ReceivedMessage {
+        ReceivedMessage::ReceivedPacket {
+            remote_node_id: from,
+            data: Bytes::from_static(b"hello"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_recent_peers() {
+        let peers = RecentPeers::new(RecentPeersConfig {
+            capacity: 2.try_into().unwrap(),
+            idle_timeout: Duration::from_secs(10),
+        });
+        let mut evictions = peers.evictions();
+        let a = SecretKey::generate(rand::thread_rng()).public();
+        let b = SecretKey::generate(rand::thread_rng()).public();
+        let c = SecretKey::generate(rand::thread_rng()).public();
+
+        peers.on_received(&packet(a));
+        tokio::time::advance(Duration::from_secs(2)).await;
+        peers.on_received(&packet(b));
+        tokio::time::advance(Duration::from_millis(10)).await;
+        peers.on_received(&packet(a));
+        let list = peers.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].node_id, a);
+        assert_eq!(list[0].packets, 2);
+        assert_eq!(
+            list[0].last_seen - list[0].first_seen,
+            Duration::from_millis(2010)
+        );
+        assert_eq!(list[1].node_id, b);
+
+        // A is seen more recently than B, which makes room for C.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        peers.on_received(&packet(a));
+        peers.on_received(&packet(c));
+        let eviction = evictions.next().await.unwrap();
+        assert_eq!(eviction.peer.node_id, b);
+        assert_eq!(eviction.reason, EvictionReason::Capacity);
+        assert!(peers.get(&b).is_none());
+
+        peers.on_received(&ReceivedMessage::NodeGone(c));
+        let eviction = evictions.next().await.unwrap();
+        assert_eq!(eviction.peer.node_id, c);
+        assert_eq!(eviction.reason, EvictionReason::Gone);
+
+        // Other messages are not recorded.
+        peers.on_received(&ReceivedMessage::NodeGone(b));
+        peers.on_received(&ReceivedMessage::KeepAlive);
+        assert_eq!(peers.list().len(), 1);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(peers.list().is_empty());
+        let eviction = evictions.next().await.unwrap();
+        assert_eq!(eviction.peer.node_id, a);
+        assert_eq!(eviction.peer.packets, 3);
+        assert_eq!(eviction.reason, EvictionReason::Idle);
+    }
+}
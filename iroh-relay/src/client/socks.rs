@@ -0,0 +1,244 @@
+//! A minimal SOCKS5 client, to reach the relay server through a SOCKS proxy.
+//!
+//! Only the `CONNECT` command of [RFC 1928] is implemented, without authentication or with
+//! the username and password authentication of [RFC 1929].
+//!
+//! [RFC 1928]: https://www.rfc-editor.org/rfc/rfc1928
+//! [RFC 1929]: https://www.rfc-editor.org/rfc/rfc1929
+
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{bail, ensure, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const VERSION: u8 = 5;
+const AUTH_NONE: u8 = 0x00;
+const AUTH_PASSWORD: u8 = 0x02;
+const AUTH_UNACCEPTABLE: u8 = 0xff;
+const AUTH_PASSWORD_VERSION: u8 = 1;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// The destination of a SOCKS5 tunnel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum TargetAddr<'a> {
+    /// An address resolved by the client.
+    Ip(SocketAddr),
+    /// A host name resolved by the proxy, e.g. to not leak DNS requests.
+    Domain(&'a str, u16),
+}
+
+/// Establishes a tunnel to `target` over the `stream` connected to a SOCKS5 proxy.
+///
+/// Once this returns the stream is connected to the target.
+pub(super) async fn connect<S>(
+    stream: &mut S,
+    target: TargetAddr<'_>,
+    auth: Option<(&str, &str)>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Offer the authentication methods.
+    let greeting: &[u8] = match auth {
+        Some(_) => &[VERSION, 2, AUTH_NONE, AUTH_PASSWORD],
+        None => &[VERSION, 1, AUTH_NONE],
+    };
+    stream.write_all(greeting).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    ensure!(reply[0] == VERSION, "invalid SOCKS version {}", reply[0]);
+    match (reply[1], auth) {
+        (AUTH_NONE, _) => {}
+        (AUTH_PASSWORD, Some((username, password))) => {
+            authenticate(stream, username, password).await?
+        }
+        (AUTH_UNACCEPTABLE, _) => bail!("SOCKS proxy requires authentication"),
+        (method, _) => bail!("SOCKS proxy chose unoffered authentication method {method}"),
+    }
+
+    // Request the tunnel.
+    let mut request = vec![VERSION, CMD_CONNECT, 0];
+    let port = match target {
+        TargetAddr::Ip(addr) => {
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    request.push(ATYP_IPV4);
+                    request.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    request.push(ATYP_IPV6);
+                    request.extend_from_slice(&ip.octets());
+                }
+            }
+            addr.port()
+        }
+        TargetAddr::Domain(host, port) => {
+            let len = u8::try_from(host.len())
+                .ok()
+                .filter(|len| *len > 0)
+                .ok_or_else(|| anyhow::anyhow!("invalid host name length {}", host.len()))?;
+            request.push(ATYP_DOMAIN);
+            request.push(len);
+            request.extend_from_slice(host.as_bytes());
+            port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    ensure!(reply[0] == VERSION, "invalid SOCKS version {}", reply[0]);
+    if reply[1] != 0 {
+        bail!("SOCKS proxy failed to connect: {}", reply_error(reply[1]));
+    }
+    // The address the proxy bound for the tunnel is of no use.
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        atyp => bail!("invalid SOCKS address type {atyp}"),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+/// Authenticates with username and password, as of RFC 1929.
+async fn authenticate<S>(stream: &mut S, username: &str, password: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (Ok(username_len), Ok(password_len)) =
+        (u8::try_from(username.len()), u8::try_from(password.len()))
+    else {
+        bail!("SOCKS username and password must be at most 255 bytes");
+    };
+    let mut request = vec![AUTH_PASSWORD_VERSION, username_len];
+    request.extend_from_slice(username.as_bytes());
+    request.push(password_len);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    ensure!(reply[1] == 0, "SOCKS proxy authentication failed");
+    Ok(())
+}
+
+fn reply_error(rep: u8) -> &'static str {
+    match rep {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    /// Plays the proxy side of a tunnel request, returning the requested target.
+    async fn serve(
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+        credentials: Option<(&str, &str)>,
+    ) -> Result<Vec<u8>> {
+        let mut header = [0u8; 2];
+        stream.read_exact(&mut header).await?;
+        let mut methods = vec![0u8; header[1] as usize];
+        stream.read_exact(&mut methods).await?;
+        match credentials {
+            Some((username, password)) => {
+                if !methods.contains(&AUTH_PASSWORD) {
+                    stream.write_all(&[VERSION, AUTH_UNACCEPTABLE]).await?;
+                    bail!("no password offered");
+                }
+                stream.write_all(&[VERSION, AUTH_PASSWORD]).await?;
+                let mut buf = [0u8; 2];
+                stream.read_exact(&mut buf).await?;
+                let mut user = vec![0u8; buf[1] as usize];
+                stream.read_exact(&mut user).await?;
+                let mut pass = vec![0u8; stream.read_u8().await? as usize];
+                stream.read_exact(&mut pass).await?;
+                let ok = user == username.as_bytes() && pass == password.as_bytes();
+                stream
+                    .write_all(&[AUTH_PASSWORD_VERSION, if ok { 0 } else { 1 }])
+                    .await?;
+                ensure!(ok, "wrong credentials");
+            }
+            None => stream.write_all(&[VERSION, AUTH_NONE]).await?,
+        }
+
+        let mut request = [0u8; 4];
+        stream.read_exact(&mut request).await?;
+        let len = match request[3] {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            _ => stream.read_u8().await? as usize,
+        };
+        let mut target = vec![0u8; len + 2];
+        stream.read_exact(&mut target).await?;
+        stream
+            .write_all(&[VERSION, 0, 0, ATYP_IPV4, 127, 0, 0, 1, 0, 80])
+            .await?;
+        Ok(target)
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connect() -> Result<()> {
+        // A host name is resolved by the proxy.
+        let (mut client, mut proxy) = duplex(1024);
+        let target = TargetAddr::Domain("relay.example.com", 443);
+        let (res, target) =
+            tokio::join!(connect(&mut client, target, None), serve(&mut proxy, None));
+        res?;
+        assert_eq!(target?, b"relay.example.com\x01\xbb");
+        // The stream is the tunnel afterwards.
+        proxy.write_all(b"hello").await?;
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+
+        // With credentials.
+        let (mut client, mut proxy) = duplex(1024);
+        let target = TargetAddr::Ip("[::1]:443".parse()?);
+        let credentials = Some(("user", "secret"));
+        let (res, target) = tokio::join!(
+            connect(&mut client, target, credentials),
+            serve(&mut proxy, credentials)
+        );
+        res?;
+        let mut expected = std::net::Ipv6Addr::LOCALHOST.octets().to_vec();
+        expected.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(target?, expected);
+
+        // Wrong or missing credentials fail.
+        let (mut client, mut proxy) = duplex(1024);
+        let target = TargetAddr::Domain("relay.example.com", 443);
+        let (res, _) = tokio::join!(
+            connect(&mut client, target.clone(), Some(("user", "wrong"))),
+            serve(&mut proxy, credentials)
+        );
+        assert!(res.is_err());
+        let (mut client, mut proxy) = duplex(1024);
+        let (res, _) = tokio::join!(
+            connect(&mut client, target, None),
+            serve(&mut proxy, credentials)
+        );
+        assert!(res.is_err());
+        Ok(())
+    }
+}
@@ -14,12 +14,16 @@ use tokio::{
 };
 
 use super::util;
+use crate::protos::webtransport::WebTransportStream;
 
 pub enum MaybeTlsStreamChained {
     Raw(util::Chain<std::io::Cursor<Bytes>, ProxyStream>),
     Tls(util::Chain<std::io::Cursor<Bytes>, tokio_rustls::client::TlsStream<ProxyStream>>),
+    WebTransport(Box<WebTransportStream>),
     #[cfg(all(test, feature = "server"))]
     Mem(tokio::io::DuplexStream),
+    #[cfg(any(test, feature = "test-utils"))]
+    Faulty(Box<crate::faults::FaultyStream<MaybeTlsStreamChained>>),
 }
 
 impl AsyncRead for MaybeTlsStreamChained {
@@ -31,8 +35,11 @@ impl AsyncRead for MaybeTlsStreamChained {
         match &mut *self {
             Self::Raw(stream) => Pin::new(stream).poll_read(cx, buf),
             Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::WebTransport(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
             #[cfg(all(test, feature = "server"))]
             Self::Mem(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(any(test, feature = "test-utils"))]
+            Self::Faulty(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
         }
     }
 }
@@ -46,8 +53,11 @@ impl AsyncWrite for MaybeTlsStreamChained {
         match &mut *self {
             Self::Raw(stream) => Pin::new(stream.get_mut().1).poll_write(cx, buf),
             Self::Tls(stream) => Pin::new(stream.get_mut().1).poll_write(cx, buf),
+            Self::WebTransport(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
             #[cfg(all(test, feature = "server"))]
             Self::Mem(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(any(test, feature = "test-utils"))]
+            Self::Faulty(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
         }
     }
 
@@ -58,8 +68,11 @@ impl AsyncWrite for MaybeTlsStreamChained {
         match &mut *self {
             Self::Raw(stream) => Pin::new(stream.get_mut().1).poll_flush(cx),
             Self::Tls(stream) => Pin::new(stream.get_mut().1).poll_flush(cx),
+            Self::WebTransport(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
             #[cfg(all(test, feature = "server"))]
             Self::Mem(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(any(test, feature = "test-utils"))]
+            Self::Faulty(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
         }
     }
 
@@ -70,8 +83,11 @@ impl AsyncWrite for MaybeTlsStreamChained {
         match &mut *self {
             Self::Raw(stream) => Pin::new(stream.get_mut().1).poll_shutdown(cx),
             Self::Tls(stream) => Pin::new(stream.get_mut().1).poll_shutdown(cx),
+            Self::WebTransport(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
             #[cfg(all(test, feature = "server"))]
             Self::Mem(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(any(test, feature = "test-utils"))]
+            Self::Faulty(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
         }
     }
 
@@ -83,8 +99,11 @@ impl AsyncWrite for MaybeTlsStreamChained {
         match &mut *self {
             Self::Raw(stream) => Pin::new(stream.get_mut().1).poll_write_vectored(cx, bufs),
             Self::Tls(stream) => Pin::new(stream.get_mut().1).poll_write_vectored(cx, bufs),
+            Self::WebTransport(stream) => Pin::new(stream.as_mut()).poll_write_vectored(cx, bufs),
             #[cfg(all(test, feature = "server"))]
             Self::Mem(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            #[cfg(any(test, feature = "test-utils"))]
+            Self::Faulty(stream) => Pin::new(stream.as_mut()).poll_write_vectored(cx, bufs),
         }
     }
 }
@@ -0,0 +1,247 @@
+//! Opt-in sampling of the timing of frames sent by a relay [`Client`].
+//!
+//! Sampling records, for every Nth frame sent:
+//!
+//! - The *queue time*: how long the frame waited for the connection to become ready to
+//!   accept it.  This is time spent in the local host, e.g. because of backpressure from
+//!   the socket.
+//! - The *write time*: how long it took to flush the frame to the connection after it was
+//!   accepted.
+//! - For pings, the *pong time*: how long it took until the matching pong was received
+//!   from the relay server.
+//!
+//! A large queue or write time points to the local host, while a large pong time with
+//! small queue and write times points to the network or the relay server.
+//!
+//! [`Client`]: super::Client
+
+use std::{
+    collections::VecDeque,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+
+use n0_future::time::{Duration, Instant};
+
+use super::{ReceivedMessage, SendMessage};
+
+/// Configuration for sampling the timing of frames sent by a relay client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TelemetryConfig {
+    /// Sample every Nth frame sent.
+    pub sample_every: NonZeroUsize,
+    /// The number of samples kept, older samples are dropped.
+    pub capacity: NonZeroUsize,
+}
+
+/// The kind of a sampled frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampledFrame {
+    /// A [`SendMessage::SendPacket`], [`SendMessage::SendAckedPacket`] or
+    /// [`SendMessage::ForwardPacket`] with the given packet length.
+    Packet(usize),
+    /// A [`SendMessage::Ping`].
+    Ping,
+    /// A [`SendMessage::Pong`].
+    Pong,
+}
+
+/// The timing of a single sampled frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameSample {
+    /// The number of the frame on this connection, counting from zero.
+    pub seq: u64,
+    /// The kind of frame.
+    pub frame: SampledFrame,
+    /// How long the frame waited for the connection to be ready to accept it.
+    pub queue_time: Duration,
+    /// How long it took to flush the frame, `None` until it was flushed.
+    pub write_time: Option<Duration>,
+    /// For pings, how long it took to receive the matching pong, `None` until then.
+    pub pong_time: Option<Duration>,
+    sent_at: Instant,
+    ping: Option<[u8; 8]>,
+}
+
+/// Handle to the samples recorded by a relay client.
+///
+/// Obtained from [`Client::telemetry`], this is cheap to clone and keeps working after
+/// the client was split.
+///
+/// [`Client::telemetry`]: super::Client::telemetry
+#[derive(Debug, Clone)]
+pub struct Telemetry(Arc<Mutex<Inner>>);
+
+#[derive(Debug)]
+struct Inner {
+    config: TelemetryConfig,
+    /// The number of frames sent so far.
+    sent: u64,
+    /// When the sender started waiting for the connection to become ready.
+    ready_since: Option<Instant>,
+    samples: VecDeque<FrameSample>,
+}
+
+impl Telemetry {
+    pub(super) fn new(config: TelemetryConfig) -> Self {
+        Self(Arc::new(Mutex::new(Inner {
+            config,
+            sent: 0,
+            ready_since: None,
+            samples: VecDeque::with_capacity(config.capacity.get()),
+        })))
+    }
+
+    /// Returns the recorded samples, oldest first.
+    pub fn samples(&self) -> Vec<FrameSample> {
+        self.0
+            .lock()
+            .expect("poisoned")
+            .samples
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Records that the sender is waiting for the connection to become ready.
+    pub(super) fn on_poll_ready(&self) {
+        let mut inner = self.0.lock().expect("poisoned");
+        inner.ready_since.get_or_insert_with(Instant::now);
+    }
+
+    /// Records a frame handed to the connection.
+    pub(super) fn on_start_send(&self, msg: &SendMessage) {
+        let (frame, ping) = match msg {
+            SendMessage::SendPacket(_, packet)
+            | SendMessage::SendAckedPacket { packet, .. }
+            | SendMessage::ForwardPacket { packet, .. } => {
+                (SampledFrame::Packet(packet.len()), None)
+            }
+            SendMessage::Ping(data) => (SampledFrame::Ping, Some(*data)),
+            SendMessage::Pong(_) => (SampledFrame::Pong, None),
+            // The last frame of a connection, nothing to learn from its timing.
+            SendMessage::Closing => return,
+            // Sent once per connection at most.
+            SendMessage::WatchConns => return,
+        };
+        let mut inner = self.0.lock().expect("poisoned");
+        let now = Instant::now();
+        let ready_since = inner.ready_since.take().unwrap_or(now);
+        let seq = inner.sent;
+        inner.sent += 1;
+        if seq % inner.config.sample_every.get() as u64 != 0 {
+            return;
+        }
+        if inner.samples.len() == inner.config.capacity.get() {
+            inner.samples.pop_front();
+        }
+        inner.samples.push_back(FrameSample {
+            seq,
+            frame,
+            queue_time: now.duration_since(ready_since),
+            write_time: None,
+            pong_time: None,
+            sent_at: now,
+            ping,
+        });
+    }
+
+    /// Records that all frames handed to the connection were flushed.
+    pub(super) fn on_flushed(&self) {
+        let mut inner = self.0.lock().expect("poisoned");
+        let now = Instant::now();
+        // Frames are flushed in order, so the unflushed samples are the newest ones.
+        for sample in inner
+            .samples
+            .iter_mut()
+            .rev()
+            .take_while(|sample| sample.write_time.is_none())
+        {
+            sample.write_time = Some(now.duration_since(sample.sent_at));
+        }
+    }
+
+    /// Records a message received from the connection.
+    pub(super) fn on_received(&self, msg: &ReceivedMessage) {
+        let ReceivedMessage::Pong(data) = msg else {
+            return;
+        };
+        let mut inner = self.0.lock().expect("poisoned");
+        let now = Instant::now();
+        if let Some(sample) = inner
+            .samples
+            .iter_mut()
+            .rev()
+            .find(|sample| sample.ping == Some(*data) && sample.pong_time.is_none())
+        {
+            sample.pong_time = Some(now.duration_since(sample.sent_at));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use iroh_base::SecretKey;
+
+    use super::*;
+
+    fn telemetry(sample_every: usize, capacity: usize) -> Telemetry {
+        Telemetry::new(TelemetryConfig {
+            sample_every: sample_every.try_into().unwrap(),
+            capacity: capacity.try_into().unwrap(),
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sample_timings() {
+        let telemetry = telemetry(2, 8);
+        let dst = SecretKey::generate(rand::thread_rng()).public();
+
+        // seq 0: sampled
+        telemetry.on_poll_ready();
+        tokio::time::advance(Duration::from_millis(5)).await;
+        telemetry.on_poll_ready();
+        telemetry.on_start_send(&SendMessage::Ping([1; 8]));
+        tokio::time::advance(Duration::from_millis(2)).await;
+        telemetry.on_flushed();
+
+        // seq 1: not sampled
+        telemetry.on_poll_ready();
+        telemetry.on_start_send(&SendMessage::Ping([2; 8]));
+        telemetry.on_flushed();
+
+        // seq 2: sampled, not yet flushed
+        telemetry.on_poll_ready();
+        telemetry.on_start_send(&SendMessage::SendPacket(dst, Bytes::from_static(b"hi")));
+
+        tokio::time::advance(Duration::from_millis(10)).await;
+        telemetry.on_received(&ReceivedMessage::Pong([2; 8]));
+        telemetry.on_received(&ReceivedMessage::Pong([1; 8]));
+
+        let samples = telemetry.samples();
+        assert_eq!(samples.len(), 2);
+
+        assert_eq!(samples[0].seq, 0);
+        assert_eq!(samples[0].frame, SampledFrame::Ping);
+        assert_eq!(samples[0].queue_time, Duration::from_millis(5));
+        assert_eq!(samples[0].write_time, Some(Duration::from_millis(2)));
+        assert_eq!(samples[0].pong_time, Some(Duration::from_millis(12)));
+
+        assert_eq!(samples[1].seq, 2);
+        assert_eq!(samples[1].frame, SampledFrame::Packet(2));
+        assert_eq!(samples[1].queue_time, Duration::ZERO);
+        assert_eq!(samples[1].write_time, None);
+        assert_eq!(samples[1].pong_time, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sample_capacity() {
+        let telemetry = telemetry(1, 3);
+        for i in 0..5 {
+            telemetry.on_start_send(&SendMessage::Pong([i; 8]));
+        }
+        let seqs: Vec<_> = telemetry.samples().iter().map(|s| s.seq).collect();
+        assert_eq!(seqs, [2, 3, 4]);
+    }
+}
@@ -3,16 +3,22 @@
 use std::{
     fmt::{self, Write},
     future::Future,
-    net::{IpAddr, Ipv6Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    str::FromStr,
+    sync::{Arc, Mutex},
 };
 
-use anyhow::{bail, Context, Result};
-use hickory_resolver::{Resolver, TokioResolver};
+use anyhow::{anyhow, bail, Context, Result};
+use hickory_resolver::{
+    proto::rr::{rdata::svcb::SvcParamValue, RData, RecordType},
+    Resolver, TokioResolver,
+};
 use iroh_base::{NodeAddr, NodeId};
 use n0_future::{
-    time::{self, Duration},
+    time::{self, Duration, Instant},
     StreamExt,
 };
+use tracing::debug;
 use url::Url;
 
 pub mod node_info;
@@ -22,9 +28,217 @@ pub const N0_DNS_NODE_ORIGIN_PROD: &str = "dns.iroh.link";
 /// The n0 testing DNS node origin, for testing.
 pub const N0_DNS_NODE_ORIGIN_STAGING: &str = "staging-dns.iroh.link";
 
+/// How long a detected IPv6 connectivity state is trusted before probing again.
+const IPV6_HEALTH_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Address used to probe for a route to the IPv6 internet.
+///
+/// Only a route lookup is done for it, no packets are sent.
+const IPV6_PROBE_ADDR: SocketAddrV6 = SocketAddrV6::new(
+    Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888),
+    53,
+    0,
+    0,
+);
+
+/// Address used to probe for a route to the IPv4 internet.
+///
+/// Only a route lookup is done for it, no packets are sent.
+const IPV4_PROBE_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 53);
+
+/// How long a discovered NAT64 prefix, or its absence, is trusted before discovering again.
+const NAT64_PREFIX_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// The name queried to discover the NAT64 prefix, see [RFC 7050].
+///
+/// [RFC 7050]: https://datatracker.ietf.org/doc/html/rfc7050#section-3
+const IPV4ONLY_ARPA: &str = "ipv4only.arpa.";
+
+/// The well-known IPv4 addresses of [`IPV4ONLY_ARPA`].
+const IPV4ONLY_ARPA_ADDRS: [Ipv4Addr; 2] =
+    [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+
+/// The timeout of the NAT64 prefix discovery.
+const NAT64_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
 /// The DNS resolver used throughout `iroh`.
 #[derive(Debug, Clone)]
-pub struct DnsResolver(TokioResolver);
+pub struct DnsResolver {
+    resolver: TokioResolver,
+    ip_strategy: IpStrategy,
+    /// The last known IPv6 connectivity, shared by all clones of the resolver.
+    ipv6_health: Arc<Mutex<Option<Ipv6Health>>>,
+    nat64: Nat64,
+    /// The last discovered NAT64 prefix, shared by all clones of the resolver.
+    nat64_discovered: Arc<Mutex<Option<Nat64Discovery>>>,
+    /// Whether there is a route to the IPv4 internet, probed once per network.
+    ipv4_route: Arc<Mutex<Option<bool>>>,
+}
+
+/// Whether a [`DnsResolver`] synthesizes IPv6 addresses for IPv4-only hosts.
+///
+/// On IPv6-only networks a NAT64 gateway translates IPv6 addresses of a prefix to IPv4
+/// addresses.  Usually the DNS64 resolver of such networks synthesizes these addresses for
+/// hostnames having only IPv4 addresses, but this does not cover IPv4 literals, e.g. in
+/// relay URLs, nor resolvers without DNS64.  For these the resolver synthesizes the IPv6
+/// addresses itself, see [`DnsResolver::synthesize_ipv6`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Nat64 {
+    /// Synthesize if there is no route to the IPv4 internet, using the prefix discovered
+    /// as described in [RFC 7050].
+    ///
+    /// [RFC 7050]: https://datatracker.ietf.org/doc/html/rfc7050
+    #[default]
+    Auto,
+    /// Always synthesize using the given prefix.
+    Prefix(Nat64Prefix),
+    /// Never synthesize.
+    Disabled,
+}
+
+/// A NAT64 prefix, translating IPv4 addresses to IPv6 addresses as described in [RFC 6052].
+///
+/// [RFC 6052]: https://datatracker.ietf.org/doc/html/rfc6052#section-2.2
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Nat64Prefix {
+    prefix: Ipv6Addr,
+    len: u8,
+}
+
+impl Nat64Prefix {
+    /// The Well-Known Prefix `64:ff9b::/96`.
+    pub const WELL_KNOWN: Self = Self {
+        prefix: Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0),
+        len: 96,
+    };
+
+    /// The prefix lengths allowed by RFC 6052, in the order they are tried when discovering.
+    const LENGTHS: [u8; 6] = [96, 64, 56, 48, 40, 32];
+
+    /// Creates a prefix of the given length, which must be 32, 40, 48, 56, 64 or 96.
+    ///
+    /// The bits of `prefix` after `len` are ignored.
+    pub fn new(prefix: Ipv6Addr, len: u8) -> Result<Self> {
+        if !Self::LENGTHS.contains(&len) {
+            bail!("invalid NAT64 prefix length: {len}");
+        }
+        let mask = u128::MAX << (128 - len as u32);
+        let prefix = Ipv6Addr::from(u128::from(prefix) & mask);
+        Ok(Self { prefix, len })
+    }
+
+    /// Returns the prefix address.
+    pub fn prefix(&self) -> Ipv6Addr {
+        self.prefix
+    }
+
+    /// Returns the prefix length.
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+
+    /// Embeds the IPv4 address into the prefix.
+    pub fn synthesize(&self, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.prefix.octets();
+        let start = self.len as usize / 8;
+        let mut pos = start;
+        for byte in ip.octets() {
+            // Bits 64 to 71 are reserved and must be zero.
+            if pos == 8 {
+                pos += 1;
+            }
+            octets[pos] = byte;
+            pos += 1;
+        }
+        Ipv6Addr::from(octets)
+    }
+
+    /// Extracts the embedded IPv4 address, if the address is within the prefix.
+    pub fn extract(&self, ip: Ipv6Addr) -> Option<Ipv4Addr> {
+        if Self::new(ip, self.len).ok()? != *self {
+            return None;
+        }
+        let octets = ip.octets();
+        let mut pos = self.len as usize / 8;
+        let mut v4 = [0u8; 4];
+        for byte in &mut v4 {
+            if pos == 8 {
+                pos += 1;
+            }
+            *byte = octets[pos];
+            pos += 1;
+        }
+        Some(Ipv4Addr::from(v4))
+    }
+
+    /// Finds the prefix of an address of `ipv4only.arpa`, as described in [RFC 7050].
+    ///
+    /// [RFC 7050]: https://datatracker.ietf.org/doc/html/rfc7050#section-3
+    fn from_ipv4only_arpa(ip: Ipv6Addr) -> Option<Self> {
+        Self::LENGTHS.into_iter().find_map(|len| {
+            let prefix = Self::new(ip, len).expect("valid length");
+            let embedded = prefix.extract(ip)?;
+            IPV4ONLY_ARPA_ADDRS.contains(&embedded).then_some(prefix)
+        })
+    }
+}
+
+impl fmt::Display for Nat64Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.prefix, self.len)
+    }
+}
+
+impl FromStr for Nat64Prefix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (prefix, len) = s.split_once('/').context("missing prefix length")?;
+        Self::new(prefix.parse()?, len.parse()?)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Nat64Discovery {
+    prefix: Option<Nat64Prefix>,
+    updated: Instant,
+}
+
+/// How a [`DnsResolver`] orders the IPv4 and IPv6 addresses of a host.
+///
+/// This applies to all lookups returning addresses of both families, like
+/// [`DnsResolver::lookup_ipv4_ipv6`] and [`DnsResolver::resolve_host`], and thus to relay
+/// dialing and the HTTP requests of net reports.  Within each family addresses are sorted by
+/// the precedence of the [RFC 6724] default policy table, e.g. global IPv6 addresses before
+/// unique local ones.
+///
+/// [RFC 6724]: https://datatracker.ietf.org/doc/html/rfc6724#section-2.1
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpStrategy {
+    /// IPv4 addresses first, unless the caller prefers IPv6.
+    ///
+    /// The relay client prefers IPv6 once IPv6 connectivity has been detected.
+    #[default]
+    Ipv4ThenIpv6,
+    /// IPv6 addresses first.
+    Ipv6ThenIpv4,
+    /// IPv6 addresses first, unless IPv6 connectivity is broken.
+    ///
+    /// IPv6 is considered broken when dialing over IPv6 failed while the IPv4 fallback
+    /// succeeded, or when a probe finds no route to the IPv6 internet.  The connectivity
+    /// state is trusted for five minutes, after which IPv6 is probed again.
+    Ipv6UnlessBroken,
+    /// Only IPv4 addresses.
+    Ipv4Only,
+    /// Only IPv6 addresses.
+    Ipv6Only,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Ipv6Health {
+    working: bool,
+    updated: Instant,
+}
 
 impl DnsResolver {
     /// Create a new DNS resolver with sensible cross-platform defaults.
@@ -55,7 +269,7 @@ impl DnsResolver {
         options.ip_strategy = hickory_resolver::config::LookupIpStrategy::Ipv4thenIpv6;
 
         let resolver = Resolver::tokio(config, options);
-        DnsResolver(resolver)
+        DnsResolver::from(resolver)
     }
 
     /// Create a new DNS resolver configured with a single UDP DNS nameserver.
@@ -66,21 +280,259 @@ impl DnsResolver {
             hickory_resolver::proto::xfer::Protocol::Udp,
         );
         config.add_name_server(nameserver_config);
-        DnsResolver(Resolver::tokio(config, Default::default()))
+        DnsResolver::from(Resolver::tokio(config, Default::default()))
+    }
+
+    /// Sets how addresses of both families are ordered, see [`IpStrategy`].
+    pub fn with_ip_strategy(mut self, ip_strategy: IpStrategy) -> Self {
+        self.ip_strategy = ip_strategy;
+        self
+    }
+
+    /// Returns how addresses of both families are ordered.
+    pub fn ip_strategy(&self) -> IpStrategy {
+        self.ip_strategy
+    }
+
+    /// Sets whether IPv6 addresses are synthesized for IPv4-only hosts, see [`Nat64`].
+    pub fn with_nat64(mut self, nat64: Nat64) -> Self {
+        self.nat64 = nat64;
+        self
+    }
+
+    /// Returns the NAT64 prefix to synthesize IPv6 addresses with, if any.
+    ///
+    /// With [`Nat64::Auto`] the prefix is only discovered if there is no route to the IPv4
+    /// internet, the discovered prefix is shared by all clones of the resolver and trusted
+    /// for five minutes.  Both are forgotten by [`DnsResolver::clear_cache`], e.g. when the
+    /// network changed.
+    pub async fn nat64_prefix(&self) -> Option<Nat64Prefix> {
+        if self.ip_strategy == IpStrategy::Ipv4Only {
+            return None;
+        }
+        match self.nat64 {
+            Nat64::Disabled => None,
+            Nat64::Prefix(prefix) => Some(prefix),
+            Nat64::Auto => {
+                if self.has_ipv4_route().await {
+                    return None;
+                }
+                let discovered = *self.nat64_discovered.lock().expect("poisoned");
+                if let Some(discovered) = discovered {
+                    if discovered.updated.elapsed() < NAT64_PREFIX_TIMEOUT {
+                        return discovered.prefix;
+                    }
+                }
+                let prefix = self.discover_nat64_prefix().await;
+                debug!(?prefix, "discovered NAT64 prefix");
+                *self.nat64_discovered.lock().expect("poisoned") = Some(Nat64Discovery {
+                    prefix,
+                    updated: Instant::now(),
+                });
+                prefix
+            }
+        }
+    }
+
+    /// Synthesizes an IPv6 address reaching the IPv4 address through NAT64.
+    ///
+    /// Returns `None` if there is no NAT64 prefix, see [`DnsResolver::nat64_prefix`].
+    pub async fn synthesize_ipv6(&self, ip: Ipv4Addr) -> Option<Ipv6Addr> {
+        self.nat64_prefix()
+            .await
+            .map(|prefix| prefix.synthesize(ip))
+    }
+
+    /// Returns whether there is a route to the IPv4 internet, probing it if unknown.
+    async fn has_ipv4_route(&self) -> bool {
+        if let Some(route) = *self.ipv4_route.lock().expect("poisoned") {
+            return route;
+        }
+        let route = probe_route(probe_ipv4_route).await;
+        *self.ipv4_route.lock().expect("poisoned") = Some(route);
+        route
+    }
+
+    /// Discovers the NAT64 prefix by looking up the IPv6 addresses of `ipv4only.arpa`.
+    async fn discover_nat64_prefix(&self) -> Option<Nat64Prefix> {
+        match self
+            .lookup_ipv6(IPV4ONLY_ARPA, NAT64_DISCOVERY_TIMEOUT)
+            .await
+        {
+            Ok(addrs) => addrs.into_iter().find_map(|ip| match ip {
+                IpAddr::V6(ip) => Nat64Prefix::from_ipv4only_arpa(ip),
+                IpAddr::V4(_) => None,
+            }),
+            Err(err) => {
+                debug!("no NAT64 prefix: {err:#}");
+                None
+            }
+        }
+    }
+
+    /// Synthesizes IPv6 addresses for IPv4 addresses, if there is a NAT64 prefix.
+    async fn synthesize_all(&self, v4: &[IpAddr]) -> Vec<IpAddr> {
+        if v4.is_empty() {
+            return Vec::new();
+        }
+        let Some(prefix) = self.nat64_prefix().await else {
+            return Vec::new();
+        };
+        v4.iter()
+            .filter_map(|ip| match ip {
+                IpAddr::V4(ip) => Some(IpAddr::V6(prefix.synthesize(*ip))),
+                IpAddr::V6(_) => None,
+            })
+            .collect()
+    }
+
+    /// Reports whether connecting over IPv6 works.
+    ///
+    /// Used by [`IpStrategy::Ipv6UnlessBroken`], the relay client reports this itself when
+    /// dialing.
+    pub fn report_ipv6_connectivity(&self, working: bool) {
+        let mut health = self.ipv6_health.lock().expect("poisoned");
+        if !matches!(*health, Some(health) if health.working == working) {
+            debug!(working, "IPv6 connectivity changed");
+        }
+        *health = Some(Ipv6Health {
+            working,
+            updated: Instant::now(),
+        });
+    }
+
+    /// Returns whether IPv6 connectivity is broken, probing it if unknown.
+    async fn ipv6_broken(&self) -> bool {
+        let health = *self.ipv6_health.lock().expect("poisoned");
+        match health {
+            Some(health) if health.updated.elapsed() < IPV6_HEALTH_TIMEOUT => !health.working,
+            _ => {
+                let working = probe_route(probe_ipv6_route).await;
+                self.report_ipv6_connectivity(working);
+                !working
+            }
+        }
+    }
+
+    /// Orders looked up addresses according to the [`IpStrategy`].
+    async fn order_addrs(
+        &self,
+        mut v4: Vec<IpAddr>,
+        mut v6: Vec<IpAddr>,
+        prefer_ipv6: bool,
+    ) -> Vec<IpAddr> {
+        let ipv6_first = match self.ip_strategy {
+            IpStrategy::Ipv4ThenIpv6 => prefer_ipv6,
+            IpStrategy::Ipv6ThenIpv4 => true,
+            // The order only matters, and IPv6 is only probed, with addresses of both families.
+            IpStrategy::Ipv6UnlessBroken => {
+                v4.is_empty() || (!v6.is_empty() && !self.ipv6_broken().await)
+            }
+            IpStrategy::Ipv4Only => {
+                v6.clear();
+                false
+            }
+            IpStrategy::Ipv6Only => {
+                v4.clear();
+                true
+            }
+        };
+        v4.sort_by_key(|ip| std::cmp::Reverse(rfc6724_precedence(ip)));
+        v6.sort_by_key(|ip| std::cmp::Reverse(rfc6724_precedence(ip)));
+        if ipv6_first {
+            v6.append(&mut v4);
+            v6
+        } else {
+            v4.append(&mut v6);
+            v4
+        }
+    }
+
+    /// Looks up the addresses of the families allowed by the [`IpStrategy`], concurrently.
+    async fn lookup_families(
+        &self,
+        host: &str,
+        timeout: Duration,
+    ) -> Result<(Vec<IpAddr>, Vec<IpAddr>)> {
+        let want_v4 = self.ip_strategy != IpStrategy::Ipv6Only;
+        let want_v6 = self.ip_strategy != IpStrategy::Ipv4Only;
+        let res = tokio::join!(
+            async {
+                match want_v4 {
+                    true => self.lookup_ipv4(host, timeout).await.map(Iterator::collect),
+                    false => Ok(Vec::new()),
+                }
+            },
+            async {
+                match want_v6 {
+                    true => self.lookup_ipv6(host, timeout).await.map(Iterator::collect),
+                    false => Ok(Vec::new()),
+                }
+            },
+        );
+        let (v4, v6) = match res {
+            (Ok(v4), Ok(v6)) => (v4, v6),
+            (Ok(v4), Err(_)) => (v4, Vec::new()),
+            (Err(_), Ok(v6)) => (Vec::new(), v6),
+            (Err(ipv4_err), Err(ipv6_err)) => {
+                bail!("Ipv4: {:?}, Ipv6: {:?}", ipv4_err, ipv6_err)
+            }
+        };
+        // Without DNS64 IPv4-only hosts are unreachable from IPv6-only networks.
+        let v6 = match want_v6 && v6.is_empty() {
+            true => self.synthesize_all(&v4).await,
+            false => v6,
+        };
+        Ok((v4, v6))
     }
 
     /// Removes all entries from the cache.
+    ///
+    /// This also forgets the probed route to the IPv4 internet and the discovered NAT64
+    /// prefix, which depend on the network.
     pub fn clear_cache(&self) {
-        self.0.clear_cache();
+        self.resolver.clear_cache();
+        *self.ipv4_route.lock().expect("poisoned") = None;
+        *self.nat64_discovered.lock().expect("poisoned") = None;
     }
 
     /// Lookup a TXT record.
     pub async fn lookup_txt(&self, host: impl ToString, timeout: Duration) -> Result<TxtLookup> {
         let host = host.to_string();
-        let res = time::timeout(timeout, self.0.txt_lookup(host)).await??;
+        let res = time::timeout(timeout, self.resolver.txt_lookup(host)).await??;
         Ok(TxtLookup(res))
     }
 
+    /// Looks up the ECH config list published in the `HTTPS` record of a host.
+    ///
+    /// Returns the raw `ECHConfigList` of the first record advertising one, or `None` if
+    /// the host has no `HTTPS` record or none of them contain an ECH config.
+    pub async fn lookup_ech_config_list(
+        &self,
+        host: impl ToString,
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>> {
+        let host = host.to_string();
+        let lookup =
+            match time::timeout(timeout, self.resolver.lookup(host, RecordType::HTTPS)).await? {
+                Ok(lookup) => lookup,
+                Err(err) if err.is_no_records_found() => return Ok(None),
+                Err(err) => return Err(err.into()),
+            };
+        let ech_config_list = lookup
+            .iter()
+            .filter_map(|rdata| match rdata {
+                RData::HTTPS(https) => Some(https),
+                _ => None,
+            })
+            .flat_map(|https| https.svc_params())
+            .find_map(|(_, value)| match value {
+                SvcParamValue::EchConfigList(ech) => Some(ech.0.clone()),
+                _ => None,
+            });
+        Ok(ech_config_list)
+    }
+
     /// Perform an ipv4 lookup with a timeout.
     pub async fn lookup_ipv4(
         &self,
@@ -88,7 +540,7 @@ impl DnsResolver {
         timeout: Duration,
     ) -> Result<impl Iterator<Item = IpAddr>> {
         let host = host.to_string();
-        let addrs = time::timeout(timeout, self.0.ipv4_lookup(host)).await??;
+        let addrs = time::timeout(timeout, self.resolver.ipv4_lookup(host)).await??;
         Ok(addrs.into_iter().map(|ip| IpAddr::V4(ip.0)))
     }
 
@@ -99,7 +551,7 @@ impl DnsResolver {
         timeout: Duration,
     ) -> Result<impl Iterator<Item = IpAddr>> {
         let host = host.to_string();
-        let addrs = time::timeout(timeout, self.0.ipv6_lookup(host)).await??;
+        let addrs = time::timeout(timeout, self.resolver.ipv6_lookup(host)).await??;
         Ok(addrs.into_iter().map(|ip| IpAddr::V6(ip.0)))
     }
 
@@ -108,54 +560,67 @@ impl DnsResolver {
     /// `LookupIpStrategy::Ipv4AndIpv6` will wait for ipv6 resolution timeout, even if it is
     /// not usable on the stack, so we manually query both lookups concurrently and time them out
     /// individually.
+    ///
+    /// The addresses are ordered according to the [`IpStrategy`] of the resolver.
     pub async fn lookup_ipv4_ipv6(
         &self,
         host: impl ToString,
         timeout: Duration,
     ) -> Result<impl Iterator<Item = IpAddr>> {
         let host = host.to_string();
-        let res = tokio::join!(
-            self.lookup_ipv4(host.clone(), timeout),
-            self.lookup_ipv6(host, timeout)
-        );
-
-        match res {
-            (Ok(ipv4), Ok(ipv6)) => Ok(LookupIter::Both(ipv4.chain(ipv6))),
-            (Ok(ipv4), Err(_)) => Ok(LookupIter::Ipv4(ipv4)),
-            (Err(_), Ok(ipv6)) => Ok(LookupIter::Ipv6(ipv6)),
-            (Err(ipv4_err), Err(ipv6_err)) => {
-                bail!("Ipv4: {:?}, Ipv6: {:?}", ipv4_err, ipv6_err)
-            }
+        let (v4, v6) = self.lookup_families(&host, timeout).await?;
+        let addrs = self.order_addrs(v4, v6, false).await;
+        if addrs.is_empty() {
+            bail!("No addresses allowed by {:?}", self.ip_strategy);
         }
+        Ok(addrs.into_iter())
     }
 
     /// Resolve a hostname from a URL to an IP address.
+    ///
+    /// Returns the first address of [`Self::resolve_host_addrs`].
     pub async fn resolve_host(
         &self,
         url: &Url,
         prefer_ipv6: bool,
         timeout: Duration,
     ) -> Result<IpAddr> {
+        let addrs = self.resolve_host_addrs(url, prefer_ipv6, timeout).await?;
+        addrs.into_iter().next().context("No response")
+    }
+
+    /// Resolve a hostname from a URL to its IP addresses, in the order to connect to them.
+    ///
+    /// The addresses are ordered according to the [`IpStrategy`] of the resolver,
+    /// `prefer_ipv6` puts IPv6 addresses first for [`IpStrategy::Ipv4ThenIpv6`].  An IP
+    /// address in the URL is returned as is, preceded by the address synthesized for an
+    /// IPv4 address if there is a NAT64 prefix, see [`Nat64`].
+    pub async fn resolve_host_addrs(
+        &self,
+        url: &Url,
+        prefer_ipv6: bool,
+        timeout: Duration,
+    ) -> Result<Vec<IpAddr>> {
         let host = url.host().context("Invalid URL")?;
         match host {
             url::Host::Domain(domain) => {
                 // Need to do a DNS lookup
-                let lookup = tokio::join!(
-                    self.lookup_ipv4(domain, timeout),
-                    self.lookup_ipv6(domain, timeout)
-                );
-                let (v4, v6) = match lookup {
-                    (Err(ipv4_err), Err(ipv6_err)) => {
-                        bail!("Ipv4: {ipv4_err:?}, Ipv6: {ipv6_err:?}");
-                    }
-                    (Err(_), Ok(mut v6)) => (None, v6.next()),
-                    (Ok(mut v4), Err(_)) => (v4.next(), None),
-                    (Ok(mut v4), Ok(mut v6)) => (v4.next(), v6.next()),
-                };
-                if prefer_ipv6 { v6.or(v4) } else { v4.or(v6) }.context("No response")
+                let (v4, v6) = self.lookup_families(domain, timeout).await?;
+                let addrs = self.order_addrs(v4, v6, prefer_ipv6).await;
+                if addrs.is_empty() {
+                    return Err(anyhow!("No response"));
+                }
+                Ok(addrs)
             }
-            url::Host::Ipv4(ip) => Ok(IpAddr::V4(ip)),
-            url::Host::Ipv6(ip) => Ok(IpAddr::V6(ip)),
+            url::Host::Ipv4(ip) => {
+                let v4 = vec![IpAddr::V4(ip)];
+                let v6 = self.synthesize_all(&v4).await;
+                // The IPv4 address is tried last even if not allowed by the strategy.
+                let mut addrs = self.order_addrs(Vec::new(), v6, true).await;
+                addrs.extend(v4);
+                Ok(addrs)
+            }
+            url::Host::Ipv6(ip) => Ok(vec![IpAddr::V6(ip)]),
         }
     }
 
@@ -269,7 +734,14 @@ impl Default for DnsResolver {
 
 impl From<TokioResolver> for DnsResolver {
     fn from(resolver: TokioResolver) -> Self {
-        DnsResolver(resolver)
+        DnsResolver {
+            resolver,
+            ip_strategy: IpStrategy::default(),
+            ipv6_health: Default::default(),
+            nat64: Nat64::default(),
+            nat64_discovered: Default::default(),
+            ipv4_route: Default::default(),
+        }
     }
 }
 
@@ -324,22 +796,54 @@ const WINDOWS_BAD_SITE_LOCAL_DNS_SERVERS: [IpAddr; 3] = [
     IpAddr::V6(Ipv6Addr::new(0xfec0, 0, 0, 0xffff, 0, 0, 0, 3)),
 ];
 
-/// Helper enum to give a unified type to the iterators of [`DnsResolver::lookup_ipv4_ipv6`].
-enum LookupIter<A, B> {
-    Ipv4(A),
-    Ipv6(B),
-    Both(std::iter::Chain<A, B>),
+/// Runs a route probe on the blocking thread pool, it binds and connects a socket.
+async fn probe_route(probe: fn() -> bool) -> bool {
+    tokio::task::spawn_blocking(probe).await.unwrap_or(false)
+}
+
+/// Returns whether there is a route to the IPv4 internet.
+fn probe_ipv4_route() -> bool {
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| socket.connect(IPV4_PROBE_ADDR))
+        .is_ok()
 }
 
-impl<A: Iterator<Item = IpAddr>, B: Iterator<Item = IpAddr>> Iterator for LookupIter<A, B> {
-    type Item = IpAddr;
+/// Returns whether there is a route to the IPv6 internet.
+fn probe_ipv6_route() -> bool {
+    UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))
+        .and_then(|socket| socket.connect(IPV6_PROBE_ADDR))
+        .is_ok()
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        match self {
-            LookupIter::Ipv4(iter) => iter.next(),
-            LookupIter::Ipv6(iter) => iter.next(),
-            LookupIter::Both(iter) => iter.next(),
-        }
+/// The precedence of an address in the [RFC 6724] default policy table, higher is preferred.
+///
+/// IPv4 addresses are treated as IPv4-mapped IPv6 addresses.
+///
+/// [RFC 6724]: https://datatracker.ietf.org/doc/html/rfc6724#section-2.1
+fn rfc6724_precedence(ip: &IpAddr) -> u8 {
+    let ip = match ip {
+        IpAddr::V4(_) => return 35,
+        IpAddr::V6(ip) => ip,
+    };
+    let segments = ip.segments();
+    if ip.is_loopback() {
+        50
+    } else if ip.to_ipv4_mapped().is_some() {
+        35
+    } else if segments[0] == 0x2002 {
+        // 6to4
+        30
+    } else if segments[0] == 0x2001 && segments[1] == 0 {
+        // Teredo
+        5
+    } else if segments[0] & 0xfe00 == 0xfc00 {
+        // unique local
+        3
+    } else if segments[..6] == [0; 6] || segments[0] & 0xffc0 == 0xfec0 || segments[0] == 0x3ffe {
+        // IPv4-compatible, site-local and 6bone
+        1
+    } else {
+        40
     }
 }
 
@@ -406,4 +910,182 @@ pub(crate) mod tests {
         let result = stagger_call(f, &delays).await.unwrap();
         assert_eq!(result, 5)
     }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_ip_strategy_order() {
+        let v4 = vec![ip("192.0.2.1"), ip("192.0.2.2")];
+        let v6 = vec![ip("fd00::1"), ip("2001:db8::1")];
+        let order = |strategy, prefer_ipv6| {
+            let v4 = v4.clone();
+            let v6 = v6.clone();
+            async move {
+                DnsResolver::new()
+                    .with_ip_strategy(strategy)
+                    .order_addrs(v4, v6, prefer_ipv6)
+                    .await
+            }
+        };
+
+        // Global IPv6 addresses sort before unique local ones.
+        let v6_sorted = [ip("2001:db8::1"), ip("fd00::1")];
+        let v4_first: Vec<_> = v4.iter().chain(&v6_sorted).copied().collect();
+        let v6_first: Vec<_> = v6_sorted.iter().chain(&v4).copied().collect();
+
+        assert_eq!(order(IpStrategy::Ipv4ThenIpv6, false).await, v4_first);
+        assert_eq!(order(IpStrategy::Ipv4ThenIpv6, true).await, v6_first);
+        assert_eq!(order(IpStrategy::Ipv6ThenIpv4, false).await, v6_first);
+        assert_eq!(order(IpStrategy::Ipv4Only, true).await, v4);
+        assert_eq!(order(IpStrategy::Ipv6Only, false).await, v6_sorted);
+    }
+
+    #[tokio::test]
+    async fn test_ip_strategy_ipv6_unless_broken() {
+        let v4 = vec![ip("192.0.2.1")];
+        let v6 = vec![ip("2001:db8::1")];
+        let resolver = DnsResolver::new().with_ip_strategy(IpStrategy::Ipv6UnlessBroken);
+        let health = |resolver: &DnsResolver| {
+            resolver
+                .ipv6_health
+                .lock()
+                .unwrap()
+                .map(|health| health.working)
+        };
+
+        // With addresses of one family only the order does not matter, IPv6 is not probed.
+        assert_eq!(
+            resolver.order_addrs(Vec::new(), v6.clone(), false).await,
+            v6
+        );
+        assert_eq!(
+            resolver.order_addrs(v4.clone(), Vec::new(), false).await,
+            v4
+        );
+        assert_eq!(health(&resolver), None);
+
+        // Clones share the connectivity state.
+        let dialer = resolver.clone();
+        dialer.report_ipv6_connectivity(true);
+        assert_eq!(
+            resolver.order_addrs(v4.clone(), v6.clone(), false).await,
+            [v6[0], v4[0]]
+        );
+
+        dialer.report_ipv6_connectivity(false);
+        assert_eq!(
+            resolver.order_addrs(v4.clone(), v6.clone(), false).await,
+            [v4[0], v6[0]]
+        );
+
+        // Unknown connectivity is probed.
+        let resolver = DnsResolver::new().with_ip_strategy(IpStrategy::Ipv6UnlessBroken);
+        let addrs = resolver.order_addrs(v4.clone(), v6.clone(), false).await;
+        let working = health(&resolver).expect("probed");
+        assert_eq!(addrs[0], if working { v6[0] } else { v4[0] });
+    }
+
+    #[test]
+    fn test_nat64_prefix() {
+        // The examples of RFC 6052, section 2.4.
+        let v4 = Ipv4Addr::new(192, 0, 2, 33);
+        let cases = [
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::/96", "2001:db8:122:344::192.0.2.33"),
+        ];
+        for (prefix, synthesized) in cases {
+            let prefix: Nat64Prefix = prefix.parse().unwrap();
+            let synthesized: Ipv6Addr = synthesized.parse().unwrap();
+            assert_eq!(prefix.synthesize(v4), synthesized, "{prefix}");
+            assert_eq!(prefix.extract(synthesized), Some(v4), "{prefix}");
+        }
+
+        assert_eq!(
+            Nat64Prefix::WELL_KNOWN.synthesize(v4),
+            "64:ff9b::c000:221".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(Nat64Prefix::WELL_KNOWN.extract(ip6("2001:db8::1")), None);
+        assert!("64:ff9b::/80".parse::<Nat64Prefix>().is_err());
+        assert!("64:ff9b::".parse::<Nat64Prefix>().is_err());
+    }
+
+    fn ip6(s: &str) -> Ipv6Addr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_nat64_prefix_from_ipv4only_arpa() {
+        assert_eq!(
+            Nat64Prefix::from_ipv4only_arpa(ip6("64:ff9b::c000:aa")),
+            Some(Nat64Prefix::WELL_KNOWN)
+        );
+        assert_eq!(
+            Nat64Prefix::from_ipv4only_arpa(ip6("2001:db8:122:344:c0:0:ab00:0")),
+            Some("2001:db8:122:344::/64".parse().unwrap())
+        );
+        assert_eq!(
+            Nat64Prefix::from_ipv4only_arpa(ip6("2001:db8:c000:ab::")),
+            Some("2001:db8::/32".parse().unwrap())
+        );
+        assert_eq!(Nat64Prefix::from_ipv4only_arpa(ip6("2001:db8::1")), None);
+    }
+
+    #[tokio::test]
+    async fn test_ipv4_route_cached() {
+        let resolver = DnsResolver::new().with_nat64(Nat64::Auto);
+        let route = resolver.has_ipv4_route().await;
+        assert_eq!(*resolver.ipv4_route.lock().unwrap(), Some(route));
+
+        // The cached route is used by all clones, with a route there is no NAT64.
+        *resolver.ipv4_route.lock().unwrap() = Some(true);
+        assert_eq!(resolver.clone().nat64_prefix().await, None);
+
+        // A network change probes again.
+        resolver.clear_cache();
+        assert_eq!(*resolver.ipv4_route.lock().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_nat64_resolve_ipv4_literal() {
+        let url: Url = "https://192.0.2.33:443".parse().unwrap();
+        let resolver = DnsResolver::new().with_nat64(Nat64::Prefix(Nat64Prefix::WELL_KNOWN));
+        let addrs = resolver
+            .resolve_host_addrs(&url, false, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(addrs, [ip("64:ff9b::c000:221"), ip("192.0.2.33")]);
+
+        // IPv4 only never synthesizes.
+        let addrs = resolver
+            .with_ip_strategy(IpStrategy::Ipv4Only)
+            .resolve_host_addrs(&url, false, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(addrs, [ip("192.0.2.33")]);
+
+        let resolver = DnsResolver::new().with_nat64(Nat64::Disabled);
+        let addrs = resolver
+            .resolve_host_addrs(&url, false, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(addrs, [ip("192.0.2.33")]);
+    }
+
+    #[test]
+    fn test_rfc6724_precedence() {
+        assert_eq!(rfc6724_precedence(&ip("::1")), 50);
+        assert_eq!(rfc6724_precedence(&ip("2001:db8::1")), 40);
+        assert_eq!(rfc6724_precedence(&ip("192.0.2.1")), 35);
+        assert_eq!(rfc6724_precedence(&ip("::ffff:192.0.2.1")), 35);
+        assert_eq!(rfc6724_precedence(&ip("2002:c000:201::1")), 30);
+        assert_eq!(rfc6724_precedence(&ip("2001:0:4136:e378::1")), 5);
+        assert_eq!(rfc6724_precedence(&ip("fd12:3456::1")), 3);
+        assert_eq!(rfc6724_precedence(&ip("fec0::1")), 1);
+    }
 }
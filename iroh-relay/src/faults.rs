@@ -0,0 +1,322 @@
+//! Fault injection for relay connections, for tests only.
+//!
+//! A [`FaultyStream`] wraps the IO stream of a relay connection and injects latency,
+//! partial writes, stalls and disconnects, as configured by a [`FaultConfig`].  Faults
+//! triggered by byte counts are deterministic, the [`FaultHandle`] of the config allows
+//! triggering stalls and disconnects at a chosen point of a test.
+//!
+//! Faults can be enabled for the client with [`ClientBuilder::faults`], and for the
+//! connections accepted by the relay server with [`RelayConfig::faults`].  Needs the
+//! `test-utils` feature outside of the tests of this crate.
+//!
+//! [`ClientBuilder::faults`]: crate::client::ClientBuilder::faults
+//! [`RelayConfig::faults`]: crate::server::RelayConfig::faults
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll, Waker},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+
+/// The faults injected by a [`FaultyStream`].
+///
+/// The defaults inject no faults.
+#[derive(Debug, Clone, Default)]
+pub struct FaultConfig {
+    /// Delay before each read from and write to the stream.
+    pub latency: Option<Duration>,
+    /// Maximum number of bytes accepted by a single write, forcing partial writes.
+    pub max_write_len: Option<usize>,
+    /// Stalls the stream once this many bytes have been written.
+    ///
+    /// Writes are truncated so that exactly this many bytes are written, which allows
+    /// stalling in the middle of a frame.
+    pub stall_after: Option<usize>,
+    /// Disconnects the stream once this many bytes have been written.
+    ///
+    /// Writes are truncated so that exactly this many bytes are written before the
+    /// disconnect, which allows disconnecting in the middle of a frame.
+    pub disconnect_after: Option<usize>,
+    /// Controls all streams created with this config.
+    pub handle: FaultHandle,
+}
+
+/// Triggers faults at runtime, in all streams sharing the handle.
+#[derive(Debug, Clone, Default)]
+pub struct FaultHandle(Arc<FaultState>);
+
+#[derive(Debug, Default)]
+struct FaultState {
+    stalled: AtomicBool,
+    disconnected: AtomicBool,
+    /// Tasks waiting for a stall to end.
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl FaultHandle {
+    /// Stalls all reads and writes until [`Self::resume`] is called.
+    pub fn stall(&self) {
+        self.0.stalled.store(true, Ordering::SeqCst);
+    }
+
+    /// Resumes reads and writes after a stall.
+    pub fn resume(&self) {
+        self.0.stalled.store(false, Ordering::SeqCst);
+        self.wake();
+    }
+
+    /// Fails all further reads and writes with [`io::ErrorKind::ConnectionReset`].
+    pub fn disconnect(&self) {
+        self.0.disconnected.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+
+    /// Returns whether the streams are stalled.
+    pub fn is_stalled(&self) -> bool {
+        self.0.stalled.load(Ordering::SeqCst)
+    }
+
+    /// Returns whether the streams are disconnected.
+    pub fn is_disconnected(&self) -> bool {
+        self.0.disconnected.load(Ordering::SeqCst)
+    }
+
+    fn wake(&self) {
+        for waker in self.0.wakers.lock().expect("poisoned").drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Checks for stalls and disconnects, registering the task to be woken after a stall.
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.is_disconnected() {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if self.is_stalled() {
+            self.0
+                .wakers
+                .lock()
+                .expect("poisoned")
+                .push(cx.waker().clone());
+            // Resuming may have raced with registering the waker.
+            if self.is_stalled() && !self.is_disconnected() {
+                return Poll::Pending;
+            }
+            return self.poll_ready(cx);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// An IO stream injecting faults, see the [module docs](self).
+#[derive(derive_more::Debug)]
+pub struct FaultyStream<S> {
+    inner: S,
+    config: FaultConfig,
+    /// Number of bytes written so far.
+    written: usize,
+    #[debug(skip)]
+    read_delay: Option<Pin<Box<Sleep>>>,
+    #[debug(skip)]
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> FaultyStream<S> {
+    /// Wraps a stream, injecting the configured faults.
+    pub fn new(inner: S, config: FaultConfig) -> Self {
+        Self {
+            inner,
+            config,
+            written: 0,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+
+    /// Returns the wrapped stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the handle controlling this stream.
+    pub fn handle(&self) -> &FaultHandle {
+        &self.config.handle
+    }
+}
+
+/// Waits for the configured latency, starting a new delay after each completed wait.
+fn poll_latency(
+    latency: Option<Duration>,
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut Context<'_>,
+) -> Poll<()> {
+    let Some(latency) = latency else {
+        return Poll::Ready(());
+    };
+    let sleep = delay.get_or_insert_with(|| Box::pin(tokio::time::sleep(latency)));
+    ready!(sleep.as_mut().poll(cx));
+    *delay = None;
+    Poll::Ready(())
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for FaultyStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.config.handle.poll_ready(cx))?;
+        ready!(poll_latency(this.config.latency, &mut this.read_delay, cx));
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FaultyStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let mut len = buf.len();
+        if let Some(limit) = this.config.disconnect_after {
+            if this.written >= limit {
+                this.config.handle.disconnect();
+            }
+            len = len.min(limit.saturating_sub(this.written));
+        }
+        if let Some(limit) = this.config.stall_after {
+            if this.written >= limit {
+                this.config.handle.stall();
+                // Only stall once, resuming must allow writing beyond the limit.
+                this.config.stall_after = None;
+            } else {
+                len = len.min(limit - this.written);
+            }
+        }
+        ready!(this.config.handle.poll_ready(cx))?;
+        ready!(poll_latency(this.config.latency, &mut this.write_delay, cx));
+        if let Some(max_write_len) = this.config.max_write_len {
+            len = len.min(max_write_len.max(1));
+        }
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]))?;
+        this.written += n;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.config.handle.poll_ready(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.config.handle.is_disconnected() {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_partial_writes() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut stream = FaultyStream::new(
+            a,
+            FaultConfig {
+                max_write_len: Some(3),
+                ..Default::default()
+            },
+        );
+        assert_eq!(stream.write(b"hello world").await.unwrap(), 3);
+        stream.write_all(b"lo world").await.unwrap();
+        let mut buf = [0u8; 11];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_mid_write() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut stream = FaultyStream::new(
+            a,
+            FaultConfig {
+                disconnect_after: Some(5),
+                ..Default::default()
+            },
+        );
+        let err = stream.write_all(b"hello world").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert!(stream.handle().is_disconnected());
+        let err = stream.read(&mut [0u8; 8]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+
+        let mut buf = [0u8; 5];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_stall_and_resume() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let config = FaultConfig {
+            stall_after: Some(5),
+            ..Default::default()
+        };
+        let handle = config.handle.clone();
+        let mut stream = FaultyStream::new(a, config);
+        let write = tokio::spawn(async move {
+            stream.write_all(b"hello world").await.unwrap();
+            stream
+        });
+
+        let mut buf = [0u8; 5];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(handle.is_stalled());
+        assert!(!write.is_finished());
+
+        handle.resume();
+        let _stream = write.await.unwrap();
+        let mut buf = [0u8; 6];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b" world");
+    }
+
+    #[tokio::test]
+    async fn test_latency() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut stream = FaultyStream::new(
+            a,
+            FaultConfig {
+                latency: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+        );
+        let start = Instant::now();
+        b.write_all(b"hi").await.unwrap();
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
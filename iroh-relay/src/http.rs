@@ -2,6 +2,7 @@
 
 pub(crate) const HTTP_UPGRADE_PROTOCOL: &str = "iroh derp http";
 pub(crate) const WEBSOCKET_UPGRADE_PROTOCOL: &str = "websocket";
+pub(crate) const WEBTRANSPORT_CONNECT_PROTOCOL: &str = "webtransport";
 #[cfg(feature = "server")] // only used in the server for now
 pub(crate) const SUPPORTED_WEBSOCKET_VERSION: &str = "13";
 
@@ -10,6 +11,37 @@ pub(crate) const SUPPORTED_WEBSOCKET_VERSION: &str = "13";
 pub const RELAY_PATH: &str = "/relay";
 /// The HTTP path under which the relay allows doing latency queries for testing.
 pub const RELAY_PROBE_PATH: &str = "/ping";
+/// The HTTP path under which the relay publishes its ECH configs, if configured.
+///
+/// This is the well-known URI from draft-ietf-tls-wkech, allowing the `HTTPS` DNS
+/// records of the relay to be kept in sync with the ECH keys in use.
+pub const ECH_CONFIG_PATH: &str = "/.well-known/origin-svcb";
+/// The TLS ALPN protocol identifying relay connections.
+///
+/// Relay servers and clients advertise this next to [`HTTP_1_1_ALPN`], which remains in
+/// use by legacy and websocket clients.  Connections negotiating this protocol use direct
+/// framing: the client sends its [`ClientInfo`] frame right after the TLS handshake,
+/// skipping the HTTP upgrade round trip.
+///
+/// [`ClientInfo`]: crate::protos::relay::FrameType::ClientInfo
+pub const RELAY_ALPN: &[u8] = b"iroh-relay";
+/// The TLS ALPN protocol of plain HTTP/1.1 connections.
+pub const HTTP_1_1_ALPN: &[u8] = b"http/1.1";
+/// The TLS ALPN protocol of HTTP/2 connections.
+///
+/// Relay servers with [`TlsConfig::http2`] enabled accept relay connections as extended
+/// `CONNECT` requests (RFC 8441) on HTTP/2 connections, multiplexing many of them on a
+/// single connection.
+///
+/// [`TlsConfig::http2`]: crate::server::TlsConfig::http2
+pub const H2_ALPN: &[u8] = b"h2";
+/// The TLS ALPN protocol of HTTP/3 connections.
+///
+/// Relay servers with [`TlsConfig::webtransport`] enabled accept WebTransport sessions on
+/// QUIC connections negotiating this protocol, see [`Protocol::WebTransport`].
+///
+/// [`TlsConfig::webtransport`]: crate::server::TlsConfig::webtransport
+pub const H3_ALPN: &[u8] = b"h3";
 /// The legacy HTTP path under which the relay used to accept relaying connections.
 /// We keep this for backwards compatibility.
 #[cfg(feature = "server")] // legacy paths only used on server-side for backwards compat
@@ -17,6 +49,7 @@ pub(crate) const LEGACY_RELAY_PATH: &str = "/derp";
 
 /// The HTTP upgrade protocol used for relaying.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum Protocol {
     /// Relays over the custom relaying protocol with a custom HTTP upgrade header.
     Relay,
@@ -24,6 +57,12 @@ pub enum Protocol {
     ///
     /// Originally introduced to support browser connections.
     Websocket,
+    /// Relays over a stream of a WebTransport session over HTTP/3.
+    ///
+    /// The session is established on a QUIC connection to the UDP port of the HTTPS
+    /// server, the frames use the same framing as [`Protocol::Relay`].  Not supported in
+    /// browsers by this crate.
+    WebTransport,
 }
 
 impl Default for Protocol {
@@ -37,14 +76,19 @@ impl Default for Protocol {
 
 impl Protocol {
     /// The HTTP upgrade header used or expected.
+    ///
+    /// For [`Protocol::WebTransport`] this is the `:protocol` of the `CONNECT` request.
     pub const fn upgrade_header(&self) -> &'static str {
         match self {
             Protocol::Relay => HTTP_UPGRADE_PROTOCOL,
             Protocol::Websocket => WEBSOCKET_UPGRADE_PROTOCOL,
+            Protocol::WebTransport => WEBTRANSPORT_CONNECT_PROTOCOL,
         }
     }
 
     /// Tries to match the value of an HTTP upgrade header to figure out which protocol should be initiated.
+    ///
+    /// WebTransport is only established over HTTP/3, it is never matched.
     pub fn parse_header(header: &http::HeaderValue) -> Option<Self> {
         let header_bytes = header.as_bytes();
         if header_bytes == Protocol::Relay.upgrade_header().as_bytes() {
@@ -1,13 +1,27 @@
 use std::{
     num::NonZeroUsize,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
 };
 
+#[cfg(feature = "server")]
+use iroh_base::inc;
 use iroh_base::PublicKey;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "server")]
+use crate::server::Metrics;
 
 type SignatureError = <PublicKey as TryFrom<&'static [u8]>>::Error;
 type PublicKeyBytes = [u8; PublicKey::LENGTH];
 
+/// The maximum number of shards of a [`KeyCache`].
+///
+/// Each shard has its own lock, so lookups of keys in different shards do not contend.
+const MAX_SHARDS: usize = 16;
+
 /// A cache for public keys.
 ///
 /// This is used solely to make parsing public keys from byte slices more
@@ -21,7 +35,58 @@ pub enum KeyCache {
     Disabled,
     /// The key cache is enabled with a fixed capacity. It is shared between
     /// multiple threads.
-    Shared(Arc<Mutex<lru::LruCache<PublicKey, ()>>>),
+    Shared(Arc<Shards>),
+}
+
+/// How a [`KeyCache`] makes room for new keys once it is full.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum KeyCacheEviction {
+    /// Evicts the least recently used key.
+    #[default]
+    Lru,
+    /// Never evicts keys, once full new keys are parsed without being cached.
+    ///
+    /// This keeps the cached keys from being flushed out by a burst of unique keys, at the
+    /// cost of not adapting to a changing set of keys.
+    Never,
+}
+
+/// The shards of a [`KeyCache`], keys are assigned to a shard by their first byte.
+#[derive(Debug)]
+pub struct Shards {
+    shards: Box<[Mutex<lru::LruCache<PublicKey, ()>>]>,
+    eviction: KeyCacheEviction,
+    stats: Stats,
+    /// Whether to record the server metrics.
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    metrics: bool,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    inserts: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// A snapshot of the usage statistics of a [`KeyCache`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct KeyCacheStats {
+    /// The maximum number of keys in the cache.
+    pub(crate) capacity: usize,
+    /// The number of keys currently in the cache.
+    pub(crate) len: usize,
+    /// The number of lookups which found the key in the cache.
+    pub(crate) hits: u64,
+    /// The number of lookups which did not find the key in the cache.
+    pub(crate) misses: u64,
+    /// The number of keys inserted into the cache.
+    pub(crate) inserts: u64,
+    /// The number of keys evicted to make room for new keys.
+    pub(crate) evictions: u64,
 }
 
 impl KeyCache {
@@ -35,16 +100,44 @@ impl KeyCache {
     ///
     /// If the capacity is zero, the cache is disabled and has zero overhead.
     pub fn new(capacity: usize) -> Self {
+        Self::with_eviction(capacity, KeyCacheEviction::default())
+    }
+
+    /// Create a new key cache with the given capacity and eviction policy.
+    ///
+    /// The capacity is spread over up to [`MAX_SHARDS`] shards, rounding up to a multiple
+    /// of the number of shards.
+    pub(crate) fn with_eviction(capacity: usize, eviction: KeyCacheEviction) -> Self {
+        Self::build(capacity, eviction, false)
+    }
+
+    /// Create a new key cache for the relay server, which records the server [`Metrics`].
+    #[cfg(feature = "server")]
+    pub(crate) fn for_server(capacity: usize, eviction: KeyCacheEviction) -> Self {
+        Self::build(capacity, eviction, true)
+    }
+
+    fn build(capacity: usize, eviction: KeyCacheEviction, metrics: bool) -> Self {
         let Some(capacity) = NonZeroUsize::new(capacity) else {
             return Self::Disabled;
         };
-        let cache = lru::LruCache::new(capacity);
-        Self::Shared(Arc::new(Mutex::new(cache)))
+        let num_shards = capacity.get().min(MAX_SHARDS);
+        let shard_capacity =
+            NonZeroUsize::new(capacity.get().div_ceil(num_shards)).expect("capacity is non-zero");
+        let shards = (0..num_shards)
+            .map(|_| Mutex::new(lru::LruCache::new(shard_capacity)))
+            .collect();
+        Self::Shared(Arc::new(Shards {
+            shards,
+            eviction,
+            stats: Stats::default(),
+            metrics,
+        }))
     }
 
     /// Get a key from a slice of bytes.
     pub fn key_from_slice(&self, slice: &[u8]) -> Result<PublicKey, SignatureError> {
-        let Self::Shared(cache) = self else {
+        let Self::Shared(shards) = self else {
             return PublicKey::try_from(slice);
         };
         let Ok(bytes) = PublicKeyBytes::try_from(slice) else {
@@ -52,12 +145,148 @@ impl KeyCache {
             // SignatureError.
             return Err(PublicKey::try_from(slice).expect_err("invalid length"));
         };
-        let mut cache = cache.lock().expect("not poisoned");
-        if let Some((key, _)) = cache.get_key_value(&bytes) {
-            return Ok(*key);
+        let shard = &shards.shards[bytes[0] as usize % shards.shards.len()];
+        let hit = shard
+            .lock()
+            .expect("not poisoned")
+            .get_key_value(&bytes)
+            .map(|(key, _)| *key);
+        if let Some(key) = hit {
+            shards.record_hit();
+            return Ok(key);
         }
+        // Parse without holding the lock, this is the expensive part.
         let key = PublicKey::from_bytes(&bytes)?;
-        cache.put(key, ());
+        let mut cache = shard.lock().expect("not poisoned");
+        let insert = match shards.eviction {
+            // Another thread might have inserted the same key meanwhile, replacing it is
+            // not an eviction.
+            KeyCacheEviction::Lru => Some(cache.push(key, ()).is_some_and(|(k, _)| k != key)),
+            KeyCacheEviction::Never if cache.len() < cache.cap().get() => {
+                cache.put(key, ());
+                Some(false)
+            }
+            KeyCacheEviction::Never => None,
+        };
+        drop(cache);
+        shards.record_miss(insert);
         Ok(key)
     }
+
+    /// Returns the usage statistics of the cache.
+    ///
+    /// A disabled cache has no capacity and records no usage.
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) fn stats(&self) -> KeyCacheStats {
+        let Self::Shared(shards) = self else {
+            return KeyCacheStats::default();
+        };
+        let (capacity, len) = shards.shards.iter().fold((0, 0), |(capacity, len), shard| {
+            let shard = shard.lock().expect("not poisoned");
+            (capacity + shard.cap().get(), len + shard.len())
+        });
+        let stats = &shards.stats;
+        KeyCacheStats {
+            capacity,
+            len,
+            hits: stats.hits.load(Ordering::Relaxed),
+            misses: stats.misses.load(Ordering::Relaxed),
+            inserts: stats.inserts.load(Ordering::Relaxed),
+            evictions: stats.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Shards {
+    fn record_hit(&self) {
+        self.stats.hits.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "server")]
+        if self.metrics {
+            inc!(Metrics, key_cache_hits);
+        }
+    }
+
+    /// Records a miss, `insert` is whether the key was inserted and whether this evicted
+    /// another key.
+    fn record_miss(&self, insert: Option<bool>) {
+        let stats = &self.stats;
+        stats.misses.fetch_add(1, Ordering::Relaxed);
+        if let Some(evicted) = insert {
+            stats.inserts.fetch_add(1, Ordering::Relaxed);
+            if evicted {
+                stats.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        #[cfg(feature = "server")]
+        if self.metrics {
+            inc!(Metrics, key_cache_misses);
+            if let Some(evicted) = insert {
+                inc!(Metrics, key_cache_inserts);
+                if evicted {
+                    inc!(Metrics, key_cache_evictions);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::SecretKey;
+
+    use super::*;
+
+    fn keys(n: usize) -> Vec<PublicKey> {
+        (0..n)
+            .map(|_| SecretKey::generate(rand::thread_rng()).public())
+            .collect()
+    }
+
+    #[test]
+    fn test_key_cache_stats() {
+        let cache = KeyCache::new(100);
+        let keys = keys(10);
+        for key in &keys {
+            assert_eq!(cache.key_from_slice(key.as_bytes()).unwrap(), *key);
+            assert_eq!(cache.key_from_slice(key.as_bytes()).unwrap(), *key);
+        }
+        assert!(cache.key_from_slice(&[0u8; 3]).is_err());
+
+        let stats = cache.stats();
+        assert_eq!(stats.capacity, 112); // 16 shards of 7 keys
+        assert_eq!(stats.len, 10);
+        assert_eq!(stats.hits, 10);
+        assert_eq!(stats.misses, 10);
+        assert_eq!(stats.inserts, 10);
+        assert_eq!(stats.evictions, 0);
+
+        assert_eq!(KeyCache::new(0).stats(), KeyCacheStats::default());
+    }
+
+    #[test]
+    fn test_key_cache_eviction() {
+        let keys = keys(20);
+
+        // A single shard, so all keys compete for the same slots.
+        let lru = KeyCache::with_eviction(1, KeyCacheEviction::Lru);
+        let never = KeyCache::with_eviction(1, KeyCacheEviction::Never);
+        for key in &keys {
+            lru.key_from_slice(key.as_bytes()).unwrap();
+            never.key_from_slice(key.as_bytes()).unwrap();
+        }
+
+        let stats = lru.stats();
+        assert_eq!(stats.len, 1);
+        assert_eq!(stats.inserts, 20);
+        assert_eq!(stats.evictions, 19);
+        lru.key_from_slice(keys[19].as_bytes()).unwrap();
+        assert_eq!(lru.stats().hits, 1);
+
+        let stats = never.stats();
+        assert_eq!(stats.len, 1);
+        assert_eq!(stats.inserts, 1);
+        assert_eq!(stats.evictions, 0);
+        never.key_from_slice(keys[0].as_bytes()).unwrap();
+        assert_eq!(never.stats().hits, 1);
+    }
 }
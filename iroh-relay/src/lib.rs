@@ -32,7 +32,11 @@
 
 pub mod client;
 pub mod defaults;
+#[cfg(all(any(test, feature = "test-utils"), not(wasm_browser)))]
+pub mod faults;
 pub mod http;
+#[cfg(all(feature = "metrics", not(wasm_browser)))]
+pub mod metrics;
 pub mod protos;
 pub mod quic;
 #[cfg(feature = "server")]
@@ -43,13 +47,14 @@ mod ping_tracker;
 mod key_cache;
 mod relay_map;
 pub(crate) use key_cache::KeyCache;
+pub use key_cache::KeyCacheEviction;
 
 #[cfg(not(wasm_browser))]
 pub mod dns;
 
-pub use protos::relay::MAX_PACKET_SIZE;
+pub use protos::relay::{MeshKey, PayloadCompression, MAX_FRAGMENTED_PACKET_SIZE, MAX_PACKET_SIZE};
 
 pub use self::{
     ping_tracker::PingTracker,
-    relay_map::{RelayMap, RelayNode, RelayQuicConfig},
+    relay_map::{RelayMap, RelayNode, RelayQuicConfig, RelayRegion, WeightedRelay},
 };
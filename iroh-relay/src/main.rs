@@ -4,30 +4,41 @@
 //! [`iroh::relay::server`].
 
 use std::{
+    collections::{BTreeMap, HashMap},
     net::{Ipv6Addr, SocketAddr},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 
 use anyhow::{bail, Context as _, Result};
 use clap::Parser;
-use iroh_base::NodeId;
+use iroh_base::{NodeId, RelayUrl};
+#[cfg(feature = "metrics")]
+use iroh_relay::metrics::MetricsExporter;
 use iroh_relay::{
     defaults::{
         DEFAULT_HTTPS_PORT, DEFAULT_HTTP_PORT, DEFAULT_METRICS_PORT, DEFAULT_RELAY_QUIC_PORT,
         DEFAULT_STUN_PORT,
     },
-    server::{self as relay, ClientRateLimit, QuicConfig},
+    server::{
+        self as relay, ClientRateLimit, ContentEncoding, OverflowPolicy, QuicConfig, TlsVersion,
+    },
+    KeyCacheEviction,
 };
-use n0_future::FutureExt;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use tokio_rustls_acme::{caches::DirCache, AcmeConfig};
-use tracing::debug;
+use tracing::{debug, info, warn};
 use tracing_subscriber::{prelude::*, EnvFilter};
 
 /// The default `http_bind_port` when using `--dev`.
 const DEV_MODE_HTTP_PORT: u16 = 3340;
 
+/// The default `limits.handshake_queue_timeout_ms`.
+const DEFAULT_HANDSHAKE_QUEUE_TIMEOUT_MS: u64 = 5000;
+
 /// A relay server for iroh.
 #[derive(Parser, Debug, Clone)]
 #[clap(version, about, long_about = None)]
@@ -45,9 +56,43 @@ struct Cli {
     /// written to the file.
     #[clap(long, short)]
     config_path: Option<PathBuf>,
+    /// Print the JSON Schema of the configuration file and exit.
+    #[clap(long)]
+    dump_config_schema: bool,
+    /// Write the JSON startup report of the server to this file once it started.
+    ///
+    /// The report lists the bound addresses, the TLS mode and certificate fingerprints, the
+    /// enabled services and the effective limits.
+    #[clap(long)]
+    startup_report: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum Command {
+    /// Benchmark the relay server with synthetic clients over loopback.
+    ///
+    /// Spawns the relay server as configured, but serving only plain HTTP on a loopback
+    /// port, connects the clients to it and reports the forwarding latency percentiles.
+    Bench(bench::BenchArgs),
+    /// Probe a fleet of relay servers for monitoring.
+    ///
+    /// Connects to every relay server, pings it and sends a packet to itself through it, on
+    /// a schedule.  The results are printed as JSON lines and optionally served as
+    /// Prometheus metrics.
+    Probe(probe::ProbeArgs),
+    /// Generate the files to deploy the relay server.
+    ///
+    /// Prints a systemd unit, an nginx or caddy reverse proxy config or a hardened config
+    /// file for the given hostname and ports.  The systemd unit reads the config from
+    /// `--config-path`.
+    Gen(deploy::GenArgs),
 }
 
-#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(
+    clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema,
+)]
 enum CertMode {
     Manual,
     LetsEncrypt,
@@ -100,7 +145,7 @@ fn load_secret_key(
 /// Configuration for the relay-server.
 ///
 /// This is (de)serialised to/from a TOML config file.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 struct Config {
     /// Whether to enable the Relay server.
     ///
@@ -141,6 +186,14 @@ struct Config {
     ///
     /// Defaults to using the `http_bind_addr` with the port set to [`DEFAULT_STUN_PORT`].
     stun_bind_addr: Option<SocketAddr>,
+    /// Further socket addresses to bind the STUN server on.
+    ///
+    /// E.g. a unicast IP next to an anycast `stun_bind_addr`, or additional ports.  All
+    /// addresses report to the same STUN metrics.
+    ///
+    /// Defaults to none.
+    #[serde(default)]
+    stun_additional_bind_addrs: Vec<SocketAddr>,
     /// Whether to allow QUIC connections for QUIC address discovery
     ///
     /// If no `tls` is set, this will error.
@@ -164,58 +217,358 @@ struct Config {
     metrics_bind_addr: Option<SocketAddr>,
     /// The capacity of the key cache.
     key_cache_capacity: Option<usize>,
+    /// The eviction policy of the key cache, either `"lru"` or `"never"`.
+    ///
+    /// Defaults to `"lru"`.
+    #[serde(default)]
+    key_cache_eviction: KeyCacheEviction,
     /// Access control for relaying connections.
     ///
     /// This controls which nodes are allowed to relay connections, other endpoints, like STUN are not controlled by this.
     #[serde(default)]
     access: AccessConfig,
+    /// Configuration for the admin HTTP API, served under `/admin/`.
+    ///
+    /// Disabled if not present.
+    admin: Option<AdminConfig>,
+    /// Configuration for the watchdog sampling the health of the relay server.
+    ///
+    /// Disabled if not present.
+    watchdog: Option<WatchdogConfig>,
+    /// The mesh key authenticating trusted clients, as 64 hex characters.
+    ///
+    /// Trusted clients, like federated relay servers or monitoring agents, may watch the
+    /// connections of all clients and forward packets on behalf of other nodes.  Generate
+    /// one with e.g. `openssl rand -hex 32`.
+    ///
+    /// No client is trusted if not present.
+    mesh_key: Option<String>,
+    /// The relay mesh, forwarding packets to the nodes connected to other relays.
+    ///
+    /// Needs the `mesh_key`, which all relays of the mesh share.  Disabled if not present.
+    mesh: Option<MeshConfig>,
+    /// Resumption of the sessions of clients reconnecting after a brief disconnect.
+    ///
+    /// Disabled if not present.
+    sessions: Option<SessionsConfig>,
+    /// The keep-alive intervals clients can request.
+    ///
+    /// Clients are pinged every 15 seconds, whatever they request, if not present.
+    keep_alive: Option<KeepAliveConfig>,
+    /// Whether clients can request the compression of the payloads of their packets.
+    ///
+    /// Costs CPU time for every packet of these clients.  Needs the `payload-compression`
+    /// feature.  Defaults to `false`.
+    #[serde(default)]
+    payload_compression: bool,
+    /// The handling of the packets clients send to nodes not connected to the relay.
+    ///
+    /// These packets are silently dropped if not present.
+    unknown_peers: Option<UnknownPeersConfig>,
+    /// Compression of the responses of the custom HTTP routes and the admin API.
+    ///
+    /// Disabled if not present.
+    compression: Option<CompressionConfig>,
+    /// Whether the Relay HTTP(S) servers bound to IPv6 addresses only accept IPv6
+    /// connections, by setting `IPV6_V6ONLY` on their sockets.
+    ///
+    /// Defaults to the operating system default if not present.  On Linux an IPv6 server
+    /// bound to `[::]` usually also accepts IPv4 connections.
+    ipv6_only: Option<bool>,
+    /// Whether the connections to the Relay HTTP(S) server start with a PROXY protocol
+    /// header, sent by a load balancer in front of the server.
+    ///
+    /// Connections without a version 1 or 2 header are closed.  Defaults to `false`.
+    #[serde(default)]
+    proxy_protocol: bool,
+    /// The networks of the load balancers sending PROXY protocol headers, in CIDR notation.
+    ///
+    /// Required with `proxy_protocol`, connections from other addresses are closed.
+    #[serde(default)]
+    trusted_proxies: Vec<String>,
+    /// The structured access log of the Relay HTTP(S) server, one JSON object per request.
+    ///
+    /// Disabled if not present.
+    access_log: Option<AccessLogConfig>,
+    /// Custom responses for the errors of the Relay HTTP(S) server.
+    ///
+    /// Errors without a configured page are answered with short plaintext bodies.
+    error_pages: Option<ErrorPagesConfig>,
+    /// Additional HTTP headers of all responses of the Relay HTTP(S) server, by name.
+    ///
+    /// These replace the built-in headers of the same name, like
+    /// `Strict-Transport-Security`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+    /// Additional HTTP headers of the responses of a route group, by group and name.
+    ///
+    /// The groups are `pages`, `relay`, `probes` and `errors`.  These replace the built-in
+    /// headers and the `headers` of the same name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    route_headers: BTreeMap<relay::RouteGroup, BTreeMap<String, String>>,
+    /// Upgrades of the binary without closing the listeners, requested with `SIGUSR2`.
+    ///
+    /// The process starts its binary again, hands its listening sockets over and stops
+    /// accepting connections once the new process serves on them.  Under a service manager
+    /// the service must outlive its main process, which is not the default of e.g. systemd.
+    ///
+    /// Disabled if not present, only supported on Unix.
+    upgrade: Option<UpgradeConfig>,
+}
+
+/// The access log configuration.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct AccessLogConfig {
+    /// File to append the records to.
+    ///
+    /// The records are written to stdout if not present, the logs of the server go to
+    /// stderr.
+    path: Option<PathBuf>,
+}
+
+/// The admin HTTP API configuration.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct AdminConfig {
+    /// The token which must be sent as `Authorization: Bearer <token>` header.
+    bearer_token: String,
+}
+
+/// The watchdog configuration.
+///
+/// Thresholds which are not set are not checked.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct WatchdogConfig {
+    /// Seconds between samples of the server.
+    ///
+    /// Defaults to `30`.
+    #[serde(default = "cfg_defaults::watchdog::interval_secs")]
+    interval_secs: u64,
+    /// Maximum number of tasks serving HTTP connections.
+    max_connection_tasks: Option<usize>,
+    /// Maximum number of tasks serving relay clients.
+    max_client_tasks: Option<usize>,
+    /// Maximum number of client tasks which do not belong to a registered client.
+    ///
+    /// Defaults to `16`.
+    #[serde(default = "cfg_defaults::watchdog::max_orphaned_client_tasks")]
+    max_orphaned_client_tasks: Option<usize>,
+    /// Maximum number of items queued for a single client.
+    max_client_queue_depth: Option<usize>,
+    /// File to write the diagnostic report to, as JSON, when a threshold is crossed.
+    report_path: Option<PathBuf>,
+}
+
+/// The relay mesh configuration.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct MeshConfig {
+    /// The URLs of the other relays of the mesh.
+    ///
+    /// Packets are only forwarded once, so each relay of the mesh has to list all others.
+    #[schemars(with = "Vec<String>")]
+    peers: Vec<RelayUrl>,
+}
+
+/// The session resumption configuration.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct SessionsConfig {
+    /// Seconds a client may take to reconnect and resume its session.
+    ///
+    /// Its peers are only told that it is gone once this passed.  Defaults to `10`.
+    #[serde(default = "cfg_defaults::sessions::grace_period_secs")]
+    grace_period_secs: u64,
+}
+
+/// The keep-alive configuration.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct KeepAliveConfig {
+    /// Seconds between pings of clients not requesting an interval.  Defaults to `15`.
+    #[serde(default = "cfg_defaults::keep_alive::default_interval_secs")]
+    default_interval_secs: u64,
+    /// The shortest interval in seconds clients can request.  Defaults to `5`.
+    #[serde(default = "cfg_defaults::keep_alive::min_interval_secs")]
+    min_interval_secs: u64,
+    /// The longest interval in seconds clients can request.  Defaults to `60`.
+    #[serde(default = "cfg_defaults::keep_alive::max_interval_secs")]
+    max_interval_secs: u64,
+}
+
+/// The handling of the packets sent to unknown nodes.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct UnknownPeersConfig {
+    /// Whether clients requesting it are notified of the packets dropped for an unknown
+    /// destination.  Defaults to `true`.
+    #[serde(default = "cfg_defaults::unknown_peers::notify")]
+    notify: bool,
+    /// Packets per second a client may send to unknown nodes before the relay pauses
+    /// reading from it.  Unlimited if not present.
+    max_per_second: Option<u32>,
+    /// Packets a client may send to unknown nodes in a burst.  Defaults to
+    /// `max_per_second`.
+    max_burst: Option<u32>,
+}
+
+impl UnknownPeersConfig {
+    fn unknown_peer_config(&self) -> Result<relay::UnknownPeerConfig> {
+        if self.max_per_second.is_none() && self.max_burst.is_some() {
+            bail!("max_per_second must be specified to limit the packets to unknown nodes");
+        }
+        Ok(relay::UnknownPeerConfig {
+            notify: self.notify,
+            max_per_second: self
+                .max_per_second
+                .map(|v| v.try_into().context("max_per_second must be non-zero u32"))
+                .transpose()?,
+            max_burst: self
+                .max_burst
+                .map(|v| v.try_into().context("max_burst must be non-zero u32"))
+                .transpose()?,
+        })
+    }
+}
+
+/// The binary upgrade configuration.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct UpgradeConfig {
+    /// Seconds to wait for the new process to serve on the listeners before killing it.
+    ///
+    /// Defaults to `30`.
+    #[serde(default = "cfg_defaults::upgrade::timeout_secs")]
+    timeout_secs: u64,
+    /// Seconds the old process keeps serving its connected clients after the handover.
+    ///
+    /// Defaults to `60`.
+    #[serde(default = "cfg_defaults::upgrade::drain_secs")]
+    drain_secs: u64,
+}
+
+/// The response compression configuration.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct CompressionConfig {
+    /// The encodings offered to clients, in order of preference, `"br"` or `"gzip"`.
+    ///
+    /// Defaults to `["br", "gzip"]`.
+    #[serde(default = "cfg_defaults::compression::encodings")]
+    encodings: Vec<ContentEncoding>,
+    /// Responses smaller than this number of bytes are not compressed.
+    ///
+    /// Defaults to `1024`.
+    #[serde(default = "cfg_defaults::compression::min_size")]
+    min_size: usize,
+    /// The content types of the responses to compress, like `"text/*"`.
+    ///
+    /// Defaults to text, JSON, JavaScript, XML and SVG.
+    #[serde(default = "cfg_defaults::compression::content_types")]
+    content_types: Vec<String>,
+}
+
+/// The custom error responses.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct ErrorPagesConfig {
+    /// Served with `404 Not Found` for requests which match no route.
+    not_found: Option<ErrorPageConfig>,
+    /// Served with `400 Bad Request` for requests to the relay endpoint which are not a
+    /// valid upgrade, like opening the relay URL in a browser.
+    bad_request: Option<ErrorPageConfig>,
+    /// Served with `503 Service Unavailable` for new relay connections while the server is
+    /// draining through the admin API.
+    unavailable: Option<ErrorPageConfig>,
+}
+
+/// A custom error response, either a page read from a file or a redirect.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct ErrorPageConfig {
+    /// The file containing the page.
+    ///
+    /// `{status}`, `{reason}` and `{path}` in the page are replaced with the status code,
+    /// its reason phrase and the HTML-escaped path of the request.
+    file: Option<PathBuf>,
+    /// The content type of the page.
+    ///
+    /// Defaults to `"text/html; charset=utf-8"`.
+    #[serde(default = "cfg_defaults::error_pages::content_type")]
+    content_type: String,
+    /// Redirects to this URL with `302 Found` instead of serving a page.
+    redirect: Option<url::Url>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+impl ErrorPagesConfig {
+    async fn load(&self) -> Result<relay::ErrorPages> {
+        let mut pages = relay::ErrorPages::default();
+        for (name, config, page) in [
+            ("not_found", &self.not_found, &mut pages.not_found),
+            ("bad_request", &self.bad_request, &mut pages.bad_request),
+            ("unavailable", &self.unavailable, &mut pages.unavailable),
+        ] {
+            if let Some(config) = config {
+                let loaded = config
+                    .load()
+                    .await
+                    .with_context(|| format!("invalid error_pages.{name}"))?;
+                *page = Some(loaded);
+            }
+        }
+        Ok(pages)
+    }
+}
+
+impl ErrorPageConfig {
+    async fn load(&self) -> Result<relay::ErrorPage> {
+        match (&self.file, &self.redirect) {
+            (Some(file), None) => {
+                let body = tokio::fs::read_to_string(file)
+                    .await
+                    .with_context(|| format!("unable to read {}", file.display()))?;
+                let content_type = self.content_type.parse().context("invalid content_type")?;
+                Ok(relay::ErrorPage::Template { content_type, body })
+            }
+            (None, Some(url)) => Ok(relay::ErrorPage::Redirect(url.clone())),
+            _ => bail!("exactly one of file and redirect must be set"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, Default, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 enum AccessConfig {
     /// Allows everyone
     #[default]
     Everyone,
     /// Allows only these nodes.
-    Allowlist(Vec<NodeId>),
+    Allowlist(#[schemars(with = "Vec<String>")] Vec<NodeId>),
     /// Allows everyone, except these nodes.
-    Denylist(Vec<NodeId>),
+    Denylist(#[schemars(with = "Vec<String>")] Vec<NodeId>),
+    /// Allows only the nodes listed in this file, one node ID per line.
+    ///
+    /// The file is re-read on `SIGHUP` and through the admin API.
+    #[serde(rename = "allowlist_file")]
+    AllowlistFile(PathBuf),
+    /// Allows everyone, except the nodes listed in this file, one node ID per line.
+    ///
+    /// The file is re-read on `SIGHUP` and through the admin API.
+    #[serde(rename = "denylist_file")]
+    DenylistFile(PathBuf),
 }
 
-impl From<AccessConfig> for iroh_relay::server::AccessConfig {
-    fn from(cfg: AccessConfig) -> Self {
-        match cfg {
-            AccessConfig::Everyone => iroh_relay::server::AccessConfig::Everyone,
+impl TryFrom<AccessConfig> for relay::AccessConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: AccessConfig) -> Result<Self> {
+        let access = match cfg {
+            AccessConfig::Everyone => relay::AccessConfig::Everyone,
             AccessConfig::Allowlist(allow_list) => {
-                let allow_list = Arc::new(allow_list);
-                iroh_relay::server::AccessConfig::Restricted(Box::new(move |node_id| {
-                    let allow_list = allow_list.clone();
-                    async move {
-                        if allow_list.contains(&node_id) {
-                            iroh_relay::server::Access::Allow
-                        } else {
-                            iroh_relay::server::Access::Deny
-                        }
-                    }
-                    .boxed()
-                }))
+                relay::AccessConfig::Allowlist(relay::NodeList::new(allow_list))
             }
             AccessConfig::Denylist(deny_list) => {
-                let deny_list = Arc::new(deny_list);
-                iroh_relay::server::AccessConfig::Restricted(Box::new(move |node_id| {
-                    let deny_list = deny_list.clone();
-                    async move {
-                        if deny_list.contains(&node_id) {
-                            iroh_relay::server::Access::Deny
-                        } else {
-                            iroh_relay::server::Access::Allow
-                        }
-                    }
-                    .boxed()
-                }))
+                relay::AccessConfig::Denylist(relay::NodeList::new(deny_list))
             }
-        }
+            AccessConfig::AllowlistFile(path) => {
+                relay::AccessConfig::Allowlist(relay::NodeList::load(path)?)
+            }
+            AccessConfig::DenylistFile(path) => {
+                relay::AccessConfig::Denylist(relay::NodeList::load(path)?)
+            }
+        };
+        Ok(access)
     }
 }
 
@@ -234,6 +587,30 @@ impl Config {
         self.metrics_bind_addr
             .unwrap_or_else(|| SocketAddr::new(self.http_bind_addr().ip(), DEFAULT_METRICS_PORT))
     }
+
+    fn headers(&self) -> Result<http::HeaderMap> {
+        header_map(&self.headers)
+    }
+
+    fn route_headers(&self) -> Result<HashMap<relay::RouteGroup, http::HeaderMap>> {
+        self.route_headers
+            .iter()
+            .map(|(group, headers)| Ok((*group, header_map(headers)?)))
+            .collect()
+    }
+}
+
+/// Parses the HTTP headers of the configuration file.
+fn header_map(config: &BTreeMap<String, String>) -> Result<http::HeaderMap> {
+    let mut headers = http::HeaderMap::new();
+    for (name, value) in config {
+        let name = http::HeaderName::try_from(name)
+            .with_context(|| format!("invalid header name {name:?}"))?;
+        let value = http::HeaderValue::try_from(value)
+            .with_context(|| format!("invalid value of header {name}"))?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
 }
 
 impl Default for Config {
@@ -244,12 +621,31 @@ impl Default for Config {
             tls: None,
             enable_stun: cfg_defaults::enable_stun(),
             stun_bind_addr: None,
+            stun_additional_bind_addrs: Vec::new(),
             enable_quic_addr_discovery: cfg_defaults::enable_quic_addr_discovery(),
             limits: None,
             enable_metrics: cfg_defaults::enable_metrics(),
             metrics_bind_addr: None,
             key_cache_capacity: Default::default(),
+            key_cache_eviction: Default::default(),
             access: AccessConfig::Everyone,
+            admin: None,
+            watchdog: None,
+            mesh_key: None,
+            mesh: None,
+            sessions: None,
+            keep_alive: None,
+            payload_compression: false,
+            unknown_peers: None,
+            compression: None,
+            ipv6_only: None,
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            access_log: None,
+            error_pages: None,
+            headers: BTreeMap::new(),
+            route_headers: BTreeMap::new(),
+            upgrade: None,
         }
     }
 }
@@ -275,6 +671,78 @@ mod cfg_defaults {
         true
     }
 
+    pub(crate) mod watchdog {
+        pub(crate) fn interval_secs() -> u64 {
+            iroh_relay::server::DEFAULT_WATCHDOG_INTERVAL.as_secs()
+        }
+
+        pub(crate) fn max_orphaned_client_tasks() -> Option<usize> {
+            iroh_relay::server::WatchdogConfig::default().max_orphaned_client_tasks
+        }
+    }
+
+    pub(crate) mod sessions {
+        pub(crate) fn grace_period_secs() -> u64 {
+            iroh_relay::server::DEFAULT_SESSION_GRACE_PERIOD.as_secs()
+        }
+    }
+
+    pub(crate) mod keep_alive {
+        pub(crate) fn default_interval_secs() -> u64 {
+            iroh_relay::server::DEFAULT_KEEP_ALIVE_INTERVAL.as_secs()
+        }
+
+        pub(crate) fn min_interval_secs() -> u64 {
+            iroh_relay::server::KeepAliveConfig::default()
+                .min_interval
+                .as_secs()
+        }
+
+        pub(crate) fn max_interval_secs() -> u64 {
+            iroh_relay::server::KeepAliveConfig::default()
+                .max_interval
+                .as_secs()
+        }
+    }
+
+    pub(crate) mod unknown_peers {
+        pub(crate) fn notify() -> bool {
+            iroh_relay::server::UnknownPeerConfig::default().notify
+        }
+    }
+
+    pub(crate) mod upgrade {
+        pub(crate) fn timeout_secs() -> u64 {
+            30
+        }
+
+        pub(crate) fn drain_secs() -> u64 {
+            60
+        }
+    }
+
+    pub(crate) mod error_pages {
+        pub(crate) fn content_type() -> String {
+            "text/html; charset=utf-8".to_string()
+        }
+    }
+
+    pub(crate) mod compression {
+        use iroh_relay::server::{CompressionConfig, ContentEncoding};
+
+        pub(crate) fn encodings() -> Vec<ContentEncoding> {
+            CompressionConfig::default().encodings
+        }
+
+        pub(crate) fn min_size() -> usize {
+            iroh_relay::server::DEFAULT_COMPRESSION_MIN_SIZE
+        }
+
+        pub(crate) fn content_types() -> Vec<String> {
+            CompressionConfig::default().content_types
+        }
+    }
+
     pub(crate) mod tls_config {
         pub(crate) fn prod_tls() -> bool {
             true
@@ -283,10 +751,26 @@ mod cfg_defaults {
         pub(crate) fn dangerous_http_only() -> bool {
             false
         }
+
+        pub(crate) fn http2() -> bool {
+            false
+        }
+
+        pub(crate) fn webtransport() -> bool {
+            false
+        }
+
+        pub(crate) fn cert_watch_interval_secs() -> u64 {
+            iroh_relay::server::DEFAULT_CERT_WATCH_INTERVAL.as_secs()
+        }
+
+        pub(crate) fn min_version() -> iroh_relay::server::TlsVersion {
+            iroh_relay::server::TlsVersion::default()
+        }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 struct TlsConfig {
     /// The socket address to bind the Relay HTTPS server on.
     ///
@@ -321,6 +805,26 @@ struct TlsConfig {
     ///
     /// Only used when `cert_mode` is `Manual`.
     manual_key_path: Option<PathBuf>,
+    /// Path of a DER encoded OCSP response to staple to the certificate for the `Manual`
+    /// `cert_mode`.
+    ///
+    /// The response is fetched from the OCSP responder of the certificate issuer, e.g. with
+    /// `openssl ocsp -respout`, and needs to be refreshed before it expires.  It is watched
+    /// along with the certificate.
+    ///
+    /// Only used when `cert_mode` is `Manual`.
+    manual_ocsp_path: Option<PathBuf>,
+    /// How often to check the certificate, key and OCSP response files for changes, in
+    /// seconds.
+    ///
+    /// Changed files are loaded without restarting the server, e.g. certificates renewed
+    /// by certbot.  `0` disables watching the files.
+    ///
+    /// Default is `60`.
+    ///
+    /// Only used when `cert_mode` is `Manual`.
+    #[serde(default = "cfg_defaults::tls_config::cert_watch_interval_secs")]
+    cert_watch_interval_secs: u64,
     /// Whether to use the LetsEncrypt production or staging server.
     ///
     /// Default is `true`.
@@ -340,6 +844,45 @@ struct TlsConfig {
     ///
     /// Used when `cert_mode` is `LetsEncrypt`.
     contact: Option<String>,
+    /// The base64 encoded `ECHConfigList` to publish for Encrypted Client Hello.
+    ///
+    /// This is the same value as the `ech` parameter of the `HTTPS` DNS record.  It is
+    /// served on `/.well-known/origin-svcb`, decrypting ECH requires a TLS terminating
+    /// frontend holding the matching keys.
+    ech_config_list: Option<String>,
+    /// Whether to offer HTTP/2, carrying many relay connections on one TLS connection.
+    ///
+    /// Default is `false`.
+    #[serde(default = "cfg_defaults::tls_config::http2")]
+    http2: bool,
+    /// Whether to accept relay connections over WebTransport, on the UDP port of the
+    /// `https_bind_addr`.
+    ///
+    /// Default is `false`.
+    #[serde(default = "cfg_defaults::tls_config::webtransport")]
+    webtransport: bool,
+    /// The lowest TLS version accepted, `"1.2"` or `"1.3"`.
+    ///
+    /// Default is `"1.2"`.
+    #[serde(default = "cfg_defaults::tls_config::min_version")]
+    min_version: TlsVersion,
+    /// The cipher suites accepted, in order of preference, like `"TLS13_AES_256_GCM_SHA384"`.
+    ///
+    /// Defaults to all suites supported.
+    #[serde(default)]
+    cipher_suites: Vec<String>,
+    /// The key exchange groups accepted, in order of preference: `"X25519"`, `"secp256r1"`
+    /// or `"secp384r1"`.
+    ///
+    /// Defaults to all of these.
+    #[serde(default)]
+    kx_groups: Vec<String>,
+    /// PEM file with the certificates of the authorities issuing client certificates.
+    ///
+    /// If set, relay clients must present a TLS client certificate issued by one of them,
+    /// connections without a valid certificate are closed.  Only used when `cert_mode` is
+    /// `Manual` or `Reloading`.
+    client_ca_path: Option<PathBuf>,
     /// **This field should never be manually set**
     ///
     /// When `true`, it will force the relay to ignore binding to https. It is only
@@ -377,9 +920,49 @@ impl TlsConfig {
             .clone()
             .unwrap_or_else(|| self.cert_dir().join("default.key"))
     }
+
+    /// The files loaded in the `Manual` cert mode.
+    fn cert_files(&self) -> Vec<PathBuf> {
+        let mut files = vec![self.cert_path(), self.key_path()];
+        files.extend(self.manual_ocsp_path.clone());
+        files
+    }
+
+    /// Returns the TLS policy, resolving the names of cipher suites and groups.
+    fn policy(&self) -> Result<relay::TlsPolicy> {
+        use rustls::crypto::ring::{ALL_CIPHER_SUITES, ALL_KX_GROUPS};
+
+        let cipher_suites = self
+            .cipher_suites
+            .iter()
+            .map(|name| {
+                ALL_CIPHER_SUITES
+                    .iter()
+                    .map(|suite| suite.suite())
+                    .find(|suite| suite.as_str().is_some_and(|s| s.eq_ignore_ascii_case(name)))
+                    .with_context(|| format!("unknown cipher suite {name:?}"))
+            })
+            .collect::<Result<_>>()?;
+        let kx_groups = self
+            .kx_groups
+            .iter()
+            .map(|name| {
+                ALL_KX_GROUPS
+                    .iter()
+                    .map(|group| group.name())
+                    .find(|group| group.as_str().is_some_and(|s| s.eq_ignore_ascii_case(name)))
+                    .with_context(|| format!("unknown key exchange group {name:?}"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(relay::TlsPolicy {
+            min_version: self.min_version,
+            cipher_suites,
+            kx_groups,
+        })
+    }
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 struct Limits {
     /// Rate limit for accepting new connection. Unlimited if not set.
     accept_conn_limit: Option<f64>,
@@ -387,6 +970,67 @@ struct Limits {
     accept_conn_burst: Option<usize>,
     /// Rate limiting configuration per client.
     client: Option<PerClientRateLimitConfig>,
+    /// Rate limiting configuration per trusted client, see [`Config::mesh_key`].
+    ///
+    /// Trusted clients are not subject to the `client` limits.  Unlimited if not set.
+    trusted_client: Option<PerClientRateLimitConfig>,
+    /// Max number of connections doing their TLS or relay handshake at the same time.
+    ///
+    /// Unlimited if not set.
+    max_concurrent_handshakes: Option<usize>,
+    /// How long a connection waits for a free handshake slot, in milliseconds.
+    ///
+    /// Defaults to 5 seconds.
+    handshake_queue_timeout_ms: Option<u64>,
+    /// Max number of connections open at the same time.
+    ///
+    /// Connections beyond the limit are closed before their TLS handshake.  Unlimited if
+    /// not set.
+    max_connections: Option<usize>,
+    /// Max number of connections open at the same time from a single IP address.
+    ///
+    /// Unlimited if not set.
+    max_connections_per_ip: Option<usize>,
+    /// Max number of clients connected at the same time from a single source.
+    ///
+    /// Unlike `max_connections_per_ip` this covers all transports, and counts IPv6
+    /// addresses per /64 prefix.  Unlimited if not set.
+    clients_per_ip: Option<ClientsPerIpConfig>,
+    /// Byte quotas of the packets each node sends, across all its connections.
+    ///
+    /// Packets of nodes over their quota are dropped.  Unlimited if not set.
+    client_quota: Option<ClientQuotaConfig>,
+    /// The queues of the packets sent to each client.
+    send_queue: Option<SendQueueConfig>,
+}
+
+impl Limits {
+    /// Builds the rate limits of the incoming data from clients and trusted clients, and
+    /// of the outgoing data to clients.
+    #[allow(clippy::type_complexity)]
+    fn client_rate_limits(
+        &self,
+    ) -> Result<(
+        Option<ClientRateLimit>,
+        Option<ClientRateLimit>,
+        Option<ClientRateLimit>,
+    )> {
+        let client_rx = match &self.client {
+            Some(client) => client.client_rx()?,
+            None => None,
+        };
+        let trusted_client_rx = match &self.trusted_client {
+            Some(trusted_client) => trusted_client
+                .client_rx()
+                .context("invalid trusted client rate limit")?,
+            None => None,
+        };
+        let client_tx = match &self.client {
+            Some(client) => client.client_tx().context("invalid client tx rate limit")?,
+            None => None,
+        };
+        Ok((client_rx, trusted_client_rx, client_tx))
+    }
 }
 
 /// Rate limit configuration for each connected client.
@@ -396,13 +1040,36 @@ struct Limits {
 /// - The base rate limit uses a steady-stream rate of bytes allowed.
 /// - Additionally a burst quota allows sending bytes over this steady-stream rate
 ///   limit, as long as the maximum burst quota is not exceeded.
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 struct PerClientRateLimitConfig {
     /// Rate limit configuration for the incoming data from the client.
     rx: Option<RateLimitConfig>,
+    /// Rate limit configuration for the outgoing data to the client, summed over all
+    /// senders.
+    ///
+    /// Only applies to the `client` limits, trusted clients are not shaped.
+    tx: Option<RateLimitConfig>,
+}
+
+impl PerClientRateLimitConfig {
+    /// Builds the rate limit for the incoming data, `None` if unlimited.
+    fn client_rx(&self) -> Result<Option<ClientRateLimit>> {
+        match &self.rx {
+            Some(rx) => rx.client_rate_limit(),
+            None => Ok(None),
+        }
+    }
+
+    /// Builds the rate limit for the outgoing data, `None` if unlimited.
+    fn client_tx(&self) -> Result<Option<ClientRateLimit>> {
+        match &self.tx {
+            Some(tx) => tx.client_rate_limit(),
+            None => Ok(None),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 struct RateLimitConfig {
     /// Maximum number of bytes per second.
     bytes_per_second: Option<u32>,
@@ -410,6 +1077,106 @@ struct RateLimitConfig {
     max_burst_bytes: Option<u32>,
 }
 
+impl RateLimitConfig {
+    /// Builds the rate limit, `None` if unlimited.
+    fn client_rate_limit(&self) -> Result<Option<ClientRateLimit>> {
+        if self.bytes_per_second.is_none() && self.max_burst_bytes.is_some() {
+            bail!("bytes_per_seconds must be specified to enable the rate-limiter");
+        }
+        match self.bytes_per_second {
+            Some(bps) => Ok(Some(ClientRateLimit {
+                bytes_per_second: bps
+                    .try_into()
+                    .context("bytes_per_second must be non-zero u32")?,
+                max_burst_bytes: self
+                    .max_burst_bytes
+                    .map(|v| v.try_into().context("max_burst_bytes must be non-zero u32"))
+                    .transpose()?,
+            })),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Limit of the clients connected from a single IPv4 address or IPv6 /64 prefix.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct ClientsPerIpConfig {
+    /// Max number of distinct nodes connected at the same time from a single source.
+    max_clients: usize,
+    /// Networks exempt from the limit, in CIDR notation, e.g. carrier-grade NATs sharing
+    /// few addresses between many hosts.
+    #[serde(default)]
+    exempt: Vec<String>,
+}
+
+impl ClientsPerIpConfig {
+    fn client_ip_limit(&self) -> Result<relay::ClientIpLimit> {
+        Ok(relay::ClientIpLimit {
+            max_clients: self
+                .max_clients
+                .try_into()
+                .context("max_clients must be non-zero")?,
+            exempt: self
+                .exempt
+                .iter()
+                .map(|net| {
+                    net.parse()
+                        .with_context(|| format!("invalid exempt network: {net}"))
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+/// Byte quotas of each node, over rolling windows.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+struct ClientQuotaConfig {
+    /// Max number of bytes a node may send within 24 hours.  Unlimited if not set.
+    daily_bytes: Option<u64>,
+    /// Max number of bytes a node may send within 30 days.  Unlimited if not set.
+    monthly_bytes: Option<u64>,
+}
+
+/// The queues of the packets sent to each client.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+struct SendQueueConfig {
+    /// Max number of packets queued for a client.  Defaults to 512.
+    depth: Option<usize>,
+    /// What happens to the packets sent to a client whose queue is full.
+    ///
+    /// One of `drop-newest`, `drop-oldest` or `disconnect-client`.  Defaults to
+    /// `drop-newest`.
+    overflow: Option<OverflowPolicy>,
+}
+
+impl SendQueueConfig {
+    fn send_queue_config(&self) -> Result<relay::SendQueueConfig> {
+        let depth = self.depth.unwrap_or(relay::DEFAULT_SEND_QUEUE_DEPTH);
+        if depth == 0 {
+            bail!("depth must be non-zero");
+        }
+        Ok(relay::SendQueueConfig {
+            depth,
+            overflow: self.overflow.unwrap_or_default(),
+        })
+    }
+}
+
+impl ClientQuotaConfig {
+    fn quota_config(&self) -> Result<relay::QuotaConfig> {
+        Ok(relay::QuotaConfig {
+            daily_bytes: self
+                .daily_bytes
+                .map(|v| v.try_into().context("daily_bytes must be non-zero"))
+                .transpose()?,
+            monthly_bytes: self
+                .monthly_bytes
+                .map(|v| v.try_into().context("monthly_bytes must be non-zero"))
+                .transpose()?,
+        })
+    }
+}
+
 impl Config {
     async fn load(opts: &Cli) -> Result<Self> {
         let config_path = if let Some(config_path) = &opts.config_path {
@@ -440,6 +1207,13 @@ impl Config {
     }
 }
 
+/// Returns the JSON Schema of the configuration file, see `--dump-config-schema`.
+fn config_schema() -> schemars::Schema {
+    let mut schema = schemars::schema_for!(Config);
+    schema.insert("title".into(), "iroh-relay configuration".into());
+    schema
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::registry()
@@ -448,6 +1222,16 @@ async fn main() -> Result<()> {
         .init();
 
     let cli = Cli::parse();
+    if cli.dump_config_schema {
+        println!("{}", serde_json::to_string_pretty(&config_schema())?);
+        return Ok(());
+    }
+    match cli.command {
+        Some(Command::Bench(ref args)) => return bench::run(&cli, args).await,
+        Some(Command::Probe(ref args)) => return probe::run(args).await,
+        Some(Command::Gen(ref args)) => return deploy::run(&cli, args),
+        None => (),
+    }
     let mut cfg = Config::load(&cli).await?;
     if cfg.enable_quic_addr_discovery && cfg.tls.is_none() {
         bail!("TLS must be configured in order to spawn a QUIC endpoint");
@@ -464,47 +1248,288 @@ async fn main() -> Result<()> {
     if cfg.tls.is_none() && cfg.enable_quic_addr_discovery {
         bail!("If QUIC address discovery is enabled, TLS must also be configured");
     };
+    let upgrade_config = cfg.upgrade.clone();
+    let watched_tls = watched_tls(cfg.tls.as_ref()).cloned();
+    let mut cert_watcher = match watched_tls {
+        Some(ref tls) => {
+            let interval = Duration::from_secs(tls.cert_watch_interval_secs);
+            relay::CertWatcher::new(tls.cert_files(), interval).await
+        }
+        None => relay::CertWatcher::new(Vec::new(), Duration::ZERO).await,
+    };
     let relay_config = build_relay_config(cfg).await?;
     debug!("{relay_config:#?}");
 
+    #[cfg(unix)]
+    let (relay_config, inherited) = inherit_listeners(relay_config).await?;
+
     let mut relay = relay::Server::spawn(relay_config).await?;
+    #[cfg(unix)]
+    if let Some(inherited) = inherited {
+        inherited.ready().await?;
+    }
+    if let Some(ref path) = cli.startup_report {
+        let report = serde_json::to_vec_pretty(relay.startup_report())?;
+        tokio::fs::write(path, report)
+            .await
+            .with_context(|| format!("failed to write the startup report to {}", path.display()))?;
+    }
 
-    tokio::select! {
-        biased;
-        _ = tokio::signal::ctrl_c() => (),
-        _ = relay.task_handle() => (),
+    let mut hangups = Hangups::new();
+    loop {
+        let upgrade_requested = tokio::select! {
+            biased;
+            _ = tokio::signal::ctrl_c() => false,
+            _ = relay.task_handle() => false,
+            _ = hangups.recv() => {
+                match reload_config(&cli).await.and_then(|config| relay.reload(config)) {
+                    Ok(()) => info!("reloaded the configuration"),
+                    Err(err) => warn!("failed to reload the configuration: {err:#}"),
+                }
+                continue;
+            }
+            _ = cert_watcher.changed() => {
+                let tls = watched_tls.as_ref().expect("only watching with a TLS config");
+                match load_manual_tls(tls).await.and_then(|config| relay.reload_tls(config)) {
+                    Ok(()) => info!("reloaded the changed certificates"),
+                    Err(err) => warn!("failed to reload the changed certificates: {err:#}"),
+                }
+                continue;
+            }
+            _ = upgrade_requested(upgrade_config.is_some()) => true,
+        };
+        if !upgrade_requested {
+            break;
+        }
+        #[cfg(unix)]
+        if let Some(ref cfg) = upgrade_config {
+            match upgrade(&relay, cfg).await {
+                Ok(()) => {
+                    relay.stop_accepting();
+                    info!("serving the connected clients for {}s", cfg.drain_secs);
+                    tokio::select! {
+                        _ = tokio::time::sleep(Duration::from_secs(cfg.drain_secs)) => (),
+                        _ = tokio::signal::ctrl_c() => (),
+                    }
+                    break;
+                }
+                Err(err) => warn!("failed to upgrade: {err:#}"),
+            }
+        }
     }
 
     relay.shutdown().await
 }
 
-async fn maybe_load_tls(
-    cfg: &Config,
-) -> Result<Option<relay::TlsConfig<std::io::Error, std::io::Error>>> {
-    let Some(ref tls) = cfg.tls else {
-        return Ok(None);
+/// Takes over the listeners of the previous process, if started by [`upgrade`].
+#[cfg(unix)]
+async fn inherit_listeners(
+    mut config: relay::ServerConfig<std::io::Error>,
+) -> Result<(
+    relay::ServerConfig<std::io::Error>,
+    Option<relay::handoff::Inherited>,
+)> {
+    let Some(mut inherited) = relay::handoff::Inherited::from_env().await? else {
+        return Ok((config, None));
     };
-    let server_config = rustls::ServerConfig::builder_with_provider(std::sync::Arc::new(
-        rustls::crypto::ring::default_provider(),
-    ))
-    .with_safe_default_protocol_versions()
-    .expect("protocols supported by ring")
-    .with_no_client_auth();
-    let (cert_config, server_config) = match tls.cert_mode {
-        CertMode::Manual => {
-            let cert_path = tls.cert_path();
-            let key_path = tls.key_path();
-            // Could probably just do this blocking, we're only starting up.
-            let (private_key, certs) = tokio::task::spawn_blocking(move || {
-                let key = load_secret_key(key_path)?;
-                let certs = load_certs(cert_path)?;
-                anyhow::Ok((key, certs))
-            })
-            .await??;
-            let server_config = server_config.with_single_cert(certs.clone(), private_key)?;
-            (relay::CertConfig::Manual { certs }, server_config)
-        }
-        CertMode::LetsEncrypt => {
+    info!(
+        metadata = %String::from_utf8_lossy(&inherited.metadata),
+        "taking over the listeners of the previous process",
+    );
+    config.listeners = std::mem::take(&mut inherited.listeners);
+    Ok((config, Some(inherited)))
+}
+
+/// Waits for `SIGUSR2`, which requests an upgrade of the binary.
+///
+/// Never returns if upgrades are not enabled.
+async fn upgrade_requested(enabled: bool) {
+    #[cfg(unix)]
+    if enabled {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::user_defined2()) {
+            Ok(mut upgrade) => {
+                if upgrade.recv().await.is_some() {
+                    return;
+                }
+            }
+            Err(err) => warn!("failed to listen for SIGUSR2: {err:#}"),
+        }
+    }
+    #[cfg(not(unix))]
+    if enabled {
+        warn!("upgrades of the binary are only supported on Unix");
+    }
+    std::future::pending().await
+}
+
+/// Starts the binary again and hands the listeners of `relay` over to it.
+///
+/// The new process gets the same arguments, the version of this one is passed on as
+/// metadata for its logs.
+#[cfg(unix)]
+async fn upgrade(relay: &relay::Server, cfg: &UpgradeConfig) -> Result<()> {
+    let mut args = std::env::args_os();
+    let program = args.next().context("missing program name")?;
+    let mut command = tokio::process::Command::new(program);
+    command.args(args);
+    let metadata = serde_json::to_vec(&serde_json::json!({
+        "pid": std::process::id(),
+        "version": env!("CARGO_PKG_VERSION"),
+    }))?;
+    let timeout = Duration::from_secs(cfg.timeout_secs);
+    relay::handoff::upgrade(relay, command, &metadata, timeout).await?;
+    Ok(())
+}
+
+/// Receives `SIGHUP`, which requests a reload of the config file.
+#[derive(Debug)]
+struct Hangups {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangups {
+    fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let signal = signal(SignalKind::hangup())
+                .inspect_err(|err| warn!("failed to listen for SIGHUP: {err:#}"))
+                .ok();
+            Self { signal }
+        }
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    /// Waits for the next `SIGHUP`, never returns if not listening for it.
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(ref mut signal) = self.signal {
+            if signal.recv().await.is_some() {
+                return;
+            }
+        }
+        std::future::pending().await
+    }
+}
+
+/// Returns the TLS config whose certificate files are watched, see [`relay::CertWatcher`].
+///
+/// Only the files of the `Manual` cert mode are watched, the `Reloading` mode re-reads its
+/// files periodically and Let's Encrypt certificates are renewed automatically.
+fn watched_tls(tls: Option<&TlsConfig>) -> Option<&TlsConfig> {
+    tls.filter(|tls| {
+        tls.cert_mode == CertMode::Manual
+            && !tls.dangerous_http_only
+            && tls.cert_watch_interval_secs > 0
+    })
+}
+
+/// Loads the TLS server config of the `Manual` cert mode, e.g. with renewed certificates.
+async fn load_manual_tls(tls: &TlsConfig) -> Result<rustls::ServerConfig> {
+    let (_, server_config) = tls_server_config_builder(tls)?;
+    let (_, server_config) = manual_server_config(tls, server_config).await?;
+    Ok(server_config)
+}
+
+/// Builds the settings of the running relay server which are replaced on `SIGHUP`.
+///
+/// The certificates are only reloaded in the `Manual` cert mode, the `Reloading` mode
+/// re-reads its files periodically and Let's Encrypt certificates are renewed
+/// automatically.
+async fn reload_config(cli: &Cli) -> Result<relay::ReloadConfig> {
+    let cfg = Config::load(cli).await?;
+    let (client_rx, trusted_client_rx, client_tx) = match cfg.limits {
+        Some(ref limits) => limits.client_rate_limits()?,
+        None => Default::default(),
+    };
+    // The `--dev` mode serves HTTP only.
+    let tls_server_config = match cfg.tls {
+        Some(ref tls) if tls.cert_mode == CertMode::Manual && !cli.dev => {
+            Some(load_manual_tls(tls).await?)
+        }
+        _ => None,
+    };
+    Ok(relay::ReloadConfig {
+        client_rx,
+        trusted_client_rx,
+        client_tx,
+        access: cfg.access.clone().try_into()?,
+        headers: cfg.headers()?,
+        route_headers: cfg.route_headers()?,
+        tls_server_config,
+    })
+}
+
+/// Starts building the TLS server config, returning the client authentication as well.
+fn tls_server_config_builder(
+    tls: &TlsConfig,
+) -> Result<(
+    Option<relay::ClientAuthConfig>,
+    rustls::ConfigBuilder<rustls::ServerConfig, rustls::server::WantsServerCert>,
+)> {
+    let client_auth = match tls.client_ca_path {
+        Some(ref path) => {
+            let roots = load_certs(path)
+                .with_context(|| format!("failed to read client CAs from {}", path.display()))?;
+            Some(relay::ClientAuthConfig { roots })
+        }
+        None => None,
+    };
+    let policy = tls.policy()?;
+    let provider = rustls::crypto::ring::default_provider();
+    let server_config = match client_auth {
+        Some(ref client_auth) => policy.mutual_server_config_builder(provider, client_auth),
+        None => policy.server_config_builder(provider),
+    }
+    .context("invalid TLS configuration")?;
+    Ok((client_auth, server_config))
+}
+
+/// Loads the key, certificate chain and OCSP response of the `Manual` cert mode into the
+/// TLS server config, returning the certificate chain as well.
+async fn manual_server_config(
+    tls: &TlsConfig,
+    server_config: rustls::ConfigBuilder<rustls::ServerConfig, rustls::server::WantsServerCert>,
+) -> Result<(
+    Vec<rustls::pki_types::CertificateDer<'static>>,
+    rustls::ServerConfig,
+)> {
+    let cert_path = tls.cert_path();
+    let key_path = tls.key_path();
+    let ocsp_path = tls.manual_ocsp_path.clone();
+    let (key, certs, ocsp) = tokio::task::spawn_blocking(move || {
+        let key = load_secret_key(key_path)?;
+        let certs = load_certs(cert_path)?;
+        let ocsp = match ocsp_path {
+            Some(path) => std::fs::read(&path)
+                .with_context(|| format!("cannot read OCSP response file {}", path.display()))?,
+            None => Vec::new(),
+        };
+        anyhow::Ok((key, certs, ocsp))
+    })
+    .await??;
+    let server_config = server_config.with_single_cert_with_ocsp(certs.clone(), key, ocsp)?;
+    Ok((certs, server_config))
+}
+
+async fn maybe_load_tls(
+    cfg: &Config,
+) -> Result<Option<relay::TlsConfig<std::io::Error, std::io::Error>>> {
+    let Some(ref tls) = cfg.tls else {
+        return Ok(None);
+    };
+    let (client_auth, server_config) = tls_server_config_builder(tls)?;
+    let (cert_config, server_config) = match tls.cert_mode {
+        CertMode::Manual => {
+            let (certs, server_config) = manual_server_config(tls, server_config).await?;
+            (relay::CertConfig::Manual { certs }, server_config)
+        }
+        CertMode::LetsEncrypt => {
             let hostname = tls
                 .hostname
                 .clone()
@@ -556,11 +1581,21 @@ async fn maybe_load_tls(
             (relay::CertConfig::Reloading, server_config)
         }
     };
+    let ech_config_list = tls
+        .ech_config_list
+        .as_ref()
+        .map(|ech| data_encoding::BASE64.decode(ech.as_bytes()))
+        .transpose()
+        .context("invalid ech_config_list")?;
     Ok(Some(relay::TlsConfig {
         https_bind_addr: tls.https_bind_addr(cfg),
         cert: cert_config,
         server_config,
         quic_bind_addr: tls.quic_bind_addr(cfg),
+        ech_config_list,
+        http2: tls.http2,
+        webtransport: tls.webtransport,
+        client_auth,
     }))
 }
 
@@ -585,58 +1620,1209 @@ async fn build_relay_config(cfg: Config) -> Result<relay::ServerConfig<std::io::
     };
     let limits = match cfg.limits {
         Some(ref limits) => {
-            let client_rx = match &limits.client {
-                Some(PerClientRateLimitConfig { rx: Some(rx) }) => {
-                    if rx.bytes_per_second.is_none() && rx.max_burst_bytes.is_some() {
-                        bail!("bytes_per_seconds must be specified to enable the rate-limiter");
-                    }
-                    match rx.bytes_per_second {
-                        Some(bps) => Some(ClientRateLimit {
-                            bytes_per_second: bps
-                                .try_into()
-                                .context("bytes_per_second must be non-zero u32")?,
-                            max_burst_bytes: rx
-                                .max_burst_bytes
-                                .map(|v| {
-                                    v.try_into().context("max_burst_bytes must be non-zero u32")
-                                })
-                                .transpose()?,
-                        }),
-                        None => None,
-                    }
-                }
-                Some(PerClientRateLimitConfig { rx: None }) | None => None,
+            let (client_rx, trusted_client_rx, client_tx) = limits.client_rate_limits()?;
+            let handshakes = match limits.max_concurrent_handshakes {
+                Some(max_concurrent) => Some(relay::HandshakeLimit {
+                    max_concurrent: max_concurrent
+                        .try_into()
+                        .context("max_concurrent_handshakes must be non-zero")?,
+                    queue_timeout: Duration::from_millis(
+                        limits
+                            .handshake_queue_timeout_ms
+                            .unwrap_or(DEFAULT_HANDSHAKE_QUEUE_TIMEOUT_MS),
+                    ),
+                }),
+                None => None,
             };
+            let max_connections = limits
+                .max_connections
+                .map(NonZeroUsize::try_from)
+                .transpose()
+                .context("max_connections must be non-zero")?;
+            let max_connections_per_ip = limits
+                .max_connections_per_ip
+                .map(NonZeroUsize::try_from)
+                .transpose()
+                .context("max_connections_per_ip must be non-zero")?;
+            let clients_per_ip = limits
+                .clients_per_ip
+                .as_ref()
+                .map(ClientsPerIpConfig::client_ip_limit)
+                .transpose()
+                .context("invalid clients_per_ip limit")?;
+            let client_quota = limits
+                .client_quota
+                .as_ref()
+                .map(ClientQuotaConfig::quota_config)
+                .transpose()
+                .context("invalid client quota")?;
+            let send_queue = limits
+                .send_queue
+                .as_ref()
+                .map(SendQueueConfig::send_queue_config)
+                .transpose()
+                .context("invalid send queue")?;
             relay::Limits {
                 accept_conn_limit: limits.accept_conn_limit,
                 accept_conn_burst: limits.accept_conn_burst,
                 client_rx,
+                trusted_client_rx,
+                client_tx,
+                handshakes,
+                max_connections,
+                max_connections_per_ip,
+                clients_per_ip,
+                client_quota,
+                send_queue,
             }
         }
         None => Default::default(),
     };
+    if cfg.payload_compression && !cfg!(feature = "payload-compression") {
+        warn!("payload compression is configured but not built in, clients' requests are ignored");
+    }
 
-    let relay_config = relay::RelayConfig {
-        http_bind_addr: cfg.http_bind_addr(),
-        // if `dangerous_http_only` is set, do not pass in any tls configuration
-        tls: relay_tls.and_then(|tls| if dangerous_http_only { None } else { Some(tls) }),
-        limits,
-        key_cache_capacity: cfg.key_cache_capacity,
-        access: cfg.access.clone().into(),
+    let mut relay_config = relay::RelayConfig::new(cfg.http_bind_addr());
+    // if `dangerous_http_only` is set, do not pass in any tls configuration
+    relay_config.tls = relay_tls.filter(|_| !dangerous_http_only);
+    relay_config.limits = limits;
+    relay_config.key_cache_capacity = cfg.key_cache_capacity;
+    relay_config.key_cache_eviction = cfg.key_cache_eviction;
+    relay_config.access = cfg.access.clone().try_into()?;
+    relay_config.admin = cfg.admin.as_ref().map(|admin| relay::AdminConfig {
+        bearer_token: admin.bearer_token.clone(),
+    });
+    relay_config.watchdog = cfg.watchdog.as_ref().map(|watchdog| relay::WatchdogConfig {
+        interval: Duration::from_secs(watchdog.interval_secs),
+        max_connection_tasks: watchdog.max_connection_tasks,
+        max_client_tasks: watchdog.max_client_tasks,
+        max_orphaned_client_tasks: watchdog.max_orphaned_client_tasks,
+        max_client_queue_depth: watchdog.max_client_queue_depth,
+        report_path: watchdog.report_path.clone(),
+    });
+    relay_config.mesh_key = cfg
+        .mesh_key
+        .as_deref()
+        .map(str::parse)
+        .transpose()
+        .context("invalid mesh_key")?;
+    relay_config.mesh = cfg.mesh.as_ref().map(|mesh| relay::MeshConfig {
+        peers: mesh.peers.clone(),
+    });
+    relay_config.sessions = cfg.sessions.as_ref().map(|sessions| relay::SessionConfig {
+        grace_period: Duration::from_secs(sessions.grace_period_secs),
+    });
+    relay_config.keep_alive = cfg
+        .keep_alive
+        .as_ref()
+        .map(|keep_alive| relay::KeepAliveConfig {
+            default_interval: Duration::from_secs(keep_alive.default_interval_secs),
+            min_interval: Duration::from_secs(keep_alive.min_interval_secs),
+            max_interval: Duration::from_secs(keep_alive.max_interval_secs),
+        });
+    relay_config.payload_compression = cfg.payload_compression;
+    relay_config.unknown_peers = cfg
+        .unknown_peers
+        .as_ref()
+        .map(UnknownPeersConfig::unknown_peer_config)
+        .transpose()?;
+    relay_config.compression =
+        cfg.compression
+            .as_ref()
+            .map(|compression| relay::CompressionConfig {
+                encodings: compression.encodings.clone(),
+                min_size: compression.min_size,
+                content_types: compression.content_types.clone(),
+            });
+    relay_config.ipv6_only = cfg.ipv6_only;
+    relay_config.proxy_protocol = cfg.proxy_protocol;
+    relay_config.trusted_proxies = cfg
+        .trusted_proxies
+        .iter()
+        .map(|net| {
+            net.parse()
+                .with_context(|| format!("invalid trusted proxy network: {net}"))
+        })
+        .collect::<Result<_>>()?;
+    relay_config.access_log = cfg
+        .access_log
+        .as_ref()
+        .map(|access_log| match &access_log.path {
+            Some(path) => relay::AccessLog::File(path.clone()),
+            None => relay::AccessLog::Stdout,
+        });
+    relay_config.error_pages = match cfg.error_pages {
+        Some(ref error_pages) => error_pages.load().await?,
+        None => Default::default(),
     };
+    relay_config.headers = cfg.headers()?;
+    relay_config.route_headers = cfg.route_headers()?;
 
     let stun_config = relay::StunConfig {
         bind_addr: cfg.stun_bind_addr(),
+        additional_bind_addrs: cfg.stun_additional_bind_addrs.clone(),
     };
     Ok(relay::ServerConfig {
         relay: Some(relay_config),
         stun: Some(stun_config).filter(|_| cfg.enable_stun),
         quic: quic_config,
         #[cfg(feature = "metrics")]
-        metrics_addr: Some(cfg.metrics_bind_addr()).filter(|_| cfg.enable_metrics),
+        metrics: match cfg.enable_metrics {
+            true => MetricsExporter::Prometheus(cfg.metrics_bind_addr()),
+            false => MetricsExporter::Disabled,
+        },
+        listeners: Default::default(),
     })
 }
 
+/// The `bench` subcommand, driving synthetic clients against a relay server.
+mod bench {
+    use std::{fmt, net::Ipv4Addr};
+
+    use anyhow::{ensure, Context as _, Result};
+    use bytes::Bytes;
+    use iroh_base::{NodeId, RelayUrl, SecretKey};
+    use iroh_relay::{
+        client::{ClientBuilder, ClientSink, ClientStream, ReceivedMessage, SendMessage},
+        dns::DnsResolver,
+        MAX_PACKET_SIZE,
+    };
+    use n0_future::{SinkExt, StreamExt};
+    use tokio::{
+        task::JoinSet,
+        time::{self, Instant, MissedTickBehavior},
+    };
+    use tracing::debug;
+
+    use super::{build_relay_config, relay, Cli, Config, Duration};
+
+    /// The length of the header of the packets, the send time in microseconds.
+    const HEADER_LEN: usize = 8;
+
+    /// How long to wait for packets in flight once the clients stopped sending.
+    const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// The arguments of the `bench` subcommand.
+    #[derive(clap::Args, Debug, Clone)]
+    pub(super) struct BenchArgs {
+        /// The number of clients, at least 2.
+        #[clap(long, default_value_t = 10)]
+        clients: usize,
+        /// The number of packets each client sends per second.
+        #[clap(long, default_value_t = 100)]
+        rate: u32,
+        /// Which clients send packets to which.
+        #[clap(long, value_enum, default_value_t = Pattern::Pairs)]
+        pattern: Pattern,
+        /// The size of the packets in bytes, at least 8.
+        #[clap(long, default_value_t = 1024)]
+        size: usize,
+        /// How long the clients send packets, in seconds.
+        #[clap(long, default_value_t = 10)]
+        duration_secs: u64,
+        /// The URL of an already running relay server to benchmark instead.
+        #[clap(long)]
+        url: Option<RelayUrl>,
+    }
+
+    /// Which clients send packets to which.
+    #[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+    pub(super) enum Pattern {
+        /// Clients send to each other in pairs.
+        Pairs,
+        /// Each client sends to the next one, the last one to the first.
+        Ring,
+        /// All clients send to the first one, which sends to the second.
+        FanIn,
+    }
+
+    impl Pattern {
+        /// Returns the index of the client which client `i` of `n` sends to.
+        pub(super) fn destination(self, i: usize, n: usize) -> usize {
+            match self {
+                // Without a partner the last client sends to the previous pair.
+                Self::Pairs if i ^ 1 >= n => i - 1,
+                Self::Pairs => i ^ 1,
+                Self::Ring => (i + 1) % n,
+                Self::FanIn if i == 0 => 1,
+                Self::FanIn => 0,
+            }
+        }
+    }
+
+    /// Runs the benchmark and prints the report.
+    pub(super) async fn run(cli: &Cli, args: &BenchArgs) -> Result<()> {
+        ensure!(args.clients >= 2, "at least 2 clients are required");
+        ensure!(args.rate > 0, "the rate must be positive");
+        ensure!(
+            (HEADER_LEN..=MAX_PACKET_SIZE).contains(&args.size),
+            "the size must be between {HEADER_LEN} and {MAX_PACKET_SIZE} bytes"
+        );
+
+        let (url, server) = match args.url {
+            Some(ref url) => (url.clone(), None),
+            None => {
+                let mut cfg = Config::load(cli).await?;
+                cfg.enable_relay = true;
+                cfg.http_bind_addr = Some((Ipv4Addr::LOCALHOST, 0).into());
+                cfg.tls = None;
+                cfg.enable_stun = false;
+                cfg.enable_quic_addr_discovery = false;
+                cfg.enable_metrics = false;
+                let server = relay::Server::spawn(build_relay_config(cfg).await?).await?;
+                let addr = server
+                    .http_addr()
+                    .context("relay server has no HTTP address")?;
+                (format!("http://{addr}").parse()?, Some(server))
+            }
+        };
+        println!(
+            "benchmarking {url} with {} clients sending {} packets/s of {} bytes, {:?}",
+            args.clients, args.rate, args.size, args.pattern
+        );
+
+        let resolver = DnsResolver::new();
+        let mut keys = Vec::with_capacity(args.clients);
+        let mut clients = Vec::with_capacity(args.clients);
+        for i in 0..args.clients {
+            let secret_key = SecretKey::generate(rand::thread_rng());
+            keys.push(secret_key.public());
+            let client = connect(ClientBuilder::new(
+                url.clone(),
+                secret_key,
+                resolver.clone(),
+            ))
+            .await
+            .with_context(|| format!("failed to connect client {i}"))?;
+            clients.push(client);
+        }
+
+        let start = Instant::now();
+        let deadline = start + Duration::from_secs(args.duration_secs);
+        let mut senders = JoinSet::new();
+        let mut receivers = JoinSet::new();
+        for (i, (stream, sink)) in clients.into_iter().enumerate() {
+            let dst = keys[args.pattern.destination(i, args.clients)];
+            senders.spawn(send_packets(
+                sink, dst, args.rate, args.size, start, deadline,
+            ));
+            receivers.spawn(receive_packets(stream, start, deadline + DRAIN_TIMEOUT));
+        }
+        let mut report = Report::default();
+        while let Some(res) = senders.join_next().await {
+            report.sent += res.context("sender panicked")??;
+        }
+        report.elapsed = start.elapsed();
+        while let Some(res) = receivers.join_next().await {
+            report.latencies.extend(res.context("receiver panicked")??);
+        }
+        report.latencies.sort_unstable();
+
+        if let Some(server) = server {
+            server.shutdown().await?;
+        }
+        println!("{report}");
+        Ok(())
+    }
+
+    /// Connects a client and waits until the server registered it.
+    async fn connect(builder: ClientBuilder) -> Result<(ClientStream, ClientSink)> {
+        let mut client = builder.connect().await?;
+        // The server only reads the ping once the client is registered.
+        client.send(SendMessage::Ping([0u8; 8])).await?;
+        loop {
+            match client.next().await.context("connection closed")?? {
+                ReceivedMessage::Pong(_) => break,
+                msg => debug!(?msg, "ignoring message while connecting"),
+            }
+        }
+        Ok(client.split())
+    }
+
+    /// Sends packets at the given rate until the deadline, returns the number sent.
+    async fn send_packets(
+        mut sink: ClientSink,
+        dst: NodeId,
+        rate: u32,
+        size: usize,
+        start: Instant,
+        deadline: Instant,
+    ) -> Result<u64> {
+        let mut interval = time::interval(Duration::from_secs(1) / rate);
+        // Falling behind lowers the rate, rather than sending bursts.
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut packet = vec![0u8; size];
+        let mut sent = 0;
+        while interval.tick().await < deadline {
+            let micros = u64::try_from(start.elapsed().as_micros()).unwrap_or(u64::MAX);
+            packet[..HEADER_LEN].copy_from_slice(&micros.to_be_bytes());
+            sink.send(SendMessage::SendPacket(
+                dst,
+                Bytes::copy_from_slice(&packet),
+            ))
+            .await?;
+            sent += 1;
+        }
+        Ok(sent)
+    }
+
+    /// Receives packets until the deadline, returns how long each one took.
+    async fn receive_packets(
+        mut stream: ClientStream,
+        start: Instant,
+        deadline: Instant,
+    ) -> Result<Vec<Duration>> {
+        let mut latencies = Vec::new();
+        loop {
+            let msg = tokio::select! {
+                _ = time::sleep_until(deadline) => break,
+                msg = stream.next() => msg,
+            };
+            match msg {
+                Some(Ok(ReceivedMessage::ReceivedPacket { data, .. })) => {
+                    let Some(header) = data.get(..HEADER_LEN) else {
+                        continue;
+                    };
+                    let micros = u64::from_be_bytes(header.try_into().expect("header length"));
+                    latencies.push(
+                        start
+                            .elapsed()
+                            .saturating_sub(Duration::from_micros(micros)),
+                    );
+                }
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(err.context("receiving failed")),
+                None => break,
+            }
+        }
+        Ok(latencies)
+    }
+
+    /// The results of a benchmark run.
+    #[derive(Debug, Default)]
+    pub(super) struct Report {
+        /// The number of packets sent.
+        pub(super) sent: u64,
+        /// How long the clients sent packets.
+        pub(super) elapsed: Duration,
+        /// The forwarding latency of each received packet, sorted.
+        pub(super) latencies: Vec<Duration>,
+    }
+
+    impl Report {
+        /// Returns the latency below which the given fraction of the packets was received.
+        pub(super) fn percentile(&self, fraction: f64) -> Option<Duration> {
+            let last = self.latencies.len().checked_sub(1)?;
+            let index = (last as f64 * fraction).round() as usize;
+            self.latencies.get(index.min(last)).copied()
+        }
+    }
+
+    impl fmt::Display for Report {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let received = self.latencies.len() as u64;
+            let lost = self.sent.saturating_sub(received);
+            let loss = if self.sent == 0 {
+                0.
+            } else {
+                lost as f64 / self.sent as f64 * 100.
+            };
+            writeln!(
+                f,
+                "sent {} packets, received {received}, lost {lost} ({loss:.2}%)",
+                self.sent
+            )?;
+            writeln!(
+                f,
+                "throughput: {:.0} packets/s",
+                received as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+            )?;
+            write!(f, "latency:")?;
+            for (name, fraction) in [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("max", 1.)] {
+                match self.percentile(fraction) {
+                    Some(latency) => write!(f, " {name}={latency:?}")?,
+                    None => write!(f, " {name}=n/a")?,
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The `probe` subcommand, checking the health of a fleet of relay servers.
+///
+/// Every round each relay server gets a fresh client which connects, pings the server and
+/// sends a packet to itself.  Any step failing or timing out fails the probe.
+mod probe {
+    use std::{
+        collections::BTreeMap,
+        convert::Infallible,
+        fmt::Write as _,
+        net::SocketAddr,
+        path::PathBuf,
+        sync::{Arc, Mutex},
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use anyhow::{anyhow, bail, ensure, Context as _, Result};
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use hyper::{header::CONTENT_TYPE, server::conn::http1, service::service_fn, Response};
+    use hyper_util::rt::TokioIo;
+    use iroh_base::{RelayUrl, SecretKey};
+    use iroh_relay::{
+        client::{ClientBuilder, ReceivedMessage, SendMessage},
+        dns::DnsResolver,
+        RelayMap,
+    };
+    use n0_future::{SinkExt, StreamExt};
+    use serde::Serialize;
+    use tokio::{
+        net::TcpListener,
+        task::JoinSet,
+        time::{self, Instant, MissedTickBehavior},
+    };
+    use tracing::{debug, info};
+
+    use super::Duration;
+
+    /// The arguments of the `probe` subcommand.
+    #[derive(clap::Args, Debug, Clone)]
+    pub(super) struct ProbeArgs {
+        /// The URLs of the relay servers to probe.
+        urls: Vec<RelayUrl>,
+        /// A TOML file with a relay map, whose relay servers are probed as well.
+        ///
+        /// Relay servers only used for STUN are skipped.
+        #[clap(long)]
+        relay_map: Option<PathBuf>,
+        /// The interval between the probes of a relay server, in seconds.
+        #[clap(long, default_value_t = 60)]
+        interval_secs: u64,
+        /// How long a probe may take before it fails, in seconds.
+        #[clap(long, default_value_t = 10)]
+        timeout_secs: u64,
+        /// Probe once and exit, with an error if any relay server failed.
+        #[clap(long)]
+        once: bool,
+        /// Serve the results as Prometheus metrics on this address.
+        #[clap(long)]
+        metrics_addr: Option<SocketAddr>,
+    }
+
+    impl ProbeArgs {
+        /// Returns the URLs of the relay servers to probe, sorted and without duplicates.
+        async fn relay_urls(&self) -> Result<Vec<RelayUrl>> {
+            let mut urls = self.urls.clone();
+            if let Some(ref path) = self.relay_map {
+                let content = tokio::fs::read_to_string(path)
+                    .await
+                    .with_context(|| format!("failed to read relay map {}", path.display()))?;
+                let map: RelayMap = toml::from_str(&content)
+                    .with_context(|| format!("invalid relay map {}", path.display()))?;
+                urls.extend(
+                    map.nodes()
+                        .filter(|node| !node.stun_only)
+                        .map(|node| node.url.clone()),
+                );
+            }
+            urls.sort();
+            urls.dedup();
+            Ok(urls)
+        }
+    }
+
+    /// The outcome of probing a relay server.
+    #[derive(Debug, Clone, PartialEq, Serialize)]
+    pub(super) struct ProbeResult {
+        pub(super) url: RelayUrl,
+        /// When the probe started, in seconds since the unix epoch.
+        pub(super) timestamp: u64,
+        /// Whether all steps succeeded.
+        pub(super) success: bool,
+        /// How long connecting took, including the relay handshake.
+        pub(super) connect_ms: Option<f64>,
+        /// The round-trip time of a ping.
+        pub(super) ping_ms: Option<f64>,
+        /// How long a packet sent to the client itself took to arrive.
+        pub(super) loopback_ms: Option<f64>,
+        /// Why the probe failed.
+        pub(super) error: Option<String>,
+    }
+
+    /// The results of the probes by relay server, to export them as metrics.
+    #[derive(Debug, Default)]
+    pub(super) struct Fleet {
+        relays: BTreeMap<RelayUrl, RelayStats>,
+    }
+
+    #[derive(Debug)]
+    struct RelayStats {
+        last: ProbeResult,
+        probes: u64,
+        failures: u64,
+    }
+
+    impl Fleet {
+        /// Records the result of a probe.
+        pub(super) fn record(&mut self, result: ProbeResult) {
+            let stats = self
+                .relays
+                .entry(result.url.clone())
+                .or_insert_with(|| RelayStats {
+                    last: result.clone(),
+                    probes: 0,
+                    failures: 0,
+                });
+            stats.probes += 1;
+            if !result.success {
+                stats.failures += 1;
+            }
+            stats.last = result;
+        }
+
+        /// Renders the results in the Prometheus text format.
+        pub(super) fn prometheus(&self) -> String {
+            type Value = fn(&RelayStats) -> Option<f64>;
+            let metrics: [(&str, &str, &str, Value); 7] = [
+                (
+                    "relay_probe_success",
+                    "gauge",
+                    "Whether the last probe of the relay server succeeded.",
+                    |s| Some(if s.last.success { 1. } else { 0. }),
+                ),
+                (
+                    "relay_probe_connect_seconds",
+                    "gauge",
+                    "How long connecting took in the last probe.",
+                    |s| s.last.connect_ms.map(|ms| ms / 1000.),
+                ),
+                (
+                    "relay_probe_ping_seconds",
+                    "gauge",
+                    "The ping round-trip time in the last probe.",
+                    |s| s.last.ping_ms.map(|ms| ms / 1000.),
+                ),
+                (
+                    "relay_probe_loopback_seconds",
+                    "gauge",
+                    "How long the loopback packet took in the last probe.",
+                    |s| s.last.loopback_ms.map(|ms| ms / 1000.),
+                ),
+                (
+                    "relay_probe_timestamp_seconds",
+                    "gauge",
+                    "When the last probe started, in seconds since the unix epoch.",
+                    |s| Some(s.last.timestamp as f64),
+                ),
+                (
+                    "relay_probe_total",
+                    "counter",
+                    "The number of probes of the relay server.",
+                    |s| Some(s.probes as f64),
+                ),
+                (
+                    "relay_probe_failures_total",
+                    "counter",
+                    "The number of failed probes of the relay server.",
+                    |s| Some(s.failures as f64),
+                ),
+            ];
+            let mut out = String::new();
+            for (name, kind, help, value) in metrics {
+                writeln!(out, "# HELP {name} {help}").ok();
+                writeln!(out, "# TYPE {name} {kind}").ok();
+                for (url, stats) in &self.relays {
+                    if let Some(value) = value(stats) {
+                        let url = url.as_str().replace('\\', "\\\\").replace('"', "\\\"");
+                        writeln!(out, "{name}{{url=\"{url}\"}} {value}").ok();
+                    }
+                }
+            }
+            out
+        }
+    }
+
+    /// Probes the relay servers until interrupted, or once.
+    pub(super) async fn run(args: &ProbeArgs) -> Result<()> {
+        let urls = args.relay_urls().await?;
+        ensure!(!urls.is_empty(), "no relay servers to probe");
+        ensure!(args.interval_secs > 0, "the interval must be positive");
+        let timeout = Duration::from_secs(args.timeout_secs);
+
+        let fleet = Arc::new(Mutex::new(Fleet::default()));
+        if let Some(addr) = args.metrics_addr {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("failed to bind metrics address {addr}"))?;
+            info!("serving metrics on {}", listener.local_addr()?);
+            tokio::spawn(serve_metrics(listener, fleet.clone()));
+        }
+
+        let resolver = DnsResolver::new();
+        let mut interval = time::interval(Duration::from_secs(args.interval_secs));
+        // A round taking longer than the interval delays the next one.
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let results = probe_all(&urls, &resolver, timeout).await;
+            let failed = results.iter().filter(|r| !r.success).count();
+            for result in results {
+                println!("{}", serde_json::to_string(&result)?);
+                fleet.lock().expect("poisoned").record(result);
+            }
+            if args.once {
+                if failed > 0 {
+                    bail!("{failed} of {} relay servers failed", urls.len());
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    /// Probes all relay servers concurrently, returns the results in the order of `urls`.
+    pub(super) async fn probe_all(
+        urls: &[RelayUrl],
+        resolver: &DnsResolver,
+        timeout: Duration,
+    ) -> Vec<ProbeResult> {
+        let mut probes = JoinSet::new();
+        for (i, url) in urls.iter().enumerate() {
+            let (url, resolver) = (url.clone(), resolver.clone());
+            probes.spawn(async move { (i, probe(url, &resolver, timeout).await) });
+        }
+        let mut results: Vec<_> = probes.join_all().await;
+        results.sort_by_key(|(i, _)| *i);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    /// Probes a relay server.
+    async fn probe(url: RelayUrl, resolver: &DnsResolver, timeout: Duration) -> ProbeResult {
+        let mut result = ProbeResult {
+            url: url.clone(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            success: false,
+            connect_ms: None,
+            ping_ms: None,
+            loopback_ms: None,
+            error: None,
+        };
+        let res = time::timeout(timeout, run_steps(url, resolver, &mut result))
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("timed out after {timeout:?}")));
+        match res {
+            Ok(()) => result.success = true,
+            Err(err) => {
+                debug!(url = %result.url, "probe failed: {err:#}");
+                result.error = Some(format!("{err:#}"));
+            }
+        }
+        result
+    }
+
+    /// Runs the steps of a probe, recording their durations in `result`.
+    async fn run_steps(
+        url: RelayUrl,
+        resolver: &DnsResolver,
+        result: &mut ProbeResult,
+    ) -> Result<()> {
+        let secret_key = SecretKey::generate(rand::thread_rng());
+        let node_id = secret_key.public();
+        let start = Instant::now();
+        let mut client = ClientBuilder::new(url, secret_key, resolver.clone())
+            .connect()
+            .await
+            .context("connect failed")?;
+        result.connect_ms = Some(millis(start.elapsed()));
+
+        let rtt = client.latency().await.context("ping failed")?;
+        result.ping_ms = Some(millis(rtt));
+
+        // The server answered the ping, so the client is registered and can be sent to.
+        let payload = Bytes::copy_from_slice(&rand::random::<[u8; 16]>());
+        let start = Instant::now();
+        client
+            .send(SendMessage::SendPacket(node_id, payload.clone()))
+            .await
+            .context("loopback send failed")?;
+        loop {
+            match client.next().await.context("connection closed")?? {
+                ReceivedMessage::ReceivedPacket {
+                    remote_node_id,
+                    data,
+                } if remote_node_id == node_id && data == payload => break,
+                msg => debug!(?msg, "ignoring message while probing"),
+            }
+        }
+        result.loopback_ms = Some(millis(start.elapsed()));
+
+        client.close().await.ok();
+        Ok(())
+    }
+
+    fn millis(duration: Duration) -> f64 {
+        duration.as_secs_f64() * 1000.
+    }
+
+    /// Serves the metrics of the fleet over HTTP, on any path.
+    async fn serve_metrics(listener: TcpListener, fleet: Arc<Mutex<Fleet>>) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    debug!("failed to accept metrics connection: {err:#}");
+                    continue;
+                }
+            };
+            let fleet = fleet.clone();
+            let service = service_fn(move |_req| {
+                let body = fleet.lock().expect("poisoned").prometheus();
+                async move {
+                    Ok::<_, Infallible>(
+                        Response::builder()
+                            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+                            .body(Full::new(Bytes::from(body)))
+                            .expect("valid response"),
+                    )
+                }
+            });
+            tokio::spawn(async move {
+                if let Err(err) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    debug!("metrics connection failed: {err:#}");
+                }
+            });
+        }
+    }
+}
+
+/// The `gen` subcommand, generating the files to deploy the relay server.
+///
+/// The artifacts are parameterized by the hostname and ports of the deployment, the same
+/// parameters render a systemd unit, reverse proxy configs and a config file which fit
+/// together.
+mod deploy {
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+        path::PathBuf,
+    };
+
+    use anyhow::{Context as _, Result};
+
+    use super::{
+        cfg_defaults, AccessConfig, CertMode, Cli, Config, KeyCacheEviction, Limits,
+        PerClientRateLimitConfig, RateLimitConfig, TlsConfig, WatchdogConfig,
+        DEFAULT_HANDSHAKE_QUEUE_TIMEOUT_MS, DEFAULT_HTTPS_PORT, DEFAULT_HTTP_PORT,
+        DEFAULT_METRICS_PORT, DEFAULT_RELAY_QUIC_PORT, DEFAULT_STUN_PORT, DEV_MODE_HTTP_PORT,
+    };
+
+    /// The config file path used by the systemd unit if `--config-path` is not given.
+    const DEFAULT_CONFIG_PATH: &str = "/etc/iroh-relay/config.toml";
+
+    /// The state directory of the systemd unit, holding the LetsEncrypt certificates.
+    const STATE_DIR: &str = "/var/lib/iroh-relay";
+
+    /// The rate limit of the data received from each client in the generated config.
+    const CLIENT_RX_BYTES_PER_SECOND: u32 = 4 * 1024 * 1024;
+
+    /// The burst of the data received from each client in the generated config.
+    const CLIENT_RX_MAX_BURST_BYTES: u32 = 8 * 1024 * 1024;
+
+    /// The limit of concurrent handshakes in the generated config.
+    const MAX_CONCURRENT_HANDSHAKES: usize = 1024;
+
+    /// The arguments of the `gen` subcommand.
+    #[derive(clap::Args, Debug, Clone)]
+    pub(super) struct GenArgs {
+        /// The artifact to print.
+        #[clap(value_enum)]
+        artifact: Artifact,
+        /// The public hostname of the relay server.
+        #[clap(long)]
+        hostname: String,
+        /// The port of the plain HTTP server, serving captive portal detection.
+        #[clap(long, default_value_t = DEFAULT_HTTP_PORT)]
+        http_port: u16,
+        /// The port of the HTTPS server.
+        #[clap(long, default_value_t = DEFAULT_HTTPS_PORT)]
+        https_port: u16,
+        /// The UDP port of the STUN server.
+        #[clap(long, default_value_t = DEFAULT_STUN_PORT)]
+        stun_port: u16,
+        /// The UDP port of the QUIC address discovery server.
+        #[clap(long, default_value_t = DEFAULT_RELAY_QUIC_PORT)]
+        quic_port: u16,
+        /// The port of the metrics server, which is only bound on localhost.
+        #[clap(long, default_value_t = DEFAULT_METRICS_PORT)]
+        metrics_port: u16,
+        /// Terminate TLS in a reverse proxy instead of the relay server.
+        ///
+        /// The relay server then serves plain HTTP on localhost on the `--upstream-port`,
+        /// the nginx and caddy configs always proxy to it.
+        #[clap(long)]
+        behind_proxy: bool,
+        /// The localhost port the relay server serves plain HTTP on behind a proxy.
+        #[clap(long, default_value_t = DEV_MODE_HTTP_PORT)]
+        upstream_port: u16,
+        /// The path of the relay server binary in the systemd unit.
+        #[clap(long, default_value = "/usr/local/bin/iroh-relay")]
+        binary_path: PathBuf,
+    }
+
+    /// An artifact generated by the `gen` subcommand.
+    #[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+    pub(super) enum Artifact {
+        /// A hardened systemd service unit.
+        Systemd,
+        /// An nginx reverse proxy config.
+        Nginx,
+        /// A caddy reverse proxy config.
+        Caddy,
+        /// A hardened relay server config file.
+        Config,
+    }
+
+    /// Prints the artifact to stdout.
+    pub(super) fn run(cli: &Cli, args: &GenArgs) -> Result<()> {
+        let deployment = Deployment::new(cli, args);
+        let artifact = match args.artifact {
+            Artifact::Systemd => deployment.systemd_unit(),
+            Artifact::Nginx => deployment.nginx_config(),
+            Artifact::Caddy => deployment.caddy_config(),
+            Artifact::Config => deployment.config_toml()?,
+        };
+        print!("{artifact}");
+        Ok(())
+    }
+
+    /// The parameters of a deployment.
+    #[derive(Debug, Clone)]
+    pub(super) struct Deployment {
+        pub(super) hostname: String,
+        pub(super) http_port: u16,
+        pub(super) https_port: u16,
+        pub(super) stun_port: u16,
+        pub(super) quic_port: u16,
+        pub(super) metrics_port: u16,
+        pub(super) behind_proxy: bool,
+        pub(super) upstream_port: u16,
+        pub(super) binary_path: PathBuf,
+        pub(super) config_path: PathBuf,
+    }
+
+    impl Deployment {
+        fn new(cli: &Cli, args: &GenArgs) -> Self {
+            Self {
+                hostname: args.hostname.clone(),
+                http_port: args.http_port,
+                https_port: args.https_port,
+                stun_port: args.stun_port,
+                quic_port: args.quic_port,
+                metrics_port: args.metrics_port,
+                behind_proxy: args.behind_proxy,
+                upstream_port: args.upstream_port,
+                binary_path: args.binary_path.clone(),
+                config_path: cli
+                    .config_path
+                    .clone()
+                    .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH)),
+            }
+        }
+
+        /// The config file for this deployment.
+        pub(super) fn config(&self) -> Config {
+            let any = |port| SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+            let (http_bind_addr, tls) = if self.behind_proxy {
+                ((Ipv4Addr::LOCALHOST, self.upstream_port).into(), None)
+            } else {
+                let tls = TlsConfig {
+                    https_bind_addr: Some(any(self.https_port)),
+                    quic_bind_addr: Some(any(self.quic_port)),
+                    hostname: Some(self.hostname.clone()),
+                    cert_mode: CertMode::LetsEncrypt,
+                    cert_dir: Some(PathBuf::from(STATE_DIR).join("certs")),
+                    manual_cert_path: None,
+                    manual_key_path: None,
+                    manual_ocsp_path: None,
+                    cert_watch_interval_secs: cfg_defaults::tls_config::cert_watch_interval_secs(),
+                    prod_tls: cfg_defaults::tls_config::prod_tls(),
+                    contact: None,
+                    ech_config_list: None,
+                    http2: cfg_defaults::tls_config::http2(),
+                    webtransport: cfg_defaults::tls_config::webtransport(),
+                    min_version: cfg_defaults::tls_config::min_version(),
+                    cipher_suites: Vec::new(),
+                    kx_groups: Vec::new(),
+                    client_ca_path: None,
+                    dangerous_http_only: cfg_defaults::tls_config::dangerous_http_only(),
+                };
+                (any(self.http_port), Some(tls))
+            };
+            Config {
+                enable_relay: true,
+                http_bind_addr: Some(http_bind_addr),
+                // QUIC address discovery needs the certificates of the relay server.
+                enable_quic_addr_discovery: tls.is_some(),
+                tls,
+                enable_stun: true,
+                stun_bind_addr: Some(any(self.stun_port)),
+                stun_additional_bind_addrs: Vec::new(),
+                limits: Some(Limits {
+                    accept_conn_limit: None,
+                    accept_conn_burst: None,
+                    client: Some(PerClientRateLimitConfig {
+                        rx: Some(RateLimitConfig {
+                            bytes_per_second: Some(CLIENT_RX_BYTES_PER_SECOND),
+                            max_burst_bytes: Some(CLIENT_RX_MAX_BURST_BYTES),
+                        }),
+                        tx: None,
+                    }),
+                    trusted_client: None,
+                    max_concurrent_handshakes: Some(MAX_CONCURRENT_HANDSHAKES),
+                    handshake_queue_timeout_ms: Some(DEFAULT_HANDSHAKE_QUEUE_TIMEOUT_MS),
+                    max_connections: None,
+                    max_connections_per_ip: None,
+                    clients_per_ip: None,
+                    client_quota: None,
+                    send_queue: None,
+                }),
+                enable_metrics: true,
+                metrics_bind_addr: Some((Ipv4Addr::LOCALHOST, self.metrics_port).into()),
+                key_cache_capacity: None,
+                key_cache_eviction: KeyCacheEviction::default(),
+                access: AccessConfig::Everyone,
+                admin: None,
+                watchdog: Some(WatchdogConfig {
+                    interval_secs: cfg_defaults::watchdog::interval_secs(),
+                    max_connection_tasks: None,
+                    max_client_tasks: None,
+                    max_orphaned_client_tasks: cfg_defaults::watchdog::max_orphaned_client_tasks(),
+                    max_client_queue_depth: None,
+                    report_path: None,
+                }),
+                mesh_key: None,
+                mesh: None,
+                sessions: None,
+                keep_alive: None,
+                payload_compression: false,
+                unknown_peers: None,
+                compression: None,
+                ipv6_only: None,
+                proxy_protocol: false,
+                trusted_proxies: Vec::new(),
+                access_log: None,
+                error_pages: None,
+                headers: Default::default(),
+                route_headers: Default::default(),
+                upgrade: None,
+            }
+        }
+
+        /// The config file for this deployment, as TOML.
+        pub(super) fn config_toml(&self) -> Result<String> {
+            let config = toml::to_string(&self.config()).context("failed to serialize config")?;
+            let mode = if self.behind_proxy {
+                "TLS is terminated by a reverse proxy, see `iroh-relay gen nginx` and `iroh-relay gen caddy`."
+            } else {
+                "Certificates are obtained from LetsEncrypt, which must be able to reach the HTTPS port."
+            };
+            Ok(format!(
+                "# iroh-relay config for {hostname}, generated by `iroh-relay gen config`.\n\
+                 #\n\
+                 # {mode}\n\
+                 # Clients are rate limited and the metrics are only served on localhost.\n\
+                 \n\
+                 {config}",
+                hostname = self.hostname,
+            ))
+        }
+
+        /// A systemd unit running the relay server with a restricted set of privileges.
+        pub(super) fn systemd_unit(&self) -> String {
+            let privileged = self.public_ports().iter().any(|port| *port < 1024);
+            let capabilities = if privileged {
+                "AmbientCapabilities=CAP_NET_BIND_SERVICE\nCapabilityBoundingSet=CAP_NET_BIND_SERVICE\n"
+            } else {
+                "CapabilityBoundingSet=\n"
+            };
+            format!(
+                r#"[Unit]
+Description=iroh relay server for {hostname}
+Documentation=https://github.com/n0-computer/iroh
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+Type=simple
+ExecStart={binary} --config-path {config}
+Restart=on-failure
+RestartSec=5s
+Environment=RUST_LOG=info
+DynamicUser=yes
+StateDirectory=iroh-relay
+WorkingDirectory={state_dir}
+{capabilities}NoNewPrivileges=yes
+ProtectSystem=strict
+ProtectHome=yes
+PrivateTmp=yes
+PrivateDevices=yes
+ProtectClock=yes
+ProtectHostname=yes
+ProtectKernelLogs=yes
+ProtectKernelModules=yes
+ProtectKernelTunables=yes
+ProtectControlGroups=yes
+RestrictAddressFamilies=AF_INET AF_INET6 AF_UNIX
+RestrictNamespaces=yes
+RestrictRealtime=yes
+RestrictSUIDSGID=yes
+LockPersonality=yes
+MemoryDenyWriteExecute=yes
+SystemCallArchitectures=native
+SystemCallFilter=@system-service
+LimitNOFILE=65536
+
+[Install]
+WantedBy=multi-user.target
+"#,
+                hostname = self.hostname,
+                binary = self.binary_path.display(),
+                config = self.config_path.display(),
+                state_dir = STATE_DIR,
+            )
+        }
+
+        /// An nginx config terminating TLS and proxying to the relay server.
+        ///
+        /// Relay clients upgrade their HTTP connection to the relay protocol, so the
+        /// `Upgrade` header must be passed through and responses must not be buffered.
+        pub(super) fn nginx_config(&self) -> String {
+            format!(
+                r#"# nginx reverse proxy for the iroh relay at {hostname}.
+#
+# The relay server must serve plain HTTP on 127.0.0.1:{upstream}, see
+# `iroh-relay gen config --behind-proxy`.  STUN on UDP port {stun} can not be proxied and
+# must be reachable directly.
+
+map $http_upgrade $connection_upgrade {{
+    default upgrade;
+    ''      close;
+}}
+
+server {{
+    listen {https} ssl;
+    listen [::]:{https} ssl;
+    server_name {hostname};
+
+    ssl_certificate     /etc/letsencrypt/live/{hostname}/fullchain.pem;
+    ssl_certificate_key /etc/letsencrypt/live/{hostname}/privkey.pem;
+    ssl_protocols       TLSv1.2 TLSv1.3;
+
+    location / {{
+        proxy_pass http://127.0.0.1:{upstream};
+        proxy_http_version 1.1;
+        proxy_set_header Upgrade $http_upgrade;
+        proxy_set_header Connection $connection_upgrade;
+        proxy_set_header Host $host;
+        proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;
+        proxy_set_header X-Forwarded-Proto $scheme;
+        proxy_buffering off;
+        proxy_read_timeout 1h;
+        proxy_send_timeout 1h;
+    }}
+}}
+
+server {{
+    listen {http};
+    listen [::]:{http};
+    server_name {hostname};
+
+    # Captive portal detection must be served over plain HTTP.
+    location = /generate_204 {{
+        proxy_pass http://127.0.0.1:{upstream};
+        proxy_set_header Host $host;
+    }}
+
+    location / {{
+        return 301 https://$host{https_suffix}$request_uri;
+    }}
+}}
+"#,
+                hostname = self.hostname,
+                upstream = self.upstream_port,
+                stun = self.stun_port,
+                http = self.http_port,
+                https = self.https_port,
+                https_suffix = self.https_suffix(),
+            )
+        }
+
+        /// A caddy config terminating TLS and proxying to the relay server.
+        pub(super) fn caddy_config(&self) -> String {
+            format!(
+                r#"# caddy reverse proxy for the iroh relay at {hostname}.
+#
+# The relay server must serve plain HTTP on 127.0.0.1:{upstream}, see
+# `iroh-relay gen config --behind-proxy`.  STUN on UDP port {stun} can not be proxied and
+# must be reachable directly.
+
+{{
+    # Relay clients upgrade HTTP/1.1 connections to the relay protocol.
+    servers {{
+        protocols h1
+    }}
+}}
+
+{hostname}:{https} {{
+    # caddy passes the Upgrade header through to the relay server.
+    reverse_proxy 127.0.0.1:{upstream} {{
+        flush_interval -1
+    }}
+}}
+
+http://{hostname}:{http} {{
+    # Captive portal detection must be served over plain HTTP.
+    handle /generate_204 {{
+        reverse_proxy 127.0.0.1:{upstream}
+    }}
+    handle {{
+        redir https://{hostname}{https_suffix}{{uri}} permanent
+    }}
+}}
+"#,
+                hostname = self.hostname,
+                upstream = self.upstream_port,
+                stun = self.stun_port,
+                http = self.http_port,
+                https = self.https_port,
+                https_suffix = self.https_suffix(),
+            )
+        }
+
+        /// The ports the relay server itself binds on all interfaces.
+        fn public_ports(&self) -> Vec<u16> {
+            let mut ports = vec![self.stun_port];
+            if !self.behind_proxy {
+                ports.extend([self.http_port, self.https_port, self.quic_port]);
+            }
+            ports
+        }
+
+        /// The port to add to redirect URLs, empty for the default HTTPS port.
+        fn https_suffix(&self) -> String {
+            match self.https_port {
+                DEFAULT_HTTPS_PORT => String::new(),
+                port => format!(":{port}"),
+            }
+        }
+    }
+}
+
 mod metrics {
     use iroh_metrics::{
         core::{Counter, Metric},
@@ -696,6 +2882,180 @@ mod tests {
 
     use super::*;
 
+    fn deployment(behind_proxy: bool) -> deploy::Deployment {
+        deploy::Deployment {
+            hostname: "relay.example.com".to_string(),
+            http_port: DEFAULT_HTTP_PORT,
+            https_port: 8443,
+            stun_port: DEFAULT_STUN_PORT,
+            quic_port: DEFAULT_RELAY_QUIC_PORT,
+            metrics_port: DEFAULT_METRICS_PORT,
+            behind_proxy,
+            upstream_port: 3340,
+            binary_path: "/usr/bin/iroh-relay".into(),
+            config_path: "/etc/iroh-relay.toml".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gen_config() -> TestResult {
+        let config = Config::from_str(&deployment(false).config_toml()?)?;
+        let tls = config.tls.as_ref().context("tls")?;
+        assert_eq!(tls.hostname.as_deref(), Some("relay.example.com"));
+        assert_eq!(tls.https_bind_addr(&config).port(), 8443);
+        assert!(config.enable_quic_addr_discovery);
+        assert!(config.metrics_bind_addr().ip().is_loopback());
+
+        let config = Config::from_str(&deployment(true).config_toml()?)?;
+        assert!(config.tls.is_none());
+        assert!(!config.enable_quic_addr_discovery);
+        assert_eq!(config.http_bind_addr(), "127.0.0.1:3340".parse()?);
+        let relay_config = build_relay_config(config).await?;
+        let limits = relay_config.relay.context("relay")?.limits;
+        assert!(limits.client_rx.is_some());
+        assert!(limits.handshakes.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gen_proxy_configs() {
+        let nginx = deployment(true).nginx_config();
+        assert!(nginx.contains("proxy_pass http://127.0.0.1:3340;"));
+        assert!(nginx.contains("proxy_set_header Upgrade $http_upgrade;"));
+        assert!(nginx.contains("proxy_set_header Connection $connection_upgrade;"));
+        assert!(nginx.contains("listen 8443 ssl;"));
+        assert!(nginx.contains("return 301 https://$host:8443$request_uri;"));
+
+        let caddy = deployment(true).caddy_config();
+        assert!(caddy.contains("relay.example.com:8443 {"));
+        assert!(caddy.contains("reverse_proxy 127.0.0.1:3340"));
+    }
+
+    #[test]
+    fn test_gen_systemd_unit() {
+        let unit = deployment(false).systemd_unit();
+        assert!(unit.contains("ExecStart=/usr/bin/iroh-relay --config-path /etc/iroh-relay.toml"));
+        assert!(unit.contains("AmbientCapabilities=CAP_NET_BIND_SERVICE"));
+
+        // Behind a proxy the relay server only binds unprivileged ports.
+        let unit = deployment(true).systemd_unit();
+        assert!(!unit.contains("AmbientCapabilities"));
+        assert!(unit.contains("CapabilityBoundingSet=\n"));
+    }
+
+    #[test]
+    fn test_bench_patterns() {
+        let destinations = |pattern: bench::Pattern, n| {
+            (0..n)
+                .map(|i| pattern.destination(i, n))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(destinations(bench::Pattern::Pairs, 4), [1, 0, 3, 2]);
+        assert_eq!(destinations(bench::Pattern::Pairs, 3), [1, 0, 1]);
+        assert_eq!(destinations(bench::Pattern::Ring, 3), [1, 2, 0]);
+        assert_eq!(destinations(bench::Pattern::FanIn, 3), [1, 0, 0]);
+    }
+
+    #[test]
+    fn test_bench_report() {
+        let mut report = bench::Report {
+            sent: 200,
+            elapsed: Duration::from_secs(1),
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+        };
+        assert_eq!(report.percentile(0.5), Some(Duration::from_millis(51)));
+        assert_eq!(report.percentile(0.99), Some(Duration::from_millis(99)));
+        assert_eq!(report.percentile(1.), Some(Duration::from_millis(100)));
+        let output = report.to_string();
+        assert!(output.starts_with("sent 200 packets, received 100, lost 100 (50.00%)"));
+        assert!(output.contains("p50=51ms"));
+
+        report.latencies.clear();
+        assert_eq!(report.percentile(0.5), None);
+        assert!(report.to_string().contains("p50=n/a"));
+    }
+
+    #[test]
+    fn test_bench_args() {
+        let cli = Cli::try_parse_from([
+            "iroh-relay",
+            "bench",
+            "--clients",
+            "4",
+            "--rate",
+            "50",
+            "--pattern",
+            "fan-in",
+        ])
+        .unwrap();
+        assert!(matches!(cli.command, Some(Command::Bench(_))));
+        assert!(Cli::try_parse_from(["iroh-relay", "bench", "--pattern", "star"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_probe() -> TestResult {
+        let mut cfg = Config::from_str("")?;
+        cfg.http_bind_addr = Some((std::net::Ipv4Addr::LOCALHOST, 0).into());
+        cfg.tls = None;
+        cfg.enable_stun = false;
+        cfg.enable_quic_addr_discovery = false;
+        cfg.enable_metrics = false;
+        let server = relay::Server::spawn(build_relay_config(cfg).await?).await?;
+        let addr = server.http_addr().context("no http address")?;
+        let live: RelayUrl = format!("http://{addr}").parse()?;
+        // Nothing listens on the discard port.
+        let dead: RelayUrl = "http://127.0.0.1:9".parse()?;
+
+        let urls = [live.clone(), dead.clone()];
+        let results = probe::probe_all(
+            &urls,
+            &iroh_relay::dns::DnsResolver::new(),
+            Duration::from_secs(5),
+        )
+        .await;
+        assert_eq!(results[0].url, live);
+        assert!(results[0].success, "{:?}", results[0].error);
+        assert!(results[0].connect_ms.is_some());
+        assert!(results[0].ping_ms.is_some());
+        assert!(results[0].loopback_ms.is_some());
+        assert_eq!(results[1].url, dead);
+        assert!(!results[1].success);
+        assert!(results[1].error.is_some());
+        assert!(results[1].connect_ms.is_none());
+
+        let mut fleet = probe::Fleet::default();
+        for result in results.iter().chain(&results[1..]) {
+            fleet.record(result.clone());
+        }
+        let metrics = fleet.prometheus();
+        assert!(metrics.contains("# TYPE relay_probe_success gauge"));
+        assert!(metrics.contains(&format!("relay_probe_success{{url=\"{live}\"}} 1\n")));
+        assert!(metrics.contains(&format!("relay_probe_success{{url=\"{dead}\"}} 0\n")));
+        assert!(metrics.contains(&format!("relay_probe_failures_total{{url=\"{dead}\"}} 2\n")));
+        assert!(metrics.contains(&format!("relay_probe_total{{url=\"{live}\"}} 1\n")));
+        assert!(!metrics.contains(&format!("relay_probe_ping_seconds{{url=\"{dead}\"}}")));
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe_args() {
+        let cli = Cli::try_parse_from([
+            "iroh-relay",
+            "probe",
+            "https://relay.example.com",
+            "--relay-map",
+            "relays.toml",
+            "--once",
+            "--metrics-addr",
+            "127.0.0.1:9091",
+        ])
+        .unwrap();
+        assert!(matches!(cli.command, Some(Command::Probe(_))));
+        assert!(Cli::try_parse_from(["iroh-relay", "probe", "not a url"]).is_err());
+    }
+
     #[tokio::test]
     async fn test_rate_limit_config() -> TestResult {
         let config = "
@@ -719,6 +3079,73 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_tls_policy_config() -> TestResult {
+        let config = Config::from_str(
+            r#"
+            [tls]
+            cert_mode = "Manual"
+            min_version = "1.3"
+            cipher_suites = ["TLS13_AES_256_GCM_SHA384", "tls13_chacha20_poly1305_sha256"]
+            kx_groups = ["X25519", "secp384r1"]
+            "#,
+        )?;
+        let policy = config.tls.as_ref().context("tls")?.policy()?;
+        assert_eq!(policy.min_version, TlsVersion::Tls13);
+        assert_eq!(
+            policy.cipher_suites,
+            [
+                rustls::CipherSuite::TLS13_AES_256_GCM_SHA384,
+                rustls::CipherSuite::TLS13_CHACHA20_POLY1305_SHA256
+            ]
+        );
+        assert_eq!(
+            policy.kx_groups,
+            [rustls::NamedGroup::X25519, rustls::NamedGroup::secp384r1]
+        );
+
+        let config = Config::from_str("[tls]\ncert_mode = \"Manual\"")?;
+        let policy = config.tls.as_ref().context("tls")?.policy()?;
+        assert_eq!(policy, relay::TlsPolicy::default());
+
+        let config = Config::from_str("[tls]\ncert_mode = \"Manual\"\ncipher_suites = [\"RC4\"]")?;
+        assert!(config.tls.as_ref().context("tls")?.policy().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_manual_cert_reload() -> TestResult {
+        let dir = std::env::temp_dir().join(format!("iroh-relay-certs-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir)?;
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        std::fs::write(dir.join("default.crt"), cert.cert.pem())?;
+        std::fs::write(dir.join("default.key"), cert.key_pair.serialize_pem())?;
+        let config = Config::from_str(&format!(
+            "[tls]\ncert_mode = \"Manual\"\ncert_dir = {:?}\nmanual_ocsp_path = {:?}",
+            dir,
+            dir.join("ocsp.der")
+        ))?;
+        let tls = watched_tls(config.tls.as_ref()).context("watched")?;
+        assert_eq!(tls.cert_watch_interval_secs, 60);
+        assert_eq!(tls.cert_files().len(), 3);
+        // The OCSP response is stapled once it is configured.
+        assert!(maybe_load_tls(&config).await.is_err());
+        assert!(load_manual_tls(tls).await.is_err());
+        std::fs::write(dir.join("ocsp.der"), b"response")?;
+        maybe_load_tls(&config).await?.context("tls")?;
+        load_manual_tls(tls).await?;
+
+        // The files of other cert modes are not watched.
+        let config = Config::from_str("[tls]\ncert_mode = \"LetsEncrypt\"")?;
+        assert!(watched_tls(config.tls.as_ref()).is_none());
+        let config =
+            Config::from_str("[tls]\ncert_mode = \"Manual\"\ncert_watch_interval_secs = 0")?;
+        assert!(watched_tls(config.tls.as_ref()).is_none());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_rate_limit_default() -> TestResult {
         let config = Config::from_str("")?;
@@ -726,6 +3153,352 @@ mod tests {
 
         let relay = relay_config.relay.expect("no relay config");
         assert!(relay.limits.client_rx.is_none());
+        assert!(relay.limits.client_tx.is_none());
+        assert!(relay.limits.handshakes.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handshake_limit_config() -> TestResult {
+        let config = Config::from_str("[limits]\nmax_concurrent_handshakes = 64")?;
+        let relay_config = build_relay_config(config).await?;
+
+        let relay = relay_config.relay.expect("no relay config");
+        let handshakes = relay.limits.handshakes.expect("handshake limit");
+        assert_eq!(handshakes.max_concurrent.get(), 64);
+        assert_eq!(
+            handshakes.queue_timeout,
+            Duration::from_millis(DEFAULT_HANDSHAKE_QUEUE_TIMEOUT_MS)
+        );
+
+        let config = Config::from_str("[limits]\nmax_concurrent_handshakes = 0")?;
+        assert!(build_relay_config(config).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit_config() -> TestResult {
+        let config = "
+            [limits]
+            max_connections = 10000
+            max_connections_per_ip = 32
+        ";
+        let config = Config::from_str(config)?;
+        let relay_config = build_relay_config(config).await?;
+
+        let relay = relay_config.relay.expect("no relay config");
+        assert_eq!(relay.limits.max_connections.map(|n| n.get()), Some(10000));
+        assert_eq!(
+            relay.limits.max_connections_per_ip.map(|n| n.get()),
+            Some(32)
+        );
+
+        let config = Config::from_str("[limits]\nmax_connections_per_ip = 0")?;
+        assert!(build_relay_config(config).await.is_err());
+
+        let config = r#"
+            [limits.clients_per_ip]
+            max_clients = 8
+            exempt = ["100.64.0.0/10", "2001:db8::/32"]
+        "#;
+        let config = Config::from_str(config)?;
+        let relay_config = build_relay_config(config).await?;
+        let relay = relay_config.relay.expect("no relay config");
+        let limit = relay.limits.clients_per_ip.expect("clients per ip");
+        assert_eq!(limit.max_clients.get(), 8);
+        assert_eq!(limit.exempt.len(), 2);
+
+        let config =
+            Config::from_str("[limits.clients_per_ip]\nmax_clients = 8\nexempt = [\"nope\"]")?;
+        assert!(build_relay_config(config).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_quota_config() -> TestResult {
+        let config = "
+            [limits.client_quota]
+            daily_bytes = 1000000000
+        ";
+        let config = Config::from_str(config)?;
+        let relay_config = build_relay_config(config).await?;
+
+        let relay = relay_config.relay.expect("no relay config");
+        let quota = relay.limits.client_quota.expect("quota");
+        assert_eq!(quota.daily_bytes.map(|n| n.get()), Some(1_000_000_000));
+        assert_eq!(quota.monthly_bytes, None);
+
+        let config = Config::from_str("[limits.client_quota]\nmonthly_bytes = 0")?;
+        assert!(build_relay_config(config).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_queue_config() -> TestResult {
+        let config = "
+            [limits.send_queue]
+            depth = 64
+            overflow = \"drop-oldest\"
+        ";
+        let config = Config::from_str(config)?;
+        let relay_config = build_relay_config(config).await?;
+
+        let relay = relay_config.relay.expect("no relay config");
+        let send_queue = relay.limits.send_queue.expect("send queue");
+        assert_eq!(send_queue.depth, 64);
+        assert_eq!(send_queue.overflow, OverflowPolicy::DropOldest);
+
+        let config = Config::from_str("[limits.send_queue]\noverflow = \"disconnect-client\"")?;
+        let relay = build_relay_config(config)
+            .await?
+            .relay
+            .expect("no relay config");
+        let send_queue = relay.limits.send_queue.expect("send queue");
+        assert_eq!(send_queue.depth, relay::DEFAULT_SEND_QUEUE_DEPTH);
+        assert_eq!(send_queue.overflow, OverflowPolicy::DisconnectClient);
+
+        let config = Config::from_str("[limits.send_queue]\ndepth = 0")?;
+        assert!(build_relay_config(config).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tx_rate_limit_config() -> TestResult {
+        let config = "
+            [limits.client.tx]
+            bytes_per_second = 100000
+        ";
+        let config = Config::from_str(config)?;
+        let relay_config = build_relay_config(config).await?;
+
+        let relay = relay_config.relay.expect("no relay config");
+        assert!(relay.limits.client_rx.is_none());
+        let client_tx = relay.limits.client_tx.expect("ratelimit");
+        assert_eq!(
+            client_tx.bytes_per_second,
+            NonZeroU32::try_from(100000).unwrap()
+        );
+        assert_eq!(client_tx.max_burst_bytes, None);
+
+        let config = Config::from_str("[limits.client.tx]\nmax_burst_bytes = 10")?;
+        assert!(build_relay_config(config).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mesh_key_config() -> TestResult {
+        let mesh_key = iroh_relay::MeshKey::generate();
+        let config = format!(
+            "
+            mesh_key = \"{}\"
+            [limits.trusted_client.rx]
+            bytes_per_second = 4000
+            ",
+            data_encoding::HEXLOWER.encode(&mesh_key.to_bytes())
+        );
+        let config = Config::from_str(&config)?;
+        let relay_config = build_relay_config(config).await?;
+
+        let relay = relay_config.relay.expect("no relay config");
+        assert_eq!(
+            relay.mesh_key.expect("mesh key").to_bytes(),
+            mesh_key.to_bytes()
+        );
+        assert!(relay.limits.client_rx.is_none());
+        assert_eq!(
+            relay
+                .limits
+                .trusted_client_rx
+                .expect("ratelimit")
+                .bytes_per_second,
+            NonZeroU32::try_from(4000).unwrap()
+        );
+
+        let config = Config::from_str("mesh_key = \"00\"")?;
+        assert!(build_relay_config(config).await.is_err());
+
+        let config = Config::from_str(
+            "
+            [mesh]
+            peers = [\"https://relay-b.example.com\", \"https://relay-c.example.com\"]
+            ",
+        )?;
+        let relay = build_relay_config(config).await?.relay.expect("relay");
+        let mesh = relay.mesh.expect("mesh");
+        assert_eq!(mesh.peers.len(), 2);
+        assert_eq!(mesh.peers[0].host_str(), Some("relay-b.example.com"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sessions_config() -> TestResult {
+        let relay = build_relay_config(Config::default())
+            .await?
+            .relay
+            .expect("relay");
+        assert!(relay.sessions.is_none());
+
+        let config = Config::from_str("[sessions]")?;
+        let relay = build_relay_config(config).await?.relay.expect("relay");
+        assert_eq!(
+            relay.sessions.expect("sessions").grace_period,
+            relay::DEFAULT_SESSION_GRACE_PERIOD
+        );
+
+        let config = Config::from_str(
+            "
+            [sessions]
+            grace_period_secs = 3
+            ",
+        )?;
+        let relay = build_relay_config(config).await?.relay.expect("relay");
+        assert_eq!(
+            relay.sessions.expect("sessions").grace_period,
+            Duration::from_secs(3)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_config() -> TestResult {
+        let config = Config::from_str(
+            "
+            [keep_alive]
+            min_interval_secs = 10
+            ",
+        )?;
+        let relay = build_relay_config(config).await?.relay.expect("relay");
+        let keep_alive = relay.keep_alive.expect("keep_alive");
+        assert_eq!(
+            keep_alive.default_interval,
+            relay::DEFAULT_KEEP_ALIVE_INTERVAL
+        );
+        assert_eq!(keep_alive.min_interval, Duration::from_secs(10));
+        assert_eq!(keep_alive.max_interval, Duration::from_secs(60));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unknown_peers_config() -> TestResult {
+        let config = Config::from_str(
+            "
+            [unknown_peers]
+            max_per_second = 10
+            ",
+        )?;
+        let relay = build_relay_config(config).await?.relay.expect("relay");
+        let unknown_peers = relay.unknown_peers.expect("unknown_peers");
+        assert!(unknown_peers.notify);
+        assert_eq!(unknown_peers.max_per_second, NonZeroU32::new(10));
+        assert_eq!(unknown_peers.max_burst, None);
+
+        let config = Config::from_str("[unknown_peers]\nmax_burst = 10")?;
+        assert!(build_relay_config(config).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_headers_config() -> TestResult {
+        let config = Config::from_str(
+            r#"
+            [headers]
+            Strict-Transport-Security = "max-age=60"
+            x-custom = "1"
+            "#,
+        )?;
+        let relay = build_relay_config(config).await?.relay.expect("relay");
+        assert_eq!(relay.headers.len(), 2);
+        assert_eq!(relay.headers["strict-transport-security"], "max-age=60");
+        assert_eq!(relay.headers["x-custom"], "1");
+
+        let config = Config::from_str("[headers]\n\"not a name\" = \"1\"")?;
+        assert!(build_relay_config(config).await.is_err());
+
+        let config = Config::from_str(
+            r#"
+            [route_headers.pages]
+            Content-Security-Policy = "default-src 'self'"
+            "#,
+        )?;
+        let relay = build_relay_config(config).await?.relay.expect("relay");
+        let pages = &relay.route_headers[&relay::RouteGroup::Pages];
+        assert_eq!(pages["content-security-policy"], "default-src 'self'");
+
+        assert!(Config::from_str("[route_headers.unknown]\nx-custom = \"1\"").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_error_pages_config() -> TestResult {
+        let path =
+            std::env::temp_dir().join(format!("iroh-relay-404-{}.html", rand::random::<u64>()));
+        std::fs::write(&path, "<h1>{status}</h1>")?;
+        let config = format!(
+            "
+            [error_pages.not_found]
+            file = {:?}
+
+            [error_pages.bad_request]
+            redirect = \"https://example.com/\"
+        ",
+            path.display().to_string()
+        );
+        let config = Config::from_str(&config)?;
+        let relay = build_relay_config(config)
+            .await?
+            .relay
+            .expect("no relay config");
+        assert_eq!(
+            relay.error_pages.not_found,
+            Some(relay::ErrorPage::html("<h1>{status}</h1>"))
+        );
+        assert_eq!(
+            relay.error_pages.bad_request,
+            Some(relay::ErrorPage::Redirect("https://example.com/".parse()?))
+        );
+        assert!(relay.error_pages.unavailable.is_none());
+        std::fs::remove_file(&path)?;
+
+        let config = "
+            [error_pages.unavailable]
+            file = \"/nonexistent\"
+            redirect = \"https://example.com/\"
+        ";
+        let config = Config::from_str(config)?;
+        assert!(build_relay_config(config).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compression_config() -> TestResult {
+        let config = Config::from_str("")?;
+        let relay = build_relay_config(config).await?.relay.expect("relay");
+        assert!(relay.compression.is_none());
+
+        let config = "
+            [compression]
+            encodings = [\"gzip\"]
+        ";
+        let config = Config::from_str(config)?;
+        let relay = build_relay_config(config).await?.relay.expect("relay");
+        let compression = relay.compression.expect("compression config");
+        assert_eq!(compression.encodings, vec![ContentEncoding::Gzip]);
+        assert_eq!(compression.min_size, relay::DEFAULT_COMPRESSION_MIN_SIZE);
+        assert!(compression.content_types.contains(&"text/*".to_string()));
+
+        let config = "
+            [compression]
+            encodings = [\"deflate\"]
+        ";
+        assert!(Config::from_str(config).is_err());
 
         Ok(())
     }
@@ -751,6 +3524,111 @@ mod tests {
         let config = Config::from_str(dbg!(&config))?;
         assert_eq!(config.access, AccessConfig::Allowlist(vec![node_id]));
 
+        let path =
+            std::env::temp_dir().join(format!("iroh-relay-denylist-{}", rand::random::<u64>()));
+        std::fs::write(&path, format!("# banned\n{node_id}\n"))?;
+        let config = format!("access.denylist_file = {:?}", path.display().to_string());
+        let config = Config::from_str(&config)?;
+        assert_eq!(config.access, AccessConfig::DenylistFile(path.clone()));
+        let access: relay::AccessConfig = config.access.try_into()?;
+        let list = access.node_list().expect("node list");
+        assert!(list.contains(&node_id));
+        assert_eq!(list.path(), Some(path.as_path()));
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_key_cache_and_admin_config() -> TestResult {
+        let config = "
+            key_cache_eviction = \"never\"
+
+            [admin]
+            bearer_token = \"secret\"
+        ";
+        let config = Config::from_str(config)?;
+        let relay_config = build_relay_config(config).await?;
+
+        let relay = relay_config.relay.expect("no relay config");
+        assert_eq!(relay.key_cache_eviction, KeyCacheEviction::Never);
+        assert_eq!(relay.admin.expect("admin config").bearer_token, "secret");
+        assert!(relay.watchdog.is_none());
+
+        let config = Config::from_str("")?;
+        let relay = build_relay_config(config)
+            .await?
+            .relay
+            .expect("no relay config");
+        assert_eq!(relay.key_cache_eviction, KeyCacheEviction::Lru);
+        assert!(relay.admin.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watchdog_config() -> TestResult {
+        let config = "
+            [watchdog]
+            max_client_queue_depth = 100
+            report_path = \"/tmp/watchdog.json\"
+        ";
+        let config = Config::from_str(config)?;
+        let relay = build_relay_config(config)
+            .await?
+            .relay
+            .expect("no relay config");
+        let watchdog = relay.watchdog.expect("watchdog config");
+        assert_eq!(watchdog.interval, relay::DEFAULT_WATCHDOG_INTERVAL);
+        assert_eq!(watchdog.max_orphaned_client_tasks, Some(16));
+        assert_eq!(watchdog.max_client_queue_depth, Some(100));
+        assert_eq!(watchdog.max_connection_tasks, None);
+        assert_eq!(
+            watchdog.report_path,
+            Some(PathBuf::from("/tmp/watchdog.json"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_config() -> TestResult {
+        let config = Config::from_str("")?;
+        assert!(config.upgrade.is_none());
+
+        let config = Config::from_str(
+            "
+            [upgrade]
+            drain_secs = 5
+            ",
+        )?;
+        let upgrade = config.upgrade.expect("upgrade config");
+        assert_eq!(upgrade.timeout_secs, 30);
+        assert_eq!(upgrade.drain_secs, 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stun_bind_addrs_config() -> TestResult {
+        let config = "
+            stun_bind_addr = \"192.0.2.1:3478\"
+            stun_additional_bind_addrs = [\"198.51.100.1:3478\", \"192.0.2.1:19302\"]
+        ";
+        let config = Config::from_str(config)?;
+        let stun = build_relay_config(config)
+            .await?
+            .stun
+            .expect("no stun config");
+        let addrs: Vec<SocketAddr> = stun.bind_addrs().collect();
+        assert_eq!(
+            addrs,
+            [
+                "192.0.2.1:3478".parse()?,
+                "198.51.100.1:3478".parse()?,
+                "192.0.2.1:19302".parse()?,
+            ]
+        );
+
         Ok(())
     }
 }
@@ -0,0 +1,100 @@
+//! Exporting metrics to the metrics system of the embedding application.
+//!
+//! The relay server and the iroh endpoint record their metrics in the metric groups of
+//! [`iroh_metrics`].  Which exporter, if any, publishes them is chosen when constructing the
+//! server or endpoint using a [`MetricsExporter`]:
+//!
+//! - [`MetricsExporter::Disabled`] does not set up the metrics registry nor any exporter.
+//! - [`MetricsExporter::Prometheus`] serves the metrics in the Prometheus text format.
+//! - [`MetricsExporter::Backend`] reports every increment of a counter to a [`MetricsBackend`].
+//!
+//! A [`MetricsBackend`] lets applications which already have a metrics system forward the
+//! metrics to it, without the registry of [`iroh_metrics`] being set up.  There is no
+//! built-in OTLP exporter: an OpenTelemetry pipeline is connected with a backend recording
+//! to its meter.
+//!
+//! The metrics registry of [`iroh_metrics`] and the backend are global to the process: they
+//! can only be set up once and are shared by all servers and endpoints in the process.
+
+use std::{io, net::SocketAddr, sync::Arc};
+
+pub use iroh_base::metrics::MetricsBackend;
+
+/// Where metrics are exported to.
+#[derive(Debug, Clone, Default)]
+pub enum MetricsExporter {
+    /// Metrics are not exported and the metrics registry is not set up.
+    #[default]
+    Disabled,
+    /// Serve the metrics in the Prometheus text format on this address.
+    Prometheus(SocketAddr),
+    /// Report the increments of the counters to a custom backend.
+    ///
+    /// The backend is installed for the process, see [`MetricsExporter::install_backend`].
+    Backend(Arc<dyn MetricsBackend>),
+}
+
+impl MetricsExporter {
+    /// Creates an exporter reporting to `backend`.
+    pub fn backend(backend: impl MetricsBackend) -> Self {
+        Self::Backend(Arc::new(backend))
+    }
+
+    /// Whether metrics are exported at all.
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self, Self::Disabled)
+    }
+
+    /// Returns the address the Prometheus metrics are served on, if any.
+    pub fn prometheus_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Prometheus(addr) => Some(*addr),
+            _ => None,
+        }
+    }
+
+    /// Installs the backend of a [`MetricsExporter::Backend`] for the process.
+    ///
+    /// The backend receives the increments from then on, also after the server or endpoint
+    /// is closed.  Fails if a different backend is installed already, does nothing for the
+    /// other exporters.
+    pub fn install_backend(&self) -> io::Result<()> {
+        let Self::Backend(backend) = self else {
+            return Ok(());
+        };
+        iroh_base::metrics::set_backend(backend.clone()).map_err(|installed| {
+            io::Error::other(format!(
+                "a different metrics backend is installed already: {installed:?}"
+            ))
+        })
+    }
+
+    /// Runs the Prometheus exporter.
+    ///
+    /// This returns immediately for the other exporters and otherwise only returns on errors.
+    pub async fn run(self) -> io::Result<()> {
+        match self {
+            Self::Prometheus(addr) => iroh_metrics::metrics::start_metrics_server(addr).await,
+            Self::Disabled | Self::Backend(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_disabled() {
+        let exporter = MetricsExporter::default();
+        assert!(!exporter.is_enabled());
+        assert_eq!(exporter.prometheus_addr(), None);
+        exporter.install_backend().unwrap();
+        tokio::time::timeout(Duration::from_secs(1), exporter.run())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
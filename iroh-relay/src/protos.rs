@@ -3,3 +3,5 @@
 pub mod disco;
 pub mod relay;
 pub mod stun;
+#[cfg(not(wasm_browser))]
+pub(crate) mod webtransport;
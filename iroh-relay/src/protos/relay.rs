@@ -3,8 +3,118 @@
 //! Protocol flow:
 //!
 //! Login:
-//!  * client connects
+//!  * client connects, using an HTTP upgrade or, after negotiating the `iroh-relay` TLS
+//!    ALPN, directly
 //!  * -> client sends `FrameType::ClientInfo`
+//!  * <- server sends `FrameType::Error` and closes the connection if it rejects the client
+//!
+//! Authorization tokens:
+//!  * client sends `ClientCapabilities::auth_token` with its `FrameType::ClientInfo`, the
+//!    server authorizes it like an `Authorization: Bearer` header of the HTTP upgrade
+//!
+//! Frame checksums:
+//!  * client requests `ClientCapabilities::frame_checksums` with its `FrameType::ClientInfo`
+//!  * <- server sends a checksummed `FrameType::KeepAlive` if it accepts, and checksums
+//!    all further frames
+//!  * client checksums all frames after receiving a checksummed frame
+//!  * both sides reject unchecksummed frames once they received a checksummed frame
+//!
+//! Fragmentation:
+//!  * client requests `ClientCapabilities::fragments` with its `FrameType::ClientInfo`
+//!  * <- server sends `FrameType::Capabilities` with the accepted capabilities
+//!  * client splits packets larger than [`MAX_PACKET_SIZE`] into `FrameType::SendFragment`s
+//!  * server sends them as `FrameType::RecvFragment`s to recipients which accepted
+//!    fragments, and drops them for all others
+//!
+//! Acknowledged sends:
+//!  * client requests `ClientCapabilities::send_acks` with its `FrameType::ClientInfo`
+//!  * <- server sends `FrameType::Capabilities` with the accepted capabilities
+//!  * client sends `FrameType::SendAckedPacket`, with an id chosen by the client
+//!  * <- server sends `FrameType::SendAck` with the same id and the [`SendStatus`]
+//!
+//! Send queue status:
+//!  * client requests `ClientCapabilities::queue_status` with its `FrameType::ClientInfo`
+//!  * <- server sends `FrameType::Capabilities` with the accepted capabilities
+//!  * <- server sends `FrameType::SendQueueStatus` when the queue of a destination the
+//!    client sends to fills above the high watermark, and again once it drained below the
+//!    low watermark, so the client can pause sending before packets are dropped
+//!
+//! Trusted clients:
+//!  * client sends `ClientCapabilities::mesh_proof`, proving it knows the [`MeshKey`] of
+//!    the server, with its `FrameType::ClientInfo`
+//!  * <- server sends `FrameType::Capabilities`, echoing the proof if it accepted it
+//!  * client may send `FrameType::WatchConns`, the server then sends a
+//!    `FrameType::PeerPresent` for every connected node and a `FrameType::PeerGone` for
+//!    every disconnected one
+//!  * client may send `FrameType::ForwardPacket`s on behalf of other nodes, counting the
+//!    relays the packet was forwarded between so far; the server forwards them again with
+//!    an incremented count if the destination is connected to another relay, and drops
+//!    packets which were forwarded more than [`MAX_FORWARD_HOPS`] times, so a
+//!    misconfigured mesh does not pass packets around in a loop
+//!
+//! Key rotation:
+//!  * client sends `ClientCapabilities::key_rotation`, a [`KeyRotation`] signed by its
+//!    previous key, with its `FrameType::ClientInfo` signed by its new key
+//!  * <- server sends `FrameType::Capabilities`, echoing the rotation if it accepted it
+//!  * server routes packets addressed to either key to the connection, until the
+//!    rotation expires or the client disconnects
+//!
+//! Session resumption:
+//!  * client requests `ClientCapabilities::sessions` with its `FrameType::ClientInfo`,
+//!    sending the [`SessionToken`] of its previous session if it has one
+//!  * <- server sends `FrameType::Capabilities` with the token of the new session
+//!  * server resumes the previous session if its token is valid, skipping the
+//!    authorization of the client; if the client's connection breaks, its peers are only
+//!    sent a `FrameType::PeerGone` if it does not reconnect within a grace period
+//!
+//! Keep-alive negotiation:
+//!  * client requests the interval it prefers to be pinged at on an idle connection with
+//!    `ClientCapabilities::keep_alive`, with its `FrameType::ClientInfo`
+//!  * <- server sends `FrameType::Capabilities` with the interval it picked, the one within
+//!    its bounds closest to the requested one, and the bounds
+//!  * server pings the client at the picked interval; servers which do not negotiate the
+//!    interval send no `ClientCapabilities::keep_alive` and use their default
+//!
+//! Payload compression:
+//!  * client requests a [`PayloadCompression`] with `ClientCapabilities::compression`, with
+//!    its `FrameType::ClientInfo`
+//!  * <- server sends `FrameType::Capabilities`, echoing the compression if it accepted it
+//!  * both sides may then send packets of at least [`MIN_COMPRESSED_PAYLOAD_SIZE`] bytes as
+//!    `FrameType::SendCompressedPacket` and `FrameType::RecvCompressedPacket`, if they
+//!    compress to fewer bytes; the server decompresses the packets it relays, so the peers
+//!    of the client do not need to support compression
+//!
+//! Unknown peers:
+//!  * client requests `ClientCapabilities::unknown_peers` with its `FrameType::ClientInfo`
+//!  * <- server sends `FrameType::Capabilities`, echoing the request if it accepted it
+//!  * <- server sends `FrameType::UnknownPeer` when it drops a packet of the client because
+//!    its destination is not connected; servers may also stop reading from clients which
+//!    keep sending to unknown destinations for a while
+//!
+//! Clock skew:
+//!  * client sends its current time as `ClientCapabilities::time` with its
+//!    `FrameType::ClientInfo`
+//!  * <- server sends `FrameType::Capabilities` with its own current time
+//!  * client estimates the offset of its clock from the server's, assuming the server
+//!    answered halfway through the round trip
+//!
+//! Multiplexed channels (protocol version 4):
+//!  * client sends version 4 in its `FrameType::ClientInfo`, servers supporting it accept
+//!    versions [`MIN_PROTOCOL_VERSION`] to [`PROTOCOL_VERSION`], so version 3 clients keep
+//!    working
+//!  * the frames sent to the client are multiplexed on logical [`Channel`]s: control frames,
+//!    disco packets and other packets, told apart by their type and content
+//!  * the disco and data channels are flow controlled: the server sends at most
+//!    [`CHANNEL_WINDOW`] bytes of packets on each of them, until the client grants more
+//!    credit with `FrameType::WindowUpdate` as it consumes them
+//!  * control frames are not flow controlled, so they are never stuck behind packets the
+//!    client does not keep up with
+//!
+//! Client software:
+//!  * client sends its self-reported software name and version as a [`ClientSoftware`]
+//!    after the `ClientCapabilities`, with its `FrameType::ClientInfo`
+//!  * server counts the connected clients per software version, servers which do not know
+//!    about it ignore it
 //!
 //!  Steady state:
 //!  * server occasionally sends `FrameType::KeepAlive` (or `FrameType::Ping`)
@@ -13,9 +123,8 @@
 //!  * server then sends `FrameType::RecvPacket` to recipient
 
 use anyhow::{bail, ensure};
-use bytes::{BufMut, Bytes};
+use bytes::{Buf, BufMut, Bytes};
 use iroh_base::{PublicKey, SecretKey, Signature};
-#[cfg(feature = "server")]
 use n0_future::time::Duration;
 use n0_future::{Sink, SinkExt};
 #[cfg(any(test, feature = "server"))]
@@ -30,6 +139,21 @@ use crate::{client::conn::ConnSendError, KeyCache};
 /// including its on-wire framing overhead)
 pub const MAX_PACKET_SIZE: usize = 64 * 1024;
 
+/// The maximum size of a packet sent over relay when fragmentation is enabled.
+///
+/// Packets larger than [`MAX_PACKET_SIZE`] are split into fragments which are reassembled
+/// by the receiving client.
+pub const MAX_FRAGMENTED_PACKET_SIZE: usize = 1024 * 1024;
+
+/// The maximum number of times a packet is forwarded between meshed relay servers.
+///
+/// A full mesh needs a single hop, the limit leaves room for relays which are only
+/// connected through others.  Packets with a larger hop count are dropped.
+pub const MAX_FORWARD_HOPS: u8 = 4;
+
+/// Length of the [`FragmentHeader`] at the start of a fragment.
+pub(crate) const FRAGMENT_HEADER_LEN: usize = 4 + 2 + 2;
+
 /// The maximum frame size.
 ///
 /// This is also the minimum burst size that a rate-limiter has to accept.
@@ -39,17 +163,6 @@ const MAX_FRAME_SIZE: usize = 1024 * 1024;
 /// The Relay magic number, sent in the FrameType::ClientInfo frame upon initial connection.
 const MAGIC: &str = "RELAY🔑";
 
-/// Interval in which we ping the relay server to ensure the connection is alive.
-///
-/// The default QUIC max_idle_timeout is 30s, so setting that to half this time gives some
-/// chance of recovering.
-#[cfg(feature = "server")]
-pub(crate) const PING_INTERVAL: Duration = Duration::from_secs(15);
-
-/// The number of packets buffered for sending per client
-#[cfg(feature = "server")]
-pub(crate) const PER_CLIENT_SEND_QUEUE_DEPTH: usize = 512; //32;
-
 /// ProtocolVersion is bumped whenever there's a wire-incompatible change.
 ///  - version 1 (zero on wire): consistent box headers, in use by employee dev nodes a bit
 ///  - version 2: received packets have src addrs in FrameType::RecvPacket at beginning.
@@ -62,7 +175,28 @@ pub(crate) const PER_CLIENT_SEND_QUEUE_DEPTH: usize = 512; //32;
 /// The server will error on that connection if a client sends one of these frames.
 /// This materially affects the handshake protocol, and so relay nodes on version 3 will be unable to communicate
 /// with nodes running earlier protocol versions.
-pub(crate) const PROTOCOL_VERSION: usize = 3;
+///  - version 4: the packets sent to the client are multiplexed on flow controlled
+///    [`Channel`]s.
+pub(crate) const PROTOCOL_VERSION: usize = 4;
+
+/// The oldest protocol version supported.
+pub(crate) const MIN_PROTOCOL_VERSION: usize = 3;
+
+/// The credit, in packet bytes, the server has on each flow controlled [`Channel`] when a
+/// client of protocol version 4 connects.
+pub(crate) const CHANNEL_WINDOW: u32 = 256 * 1024;
+
+/// Set in the frame type byte of frames followed by a CRC32C checksum.
+///
+/// The checksum covers the frame header, including this flag, and the frame content.  It
+/// is big-endian encoded after the content and counted in the frame length.
+const CHECKSUM_FLAG: u8 = 0x80;
+
+/// Length of the checksum of checksummed frames.
+const CHECKSUM_LEN: usize = 4;
+
+/// The CRC32C (Castagnoli) algorithm used for frame checksums.
+const CRC32C: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI);
 
 /// Indicates this IS the client's home node
 const PREFERRED: u8 = 1u8;
@@ -92,8 +226,17 @@ pub(crate) enum FrameType {
     ///
     /// 32B pub key of peer that's gone
     PeerGone = 8,
-    /// Frames 9-11 concern meshing, which we have eliminated from our version of the protocol.
-    /// Messages with these frames will be ignored.
+    /// Sent from server to trusted clients watching connections, when a node connected.
+    ///
+    /// 32B pub key of the connected node
+    PeerPresent = 9,
+    /// Sent from a trusted client to subscribe to `FrameType::PeerPresent` and
+    /// `FrameType::PeerGone` frames for all nodes.  No payload.
+    WatchConns = 10,
+    /// Sent from a trusted client to send a packet on behalf of another node.
+    ///
+    /// 32B src pub key + 32B dest pub key + 1B hop count + packet bytes
+    ForwardPacket = 11,
     /// 8 byte ping payload, to be echoed back in FrameType::Pong
     Ping = 12,
     /// 8 byte payload, the contents of ping being replied to
@@ -110,6 +253,55 @@ pub(crate) enum FrameType {
     ///
     /// Handled on the `[relay::Client]`, but currently never sent on the `[relay::Server]`
     Restarting = 15,
+    /// Sent from server to client right before closing the connection, when the client is
+    /// rejected.
+    ///
+    /// Payload is a postcard encoded [`RejectReason`].
+    Error = 16,
+    /// Sent from client to server right before the client disconnects.
+    ///
+    /// Allows the server to notify the client's peers and free its resources right away,
+    /// rather than waiting for the connection to break.  No payload.
+    Closing = 17,
+    /// Sent from server to client after the `FrameType::ClientInfo`, if the client requested
+    /// capabilities which need to be acknowledged.
+    ///
+    /// Payload is the encoded [`ClientCapabilities`] accepted by the server, see
+    /// [`ClientCapabilities::encode`].
+    Capabilities = 18,
+    /// 32B dest pub key + [`FragmentHeader`] + fragment bytes
+    SendFragment = 19,
+    /// 32B src pub key + [`FragmentHeader`] + fragment bytes
+    RecvFragment = 20,
+    /// 32B dest pub key + 4B id + packet bytes
+    SendAckedPacket = 21,
+    /// Sent from server to client in response to a `FrameType::SendAckedPacket`.
+    ///
+    /// 4B id of the acknowledged packet + 1B [`SendStatus`]
+    SendAck = 22,
+    /// Sent from server to client when the send queue of a destination of the client
+    /// crosses a watermark.
+    ///
+    /// 32B dest pub key + 1B whether the destination is ready (0x01) or congested (0x00)
+    SendQueueStatus = 23,
+    /// Sent from a client of protocol version 4 to grant the server more credit on a
+    /// [`Channel`].
+    ///
+    /// 1B channel + 4B credit in bytes
+    WindowUpdate = 24,
+    /// Sent from the client once it negotiated a [`PayloadCompression`].
+    ///
+    /// 32B dest pub key + 1B compression + compressed packet bytes
+    SendCompressedPacket = 25,
+    /// Sent from the server to a client which negotiated a [`PayloadCompression`].
+    ///
+    /// 32B src pub key + 1B compression + compressed packet bytes
+    RecvCompressedPacket = 26,
+    /// Sent from server to client when a packet of the client was dropped because its
+    /// destination is not connected to the server.
+    ///
+    /// 32B dest pub key
+    UnknownPeer = 27,
     #[num_enum(default)]
     Unknown = 255,
 }
@@ -126,6 +318,612 @@ pub(crate) struct ClientInfo {
     pub(crate) version: usize,
 }
 
+impl ClientInfo {
+    /// Returns whether the protocol version of the client is supported.
+    #[cfg(feature = "server")]
+    pub(crate) fn version_supported(&self) -> bool {
+        (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&self.version)
+    }
+
+    /// Returns whether the frames to the client are multiplexed on [`Channel`]s.
+    #[cfg(feature = "server")]
+    pub(crate) fn multiplexed(&self) -> bool {
+        self.version >= 4
+    }
+}
+
+/// The logical channels the frames sent to a client of protocol version 4 are multiplexed
+/// on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Channel {
+    /// Everything but packets, not flow controlled.
+    Control,
+    /// Disco packets.
+    Disco,
+    /// All other packets, including fragments and compressed packets.
+    Data,
+}
+
+impl Channel {
+    /// Returns the channel of a frame, and the bytes it counts against the credit of the
+    /// channel.
+    pub(crate) fn of(frame: &Frame) -> (Self, usize) {
+        match frame {
+            Frame::RecvPacket { content, .. }
+                if super::disco::looks_like_disco_wrapper(content) =>
+            {
+                (Self::Disco, content.len())
+            }
+            Frame::RecvPacket { content, .. } => (Self::Data, content.len()),
+            Frame::RecvFragment { fragment, .. } => (Self::Data, fragment.len()),
+            Frame::RecvCompressedPacket { packet, .. } => (Self::Data, packet.len()),
+            _ => (Self::Control, 0),
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Control => 0,
+            Self::Disco => 1,
+            Self::Data => 2,
+        }
+    }
+
+    fn from_u8(channel: u8) -> anyhow::Result<Self> {
+        match channel {
+            0 => Ok(Self::Control),
+            1 => Ok(Self::Disco),
+            2 => Ok(Self::Data),
+            _ => bail!("invalid channel: {channel}"),
+        }
+    }
+}
+
+/// Optional protocol features requested by the client.
+///
+/// These are sent after the [`ClientInfo`] in the same message, servers which do not know
+/// about them ignore them.  They are encoded as a list of tagged entries, see
+/// [`ClientCapabilities::encode`], so peers skip the entries they do not know about.
+#[derive(derive_more::Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct ClientCapabilities {
+    /// Whether frames should be protected by a CRC32C checksum.
+    ///
+    /// Only useful on connections without TLS, which already detects corruption.
+    pub(crate) frame_checksums: bool,
+    /// Whether packets larger than [`MAX_PACKET_SIZE`] can be sent and received as fragments.
+    pub(crate) fragments: bool,
+    /// Whether `FrameType::SendAckedPacket`s are acknowledged by the server.
+    pub(crate) send_acks: bool,
+    /// Proof that the client knows the [`MeshKey`] of the server, see [`MeshKey::proof`].
+    pub(crate) mesh_proof: Option<Signature>,
+    /// The previous key of the client, if it is rotating its key.
+    pub(crate) key_rotation: Option<KeyRotation>,
+    /// Whether `FrameType::SendQueueStatus` frames are sent by the server.
+    pub(crate) queue_status: bool,
+    /// Whether the client resumes its session when it reconnects, see [`SessionToken`].
+    pub(crate) sessions: bool,
+    /// The token of the previous session when sent by the client, of the new session when
+    /// sent by the server.
+    pub(crate) session_token: Option<SessionToken>,
+    /// The requested keep-alive interval when sent by the client, the picked one when sent
+    /// by the server.
+    pub(crate) keep_alive: Option<KeepAliveInterval>,
+    /// The compression of the packet payloads, requested by the client and echoed by the
+    /// server if it accepts it.
+    pub(crate) compression: Option<PayloadCompression>,
+    /// Whether `FrameType::UnknownPeer` frames are sent by the server.
+    pub(crate) unknown_peers: bool,
+    /// The time when the frame was sent, in milliseconds since the Unix epoch.
+    ///
+    /// Sent by clients to request the time of the server, which answers with its own.
+    pub(crate) time: Option<u64>,
+    /// A token authorizing the client, only sent by clients.
+    ///
+    /// Carried in the handshake so it reaches the server on every transport, including the
+    /// direct framing without an HTTP request to carry headers.
+    #[debug("{}", auth_token.as_ref().map_or("None", |_| "Some(..)"))]
+    pub(crate) auth_token: Option<String>,
+    /// Whether the client is told when the nodes which sent it packets are gone.
+    ///
+    /// Servers used to send `FrameType::PeerGone` only in a few cases, clients which do
+    /// not set this are not told about the nodes which disconnected.
+    pub(crate) peer_gone: bool,
+}
+
+/// The tags of the [`ClientCapabilities`] entries.
+///
+/// Tags must never be reused, peers of other versions would misinterpret the entry.
+mod capability_tag {
+    pub(super) const FRAME_CHECKSUMS: u8 = 0;
+    pub(super) const FRAGMENTS: u8 = 1;
+    pub(super) const SEND_ACKS: u8 = 2;
+    pub(super) const MESH_PROOF: u8 = 3;
+    pub(super) const KEY_ROTATION: u8 = 4;
+    pub(super) const QUEUE_STATUS: u8 = 5;
+    pub(super) const SESSIONS: u8 = 6;
+    pub(super) const SESSION_TOKEN: u8 = 7;
+    pub(super) const KEEP_ALIVE: u8 = 8;
+    pub(super) const COMPRESSION: u8 = 9;
+    pub(super) const UNKNOWN_PEERS: u8 = 10;
+    pub(super) const TIME: u8 = 11;
+    pub(super) const AUTH_TOKEN: u8 = 12;
+    pub(super) const PEER_GONE: u8 = 13;
+}
+
+impl ClientCapabilities {
+    /// Encodes the capabilities as a postcard list of entries, leaving out the defaults.
+    ///
+    /// Each entry is a tag followed by the length prefixed, postcard encoded value.  Flags
+    /// are entries with an empty value.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        use capability_tag::*;
+
+        fn value<T: Serialize>(value: &T) -> Vec<u8> {
+            postcard::to_stdvec(value).expect("serializing capabilities is infallible")
+        }
+
+        let flags = [
+            (FRAME_CHECKSUMS, self.frame_checksums),
+            (FRAGMENTS, self.fragments),
+            (SEND_ACKS, self.send_acks),
+            (QUEUE_STATUS, self.queue_status),
+            (SESSIONS, self.sessions),
+            (UNKNOWN_PEERS, self.unknown_peers),
+            (PEER_GONE, self.peer_gone),
+        ];
+        let mut entries: Vec<(u8, Vec<u8>)> = flags
+            .into_iter()
+            .filter(|(_, set)| *set)
+            .map(|(tag, _)| (tag, Vec::new()))
+            .collect();
+        entries.extend(self.mesh_proof.map(|v| (MESH_PROOF, value(&v))));
+        entries.extend(self.key_rotation.map(|v| (KEY_ROTATION, value(&v))));
+        entries.extend(self.session_token.map(|v| (SESSION_TOKEN, value(&v))));
+        entries.extend(self.keep_alive.map(|v| (KEEP_ALIVE, value(&v))));
+        entries.extend(self.compression.map(|v| (COMPRESSION, value(&v))));
+        entries.extend(self.time.map(|v| (TIME, value(&v))));
+        entries.extend(self.auth_token.as_ref().map(|v| (AUTH_TOKEN, value(v))));
+        value(&entries)
+    }
+
+    /// Decodes capabilities encoded with [`ClientCapabilities::encode`] from the start of
+    /// `bytes`, returning them and the remaining bytes.
+    ///
+    /// Entries with unknown tags are ignored.
+    pub(crate) fn decode(bytes: &[u8]) -> anyhow::Result<(Self, &[u8])> {
+        use capability_tag::*;
+
+        let (entries, rest): (Vec<(u8, &[u8])>, _) = postcard::take_from_bytes(bytes)?;
+        let mut capabilities = Self::default();
+        for (tag, value) in entries {
+            match tag {
+                FRAME_CHECKSUMS => capabilities.frame_checksums = true,
+                FRAGMENTS => capabilities.fragments = true,
+                SEND_ACKS => capabilities.send_acks = true,
+                MESH_PROOF => capabilities.mesh_proof = Some(postcard::from_bytes(value)?),
+                KEY_ROTATION => capabilities.key_rotation = Some(postcard::from_bytes(value)?),
+                QUEUE_STATUS => capabilities.queue_status = true,
+                SESSIONS => capabilities.sessions = true,
+                SESSION_TOKEN => capabilities.session_token = Some(postcard::from_bytes(value)?),
+                KEEP_ALIVE => capabilities.keep_alive = Some(postcard::from_bytes(value)?),
+                COMPRESSION => capabilities.compression = Some(postcard::from_bytes(value)?),
+                UNKNOWN_PEERS => capabilities.unknown_peers = true,
+                TIME => capabilities.time = Some(postcard::from_bytes(value)?),
+                AUTH_TOKEN => capabilities.auth_token = Some(postcard::from_bytes(value)?),
+                PEER_GONE => capabilities.peer_gone = true,
+                // Added by a newer version.
+                _ => {}
+            }
+        }
+        Ok((capabilities, rest))
+    }
+}
+
+/// Packets smaller than this are never compressed, they rarely get smaller.
+#[cfg_attr(not(feature = "payload-compression"), allow(dead_code))]
+pub(crate) const MIN_COMPRESSED_PAYLOAD_SIZE: usize = 256;
+
+/// The zstd compression level, trading compression ratio for speed.
+#[cfg(zstd)]
+const ZSTD_LEVEL: i32 = 3;
+
+/// A compression algorithm for the payloads of relayed packets.
+///
+/// Payloads which are already compressed or encrypted, like QUIC packets, do not get
+/// smaller and are sent as they are.  The algorithms need the `payload-compression`
+/// feature, without it none is supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PayloadCompression {
+    /// LZ4, fast with a moderate compression ratio.
+    Lz4,
+    /// Zstandard, a better compression ratio at a higher CPU cost.
+    ///
+    /// Not supported in browsers.
+    Zstd,
+}
+
+impl PayloadCompression {
+    /// Whether this compression is supported on the current platform.
+    pub fn is_supported(&self) -> bool {
+        match self {
+            Self::Lz4 => cfg!(feature = "payload-compression"),
+            Self::Zstd => cfg!(zstd),
+        }
+    }
+
+    /// Compresses a packet, returns `None` if it is too small or does not get smaller.
+    #[cfg(feature = "payload-compression")]
+    pub(crate) fn compress(&self, packet: &[u8]) -> Option<Bytes> {
+        if packet.len() < MIN_COMPRESSED_PAYLOAD_SIZE {
+            return None;
+        }
+        let compressed = match self {
+            Self::Lz4 => lz4_flex::block::compress_prepend_size(packet),
+            #[cfg(zstd)]
+            Self::Zstd => zstd::bulk::compress(packet, ZSTD_LEVEL).ok()?,
+            #[cfg(not(zstd))]
+            Self::Zstd => return None,
+        };
+        (compressed.len() < packet.len()).then(|| compressed.into())
+    }
+
+    /// Compresses a packet, returns `None` if it is too small or does not get smaller.
+    #[cfg(not(feature = "payload-compression"))]
+    pub(crate) fn compress(&self, _packet: &[u8]) -> Option<Bytes> {
+        None
+    }
+
+    /// Decompresses a packet, failing if it is invalid or larger than [`MAX_PACKET_SIZE`].
+    #[cfg(feature = "payload-compression")]
+    pub(crate) fn decompress(&self, compressed: &[u8]) -> anyhow::Result<Bytes> {
+        let packet = match self {
+            Self::Lz4 => {
+                let (len, _) = lz4_flex::block::uncompressed_size(compressed)?;
+                ensure!(len <= MAX_PACKET_SIZE, "compressed packet too large: {len}");
+                lz4_flex::block::decompress_size_prepended(compressed)?
+            }
+            #[cfg(zstd)]
+            Self::Zstd => zstd::bulk::decompress(compressed, MAX_PACKET_SIZE)?,
+            #[cfg(not(zstd))]
+            Self::Zstd => bail!("zstd is not supported"),
+        };
+        Ok(packet.into())
+    }
+
+    /// Decompresses a packet, failing if it is invalid or larger than [`MAX_PACKET_SIZE`].
+    #[cfg(not(feature = "payload-compression"))]
+    pub(crate) fn decompress(&self, _compressed: &[u8]) -> anyhow::Result<Bytes> {
+        bail!("{self:?} is not supported")
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Lz4 => 0,
+            Self::Zstd => 1,
+        }
+    }
+
+    fn from_u8(compression: u8) -> anyhow::Result<Self> {
+        match compression {
+            0 => Ok(Self::Lz4),
+            1 => Ok(Self::Zstd),
+            _ => bail!("invalid payload compression: {compression}"),
+        }
+    }
+}
+
+/// The interval at which the server pings a client on an idle connection.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct KeepAliveInterval {
+    /// The interval, in milliseconds.
+    pub(crate) interval_ms: u32,
+    /// The shortest interval the server accepts, in milliseconds.
+    ///
+    /// Zero when sent by the client.
+    pub(crate) min_ms: u32,
+    /// The longest interval the server accepts, in milliseconds.
+    ///
+    /// Zero when sent by the client.
+    pub(crate) max_ms: u32,
+}
+
+impl KeepAliveInterval {
+    /// Requests the interval, as sent by the client.
+    pub(crate) fn request(interval: Duration) -> Self {
+        Self {
+            interval_ms: duration_ms(interval),
+            min_ms: 0,
+            max_ms: 0,
+        }
+    }
+
+    /// Returns the picked interval and the bounds, as sent by the server.
+    #[cfg(feature = "server")]
+    pub(crate) fn picked(interval: Duration, min: Duration, max: Duration) -> Self {
+        Self {
+            interval_ms: duration_ms(interval),
+            min_ms: duration_ms(min),
+            max_ms: duration_ms(max),
+        }
+    }
+
+    pub(crate) fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms.into())
+    }
+}
+
+fn duration_ms(duration: Duration) -> u32 {
+    duration.as_millis().try_into().unwrap_or(u32::MAX)
+}
+
+/// Identifies the session of a client with a server supporting session resumption.
+///
+/// The server issues a new token with every connection.  Only the client which was issued
+/// the token can resume the session, as it is bound to the key signing the
+/// `FrameType::ClientInfo`.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, derive_more::Debug)]
+#[debug("SessionToken(..)")]
+pub(crate) struct SessionToken([u8; 16]);
+
+impl SessionToken {
+    /// Generates a random session token.
+    #[cfg(any(test, feature = "server"))]
+    pub(crate) fn generate() -> Self {
+        Self(rand::random())
+    }
+}
+
+/// The maximum length of the name and the version of a [`ClientSoftware`], in bytes.
+#[cfg(any(test, feature = "server"))]
+pub(crate) const MAX_CLIENT_SOFTWARE_LEN: usize = 64;
+
+/// The software name and version reported by the client.
+///
+/// Sent after the [`ClientCapabilities`] in the same message.  This is self-reported and
+/// only used for statistics, it must not be relied upon for anything else.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct ClientSoftware {
+    /// The name of the client software, e.g. `iroh`.
+    pub(crate) name: String,
+    /// The version of the client software, e.g. `0.32.0`.
+    pub(crate) version: String,
+}
+
+impl ClientSoftware {
+    /// Whether the name and version are non-empty, short and printable ASCII.
+    #[cfg(any(test, feature = "server"))]
+    pub(crate) fn is_valid(&self) -> bool {
+        let valid = |field: &str| {
+            !field.is_empty()
+                && field.len() <= MAX_CLIENT_SOFTWARE_LEN
+                && field.bytes().all(|b| b.is_ascii_graphic())
+        };
+        valid(&self.name) && valid(&self.version)
+    }
+}
+
+/// The domain separator of the [`KeyRotation`] signatures.
+const KEY_ROTATION_CONTEXT: &[u8] = b"iroh-relay key rotation v1";
+
+/// An attestation by the previous key of a client that it rotated to a new key.
+///
+/// The new key signs the `FrameType::ClientInfo` containing the attestation, so both keys
+/// attest the rotation.  The attestation is only valid until its expiry, which bounds the
+/// time packets addressed to the previous key are routed to the new one.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct KeyRotation {
+    /// The previous key of the client.
+    pub(crate) previous_key: PublicKey,
+    /// The expiry of the rotation window, in seconds since the UNIX epoch.
+    pub(crate) expires: u64,
+    /// The signature of the new key and the expiry, made with the previous key.
+    pub(crate) signature: Signature,
+}
+
+impl KeyRotation {
+    /// Attests the rotation from the `previous` key to the `new` key until `expires`.
+    pub(crate) fn new(previous: &SecretKey, new: &PublicKey, expires: u64) -> Self {
+        Self {
+            previous_key: previous.public(),
+            expires,
+            signature: previous.sign(&Self::signed_message(new, expires)),
+        }
+    }
+
+    /// Verifies the rotation to the `new` key, `now` being the seconds since the UNIX epoch.
+    #[cfg(feature = "server")]
+    pub(crate) fn verify(&self, new: &PublicKey, now: u64) -> bool {
+        self.previous_key != *new
+            && self.expires > now
+            && self
+                .previous_key
+                .verify(&Self::signed_message(new, self.expires), &self.signature)
+                .is_ok()
+    }
+
+    fn signed_message(new: &PublicKey, expires: u64) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(KEY_ROTATION_CONTEXT.len() + PublicKey::LENGTH + 8);
+        msg.extend_from_slice(KEY_ROTATION_CONTEXT);
+        msg.extend_from_slice(new.as_bytes());
+        msg.extend_from_slice(&expires.to_be_bytes());
+        msg
+    }
+}
+
+/// A pre-shared key authenticating trusted clients, like other relay servers or monitoring
+/// agents.
+///
+/// Trusted clients may watch the connections of all nodes and send packets on behalf of
+/// other nodes.  They never send the key itself, only a proof that they know it.
+#[derive(Clone, derive_more::Debug)]
+pub struct MeshKey(#[debug("..")] [u8; 32]);
+
+impl MeshKey {
+    /// Creates a mesh key from its bytes.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Generates a random mesh key.
+    pub fn generate() -> Self {
+        Self(rand::random())
+    }
+
+    /// Returns the bytes of the mesh key.
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0
+    }
+
+    /// Proves the knowledge of the key for the given client.
+    ///
+    /// This is a signature of the client's [`PublicKey`], made with a signing key derived
+    /// from the mesh key.  It can not be used by other clients.
+    pub(crate) fn proof(&self, client: &PublicKey) -> Signature {
+        SecretKey::from_bytes(&self.0).sign(client.as_bytes())
+    }
+
+    /// Verifies a proof made with [`MeshKey::proof`].
+    #[cfg(feature = "server")]
+    pub(crate) fn verify(&self, client: &PublicKey, proof: &Signature) -> bool {
+        SecretKey::from_bytes(&self.0)
+            .public()
+            .verify(client.as_bytes(), proof)
+            .is_ok()
+    }
+}
+
+impl std::str::FromStr for MeshKey {
+    type Err = anyhow::Error;
+
+    /// Parses a mesh key from 64 hex characters.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = data_encoding::HEXLOWER_PERMISSIVE
+            .decode(s.trim().as_bytes())
+            .map_err(|err| anyhow::anyhow!("invalid mesh key: {err}"))?;
+        let bytes = bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("invalid mesh key: expected 32 bytes"))?;
+        Ok(Self(bytes))
+    }
+}
+
+/// The header of a fragment of a packet larger than [`MAX_PACKET_SIZE`].
+///
+/// Encoded as a big-endian u32 packet id, followed by the big-endian u16 index of the
+/// fragment and the big-endian u16 number of fragments of the packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FragmentHeader {
+    /// Identifies the packet among the packets fragmented by the same sender.
+    pub(crate) id: u32,
+    /// The index of this fragment in the packet.
+    pub(crate) index: u16,
+    /// The total number of fragments of the packet.
+    pub(crate) count: u16,
+}
+
+impl FragmentHeader {
+    /// Encodes the header.
+    pub(crate) fn to_bytes(self) -> [u8; FRAGMENT_HEADER_LEN] {
+        let mut buf = [0u8; FRAGMENT_HEADER_LEN];
+        buf[..4].copy_from_slice(&self.id.to_be_bytes());
+        buf[4..6].copy_from_slice(&self.index.to_be_bytes());
+        buf[6..].copy_from_slice(&self.count.to_be_bytes());
+        buf
+    }
+
+    /// Decodes the header from the start of a fragment, returning it and the fragment bytes.
+    pub(crate) fn decode(mut fragment: Bytes) -> anyhow::Result<(Self, Bytes)> {
+        ensure!(
+            fragment.len() >= FRAGMENT_HEADER_LEN,
+            "invalid fragment length: {}",
+            fragment.len()
+        );
+        let id = fragment.get_u32();
+        let index = fragment.get_u16();
+        let count = fragment.get_u16();
+        ensure!(
+            index < count,
+            "invalid fragment index {index} of {count} fragments"
+        );
+        Ok((Self { id, index, count }, fragment))
+    }
+}
+
+/// The reason for the server to reject a client, sent in a `FrameType::Error` frame.
+///
+/// A rejection is definitive, reconnecting with the same identity and protocol version
+/// will be rejected again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectReason {
+    /// The [`NodeId`] of the client is not allowed to use this relay server.
+    ///
+    /// [`NodeId`]: iroh_base::NodeId
+    Banned,
+    /// The client exhausted the usage quota it has on this relay server.
+    QuotaExceeded,
+    /// The relay protocol version of the client is not supported by the server.
+    VersionUnsupported {
+        /// The minimum protocol version supported by the server.
+        min: usize,
+        /// The maximum protocol version supported by the server.
+        max: usize,
+    },
+    /// The server refused the credentials of the client, or the client sent none.
+    Unauthorized {
+        /// Why the client is not authorized, meant for humans.
+        reason: String,
+    },
+    /// Too many clients are connected from the address of the client.
+    TooManyClients,
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Banned => write!(f, "node is banned"),
+            Self::QuotaExceeded => write!(f, "quota exceeded"),
+            Self::VersionUnsupported { min, max } => {
+                write!(f, "unsupported protocol version, supported: {min}..={max}")
+            }
+            Self::Unauthorized { reason } => write!(f, "unauthorized: {reason}"),
+            Self::TooManyClients => write!(f, "too many clients from this address"),
+        }
+    }
+}
+
+/// What the server did with an acknowledged packet, sent in a `FrameType::SendAck` frame.
+///
+/// This only confirms that the server accepted the packet, not that it was delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SendStatus {
+    /// The packet was queued for the destination node.
+    Queued,
+    /// The destination node is not connected to the server, the packet was dropped.
+    NodeUnknown,
+    /// The send queue of the destination node is full, the packet was dropped.
+    Dropped,
+}
+
+impl SendStatus {
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Queued => 0,
+            Self::NodeUnknown => 1,
+            Self::Dropped => 2,
+        }
+    }
+
+    fn from_u8(status: u8) -> anyhow::Result<Self> {
+        match status {
+            0 => Ok(Self::Queued),
+            1 => Ok(Self::NodeUnknown),
+            2 => Ok(Self::Dropped),
+            _ => bail!("invalid send status: {status}"),
+        }
+    }
+}
+
 /// Writes complete frame, errors if it is unable to write within the given `timeout`.
 /// Ignores the timeout if `None`
 ///
@@ -153,8 +951,16 @@ pub(crate) async fn send_client_key<S: Sink<Frame, Error = ConnSendError> + Unpi
     mut writer: S,
     client_secret_key: &SecretKey,
     client_info: &ClientInfo,
+    capabilities: &ClientCapabilities,
+    software: Option<&ClientSoftware>,
 ) -> anyhow::Result<()> {
-    let msg = postcard::to_stdvec(client_info)?;
+    let mut msg = postcard::to_stdvec(client_info)?;
+    if *capabilities != ClientCapabilities::default() || software.is_some() {
+        msg.extend(capabilities.encode());
+    }
+    if let Some(software) = software {
+        msg.extend(postcard::to_stdvec(software)?);
+    }
     let signature = client_secret_key.sign(&msg);
 
     writer
@@ -173,7 +979,12 @@ pub(crate) async fn send_client_key<S: Sink<Frame, Error = ConnSendError> + Unpi
 #[cfg(any(test, feature = "server"))]
 pub(crate) async fn recv_client_key<S: Stream<Item = anyhow::Result<Frame>> + Unpin>(
     stream: S,
-) -> anyhow::Result<(PublicKey, ClientInfo)> {
+) -> anyhow::Result<(
+    PublicKey,
+    ClientInfo,
+    ClientCapabilities,
+    Option<ClientSoftware>,
+)> {
     use anyhow::Context;
     // the client is untrusted at this point, limit the input size even smaller than our usual
     // maximum frame size, and give a timeout
@@ -196,8 +1007,21 @@ pub(crate) async fn recv_client_key<S: Stream<Item = anyhow::Result<Frame>> + Un
         client_public_key
             .verify(&message, &signature)
             .context("invalid signature")?;
-        let info: ClientInfo = postcard::from_bytes(&message).context("deserialization")?;
-        Ok((client_public_key, info))
+        let (info, rest): (ClientInfo, _) =
+            postcard::take_from_bytes(&message).context("deserialization")?;
+        if rest.is_empty() {
+            return Ok((client_public_key, info, ClientCapabilities::default(), None));
+        }
+        let (capabilities, rest) =
+            ClientCapabilities::decode(rest).context("capabilities deserialization")?;
+        let software = if rest.is_empty() {
+            None
+        } else {
+            let software: ClientSoftware =
+                postcard::from_bytes(rest).context("client software deserialization")?;
+            Some(software).filter(ClientSoftware::is_valid)
+        };
+        Ok((client_public_key, info, capabilities, software))
     } else {
         anyhow::bail!("expected FrameType::ClientInfo");
     }
@@ -211,20 +1035,64 @@ pub(crate) async fn recv_client_key<S: Stream<Item = anyhow::Result<Frame>> + Un
 #[derive(Debug, Clone)]
 pub(crate) struct RelayCodec {
     cache: KeyCache,
+    /// Whether encoded frames are checksummed.
+    ///
+    /// Enabled once the peer sent a checksummed frame, checksummed frames are always
+    /// decoded.
+    checksums: bool,
+    /// Whether the peer sent a checksummed frame.
+    ///
+    /// Unchecksummed frames are rejected from then on, a bit flip in the frame type must
+    /// not turn off the protection.
+    peer_checksums: bool,
 }
 
 #[cfg(not(wasm_browser))]
 impl RelayCodec {
     #[cfg(test)]
     pub fn test() -> Self {
+        Self::new(KeyCache::test())
+    }
+
+    pub(crate) fn new(cache: KeyCache) -> Self {
         Self {
-            cache: KeyCache::test(),
+            cache,
+            checksums: false,
+            peer_checksums: false,
         }
     }
 
-    pub(crate) fn new(cache: KeyCache) -> Self {
-        Self { cache }
+    /// Checksums all further encoded frames.
+    #[cfg(any(test, feature = "server"))]
+    pub(crate) fn enable_checksums(&mut self) {
+        self.checksums = true;
     }
+
+    /// Returns whether encoded frames are checksummed.
+    #[cfg(any(test, feature = "server"))]
+    pub(crate) fn checksums(&self) -> bool {
+        self.checksums
+    }
+}
+
+/// Verifies and strips the checksum of a checksummed frame.
+///
+/// The `header` is what the checksum covers before the content.
+fn verify_checksum(header: &[u8], mut content: Bytes) -> anyhow::Result<Bytes> {
+    ensure!(
+        content.len() >= CHECKSUM_LEN,
+        "invalid checksummed frame length: {}",
+        content.len()
+    );
+    let checksum = content.split_off(content.len() - CHECKSUM_LEN);
+    let mut digest = CRC32C.digest();
+    digest.update(header);
+    digest.update(&content);
+    ensure!(
+        digest.finalize().to_be_bytes()[..] == checksum[..],
+        "frame checksum mismatch, the frame was corrupted in transit"
+    );
+    Ok(content)
 }
 
 /// The frames in the [`RelayCodec`].
@@ -250,6 +1118,16 @@ pub(crate) enum Frame {
     NodeGone {
         node_id: PublicKey,
     },
+    PeerPresent {
+        node_id: PublicKey,
+    },
+    WatchConns,
+    ForwardPacket {
+        src_key: PublicKey,
+        dst_key: PublicKey,
+        hops: u8,
+        packet: Bytes,
+    },
     Ping {
         data: [u8; 8],
     },
@@ -263,6 +1141,51 @@ pub(crate) enum Frame {
         reconnect_in: u32,
         try_for: u32,
     },
+    Error {
+        reason: RejectReason,
+    },
+    Closing,
+    Capabilities {
+        capabilities: ClientCapabilities,
+    },
+    SendFragment {
+        dst_key: PublicKey,
+        fragment: Bytes,
+    },
+    RecvFragment {
+        src_key: PublicKey,
+        fragment: Bytes,
+    },
+    SendAckedPacket {
+        dst_key: PublicKey,
+        id: u32,
+        packet: Bytes,
+    },
+    SendAck {
+        id: u32,
+        status: SendStatus,
+    },
+    SendQueueStatus {
+        dst_key: PublicKey,
+        ready: bool,
+    },
+    WindowUpdate {
+        channel: Channel,
+        credit: u32,
+    },
+    SendCompressedPacket {
+        dst_key: PublicKey,
+        compression: PayloadCompression,
+        packet: Bytes,
+    },
+    RecvCompressedPacket {
+        src_key: PublicKey,
+        compression: PayloadCompression,
+        packet: Bytes,
+    },
+    UnknownPeer {
+        dst_key: PublicKey,
+    },
 }
 
 impl Frame {
@@ -274,15 +1197,29 @@ impl Frame {
             Frame::KeepAlive => FrameType::KeepAlive,
             Frame::NotePreferred { .. } => FrameType::NotePreferred,
             Frame::NodeGone { .. } => FrameType::PeerGone,
+            Frame::PeerPresent { .. } => FrameType::PeerPresent,
+            Frame::WatchConns => FrameType::WatchConns,
+            Frame::ForwardPacket { .. } => FrameType::ForwardPacket,
             Frame::Ping { .. } => FrameType::Ping,
             Frame::Pong { .. } => FrameType::Pong,
             Frame::Health { .. } => FrameType::Health,
             Frame::Restarting { .. } => FrameType::Restarting,
+            Frame::Error { .. } => FrameType::Error,
+            Frame::Closing => FrameType::Closing,
+            Frame::Capabilities { .. } => FrameType::Capabilities,
+            Frame::SendFragment { .. } => FrameType::SendFragment,
+            Frame::RecvFragment { .. } => FrameType::RecvFragment,
+            Frame::SendAckedPacket { .. } => FrameType::SendAckedPacket,
+            Frame::SendAck { .. } => FrameType::SendAck,
+            Frame::SendQueueStatus { .. } => FrameType::SendQueueStatus,
+            Frame::WindowUpdate { .. } => FrameType::WindowUpdate,
+            Frame::SendCompressedPacket { .. } => FrameType::SendCompressedPacket,
+            Frame::RecvCompressedPacket { .. } => FrameType::RecvCompressedPacket,
+            Frame::UnknownPeer { .. } => FrameType::UnknownPeer,
         }
     }
 
     /// Serialized length (without the frame header)
-    #[cfg(not(wasm_browser))] // Not needed with websocket framing - thus not needed in browsers
     pub(crate) fn len(&self) -> usize {
         match self {
             Frame::ClientInfo {
@@ -298,10 +1235,32 @@ impl Frame {
             Frame::KeepAlive => 0,
             Frame::NotePreferred { .. } => 1,
             Frame::NodeGone { .. } => PublicKey::LENGTH,
+            Frame::PeerPresent { .. } => PublicKey::LENGTH,
+            Frame::WatchConns => 0,
+            Frame::ForwardPacket { packet, .. } => 2 * PublicKey::LENGTH + 1 + packet.len(),
             Frame::Ping { .. } => 8,
             Frame::Pong { .. } => 8,
             Frame::Health { problem } => problem.len(),
             Frame::Restarting { .. } => 4 + 4,
+            Frame::Error { reason } => postcard::experimental::serialized_size(reason)
+                .expect("serializing a reject reason is infallible"),
+            Frame::Closing => 0,
+            Frame::Capabilities { capabilities } => capabilities.encode().len(),
+            Frame::SendFragment {
+                dst_key: _,
+                fragment,
+            } => PublicKey::LENGTH + fragment.len(),
+            Frame::RecvFragment {
+                src_key: _,
+                fragment,
+            } => PublicKey::LENGTH + fragment.len(),
+            Frame::SendAckedPacket { packet, .. } => PublicKey::LENGTH + 4 + packet.len(),
+            Frame::SendAck { .. } => 4 + 1,
+            Frame::SendQueueStatus { .. } => PublicKey::LENGTH + 1,
+            Frame::WindowUpdate { .. } => 1 + 4,
+            Frame::SendCompressedPacket { packet, .. }
+            | Frame::RecvCompressedPacket { packet, .. } => PublicKey::LENGTH + 1 + packet.len(),
+            Frame::UnknownPeer { .. } => PublicKey::LENGTH,
         }
     }
 
@@ -313,24 +1272,49 @@ impl Frame {
 
     /// Tries to decode a frame received over websockets.
     ///
-    /// Specifically, bytes received from a binary websocket message frame.
-    pub(crate) fn decode_from_ws_msg(vec: Vec<u8>, cache: &KeyCache) -> anyhow::Result<Self> {
+    /// Specifically, bytes received from a binary websocket message frame.  `peer_checksums`
+    /// is set once the peer sent a checksummed frame, unchecksummed frames are rejected from
+    /// then on.
+    pub(crate) fn decode_from_ws_msg(
+        vec: Vec<u8>,
+        cache: &KeyCache,
+        peer_checksums: &mut bool,
+    ) -> anyhow::Result<Self> {
         if vec.is_empty() {
             bail!("error parsing relay::codec::Frame: too few bytes (0)");
         }
-        let bytes = Bytes::from(vec);
-        let typ = FrameType::from(bytes[0]);
-        let frame = Self::from_bytes(typ, bytes.slice(1..), cache)?;
+        let mut bytes = Bytes::from(vec);
+        let typ_byte = bytes[0];
+        // Advancing, unlike slicing, does not need to move the vec into shared storage.
+        bytes.advance(1);
+        if typ_byte & CHECKSUM_FLAG != 0 {
+            bytes = verify_checksum(&[typ_byte], bytes)?;
+            *peer_checksums = true;
+        } else {
+            ensure!(
+                !*peer_checksums,
+                "unchecksummed frame after checksums were enabled"
+            );
+        }
+        let frame = Self::from_bytes(FrameType::from(typ_byte & !CHECKSUM_FLAG), bytes, cache)?;
         Ok(frame)
     }
 
-    /// Encodes this frame for sending over websockets.
+    /// Encodes this frame for sending over websockets, optionally checksummed.
     ///
     /// Specifically meant for being put into a binary websocket message frame.
-    pub(crate) fn encode_for_ws_msg(self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.put_u8(self.typ().into());
-        self.write_to(&mut bytes);
+    pub(crate) fn encode_for_ws_msg(self, checksum: bool) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(1 + self.len() + CHECKSUM_LEN);
+        let typ: u8 = self.typ().into();
+        if checksum {
+            bytes.put_u8(typ | CHECKSUM_FLAG);
+            self.write_to(&mut bytes);
+            let checksum = CRC32C.checksum(&bytes);
+            bytes.put_u32(checksum);
+        } else {
+            bytes.put_u8(typ);
+            self.write_to(&mut bytes);
+        }
         bytes
     }
 
@@ -366,6 +1350,21 @@ impl Frame {
             Frame::NodeGone { node_id: peer } => {
                 dst.put(peer.as_ref());
             }
+            Frame::PeerPresent { node_id } => {
+                dst.put(node_id.as_ref());
+            }
+            Frame::WatchConns => {}
+            Frame::ForwardPacket {
+                src_key,
+                dst_key,
+                hops,
+                packet,
+            } => {
+                dst.put(src_key.as_ref());
+                dst.put(dst_key.as_ref());
+                dst.put_u8(*hops);
+                dst.put(packet.as_ref());
+            }
             Frame::Ping { data } => {
                 dst.put(&data[..]);
             }
@@ -382,6 +1381,61 @@ impl Frame {
                 dst.put_u32(*reconnect_in);
                 dst.put_u32(*try_for);
             }
+            Frame::Error { reason } => {
+                let reason =
+                    postcard::to_stdvec(reason).expect("serializing a reject reason is infallible");
+                dst.put(&reason[..]);
+            }
+            Frame::Closing => {}
+            Frame::Capabilities { capabilities } => {
+                dst.put(&capabilities.encode()[..]);
+            }
+            Frame::SendFragment { dst_key, fragment } => {
+                dst.put(dst_key.as_ref());
+                dst.put(fragment.as_ref());
+            }
+            Frame::RecvFragment { src_key, fragment } => {
+                dst.put(src_key.as_ref());
+                dst.put(fragment.as_ref());
+            }
+            Frame::SendAckedPacket {
+                dst_key,
+                id,
+                packet,
+            } => {
+                dst.put(dst_key.as_ref());
+                dst.put_u32(*id);
+                dst.put(packet.as_ref());
+            }
+            Frame::SendAck { id, status } => {
+                dst.put_u32(*id);
+                dst.put_u8(status.to_u8());
+            }
+            Frame::SendQueueStatus { dst_key, ready } => {
+                dst.put(dst_key.as_ref());
+                dst.put_u8(u8::from(*ready));
+            }
+            Frame::WindowUpdate { channel, credit } => {
+                dst.put_u8(channel.to_u8());
+                dst.put_u32(*credit);
+            }
+            Frame::SendCompressedPacket {
+                dst_key: key,
+                compression,
+                packet,
+            }
+            | Frame::RecvCompressedPacket {
+                src_key: key,
+                compression,
+                packet,
+            } => {
+                dst.put(key.as_ref());
+                dst.put_u8(compression.to_u8());
+                dst.put(packet.as_ref());
+            }
+            Frame::UnknownPeer { dst_key } => {
+                dst.put(dst_key.as_ref());
+            }
         }
     }
 
@@ -424,7 +1478,8 @@ impl Frame {
                     "data packet longer ({packet_len}) than max of {MAX_PACKET_SIZE}"
                 );
                 let dst_key = cache.key_from_slice(&content[..PublicKey::LENGTH])?;
-                let packet = content.slice(PublicKey::LENGTH..);
+                let mut packet = content;
+                packet.advance(PublicKey::LENGTH);
                 Self::SendPacket { dst_key, packet }
             }
             FrameType::RecvPacket => {
@@ -439,7 +1494,8 @@ impl Frame {
                     "data packet longer ({packet_len}) than max of {MAX_PACKET_SIZE}"
                 );
                 let src_key = cache.key_from_slice(&content[..PublicKey::LENGTH])?;
-                let content = content.slice(PublicKey::LENGTH..);
+                let mut content = content;
+                content.advance(PublicKey::LENGTH);
                 Self::RecvPacket { src_key, content }
             }
             FrameType::KeepAlive => {
@@ -460,8 +1516,45 @@ impl Frame {
                     content.len() == PublicKey::LENGTH,
                     "invalid peer gone frame length"
                 );
-                let peer = cache.key_from_slice(&content[..32])?;
-                Self::NodeGone { node_id: peer }
+                let peer = cache.key_from_slice(&content[..32])?;
+                Self::NodeGone { node_id: peer }
+            }
+            FrameType::PeerPresent => {
+                anyhow::ensure!(
+                    content.len() == PublicKey::LENGTH,
+                    "invalid peer present frame length"
+                );
+                let node_id = cache.key_from_slice(&content[..])?;
+                Self::PeerPresent { node_id }
+            }
+            FrameType::WatchConns => {
+                anyhow::ensure!(content.is_empty(), "invalid watch conns frame length");
+                Self::WatchConns
+            }
+            FrameType::ForwardPacket => {
+                const HEADER_LEN: usize = 2 * PublicKey::LENGTH + 1;
+                ensure!(
+                    content.len() >= HEADER_LEN,
+                    "invalid forward packet frame length: {}",
+                    content.len()
+                );
+                let packet_len = content.len() - HEADER_LEN;
+                ensure!(
+                    packet_len <= MAX_PACKET_SIZE,
+                    "data packet longer ({packet_len}) than max of {MAX_PACKET_SIZE}"
+                );
+                let src_key = cache.key_from_slice(&content[..PublicKey::LENGTH])?;
+                let dst_key =
+                    cache.key_from_slice(&content[PublicKey::LENGTH..2 * PublicKey::LENGTH])?;
+                let hops = content[2 * PublicKey::LENGTH];
+                let mut packet = content;
+                packet.advance(HEADER_LEN);
+                Self::ForwardPacket {
+                    src_key,
+                    dst_key,
+                    hops,
+                    packet,
+                }
             }
             FrameType::Ping => {
                 anyhow::ensure!(content.len() == 8, "invalid ping frame length");
@@ -489,6 +1582,139 @@ impl Frame {
                     try_for,
                 }
             }
+            FrameType::Error => {
+                let reason = postcard::from_bytes(&content)
+                    .map_err(|err| anyhow::anyhow!("invalid error frame: {err}"))?;
+                Self::Error { reason }
+            }
+            FrameType::Closing => {
+                anyhow::ensure!(content.is_empty(), "invalid closing frame length");
+                Self::Closing
+            }
+            FrameType::Capabilities => {
+                let (capabilities, _) = ClientCapabilities::decode(&content)
+                    .map_err(|err| anyhow::anyhow!("invalid capabilities frame: {err}"))?;
+                Self::Capabilities { capabilities }
+            }
+            FrameType::SendFragment | FrameType::RecvFragment => {
+                ensure!(
+                    content.len() >= PublicKey::LENGTH + FRAGMENT_HEADER_LEN,
+                    "invalid fragment frame length: {}",
+                    content.len()
+                );
+                let fragment_len = content.len() - PublicKey::LENGTH;
+                ensure!(
+                    fragment_len <= MAX_PACKET_SIZE,
+                    "fragment longer ({fragment_len}) than max of {MAX_PACKET_SIZE}"
+                );
+                let key = cache.key_from_slice(&content[..PublicKey::LENGTH])?;
+                let mut fragment = content;
+                fragment.advance(PublicKey::LENGTH);
+                if frame_type == FrameType::SendFragment {
+                    Self::SendFragment {
+                        dst_key: key,
+                        fragment,
+                    }
+                } else {
+                    Self::RecvFragment {
+                        src_key: key,
+                        fragment,
+                    }
+                }
+            }
+            FrameType::SendAckedPacket => {
+                ensure!(
+                    content.len() >= PublicKey::LENGTH + 4,
+                    "invalid send acked packet frame length: {}",
+                    content.len()
+                );
+                let packet_len = content.len() - PublicKey::LENGTH - 4;
+                ensure!(
+                    packet_len <= MAX_PACKET_SIZE,
+                    "data packet longer ({packet_len}) than max of {MAX_PACKET_SIZE}"
+                );
+                let dst_key = cache.key_from_slice(&content[..PublicKey::LENGTH])?;
+                let mut packet = content;
+                packet.advance(PublicKey::LENGTH);
+                let id = packet.get_u32();
+                Self::SendAckedPacket {
+                    dst_key,
+                    id,
+                    packet,
+                }
+            }
+            FrameType::SendAck => {
+                ensure!(
+                    content.len() == 4 + 1,
+                    "invalid send ack frame length: {}",
+                    content.len()
+                );
+                let id = u32::from_be_bytes(content[..4].try_into()?);
+                let status = SendStatus::from_u8(content[4])?;
+                Self::SendAck { id, status }
+            }
+            FrameType::SendQueueStatus => {
+                ensure!(
+                    content.len() == PublicKey::LENGTH + 1,
+                    "invalid send queue status frame length: {}",
+                    content.len()
+                );
+                let dst_key = cache.key_from_slice(&content[..PublicKey::LENGTH])?;
+                let ready = match content[PublicKey::LENGTH] {
+                    0 => false,
+                    1 => true,
+                    status => bail!("invalid send queue status: {status}"),
+                };
+                Self::SendQueueStatus { dst_key, ready }
+            }
+            FrameType::WindowUpdate => {
+                ensure!(
+                    content.len() == 1 + 4,
+                    "invalid window update frame length: {}",
+                    content.len()
+                );
+                let channel = Channel::from_u8(content[0])?;
+                let credit = u32::from_be_bytes(content[1..].try_into()?);
+                Self::WindowUpdate { channel, credit }
+            }
+            FrameType::SendCompressedPacket | FrameType::RecvCompressedPacket => {
+                ensure!(
+                    content.len() > PublicKey::LENGTH,
+                    "invalid compressed packet frame length: {}",
+                    content.len()
+                );
+                let packet_len = content.len() - PublicKey::LENGTH - 1;
+                ensure!(
+                    packet_len <= MAX_PACKET_SIZE,
+                    "data packet longer ({packet_len}) than max of {MAX_PACKET_SIZE}"
+                );
+                let key = cache.key_from_slice(&content[..PublicKey::LENGTH])?;
+                let compression = PayloadCompression::from_u8(content[PublicKey::LENGTH])?;
+                let mut packet = content;
+                packet.advance(PublicKey::LENGTH + 1);
+                if frame_type == FrameType::SendCompressedPacket {
+                    Self::SendCompressedPacket {
+                        dst_key: key,
+                        compression,
+                        packet,
+                    }
+                } else {
+                    Self::RecvCompressedPacket {
+                        src_key: key,
+                        compression,
+                        packet,
+                    }
+                }
+            }
+            FrameType::UnknownPeer => {
+                ensure!(
+                    content.len() == PublicKey::LENGTH,
+                    "invalid unknown peer frame length: {}",
+                    content.len()
+                );
+                let dst_key = cache.key_from_slice(&content)?;
+                Self::UnknownPeer { dst_key }
+            }
             _ => {
                 anyhow::bail!("invalid frame type: {:?}", frame_type);
             }
@@ -505,13 +1731,47 @@ pub use framing::*;
 
 #[cfg(not(wasm_browser))]
 mod framing {
-    use bytes::{Buf, BytesMut};
+    use bytes::BytesMut;
     use tokio_util::codec::{Decoder, Encoder};
 
     use super::*;
 
     pub(super) const HEADER_LEN: usize = 5;
 
+    /// Length of the encoded `FrameType::RecvPacket` and `FrameType::RecvFragment` headers,
+    /// including the source key.
+    #[cfg(feature = "server")]
+    pub(crate) const RECV_HEADER_LEN: usize = HEADER_LEN + PublicKey::LENGTH;
+
+    impl RelayCodec {
+        /// Encodes everything but the content of a `FrameType::RecvPacket` or
+        /// `FrameType::RecvFragment` frame.
+        ///
+        /// Together with the content this is the same as encoding the frame, allowing the
+        /// content to be written without copying it into the write buffer.
+        #[cfg(feature = "server")]
+        pub(crate) fn encode_recv_header(
+            frame_type: FrameType,
+            src_key: &PublicKey,
+            content_len: usize,
+        ) -> std::io::Result<[u8; RECV_HEADER_LEN]> {
+            let frame_len = PublicKey::LENGTH + content_len;
+            if frame_len > MAX_FRAME_SIZE {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Frame of length {} is too large.", frame_len),
+                ));
+            }
+            let frame_len_u32 = u32::try_from(frame_len).expect("just checked");
+
+            let mut header = [0u8; RECV_HEADER_LEN];
+            header[0] = frame_type.into();
+            header[1..HEADER_LEN].copy_from_slice(&frame_len_u32.to_be_bytes());
+            header[HEADER_LEN..].copy_from_slice(src_key.as_ref());
+            Ok(header)
+        }
+    }
+
     impl Decoder for RelayCodec {
         type Item = Frame;
         type Error = anyhow::Error;
@@ -523,7 +1783,7 @@ mod framing {
             }
 
             // Can't use the `Buf::get_*` APIs, as that advances the buffer.
-            let Some(frame_type) = src.first().map(|b| FrameType::from(*b)) else {
+            let Some(typ_byte) = src.first().copied() else {
                 return Ok(None); // Not enough bytes
             };
             let Some(frame_len) = src
@@ -546,10 +1806,20 @@ mod framing {
                 return Ok(None);
             }
 
-            // advance the header
-            src.advance(HEADER_LEN);
-
-            let content = src.split_to(frame_len).freeze();
+            let header = src.split_to(HEADER_LEN);
+            let mut content = src.split_to(frame_len).freeze();
+            if typ_byte & CHECKSUM_FLAG != 0 {
+                content = verify_checksum(&header, content)?;
+                // The peer supports checksums, protect our frames as well.
+                self.checksums = true;
+                self.peer_checksums = true;
+            } else {
+                ensure!(
+                    !self.peer_checksums,
+                    "unchecksummed frame after checksums were enabled"
+                );
+            }
+            let frame_type = FrameType::from(typ_byte & !CHECKSUM_FLAG);
             let frame = Frame::from_bytes(frame_type, content, &self.cache)?;
 
             Ok(Some(frame))
@@ -568,12 +1838,23 @@ mod framing {
                 ));
             }
 
-            let frame_len_u32 = u32::try_from(frame_len).expect("just checked");
-
-            dst.reserve(HEADER_LEN + frame_len);
-            dst.put_u8(frame.typ().into());
-            dst.put_u32(frame_len_u32);
-            frame.write_to(dst);
+            let typ: u8 = frame.typ().into();
+            if self.checksums {
+                let frame_len_u32 = u32::try_from(frame_len + CHECKSUM_LEN).expect("just checked");
+                let start = dst.len();
+                dst.reserve(HEADER_LEN + frame_len + CHECKSUM_LEN);
+                dst.put_u8(typ | CHECKSUM_FLAG);
+                dst.put_u32(frame_len_u32);
+                frame.write_to(dst);
+                let checksum = CRC32C.checksum(&dst[start..]);
+                dst.put_u32(checksum);
+            } else {
+                let frame_len_u32 = u32::try_from(frame_len).expect("just checked");
+                dst.reserve(HEADER_LEN + frame_len);
+                dst.put_u8(typ);
+                dst.put_u32(frame_len_u32);
+                frame.write_to(dst);
+            }
 
             Ok(())
         }
@@ -630,6 +1911,30 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_capabilities_unknown_entries() -> anyhow::Result<()> {
+        let capabilities = ClientCapabilities {
+            fragments: true,
+            keep_alive: Some(KeepAliveInterval::request(Duration::from_secs(30))),
+            ..Default::default()
+        };
+        let encoded = capabilities.encode();
+        let (decoded, rest) = ClientCapabilities::decode(&encoded)?;
+        assert_eq!(decoded, capabilities);
+        assert!(rest.is_empty());
+
+        // Entries of a newer version are skipped, whatever their value.
+        let mut entries: Vec<(u8, Vec<u8>)> = postcard::from_bytes(&encoded)?;
+        entries.insert(0, (200, vec![1, 2, 3]));
+        entries.push((201, Vec::new()));
+        let mut encoded = postcard::to_stdvec(&entries)?;
+        encoded.extend_from_slice(b"rest");
+        let (decoded, rest) = ClientCapabilities::decode(&encoded)?;
+        assert_eq!(decoded, capabilities);
+        assert_eq!(rest, b"rest");
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_send_recv_client_key() -> anyhow::Result<()> {
         let (reader, writer) = tokio::io::duplex(1024);
@@ -642,19 +1947,147 @@ mod tests {
             version: PROTOCOL_VERSION,
         };
         println!("client_key pub {:?}", client_key.public());
-        send_client_key(&mut writer, &client_key, &client_info).await?;
-        let (client_pub_key, got_client_info) = recv_client_key(&mut reader).await?;
+        send_client_key(
+            &mut writer,
+            &client_key,
+            &client_info,
+            &Default::default(),
+            None,
+        )
+        .await?;
+        let (client_pub_key, got_client_info, capabilities, software) =
+            recv_client_key(&mut reader).await?;
         assert_eq!(client_key.public(), client_pub_key);
         assert_eq!(client_info, got_client_info);
+        assert_eq!(capabilities, ClientCapabilities::default());
+        assert_eq!(software, None);
+
+        let requested = ClientCapabilities {
+            frame_checksums: true,
+            fragments: true,
+            send_acks: true,
+            mesh_proof: Some(MeshKey::generate().proof(&client_key.public())),
+            key_rotation: Some(KeyRotation::new(
+                &SecretKey::generate(rand::thread_rng()),
+                &client_key.public(),
+                u64::MAX,
+            )),
+            queue_status: true,
+            sessions: true,
+            session_token: Some(SessionToken::generate()),
+            keep_alive: Some(KeepAliveInterval::request(Duration::from_secs(30))),
+            compression: Some(PayloadCompression::Lz4),
+            unknown_peers: true,
+            time: Some(1_700_000_000_000),
+            auth_token: Some("secret".to_string()),
+            peer_gone: true,
+        };
+        send_client_key(&mut writer, &client_key, &client_info, &requested, None).await?;
+        let (_, got_client_info, capabilities, _) = recv_client_key(&mut reader).await?;
+        assert_eq!(client_info, got_client_info);
+        assert_eq!(capabilities, requested);
+
+        let software = ClientSoftware {
+            name: "iroh".to_string(),
+            version: "0.32.0".to_string(),
+        };
+        send_client_key(
+            &mut writer,
+            &client_key,
+            &client_info,
+            &Default::default(),
+            Some(&software),
+        )
+        .await?;
+        let (_, _, capabilities, got_software) = recv_client_key(&mut reader).await?;
+        assert_eq!(capabilities, ClientCapabilities::default());
+        assert_eq!(got_software, Some(software));
+
+        // Invalid software reports are ignored.
+        let software = ClientSoftware {
+            name: "iroh\n".to_string(),
+            version: "x".repeat(MAX_CLIENT_SOFTWARE_LEN + 1),
+        };
+        send_client_key(
+            &mut writer,
+            &client_key,
+            &client_info,
+            &requested,
+            Some(&software),
+        )
+        .await?;
+        let (_, _, capabilities, got_software) = recv_client_key(&mut reader).await?;
+        assert_eq!(capabilities, requested);
+        assert_eq!(got_software, None);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "server")]
+    fn test_recv_header() -> anyhow::Result<()> {
+        use bytes::BytesMut;
+        use tokio_util::codec::Encoder;
+
+        let src_key = SecretKey::from_bytes(&[42u8; 32]).public();
+        let content = Bytes::from_static(b"Hello World!");
+        let frames = [
+            Frame::RecvPacket {
+                src_key,
+                content: content.clone(),
+            },
+            Frame::RecvFragment {
+                src_key,
+                fragment: content.clone(),
+            },
+        ];
+        for frame in frames {
+            let frame_type = frame.typ();
+            let mut expected = BytesMut::new();
+            RelayCodec::test().encode(frame, &mut expected)?;
+
+            let header = RelayCodec::encode_recv_header(frame_type, &src_key, content.len())?;
+            assert_eq!(&expected[..RECV_HEADER_LEN], &header[..]);
+            assert_eq!(&expected[RECV_HEADER_LEN..], &content[..]);
+        }
+
+        assert!(
+            RelayCodec::encode_recv_header(FrameType::RecvPacket, &src_key, MAX_FRAME_SIZE)
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "payload-compression")]
+    fn test_payload_compression() -> anyhow::Result<()> {
+        use anyhow::Context;
+
+        let text = "the relay relays the packets of the nodes ".repeat(64);
+        for compression in [PayloadCompression::Lz4, PayloadCompression::Zstd] {
+            let compressed = compression
+                .compress(text.as_bytes())
+                .context("compresses")?;
+            assert!(compressed.len() < text.len());
+            assert_eq!(compression.decompress(&compressed)?, text.as_bytes());
+            // Small and incompressible packets are sent as they are.
+            assert_eq!(compression.compress(b"hello"), None);
+            let random: Vec<u8> = (0..1024).map(|_| rand::random()).collect();
+            assert_eq!(compression.compress(&random), None);
+            assert!(compression.decompress(b"garbage").is_err());
+        }
+        // Packets decompressing beyond the maximum packet size are rejected.
+        let large = vec![0u8; MAX_PACKET_SIZE + 1];
+        let compressed = lz4_flex::block::compress_prepend_size(&large);
+        assert!(PayloadCompression::Lz4.decompress(&compressed).is_err());
+        let compressed = zstd::bulk::compress(&large, 3)?;
+        assert!(PayloadCompression::Zstd.decompress(&compressed).is_err());
         Ok(())
     }
 
     #[test]
     fn test_frame_snapshot() -> anyhow::Result<()> {
         let client_key = SecretKey::from_bytes(&[42u8; 32]);
-        let client_info = ClientInfo {
-            version: PROTOCOL_VERSION,
-        };
+        let client_info = ClientInfo { version: 3 };
         let message = postcard::to_stdvec(&client_info)?;
         let signature = client_key.sign(&message);
 
@@ -723,10 +2156,210 @@ mod tests {
                 },
                 "0f 00 00 00 0a 00 00 00 14",
             ),
+            (
+                Frame::Error {
+                    reason: RejectReason::Banned,
+                },
+                "10 00",
+            ),
+            (
+                Frame::Error {
+                    reason: RejectReason::VersionUnsupported { min: 3, max: 4 },
+                },
+                "10 02 03 04",
+            ),
+            (
+                Frame::Error {
+                    reason: RejectReason::Unauthorized {
+                        reason: "expired".into(),
+                    },
+                },
+                "10 03 07 65 78 70 69 72 65 64",
+            ),
+            (
+                Frame::Error {
+                    reason: RejectReason::TooManyClients,
+                },
+                "10 04",
+            ),
+            (Frame::Closing, "11"),
+            (
+                Frame::PeerPresent {
+                    node_id: client_key.public(),
+                },
+                "09 19 7f 6b 23 e1 6c 85 32 c6 ab c8 38 fa cd 5e
+                a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
+                61",
+            ),
+            (Frame::WatchConns, "0a"),
+            (
+                Frame::ForwardPacket {
+                    src_key: client_key.public(),
+                    dst_key: client_key.public(),
+                    hops: 2,
+                    packet: "Hi".into(),
+                },
+                "0b 19 7f 6b 23 e1 6c 85 32 c6 ab c8 38 fa cd 5e
+                a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
+                61 19 7f 6b 23 e1 6c 85 32 c6 ab c8 38 fa cd 5e
+                a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
+                61 02 48 69",
+            ),
+            (
+                Frame::Capabilities {
+                    capabilities: ClientCapabilities {
+                        frame_checksums: false,
+                        fragments: true,
+                        send_acks: true,
+                        mesh_proof: None,
+                        key_rotation: None,
+                        queue_status: true,
+                        sessions: false,
+                        session_token: None,
+                        keep_alive: None,
+                        compression: None,
+                        unknown_peers: false,
+                        time: None,
+                        auth_token: None,
+                        peer_gone: false,
+                    },
+                },
+                "12 03 01 00 02 00 05 00",
+            ),
+            (
+                Frame::Capabilities {
+                    capabilities: ClientCapabilities {
+                        sessions: true,
+                        session_token: Some(SessionToken([0x2a; 16])),
+                        ..Default::default()
+                    },
+                },
+                "12 02 06 00 07 10 2a 2a 2a 2a 2a 2a 2a 2a 2a 2a
+                2a 2a 2a 2a 2a 2a",
+            ),
+            (
+                Frame::Capabilities {
+                    capabilities: ClientCapabilities {
+                        keep_alive: Some(KeepAliveInterval {
+                            interval_ms: 15_000,
+                            min_ms: 5_000,
+                            max_ms: 60_000,
+                        }),
+                        ..Default::default()
+                    },
+                },
+                "12 01 08 07 98 75 88 27 e0 d4 03",
+            ),
+            (
+                Frame::Capabilities {
+                    capabilities: ClientCapabilities {
+                        compression: Some(PayloadCompression::Zstd),
+                        ..Default::default()
+                    },
+                },
+                "12 01 09 01 01",
+            ),
+            (
+                Frame::Capabilities {
+                    capabilities: ClientCapabilities {
+                        unknown_peers: true,
+                        ..Default::default()
+                    },
+                },
+                "12 01 0a 00",
+            ),
+            (
+                Frame::Capabilities {
+                    capabilities: ClientCapabilities {
+                        time: Some(1_700_000_000_000),
+                        ..Default::default()
+                    },
+                },
+                "12 01 0b 06 80 d0 95 ff bc 31",
+            ),
+            (
+                Frame::SendFragment {
+                    dst_key: client_key.public(),
+                    fragment: Bytes::from_static(b"\0\0\0\x07\0\x01\0\x02Hi"),
+                },
+                "13 19 7f 6b 23 e1 6c 85 32 c6 ab c8 38 fa cd 5e
+                a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
+                61 00 00 00 07 00 01 00 02 48 69",
+            ),
+            (
+                Frame::RecvFragment {
+                    src_key: client_key.public(),
+                    fragment: Bytes::from_static(b"\0\0\0\x07\0\x01\0\x02Hi"),
+                },
+                "14 19 7f 6b 23 e1 6c 85 32 c6 ab c8 38 fa cd 5e
+                a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
+                61 00 00 00 07 00 01 00 02 48 69",
+            ),
+            (
+                Frame::SendAckedPacket {
+                    dst_key: client_key.public(),
+                    id: 7,
+                    packet: "Goodbye!".into(),
+                },
+                "15 19 7f 6b 23 e1 6c 85 32 c6 ab c8 38 fa cd 5e
+                a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
+                61 00 00 00 07 47 6f 6f 64 62 79 65 21",
+            ),
+            (
+                Frame::SendAck {
+                    id: 7,
+                    status: SendStatus::NodeUnknown,
+                },
+                "16 00 00 00 07 01",
+            ),
+            (
+                Frame::SendQueueStatus {
+                    dst_key: client_key.public(),
+                    ready: false,
+                },
+                "17 19 7f 6b 23 e1 6c 85 32 c6 ab c8 38 fa cd 5e
+                a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
+                61 00",
+            ),
+            (
+                Frame::WindowUpdate {
+                    channel: Channel::Disco,
+                    credit: 65536,
+                },
+                "18 01 00 01 00 00",
+            ),
+            (
+                Frame::SendCompressedPacket {
+                    dst_key: client_key.public(),
+                    compression: PayloadCompression::Lz4,
+                    packet: "Hi".into(),
+                },
+                "19 19 7f 6b 23 e1 6c 85 32 c6 ab c8 38 fa cd 5e
+                a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
+                61 00 48 69",
+            ),
+            (
+                Frame::RecvCompressedPacket {
+                    src_key: client_key.public(),
+                    compression: PayloadCompression::Zstd,
+                    packet: "Hi".into(),
+                },
+                "1a 19 7f 6b 23 e1 6c 85 32 c6 ab c8 38 fa cd 5e
+                a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
+                61 01 48 69",
+            ),
+            (
+                Frame::UnknownPeer {
+                    dst_key: client_key.public(),
+                },
+                "1b 19 7f 6b 23 e1 6c 85 32 c6 ab c8 38 fa cd 5e
+                a7 89 be 0c 76 b2 92 03 34 03 9b fa 8b 3d 36 8d
+                61",
+            ),
         ];
 
         for (frame, expected_hex) in frames {
-            let bytes = frame.encode_for_ws_msg();
+            let bytes = frame.encode_for_ws_msg(false);
             let stripped: Vec<u8> = expected_hex
                 .chars()
                 .filter_map(|s| {
@@ -743,6 +2376,69 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    #[cfg(feature = "server")]
+    fn test_mesh_key_proof() -> anyhow::Result<()> {
+        let mesh_key = MeshKey::generate();
+        let client = SecretKey::generate(rand::thread_rng()).public();
+        let other = SecretKey::generate(rand::thread_rng()).public();
+        let proof = mesh_key.proof(&client);
+        assert!(mesh_key.verify(&client, &proof));
+        assert!(!mesh_key.verify(&other, &proof));
+        assert!(!MeshKey::generate().verify(&client, &proof));
+
+        let hex = data_encoding::HEXLOWER.encode(&mesh_key.to_bytes());
+        let parsed: MeshKey = hex.parse()?;
+        assert_eq!(parsed.to_bytes(), mesh_key.to_bytes());
+        assert!("abcd".parse::<MeshKey>().is_err());
+        assert!(!format!("{mesh_key:?}").contains(&hex));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "server")]
+    fn test_key_rotation() {
+        let previous = SecretKey::generate(rand::thread_rng());
+        let new = SecretKey::generate(rand::thread_rng()).public();
+        let other = SecretKey::generate(rand::thread_rng()).public();
+        let rotation = KeyRotation::new(&previous, &new, 100);
+        assert!(rotation.verify(&new, 99));
+        assert!(!rotation.verify(&new, 100));
+        assert!(!rotation.verify(&other, 99));
+
+        let extended = KeyRotation {
+            expires: 200,
+            ..rotation
+        };
+        assert!(!extended.verify(&new, 99));
+
+        let to_self = KeyRotation::new(&previous, &previous.public(), 100);
+        assert!(!to_self.verify(&previous.public(), 99));
+    }
+
+    #[test]
+    fn test_fragment_header() -> anyhow::Result<()> {
+        let header = FragmentHeader {
+            id: 7,
+            index: 1,
+            count: 2,
+        };
+        let mut fragment = header.to_bytes().to_vec();
+        fragment.extend_from_slice(b"Hi");
+        let (decoded, data) = FragmentHeader::decode(fragment.into())?;
+        assert_eq!(decoded, header);
+        assert_eq!(&data[..], b"Hi");
+
+        assert!(FragmentHeader::decode(Bytes::from_static(&[0u8; 7])).is_err());
+        let out_of_range = FragmentHeader {
+            id: 7,
+            index: 2,
+            count: 2,
+        };
+        assert!(FragmentHeader::decode(Bytes::copy_from_slice(&out_of_range.to_bytes())).is_err());
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -767,6 +2463,12 @@ mod proptests {
         prop::collection::vec(any::<u8>(), 0..len).prop_map(Bytes::from)
     }
 
+    /// Generates a random fragment, including its header
+    fn fragment() -> impl Strategy<Value = Bytes> {
+        prop::collection::vec(any::<u8>(), FRAGMENT_HEADER_LEN..MAX_PACKET_SIZE)
+            .prop_map(Bytes::from)
+    }
+
     /// Generates a random valid frame
     fn frame() -> impl Strategy<Value = Frame> {
         let client_info = (secret_key()).prop_map(|secret_key| {
@@ -796,6 +2498,148 @@ mod proptests {
                 reconnect_in,
                 try_for,
             });
+        let error = prop_oneof![
+            Just(RejectReason::Banned),
+            Just(RejectReason::QuotaExceeded),
+            (any::<usize>(), any::<usize>())
+                .prop_map(|(min, max)| RejectReason::VersionUnsupported { min, max }),
+            ".{0,32}".prop_map(|reason| RejectReason::Unauthorized { reason }),
+            Just(RejectReason::TooManyClients),
+        ]
+        .prop_map(|reason| Frame::Error { reason });
+        let closing = Just(Frame::Closing);
+        let mesh_proof = prop::option::of(
+            (secret_key(), key()).prop_map(|(secret_key, key)| secret_key.sign(key.as_bytes())),
+        );
+        let key_rotation = prop::option::of(
+            (secret_key(), key(), any::<u64>())
+                .prop_map(|(previous, new, expires)| KeyRotation::new(&previous, &new, expires)),
+        );
+        let compression = || {
+            prop_oneof![
+                Just(PayloadCompression::Lz4),
+                Just(PayloadCompression::Zstd)
+            ]
+        };
+        let capabilities = (
+            any::<bool>(),
+            any::<bool>(),
+            any::<bool>(),
+            mesh_proof,
+            key_rotation,
+            any::<bool>(),
+            any::<bool>(),
+            prop::option::of(any::<[u8; 16]>().prop_map(SessionToken)),
+            prop::option::of((any::<u32>(), any::<u32>(), any::<u32>()).prop_map(
+                |(interval_ms, min_ms, max_ms)| KeepAliveInterval {
+                    interval_ms,
+                    min_ms,
+                    max_ms,
+                },
+            )),
+            prop::option::of(compression()),
+            any::<bool>(),
+            (
+                prop::option::of(any::<u64>()),
+                prop::option::of("[ -~]{0,64}"),
+                any::<bool>(),
+            ),
+        )
+            .prop_map(
+                |(
+                    frame_checksums,
+                    fragments,
+                    send_acks,
+                    mesh_proof,
+                    key_rotation,
+                    queue_status,
+                    sessions,
+                    session_token,
+                    keep_alive,
+                    compression,
+                    unknown_peers,
+                    (time, auth_token, peer_gone),
+                )| {
+                    Frame::Capabilities {
+                        capabilities: ClientCapabilities {
+                            frame_checksums,
+                            fragments,
+                            send_acks,
+                            mesh_proof,
+                            key_rotation,
+                            queue_status,
+                            sessions,
+                            session_token,
+                            keep_alive,
+                            compression,
+                            unknown_peers,
+                            time,
+                            auth_token,
+                            peer_gone,
+                        },
+                    }
+                },
+            );
+        let peer_present = key().prop_map(|node_id| Frame::PeerPresent { node_id });
+        let watch_conns = Just(Frame::WatchConns);
+        let forward_packet =
+            (key(), key(), any::<u8>(), data(64)).prop_map(|(src_key, dst_key, hops, packet)| {
+                Frame::ForwardPacket {
+                    src_key,
+                    dst_key,
+                    hops,
+                    packet,
+                }
+            });
+        let send_fragment = (key(), fragment())
+            .prop_map(|(dst_key, fragment)| Frame::SendFragment { dst_key, fragment });
+        let recv_fragment = (key(), fragment())
+            .prop_map(|(src_key, fragment)| Frame::RecvFragment { src_key, fragment });
+        let send_acked_packet =
+            (key(), any::<u32>(), data(36)).prop_map(|(dst_key, id, packet)| {
+                Frame::SendAckedPacket {
+                    dst_key,
+                    id,
+                    packet,
+                }
+            });
+        let send_ack = (
+            any::<u32>(),
+            prop_oneof![
+                Just(SendStatus::Queued),
+                Just(SendStatus::NodeUnknown),
+                Just(SendStatus::Dropped)
+            ],
+        )
+            .prop_map(|(id, status)| Frame::SendAck { id, status });
+        let send_queue_status = (key(), any::<bool>())
+            .prop_map(|(dst_key, ready)| Frame::SendQueueStatus { dst_key, ready });
+        let window_update = (
+            prop_oneof![
+                Just(Channel::Control),
+                Just(Channel::Disco),
+                Just(Channel::Data)
+            ],
+            any::<u32>(),
+        )
+            .prop_map(|(channel, credit)| Frame::WindowUpdate { channel, credit });
+        let send_compressed_packet =
+            (key(), compression(), data(33)).prop_map(|(dst_key, compression, packet)| {
+                Frame::SendCompressedPacket {
+                    dst_key,
+                    compression,
+                    packet,
+                }
+            });
+        let recv_compressed_packet =
+            (key(), compression(), data(33)).prop_map(|(src_key, compression, packet)| {
+                Frame::RecvCompressedPacket {
+                    src_key,
+                    compression,
+                    packet,
+                }
+            });
+        let unknown_peer = key().prop_map(|dst_key| Frame::UnknownPeer { dst_key });
         prop_oneof![
             client_info,
             send_packet,
@@ -807,6 +2651,21 @@ mod proptests {
             pong,
             health,
             restarting,
+            error,
+            closing,
+            capabilities,
+            send_fragment,
+            recv_fragment,
+            send_acked_packet,
+            send_ack,
+            send_queue_status,
+            peer_present,
+            watch_conns,
+            forward_packet,
+            window_update,
+            send_compressed_packet,
+            recv_compressed_packet,
+            unknown_peer,
         ]
     }
 
@@ -818,11 +2677,26 @@ mod proptests {
                 | FrameType::Ping
                 | FrameType::Pong
                 | FrameType::Restarting
-                | FrameType::PeerGone => true,
+                | FrameType::PeerGone
+                | FrameType::PeerPresent
+                | FrameType::WatchConns
+                | FrameType::Closing
+                | FrameType::SendAck
+                | FrameType::SendQueueStatus
+                | FrameType::WindowUpdate
+                | FrameType::UnknownPeer => true,
                 FrameType::ClientInfo
                 | FrameType::Health
                 | FrameType::SendPacket
                 | FrameType::RecvPacket
+                | FrameType::Error
+                | FrameType::Capabilities
+                | FrameType::SendFragment
+                | FrameType::RecvFragment
+                | FrameType::SendAckedPacket
+                | FrameType::ForwardPacket
+                | FrameType::SendCompressedPacket
+                | FrameType::RecvCompressedPacket
                 | FrameType::Unknown => false,
             }
         }
@@ -852,9 +2726,75 @@ mod proptests {
 
         #[test]
         fn frame_ws_roundtrip(frame in frame()) {
-            let encoded = frame.clone().encode_for_ws_msg();
-            let decoded = Frame::decode_from_ws_msg(encoded, &KeyCache::test()).unwrap();
+            let encoded = frame.clone().encode_for_ws_msg(false);
+            let decoded =
+                Frame::decode_from_ws_msg(encoded, &KeyCache::test(), &mut false).unwrap();
+            prop_assert_eq!(frame, decoded);
+        }
+
+        #[test]
+        fn checksummed_frame_roundtrip(frame in frame()) {
+            let mut buf = BytesMut::new();
+            let mut codec = RelayCodec::test();
+            codec.enable_checksums();
+            codec.encode(frame.clone(), &mut buf).unwrap();
+            let mut decoder = RelayCodec::test();
+            let decoded = decoder.decode(&mut buf).unwrap().unwrap();
+            prop_assert_eq!(frame.clone(), decoded);
+            prop_assert!(decoder.checksums());
+
+            let encoded = frame.clone().encode_for_ws_msg(true);
+            let mut peer_checksums = false;
+            let decoded =
+                Frame::decode_from_ws_msg(encoded, &KeyCache::test(), &mut peer_checksums).unwrap();
             prop_assert_eq!(frame, decoded);
+            prop_assert!(peer_checksums);
+        }
+
+        // Test that frames without checksums are rejected once the peer sent checksums
+        #[test]
+        fn unchecksummed_frame_after_checksums(frame in frame()) {
+            let mut buf = BytesMut::new();
+            let mut codec = RelayCodec::test();
+            codec.enable_checksums();
+            codec.encode(frame.clone(), &mut buf).unwrap();
+            RelayCodec::test().encode(frame.clone(), &mut buf).unwrap();
+            let mut decoder = RelayCodec::test();
+            prop_assert_eq!(decoder.decode(&mut buf).unwrap(), Some(frame.clone()));
+            let err = decoder.decode(&mut buf).unwrap_err();
+            prop_assert!(err.to_string().contains("unchecksummed frame"), "{err:#}");
+
+            let mut peer_checksums = false;
+            let encoded = frame.clone().encode_for_ws_msg(true);
+            Frame::decode_from_ws_msg(encoded, &KeyCache::test(), &mut peer_checksums).unwrap();
+            let encoded = frame.encode_for_ws_msg(false);
+            let err = Frame::decode_from_ws_msg(encoded, &KeyCache::test(), &mut peer_checksums)
+                .unwrap_err();
+            prop_assert!(err.to_string().contains("unchecksummed frame"), "{err:#}");
+        }
+
+        // Test that any single bit flip after the header is detected
+        #[test]
+        fn checksummed_frame_corruption(
+            frame in frame(),
+            index in any::<prop::sample::Index>(),
+            bit in 0u8..8,
+        ) {
+            let mut buf = BytesMut::new();
+            let mut codec = RelayCodec::test();
+            codec.enable_checksums();
+            codec.encode(frame.clone(), &mut buf).unwrap();
+            let i = HEADER_LEN + index.index(buf.len() - HEADER_LEN);
+            buf[i] ^= 1 << bit;
+            let err = codec.decode(&mut buf).unwrap_err();
+            prop_assert!(err.to_string().contains("checksum mismatch"), "{err:#}");
+
+            let mut encoded = frame.encode_for_ws_msg(true);
+            let i = 1 + index.index(encoded.len() - 1);
+            encoded[i] ^= 1 << bit;
+            let err =
+                Frame::decode_from_ws_msg(encoded, &KeyCache::test(), &mut false).unwrap_err();
+            prop_assert!(err.to_string().contains("checksum mismatch"), "{err:#}");
         }
 
         // Test that typical invalid frames will result in an error
@@ -0,0 +1,467 @@
+//! WebTransport over HTTP/3, as far as needed for relaying.
+//!
+//! A session is established with an extended `CONNECT` request (RFC 9220) on a QUIC
+//! connection negotiating [`H3_ALPN`], following draft-ietf-webtrans-http3-02 which is what
+//! browsers implement.  The relay protocol then runs over a single bidirectional stream of
+//! the session with the usual relay framing.  Datagrams, server push and the QPACK dynamic
+//! table are not used, leaving a small part of HTTP/3: the control streams and their
+//! settings, the `CONNECT` request with its response and the stream signal of WebTransport
+//! streams.
+//!
+//! Each QUIC connection carries at most one session.
+//!
+//! [`H3_ALPN`]: crate::http::H3_ALPN
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::{bail, ensure, Context as _, Result};
+use quinn::{RecvStream, SendStream, VarInt};
+use quinn_proto::coding::Codec;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    sync::oneshot,
+    task::JoinSet,
+};
+use tokio_util::task::AbortOnDropHandle;
+use tracing::{debug, trace, Instrument};
+
+pub(crate) mod qpack;
+
+/// HTTP/3 frame types, RFC 9114 Section 7.2.
+const FRAME_HEADERS: u64 = 0x01;
+const FRAME_SETTINGS: u64 = 0x04;
+/// The highest frame type defined by RFC 9114, higher types are skipped when unknown.
+const FRAME_MAX_KNOWN: u64 = 0x0d;
+
+/// HTTP/3 unidirectional stream types, RFC 9114 Section 6.2 and RFC 9204 Section 4.2.
+const STREAM_CONTROL: u64 = 0x00;
+const STREAM_QPACK_ENCODER: u64 = 0x02;
+const STREAM_QPACK_DECODER: u64 = 0x03;
+
+/// The signal value starting a bidirectional WebTransport stream, followed by the session ID.
+pub(crate) const WEBTRANSPORT_STREAM: u64 = 0x41;
+
+/// HTTP/3 settings, RFC 9114 Section 7.2.4.1 and the drafts introducing them.
+const SETTINGS_QPACK_MAX_TABLE_CAPACITY: u64 = 0x01;
+const SETTINGS_QPACK_BLOCKED_STREAMS: u64 = 0x07;
+const SETTINGS_ENABLE_CONNECT_PROTOCOL: u64 = 0x08;
+const SETTINGS_H3_DATAGRAM: u64 = 0x33;
+const SETTINGS_ENABLE_WEBTRANSPORT: u64 = 0x2b60_3742;
+const SETTINGS_WEBTRANSPORT_MAX_SESSIONS: u64 = 0xc671_706a;
+
+/// HTTP/3 error codes, RFC 9114 Section 8.1.
+pub(crate) const H3_NO_ERROR: VarInt = VarInt::from_u32(0x100);
+const H3_STREAM_CREATION_ERROR: VarInt = VarInt::from_u32(0x103);
+const H3_CLOSED_CRITICAL_STREAM: VarInt = VarInt::from_u32(0x104);
+#[cfg(feature = "server")]
+pub(crate) const H3_REQUEST_REJECTED: VarInt = VarInt::from_u32(0x10b);
+
+/// The longest frame accepted, the frames read here only carry headers and settings.
+const MAX_FRAME_LEN: u64 = 16 * 1024;
+
+/// The header of requests offering draft-02 of WebTransport over HTTP/3.
+const DRAFT02_REQUEST_HEADER: &str = "sec-webtransport-http3-draft02";
+/// The header of responses accepting draft-02 of WebTransport over HTTP/3.
+#[cfg(feature = "server")]
+pub(crate) const DRAFT02_RESPONSE_HEADER: (&str, &str) =
+    ("sec-webtransport-http3-draft", "draft02");
+
+/// The HTTP/3 settings of a peer relevant for WebTransport.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Settings {
+    /// Whether extended `CONNECT` requests are supported.
+    pub(crate) enable_connect_protocol: bool,
+    /// Whether WebTransport sessions are supported.
+    pub(crate) enable_webtransport: bool,
+}
+
+impl Settings {
+    fn decode(mut payload: &[u8]) -> Result<Self> {
+        let mut settings = Self::default();
+        while !payload.is_empty() {
+            let id = VarInt::decode(&mut payload).context("truncated settings")?;
+            let value = VarInt::decode(&mut payload).context("truncated settings")?;
+            match id.into_inner() {
+                SETTINGS_ENABLE_CONNECT_PROTOCOL => {
+                    settings.enable_connect_protocol = value.into_inner() == 1
+                }
+                // Newer drafts announce the number of sessions instead.
+                SETTINGS_ENABLE_WEBTRANSPORT | SETTINGS_WEBTRANSPORT_MAX_SESSIONS => {
+                    settings.enable_webtransport |= value.into_inner() > 0
+                }
+                _ => {}
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Returns whether WebTransport sessions can be established with the peer.
+    fn supports_webtransport(&self) -> bool {
+        self.enable_connect_protocol && self.enable_webtransport
+    }
+}
+
+/// The settings frame sent on our control stream.
+fn settings_frame() -> Vec<u8> {
+    let mut payload = Vec::new();
+    for (id, value) in [
+        (SETTINGS_QPACK_MAX_TABLE_CAPACITY, 0),
+        (SETTINGS_QPACK_BLOCKED_STREAMS, 0),
+        (SETTINGS_ENABLE_CONNECT_PROTOCOL, 1),
+        (SETTINGS_H3_DATAGRAM, 1),
+        (SETTINGS_ENABLE_WEBTRANSPORT, 1),
+        (SETTINGS_WEBTRANSPORT_MAX_SESSIONS, 1),
+    ] {
+        encode_varint(&mut payload, id);
+        encode_varint(&mut payload, value);
+    }
+    let mut buf = Vec::new();
+    encode_varint(&mut buf, STREAM_CONTROL);
+    encode_frame(&mut buf, FRAME_SETTINGS, &payload);
+    buf
+}
+
+/// Encodes a `HEADERS` frame with the fields.
+pub(crate) fn headers_frame(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut section = Vec::new();
+    qpack::encode(fields, &mut section);
+    let mut buf = Vec::new();
+    encode_frame(&mut buf, FRAME_HEADERS, &section);
+    buf
+}
+
+/// Returns the value of a field, if present.
+pub(crate) fn field<'a>(fields: &'a [qpack::Field], name: &str) -> Option<&'a [u8]> {
+    fields
+        .iter()
+        .find(|(n, _)| n == name.as_bytes())
+        .map(|(_, value)| value.as_slice())
+}
+
+fn encode_varint(buf: &mut Vec<u8>, value: u64) {
+    VarInt::from_u64(value)
+        .expect("values are below 2^62")
+        .encode(buf);
+}
+
+fn encode_frame(buf: &mut Vec<u8>, frame_type: u64, payload: &[u8]) {
+    encode_varint(buf, frame_type);
+    encode_varint(buf, payload.len() as u64);
+    buf.extend_from_slice(payload);
+}
+
+/// Reads a QUIC variable-length integer, RFC 9000 Section 16.
+pub(crate) async fn read_varint(recv: &mut (impl AsyncRead + Unpin)) -> std::io::Result<u64> {
+    let first = recv.read_u8().await?;
+    let len = 1 << (first >> 6);
+    let mut buf = [0u8; 8];
+    buf[8 - len] = first & 0x3f;
+    recv.read_exact(&mut buf[9 - len..]).await?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// Reads the payload of the next frame of `frame_type`, skipping unknown frames.
+///
+/// The type of the first frame is passed as `first_type` if it was already read.
+pub(crate) async fn read_frame(
+    recv: &mut (impl AsyncRead + Unpin),
+    mut first_type: Option<u64>,
+    frame_type: u64,
+) -> Result<Vec<u8>> {
+    loop {
+        let ty = match first_type.take() {
+            Some(ty) => ty,
+            None => read_varint(recv).await?,
+        };
+        let len = read_varint(recv).await?;
+        ensure!(
+            len <= MAX_FRAME_LEN,
+            "frame of type {ty:#x} too long: {len}"
+        );
+        let mut payload = vec![0u8; len as usize];
+        recv.read_exact(&mut payload).await?;
+        if ty == frame_type {
+            return Ok(payload);
+        }
+        // Reserved and extension frames are ignored, RFC 9114 Section 9.
+        ensure!(ty > FRAME_MAX_KNOWN, "unexpected frame of type {ty:#x}");
+        trace!(ty, len, "skipping unknown frame");
+    }
+}
+
+/// Reads the `HEADERS` frame starting a request or response stream.
+pub(crate) async fn read_headers(
+    recv: &mut RecvStream,
+    first_type: Option<u64>,
+) -> Result<Vec<qpack::Field>> {
+    let section = read_frame(recv, first_type, FRAME_HEADERS).await?;
+    qpack::decode(&section)
+}
+
+/// Runs the HTTP/3 control streams of a connection.
+///
+/// Opens our control stream announcing our settings and serves the unidirectional streams
+/// of the peer.  The settings of the peer are sent on `settings` once received.  Only
+/// completes once the connection fails, closing it if a critical stream failed.
+pub(crate) async fn run_control_streams(
+    conn: quinn::Connection,
+    settings: oneshot::Sender<Settings>,
+) -> Result<()> {
+    let res = serve_uni_streams(&conn, settings).await;
+    if let Err(ref err) = res {
+        debug!("closing connection: {err:#}");
+        conn.close(H3_CLOSED_CRITICAL_STREAM, b"critical stream failed");
+    }
+    res
+}
+
+async fn serve_uni_streams(
+    conn: &quinn::Connection,
+    settings: oneshot::Sender<Settings>,
+) -> Result<()> {
+    // Our control stream must stay open for as long as the connection.
+    let mut control = conn.open_uni().await?;
+    control.write_all(&settings_frame()).await?;
+
+    let mut settings = Some(settings);
+    let mut streams = JoinSet::new();
+    loop {
+        tokio::select! {
+            Some(res) = streams.join_next() => {
+                // Only the control stream of the peer fails the connection.
+                res??;
+            }
+            res = conn.accept_uni() => {
+                let mut recv = res?;
+                let ty = read_varint(&mut recv).await?;
+                match ty {
+                    STREAM_CONTROL => {
+                        let settings = settings.take().context("second control stream")?;
+                        streams.spawn(
+                            run_peer_control_stream(recv, settings)
+                                .instrument(tracing::Span::current()),
+                        );
+                    }
+                    STREAM_QPACK_ENCODER | STREAM_QPACK_DECODER => {
+                        // Without a dynamic table these carry nothing of interest, but they
+                        // must not be closed.
+                        streams.spawn(async move {
+                            tokio::io::copy(&mut recv, &mut tokio::io::sink()).await.ok();
+                            Ok(())
+                        });
+                    }
+                    _ => {
+                        trace!(ty, "rejecting unidirectional stream");
+                        recv.stop(H3_STREAM_CREATION_ERROR).ok();
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn run_peer_control_stream(
+    mut recv: RecvStream,
+    settings: oneshot::Sender<Settings>,
+) -> Result<()> {
+    let payload = read_frame(&mut recv, None, FRAME_SETTINGS)
+        .await
+        .context("control stream without settings")?;
+    let peer_settings = Settings::decode(&payload)?;
+    debug!(?peer_settings, "received settings");
+    settings.send(peer_settings).ok();
+    // Later frames, e.g. `GOAWAY`, are of no interest to a single session.
+    tokio::io::copy(&mut recv, &mut tokio::io::sink()).await?;
+    bail!("control stream closed")
+}
+
+/// Establishes a WebTransport session on a connection and opens the relay stream.
+///
+/// The connection must have negotiated [`H3_ALPN`], `authority` is the `:authority` of the
+/// `CONNECT` request.
+///
+/// [`H3_ALPN`]: crate::http::H3_ALPN
+pub(crate) async fn connect(
+    conn: quinn::Connection,
+    authority: &str,
+) -> Result<WebTransportStream> {
+    use crate::http::{Protocol, RELAY_PATH};
+
+    let (settings_tx, settings_rx) = oneshot::channel();
+    let control_streams = conn.clone();
+    let driver = AbortOnDropHandle::new(tokio::task::spawn(
+        async move {
+            run_control_streams(control_streams, settings_tx).await.ok();
+        }
+        .instrument(tracing::Span::current()),
+    ));
+    let settings = settings_rx
+        .await
+        .context("connection failed before receiving the server settings")?;
+    ensure!(
+        settings.supports_webtransport(),
+        "the server does not support WebTransport: {settings:?}"
+    );
+
+    let (mut send, mut recv) = conn.open_bi().await?;
+    let session_id = u64::from(send.id());
+    send.write_all(&headers_frame(&[
+        (":method", "CONNECT"),
+        (":scheme", "https"),
+        (":authority", authority),
+        (":path", RELAY_PATH),
+        (":protocol", Protocol::WebTransport.upgrade_header()),
+        (DRAFT02_REQUEST_HEADER, "1"),
+    ]))
+    .await?;
+    let fields = read_headers(&mut recv, None).await?;
+    let status = field(&fields, ":status").context("response without status")?;
+    ensure!(
+        status == b"200",
+        "WebTransport session rejected with status {}",
+        String::from_utf8_lossy(status)
+    );
+    debug!(session_id, "WebTransport session established");
+
+    let (mut relay_send, relay_recv) = conn.open_bi().await?;
+    let mut signal = Vec::new();
+    encode_varint(&mut signal, WEBTRANSPORT_STREAM);
+    encode_varint(&mut signal, session_id);
+    relay_send.write_all(&signal).await?;
+    Ok(WebTransportStream {
+        send: relay_send,
+        recv: relay_recv,
+        _session: Some(ClientSession {
+            conn,
+            _connect_stream: (send, recv),
+            _driver: driver,
+        }),
+    })
+}
+
+/// The bidirectional WebTransport stream of a session carrying the relay protocol.
+#[derive(Debug)]
+pub(crate) struct WebTransportStream {
+    send: SendStream,
+    recv: RecvStream,
+    /// The session of a client, closed along with the stream.
+    _session: Option<ClientSession>,
+}
+
+impl WebTransportStream {
+    /// Wraps a stream accepted by the server, after having read its stream signal.
+    ///
+    /// The server keeps serving the session and its connection, they are not closed when
+    /// the stream is dropped.
+    #[cfg(feature = "server")]
+    pub(crate) fn accepted(send: SendStream, recv: RecvStream) -> Self {
+        Self {
+            send,
+            recv,
+            _session: None,
+        }
+    }
+}
+
+/// The session of a client, owning everything besides the relay stream.
+#[derive(Debug)]
+struct ClientSession {
+    conn: quinn::Connection,
+    /// The stream of the `CONNECT` request, the session ends once it is closed.
+    _connect_stream: (SendStream, RecvStream),
+    /// The task running the control streams.
+    _driver: AbortOnDropHandle<()>,
+}
+
+impl Drop for ClientSession {
+    fn drop(&mut self) {
+        self.conn.close(H3_NO_ERROR, b"");
+    }
+}
+
+impl AsyncRead for WebTransportStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for WebTransportStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        // Not the inherent method, which fails with a `WriteError`.
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_varint() -> Result<()> {
+        for value in [0, 37, 63, 64, 15293, 16383, 16384, 494878333, (1 << 62) - 1] {
+            let mut buf = Vec::new();
+            encode_varint(&mut buf, value);
+            let mut reader = buf.as_slice();
+            assert_eq!(read_varint(&mut reader).await?, value);
+            assert!(reader.is_empty());
+        }
+        // RFC 9000 Appendix A.1.
+        let mut reader = &[0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8, 0x8c][..];
+        assert_eq!(read_varint(&mut reader).await?, 151288809941952652);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_settings() -> Result<()> {
+        let frame = settings_frame();
+        let mut reader = &frame[..];
+        assert_eq!(read_varint(&mut reader).await?, STREAM_CONTROL);
+        let payload = read_frame(&mut reader, None, FRAME_SETTINGS).await?;
+        let settings = Settings::decode(&payload)?;
+        assert!(settings.supports_webtransport());
+
+        // Only connect protocol, from a plain HTTP/3 server.
+        let mut payload = Vec::new();
+        encode_varint(&mut payload, SETTINGS_ENABLE_CONNECT_PROTOCOL);
+        encode_varint(&mut payload, 1);
+        assert!(!Settings::decode(&payload)?.supports_webtransport());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_skips_unknown() -> Result<()> {
+        let mut buf = Vec::new();
+        // A reserved frame type, 0x1f * N + 0x21.
+        encode_frame(&mut buf, 0x21, b"grease");
+        buf.extend_from_slice(&headers_frame(&[(":status", "200")]));
+        let fields = qpack::decode(&read_frame(&mut buf.as_slice(), None, FRAME_HEADERS).await?)?;
+        assert_eq!(field(&fields, ":status"), Some(&b"200"[..]));
+
+        // Known frames of the wrong type are an error.
+        let mut buf = Vec::new();
+        encode_frame(&mut buf, 0x00, b"data");
+        assert!(read_frame(&mut buf.as_slice(), None, FRAME_HEADERS)
+            .await
+            .is_err());
+        Ok(())
+    }
+}
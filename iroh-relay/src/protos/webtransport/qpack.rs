@@ -0,0 +1,419 @@
+//! QPACK coding of HTTP/3 field sections (RFC 9204), without a dynamic table.
+//!
+//! Both sides of a WebTransport session announce a dynamic table capacity of zero, so
+//! field sections only reference the static table or carry literals.  Literals are
+//! encoded without Huffman coding, but Huffman coded literals of the peer are decoded.
+
+use std::sync::OnceLock;
+
+use anyhow::{bail, ensure, Context, Result};
+
+/// A decoded field, its name and value.
+pub(crate) type Field = (Vec<u8>, Vec<u8>);
+
+/// The QPACK static table, RFC 9204 Appendix A.
+const STATIC_TABLE: [(&str, &str); 99] = [
+    (":authority", ""),
+    (":path", "/"),
+    ("age", "0"),
+    ("content-disposition", ""),
+    ("content-length", "0"),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("referer", ""),
+    ("set-cookie", ""),
+    (":method", "CONNECT"),
+    (":method", "DELETE"),
+    (":method", "GET"),
+    (":method", "HEAD"),
+    (":method", "OPTIONS"),
+    (":method", "POST"),
+    (":method", "PUT"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "103"),
+    (":status", "200"),
+    (":status", "304"),
+    (":status", "404"),
+    (":status", "503"),
+    ("accept", "*/*"),
+    ("accept", "application/dns-message"),
+    ("accept-encoding", "gzip, deflate, br"),
+    ("accept-ranges", "bytes"),
+    ("access-control-allow-headers", "cache-control"),
+    ("access-control-allow-headers", "content-type"),
+    ("access-control-allow-origin", "*"),
+    ("cache-control", "max-age=0"),
+    ("cache-control", "max-age=2592000"),
+    ("cache-control", "max-age=604800"),
+    ("cache-control", "no-cache"),
+    ("cache-control", "no-store"),
+    ("cache-control", "public, max-age=31536000"),
+    ("content-encoding", "br"),
+    ("content-encoding", "gzip"),
+    ("content-type", "application/dns-message"),
+    ("content-type", "application/javascript"),
+    ("content-type", "application/json"),
+    ("content-type", "application/x-www-form-urlencoded"),
+    ("content-type", "image/gif"),
+    ("content-type", "image/jpeg"),
+    ("content-type", "image/png"),
+    ("content-type", "text/css"),
+    ("content-type", "text/html; charset=utf-8"),
+    ("content-type", "text/plain"),
+    ("content-type", "text/plain;charset=utf-8"),
+    ("range", "bytes=0-"),
+    ("strict-transport-security", "max-age=31536000"),
+    (
+        "strict-transport-security",
+        "max-age=31536000; includesubdomains",
+    ),
+    (
+        "strict-transport-security",
+        "max-age=31536000; includesubdomains; preload",
+    ),
+    ("vary", "accept-encoding"),
+    ("vary", "origin"),
+    ("x-content-type-options", "nosniff"),
+    ("x-xss-protection", "1; mode=block"),
+    (":status", "100"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "302"),
+    (":status", "400"),
+    (":status", "403"),
+    (":status", "421"),
+    (":status", "425"),
+    (":status", "500"),
+    ("accept-language", ""),
+    ("access-control-allow-credentials", "FALSE"),
+    ("access-control-allow-credentials", "TRUE"),
+    ("access-control-allow-headers", "*"),
+    ("access-control-allow-methods", "get"),
+    ("access-control-allow-methods", "get, post, options"),
+    ("access-control-allow-methods", "options"),
+    ("access-control-expose-headers", "content-length"),
+    ("access-control-request-headers", "content-type"),
+    ("access-control-request-method", "get"),
+    ("access-control-request-method", "post"),
+    ("alt-svc", "clear"),
+    ("authorization", ""),
+    (
+        "content-security-policy",
+        "script-src 'none'; object-src 'none'; base-uri 'none'",
+    ),
+    ("early-data", "1"),
+    ("expect-ct", ""),
+    ("forwarded", ""),
+    ("if-range", ""),
+    ("origin", ""),
+    ("purpose", "prefetch"),
+    ("server", ""),
+    ("timing-allow-origin", "*"),
+    ("upgrade-insecure-requests", "1"),
+    ("user-agent", ""),
+    ("x-forwarded-for", ""),
+    ("x-frame-options", "deny"),
+    ("x-frame-options", "sameorigin"),
+];
+
+/// The lengths of the Huffman codes of the 256 octets and EOS, RFC 7541 Appendix B.
+///
+/// The code is canonical, so the codes follow from their lengths.
+const HUFFMAN_CODE_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 30, 28,
+    28, 28, 28, 28, 28, 28, 28, 28, 6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, 5, 5,
+    5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10, 13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6, 15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6,
+    6, 5, 6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28, 20, 22, 20, 20, 22, 22, 22, 23, 22,
+    23, 23, 23, 23, 23, 24, 23, 24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24, 22,
+    21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, 21, 21, 22, 21, 23, 22, 23, 23, 20,
+    22, 22, 22, 23, 22, 22, 23, 26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, 19,
+    21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27, 20, 24, 20, 21, 22, 21, 21, 23, 22,
+    22, 25, 25, 24, 24, 26, 23, 26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26, 30,
+];
+
+/// The longest Huffman code.
+const HUFFMAN_MAX_LEN: usize = 30;
+
+/// The symbol marking the end of a Huffman coded string, which must not be encoded.
+const HUFFMAN_EOS: u16 = 256;
+
+/// Encodes a field section.
+///
+/// Fields of the static table are referenced, all others are encoded as literals.
+pub(crate) fn encode(fields: &[(&str, &str)], buf: &mut Vec<u8>) {
+    // Required Insert Count and Delta Base, there is no dynamic table.
+    buf.extend_from_slice(&[0, 0]);
+    for &(name, value) in fields {
+        if let Some(index) = STATIC_TABLE
+            .iter()
+            .position(|&entry| entry == (name, value))
+        {
+            // Indexed field line, static table.
+            encode_int(buf, 6, 0b1100_0000, index as u64);
+        } else if let Some(index) = STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+            // Literal field line with name reference, static table.
+            encode_int(buf, 4, 0b0101_0000, index as u64);
+            encode_string(buf, 7, 0, value.as_bytes());
+        } else {
+            // Literal field line with literal name.
+            encode_string(buf, 3, 0b0010_0000, name.as_bytes());
+            encode_string(buf, 7, 0, value.as_bytes());
+        }
+    }
+}
+
+/// Decodes a field section.
+///
+/// Fails for sections referencing the dynamic table.
+pub(crate) fn decode(mut buf: &[u8]) -> Result<Vec<Field>> {
+    let buf = &mut buf;
+    let required_insert_count = decode_int(buf, 8)?;
+    ensure!(
+        required_insert_count == 0,
+        "field section references the dynamic table"
+    );
+    // The base is only used for dynamic table references.
+    decode_int(buf, 7)?;
+    let mut fields = Vec::new();
+    while let Some(&first) = buf.first() {
+        let field = if first & 0b1000_0000 != 0 {
+            ensure!(
+                first & 0b0100_0000 != 0,
+                "indexed field of the dynamic table"
+            );
+            let (name, value) = static_entry(decode_int(buf, 6)?)?;
+            (name.as_bytes().to_vec(), value.as_bytes().to_vec())
+        } else if first & 0b0100_0000 != 0 {
+            ensure!(first & 0b0001_0000 != 0, "field name of the dynamic table");
+            let (name, _) = static_entry(decode_int(buf, 4)?)?;
+            (name.as_bytes().to_vec(), decode_string(buf, 7)?)
+        } else if first & 0b0010_0000 != 0 {
+            let name = decode_string(buf, 3)?;
+            (name, decode_string(buf, 7)?)
+        } else {
+            bail!("field with a post-base index of the dynamic table");
+        };
+        fields.push(field);
+    }
+    Ok(fields)
+}
+
+fn static_entry(index: u64) -> Result<(&'static str, &'static str)> {
+    usize::try_from(index)
+        .ok()
+        .and_then(|index| STATIC_TABLE.get(index))
+        .copied()
+        .with_context(|| format!("invalid static table index {index}"))
+}
+
+/// Encodes an integer with an N-bit prefix, RFC 7541 Section 5.1.
+///
+/// The `flags` are set in the bits of the first byte above the prefix.
+fn encode_int(buf: &mut Vec<u8>, prefix_bits: u8, flags: u8, value: u64) {
+    let max = (1u64 << prefix_bits) - 1;
+    if value < max {
+        buf.push(flags | value as u8);
+        return;
+    }
+    buf.push(flags | max as u8);
+    let mut value = value - max;
+    while value >= 0x80 {
+        buf.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Decodes an integer with an N-bit prefix, ignoring the bits above the prefix.
+fn decode_int(buf: &mut &[u8], prefix_bits: u8) -> Result<u64> {
+    let max = (1u64 << prefix_bits) - 1;
+    let mut value = u64::from(take(buf, 1)?[0]) & max;
+    if value < max {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let byte = take(buf, 1)?[0];
+        ensure!(shift <= 56, "integer overflow");
+        value += u64::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+/// Encodes a string literal without Huffman coding, its length with an N-bit prefix.
+fn encode_string(buf: &mut Vec<u8>, prefix_bits: u8, flags: u8, s: &[u8]) {
+    encode_int(buf, prefix_bits, flags, s.len() as u64);
+    buf.extend_from_slice(s);
+}
+
+/// Decodes a string literal with an N-bit length prefix, preceded by the Huffman flag.
+fn decode_string(buf: &mut &[u8], prefix_bits: u8) -> Result<Vec<u8>> {
+    let huffman = buf.first().context("truncated field section")? & (1 << prefix_bits) != 0;
+    let len = usize::try_from(decode_int(buf, prefix_bits)?)?;
+    let s = take(buf, len)?;
+    match huffman {
+        true => huffman_decode(s),
+        false => Ok(s.to_vec()),
+    }
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    ensure!(buf.len() >= len, "truncated field section");
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Ok(head)
+}
+
+/// The canonical Huffman code of [`HUFFMAN_CODE_LENGTHS`], ordered for decoding.
+struct Huffman {
+    /// The first code of each length.
+    first_code: [u32; HUFFMAN_MAX_LEN + 1],
+    /// The number of codes of each length.
+    count: [u32; HUFFMAN_MAX_LEN + 1],
+    /// The index into `symbols` of the first code of each length.
+    offset: [usize; HUFFMAN_MAX_LEN + 1],
+    /// The symbols ordered by code.
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn get() -> &'static Self {
+        static HUFFMAN: OnceLock<Huffman> = OnceLock::new();
+        HUFFMAN.get_or_init(|| {
+            let mut symbols: Vec<u16> = (0..=HUFFMAN_EOS).collect();
+            symbols.sort_by_key(|&symbol| (HUFFMAN_CODE_LENGTHS[usize::from(symbol)], symbol));
+            let mut count = [0; HUFFMAN_MAX_LEN + 1];
+            for &len in &HUFFMAN_CODE_LENGTHS {
+                count[usize::from(len)] += 1;
+            }
+            let mut first_code = [0; HUFFMAN_MAX_LEN + 1];
+            let mut offset = [0; HUFFMAN_MAX_LEN + 1];
+            let mut code = 0;
+            let mut index = 0;
+            for len in 1..=HUFFMAN_MAX_LEN {
+                first_code[len] = code;
+                offset[len] = index;
+                code = (code + count[len]) << 1;
+                index += count[len] as usize;
+            }
+            Self {
+                first_code,
+                count,
+                offset,
+                symbols,
+            }
+        })
+    }
+}
+
+/// Decodes a Huffman coded string, RFC 7541 Section 5.2.
+fn huffman_decode(s: &[u8]) -> Result<Vec<u8>> {
+    let huffman = Huffman::get();
+    let mut decoded = Vec::with_capacity(s.len() * 8 / 5);
+    let mut code = 0u32;
+    let mut len = 0;
+    for byte in s {
+        for bit in (0..8).rev() {
+            code = (code << 1) | u32::from(byte >> bit & 1);
+            len += 1;
+            let index = code.wrapping_sub(huffman.first_code[len]);
+            if index < huffman.count[len] {
+                let symbol = huffman.symbols[huffman.offset[len] + index as usize];
+                ensure!(symbol != HUFFMAN_EOS, "EOS in Huffman coded string");
+                decoded.push(symbol as u8);
+                code = 0;
+                len = 0;
+            } else {
+                ensure!(len < HUFFMAN_MAX_LEN, "invalid Huffman code");
+            }
+        }
+    }
+    // The padding is the most significant bits of EOS, which are all ones.
+    ensure!(len < 8 && code == (1 << len) - 1, "invalid Huffman padding");
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() -> Result<()> {
+        let fields = [
+            (":method", "CONNECT"),
+            (":protocol", "webtransport"),
+            (":path", "/relay"),
+            (":authority", "relay.example:443"),
+            ("sec-webtransport-http3-draft02", "1"),
+        ];
+        let mut buf = Vec::new();
+        encode(&fields, &mut buf);
+        // The method is fully indexed.
+        assert_eq!(&buf[..3], &[0, 0, 0b1100_0000 | 15]);
+        let decoded = decode(&buf)?;
+        let decoded: Vec<_> = decoded
+            .iter()
+            .map(|(name, value)| (std::str::from_utf8(name), std::str::from_utf8(value)))
+            .map(|(name, value)| (name.unwrap(), value.unwrap()))
+            .collect();
+        assert_eq!(decoded, fields);
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_integers() -> Result<()> {
+        let mut buf = Vec::new();
+        encode_int(&mut buf, 5, 0, 1337);
+        // RFC 7541 Appendix C.1.2.
+        assert_eq!(buf, [0b1_1111, 0b1001_1010, 0b0000_1010]);
+        assert_eq!(decode_int(&mut buf.as_slice(), 5)?, 1337);
+        Ok(())
+    }
+
+    #[test]
+    fn test_huffman() -> Result<()> {
+        // RFC 7541 Appendix C.4.1.
+        let coded = [
+            0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff,
+        ];
+        assert_eq!(huffman_decode(&coded)?, b"www.example.com");
+        // RFC 7541 Appendix C.4.2.
+        assert_eq!(
+            huffman_decode(&[0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf])?,
+            b"no-cache"
+        );
+
+        // A Huffman coded value with a name reference, as browsers send them.
+        let mut section = vec![0, 0, 0b0101_0000];
+        section.push(0x80 | coded.len() as u8);
+        section.extend_from_slice(&coded);
+        assert_eq!(
+            decode(&section)?,
+            [(b":authority".to_vec(), b"www.example.com".to_vec())]
+        );
+
+        // Padding longer than 7 bits and padding with zeros are invalid.
+        assert!(huffman_decode(&[0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf, 0xff]).is_err());
+        assert!(huffman_decode(&[0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbe]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dynamic_table_rejected() {
+        // Required Insert Count of one.
+        assert!(decode(&[1, 0]).is_err());
+        // Indexed field of the dynamic table.
+        assert!(decode(&[0, 0, 0b1000_0001]).is_err());
+    }
+}
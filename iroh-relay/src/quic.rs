@@ -24,6 +24,7 @@ pub(crate) mod server {
 
     pub struct QuicServer {
         bind_addr: SocketAddr,
+        endpoint: quinn::Endpoint,
         cancel: CancellationToken,
         handle: AbortOnDropHandle<()>,
     }
@@ -35,6 +36,7 @@ pub(crate) mod server {
         /// the server, in particular it allows gracefully shutting down the server.
         pub fn handle(&self) -> ServerHandle {
             ServerHandle {
+                endpoint: self.endpoint.clone(),
                 cancel_token: self.cancel.clone(),
             }
         }
@@ -65,24 +67,14 @@ pub(crate) mod server {
         /// If there is a panic during a connection, it will be propagated
         /// up here. Any other errors in a connection will be logged as a
         ///  warning.
-        pub(crate) fn spawn(mut quic_config: QuicConfig) -> Result<Self> {
-            quic_config.server_config.alpn_protocols =
-                vec![crate::quic::ALPN_QUIC_ADDR_DISC.to_vec()];
-            let server_config = QuicServerConfig::try_from(quic_config.server_config)?;
-            let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(server_config));
-            let transport_config =
-                Arc::get_mut(&mut server_config.transport).expect("not used yet");
-            transport_config
-                .max_concurrent_uni_streams(0_u8.into())
-                .max_concurrent_bidi_streams(0_u8.into())
-                // enable sending quic address discovery frames
-                .send_observed_address_reports(true);
-
+        pub(crate) fn spawn(quic_config: QuicConfig) -> Result<Self> {
+            let server_config = server_config(quic_config.server_config)?;
             let endpoint = quinn::Endpoint::server(server_config, quic_config.bind_addr)?;
             let bind_addr = endpoint.local_addr()?;
 
             info!(?bind_addr, "QUIC server listening on");
 
+            let endpoint_handle = endpoint.clone();
             let cancel = CancellationToken::new();
             let cancel_accept_loop = cancel.clone();
 
@@ -137,6 +129,7 @@ pub(crate) mod server {
             );
             Ok(Self {
                 bind_addr,
+                endpoint: endpoint_handle,
                 cancel,
                 handle: AbortOnDropHandle::new(task),
             })
@@ -153,11 +146,30 @@ pub(crate) mod server {
         }
     }
 
+    /// Builds the QUIC server config of the endpoint from the TLS server config.
+    ///
+    /// Fails if the TLS config does not support TLS 1.3.
+    pub(crate) fn server_config(
+        mut tls_config: rustls::ServerConfig,
+    ) -> Result<quinn::ServerConfig> {
+        tls_config.alpn_protocols = vec![crate::quic::ALPN_QUIC_ADDR_DISC.to_vec()];
+        let server_config = QuicServerConfig::try_from(tls_config)?;
+        let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(server_config));
+        let transport_config = Arc::get_mut(&mut server_config.transport).expect("not used yet");
+        transport_config
+            .max_concurrent_uni_streams(0_u8.into())
+            .max_concurrent_bidi_streams(0_u8.into())
+            // enable sending quic address discovery frames
+            .send_observed_address_reports(true);
+        Ok(server_config)
+    }
+
     /// A handle for the Server side of QUIC address discovery.
     ///
     /// This does not allow access to the task but can communicate with it.
     #[derive(Debug, Clone)]
     pub struct ServerHandle {
+        endpoint: quinn::Endpoint,
         cancel_token: CancellationToken,
     }
 
@@ -166,6 +178,11 @@ pub(crate) mod server {
         pub fn shutdown(&self) {
             self.cancel_token.cancel()
         }
+
+        /// Replaces the server config for the following connections, see [`server_config`].
+        pub(crate) fn reload_tls(&self, server_config: quinn::ServerConfig) {
+            self.endpoint.set_server_config(Some(server_config));
+        }
     }
 
     /// Handle the connection from the client.
@@ -2,17 +2,42 @@
 
 use std::{collections::BTreeMap, fmt, sync::Arc};
 
-use anyhow::{ensure, Result};
-use iroh_base::RelayUrl;
+use anyhow::{bail, ensure, Context, Result};
+use iroh_base::{NodeId, RelayUrl};
 use serde::{Deserialize, Serialize};
 
 use crate::defaults::{DEFAULT_RELAY_QUIC_PORT, DEFAULT_STUN_PORT};
 
 /// Configuration of all the relay servers that can be used.
-#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// Relay servers can be grouped into named [`RelayRegion`]s, e.g. those of one data center.
+/// The home relay is chosen by latency, but within a region the weights of its relays
+/// decide which one a node uses, see [`RelayMap::select`].
+///
+/// The map (de)serialises as a list of `nodes` outside of any region and a list of
+/// `regions`, e.g. in TOML:
+///
+/// ```toml
+/// [[nodes]]
+/// url = "https://relay.example.org"
+/// stun_only = false
+/// stun_port = 3478
+///
+/// [[regions]]
+/// name = "eu"
+/// stun_port = 3478
+/// relays = [
+///     { url = "https://eu1.relay.example.org", weight = 90 },
+///     { url = "https://eu2.relay.example.org", weight = 10 },
+/// ]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "RelayMapConfig", into = "RelayMapConfig")]
 pub struct RelayMap {
     /// A map of the different relay IDs to the [`RelayNode`] information
     nodes: Arc<BTreeMap<RelayUrl, Arc<RelayNode>>>,
+    /// The regions by name, their relays are part of `nodes` as well.
+    regions: Arc<BTreeMap<String, RelayRegion>>,
 }
 
 impl RelayMap {
@@ -25,6 +50,7 @@ impl RelayMap {
     pub fn empty() -> Self {
         Self {
             nodes: Default::default(),
+            regions: Default::default(),
         }
     }
 
@@ -74,6 +100,7 @@ impl RelayMap {
 
         RelayMap {
             nodes: Arc::new(nodes),
+            regions: Default::default(),
         }
     }
 
@@ -95,7 +122,158 @@ impl RelayMap {
             ensure!(!map.contains_key(&node.url), "Duplicate node url");
             map.insert(node.url.clone(), node);
         }
-        Ok(RelayMap { nodes: map.into() })
+        Ok(RelayMap {
+            nodes: map.into(),
+            regions: Default::default(),
+        })
+    }
+
+    /// Constructs the [`RelayMap`] from an iterator of [`RelayRegion`]s.
+    pub fn from_regions(regions: impl IntoIterator<Item = RelayRegion>) -> Result<Self> {
+        let mut map = Self::empty();
+        for region in regions {
+            ensure!(
+                map.region(&region.name).is_none(),
+                "Duplicate region name: {}",
+                region.name
+            );
+            map.insert_region(region)?;
+        }
+        Ok(map)
+    }
+
+    /// Returns an `Iterator` over all regions, sorted by name.
+    pub fn regions(&self) -> impl Iterator<Item = &RelayRegion> {
+        self.regions.values()
+    }
+
+    /// Get the region with the given name.
+    pub fn region(&self, name: &str) -> Option<&RelayRegion> {
+        self.regions.get(name)
+    }
+
+    /// Get the region the given relay is part of, if any.
+    pub fn region_of(&self, url: &RelayUrl) -> Option<&RelayRegion> {
+        self.regions
+            .values()
+            .find(|region| region.relays.iter().any(|relay| relay.url == *url))
+    }
+
+    /// Adds a relay server outside of any region.
+    ///
+    /// Replaces a node with the same URL, which is removed from its region.
+    pub fn insert_node(&mut self, node: impl Into<Arc<RelayNode>>) {
+        let node = node.into();
+        self.remove_node(&node.url);
+        Arc::make_mut(&mut self.nodes).insert(node.url.clone(), node);
+    }
+
+    /// Removes a relay server, also from its region.
+    pub fn remove_node(&mut self, url: &RelayUrl) -> Option<Arc<RelayNode>> {
+        let node = Arc::make_mut(&mut self.nodes).remove(url)?;
+        if let Some(name) = self.region_of(url).map(|region| region.name.clone()) {
+            let regions = Arc::make_mut(&mut self.regions);
+            let region = regions.get_mut(&name).expect("region exists");
+            region.relays.retain(|relay| relay.url != *url);
+            if region.relays.is_empty() {
+                regions.remove(&name);
+            }
+        }
+        Some(node)
+    }
+
+    /// Adds a region, replacing the region with the same name.
+    ///
+    /// Fails if the region has no relays or a relay is part of another region or listed
+    /// twice.  Relays outside of any region are moved into the region.
+    pub fn insert_region(&mut self, region: RelayRegion) -> Result<()> {
+        ensure!(
+            !region.relays.is_empty(),
+            "Region {} has no relays",
+            region.name
+        );
+        for (i, relay) in region.relays.iter().enumerate() {
+            ensure!(
+                !region.relays[..i]
+                    .iter()
+                    .any(|other| other.url == relay.url),
+                "Duplicate relay {} in region {}",
+                relay.url,
+                region.name
+            );
+            if let Some(other) = self.region_of(&relay.url) {
+                ensure!(
+                    other.name == region.name,
+                    "Relay {} is already part of region {}",
+                    relay.url,
+                    other.name
+                );
+            }
+        }
+        self.remove_region(&region.name);
+        let nodes = Arc::make_mut(&mut self.nodes);
+        for node in region.nodes() {
+            nodes.insert(node.url.clone(), Arc::new(node));
+        }
+        Arc::make_mut(&mut self.regions).insert(region.name.clone(), region);
+        Ok(())
+    }
+
+    /// Removes a region and its relays.
+    pub fn remove_region(&mut self, name: &str) -> Option<RelayRegion> {
+        let region = Arc::make_mut(&mut self.regions).remove(name)?;
+        let nodes = Arc::make_mut(&mut self.nodes);
+        for relay in &region.relays {
+            nodes.remove(&relay.url);
+        }
+        Some(region)
+    }
+
+    /// Sets the selection weight of a relay within its region.
+    ///
+    /// Fails if the relay is not part of a region.
+    pub fn set_weight(&mut self, url: &RelayUrl, weight: u32) -> Result<()> {
+        let name = self
+            .region_of(url)
+            .map(|region| region.name.clone())
+            .with_context(|| format!("Relay {url} is not part of a region"))?;
+        let region = Arc::make_mut(&mut self.regions)
+            .get_mut(&name)
+            .expect("region exists");
+        for relay in region.relays.iter_mut().filter(|relay| relay.url == *url) {
+            relay.weight = weight;
+        }
+        Ok(())
+    }
+
+    /// Selects the relay a node uses, given the relay with the best latency.
+    ///
+    /// Outside of a region this is the given relay.  Within a region a relay is picked
+    /// in proportion to the weights, based on the node id so that a node keeps picking
+    /// the same relay.  Changing the weight of a relay only moves nodes to or from that
+    /// relay, which allows shifting traffic gradually.  Relays with a weight of `0` are
+    /// only picked if all relays of the region have a weight of `0`.
+    ///
+    /// Returns `None` if `preferred` is not part of this map.
+    pub fn select(&self, preferred: &RelayUrl, node_id: &NodeId) -> Option<RelayUrl> {
+        if !self.contains_node(preferred) {
+            return None;
+        }
+        let Some(region) = self.region_of(preferred) else {
+            return Some(preferred.clone());
+        };
+        // Weighted rendezvous hashing: the relay with the lowest score wins.
+        let selected = region
+            .relays
+            .iter()
+            .filter(|relay| relay.weight > 0)
+            .map(|relay| {
+                let score = -rendezvous_hash(node_id, &relay.url).ln() / f64::from(relay.weight);
+                (score, &relay.url)
+            })
+            .min_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map_or(preferred, |(_, url)| url);
+        Some(selected.clone())
     }
 }
 
@@ -151,8 +329,274 @@ impl Default for RelayQuicConfig {
     }
 }
 
+/// Hashes the node id and relay URL to a number in `(0, 1]`, stable across versions.
+fn rendezvous_hash(node_id: &NodeId, url: &RelayUrl) -> f64 {
+    // FNV-1a, followed by the finalizer of splitmix64 to spread the bits.
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in node_id.as_bytes().iter().chain(url.as_str().as_bytes()) {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^= hash >> 31;
+    ((hash >> 11) + 1) as f64 / (1u64 << 53) as f64
+}
+
+/// A named group of relay servers.
+///
+/// All relays of a region share the STUN and QUIC settings of the region.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayRegion {
+    /// The name of the region.
+    pub name: String,
+    /// The relay servers of the region.
+    pub relays: Vec<WeightedRelay>,
+    /// Whether the relay servers of this region should only be used for STUN requests.
+    #[serde(default)]
+    pub stun_only: bool,
+    /// The STUN port of the relay servers of this region.
+    ///
+    /// Setting this to `0` means the default STUN port is used.
+    #[serde(default)]
+    pub stun_port: u16,
+    /// Configuration to speak to the QUIC endpoint on the relay servers of this region.
+    #[serde(default = "quic_config")]
+    pub quic: Option<RelayQuicConfig>,
+}
+
+impl RelayRegion {
+    /// Creates a region with the default STUN and QUIC settings.
+    pub fn new(name: impl Into<String>, relays: impl IntoIterator<Item = WeightedRelay>) -> Self {
+        Self {
+            name: name.into(),
+            relays: relays.into_iter().collect(),
+            stun_only: false,
+            stun_port: DEFAULT_STUN_PORT,
+            quic: quic_config(),
+        }
+    }
+
+    /// Returns the [`RelayNode`]s of the relays of this region.
+    fn nodes(&self) -> impl Iterator<Item = RelayNode> + '_ {
+        self.relays.iter().map(|relay| RelayNode {
+            url: relay.url.clone(),
+            stun_only: self.stun_only,
+            stun_port: self.stun_port,
+            quic: self.quic.clone(),
+        })
+    }
+}
+
+/// A relay server of a [`RelayRegion`], with its selection weight.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WeightedRelay {
+    /// The [`RelayUrl`] where this relay server can be dialed.
+    pub url: RelayUrl,
+    /// The weight of this relay in the selection within the region.
+    ///
+    /// Defaults to [`WeightedRelay::DEFAULT_WEIGHT`].
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+impl WeightedRelay {
+    /// The default weight of a relay.
+    pub const DEFAULT_WEIGHT: u32 = 100;
+
+    /// Creates a relay with the default weight.
+    pub fn new(url: RelayUrl) -> Self {
+        Self {
+            url,
+            weight: Self::DEFAULT_WEIGHT,
+        }
+    }
+}
+
+fn default_weight() -> u32 {
+    WeightedRelay::DEFAULT_WEIGHT
+}
+
+/// The serialised form of a [`RelayMap`].
+#[derive(Debug, Serialize, Deserialize)]
+struct RelayMapConfig {
+    /// The relay servers outside of any region.
+    #[serde(default)]
+    nodes: Vec<RelayNode>,
+    #[serde(default)]
+    regions: Vec<RelayRegion>,
+}
+
+impl TryFrom<RelayMapConfig> for RelayMap {
+    type Error = anyhow::Error;
+
+    fn try_from(config: RelayMapConfig) -> Result<Self> {
+        let mut map = Self::from_regions(config.regions)?;
+        for node in config.nodes {
+            if map.contains_node(&node.url) {
+                bail!("Duplicate node url: {}", node.url);
+            }
+            map.insert_node(node);
+        }
+        Ok(map)
+    }
+}
+
+impl From<RelayMap> for RelayMapConfig {
+    fn from(map: RelayMap) -> Self {
+        Self {
+            nodes: map
+                .nodes()
+                .filter(|node| map.region_of(&node.url).is_none())
+                .map(|node| RelayNode::clone(node))
+                .collect(),
+            regions: map.regions().cloned().collect(),
+        }
+    }
+}
+
 impl fmt::Display for RelayNode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.url)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::SecretKey;
+
+    use super::*;
+
+    fn url(s: &str) -> RelayUrl {
+        s.parse().unwrap()
+    }
+
+    fn weighted(s: &str, weight: u32) -> WeightedRelay {
+        WeightedRelay {
+            url: url(s),
+            weight,
+        }
+    }
+
+    #[test]
+    fn test_regions() -> Result<()> {
+        let mut region = RelayRegion::new(
+            "eu",
+            [
+                weighted("https://eu1.test", 1),
+                weighted("https://eu2.test", 1),
+            ],
+        );
+        region.stun_port = 1234;
+        let mut map = RelayMap::from_regions([region])?;
+        map.insert_node(RelayNode {
+            url: url("https://us.test"),
+            stun_only: false,
+            stun_port: 0,
+            quic: None,
+        });
+        assert_eq!(map.len(), 3);
+        assert_eq!(
+            map.get_node(&url("https://eu2.test")).unwrap().stun_port,
+            1234
+        );
+        assert_eq!(map.region_of(&url("https://eu1.test")).unwrap().name, "eu");
+        assert!(map.region_of(&url("https://us.test")).is_none());
+
+        // A relay can be part of one region only.
+        let other = RelayRegion::new("other", [weighted("https://eu1.test", 1)]);
+        assert!(map.insert_region(other).is_err());
+        assert!(map.set_weight(&url("https://us.test"), 1).is_err());
+
+        map.remove_node(&url("https://eu1.test"));
+        assert_eq!(map.region("eu").unwrap().relays.len(), 1);
+        map.remove_region("eu");
+        assert_eq!(map.urls().collect::<Vec<_>>(), [&url("https://us.test")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_select() -> Result<()> {
+        let mut map = RelayMap::from_regions([RelayRegion::new(
+            "eu",
+            [
+                weighted("https://eu1.test", 90),
+                weighted("https://eu2.test", 10),
+            ],
+        )])?;
+        map.insert_node(RelayNode {
+            url: url("https://us.test"),
+            stun_only: false,
+            stun_port: 0,
+            quic: None,
+        });
+        let node_ids = (0..1000)
+            .map(|_| SecretKey::generate(rand::thread_rng()).public())
+            .collect::<Vec<_>>();
+        let eu1 = url("https://eu1.test");
+        let eu2 = url("https://eu2.test");
+        let select = |map: &RelayMap| {
+            node_ids
+                .iter()
+                .map(|node_id| map.select(&eu1, node_id).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let count = |selected: &[RelayUrl], url: &RelayUrl| {
+            selected.iter().filter(|selected| *selected == url).count()
+        };
+        let before = select(&map);
+        assert!((50..150).contains(&count(&before, &eu2)));
+        // The selection does not depend on the relay with the best latency.
+        assert_eq!(map.select(&eu2, &node_ids[0]), Some(before[0].clone()));
+
+        // Shifting weight to eu2 only moves nodes from eu1 to eu2.
+        map.set_weight(&eu2, 50)?;
+        let after = select(&map);
+        assert!(count(&after, &eu2) > count(&before, &eu2));
+        assert!(before
+            .iter()
+            .zip(&after)
+            .all(|(before, after)| before == after || *after == eu2));
+
+        // Draining a relay moves all nodes away from it.
+        map.set_weight(&eu1, 0)?;
+        assert_eq!(count(&select(&map), &eu2), node_ids.len());
+
+        let us = url("https://us.test");
+        assert_eq!(map.select(&us, &node_ids[0]), Some(us));
+        assert_eq!(map.select(&url("https://unknown.test"), &node_ids[0]), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_serde() -> Result<()> {
+        let map: RelayMap = serde_json::from_str(
+            r#"{
+                "nodes": [{"url": "https://us.test", "stun_only": false, "stun_port": 0}],
+                "regions": [{
+                    "name": "eu",
+                    "stun_port": 1234,
+                    "relays": [{"url": "https://eu1.test"}, {"url": "https://eu2.test", "weight": 5}]
+                }]
+            }"#,
+        )?;
+        assert_eq!(map.len(), 3);
+        let region = map.region("eu").unwrap();
+        assert_eq!(region.relays[0].weight, WeightedRelay::DEFAULT_WEIGHT);
+        assert_eq!(region.relays[1].weight, 5);
+        assert_eq!(region.quic, Some(RelayQuicConfig::default()));
+
+        let json = serde_json::to_string(&map)?;
+        assert_eq!(serde_json::from_str::<RelayMap>(&json)?, map);
+
+        // Relays listed twice are rejected.
+        let res = serde_json::from_str::<RelayMap>(
+            r#"{
+                "nodes": [{"url": "https://eu1.test", "stun_only": false, "stun_port": 0}],
+                "regions": [{"name": "eu", "relays": [{"url": "https://eu1.test"}]}]
+            }"#,
+        );
+        assert!(res.is_err());
+        Ok(())
+    }
+}
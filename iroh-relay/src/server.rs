@@ -16,7 +16,16 @@
 //! - HTTPS `/generate_204`: Used for net_report probes.
 //! - STUN: UDP port for STUN requests/responses.
 
-use std::{fmt, future::Future, net::SocketAddr, num::NonZeroU32, pin::Pin, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    net::SocketAddr,
+    num::{NonZeroU32, NonZeroUsize},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{anyhow, bail, Context, Result};
 use derive_more::Debug;
@@ -24,10 +33,9 @@ use http::{
     response::Builder as ResponseBuilder, HeaderMap, Method, Request, Response, StatusCode,
 };
 use hyper::body::Incoming;
-use iroh_base::NodeId;
 #[cfg(feature = "test-utils")]
 use iroh_base::RelayUrl;
-use iroh_metrics::inc;
+use iroh_base::{inc, NodeId};
 use n0_future::{future::Boxed, StreamExt};
 use tokio::{
     net::{TcpListener, UdpSocket},
@@ -36,25 +44,65 @@ use tokio::{
 use tokio_util::task::AbortOnDropHandle;
 use tracing::{debug, error, info, info_span, instrument, trace, warn, Instrument};
 
+#[cfg(feature = "metrics")]
+use crate::metrics::MetricsExporter;
 use crate::{
     defaults::DEFAULT_KEY_CACHE_CAPACITY,
-    http::RELAY_PROBE_PATH,
-    protos,
+    http::{ECH_CONFIG_PATH, H2_ALPN, HTTP_1_1_ALPN, RELAY_ALPN, RELAY_PROBE_PATH},
+    key_cache::KeyCacheEviction,
+    protos::{self, relay::MeshKey},
     quic::server::{QuicServer, ServerHandle as QuicServerHandle},
 };
 
+mod access;
+mod access_log;
+mod cert_watcher;
 mod client;
+mod client_auth;
 mod clients;
+mod compression;
+mod error_pages;
+#[cfg(unix)]
+pub mod handoff;
 mod http_server;
+mod ip_limit;
+mod keep_alive;
+mod mesh;
 mod metrics;
+mod proxy_protocol;
+mod quotas;
 pub(crate) mod resolver;
+mod send_queue;
+mod sessions;
+mod startup;
 pub(crate) mod streams;
 #[cfg(feature = "test-utils")]
 pub mod testing;
+mod tls_policy;
+mod unknown_peers;
+mod watchdog;
+mod webtransport;
 
 pub use self::{
+    access::NodeList,
+    access_log::{AccessLog, AccessRecord},
+    cert_watcher::{CertWatcher, DEFAULT_CERT_WATCH_INTERVAL},
+    client_auth::{ClientAuthConfig, ClientIdentity},
+    compression::{CompressionConfig, ContentEncoding, DEFAULT_COMPRESSION_MIN_SIZE},
+    error_pages::{ErrorPage, ErrorPages},
+    http_server::RouteGroup,
+    ip_limit::ClientIpLimit,
+    keep_alive::{KeepAliveConfig, DEFAULT_KEEP_ALIVE_INTERVAL},
+    mesh::MeshConfig,
     metrics::{Metrics, StunMetrics},
+    quotas::QuotaConfig,
     resolver::{ReloadingResolver, DEFAULT_CERT_RELOAD_INTERVAL},
+    send_queue::{OverflowPolicy, SendQueueConfig, DEFAULT_SEND_QUEUE_DEPTH},
+    sessions::{SessionConfig, DEFAULT_SESSION_GRACE_PERIOD},
+    startup::{Features, ListenerAddrs, StartupReport, TlsMode, TlsReport},
+    tls_policy::{TlsPolicy, TlsVersion},
+    unknown_peers::UnknownPeerConfig,
+    watchdog::{WatchdogConfig, DEFAULT_WATCHDOG_INTERVAL},
 };
 
 const NO_CONTENT_CHALLENGE_HEADER: &str = "X-Tailscale-Challenge";
@@ -71,6 +119,8 @@ const TLS_HEADERS: [(&str, &str); 2] = [
     ("Strict-Transport-Security", "max-age=63072000; includeSubDomains"),
     ("Content-Security-Policy", "default-src 'none'; frame-ancestors 'none'; form-action 'none'; base-uri 'self'; block-all-mixed-content; plugin-types 'none'")
 ];
+/// The [`TLS_HEADERS`] which apply to the non-HTML responses of the relay and probe routes.
+const TRANSPORT_HEADERS: [(&str, &str); 1] = [TLS_HEADERS[0]];
 
 type BytesBody = http_body_util::Full<hyper::body::Bytes>;
 type HyperError = Box<dyn std::error::Error + Send + Sync>;
@@ -81,6 +131,14 @@ fn body_empty() -> BytesBody {
     http_body_util::Full::new(hyper::body::Bytes::new())
 }
 
+/// The current time in seconds since the UNIX epoch.
+fn unix_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
 /// Configuration for the full Relay & STUN server.
 ///
 /// Be aware the generic parameters are for when using the Let's Encrypt TLS configuration.
@@ -93,16 +151,28 @@ pub struct ServerConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
     pub stun: Option<StunConfig>,
     /// Configuration for the QUIC server, disabled if `None`.
     pub quic: Option<QuicConfig>,
-    /// Socket to serve metrics on.
+    /// Where to export the metrics to.
+    ///
+    /// [`MetricsExporter::Prometheus`] sets up the process-wide metrics registry,
+    /// [`MetricsExporter::Backend`] installs the process-wide metrics backend.
     #[cfg(feature = "metrics")]
-    pub metrics_addr: Option<SocketAddr>,
+    pub metrics: MetricsExporter,
+    /// Sockets bound by a previous server process, used instead of binding new ones.
+    ///
+    /// Each socket is used for the configured bind address it is bound to, the others are
+    /// closed.  See the [`handoff`] module for upgrading the server without closing its
+    /// listeners.
+    pub listeners: Listeners,
 }
 
 /// Configuration for the Relay HTTP and HTTPS server.
 ///
 /// This includes the HTTP services hosted by the Relay server, the Relay `/relay` HTTP
 /// endpoint is only one of the services served.
+///
+/// Created with [`RelayConfig::new`], further fields may be added in the future.
 #[derive(Debug)]
+#[non_exhaustive]
 pub struct RelayConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
     /// The socket address on which the Relay HTTP server should bind.
     ///
@@ -121,11 +191,193 @@ pub struct RelayConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
     pub limits: Limits,
     /// Key cache capacity.
     pub key_cache_capacity: Option<usize>,
+    /// Key cache eviction policy.
+    pub key_cache_eviction: KeyCacheEviction,
     /// Access configuration.
     pub access: AccessConfig,
+    /// Admin HTTP API configuration.
+    ///
+    /// The admin API is disabled if `None`.
+    pub admin: Option<AdminConfig>,
+    /// Watchdog configuration.
+    ///
+    /// The watchdog is disabled if `None`.
+    pub watchdog: Option<WatchdogConfig>,
+    /// The mesh key authenticating trusted clients.
+    ///
+    /// Trusted clients, like other relay servers federating with this one or monitoring
+    /// agents, may watch the connections of all clients and forward packets on behalf of
+    /// other nodes.  They are rate limited by [`Limits::trusted_client_rx`].  No client is
+    /// trusted if `None`.
+    pub mesh_key: Option<MeshKey>,
+    /// The mesh of relays this one forwards packets with.
+    ///
+    /// The relay connects to each peer as a trusted client with the [`RelayConfig::mesh_key`],
+    /// which is required, and forwards the packets of its clients to the nodes connected
+    /// to the peers.  No packets are forwarded if `None`.
+    pub mesh: Option<MeshConfig>,
+    /// Resumption of the sessions of clients which reconnect after a brief disconnect.
+    ///
+    /// Clients requesting it are issued a session token, reconnecting with it within the
+    /// grace period skips the [`RelayConfig::authorizer`] and hides the disconnect from the
+    /// peers of the client.  Sessions are not supported if `None`.
+    pub sessions: Option<SessionConfig>,
+    /// The keep-alive intervals clients can request.
+    ///
+    /// Clients requesting an interval are pinged at it, clamped to the configured bounds.
+    /// All clients are pinged at the [`DEFAULT_KEEP_ALIVE_INTERVAL`] if `None`.
+    pub keep_alive: Option<KeepAliveConfig>,
+    /// Whether clients can request the compression of the payloads of their packets.
+    ///
+    /// Compressing costs CPU time on the relay for every packet of these clients, which
+    /// only pays off for compressible payloads on slow links.  Requests are ignored if
+    /// false, or without the `payload-compression` feature.
+    pub payload_compression: bool,
+    /// The handling of the packets clients send to nodes not connected to the relay.
+    ///
+    /// Clients requesting it are notified of these packets and clients sending too many
+    /// of them can be rate limited.  They are silently dropped if `None`.
+    pub unknown_peers: Option<UnknownPeerConfig>,
+    /// Compression of the responses of the custom HTTP routes and the admin API.
+    ///
+    /// Responses are sent uncompressed if `None`.
+    pub compression: Option<CompressionConfig>,
+    /// Called whenever a client disconnects.
+    ///
+    /// Lets embedders act on disconnects, e.g. marking a device offline in a presence
+    /// service.
+    pub on_disconnect: Option<DisconnectHook>,
+    /// Decides whether connecting clients may use the relay.
+    ///
+    /// Lets embedders restrict the relay to clients presenting credentials, e.g. a token
+    /// in the `Authorization` header.  All clients passing the [`RelayConfig::access`]
+    /// check are accepted if `None`.
+    pub authorizer: Option<Arc<dyn ClientAuthorizer>>,
+    /// Whether the HTTP(S) listeners bound to IPv6 addresses only accept IPv6 connections.
+    ///
+    /// Sets `IPV6_V6ONLY` on the listening sockets.  If `None` the operating system default
+    /// is used, on Linux IPv6 listeners usually accept IPv4 connections as well, which
+    /// then show up with IPv4-mapped addresses like `::ffff:192.0.2.1`.  Peer addresses
+    /// are always reported in their IPv4 form for these connections.
+    pub ipv6_only: Option<bool>,
+    /// Whether the connections to the Relay HTTP(S) server start with a PROXY protocol header.
+    ///
+    /// Enable this when the server runs behind a load balancer like HAProxy or an AWS NLB
+    /// sending the address of the client in a version 1 or 2 header, connections without a
+    /// header are closed.  The client address then replaces the address of the load
+    /// balancer in the logs, access checks and limits.  The captive portal served on
+    /// [`RelayConfig::http_bind_addr`] besides the HTTPS server never expects a header.
+    ///
+    /// Requires [`RelayConfig::trusted_proxies`].
+    pub proxy_protocol: bool,
+    /// The networks of the load balancers sending PROXY protocol headers.
+    ///
+    /// Connections from other addresses are closed when [`RelayConfig::proxy_protocol`] is
+    /// enabled, anyone else could claim any client address.
+    pub trusted_proxies: Vec<ipnet::IpNet>,
+    /// Where to write a structured record of every request to the Relay HTTP(S) server.
+    ///
+    /// Records carry the method, path and status of the request, the peer address, the
+    /// node ID of clients upgrading to the relay protocol, the duration and the size of the
+    /// response.  No records are written if `None`.
+    pub access_log: Option<AccessLog>,
+    /// Custom responses for the `404`, `400` and `503` errors of the HTTP(S) server.
+    pub error_pages: ErrorPages,
+    /// Additional HTTP headers of all responses of the Relay HTTP(S) server.
+    ///
+    /// These replace the built-in headers of the same name, like `Strict-Transport-Security`.
+    pub headers: HeaderMap,
+    /// Additional HTTP headers of the responses of a route group.
+    ///
+    /// These replace the built-in headers and the [`RelayConfig::headers`] of the same name,
+    /// e.g. to set a different `Content-Security-Policy` on the HTML pages.  The built-in
+    /// `Content-Security-Policy` is only sent on the [`RouteGroup::Pages`] and the
+    /// [`RouteGroup::Errors`].
+    pub route_headers: HashMap<RouteGroup, HeaderMap>,
+    /// Faults injected into the connections accepted by the Relay HTTP(S) server.
+    ///
+    /// For tests only, see [`crate::faults`].
+    #[cfg(any(test, feature = "test-utils"))]
+    pub faults: Option<crate::faults::FaultConfig>,
+}
+
+impl<EC: fmt::Debug, EA: fmt::Debug> RelayConfig<EC, EA> {
+    /// Creates a configuration serving all HTTP services on `http_bind_addr` without TLS.
+    ///
+    /// All nodes have access, the default limits apply and the optional services, like the
+    /// admin API or the mesh, are disabled.
+    pub fn new(http_bind_addr: SocketAddr) -> Self {
+        Self {
+            http_bind_addr,
+            tls: None,
+            limits: Default::default(),
+            key_cache_capacity: None,
+            key_cache_eviction: Default::default(),
+            access: AccessConfig::Everyone,
+            admin: None,
+            watchdog: None,
+            mesh_key: None,
+            mesh: None,
+            sessions: None,
+            keep_alive: None,
+            payload_compression: false,
+            unknown_peers: None,
+            compression: None,
+            on_disconnect: None,
+            authorizer: None,
+            ipv6_only: None,
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            access_log: None,
+            error_pages: Default::default(),
+            headers: Default::default(),
+            route_headers: Default::default(),
+            #[cfg(any(test, feature = "test-utils"))]
+            faults: None,
+        }
+    }
+}
+
+/// Configuration for the admin HTTP API.
+///
+/// The admin API is served under `/admin/` by the Relay HTTP server.  Requests must be
+/// authenticated with an `Authorization: Bearer <token>` header.
+///
+/// Endpoints:
+///
+/// - `GET /admin/key-cache`: statistics of the public key cache, as JSON.
+/// - `GET /admin/watchdog`: the current watchdog sample and the last one which crossed a
+///   threshold, as JSON.  Only served if the watchdog is enabled.
+/// - `POST /admin/listener?addr=<addr>`: binds the Relay HTTP(S) listener to a new socket
+///   address, replying with the bound address as JSON.  Connections accepted by the old
+///   listener keep being served.  The addresses returned by [`Server::http_addr`] and
+///   [`Server::https_addr`] are not updated.
+/// - `GET /admin/config`: the effective configuration, as JSON: the listener addresses,
+///   the TLS mode, the rate and handshake limits, the key cache, access, watchdog and
+///   compression settings.  Secrets, like the mesh key, are left out.
+/// - `GET /admin/clients`: the connected clients with their node ID, protocol, seconds since
+///   connecting, the bytes of the packets relayed to and from them and their reported
+///   software, as JSON.
+/// - `GET /admin/client-versions`: the number of connected clients per self-reported
+///   software name and version, and of those which reported none, as JSON.
+/// - `POST /admin/access/reload`: re-reads the node list file of an allowlist or denylist
+///   [`AccessConfig`], replying with the number of nodes as JSON.  The list is unchanged
+///   if the file is invalid.
+/// - `POST /admin/drain`: starts draining the server, new relay connections are refused with
+///   `503 Service Unavailable`, or closed when using direct framing, while the connected
+///   clients keep being served.  `DELETE /admin/drain` stops draining.  Both reply with the number of connected
+///   clients as JSON.
+#[derive(derive_more::Debug, Clone)]
+pub struct AdminConfig {
+    /// The bearer token authenticating requests to the admin API.
+    #[debug("..")]
+    pub bearer_token: String,
 }
 
 /// Controls which nodes are allowed to use the relay.
+///
+/// Access is checked when a client connects, before it is registered with the server.
+/// Changes to a [`NodeList`] apply to clients connecting afterwards.
 #[derive(derive_more::Debug)]
 pub enum AccessConfig {
     /// Everyone
@@ -133,6 +385,10 @@ pub enum AccessConfig {
     /// Only nodes for which the function returns `Access::Allow`.
     #[debug("restricted")]
     Restricted(Box<dyn Fn(NodeId) -> Boxed<Access> + Send + Sync + 'static>),
+    /// Only the nodes in the list.
+    Allowlist(NodeList),
+    /// Everyone, except the nodes in the list.
+    Denylist(NodeList),
 }
 
 impl AccessConfig {
@@ -144,10 +400,129 @@ impl AccessConfig {
                 let res = check(node).await;
                 matches!(res, Access::Allow)
             }
+            Self::Allowlist(list) => list.contains(&node),
+            Self::Denylist(list) => !list.contains(&node),
+        }
+    }
+
+    /// The node list of an allowlist or denylist.
+    pub fn node_list(&self) -> Option<&NodeList> {
+        match self {
+            Self::Allowlist(list) | Self::Denylist(list) => Some(list),
+            Self::Everyone | Self::Restricted(_) => None,
         }
     }
 }
 
+/// A callback invoked when a client disconnects from the relay server.
+///
+/// It is called on the task serving the client after the client is unregistered, so packets
+/// sent to the node are no longer routed to this connection.  It must not block, hand the
+/// event off to a channel or spawn a task for any work which takes time.
+#[derive(Clone, derive_more::Debug)]
+#[debug("DisconnectHook")]
+pub struct DisconnectHook(Arc<dyn Fn(&Disconnect) + Send + Sync + 'static>);
+
+impl DisconnectHook {
+    /// Creates a hook calling `f` for every disconnect.
+    pub fn new(f: impl Fn(&Disconnect) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub(crate) fn call(&self, disconnect: &Disconnect) {
+        (self.0)(disconnect)
+    }
+}
+
+/// A client which disconnected from the relay server, see [`DisconnectHook`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disconnect {
+    /// The node of the client.
+    pub node_id: NodeId,
+    /// Why the client disconnected.
+    pub reason: DisconnectReason,
+    /// How long the client was connected.
+    pub duration: Duration,
+    /// The number of packets queued for the client which were never sent to it.
+    pub unflushed_packets: usize,
+    /// The number of bytes of the packets queued for the client which were never sent to it.
+    pub unflushed_bytes: usize,
+}
+
+/// Why a client disconnected from the relay server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client announced that it is closing the connection.
+    Closing,
+    /// The client did not answer the pings of the server in time.
+    PingTimeout,
+    /// The node connected again, replacing this connection.
+    Replaced,
+    /// The server closed the connection, e.g. because it is shutting down.
+    ServerClosed,
+    /// The client did not keep up with the packets sent to it, see
+    /// [`OverflowPolicy::DisconnectClient`].
+    SendQueueOverflow,
+    /// Reading from or writing to the connection failed, or the client closed it without
+    /// announcing it.
+    Error(String),
+}
+
+/// Decides whether a client may use the relay, e.g. by validating a token it sent.
+///
+/// Called for every connecting client after it identified itself and passed the
+/// [`AccessConfig`], before it is registered with the server.  The headers are those of the
+/// HTTP request which upgraded the connection, they are empty for clients speaking the
+/// relay protocol right away.  A token the client sent with its handshake, see
+/// [`ClientBuilder::auth_token`], shows up as an `Authorization: Bearer` header unless the
+/// request had one already.
+///
+/// Clients are disconnected without a decision if it takes longer than ten seconds.
+///
+/// [`ClientBuilder::auth_token`]: crate::client::ClientBuilder::auth_token
+pub trait ClientAuthorizer: fmt::Debug + Send + Sync + 'static {
+    /// Decides about the client with the node ID.
+    fn authorize(
+        &self,
+        node_id: NodeId,
+        remote_addr: SocketAddr,
+        headers: &HeaderMap,
+    ) -> Boxed<Decision>;
+
+    /// Decides about the client with the node ID, which presented a verified TLS client
+    /// certificate.
+    ///
+    /// Called instead of [`ClientAuthorizer::authorize`] when the server requires client
+    /// certificates, see [`TlsConfig::client_auth`].  Ignores the certificate by default.
+    fn authorize_certified(
+        &self,
+        node_id: NodeId,
+        remote_addr: SocketAddr,
+        headers: &HeaderMap,
+        identity: &ClientIdentity,
+    ) -> Boxed<Decision> {
+        let _ = identity;
+        self.authorize(node_id, remote_addr, headers)
+    }
+}
+
+/// The decision of a [`ClientAuthorizer`] about a connecting client.
+#[derive(Debug, Clone)]
+pub enum Decision {
+    /// The client may use the relay.
+    Accept,
+    /// The client is refused, the reason is sent to it.
+    Reject {
+        /// Why the client is refused, meant for humans.
+        reason: String,
+    },
+    /// The client may use the relay, with this rate limit for the data it sends.
+    ///
+    /// Replaces [`Limits::client_rx`] for this client, e.g. to grant paying customers a
+    /// higher throughput.
+    AcceptWithRateLimit(ClientRateLimit),
+}
+
 /// Access restriction for a node.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Access {
@@ -164,6 +539,85 @@ pub struct StunConfig {
     ///
     /// Normally you'd chose port `3478`, see [`crate::defaults::DEFAULT_STUN_PORT`].
     pub bind_addr: SocketAddr,
+    /// Further socket addresses on which the STUN server should bind.
+    ///
+    /// Clients probe STUN in different ways, serving e.g. both an anycast and a unicast IP
+    /// or several ports improves compatibility.  All addresses share the STUN metrics.
+    pub additional_bind_addrs: Vec<SocketAddr>,
+}
+
+impl StunConfig {
+    /// Returns all the socket addresses on which the STUN server should bind.
+    pub fn bind_addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        std::iter::once(self.bind_addr).chain(self.additional_bind_addrs.iter().copied())
+    }
+}
+
+/// The listening sockets of a [`Server`].
+///
+/// Returned by [`Server::listeners`] and passed on to a new server with
+/// [`ServerConfig::listeners`].
+#[derive(Debug, Default)]
+pub struct Listeners {
+    /// The listener of the Relay HTTP(S) server.
+    ///
+    /// Bound to [`TlsConfig::https_bind_addr`] when using TLS, to
+    /// [`RelayConfig::http_bind_addr`] otherwise.
+    pub relay: Option<std::net::TcpListener>,
+    /// The listener of the plain HTTP server for captive portal detection.
+    ///
+    /// Bound to [`RelayConfig::http_bind_addr`], only used with TLS.
+    pub http: Option<std::net::TcpListener>,
+    /// The sockets of the STUN server.
+    pub stun: Vec<std::net::UdpSocket>,
+}
+
+impl Listeners {
+    /// Takes the TCP listener from `slot` if it is bound to `addr`.
+    fn take_tcp(
+        slot: &mut Option<std::net::TcpListener>,
+        addr: SocketAddr,
+    ) -> Option<std::net::TcpListener> {
+        match slot {
+            Some(listener) if listener.local_addr().ok() == Some(addr) => slot.take(),
+            _ => None,
+        }
+    }
+
+    /// Takes the STUN socket bound to `addr`.
+    fn take_stun(&mut self, addr: SocketAddr) -> Option<std::net::UdpSocket> {
+        let i = self
+            .stun
+            .iter()
+            .position(|sock| sock.local_addr().ok() == Some(addr))?;
+        Some(self.stun.remove(i))
+    }
+
+    /// Closes the sockets which were not used, logging their addresses.
+    fn close_unused(self) {
+        let addrs = self
+            .relay
+            .iter()
+            .chain(self.http.iter())
+            .map(|listener| listener.local_addr())
+            .chain(self.stun.iter().map(|sock| sock.local_addr()));
+        for addr in addrs {
+            warn!(
+                ?addr,
+                "closing inherited socket, it matches no configured bind address"
+            );
+        }
+    }
+}
+
+/// Duplicates a socket, for handing it to another server.
+fn try_clone_socket<S, T>(socket: &S) -> Result<T>
+where
+    for<'a> socket2::SockRef<'a>: From<&'a S>,
+    T: From<socket2::Socket>,
+{
+    let socket = socket2::SockRef::from(socket).try_clone()?;
+    Ok(socket.into())
 }
 
 /// Configuration for the QUIC server.
@@ -198,7 +652,42 @@ pub struct TlsConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
     /// Mode for getting a cert.
     pub cert: CertConfig<EC, EA>,
     /// The server configuration.
+    ///
+    /// The [`RELAY_ALPN`] and [`HTTP_1_1_ALPN`] protocols are added to its ALPN protocols.
+    /// Clients offering ALPN protocols need to offer one of these, clients offering none
+    /// are still accepted.
     pub server_config: rustls::ServerConfig,
+    /// The `ECHConfigList` to publish for Encrypted Client Hello, if any.
+    ///
+    /// The config list is served on [`ECH_CONFIG_PATH`] so it can be published in the
+    /// `HTTPS` DNS record of the relay, from where clients fetch it.  The relay itself does
+    /// not decrypt ECH, this needs a TLS terminating frontend holding the matching keys.
+    ///
+    /// [`ECH_CONFIG_PATH`]: crate::http::ECH_CONFIG_PATH
+    pub ech_config_list: Option<Vec<u8>>,
+    /// Whether to offer HTTP/2 to clients, with the [`H2_ALPN`] protocol.
+    ///
+    /// On HTTP/2 connections the relay upgrade is an extended `CONNECT` request, so many
+    /// relay connections can share one TLS connection.  If this is disabled, [`H2_ALPN`] is
+    /// not offered even if it was added to [`TlsConfig::server_config`] directly.
+    ///
+    /// [`H2_ALPN`]: crate::http::H2_ALPN
+    pub http2: bool,
+    /// Whether to accept relay connections over WebTransport.
+    ///
+    /// WebTransport sessions are served over HTTP/3, on a QUIC endpoint bound to the UDP
+    /// port of [`TlsConfig::https_bind_addr`], see [`Server::webtransport_addr`].  This
+    /// needs [`TlsConfig::server_config`] to support TLS 1.3.
+    pub webtransport: bool,
+    /// Requires the relay clients to present a TLS client certificate.
+    ///
+    /// Connections without a certificate are closed right after the TLS handshake, the
+    /// verified certificate is passed to [`ClientAuthorizer::authorize_certified`].  The
+    /// [`TlsConfig::server_config`] must verify the certificates, build it with
+    /// [`TlsPolicy::mutual_server_config_builder`].  Only supported with
+    /// [`CertConfig::Manual`] and [`CertConfig::Reloading`].  Client certificates are not
+    /// required if `None`.
+    pub client_auth: Option<ClientAuthConfig>,
 }
 
 /// Rate limits.
@@ -211,17 +700,97 @@ pub struct Limits {
     pub accept_conn_burst: Option<usize>,
     /// Rate limits for incoming traffic from a client connection.
     pub client_rx: Option<ClientRateLimit>,
+    /// Rate limits for incoming traffic from a trusted client connection.
+    ///
+    /// Replaces [`Limits::client_rx`] for clients authenticated by the
+    /// [`RelayConfig::mesh_key`].  Unlimited if not set.
+    pub trusted_client_rx: Option<ClientRateLimit>,
+    /// Rate limits for outgoing traffic to a client connection, summed over all senders.
+    ///
+    /// Packets exceeding the rate are held back, filling the queue of the client until
+    /// further packets are dropped.  Disco packets are not limited, nor are trusted
+    /// clients.  Unlimited if not set.
+    pub client_tx: Option<ClientRateLimit>,
+    /// Limit of the connections doing their handshakes at the same time.
+    ///
+    /// Unlimited if not set.
+    pub handshakes: Option<HandshakeLimit>,
+    /// Max number of connections open at the same time.
+    ///
+    /// Enforced when accepting a TCP connection, before the TLS handshake: connections
+    /// beyond the limit are closed right away.  Unlimited if not set.
+    pub max_connections: Option<NonZeroUsize>,
+    /// Max number of connections open at the same time from a single IP address.
+    ///
+    /// Enforced like [`Limits::max_connections`], keeping a single host from exhausting
+    /// the file descriptors of the server.  Unlimited if not set.
+    pub max_connections_per_ip: Option<NonZeroUsize>,
+    /// Max number of clients connected at the same time from a single source.
+    ///
+    /// Enforced during the relay handshake, so unlike [`Limits::max_connections_per_ip`]
+    /// it covers all transports, and groups IPv6 addresses by their /64 prefix.  Trusted
+    /// clients are not limited.  Unlimited if not set.
+    pub clients_per_ip: Option<ClientIpLimit>,
+    /// Byte quotas of the packets each node sends, across all its connections.
+    ///
+    /// Unlike the rate limits, the packets of a node over its quota are dropped.  Trusted
+    /// clients are not subject to the quotas.  Unlimited if not set.
+    pub client_quota: Option<QuotaConfig>,
+    /// The queues of the packets sent to each client.
+    ///
+    /// If not set [`DEFAULT_SEND_QUEUE_DEPTH`] packets are queued, and packets to a client
+    /// whose queue is full are dropped.
+    pub send_queue: Option<SendQueueConfig>,
+}
+
+/// Limit of the connections in the handshake phase.
+///
+/// A connection is in the handshake phase during its TLS handshake and during the relay
+/// handshake until the client is registered.  Connections exceeding the limit wait in a
+/// queue for a free slot, and are closed if none becomes free in time.  This keeps a flood
+/// of handshakes from starving the established clients of CPU.
+#[derive(Debug, Copy, Clone)]
+pub struct HandshakeLimit {
+    /// Max number of connections in the handshake phase at the same time.
+    pub max_concurrent: NonZeroUsize,
+    /// How long a connection waits for a free slot before being closed.
+    pub queue_timeout: Duration,
 }
 
 /// Per-client rate limit configuration.
 #[derive(Debug, Copy, Clone)]
 pub struct ClientRateLimit {
-    /// Max number of bytes per second to read from the client connection.
+    /// Max number of bytes per second to read from, or write to, the client connection.
     pub bytes_per_second: NonZeroU32,
-    /// Max number of bytes to read in a single burst.
+    /// Max number of bytes to read, or write, in a single burst.
     pub max_burst_bytes: Option<NonZeroU32>,
 }
 
+/// The settings of a running Relay server which [`Server::reload`] replaces.
+///
+/// Each setting replaces the one of the same name in the [`RelayConfig`].
+#[derive(Debug)]
+pub struct ReloadConfig {
+    /// See [`Limits::client_rx`].
+    pub client_rx: Option<ClientRateLimit>,
+    /// See [`Limits::trusted_client_rx`].
+    pub trusted_client_rx: Option<ClientRateLimit>,
+    /// See [`Limits::client_tx`].
+    pub client_tx: Option<ClientRateLimit>,
+    /// See [`RelayConfig::access`].
+    pub access: AccessConfig,
+    /// See [`RelayConfig::headers`].
+    pub headers: HeaderMap,
+    /// See [`RelayConfig::route_headers`].
+    pub route_headers: HashMap<RouteGroup, HeaderMap>,
+    /// A TLS server config with new certificates, the certificates are kept if `None`.
+    ///
+    /// Only supported with [`CertConfig::Manual`] and [`CertConfig::Reloading`], the
+    /// ALPN protocols of the running server are kept.  If clients must present a
+    /// certificate, the config must verify them as well.
+    pub tls_server_config: Option<rustls::ServerConfig>,
+}
+
 /// TLS certificate configuration.
 #[derive(derive_more::Debug)]
 pub enum CertConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
@@ -249,8 +818,10 @@ pub enum CertConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
 pub struct Server {
     /// The address of the HTTP server, if configured.
     http_addr: Option<SocketAddr>,
-    /// The address of the STUN server, if configured.
-    stun_addr: Option<SocketAddr>,
+    /// The addresses of the STUN server, if configured.
+    ///
+    /// The first address is the primary [`StunConfig::bind_addr`].
+    stun_addrs: Vec<SocketAddr>,
     /// The address of the HTTPS server, if the relay server is using TLS.
     ///
     /// If the Relay server is not using TLS then it is served from the
@@ -258,6 +829,13 @@ pub struct Server {
     https_addr: Option<SocketAddr>,
     /// The address of the QUIC server, if configured.
     quic_addr: Option<SocketAddr>,
+    /// The address of the WebTransport endpoint, if enabled.
+    webtransport_addr: Option<SocketAddr>,
+    /// The WebTransport endpoint, if enabled.
+    webtransport_endpoint: Option<quinn::Endpoint>,
+    /// Duplicates of the captive portal listener and the STUN sockets, for
+    /// [`Server::listeners`].
+    sockets: Listeners,
     /// Handle to the relay server.
     relay_handle: Option<http_server::ServerHandle>,
     /// Handle to the quic server.
@@ -269,6 +847,8 @@ pub struct Server {
     /// If the server has manual certificates configured the certificate chain will be
     /// available here, this can be used by a client to authenticate the server.
     certificates: Option<Vec<rustls::pki_types::CertificateDer<'static>>>,
+    /// Describes how the server started.
+    startup_report: StartupReport,
 }
 
 impl Server {
@@ -279,19 +859,27 @@ impl Server {
         EA: fmt::Debug + 'static,
     {
         let mut tasks = JoinSet::new();
+        let mut inherited = config.listeners;
+        let mut sockets = Listeners::default();
 
         #[cfg(feature = "metrics")]
-        if let Some(addr) = config.metrics_addr {
-            debug!("Starting metrics server");
+        config.metrics.install_backend()?;
+        #[cfg(feature = "metrics")]
+        if config.metrics.prometheus_addr().is_some() {
+            debug!("Starting metrics exporter");
             use iroh_metrics::core::Metric;
-
-            iroh_metrics::core::Core::init(|reg, metrics| {
+            if iroh_metrics::core::Core::try_init(|reg, metrics| {
                 metrics.insert(metrics::Metrics::new(reg));
                 metrics.insert(StunMetrics::new(reg));
-            });
+            })
+            .is_err()
+            {
+                warn!("metrics registry already set up, relay metrics may not be recorded");
+            }
+            let exporter = config.metrics.clone();
             tasks.spawn(
                 async move {
-                    iroh_metrics::metrics::start_metrics_server(addr).await?;
+                    exporter.run().await?;
                     anyhow::Ok(())
                 }
                 .instrument(info_span!("metrics-server")),
@@ -299,23 +887,31 @@ impl Server {
         }
 
         // Start the STUN server.
-        let stun_addr = match config.stun {
-            Some(stun) => {
-                debug!("Starting STUN server");
-                match UdpSocket::bind(stun.bind_addr).await {
+        let mut stun_addrs = Vec::new();
+        if let Some(stun) = config.stun {
+            debug!("Starting STUN server");
+            for bind_addr in stun.bind_addrs() {
+                let sock = match inherited.take_stun(bind_addr) {
+                    Some(sock) => {
+                        sock.set_nonblocking(true)?;
+                        UdpSocket::from_std(sock)
+                    }
+                    None => UdpSocket::bind(bind_addr).await,
+                };
+                match sock {
                     Ok(sock) => {
                         let addr = sock.local_addr()?;
+                        sockets.stun.push(try_clone_socket(&sock)?);
                         info!("STUN server listening on {addr}");
                         tasks.spawn(
                             server_stun_listener(sock).instrument(info_span!("stun-server", %addr)),
                         );
-                        Some(addr)
+                        stun_addrs.push(addr);
                     }
-                    Err(err) => bail!("failed to bind STUN listener: {err:#?}"),
+                    Err(err) => bail!("failed to bind STUN listener on {bind_addr}: {err:#?}"),
                 }
             }
-            None => None,
-        };
+        }
 
         // Start the Relay server, but first clone the certs out.
         let certificates = config.relay.as_ref().and_then(|relay| {
@@ -336,6 +932,14 @@ impl Server {
         let quic_addr = quic_server.as_ref().map(|srv| srv.bind_addr());
         let quic_handle = quic_server.as_ref().map(|srv| srv.handle());
 
+        #[cfg(feature = "metrics")]
+        let metrics_addr = config.metrics.prometheus_addr();
+        #[cfg(not(feature = "metrics"))]
+        let metrics_addr = None;
+
+        let mut webtransport_endpoint = None;
+        let mut relay_tls_mode = None;
+        let mut mesh_enabled = false;
         let (relay_server, http_addr) = match config.relay {
             Some(relay_config) => {
                 debug!("Starting Relay server");
@@ -343,26 +947,131 @@ impl Server {
                 for (name, value) in TLS_HEADERS.iter() {
                     headers.insert(*name, value.parse()?);
                 }
+                let mut transport_headers = HeaderMap::new();
+                for (name, value) in TRANSPORT_HEADERS.iter() {
+                    transport_headers.insert(*name, value.parse()?);
+                }
                 let relay_bind_addr = match relay_config.tls {
                     Some(ref tls) => tls.https_bind_addr,
                     None => relay_config.http_bind_addr,
                 };
+                let mesh = match (relay_config.mesh, &relay_config.mesh_key) {
+                    (Some(mesh_config), Some(mesh_key)) => {
+                        Some(mesh::Mesh::new(mesh_config, mesh_key.clone()))
+                    }
+                    (Some(_), None) => bail!("a relay mesh needs the mesh key"),
+                    (None, _) => None,
+                };
+                let (mesh_routes, mesh) = mesh.unzip();
+                mesh_enabled = mesh.is_some();
+                if relay_config.proxy_protocol && relay_config.trusted_proxies.is_empty() {
+                    bail!("the PROXY protocol needs the trusted proxies");
+                }
                 let key_cache_capacity = relay_config
                     .key_cache_capacity
                     .unwrap_or(DEFAULT_KEY_CACHE_CAPACITY);
                 let mut builder = http_server::ServerBuilder::new(relay_bind_addr)
+                    .listener(Listeners::take_tcp(&mut inherited.relay, relay_bind_addr))
                     .headers(headers)
+                    .route_group(RouteGroup::Relay, transport_headers.clone())
+                    .route_group(RouteGroup::Probes, transport_headers)
                     .key_cache_capacity(key_cache_capacity)
+                    .key_cache_eviction(relay_config.key_cache_eviction)
                     .access(relay_config.access)
-                    .request_handler(Method::GET, "/", Box::new(root_handler))
-                    .request_handler(Method::GET, "/index.html", Box::new(root_handler))
-                    .request_handler(Method::GET, RELAY_PROBE_PATH, Box::new(probe_handler))
-                    .request_handler(Method::GET, "/robots.txt", Box::new(robots_handler));
+                    .admin(relay_config.admin)
+                    .watchdog(relay_config.watchdog)
+                    .mesh_key(relay_config.mesh_key)
+                    .mesh(mesh_routes)
+                    .sessions(relay_config.sessions)
+                    .keep_alive(relay_config.keep_alive)
+                    .payload_compression(relay_config.payload_compression)
+                    .unknown_peers(relay_config.unknown_peers)
+                    .compression(relay_config.compression)
+                    .disconnect_hook(relay_config.on_disconnect)
+                    .authorizer(relay_config.authorizer)
+                    .ipv6_only(relay_config.ipv6_only)
+                    .proxy_protocol(
+                        relay_config
+                            .proxy_protocol
+                            .then_some(relay_config.trusted_proxies),
+                    )
+                    .access_log(relay_config.access_log)
+                    .error_pages(relay_config.error_pages)
+                    .extra_headers(relay_config.headers)
+                    .extra_route_headers(relay_config.route_headers)
+                    .route_group_handler(
+                        RouteGroup::Pages,
+                        Method::GET,
+                        "/",
+                        Box::new(root_handler),
+                    )
+                    .route_group_handler(
+                        RouteGroup::Pages,
+                        Method::GET,
+                        "/index.html",
+                        Box::new(root_handler),
+                    )
+                    .route_group_handler(
+                        RouteGroup::Probes,
+                        Method::GET,
+                        RELAY_PROBE_PATH,
+                        Box::new(probe_handler),
+                    )
+                    .route_group_handler(
+                        RouteGroup::Pages,
+                        Method::GET,
+                        "/robots.txt",
+                        Box::new(robots_handler),
+                    );
+                #[cfg(any(test, feature = "test-utils"))]
+                if let Some(faults) = relay_config.faults {
+                    builder = builder.faults(faults);
+                }
                 if let Some(cfg) = relay_config.limits.client_rx {
                     builder = builder.client_rx_ratelimit(cfg);
                 }
-                let http_addr = match relay_config.tls {
+                if let Some(cfg) = relay_config.limits.trusted_client_rx {
+                    builder = builder.trusted_client_rx_ratelimit(cfg);
+                }
+                if let Some(cfg) = relay_config.limits.client_tx {
+                    builder = builder.client_tx_ratelimit(cfg);
+                }
+                builder = builder
+                    .handshake_limit(relay_config.limits.handshakes)
+                    .connection_limit(http_server::ConnectionLimit {
+                        max_total: relay_config.limits.max_connections,
+                        max_per_ip: relay_config.limits.max_connections_per_ip,
+                    })
+                    .client_ip_limit(relay_config.limits.clients_per_ip)
+                    .client_quota(relay_config.limits.client_quota)
+                    .send_queue(relay_config.limits.send_queue.unwrap_or_default());
+                let (tls_mode, http_addr, webtransport_config) = match relay_config.tls {
                     Some(tls_config) => {
+                        if let Some(ref ech_config_list) = tls_config.ech_config_list {
+                            let body = origin_svcb_json(ech_config_list);
+                            builder = builder.request_handler(
+                                Method::GET,
+                                ECH_CONFIG_PATH,
+                                Box::new(move |_r, response| {
+                                    ech_config_handler(body.clone(), response)
+                                }),
+                            );
+                        }
+                        let mut server_config = tls_config.server_config;
+                        let webtransport_config = tls_config
+                            .webtransport
+                            .then(|| (tls_config.https_bind_addr, server_config.clone()));
+                        set_relay_alpns(&mut server_config, tls_config.http2);
+                        let tls_mode = match tls_config.cert {
+                            CertConfig::LetsEncrypt { .. } => http_server::TlsMode::LetsEncrypt,
+                            CertConfig::Manual { .. } => http_server::TlsMode::Manual,
+                            CertConfig::Reloading => http_server::TlsMode::Reloading,
+                        };
+                        if tls_config.client_auth.is_some()
+                            && matches!(tls_config.cert, CertConfig::LetsEncrypt { .. })
+                        {
+                            bail!("client certificates are not supported with Let's Encrypt");
+                        }
                         let server_tls_config = match tls_config.cert {
                             CertConfig::LetsEncrypt { mut state } => {
                                 let acceptor =
@@ -380,15 +1089,18 @@ impl Server {
                                     .instrument(info_span!("acme")),
                                 );
                                 Some(http_server::TlsConfig {
-                                    config: Arc::new(tls_config.server_config),
+                                    config: Arc::new(server_config),
                                     acceptor,
                                 })
                             }
                             CertConfig::Manual { .. } | CertConfig::Reloading { .. } => {
-                                let server_config = Arc::new(tls_config.server_config);
+                                let server_config = Arc::new(server_config);
                                 let acceptor =
                                     tokio_rustls::TlsAcceptor::from(server_config.clone());
-                                let acceptor = http_server::TlsAcceptor::Manual(acceptor);
+                                let acceptor = match tls_config.client_auth {
+                                    Some(_) => http_server::TlsAcceptor::ManualMutual(acceptor),
+                                    None => http_server::TlsAcceptor::Manual(acceptor),
+                                };
                                 Some(http_server::TlsConfig {
                                     config: server_config,
                                     acceptor,
@@ -399,28 +1111,63 @@ impl Server {
 
                         // Some services always need to be served over HTTP without TLS.  Run
                         // these standalone.
-                        let http_listener = TcpListener::bind(&relay_config.http_bind_addr)
-                            .await
-                            .context("failed to bind http")?;
+                        let http_listener = match Listeners::take_tcp(
+                            &mut inherited.http,
+                            relay_config.http_bind_addr,
+                        ) {
+                            Some(listener) => http_server::inherit_listener(listener)
+                                .context("failed to use the inherited http listener")?,
+                            None => http_server::bind_listener(
+                                relay_config.http_bind_addr,
+                                relay_config.ipv6_only,
+                            )
+                            .context("failed to bind http")?,
+                        };
                         let http_addr = http_listener.local_addr()?;
+                        sockets.http = Some(try_clone_socket(&http_listener)?);
                         tasks.spawn(
                             run_captive_portal_service(http_listener)
                                 .instrument(info_span!("http-service", addr = %http_addr)),
                         );
-                        Some(http_addr)
+                        (Some(tls_mode), Some(http_addr), webtransport_config)
                     }
                     None => {
                         // If running Relay without TLS add the plain HTTP server directly
                         // to the Relay server.
-                        builder = builder.request_handler(
+                        builder = builder.route_group_handler(
+                            RouteGroup::Probes,
                             Method::GET,
                             "/generate_204",
                             Box::new(serve_no_content_handler),
                         );
-                        None
+                        (None, None, None)
                     }
                 };
-                let relay_server = builder.spawn().await?;
+                relay_tls_mode = tls_mode;
+                builder = builder.services(http_server::ServiceConfig {
+                    tls: tls_mode,
+                    http_addr,
+                    stun_addrs: stun_addrs.clone(),
+                    quic_addr,
+                    metrics_addr,
+                });
+                let relay_server = builder.spawn()?;
+                if let Some(mesh) = mesh {
+                    tasks.spawn(
+                        mesh.run(relay_server.handle())
+                            .instrument(info_span!("relay-mesh")),
+                    );
+                }
+                if let Some((bind_addr, tls_config)) = webtransport_config {
+                    // Bound to the port of the HTTPS server, it may have been picked by the OS.
+                    let bind_addr = SocketAddr::new(bind_addr.ip(), relay_server.addr().port());
+                    let endpoint = webtransport::bind(bind_addr, tls_config)?;
+                    tasks.spawn(
+                        webtransport::serve(endpoint.clone(), relay_server.handle())
+                            .instrument(info_span!("webtransport-server")),
+                    );
+                    webtransport_endpoint = Some(endpoint);
+                }
                 (Some(relay_server), http_addr)
             }
             None => (None, None),
@@ -430,16 +1177,68 @@ impl Server {
         let relay_addr = relay_server.as_ref().map(|srv| srv.addr());
         let relay_handle = relay_server.as_ref().map(|srv| srv.handle());
         let task = tokio::spawn(relay_supervisor(tasks, relay_server, quic_server));
+        inherited.close_unused();
+        let webtransport_addr = webtransport_endpoint
+            .as_ref()
+            .map(quinn::Endpoint::local_addr)
+            .transpose()?;
+
+        let listeners = ListenerAddrs {
+            http: http_addr.or(relay_addr),
+            https: http_addr.and(relay_addr),
+            stun: stun_addrs.clone(),
+            quic: quic_addr,
+            webtransport: webtransport_addr,
+            metrics: metrics_addr,
+        };
+        let startup_report = StartupReport {
+            tls: relay_tls_mode.map(|mode| TlsReport {
+                mode,
+                cert_fingerprints: certificates
+                    .iter()
+                    .flatten()
+                    .map(startup::cert_fingerprint)
+                    .collect(),
+                client_auth: relay_handle
+                    .as_ref()
+                    .is_some_and(|handle| handle.requires_client_certificate()),
+            }),
+            features: Features {
+                relay: relay_handle.is_some(),
+                stun: !listeners.stun.is_empty(),
+                captive_portal: relay_handle.is_some(),
+                admin_api: relay_handle
+                    .as_ref()
+                    .is_some_and(|handle| handle.has_admin_api()),
+                quic: listeners.quic.is_some(),
+                webtransport: listeners.webtransport.is_some(),
+                metrics: listeners.metrics.is_some(),
+                mesh: mesh_enabled,
+            },
+            limits: relay_handle
+                .as_ref()
+                .map(|handle| handle.effective_config()["limits"].take())
+                .unwrap_or_default(),
+            listeners,
+        };
+        match serde_json::to_string(&startup_report) {
+            Ok(report) => info!(%report, "relay server started"),
+            Err(err) => warn!("failed to serialize the startup report: {err:#}"),
+        }
 
         Ok(Self {
             http_addr: http_addr.or(relay_addr),
-            stun_addr,
+            stun_addrs,
             https_addr: http_addr.and(relay_addr),
             quic_addr,
+            webtransport_addr,
+            webtransport_endpoint,
+            sockets,
             relay_handle,
             quic_handle,
             supervisor: AbortOnDropHandle::new(task),
             certificates,
+            startup_report,
         })
     }
 
@@ -481,9 +1280,124 @@ impl Server {
         self.quic_addr
     }
 
+    /// The socket address the WebTransport endpoint is listening on, if enabled.
+    ///
+    /// See [`TlsConfig::webtransport`].
+    pub fn webtransport_addr(&self) -> Option<SocketAddr> {
+        self.webtransport_addr
+    }
+
     /// The socket address the STUN server is listening on.
+    ///
+    /// This is the address bound for [`StunConfig::bind_addr`], see [`Server::stun_addrs`]
+    /// for all addresses.
     pub fn stun_addr(&self) -> Option<SocketAddr> {
-        self.stun_addr
+        self.stun_addrs.first().copied()
+    }
+
+    /// All the socket addresses the STUN server is listening on.
+    pub fn stun_addrs(&self) -> &[SocketAddr] {
+        &self.stun_addrs
+    }
+
+    /// Duplicates the listening sockets of the server.
+    ///
+    /// The sockets stay open in this server, they are shared with whoever the duplicates are
+    /// handed to.  Passed on in [`ServerConfig::listeners`] a new server accepts on them
+    /// while this one is still running, see [`Server::stop_accepting`].
+    pub fn listeners(&self) -> Result<Listeners> {
+        let relay = match self.relay_handle {
+            Some(ref handle) => handle.try_clone_listener()?,
+            None => None,
+        };
+        let http = self
+            .sockets
+            .http
+            .as_ref()
+            .map(|listener| listener.try_clone())
+            .transpose()?;
+        let stun = self
+            .sockets
+            .stun
+            .iter()
+            .map(|sock| sock.try_clone())
+            .collect::<std::io::Result<_>>()?;
+        Ok(Listeners { relay, http, stun })
+    }
+
+    /// Stops accepting connections on the Relay HTTP(S) listener.
+    ///
+    /// The connected clients keep being served until the server is shut down.  The captive
+    /// portal and STUN servers keep running, they have no state worth handing over.
+    pub fn stop_accepting(&self) {
+        if let Some(ref handle) = self.relay_handle {
+            handle.stop_accepting();
+        }
+    }
+
+    /// Replaces the rate limits, access configuration, headers and TLS certificates of the
+    /// Relay server.
+    ///
+    /// The connected clients stay connected, keeping the rate limits they connected with.
+    /// The new settings apply to the clients connecting afterwards and the new certificates
+    /// to the following TLS handshakes, including those of the QUIC and WebTransport
+    /// endpoints.  Nothing is replaced if this fails.
+    pub fn reload(&self, config: ReloadConfig) -> Result<()> {
+        let handle = self
+            .relay_handle
+            .as_ref()
+            .context("the relay server is not running")?;
+        let endpoints = match &config.tls_server_config {
+            Some(server_config) => self.endpoint_server_configs(server_config)?,
+            None => EndpointServerConfigs::default(),
+        };
+        handle.reload(config)?;
+        endpoints.apply(self);
+        Ok(())
+    }
+
+    /// Replaces the TLS certificates of the Relay server, like [`Server::reload`].
+    ///
+    /// Used to pick up renewed certificates, along with a new stapled OCSP response, see
+    /// [`CertWatcher`].  The OCSP response is not fetched by the server, it has to be set
+    /// in the config.
+    pub fn reload_tls(&self, server_config: rustls::ServerConfig) -> Result<()> {
+        let handle = self
+            .relay_handle
+            .as_ref()
+            .context("the relay server is not running")?;
+        let endpoints = self.endpoint_server_configs(&server_config)?;
+        handle.reload_tls(server_config)?;
+        endpoints.apply(self);
+        Ok(())
+    }
+
+    /// Builds the server configs of the QUIC and WebTransport endpoints for new
+    /// certificates, so they are only replaced once all of them are valid.
+    fn endpoint_server_configs(
+        &self,
+        server_config: &rustls::ServerConfig,
+    ) -> Result<EndpointServerConfigs> {
+        let quic = self
+            .quic_handle
+            .as_ref()
+            .map(|_| crate::quic::server::server_config(server_config.clone()))
+            .transpose()
+            .context("invalid QUIC server config")?;
+        let webtransport = self
+            .webtransport_endpoint
+            .as_ref()
+            .map(|_| webtransport::server_config(server_config.clone()))
+            .transpose()?;
+        Ok(EndpointServerConfigs { quic, webtransport })
+    }
+
+    /// The report describing how the server started.
+    ///
+    /// Lists the bound addresses, the TLS setup, the enabled services and the effective
+    /// limits.  The same report is logged once the server started.
+    pub fn startup_report(&self) -> &StartupReport {
+        &self.startup_report
     }
 
     /// The certificates chain if configured with manual TLS certificates.
@@ -516,6 +1430,43 @@ impl Server {
     }
 }
 
+/// Advertises the relay ALPN protocols on the TLS listener of the relay server.
+///
+/// [`RELAY_ALPN`] is preferred over [`H2_ALPN`], if enabled, and [`HTTP_1_1_ALPN`].
+/// Protocols already configured are kept after these, except for [`H2_ALPN`] if HTTP/2 is
+/// disabled.
+fn set_relay_alpns(server_config: &mut rustls::ServerConfig, http2: bool) {
+    let mut alpns = vec![RELAY_ALPN.to_vec()];
+    if http2 {
+        alpns.push(H2_ALPN.to_vec());
+    }
+    alpns.push(HTTP_1_1_ALPN.to_vec());
+    for alpn in std::mem::take(&mut server_config.alpn_protocols) {
+        if !alpns.contains(&alpn) && (http2 || alpn != H2_ALPN) {
+            alpns.push(alpn);
+        }
+    }
+    server_config.alpn_protocols = alpns;
+}
+
+/// The server configs replacing those of the QUIC and WebTransport endpoints.
+#[derive(Default)]
+struct EndpointServerConfigs {
+    quic: Option<quinn::ServerConfig>,
+    webtransport: Option<quinn::ServerConfig>,
+}
+
+impl EndpointServerConfigs {
+    fn apply(self, server: &Server) {
+        if let (Some(handle), Some(config)) = (&server.quic_handle, self.quic) {
+            handle.reload_tls(config);
+        }
+        if let (Some(endpoint), Some(config)) = (&server.webtransport_endpoint, self.webtransport) {
+            endpoint.set_server_config(Some(config));
+        }
+    }
+}
+
 /// Supervisor for the relay server tasks.
 ///
 /// As soon as one of the tasks exits, all other tasks are stopped and the server stops.
@@ -623,14 +1574,17 @@ async fn server_stun_listener(sock: UdpSocket) -> Result<()> {
 
 /// Handles a single STUN request, doing all logging required.
 async fn handle_stun_request(src_addr: SocketAddr, pkt: Vec<u8>, sock: Arc<UdpSocket>) {
+    // Replies are sent to the address as received, but IPv4 clients of a dual-stack socket
+    // are told and counted by their IPv4 address.
+    let peer_addr = canonical_addr(src_addr);
     let (txid, response) = match protos::stun::parse_binding_request(&pkt) {
         Ok(txid) => {
-            debug!(%src_addr, %txid, "STUN: received binding request");
-            (txid, protos::stun::response(txid, src_addr))
+            debug!(%peer_addr, %txid, "STUN: received binding request");
+            (txid, protos::stun::response(txid, peer_addr))
         }
         Err(err) => {
             inc!(StunMetrics, bad_requests);
-            warn!(%src_addr, "STUN: invalid binding request: {:?}", err);
+            warn!(%peer_addr, "STUN: invalid binding request: {:?}", err);
             return;
         }
     };
@@ -639,26 +1593,34 @@ async fn handle_stun_request(src_addr: SocketAddr, pkt: Vec<u8>, sock: Arc<UdpSo
         Ok(len) => {
             if len != response.len() {
                 warn!(
-                    %src_addr,
+                    %peer_addr,
                     %txid,
                     "failed to write response, {len}/{} bytes sent",
                     response.len()
                 );
             } else {
-                match src_addr {
+                match peer_addr {
                     SocketAddr::V4(_) => inc!(StunMetrics, ipv4_success),
                     SocketAddr::V6(_) => inc!(StunMetrics, ipv6_success),
                 }
             }
-            trace!(%src_addr, %txid, "sent {len} bytes");
+            trace!(%peer_addr, %txid, "sent {len} bytes");
         }
         Err(err) => {
             inc!(StunMetrics, failures);
-            warn!(%src_addr, %txid, "failed to write response: {err:#}");
+            warn!(%peer_addr, %txid, "failed to write response: {err:#}");
         }
     }
 }
 
+/// Returns the address with an IPv4-mapped IPv6 address converted to its IPv4 form.
+///
+/// Dual-stack sockets see IPv4 peers as `::ffff:a.b.c.d`, the same host must not look
+/// different depending on the socket it connected to.
+fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
 fn root_handler(
     _r: Request<Incoming>,
     response: ResponseBuilder,
@@ -692,6 +1654,25 @@ fn robots_handler(
         .map_err(|err| Box::new(err) as HyperError)
 }
 
+/// Serves the ECH configs, see [`ECH_CONFIG_PATH`].
+fn ech_config_handler(
+    body: hyper::body::Bytes,
+    response: ResponseBuilder,
+) -> HyperResult<Response<BytesBody>> {
+    response
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(body.into())
+        .map_err(|err| Box::new(err) as HyperError)
+}
+
+/// Builds the draft-ietf-tls-wkech JSON document announcing the ECH config list.
+fn origin_svcb_json(ech_config_list: &[u8]) -> hyper::body::Bytes {
+    // Base64 does not need any JSON escaping.
+    let ech = data_encoding::BASE64.encode(ech_config_list);
+    format!(r#"{{"endpoints":[{{"ech":"{ech}"}}]}}"#).into()
+}
+
 /// For captive portal detection.
 fn serve_no_content_handler<B: hyper::body::Body>(
     r: Request<B>,
@@ -750,6 +1731,7 @@ async fn run_captive_portal_service(http_listener: TcpListener) -> Result<()> {
             res = http_listener.accept() => {
                 match res {
                     Ok((stream, peer_addr)) => {
+                        let peer_addr = canonical_addr(peer_addr);
                         debug!(%peer_addr, "Connection opened",);
                         let handler = CaptivePortalService;
 
@@ -805,7 +1787,10 @@ impl hyper::service::Service<Request<Incoming>> for CaptivePortalService {
 
 #[cfg(test)]
 mod tests {
-    use std::{net::Ipv4Addr, time::Duration};
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr},
+        time::Duration,
+    };
 
     use bytes::Bytes;
     use http::header::UPGRADE;
@@ -816,11 +1801,69 @@ mod tests {
 
     use super::*;
     use crate::{
-        client::{conn::ReceivedMessage, ClientBuilder, SendMessage},
+        client::{conn::ReceivedMessage, ClientBuilder, ConnectionRejected, SendMessage},
         dns::DnsResolver,
         http::{Protocol, HTTP_UPGRADE_PROTOCOL},
+        protos::relay::RejectReason,
     };
 
+    /// Spawns a relay server using TLS with a self signed certificate for `localhost`.
+    async fn spawn_local_tls_relay(alpn_protocols: Vec<Vec<u8>>) -> Result<Server> {
+        let addr = (Ipv4Addr::LOCALHOST, 0).into();
+        spawn_tls_relay(alpn_protocols, addr, addr, Listeners::default()).await
+    }
+
+    async fn spawn_tls_relay(
+        alpn_protocols: Vec<Vec<u8>>,
+        http_bind_addr: SocketAddr,
+        https_bind_addr: SocketAddr,
+        listeners: Listeners,
+    ) -> Result<Server> {
+        let config = tls_relay_config(alpn_protocols, http_bind_addr, https_bind_addr, listeners)?;
+        Server::spawn(config).await
+    }
+
+    /// Returns the config of a relay server using TLS with a self signed certificate for
+    /// `localhost`.
+    fn tls_relay_config(
+        alpn_protocols: Vec<Vec<u8>>,
+        http_bind_addr: SocketAddr,
+        https_bind_addr: SocketAddr,
+        listeners: Listeners,
+    ) -> Result<ServerConfig<(), ()>> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let private_key =
+            rustls::pki_types::PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()).into();
+        let certs = vec![cert.cert.der().clone()];
+        let mut server_config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs.clone(), private_key)?;
+        server_config.alpn_protocols = alpn_protocols;
+        Ok(ServerConfig::<(), ()> {
+            relay: Some(RelayConfig {
+                tls: Some(TlsConfig {
+                    https_bind_addr,
+                    quic_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                    cert: CertConfig::Manual { certs },
+                    server_config,
+                    ech_config_list: None,
+                    http2: false,
+                    webtransport: false,
+                    client_auth: None,
+                }),
+                key_cache_capacity: Some(1024),
+                ..RelayConfig::new(http_bind_addr)
+            }),
+            quic: None,
+            stun: None,
+            metrics: Default::default(),
+            listeners,
+        })
+    }
+
     async fn spawn_local_relay() -> Result<Server> {
         Server::spawn(ServerConfig::<(), ()> {
             relay: Some(RelayConfig::<(), ()> {
@@ -828,11 +1871,32 @@ mod tests {
                 tls: None,
                 limits: Default::default(),
                 key_cache_capacity: Some(1024),
+                key_cache_eviction: Default::default(),
                 access: AccessConfig::Everyone,
+                admin: None,
+                watchdog: None,
+                mesh_key: None,
+                mesh: None,
+                sessions: None,
+                keep_alive: None,
+                payload_compression: false,
+                unknown_peers: None,
+                compression: None,
+                on_disconnect: None,
+                authorizer: None,
+                ipv6_only: None,
+                proxy_protocol: false,
+                trusted_proxies: Vec::new(),
+                access_log: None,
+                error_pages: Default::default(),
+                headers: Default::default(),
+                route_headers: Default::default(),
+                faults: None,
             }),
             quic: None,
             stun: None,
-            metrics_addr: None,
+            metrics: Default::default(),
+            listeners: Default::default(),
         })
         .await
     }
@@ -879,15 +1943,13 @@ mod tests {
     async fn test_conflicting_bind() {
         let mut server = Server::spawn(ServerConfig::<(), ()> {
             relay: Some(RelayConfig {
-                http_bind_addr: (Ipv4Addr::LOCALHOST, 1234).into(),
-                tls: None,
-                limits: Default::default(),
                 key_cache_capacity: Some(1024),
-                access: AccessConfig::Everyone,
+                ..RelayConfig::new((Ipv4Addr::LOCALHOST, 1234).into())
             }),
             stun: None,
             quic: None,
-            metrics_addr: Some((Ipv4Addr::LOCALHOST, 1234).into()),
+            metrics: MetricsExporter::Prometheus((Ipv4Addr::LOCALHOST, 1234).into()),
+            listeners: Default::default(),
         })
         .await
         .unwrap();
@@ -898,6 +1960,55 @@ mod tests {
         assert!(res.is_err()); // AddrInUse
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_metrics_backend() -> TestResult {
+        #[derive(std::fmt::Debug)]
+        struct Backend(tokio::sync::mpsc::UnboundedSender<(&'static str, &'static str)>);
+
+        impl crate::metrics::MetricsBackend for Backend {
+            fn increment_counter(
+                &self,
+                group: &'static str,
+                name: &'static str,
+                _: &'static str,
+                _: u64,
+            ) {
+                self.0.send((group, name)).ok();
+            }
+        }
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let server = Server::spawn(ServerConfig::<(), ()> {
+            relay: None,
+            stun: Some(StunConfig {
+                bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                additional_bind_addrs: Vec::new(),
+            }),
+            quic: None,
+            metrics: MetricsExporter::backend(Backend(tx)),
+            listeners: Default::default(),
+        })
+        .await?;
+
+        // The requests are reported as they are counted.
+        let req = protos::stun::request(protos::stun::TransactionId::default());
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                socket.send_to(&req, server.stun_addr().unwrap()).await?;
+                let reported = tokio::time::timeout(Duration::from_millis(100), async {
+                    while rx.recv().await != Some(("stun", "requests")) {}
+                });
+                if reported.await.is_ok() {
+                    return anyhow::Ok(());
+                }
+            }
+        })
+        .await??;
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_root_handler() {
@@ -906,8 +2017,432 @@ mod tests {
 
         let response = reqwest::get(&url).await.unwrap();
         assert_eq!(response.status(), 200);
+        assert!(response.headers().contains_key("Content-Security-Policy"));
         let body = response.text().await.unwrap();
         assert!(body.contains("iroh.computer"));
+
+        // The probe endpoints do not serve HTML and get no content security policy.
+        let url = format!("http://{}{RELAY_PROBE_PATH}", server.http_addr().unwrap());
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().contains_key("Strict-Transport-Security"));
+        assert!(!response.headers().contains_key("Content-Security-Policy"));
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_startup_report() -> TestResult {
+        let server = spawn_local_tls_relay(Vec::new()).await?;
+        let report = server.startup_report();
+        assert_eq!(report.listeners.https, server.https_addr());
+        assert_eq!(report.listeners.http, server.http_addr());
+        assert_eq!(report.listeners.stun, server.stun_addrs());
+        let tls = report.tls.as_ref().expect("tls");
+        assert_eq!(tls.mode, TlsMode::Manual);
+        assert_eq!(tls.cert_fingerprints.len(), 1);
+        assert!(!tls.client_auth);
+        assert!(report.features.relay);
+        assert!(report.features.captive_portal);
+        assert!(!report.features.admin_api);
+        assert!(!report.features.mesh);
+        assert_eq!(
+            report.limits["client_send_queue_depth"],
+            DEFAULT_SEND_QUEUE_DEPTH
+        );
+        assert!(logs_contain("relay server started"));
+
+        let server = Server::spawn(ServerConfig::<(), ()> {
+            relay: None,
+            stun: Some(StunConfig {
+                bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                additional_bind_addrs: Vec::new(),
+            }),
+            quic: None,
+            metrics: Default::default(),
+            listeners: Default::default(),
+        })
+        .await?;
+        let report = server.startup_report();
+        assert!(report.tls.is_none());
+        assert_eq!(
+            report.features,
+            Features {
+                stun: true,
+                ..Default::default()
+            }
+        );
+        assert!(report.limits.is_null());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_alpn() -> TestResult {
+        let server = spawn_local_tls_relay(vec![H2_ALPN.to_vec(), HTTP_1_1_ALPN.to_vec()]).await?;
+        let https_addr = server.https_addr().unwrap();
+
+        let handshake = |alpns: &[&[u8]]| {
+            let mut config = crate::client::make_dangerous_client_config();
+            config.alpn_protocols = alpns.iter().map(|alpn| alpn.to_vec()).collect();
+            let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+            async move {
+                let stream = tokio::net::TcpStream::connect(https_addr).await?;
+                let server_name = rustls::pki_types::ServerName::try_from("localhost")?;
+                let stream = connector.connect(server_name, stream).await?;
+                let alpn = stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec);
+                anyhow::Ok(alpn)
+            }
+        };
+
+        // The relay ALPN is preferred, even over configured protocols.
+        let alpn = handshake(&[HTTP_1_1_ALPN, RELAY_ALPN]).await?;
+        assert_eq!(alpn.as_deref(), Some(RELAY_ALPN));
+        let alpn = handshake(&[HTTP_1_1_ALPN]).await?;
+        assert_eq!(alpn.as_deref(), Some(HTTP_1_1_ALPN));
+        // HTTP/2 is disabled, the configured protocol is not offered.
+        assert!(handshake(&[H2_ALPN]).await.is_err());
+        let alpn = handshake(&[H2_ALPN, HTTP_1_1_ALPN]).await?;
+        assert_eq!(alpn.as_deref(), Some(HTTP_1_1_ALPN));
+        // Clients without ALPN are accepted, clients offering only unknown protocols not.
+        assert_eq!(handshake(&[]).await?, None);
+        assert!(handshake(&[b"unknown"]).await.is_err());
+
+        // The relay client negotiates the relay ALPN.
+        let relay_url: RelayUrl = format!("https://localhost:{}", https_addr.port()).parse()?;
+        let mut client = ClientBuilder::new(
+            relay_url,
+            SecretKey::generate(rand::thread_rng()),
+            DnsResolver::new(),
+        )
+        .insecure_skip_cert_verify(true)
+        .connect()
+        .await?;
+        client.send(SendMessage::Ping([1u8; 8])).await?;
+        client.next().await.expect("eos")?;
+        assert!(logs_contain("serving relay client with direct framing"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_inherited_listeners() -> TestResult {
+        let old = spawn_local_tls_relay(Vec::new()).await?;
+        let https_addr = old.https_addr().unwrap();
+        let http_addr = old.http_addr().unwrap();
+        let listeners = old.listeners()?;
+        let new = spawn_tls_relay(Vec::new(), http_addr, https_addr, listeners).await?;
+        assert_eq!(new.https_addr(), Some(https_addr));
+        assert_eq!(new.http_addr(), Some(http_addr));
+
+        old.stop_accepting();
+        assert!(old.listeners()?.relay.is_none());
+        old.shutdown().await?;
+
+        // The new server keeps accepting on the sockets of the old one.
+        let relay_url: RelayUrl = format!("https://localhost:{}", https_addr.port()).parse()?;
+        let mut client = ClientBuilder::new(
+            relay_url,
+            SecretKey::generate(rand::thread_rng()),
+            DnsResolver::new(),
+        )
+        .insecure_skip_cert_verify(true)
+        .connect()
+        .await?;
+        client.send(SendMessage::Ping([1u8; 8])).await?;
+        client.next().await.expect("eos")?;
+        let response = reqwest::get(format!("http://{http_addr}/generate_204")).await?;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        // Sockets bound to other addresses than configured are closed.
+        let listeners = new.listeners()?;
+        let addr = (Ipv4Addr::LOCALHOST, 0).into();
+        let _other = spawn_tls_relay(Vec::new(), addr, addr, listeners).await?;
+        assert!(logs_contain("closing inherited socket"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_direct_framing() -> TestResult {
+        let server = spawn_local_tls_relay(Vec::new()).await?;
+        let relay_url: RelayUrl =
+            format!("https://localhost:{}", server.https_addr().unwrap().port()).parse()?;
+        let a_secret_key = SecretKey::generate(rand::thread_rng());
+        let b_secret_key = SecretKey::generate(rand::thread_rng());
+        let b_key = b_secret_key.public();
+
+        // a uses direct framing, b only offers HTTP and upgrades.
+        let mut client_a = ClientBuilder::new(relay_url.clone(), a_secret_key, DnsResolver::new())
+            .insecure_skip_cert_verify(true)
+            .connect()
+            .await?;
+        let mut http_config = crate::client::make_dangerous_client_config();
+        http_config.alpn_protocols = vec![HTTP_1_1_ALPN.to_vec()];
+        let mut client_b = ClientBuilder::new(relay_url, b_secret_key, DnsResolver::new())
+            .rustls_config(Arc::new(http_config))
+            .connect()
+            .await?;
+        assert!(logs_contain("serving relay client with direct framing"));
+        assert!(logs_contain("serving HTTP connection"));
+        let timing = client_a.connect_timing();
+        assert!(timing.dns.is_some() && timing.tcp.is_some() && timing.tls.is_some());
+        assert_eq!(timing.upgrade, None);
+        let timing = client_b.connect_timing();
+        assert!(timing.tls.is_some() && timing.upgrade.is_some());
+        assert!(timing.total() >= timing.tls.unwrap() + timing.upgrade.unwrap());
+
+        let msg = Bytes::from_static(b"hello over direct framing");
+        let res = try_send_recv(&mut client_a, &mut client_b, b_key, msg.clone()).await?;
+        let ReceivedMessage::ReceivedPacket { data, .. } = res else {
+            panic!("client_b received unexpected message {res:?}");
+        };
+        assert_eq!(data, msg);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_config_faults() -> TestResult {
+        let faults = crate::faults::FaultConfig::default();
+        let handle = faults.handle.clone();
+        let mut relay = RelayConfig::<(), ()>::new((Ipv4Addr::LOCALHOST, 0).into());
+        relay.faults = Some(faults);
+        let server = Server::spawn(ServerConfig::<(), ()> {
+            relay: Some(relay),
+            quic: None,
+            stun: None,
+            metrics: Default::default(),
+            listeners: Default::default(),
+        })
+        .await?;
+        let relay_url: RelayUrl = format!("http://{}", server.http_addr().unwrap()).parse()?;
+        let mut client = ClientBuilder::new(
+            relay_url,
+            SecretKey::generate(rand::thread_rng()),
+            DnsResolver::new(),
+        )
+        .connect()
+        .await?;
+        client.send(SendMessage::Ping([1u8; 8])).await?;
+        client.next().await.expect("eos")?;
+
+        // The server side of the connection breaks.
+        handle.disconnect();
+        client.send(SendMessage::Ping([2u8; 8])).await.ok();
+        let res = tokio::time::timeout(Duration::from_secs(5), client.next()).await?;
+        assert!(!matches!(res, Some(Ok(ReceivedMessage::Pong(_)))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_webtransport() -> TestResult {
+        let addr = (Ipv4Addr::LOCALHOST, 0).into();
+        let mut config = tls_relay_config(Vec::new(), addr, addr, Listeners::default())?;
+        let (records_tx, mut records) = tokio::sync::mpsc::channel(16);
+        if let Some(relay) = config.relay.as_mut() {
+            relay.access_log = Some(AccessLog::Channel(records_tx));
+            if let Some(tls) = relay.tls.as_mut() {
+                tls.webtransport = true;
+            }
+        }
+        let server = Server::spawn(config).await?;
+        let webtransport_addr = server.webtransport_addr().context("no WebTransport")?;
+        assert_eq!(
+            webtransport_addr.port(),
+            server.https_addr().unwrap().port()
+        );
+        let relay_url: RelayUrl =
+            format!("https://localhost:{}", webtransport_addr.port()).parse()?;
+
+        let a_secret_key = SecretKey::generate(rand::thread_rng());
+        let a_key = a_secret_key.public();
+        let b_secret_key = SecretKey::generate(rand::thread_rng());
+        let b_key = b_secret_key.public();
+        let mut client_a = ClientBuilder::new(relay_url.clone(), a_secret_key, DnsResolver::new())
+            .insecure_skip_cert_verify(true)
+            .protocol(Protocol::WebTransport)
+            .connect()
+            .await?;
+        // b uses the relay framing over TLS.
+        let mut client_b = ClientBuilder::new(relay_url.clone(), b_secret_key, DnsResolver::new())
+            .insecure_skip_cert_verify(true)
+            .connect()
+            .await?;
+        assert!(logs_contain("session established"));
+        // The session is recorded like an upgrade, once the client finished its handshake.
+        let record = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let record = records.recv().await.context("no record")?;
+                if record.node_id == Some(a_key) {
+                    return anyhow::Ok(record);
+                }
+            }
+        })
+        .await??;
+        assert_eq!(record.method, http::Method::CONNECT);
+        assert_eq!(record.path, crate::http::RELAY_PATH);
+        assert_eq!(record.status, StatusCode::OK);
+        assert_eq!(record.peer.ip(), Ipv4Addr::LOCALHOST);
+        let timing = client_a.connect_timing();
+        assert!(timing.tls.is_some() && timing.upgrade.is_some());
+        assert_eq!(timing.tcp, None);
+
+        let msg = Bytes::from_static(b"hello over webtransport");
+        let res = try_send_recv(&mut client_a, &mut client_b, b_key, msg.clone()).await?;
+        let ReceivedMessage::ReceivedPacket { data, .. } = res else {
+            panic!("client_b received unexpected message {res:?}");
+        };
+        assert_eq!(data, msg);
+        let msg = Bytes::from_static(b"hello back");
+        let res = try_send_recv(&mut client_b, &mut client_a, a_key, msg.clone()).await?;
+        let ReceivedMessage::ReceivedPacket { data, .. } = res else {
+            panic!("client_a received unexpected message {res:?}");
+        };
+        assert_eq!(data, msg);
+        drop(client_a);
+        server.shutdown().await?;
+
+        // Plain HTTP relays have no WebTransport endpoint.
+        let server = spawn_local_relay().await?;
+        assert_eq!(server.webtransport_addr(), None);
+        let relay_url: RelayUrl = format!("http://{}", server.http_addr().unwrap()).parse()?;
+        let res = ClientBuilder::new(
+            relay_url,
+            SecretKey::generate(rand::thread_rng()),
+            DnsResolver::new(),
+        )
+        .protocol(Protocol::WebTransport)
+        .connect()
+        .await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_reload_tls_quic_endpoints() -> TestResult {
+        /// Returns the certificate served by the QUIC endpoint.
+        async fn served_cert(
+            addr: SocketAddr,
+            alpn: &[u8],
+        ) -> Result<rustls::pki_types::CertificateDer<'static>> {
+            let mut config = crate::client::make_dangerous_client_config();
+            config.alpn_protocols = vec![alpn.to_vec()];
+            let config = quinn::ClientConfig::new(Arc::new(
+                quinn::crypto::rustls::QuicClientConfig::try_from(config)?,
+            ));
+            let endpoint = quinn::Endpoint::client((Ipv4Addr::LOCALHOST, 0).into())?;
+            let conn = endpoint.connect_with(config, addr, "localhost")?.await?;
+            let certs = conn
+                .peer_identity()
+                .context("no identity")?
+                .downcast::<Vec<rustls::pki_types::CertificateDer<'static>>>()
+                .ok()
+                .context("no certificates")?;
+            conn.close(0u32.into(), b"");
+            Ok(certs[0].clone())
+        }
+
+        let addr = (Ipv4Addr::LOCALHOST, 0).into();
+        let mut config = tls_relay_config(Vec::new(), addr, addr, Listeners::default())?;
+        let tls = config
+            .relay
+            .as_mut()
+            .and_then(|relay| relay.tls.as_mut())
+            .context("no TLS")?;
+        tls.webtransport = true;
+        config.quic = Some(QuicConfig {
+            bind_addr: addr,
+            server_config: tls.server_config.clone(),
+        });
+        let server = Server::spawn(config).await?;
+        let quic_addr = server.quic_addr().context("no QUIC")?;
+        let webtransport_addr = server.webtransport_addr().context("no WebTransport")?;
+        let quic_cert = served_cert(quic_addr, crate::quic::ALPN_QUIC_ADDR_DISC).await?;
+        let webtransport_cert = served_cert(webtransport_addr, crate::http::H3_ALPN).await?;
+
+        let reloaded = tls_relay_config(Vec::new(), addr, addr, Listeners::default())?
+            .relay
+            .and_then(|relay| relay.tls)
+            .context("no TLS")?
+            .server_config;
+        server.reload_tls(reloaded)?;
+        let reloaded_cert = served_cert(quic_addr, crate::quic::ALPN_QUIC_ADDR_DISC).await?;
+        assert_ne!(reloaded_cert, quic_cert);
+        assert_eq!(
+            served_cert(webtransport_addr, crate::http::H3_ALPN).await?,
+            reloaded_cert
+        );
+        assert_ne!(reloaded_cert, webtransport_cert);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_ech_config_handler() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let private_key =
+            rustls::pki_types::PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()).into();
+        let certs = vec![cert.cert.der().clone()];
+        let server_config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(certs.clone(), private_key)
+        .unwrap();
+        let server = Server::spawn(ServerConfig::<(), ()> {
+            relay: Some(RelayConfig {
+                tls: Some(TlsConfig {
+                    https_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                    quic_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                    cert: CertConfig::Manual { certs },
+                    server_config,
+                    ech_config_list: Some(b"not really an ECHConfigList".to_vec()),
+                    http2: false,
+                    webtransport: false,
+                    client_auth: None,
+                }),
+                key_cache_capacity: Some(1024),
+                ..RelayConfig::new((Ipv4Addr::LOCALHOST, 0).into())
+            }),
+            quic: None,
+            stun: None,
+            metrics: Default::default(),
+            listeners: Default::default(),
+        })
+        .await
+        .unwrap();
+
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let url = format!(
+            "https://localhost:{}{ECH_CONFIG_PATH}",
+            server.https_addr().unwrap().port()
+        );
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        let ech = body["endpoints"][0]["ech"].as_str().unwrap();
+        assert_eq!(
+            data_encoding::BASE64.decode(ech.as_bytes()).unwrap(),
+            b"not really an ECHConfigList"
+        );
+
+        // Not published without TLS.
+        let server = spawn_local_relay().await.unwrap();
+        let url = format!("http://{}{ECH_CONFIG_PATH}", server.http_addr().unwrap());
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
 
     #[tokio::test]
@@ -970,6 +2505,10 @@ mod tests {
         let mut client_b = ClientBuilder::new(relay_url.clone(), b_secret_key, resolver.clone())
             .connect()
             .await?;
+        // Plain HTTP, without TLS.
+        let timing = client_a.connect_timing();
+        assert_eq!(timing.tls, None);
+        assert!(timing.dns.is_some() && timing.tcp.is_some() && timing.upgrade.is_some());
 
         // send message from a to b
         let msg = Bytes::from("hello, b");
@@ -1001,6 +2540,139 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_mesh() -> TestResult<()> {
+        // Bound up front, so each relay can be configured with the URL of the other one.
+        let listeners: Vec<_> = (0..2)
+            .map(|_| std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)))
+            .collect::<std::io::Result<_>>()?;
+        let urls: Vec<RelayUrl> = listeners
+            .iter()
+            .map(|listener| Ok(format!("http://{}", listener.local_addr()?).parse()?))
+            .collect::<Result<_>>()?;
+        let mesh_key = MeshKey::generate();
+        let mut servers = Vec::new();
+        for (i, listener) in listeners.into_iter().enumerate() {
+            let http_bind_addr = listener.local_addr()?;
+            let server = Server::spawn(ServerConfig::<(), ()> {
+                relay: Some(RelayConfig::<(), ()> {
+                    http_bind_addr,
+                    tls: None,
+                    limits: Default::default(),
+                    key_cache_capacity: Some(1024),
+                    key_cache_eviction: Default::default(),
+                    access: AccessConfig::Everyone,
+                    admin: None,
+                    watchdog: None,
+                    mesh_key: Some(mesh_key.clone()),
+                    mesh: Some(MeshConfig {
+                        peers: vec![urls[1 - i].clone()],
+                    }),
+                    sessions: None,
+                    keep_alive: None,
+                    payload_compression: false,
+                    unknown_peers: None,
+                    compression: None,
+                    on_disconnect: None,
+                    authorizer: None,
+                    ipv6_only: None,
+                    proxy_protocol: false,
+                    trusted_proxies: Vec::new(),
+                    access_log: None,
+                    error_pages: Default::default(),
+                    headers: Default::default(),
+                    route_headers: Default::default(),
+                    faults: None,
+                }),
+                quic: None,
+                stun: None,
+                metrics: Default::default(),
+                listeners: Listeners {
+                    relay: Some(listener),
+                    ..Default::default()
+                },
+            })
+            .await?;
+            servers.push(server);
+        }
+
+        let resolver = dns_resolver();
+        let a_secret_key = SecretKey::generate(rand::thread_rng());
+        let a_key = a_secret_key.public();
+        let mut client_a = ClientBuilder::new(urls[0].clone(), a_secret_key, resolver.clone())
+            .connect()
+            .await?;
+        let b_secret_key = SecretKey::generate(rand::thread_rng());
+        let b_key = b_secret_key.public();
+        let mut client_b = ClientBuilder::new(urls[1].clone(), b_secret_key, resolver)
+            .connect()
+            .await?;
+
+        // Resent until the relays learned where the other client is connected.
+        let msg = Bytes::from("hello over the mesh");
+        let res = try_send_recv(&mut client_a, &mut client_b, b_key, msg.clone()).await?;
+        let ReceivedMessage::ReceivedPacket {
+            remote_node_id,
+            data,
+        } = res
+        else {
+            panic!("client_b received unexpected message {res:?}");
+        };
+        assert_eq!(remote_node_id, a_key);
+        assert_eq!(data, msg);
+
+        let msg = Bytes::from("and back");
+        let res = try_send_recv(&mut client_b, &mut client_a, a_key, msg.clone()).await?;
+        assert!(
+            matches!(res, ReceivedMessage::ReceivedPacket { remote_node_id, data } if remote_node_id == b_key && data == msg)
+        );
+
+        for server in servers {
+            server.shutdown().await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_relay_mesh_needs_mesh_key() {
+        let res = Server::spawn(ServerConfig::<(), ()> {
+            relay: Some(RelayConfig::<(), ()> {
+                http_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                tls: None,
+                limits: Default::default(),
+                key_cache_capacity: Some(1024),
+                key_cache_eviction: Default::default(),
+                access: AccessConfig::Everyone,
+                admin: None,
+                watchdog: None,
+                mesh_key: None,
+                mesh: Some(MeshConfig::default()),
+                sessions: None,
+                keep_alive: None,
+                payload_compression: false,
+                unknown_peers: None,
+                compression: None,
+                on_disconnect: None,
+                authorizer: None,
+                ipv6_only: None,
+                proxy_protocol: false,
+                trusted_proxies: Vec::new(),
+                access_log: None,
+                error_pages: Default::default(),
+                headers: Default::default(),
+                route_headers: Default::default(),
+                faults: None,
+            }),
+            quic: None,
+            stun: None,
+            metrics: Default::default(),
+            listeners: Default::default(),
+        })
+        .await;
+        assert!(res.is_err());
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_relay_clients_both_websockets() -> TestResult<()> {
@@ -1126,9 +2798,11 @@ mod tests {
             relay: None,
             stun: Some(StunConfig {
                 bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                additional_bind_addrs: Vec::new(),
             }),
             quic: None,
-            metrics_addr: None,
+            metrics: Default::default(),
+            listeners: Default::default(),
         })
         .await
         .unwrap();
@@ -1151,6 +2825,176 @@ mod tests {
         assert_eq!(response_addr, socket.local_addr().unwrap());
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_stun_ipv4_mapped() {
+        // Dual-stack sockets see IPv4 clients with IPv4-mapped addresses, clients are
+        // still told their IPv4 address.
+        let server = Server::spawn(ServerConfig::<(), ()> {
+            relay: None,
+            stun: Some(StunConfig {
+                bind_addr: (Ipv6Addr::UNSPECIFIED, 0).into(),
+                additional_bind_addrs: Vec::new(),
+            }),
+            quic: None,
+            metrics: Default::default(),
+            listeners: Default::default(),
+        })
+        .await
+        .unwrap();
+
+        let txid = protos::stun::TransactionId::default();
+        let req = protos::stun::request(txid);
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.stun_addr().unwrap().port();
+        socket
+            .send_to(&req, (Ipv4Addr::LOCALHOST, port))
+            .await
+            .unwrap();
+
+        let mut buf = vec![0u8; 64000];
+        let (len, _addr) = socket.recv_from(&mut buf).await.unwrap();
+        buf.truncate(len);
+        let (txid_back, response_addr) = protos::stun::parse_response(&buf).unwrap();
+        assert_eq!(txid, txid_back);
+        assert_eq!(response_addr, socket.local_addr().unwrap());
+    }
+
+    #[test]
+    fn test_canonical_addr() {
+        let mapped: SocketAddr = "[::ffff:192.0.2.1]:1234".parse().unwrap();
+        assert_eq!(canonical_addr(mapped), "192.0.2.1:1234".parse().unwrap());
+        let v6: SocketAddr = "[2001:db8::1]:1234".parse().unwrap();
+        assert_eq!(canonical_addr(v6), v6);
+        let v4: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+        assert_eq!(canonical_addr(v4), v4);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_stun_multiple_addrs() {
+        let server = Server::spawn(ServerConfig::<(), ()> {
+            relay: None,
+            stun: Some(StunConfig {
+                bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                additional_bind_addrs: vec![(Ipv4Addr::LOCALHOST, 0).into()],
+            }),
+            quic: None,
+            metrics: Default::default(),
+            listeners: Default::default(),
+        })
+        .await
+        .unwrap();
+
+        let addrs = server.stun_addrs();
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(server.stun_addr(), Some(addrs[0]));
+        assert_ne!(addrs[0], addrs[1]);
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for &stun_addr in addrs {
+            let txid = protos::stun::TransactionId::default();
+            let req = protos::stun::request(txid);
+            socket.send_to(&req, stun_addr).await.unwrap();
+
+            let mut buf = vec![0u8; 64000];
+            let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(addr, stun_addr);
+            buf.truncate(len);
+            let (txid_back, response_addr) = protos::stun::parse_response(&buf).unwrap();
+            assert_eq!(txid, txid_back);
+            assert_eq!(response_addr, socket.local_addr().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_disconnect_hook() -> Result<()> {
+        let (disconnect_tx, mut disconnect_rx) = tokio::sync::mpsc::unbounded_channel();
+        let server = Server::spawn(ServerConfig::<(), ()> {
+            relay: Some(RelayConfig::<(), ()> {
+                http_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                tls: None,
+                limits: Default::default(),
+                key_cache_capacity: Some(1024),
+                key_cache_eviction: Default::default(),
+                access: AccessConfig::Everyone,
+                admin: None,
+                watchdog: None,
+                mesh_key: None,
+                mesh: None,
+                sessions: None,
+                keep_alive: None,
+                payload_compression: false,
+                unknown_peers: None,
+                compression: None,
+                on_disconnect: Some(DisconnectHook::new(move |disconnect| {
+                    disconnect_tx.send(disconnect.clone()).ok();
+                })),
+                authorizer: None,
+                ipv6_only: None,
+                proxy_protocol: false,
+                trusted_proxies: Vec::new(),
+                access_log: None,
+                error_pages: Default::default(),
+                headers: Default::default(),
+                route_headers: Default::default(),
+                faults: None,
+            }),
+            quic: None,
+            stun: None,
+            metrics: Default::default(),
+            listeners: Default::default(),
+        })
+        .await?;
+        let relay_url: RelayUrl = format!("http://{}", server.http_addr().unwrap()).parse()?;
+        async fn next_disconnect(
+            rx: &mut tokio::sync::mpsc::UnboundedReceiver<Disconnect>,
+        ) -> Disconnect {
+            tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .expect("timeout")
+                .expect("hook dropped")
+        }
+
+        // A client closing gracefully.
+        let a_secret_key = SecretKey::generate(rand::thread_rng());
+        let mut client_a = ClientBuilder::new(relay_url.clone(), a_secret_key, dns_resolver())
+            .connect()
+            .await?;
+        client_a.send(SendMessage::Ping([1u8; 8])).await?;
+        client_a.next().await.context("eos")??;
+        client_a.close().await?;
+        let disconnect = next_disconnect(&mut disconnect_rx).await;
+        assert_eq!(disconnect.reason, DisconnectReason::Closing);
+        assert_eq!(disconnect.unflushed_packets, 0);
+
+        // A client replaced by a new connection of the same node.
+        let b_secret_key = SecretKey::generate(rand::thread_rng());
+        let mut client_b =
+            ClientBuilder::new(relay_url.clone(), b_secret_key.clone(), dns_resolver())
+                .connect()
+                .await?;
+        client_b.send(SendMessage::Ping([1u8; 8])).await?;
+        client_b.next().await.context("eos")??;
+        let mut client_b2 =
+            ClientBuilder::new(relay_url.clone(), b_secret_key.clone(), dns_resolver())
+                .connect()
+                .await?;
+        client_b2.send(SendMessage::Ping([1u8; 8])).await?;
+        client_b2.next().await.context("eos")??;
+        let disconnect = next_disconnect(&mut disconnect_rx).await;
+        assert_eq!(disconnect.node_id, b_secret_key.public());
+        assert_eq!(disconnect.reason, DisconnectReason::Replaced);
+
+        // The remaining client is closed by the server.
+        server.shutdown().await?;
+        let disconnect = next_disconnect(&mut disconnect_rx).await;
+        assert_eq!(disconnect.node_id, b_secret_key.public());
+        assert_eq!(disconnect.reason, DisconnectReason::ServerClosed);
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_relay_access_control() -> Result<()> {
@@ -1163,6 +3007,7 @@ mod tests {
                 tls: None,
                 limits: Default::default(),
                 key_cache_capacity: Some(1024),
+                key_cache_eviction: Default::default(),
                 access: AccessConfig::Restricted(Box::new(move |node_id| {
                     async move {
                         info!("checking {}", node_id);
@@ -1175,10 +3020,30 @@ mod tests {
                     }
                     .boxed()
                 })),
+                admin: None,
+                watchdog: None,
+                mesh_key: None,
+                mesh: None,
+                sessions: None,
+                keep_alive: None,
+                payload_compression: false,
+                unknown_peers: None,
+                compression: None,
+                on_disconnect: None,
+                authorizer: None,
+                ipv6_only: None,
+                proxy_protocol: false,
+                trusted_proxies: Vec::new(),
+                access_log: None,
+                error_pages: Default::default(),
+                headers: Default::default(),
+                route_headers: Default::default(),
+                faults: None,
             }),
             quic: None,
             stun: None,
-            metrics_addr: None,
+            metrics: Default::default(),
+            listeners: Default::default(),
         })
         .await
         .unwrap();
@@ -1201,6 +3066,12 @@ mod tests {
                     panic!("other msg: {:?}", msg);
                 }
             }
+            // followed by the machine-readable reason
+            let err = client_a.next().await.unwrap().unwrap_err();
+            let rejected = err
+                .downcast_ref::<ConnectionRejected>()
+                .expect("rejection error");
+            assert_eq!(rejected.reason(), &RejectReason::Banned);
         })
         .await?;
 
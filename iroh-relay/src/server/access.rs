@@ -0,0 +1,157 @@
+//! Lists of nodes for the access control of the relay server.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{Context, Result};
+use iroh_base::NodeId;
+use tracing::info;
+
+/// A set of nodes which can be replaced while the server is running.
+///
+/// Used by [`AccessConfig::Allowlist`] and [`AccessConfig::Denylist`].  Clones share the
+/// same set, so a handle kept by the application can replace the nodes of a running server.
+///
+/// A list loaded with [`NodeList::load`] remembers its file and can re-read it with
+/// [`NodeList::reload`], e.g. on `SIGHUP` or through the `POST /admin/access/reload`
+/// endpoint of the admin API.  The file contains one node ID per line, empty lines and
+/// lines starting with `#` are ignored.
+///
+/// [`AccessConfig::Allowlist`]: super::AccessConfig::Allowlist
+/// [`AccessConfig::Denylist`]: super::AccessConfig::Denylist
+#[derive(Debug, Clone, Default)]
+pub struct NodeList {
+    nodes: Arc<RwLock<HashSet<NodeId>>>,
+    path: Option<Arc<PathBuf>>,
+}
+
+impl NodeList {
+    /// Creates a list of these nodes.
+    pub fn new(nodes: impl IntoIterator<Item = NodeId>) -> Self {
+        Self {
+            nodes: Arc::new(RwLock::new(nodes.into_iter().collect())),
+            path: None,
+        }
+    }
+
+    /// Loads the list from a file.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let nodes = read_nodes(&path)?;
+        Ok(Self {
+            nodes: Arc::new(RwLock::new(nodes)),
+            path: Some(Arc::new(path)),
+        })
+    }
+
+    /// Re-reads the file the list was loaded from, returning the number of nodes.
+    ///
+    /// The list is left unchanged if the file can not be read or contains invalid node
+    /// IDs.  Fails for lists not loaded from a file.
+    pub fn reload(&self) -> Result<usize> {
+        let path = self
+            .path
+            .as_ref()
+            .context("node list not loaded from a file")?;
+        let nodes = read_nodes(path)?;
+        let len = nodes.len();
+        *self.nodes.write().expect("poisoned") = nodes;
+        info!(path = %path.display(), nodes = len, "reloaded node list");
+        Ok(len)
+    }
+
+    /// Replaces the nodes of the list.
+    pub fn replace(&self, nodes: impl IntoIterator<Item = NodeId>) {
+        *self.nodes.write().expect("poisoned") = nodes.into_iter().collect();
+    }
+
+    /// Whether the node is in the list.
+    pub fn contains(&self, node: &NodeId) -> bool {
+        self.nodes.read().expect("poisoned").contains(node)
+    }
+
+    /// The number of nodes in the list.
+    pub fn len(&self) -> usize {
+        self.nodes.read().expect("poisoned").len()
+    }
+
+    /// Whether the list is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The file the list was loaded from, if any.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref().map(PathBuf::as_path)
+    }
+}
+
+/// Reads the node IDs of a node list file.
+fn read_nodes(path: &Path) -> Result<HashSet<NodeId>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read node list {}", path.display()))?;
+    parse_nodes(&content).with_context(|| format!("invalid node list {}", path.display()))
+}
+
+fn parse_nodes(content: &str) -> Result<HashSet<NodeId>> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            line.parse()
+                .with_context(|| format!("line {}: invalid node ID", i + 1))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::SecretKey;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_nodes() {
+        let a = SecretKey::generate(rand::thread_rng()).public();
+        let b = SecretKey::generate(rand::thread_rng()).public();
+        let content = format!("# operators\n{a}\n\n  {b}  \n");
+        let nodes = parse_nodes(&content).unwrap();
+        assert_eq!(nodes, HashSet::from([a, b]));
+
+        let err = parse_nodes(&format!("{a}\nnot-a-node\n")).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: invalid node ID");
+    }
+
+    #[test]
+    fn test_reload() -> Result<()> {
+        let a = SecretKey::generate(rand::thread_rng()).public();
+        let b = SecretKey::generate(rand::thread_rng()).public();
+        let path =
+            std::env::temp_dir().join(format!("iroh-relay-allowlist-{}", rand::random::<u64>()));
+        std::fs::write(&path, format!("{a}\n"))?;
+
+        let list = NodeList::load(&path)?;
+        let shared = list.clone();
+        assert!(shared.contains(&a));
+        assert!(!shared.contains(&b));
+
+        std::fs::write(&path, format!("{b}\n"))?;
+        assert_eq!(list.reload()?, 1);
+        assert!(!shared.contains(&a));
+        assert!(shared.contains(&b));
+
+        // An invalid file keeps the previous nodes.
+        std::fs::write(&path, "invalid\n")?;
+        assert!(list.reload().is_err());
+        assert!(shared.contains(&b));
+
+        assert!(NodeList::new([a]).reload().is_err());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,296 @@
+//! Structured access logs of the Relay HTTP(S) server.
+//!
+//! One [`AccessRecord`] is written per HTTP request, including the requests for WebTransport
+//! sessions.  Requests upgrading the connection to the relay protocol, accepted WebTransport
+//! sessions and connections speaking the relay protocol right away are recorded once the
+//! client finished its handshake, so the record carries the node ID of the client.  Records are
+//! written off the request path, they are dropped if the log does not keep up.
+
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use http::{Method, Request, StatusCode};
+use iroh_base::{inc, NodeId};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    time::Instant,
+};
+use tokio_util::task::AbortOnDropHandle;
+use tracing::{info_span, warn, Instrument};
+
+use super::Metrics;
+use crate::http::RELAY_PATH;
+
+/// The number of records queued for the writer of a file or stdout.
+const QUEUE_DEPTH: usize = 1024;
+
+/// Where the access records of the Relay HTTP(S) server are written to.
+#[derive(Debug, Clone)]
+pub enum AccessLog {
+    /// Appends the records to the file, one JSON object per line.
+    File(PathBuf),
+    /// Writes the records to stdout, one JSON object per line.
+    Stdout,
+    /// Sends the records to the channel.
+    ///
+    /// Records are dropped while the channel is full.
+    Channel(mpsc::Sender<AccessRecord>),
+}
+
+impl AccessLog {
+    /// Returns the name of the sink, for the effective configuration.
+    pub(super) fn kind(&self) -> &'static str {
+        match self {
+            Self::File(_) => "file",
+            Self::Stdout => "stdout",
+            Self::Channel(_) => "channel",
+        }
+    }
+}
+
+/// A request served by the Relay HTTP(S) server, see [`AccessLog`].
+///
+/// Connections speaking the relay protocol right away, see [`RELAY_ALPN`], are recorded as
+/// a `CONNECT` request to the [`RELAY_PATH`].
+///
+/// [`RELAY_ALPN`]: crate::http::RELAY_ALPN
+/// [`RELAY_PATH`]: crate::http::RELAY_PATH
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessRecord {
+    /// When the request was received.
+    pub time: SystemTime,
+    /// The method of the request.
+    pub method: Method,
+    /// The path of the request.
+    pub path: String,
+    /// The status of the response.
+    pub status: StatusCode,
+    /// The address of the peer of the connection.
+    pub peer: SocketAddr,
+    /// The node of the client, if the request upgraded the connection to the relay protocol
+    /// and the client completed its handshake.
+    pub node_id: Option<NodeId>,
+    /// How long it took to answer the request, for upgrades until the client completed
+    /// its handshake.
+    pub duration: Duration,
+    /// The size of the response body, in bytes.
+    ///
+    /// The data relayed over upgraded connections is not included.
+    pub bytes: u64,
+}
+
+impl AccessRecord {
+    /// Formats the record as a line of JSON.
+    fn to_json_line(&self) -> String {
+        let timestamp_ms = self
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|time| time.as_millis())
+            .unwrap_or_default();
+        let mut line = serde_json::json!({
+            "timestamp_ms": timestamp_ms,
+            "method": self.method.as_str(),
+            "path": self.path,
+            "status": self.status.as_u16(),
+            "peer": self.peer,
+            "node_id": self.node_id.map(|node_id| node_id.to_string()),
+            "duration_ms": self.duration.as_millis(),
+            "bytes": self.bytes,
+        })
+        .to_string();
+        line.push('\n');
+        line
+    }
+}
+
+/// A request which is recorded once it is answered.
+#[derive(Debug, Clone)]
+pub(super) struct PendingAccess {
+    time: SystemTime,
+    start: Instant,
+    method: Method,
+    path: String,
+    peer: SocketAddr,
+    status: StatusCode,
+    bytes: u64,
+}
+
+impl PendingAccess {
+    pub(super) fn new<B>(req: &Request<B>, peer: SocketAddr) -> Self {
+        Self {
+            time: SystemTime::now(),
+            start: Instant::now(),
+            method: req.method().clone(),
+            path: req.uri().path().to_string(),
+            peer,
+            status: StatusCode::OK,
+            bytes: 0,
+        }
+    }
+
+    /// Returns the record of a connection speaking the relay protocol right away.
+    pub(super) fn direct(peer: SocketAddr) -> Self {
+        Self::request(Method::CONNECT, RELAY_PATH.to_string(), peer)
+    }
+
+    /// Returns the record of a request which is not a [`Request`], e.g. the request for a
+    /// WebTransport session.
+    pub(super) fn request(method: Method, path: String, peer: SocketAddr) -> Self {
+        Self {
+            time: SystemTime::now(),
+            start: Instant::now(),
+            method,
+            path,
+            peer,
+            status: StatusCode::OK,
+            bytes: 0,
+        }
+    }
+
+    /// Sets the response the request was answered with.
+    pub(super) fn respond(&mut self, status: StatusCode, bytes: u64) {
+        self.status = status;
+        self.bytes = bytes;
+    }
+
+    /// Completes the record, taking the duration until now.
+    pub(super) fn finish(self, node_id: Option<NodeId>) -> AccessRecord {
+        AccessRecord {
+            time: self.time,
+            method: self.method,
+            path: self.path,
+            status: self.status,
+            peer: self.peer,
+            node_id,
+            duration: self.start.elapsed(),
+            bytes: self.bytes,
+        }
+    }
+}
+
+/// Hands the access records to the configured [`AccessLog`].
+#[derive(Debug)]
+pub(super) struct AccessLogger {
+    records: mpsc::Sender<AccessRecord>,
+    /// Writes the records to the file or stdout.
+    _writer: Option<AbortOnDropHandle<()>>,
+}
+
+impl AccessLogger {
+    /// Creates the logger, opening the file of the log.
+    ///
+    /// Must be called in the context of a tokio runtime to spawn the writer.
+    pub(super) fn new(log: AccessLog) -> Result<Self> {
+        let (records, writer) = match log {
+            AccessLog::Channel(records) => (records, None),
+            AccessLog::Stdout => {
+                let (tx, rx) = mpsc::channel(QUEUE_DEPTH);
+                (tx, Some(spawn_writer(tokio::io::stdout(), rx)))
+            }
+            AccessLog::File(path) => {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .with_context(|| format!("failed to open access log {}", path.display()))?;
+                let (tx, rx) = mpsc::channel(QUEUE_DEPTH);
+                let writer = spawn_writer(tokio::fs::File::from_std(file), rx);
+                (tx, Some(writer))
+            }
+        };
+        Ok(Self {
+            records,
+            _writer: writer,
+        })
+    }
+
+    /// Records a request, dropping the record if the log does not keep up.
+    pub(super) fn log(&self, record: AccessRecord) {
+        if self.records.try_send(record).is_err() {
+            inc!(Metrics, access_records_dropped);
+        }
+    }
+}
+
+fn spawn_writer(
+    out: impl AsyncWrite + Send + Unpin + 'static,
+    records: mpsc::Receiver<AccessRecord>,
+) -> AbortOnDropHandle<()> {
+    AbortOnDropHandle::new(tokio::task::spawn(
+        write_records(out, records).instrument(info_span!("access-log")),
+    ))
+}
+
+/// Writes the records as they arrive, flushing whenever the queue is empty.
+async fn write_records(
+    mut out: impl AsyncWrite + Unpin,
+    mut records: mpsc::Receiver<AccessRecord>,
+) {
+    while let Some(record) = records.recv().await {
+        let mut lines = record.to_json_line();
+        while let Ok(record) = records.try_recv() {
+            lines.push_str(&record.to_json_line());
+        }
+        let res = match out.write_all(lines.as_bytes()).await {
+            Ok(()) => out.flush().await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = res {
+            warn!("failed to write access log: {err:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::SecretKey;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_access_log_file() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("iroh-relay-access-{}.log", rand::random::<u64>()));
+        let node_id = SecretKey::generate(rand::thread_rng()).public();
+        let req = Request::get("/relay").body(())?;
+        let mut pending = PendingAccess::new(&req, "192.0.2.1:4000".parse()?);
+        pending.respond(StatusCode::SWITCHING_PROTOCOLS, 0);
+
+        let logger = AccessLogger::new(AccessLog::File(path.clone()))?;
+        logger.log(pending.finish(Some(node_id)));
+        let req = Request::get("/missing").body(())?;
+        let mut pending = PendingAccess::new(&req, "192.0.2.2:4000".parse()?);
+        pending.respond(StatusCode::NOT_FOUND, 9);
+        logger.log(pending.finish(None));
+
+        let lines = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let content = tokio::fs::read_to_string(&path).await.unwrap_or_default();
+                if content.lines().count() == 2 {
+                    break content;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        let records: Vec<serde_json::Value> = lines
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(records[0]["method"], "GET");
+        assert_eq!(records[0]["path"], "/relay");
+        assert_eq!(records[0]["status"], 101);
+        assert_eq!(records[0]["peer"], "192.0.2.1:4000");
+        assert_eq!(records[0]["node_id"], node_id.to_string());
+        assert_eq!(records[1]["status"], 404);
+        assert_eq!(records[1]["node_id"], serde_json::Value::Null);
+        assert_eq!(records[1]["bytes"], 9);
+        tokio::fs::remove_file(path).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+//! Watching certificate files for changes, to reload them with [`Server::reload_tls`].
+//!
+//! [`Server::reload_tls`]: super::Server::reload_tls
+
+use std::{path::PathBuf, time::SystemTime};
+
+use n0_future::time::{self, Duration};
+
+/// The default interval at which the [`CertWatcher`] checks the files.
+pub const DEFAULT_CERT_WATCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Watches the files of a certificate, e.g. renewed by certbot, for changes.
+///
+/// Usually watches the certificate chain, the private key and the OCSP response to staple.
+/// Once [`CertWatcher::changed`] returns, the files are loaded into a new
+/// [`rustls::ServerConfig`] and handed to [`Server::reload_tls`].
+///
+/// The server does not fetch OCSP responses itself.  A stapled response has to be fetched
+/// from the OCSP responder of the certificate issuer, e.g. with `openssl ocsp -respout`,
+/// and written to a watched file before the previous one expires.
+///
+/// [`Server::reload_tls`]: super::Server::reload_tls
+#[derive(Debug)]
+pub struct CertWatcher {
+    files: Vec<PathBuf>,
+    interval: Duration,
+    /// The modification times of the files when they were last loaded.
+    loaded: Vec<Option<SystemTime>>,
+    /// The modification times of the files when they were last checked.
+    checked: Vec<Option<SystemTime>>,
+}
+
+impl CertWatcher {
+    /// Starts watching the files, taking their current state as loaded.
+    ///
+    /// Does not watch anything if the interval is zero.
+    pub async fn new(files: Vec<PathBuf>, interval: Duration) -> Self {
+        let files = if interval.is_zero() {
+            Vec::new()
+        } else {
+            files
+        };
+        let mut this = Self {
+            files,
+            interval,
+            loaded: Vec::new(),
+            checked: Vec::new(),
+        };
+        this.loaded = this.modified().await;
+        this.checked = this.loaded.clone();
+        this
+    }
+
+    /// Whether any files are watched.
+    pub fn is_watching(&self) -> bool {
+        !self.files.is_empty()
+    }
+
+    /// The modification times of the watched files, `None` for missing files.
+    async fn modified(&self) -> Vec<Option<SystemTime>> {
+        let mut modified = Vec::new();
+        for path in &self.files {
+            let metadata = tokio::fs::metadata(path).await;
+            modified.push(metadata.and_then(|metadata| metadata.modified()).ok());
+        }
+        modified
+    }
+
+    /// Waits until the files changed, never returns if not watching any.
+    ///
+    /// The files are considered changed once they stopped changing for an interval, the
+    /// certificate and the key are usually replaced one after the other.
+    pub async fn changed(&mut self) {
+        if !self.is_watching() {
+            std::future::pending().await
+        }
+        loop {
+            time::sleep(self.interval).await;
+            let modified = self.modified().await;
+            if modified == self.checked && modified != self.loaded {
+                self.loaded = modified;
+                return;
+            }
+            self.checked = modified;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cert_watcher() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("iroh-relay-watch-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir)?;
+        let cert = dir.join("default.crt");
+        std::fs::write(&cert, b"cert")?;
+
+        let mut watcher = CertWatcher::new(vec![cert.clone()], Duration::from_millis(10)).await;
+        assert!(watcher.is_watching());
+        let unchanged = Duration::from_millis(100);
+        assert!(time::timeout(unchanged, watcher.changed()).await.is_err());
+        // Modification times may be coarse, make sure the change is seen.
+        std::fs::File::options()
+            .write(true)
+            .open(&cert)?
+            .set_modified(SystemTime::now() + Duration::from_secs(1))?;
+        time::timeout(Duration::from_secs(5), watcher.changed()).await?;
+        assert!(time::timeout(unchanged, watcher.changed()).await.is_err());
+
+        // Nothing is watched without an interval.
+        let mut watcher = CertWatcher::new(vec![cert], Duration::ZERO).await;
+        assert!(!watcher.is_watching());
+        assert!(time::timeout(unchanged, watcher.changed()).await.is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
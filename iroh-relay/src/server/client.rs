@@ -1,29 +1,65 @@
 //! The server-side representation of an ongoing client relaying connection.
 
-use std::{future::Future, num::NonZeroU32, pin::Pin, sync::Arc, task::Poll, time::Duration};
+use std::{
+    collections::HashSet,
+    future::Future,
+    num::NonZeroU32,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::Poll,
+    time::Duration,
+};
 
 use anyhow::{bail, Context, Result};
 use bytes::Bytes;
-use iroh_base::NodeId;
-use iroh_metrics::{inc, inc_by};
+use iroh_base::{inc, inc_by, NodeId};
 use n0_future::{FutureExt, Sink, SinkExt, Stream, StreamExt};
 use rand::Rng;
 use tokio::{
     sync::mpsc::{self, error::TrySendError},
-    time::MissedTickBehavior,
+    time::{Instant, MissedTickBehavior},
 };
 use tokio_util::{sync::CancellationToken, task::AbortOnDropHandle};
 use tracing::{debug, error, instrument, trace, warn, Instrument};
 
 use crate::{
+    http::Protocol,
     protos::{
         disco,
-        relay::{write_frame, Frame, PING_INTERVAL},
+        relay::{
+            write_frame, Channel, ClientSoftware, Frame, KeyRotation, PayloadCompression,
+            SendStatus, SessionToken, CHANNEL_WINDOW,
+        },
+    },
+    server::{
+        clients::{ClientInfo, Clients},
+        ip_limit::ClientIpPermit,
+        metrics::Metrics,
+        send_queue::{self, OverflowPolicy, SendQueueConfig},
+        streams::RelayedStream,
+        watchdog::{ClientQueues, TaskGuard},
+        ClientRateLimit, Disconnect, DisconnectHook, DisconnectReason,
     },
-    server::{clients::Clients, metrics::Metrics, streams::RelayedStream, ClientRateLimit},
     PingTracker,
 };
 
+/// How long a client announcing that it is closing is given to close the connection.
+const CLOSING_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Senders are told that a client is congested once its send queue is filled above this
+/// fraction of its capacity, see [`SEND_QUEUE_LOW_WATERMARK`].
+const SEND_QUEUE_HIGH_WATERMARK: f64 = 0.75;
+
+/// Senders told that a client is congested are told that it is ready again once its send
+/// queue drained below this fraction of its capacity.
+const SEND_QUEUE_LOW_WATERMARK: f64 = 0.25;
+
+/// The health problem sent to a client once its packets are dropped by its byte quota.
+const QUOTA_EXCEEDED_PROBLEM: &[u8] = b"byte quota exceeded, packets are dropped";
+
 /// A request to write a dataframe to a Client
 #[derive(Debug, Clone)]
 pub(super) struct Packet {
@@ -31,6 +67,8 @@ pub(super) struct Packet {
     src: NodeId,
     /// The data packet bytes.
     data: Bytes,
+    /// Whether the data is a fragment of a larger packet.
+    fragment: bool,
 }
 
 /// Configuration for a [`Client`].
@@ -39,8 +77,39 @@ pub(super) struct Config {
     pub(super) node_id: NodeId,
     pub(super) stream: RelayedStream,
     pub(super) write_timeout: Duration,
-    pub(super) channel_capacity: usize,
+    /// The queues of the packets sent to the client.
+    pub(super) send_queue: SendQueueConfig,
     pub(super) rate_limit: Option<ClientRateLimit>,
+    /// The rate limit of the packets sent to the client, summed over all senders.
+    pub(super) tx_rate_limit: Option<ClientRateLimit>,
+    /// Whether the client accepts fragments of packets larger than the maximum packet size.
+    pub(super) fragments: bool,
+    /// Whether the client accepts `FrameType::SendQueueStatus` frames.
+    pub(super) queue_status: bool,
+    /// Whether the client is told when the nodes which sent it packets are gone.
+    pub(super) peer_gone: bool,
+    /// Whether the packets to the client are flow controlled per [`Channel`].
+    pub(super) multiplexed: bool,
+    /// The compression of the packet payloads negotiated with the client, if any.
+    pub(super) compression: Option<PayloadCompression>,
+    /// Whether the client accepts `FrameType::UnknownPeer` frames.
+    pub(super) notify_unknown: bool,
+    /// Limits the rate of the packets the client sends to unknown nodes.
+    pub(super) unknown_limiter: Option<governor::DefaultDirectRateLimiter>,
+    /// The interval the client is pinged at while idle.
+    pub(super) keep_alive: Duration,
+    /// Whether the client proved the knowledge of the mesh key.
+    pub(super) trusted: bool,
+    /// The verified rotation from a previous key of the client.
+    pub(super) key_rotation: Option<KeyRotation>,
+    /// The software name and version reported by the client.
+    pub(super) software: Option<ClientSoftware>,
+    /// Called when the client disconnects.
+    pub(super) disconnect_hook: Option<DisconnectHook>,
+    /// The token of the session of the client, if it has one.
+    pub(super) session: Option<SessionToken>,
+    /// Counts the client for the limit of its source address, if it is limited.
+    pub(super) ip_permit: Option<ClientIpPermit>,
 }
 
 /// The [`Server`] side representation of a [`Client`]'s connection.
@@ -58,11 +127,48 @@ pub(super) struct Client {
     /// Actor handle.
     handle: AbortOnDropHandle<()>,
     /// Queue of packets intended for the client.
-    send_queue: mpsc::Sender<Packet>,
+    send_queue: send_queue::Sender<Packet>,
     /// Queue of disco packets intended for the client.
-    disco_send_queue: mpsc::Sender<Packet>,
-    /// Channel to notify the client that a previous sender has disconnected.
-    peer_gone: mpsc::Sender<NodeId>,
+    disco_send_queue: send_queue::Sender<Packet>,
+    /// What happens to the packets sent to the client while its queue is full.
+    overflow: OverflowPolicy,
+    /// Whether the client is disconnected for overflowing its send queue.
+    overflowed: Arc<AtomicBool>,
+    /// Channel of the control messages for the client.
+    control: mpsc::Sender<Control>,
+    /// The senders told that the send queue of this client is congested.
+    congested: Arc<CongestedSenders>,
+    /// Whether the client accepts fragments.
+    fragments: bool,
+    /// Whether the client accepts `FrameType::SendQueueStatus` frames.
+    accepts_queue_status: bool,
+    /// Whether the client is told when the nodes which sent it packets are gone.
+    accepts_peer_gone: bool,
+    /// The software name and version reported by the client.
+    software: Option<ClientSoftware>,
+    /// The protocol of the connection.
+    protocol: Protocol,
+    /// When the client connected.
+    connected_at: Instant,
+    /// The data relayed from and to the client.
+    traffic: Arc<Traffic>,
+    /// The token of the session of the client, if it has one.
+    session: Option<SessionToken>,
+    /// Counts the client for the limit of its source address, if it is limited.
+    _ip_permit: Option<ClientIpPermit>,
+}
+
+/// The packets relayed from and to a client, shared with its actor.
+#[derive(Debug, Default)]
+struct Traffic {
+    /// The bytes of the packets sent to the client.
+    sent: AtomicU64,
+    /// The bytes of the packets received from the client.
+    recv: AtomicU64,
+    /// The packets to the client dropped as its send queue was full.
+    dropped: AtomicU64,
+    /// The packets from the client dropped as their destination was not connected.
+    unknown: AtomicU64,
 }
 
 impl Client {
@@ -74,38 +180,69 @@ impl Client {
             node_id,
             stream: io,
             write_timeout,
-            channel_capacity,
+            send_queue,
             rate_limit,
+            tx_rate_limit,
+            fragments,
+            queue_status: accepts_queue_status,
+            peer_gone: accepts_peer_gone,
+            multiplexed,
+            compression,
+            notify_unknown,
+            unknown_limiter,
+            keep_alive,
+            trusted,
+            key_rotation: _,
+            software,
+            disconnect_hook,
+            session,
+            ip_permit,
         } = config;
 
+        let protocol = io.protocol();
         let stream = match rate_limit {
-            Some(cfg) => {
-                let mut quota = governor::Quota::per_second(cfg.bytes_per_second);
-                if let Some(max_burst) = cfg.max_burst_bytes {
-                    quota = quota.allow_burst(max_burst);
-                }
-                let limiter = governor::RateLimiter::direct(quota);
-                RateLimitedRelayedStream::new(io, limiter)
-            }
+            Some(cfg) => RateLimitedRelayedStream::new(io, rate_limiter(cfg)),
             None => RateLimitedRelayedStream::unlimited(io),
         };
 
         let done = CancellationToken::new();
-        let (send_queue_s, send_queue_r) = mpsc::channel(channel_capacity);
+        let channel_capacity = send_queue.depth;
+        let (send_queue_s, send_queue_r) = send_queue::channel(channel_capacity);
 
-        let (disco_send_queue_s, disco_send_queue_r) = mpsc::channel(channel_capacity);
-        let (peer_gone_s, peer_gone_r) = mpsc::channel(channel_capacity);
+        let (disco_send_queue_s, disco_send_queue_r) = send_queue::channel(channel_capacity);
+        let (control_s, control_r) = mpsc::channel(channel_capacity);
+        let congested = Arc::new(CongestedSenders::default());
+        let traffic = Arc::new(Traffic::default());
+        let overflowed = Arc::new(AtomicBool::new(false));
+        let connected_at = Instant::now();
 
         let actor = Actor {
             stream,
             timeout: write_timeout,
             send_queue: send_queue_r,
             disco_send_queue: disco_send_queue_r,
-            node_gone: peer_gone_r,
+            control: control_r,
+            congested: congested.clone(),
             node_id,
             connection_id,
             clients: clients.clone(),
             ping_tracker: PingTracker::default(),
+            keep_alive,
+            trusted,
+            tx_limiter: tx_rate_limit.map(|cfg| Arc::new(rate_limiter(cfg))),
+            shaped: None,
+            tx_limited_once: false,
+            quota_exceeded: false,
+            credit: multiplexed.then(ChannelCredit::default),
+            compression,
+            notify_unknown,
+            unknown_limiter: unknown_limiter.map(Arc::new),
+            unknown_paused: None,
+            disconnect_hook,
+            connected_at,
+            traffic: traffic.clone(),
+            overflowed: overflowed.clone(),
+            _task: clients.task_guard(),
         };
 
         // start io loop
@@ -123,7 +260,19 @@ impl Client {
             done,
             send_queue: send_queue_s,
             disco_send_queue: disco_send_queue_s,
-            peer_gone: peer_gone_s,
+            overflow: send_queue.overflow,
+            overflowed,
+            control: control_s,
+            congested,
+            fragments,
+            accepts_queue_status,
+            accepts_peer_gone,
+            software,
+            protocol,
+            connected_at,
+            traffic,
+            session,
+            _ip_permit: ip_permit,
         }
     }
 
@@ -131,6 +280,75 @@ impl Client {
         self.connection_id
     }
 
+    /// Whether the client accepts fragments of packets larger than the maximum packet size.
+    pub(super) fn accepts_fragments(&self) -> bool {
+        self.fragments
+    }
+
+    /// Whether the client accepts `FrameType::SendQueueStatus` frames.
+    pub(super) fn accepts_queue_status(&self) -> bool {
+        self.accepts_queue_status
+    }
+
+    /// Whether the client is told when the nodes which sent it packets are gone.
+    pub(super) fn accepts_peer_gone(&self) -> bool {
+        self.accepts_peer_gone
+    }
+
+    /// Records that `src` sent a packet to this client, addressing it as `dst`.
+    ///
+    /// Returns whether `src` needs to be told that this client is congested, i.e. whether
+    /// the send queue is above the high watermark and `src` was not told before.
+    pub(super) fn note_sender(&self, src: NodeId, dst: NodeId) -> bool {
+        let capacity = self.send_queue.max_capacity();
+        let depth = capacity - self.send_queue.capacity();
+        if (depth as f64) < capacity as f64 * SEND_QUEUE_HIGH_WATERMARK {
+            return false;
+        }
+        self.congested.insert(src, dst)
+    }
+
+    /// The token of the session of the client, if it has one.
+    pub(super) fn session(&self) -> Option<SessionToken> {
+        self.session
+    }
+
+    /// The software name and version reported by the client, if any.
+    pub(super) fn software(&self) -> Option<&ClientSoftware> {
+        self.software.as_ref()
+    }
+
+    /// Returns the number of items currently queued for the client.
+    pub(super) fn queues(&self) -> ClientQueues {
+        ClientQueues {
+            node_id: self.node_id.to_string(),
+            connection_id: self.connection_id,
+            send_queue: self.send_queue.max_capacity() - self.send_queue.capacity(),
+            disco_send_queue: self.disco_send_queue.max_capacity()
+                - self.disco_send_queue.capacity(),
+            control_queue: self.control.max_capacity() - self.control.capacity(),
+        }
+    }
+
+    /// Describes the connection of the client for the admin API.
+    pub(super) fn info(&self) -> ClientInfo {
+        ClientInfo {
+            node_id: self.node_id.to_string(),
+            connection_id: self.connection_id,
+            protocol: match self.protocol {
+                Protocol::Relay => "relay",
+                Protocol::Websocket => "websocket",
+                Protocol::WebTransport => "webtransport",
+            },
+            connected_secs: self.connected_at.elapsed().as_secs(),
+            bytes_sent: self.traffic.sent.load(Ordering::Relaxed),
+            bytes_recv: self.traffic.recv.load(Ordering::Relaxed),
+            packets_dropped: self.traffic.dropped.load(Ordering::Relaxed),
+            packets_to_unknown: self.traffic.unknown.load(Ordering::Relaxed),
+            software: self.software.clone(),
+        }
+    }
+
     /// Shutdown the reader and writer loops and closes the connection.
     ///
     /// Any shutdown errors will be logged as warnings.
@@ -149,24 +367,137 @@ impl Client {
         self.done.cancel();
     }
 
+    /// Queues a packet for the client.
+    ///
+    /// Fails with [`TrySendError::Full`] if the queue overflowed and a packet was dropped,
+    /// which is this packet unless the client drops its oldest packets.
     pub(super) fn try_send_packet(
         &self,
         src: NodeId,
         data: Bytes,
+        fragment: bool,
     ) -> Result<(), TrySendError<Packet>> {
-        self.send_queue.try_send(Packet { src, data })
+        let packet = Packet {
+            src,
+            data,
+            fragment,
+        };
+        self.enqueue(&self.send_queue, packet)
     }
 
+    /// Queues a disco packet for the client, see [`Client::try_send_packet`].
     pub(super) fn try_send_disco_packet(
         &self,
         src: NodeId,
         data: Bytes,
     ) -> Result<(), TrySendError<Packet>> {
-        self.disco_send_queue.try_send(Packet { src, data })
+        let packet = Packet {
+            src,
+            data,
+            fragment: false,
+        };
+        self.enqueue(&self.disco_send_queue, packet)
+    }
+
+    /// The status of a packet which overflowed the send queue of the client.
+    ///
+    /// With [`OverflowPolicy::DropOldest`] the packet is queued in place of an older one.
+    pub(super) fn overflow_status(&self) -> SendStatus {
+        match self.overflow {
+            OverflowPolicy::DropOldest => SendStatus::Queued,
+            OverflowPolicy::DropNewest | OverflowPolicy::DisconnectClient => SendStatus::Dropped,
+        }
+    }
+
+    /// Queues a packet, handling a full queue by the [`OverflowPolicy`] of the client.
+    fn enqueue(
+        &self,
+        queue: &send_queue::Sender<Packet>,
+        packet: Packet,
+    ) -> Result<(), TrySendError<Packet>> {
+        let dropped = match self.overflow {
+            OverflowPolicy::DropOldest => match queue.send_evicting(packet) {
+                Ok(None) => return Ok(()),
+                Ok(Some(evicted)) => evicted,
+                Err(packet) => return Err(TrySendError::Closed(packet)),
+            },
+            OverflowPolicy::DropNewest | OverflowPolicy::DisconnectClient => {
+                match queue.try_send(packet) {
+                    Err(TrySendError::Full(packet)) => packet,
+                    res => return res,
+                }
+            }
+        };
+        self.traffic.dropped.fetch_add(1, Ordering::Relaxed);
+        inc!(Metrics, send_queue_overflows);
+        if self.overflow == OverflowPolicy::DisconnectClient
+            && !self.overflowed.swap(true, Ordering::Relaxed)
+        {
+            debug!(
+                remote_node = %self.node_id.fmt_short(),
+                "send queue overflowed, disconnecting client",
+            );
+            inc!(Metrics, send_queue_overflow_disconnects);
+            self.start_shutdown();
+        }
+        Err(TrySendError::Full(dropped))
+    }
+
+    pub(super) fn try_send_peer_gone(&self, key: NodeId) -> Result<(), TrySendError<Control>> {
+        self.control.try_send(Control::PeerGone(key))
+    }
+
+    pub(super) fn try_send_peer_present(&self, key: NodeId) -> Result<(), TrySendError<Control>> {
+        self.control.try_send(Control::PeerPresent(key))
+    }
+
+    pub(super) fn try_send_queue_status(
+        &self,
+        dst: NodeId,
+        ready: bool,
+    ) -> Result<(), TrySendError<Control>> {
+        self.control.try_send(Control::QueueStatus { dst, ready })
+    }
+}
+
+/// A message for the client about other nodes, sent ahead of the packets.
+///
+/// These are sent on their own channel, so a flood of packets can not delay them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Control {
+    /// A previous sender has disconnected.
+    PeerGone(NodeId),
+    /// A node has connected, sent to watching clients.
+    PeerPresent(NodeId),
+    /// The send queue of a destination crossed a watermark.
+    QueueStatus { dst: NodeId, ready: bool },
+}
+
+/// The senders told that the send queue of a client is congested, with the key they
+/// addressed the client by.
+#[derive(Debug, Default)]
+struct CongestedSenders {
+    /// Whether `senders` is not empty, to not lock it for every packet sent to the client.
+    any: AtomicBool,
+    senders: Mutex<HashSet<(NodeId, NodeId)>>,
+}
+
+impl CongestedSenders {
+    /// Adds a sender, returns whether it was not added before.
+    fn insert(&self, src: NodeId, dst: NodeId) -> bool {
+        let mut senders = self.senders.lock().expect("poisoned");
+        self.any.store(true, Ordering::Relaxed);
+        senders.insert((src, dst))
     }
 
-    pub(super) fn try_send_peer_gone(&self, key: NodeId) -> Result<(), TrySendError<NodeId>> {
-        self.peer_gone.try_send(key)
+    /// Removes and returns all senders.
+    fn take(&self) -> HashSet<(NodeId, NodeId)> {
+        if !self.any.load(Ordering::Relaxed) {
+            return HashSet::new();
+        }
+        let mut senders = self.senders.lock().expect("poisoned");
+        self.any.store(false, Ordering::Relaxed);
+        std::mem::take(&mut *senders)
     }
 }
 
@@ -182,7 +513,14 @@ impl Client {
 ///  - a KEEP_ALIVE frame
 ///  - a PEER_GONE frame to inform the client that a peer they have previously sent messages to
 ///    is gone from the network
-///  - packets from other peers
+///  - a PEER_PRESENT frame to inform a trusted client watching connections that a peer
+///    connected
+///  - a SEND_QUEUE_STATUS frame to inform the client that a destination it sends to is
+///    congested or ready again
+///  - an UNKNOWN_PEER frame to inform the client that a packet was dropped as its
+///    destination is not connected
+///  - packets from other peers, while the client granted credit on their [`Channel`], if it
+///    is multiplexed
 ///
 /// On the "read" side, it can:
 ///     - receive a ping and write a pong back
@@ -194,11 +532,13 @@ struct Actor {
     /// Maximum time we wait to complete a write to the client
     timeout: Duration,
     /// Packets queued to send to the client
-    send_queue: mpsc::Receiver<Packet>,
+    send_queue: send_queue::Receiver<Packet>,
     /// Important packets queued to send to the client
-    disco_send_queue: mpsc::Receiver<Packet>,
-    /// Notify the client that a previous sender has disconnected
-    node_gone: mpsc::Receiver<NodeId>,
+    disco_send_queue: send_queue::Receiver<Packet>,
+    /// Control messages for the client, about other nodes
+    control: mpsc::Receiver<Control>,
+    /// The senders told that the send queue of this client is congested
+    congested: Arc<CongestedSenders>,
     /// [`NodeId`] of this client
     node_id: NodeId,
     /// Connection identifier.
@@ -206,27 +546,179 @@ struct Actor {
     /// Reference to the other connected clients.
     clients: Clients,
     ping_tracker: PingTracker,
+    /// The interval the client is pinged at while idle, before the jitter.
+    keep_alive: Duration,
+    /// Whether the client may watch connections and forward packets.
+    trusted: bool,
+    /// Limits the rate of the packets sent to the client, summed over all senders.
+    tx_limiter: Option<Arc<governor::DefaultDirectRateLimiter>>,
+    /// A packet held back by the `tx_limiter`.
+    ///
+    /// The send queue is not read while a packet is held back, so it fills up and further
+    /// packets for the client are dropped.
+    shaped: Option<ShapedPacket>,
+    /// Whether the `tx_limiter` ever held back a packet.
+    tx_limited_once: bool,
+    /// Whether the packets of the client are dropped by its byte quota.
+    quota_exceeded: bool,
+    /// The credit of the flow controlled channels, `None` if the client is not multiplexed.
+    ///
+    /// The send queue of a channel is not read while it has no credit, so the packets of
+    /// the other channels and the control frames are not stuck behind them.
+    credit: Option<ChannelCredit>,
+    /// The compression of the packet payloads negotiated with the client, if any.
+    compression: Option<PayloadCompression>,
+    /// Whether the client accepts `FrameType::UnknownPeer` frames.
+    notify_unknown: bool,
+    /// Limits the rate of the packets the client sends to unknown nodes.
+    unknown_limiter: Option<Arc<governor::DefaultDirectRateLimiter>>,
+    /// Completes once the `unknown_limiter` allows reading from the client again.
+    ///
+    /// The client is not read from while set, so it can not send further packets.
+    unknown_paused: Option<PausedReads>,
+    /// Called when the client disconnects.
+    disconnect_hook: Option<DisconnectHook>,
+    /// When the client connected.
+    connected_at: Instant,
+    /// The data relayed from and to the client.
+    traffic: Arc<Traffic>,
+    /// Whether the client is disconnected for overflowing its send queue.
+    overflowed: Arc<AtomicBool>,
+    /// Counts this actor as a running client task for the watchdog.
+    _task: TaskGuard,
+}
+
+/// A packet held back until the rate limit allows sending it.
+#[derive(derive_more::Debug)]
+struct ShapedPacket {
+    packet: Packet,
+    #[debug("delay")]
+    delay: Pin<Box<dyn Future<Output = ()> + Send + Sync>>,
+}
+
+/// Reads from the client paused until the rate limit allows them again.
+#[derive(derive_more::Debug)]
+struct PausedReads {
+    #[debug("delay")]
+    delay: Pin<Box<dyn Future<Output = ()> + Send + Sync>>,
+}
+
+/// The credit, in bytes, the client granted on its flow controlled [`Channel`]s.
+///
+/// A packet is sent while the credit of its channel is positive, so the credit can go
+/// negative by at most one packet.
+#[derive(Debug)]
+struct ChannelCredit {
+    disco: i64,
+    data: i64,
+}
+
+impl Default for ChannelCredit {
+    fn default() -> Self {
+        Self {
+            disco: CHANNEL_WINDOW.into(),
+            data: CHANNEL_WINDOW.into(),
+        }
+    }
+}
+
+impl ChannelCredit {
+    fn get_mut(&mut self, channel: Channel) -> Option<&mut i64> {
+        match channel {
+            Channel::Control => None,
+            Channel::Disco => Some(&mut self.disco),
+            Channel::Data => Some(&mut self.data),
+        }
+    }
+
+    /// Returns whether a packet may be sent on the channel.
+    fn available(credit: &Option<Self>, channel: Channel) -> bool {
+        match (credit, channel) {
+            (None, _) | (_, Channel::Control) => true,
+            (Some(credit), Channel::Disco) => credit.disco > 0,
+            (Some(credit), Channel::Data) => credit.data > 0,
+        }
+    }
+
+    /// Counts a frame sent to the client against the credit of its channel.
+    fn consume(&mut self, frame: &Frame) {
+        let (channel, len) = Channel::of(frame);
+        if let Some(credit) = self.get_mut(channel) {
+            *credit -= len as i64;
+        }
+    }
+
+    /// Adds the credit granted by the client.
+    fn grant(&mut self, channel: Channel, credit: u32) {
+        if let Some(current) = self.get_mut(channel) {
+            *current = current.saturating_add(credit.into());
+        }
+    }
+}
+
+/// Builds the rate limiter for a rate limit configuration.
+fn rate_limiter(cfg: ClientRateLimit) -> governor::DefaultDirectRateLimiter {
+    let mut quota = governor::Quota::per_second(cfg.bytes_per_second);
+    if let Some(max_burst) = cfg.max_burst_bytes {
+        quota = quota.allow_burst(max_burst);
+    }
+    governor::RateLimiter::direct(quota)
 }
 
 impl Actor {
     async fn run(mut self, done: CancellationToken) {
-        match self.run_inner(done).await {
+        let protocol = self.stream.inner.protocol();
+        let reason = match self.run_inner(done).await {
             Err(e) => {
                 warn!("actor errored {e:#?}, exiting");
+                ErrorClass::of(&e).record(protocol);
+                DisconnectReason::Error(format!("{e:#}"))
             }
-            Ok(()) => {
-                debug!("actor finished, exiting");
+            Ok(reason) => {
+                debug!(?reason, "actor finished, exiting");
+                if reason == DisconnectReason::PingTimeout {
+                    ErrorClass::Timeout.record(protocol);
+                }
+                reason
             }
+        };
+
+        // A broken connection can be resumed, unless the client announced it is closing.
+        self.clients
+            .unregister(self.connection_id, self.node_id, true);
+        if let Some(hook) = self.disconnect_hook.take() {
+            hook.call(&self.disconnect(reason));
         }
+    }
 
-        self.clients.unregister(self.connection_id, self.node_id);
+    /// Describes the disconnect of the client, counting the packets never sent to it.
+    fn disconnect(&mut self, reason: DisconnectReason) -> Disconnect {
+        self.send_queue.close();
+        self.disco_send_queue.close();
+        let shaped = self.shaped.take().map(|shaped| shaped.packet);
+        let queued = std::iter::from_fn(|| self.send_queue.try_recv())
+            .chain(std::iter::from_fn(|| self.disco_send_queue.try_recv()));
+        let (unflushed_packets, unflushed_bytes) = shaped
+            .into_iter()
+            .chain(queued)
+            .fold((0, 0), |(packets, bytes), packet| {
+                (packets + 1, bytes + packet.data.len())
+            });
+        Disconnect {
+            node_id: self.node_id,
+            reason,
+            duration: self.connected_at.elapsed(),
+            unflushed_packets,
+            unflushed_bytes,
+        }
     }
 
-    async fn run_inner(&mut self, done: CancellationToken) -> Result<()> {
+    async fn run_inner(&mut self, done: CancellationToken) -> Result<DisconnectReason> {
         // Add some jitter to ping pong interactions, to avoid all pings being sent at the same time
+        let keep_alive = self.keep_alive;
         let next_interval = || {
-            let random_secs = rand::rngs::OsRng.gen_range(1..=5);
-            Duration::from_secs(random_secs) + PING_INTERVAL
+            let jitter = rand::rngs::OsRng.gen_range(Duration::ZERO..=keep_alive / 3);
+            keep_alive + jitter
         };
 
         let mut ping_interval = tokio::time::interval(next_interval());
@@ -242,32 +734,55 @@ impl Actor {
                     trace!("actor loop cancelled, exiting");
                     // final flush
                     self.stream.flush().await.context("flush")?;
-                    break;
+                    if self.clients.is_replaced(self.node_id, self.connection_id) {
+                        return Ok(DisconnectReason::Replaced);
+                    }
+                    if self.overflowed.load(Ordering::Relaxed) {
+                        return Ok(DisconnectReason::SendQueueOverflow);
+                    }
+                    return Ok(DisconnectReason::ServerClosed);
                 }
-                maybe_frame = self.stream.next() => {
+                _ = paused_delay(&mut self.unknown_paused), if self.unknown_paused.is_some() => {
+                    trace!("resuming reads after packets to unknown nodes");
+                    self.unknown_paused = None;
+                }
+                maybe_frame = self.stream.next(), if self.unknown_paused.is_none() => {
+                    if matches!(maybe_frame, Some(Ok(Frame::Closing))) {
+                        self.handle_closing().await;
+                        return Ok(DisconnectReason::Closing);
+                    }
                     self.handle_frame(maybe_frame).await.context("handle read")?;
                     // reset the ping interval, we just received a message
                     ping_interval.reset();
                 }
+                // Control messages, ahead of the packets
+                control = self.control.recv() => {
+                    let control = control.context("Server.control dropped")?;
+                    self.handle_control(control).await?;
+                }
                 // First priority, disco packets
-                packet = self.disco_send_queue.recv() => {
+                packet = self.disco_send_queue.recv(),
+                    if ChannelCredit::available(&self.credit, Channel::Disco) => {
                     let packet = packet.context("Server.disco_send_queue dropped")?;
                     self.send_disco_packet(packet).await.context("send packet")?;
                 }
                 // Second priority, sending regular packets
-                packet = self.send_queue.recv() => {
-                    let packet = packet.context("Server.send_queue dropped")?;
-                    self.send_packet(packet).await.context("send packet")?;
+                _ = shaped_delay(&mut self.shaped), if self.shaped.is_some() => {
+                    let shaped = self.shaped.take().expect("checked");
+                    self.send_packet(shaped.packet).await.context("send packet")?;
+                    self.notify_drained();
                 }
-                // Last priority, sending left nodes
-                node_id = self.node_gone.recv() => {
-                    let node_id = node_id.context("Server.node_gone dropped")?;
-                    trace!("node_id gone: {:?}", node_id);
-                    self.write_frame(Frame::NodeGone { node_id }).await?;
+                packet = self.send_queue.recv(),
+                    if self.shaped.is_none() && ChannelCredit::available(&self.credit, Channel::Data) => {
+                    let packet = packet.context("Server.send_queue dropped")?;
+                    if let Some(packet) = self.shape(packet) {
+                        self.send_packet(packet).await.context("send packet")?;
+                    }
+                    self.notify_drained();
                 }
                 _ = self.ping_tracker.timeout() => {
                     trace!("pong timed out");
-                    break;
+                    return Ok(DisconnectReason::PingTimeout);
                 }
                 _ = ping_interval.tick() => {
                     trace!("keep alive ping");
@@ -280,7 +795,41 @@ impl Actor {
 
             self.stream.flush().await.context("tick flush")?;
         }
-        Ok(())
+    }
+
+    /// Writes a control message to the client.
+    async fn handle_control(&mut self, control: Control) -> Result<()> {
+        trace!(?control, "control message");
+        let frame = match control {
+            Control::PeerGone(node_id) => Frame::NodeGone { node_id },
+            Control::PeerPresent(node_id) => Frame::PeerPresent { node_id },
+            Control::QueueStatus { dst, ready } => Frame::SendQueueStatus {
+                dst_key: dst,
+                ready,
+            },
+        };
+        self.write_frame(frame).await
+    }
+
+    /// Handles the client announcing that it is about to disconnect.
+    ///
+    /// The client is unregistered right away, so its peers are notified and its resources
+    /// freed.  It is then given [`CLOSING_GRACE_PERIOD`] to close the connection, after
+    /// which the server closes it.
+    async fn handle_closing(&mut self) {
+        debug!("client is closing");
+        inc!(Metrics, graceful_disconnects);
+        // Dropping the client aborts this actor, keep it until the grace period is over.
+        let _client = self
+            .clients
+            .unregister(self.connection_id, self.node_id, false);
+        let closed = async { while let Some(Ok(_)) = self.stream.next().await {} };
+        if tokio::time::timeout(CLOSING_GRACE_PERIOD, closed)
+            .await
+            .is_err()
+        {
+            debug!("client did not close the connection within the grace period");
+        }
     }
 
     /// Writes the given frame to the connection.
@@ -290,7 +839,8 @@ impl Actor {
         write_frame(&mut self.stream, frame, Some(self.timeout)).await
     }
 
-    /// Writes contents to the client in a `RECV_PACKET` frame.
+    /// Writes contents to the client in a `RECV_PACKET`, `RECV_COMPRESSED_PACKET` or
+    /// `RECV_FRAGMENT` frame.
     ///
     /// Errors if the send does not happen within the `timeout` duration
     /// Does not flush.
@@ -300,9 +850,75 @@ impl Actor {
 
         if let Ok(len) = content.len().try_into() {
             inc_by!(Metrics, bytes_sent, len);
+            self.traffic.sent.fetch_add(len, Ordering::Relaxed);
+        }
+        let frame = if packet.fragment {
+            Frame::RecvFragment {
+                src_key,
+                fragment: content,
+            }
+        } else if let Some((compression, packet)) = self.compress(&content) {
+            Frame::RecvCompressedPacket {
+                src_key,
+                compression,
+                packet,
+            }
+        } else {
+            Frame::RecvPacket { src_key, content }
+        };
+        if let Some(credit) = &mut self.credit {
+            credit.consume(&frame);
+        }
+        self.write_frame(frame).await
+    }
+
+    /// Compresses a packet for the client, if it negotiated compression and it shrinks.
+    ///
+    /// Disco packets are encrypted and not compressed.
+    fn compress(&self, content: &Bytes) -> Option<(PayloadCompression, Bytes)> {
+        let compression = self.compression?;
+        if disco::looks_like_disco_wrapper(content) {
+            return None;
+        }
+        compression
+            .compress(content)
+            .map(|packet| (compression, packet))
+    }
+
+    /// Holds the packet back if it exceeds the rate limit, returns it otherwise.
+    fn shape(&mut self, packet: Packet) -> Option<Packet> {
+        let Some(limiter) = self.tx_limiter.clone() else {
+            return Some(packet);
+        };
+        let Some(len) = u32::try_from(packet.data.len())
+            .ok()
+            .and_then(NonZeroU32::new)
+        else {
+            return Some(packet);
+        };
+        match limiter.check_n(len) {
+            Ok(Ok(())) => Some(packet),
+            Ok(Err(_)) => {
+                inc!(Metrics, frames_tx_ratelimited_total);
+                if !self.tx_limited_once {
+                    inc!(Metrics, conns_tx_ratelimited_total);
+                    self.tx_limited_once = true;
+                }
+                let delay = Box::pin(async move {
+                    limiter.until_n_ready(len).await.ok();
+                });
+                self.shaped = Some(ShapedPacket { packet, delay });
+                None
+            }
+            Err(_insufficient_capacity) => {
+                error!(
+                    "packet larger than bucket capacity: \
+                     configuration error: max_burst_bytes < MAX_PACKET_SIZE?"
+                );
+                // Let this packet through so to not completely break.
+                Some(packet)
+            }
         }
-        self.write_frame(Frame::RecvPacket { src_key, content })
-            .await
     }
 
     async fn send_packet(&mut self, packet: Packet) -> Result<()> {
@@ -319,6 +935,18 @@ impl Actor {
         }
     }
 
+    /// Tells the senders told that this client is congested that it is ready again, once
+    /// the send queue drained below the low watermark.
+    fn notify_drained(&self) {
+        let capacity = self.send_queue.max_capacity();
+        if self.send_queue.len() as f64 > capacity as f64 * SEND_QUEUE_LOW_WATERMARK {
+            return;
+        }
+        for (src, dst) in self.congested.take() {
+            self.clients.send_queue_status(src, dst, true);
+        }
+    }
+
     async fn send_disco_packet(&mut self, packet: Packet) -> Result<()> {
         trace!("send disco packet");
         match self.send_raw(packet).await {
@@ -333,6 +961,12 @@ impl Actor {
         }
     }
 
+    /// Counts the bytes of a packet received from the client.
+    fn record_recv(&self, len: usize) {
+        inc_by!(Metrics, bytes_recv, len as u64);
+        self.traffic.recv.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
     /// Handles frame read results.
     async fn handle_frame(&mut self, maybe_frame: Option<Result<Frame>>) -> Result<()> {
         trace!(?maybe_frame, "handle incoming frame");
@@ -344,8 +978,74 @@ impl Actor {
         match frame {
             Frame::SendPacket { dst_key, packet } => {
                 let packet_len = packet.len();
-                self.handle_frame_send_packet(dst_key, packet)?;
-                inc_by!(Metrics, bytes_recv, packet_len as u64);
+                if self.within_quota(&packet).await? {
+                    let status = self.handle_frame_send_packet(dst_key, packet)?;
+                    self.handle_send_status(dst_key, status, true).await?;
+                }
+                self.record_recv(packet_len);
+            }
+            Frame::SendCompressedPacket {
+                dst_key,
+                compression,
+                packet,
+            } if self.compression == Some(compression) => {
+                let packet = compression
+                    .decompress(&packet)
+                    .context("invalid compressed packet")?;
+                let packet_len = packet.len();
+                if self.within_quota(&packet).await? {
+                    let status = self.handle_frame_send_packet(dst_key, packet)?;
+                    self.handle_send_status(dst_key, status, true).await?;
+                }
+                self.record_recv(packet_len);
+            }
+            Frame::SendAckedPacket {
+                dst_key,
+                id,
+                packet,
+            } => {
+                let packet_len = packet.len();
+                // Packets dropped by the quota are not acknowledged.
+                if self.within_quota(&packet).await? {
+                    let status = self.handle_frame_send_packet(dst_key, packet)?;
+                    self.write_frame(Frame::SendAck { id, status }).await?;
+                    // The acknowledgement already tells the client about unknown nodes.
+                    self.handle_send_status(dst_key, status, false).await?;
+                }
+                self.record_recv(packet_len);
+            }
+            Frame::SendFragment { dst_key, fragment } => {
+                let fragment_len = fragment.len();
+                inc!(Metrics, send_packets_recv);
+                if self.within_quota(&fragment).await? {
+                    self.clients
+                        .send_fragment(dst_key, fragment, self.node_id)?;
+                }
+                self.record_recv(fragment_len);
+            }
+            Frame::WatchConns if self.trusted => {
+                debug!("client is watching connections");
+                // Written directly rather than queued, there may be more clients than fit
+                // into the queue.
+                for node_id in self.clients.watch(self.node_id) {
+                    self.write_frame(Frame::PeerPresent { node_id }).await?;
+                }
+            }
+            Frame::ForwardPacket {
+                src_key,
+                dst_key,
+                hops,
+                packet,
+            } if self.trusted => {
+                let packet_len = packet.len();
+                inc!(Metrics, send_packets_recv);
+                self.clients
+                    .forward_packet(dst_key, packet, src_key, hops)?;
+                self.record_recv(packet_len);
+            }
+            Frame::WatchConns | Frame::ForwardPacket { .. } => {
+                debug!(frame = ?frame.typ(), "ignoring frame of untrusted client");
+                inc!(Metrics, unknown_frames);
             }
             Frame::Ping { data } => {
                 inc!(Metrics, got_ping);
@@ -356,6 +1056,13 @@ impl Actor {
             Frame::Pong { data } => {
                 self.ping_tracker.pong_received(data);
             }
+            Frame::WindowUpdate { channel, credit } if self.credit.is_some() => {
+                trace!(?channel, credit, "window update");
+                self.credit
+                    .as_mut()
+                    .expect("checked")
+                    .grant(channel, credit);
+            }
             Frame::Health { problem } => {
                 bail!("server issue: {:?}", problem);
             }
@@ -366,15 +1073,128 @@ impl Actor {
         Ok(())
     }
 
-    fn handle_frame_send_packet(&self, dst: NodeId, data: Bytes) -> Result<()> {
+    /// Counts a packet of the client against its byte quota, returns whether it is relayed.
+    ///
+    /// The client is told that its packets are dropped whenever it used up its quota.
+    /// Packets of trusted clients and disco packets are not counted.
+    async fn within_quota(&mut self, packet: &Bytes) -> Result<bool> {
+        if self.trusted || disco::looks_like_disco_wrapper(packet) {
+            return Ok(true);
+        }
+        if self.clients.record_quota(self.node_id, packet.len()) {
+            self.quota_exceeded = false;
+            return Ok(true);
+        }
+        inc!(Metrics, frames_quota_dropped_total);
+        if !self.quota_exceeded {
+            debug!("byte quota used up, dropping packets");
+            inc!(Metrics, conns_quota_exceeded_total);
+            self.quota_exceeded = true;
+            self.write_frame(Frame::Health {
+                problem: Bytes::from_static(QUOTA_EXCEEDED_PROBLEM),
+            })
+            .await?;
+        }
+        Ok(false)
+    }
+
+    /// Handles the outcome of relaying a packet of the client.
+    ///
+    /// Packets dropped as their destination is not connected are counted, the client is
+    /// told about them if it accepts `FrameType::UnknownPeer` frames and `notify` is set,
+    /// and reading from the client is paused while it exceeds the rate of these packets.
+    async fn handle_send_status(
+        &mut self,
+        dst_key: NodeId,
+        status: SendStatus,
+        notify: bool,
+    ) -> Result<()> {
+        if status != SendStatus::NodeUnknown {
+            return Ok(());
+        }
+        self.traffic.unknown.fetch_add(1, Ordering::Relaxed);
+        if notify && self.notify_unknown {
+            inc!(Metrics, unknown_peer_notifications);
+            self.write_frame(Frame::UnknownPeer { dst_key }).await?;
+        }
+        if let Some(limiter) = &self.unknown_limiter {
+            if limiter.check().is_err() {
+                debug!(
+                    dst = dst_key.fmt_short(),
+                    "too many packets to unknown nodes, pausing reads"
+                );
+                inc!(Metrics, unknown_peers_ratelimited);
+                let limiter = limiter.clone();
+                let delay = Box::pin(async move { limiter.until_ready().await });
+                self.unknown_paused = Some(PausedReads { delay });
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_frame_send_packet(&self, dst: NodeId, data: Bytes) -> Result<SendStatus> {
         if disco::looks_like_disco_wrapper(&data) {
             inc!(Metrics, disco_packets_recv);
-            self.clients.send_disco_packet(dst, data, self.node_id)?;
+            self.clients.send_disco_packet(dst, data, self.node_id)
         } else {
             inc!(Metrics, send_packets_recv);
-            self.clients.send_packet(dst, data, self.node_id)?;
+            self.clients.send_packet(dst, data, self.node_id)
         }
-        Ok(())
+    }
+}
+
+/// Waits until the held back packet may be sent, forever if there is none.
+async fn shaped_delay(shaped: &mut Option<ShapedPacket>) {
+    match shaped {
+        Some(shaped) => shaped.delay.as_mut().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits until reading from the client is resumed, forever if it is not paused.
+async fn paused_delay(paused: &mut Option<PausedReads>) {
+    match paused {
+        Some(paused) => paused.delay.as_mut().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The classes of errors closing a client connection, counted per protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorClass {
+    /// Reading from or writing to the connection failed.
+    Io,
+    /// A write or the reply to a ping took too long.
+    Timeout,
+    /// The client violated the protocol, e.g. by sending an invalid frame.
+    Protocol,
+}
+
+impl ErrorClass {
+    fn of(error: &anyhow::Error) -> Self {
+        if error.chain().any(|e| e.is::<tokio::time::error::Elapsed>()) {
+            Self::Timeout
+        } else if error.chain().any(|e| e.is::<std::io::Error>()) {
+            Self::Io
+        } else {
+            Self::Protocol
+        }
+    }
+
+    fn record(self, protocol: Protocol) {
+        match (protocol, self) {
+            (Protocol::Relay, Self::Io) => inc!(Metrics, relay_io_errors),
+            (Protocol::Relay, Self::Timeout) => inc!(Metrics, relay_timeouts),
+            (Protocol::Relay, Self::Protocol) => inc!(Metrics, relay_protocol_errors),
+            (Protocol::Websocket, Self::Io) => inc!(Metrics, websocket_io_errors),
+            (Protocol::Websocket, Self::Timeout) => inc!(Metrics, websocket_timeouts),
+            (Protocol::Websocket, Self::Protocol) => inc!(Metrics, websocket_protocol_errors),
+            (Protocol::WebTransport, Self::Io) => inc!(Metrics, webtransport_io_errors),
+            (Protocol::WebTransport, Self::Timeout) => inc!(Metrics, webtransport_timeouts),
+            (Protocol::WebTransport, Self::Protocol) => {
+                inc!(Metrics, webtransport_protocol_errors)
+            }
+        };
     }
 }
 
@@ -400,7 +1220,7 @@ enum State {
         /// Future which will complete when the item can be yielded.
         delay: Pin<Box<dyn Future<Output = ()> + Send + Sync>>,
         /// Item to yield when the `delay` future completes.
-        item: anyhow::Result<Frame>,
+        item: Box<anyhow::Result<Frame>>,
     },
     Ready,
 }
@@ -479,7 +1299,10 @@ impl Stream for RateLimitedRelayedStream {
                                                     limiter.until_n_ready(frame_len).await.ok();
                                                 }
                                             });
-                                            self.state = State::Blocked { delay, item };
+                                            self.state = State::Blocked {
+                                                delay,
+                                                item: Box::new(item),
+                                            };
                                             continue;
                                         }
                                         Err(_insufficient_capacity) => {
@@ -511,7 +1334,7 @@ impl Stream for RateLimitedRelayedStream {
                                 State::Blocked { item, .. } => {
                                     // Yield the item directly, rate-limit has already been
                                     // accounted for by awaiting the future.
-                                    return Poll::Ready(Some(item));
+                                    return Poll::Ready(Some(*item));
                                 }
                             }
                         }
@@ -564,21 +1387,20 @@ mod tests {
     use super::*;
     use crate::{
         protos::relay::{recv_frame, FrameType, RelayCodec},
-        server::streams::MaybeTlsStream,
+        server::{keep_alive::DEFAULT_KEEP_ALIVE_INTERVAL, streams::MaybeTlsStream},
     };
 
     #[tokio::test]
     #[traced_test]
     async fn test_client_actor_basic() -> Result<()> {
-        let (send_queue_s, send_queue_r) = mpsc::channel(10);
-        let (disco_send_queue_s, disco_send_queue_r) = mpsc::channel(10);
-        let (peer_gone_s, peer_gone_r) = mpsc::channel(10);
+        let (send_queue_s, send_queue_r) = send_queue::channel(10);
+        let (disco_send_queue_s, disco_send_queue_r) = send_queue::channel(10);
+        let (control_s, control_r) = mpsc::channel(10);
 
         let node_id = SecretKey::generate(rand::thread_rng()).public();
         let (io, io_rw) = tokio::io::duplex(1024);
         let mut io_rw = Framed::new(io_rw, RelayCodec::test());
-        let stream =
-            RelayedStream::Relay(Framed::new(MaybeTlsStream::Test(io), RelayCodec::test()));
+        let stream = RelayedStream::relay(MaybeTlsStream::Test(io), RelayCodec::test());
 
         let clients = Clients::default();
         let actor = Actor {
@@ -586,11 +1408,28 @@ mod tests {
             timeout: Duration::from_secs(1),
             send_queue: send_queue_r,
             disco_send_queue: disco_send_queue_r,
-            node_gone: peer_gone_r,
+            control: control_r,
+            congested: Default::default(),
             connection_id: 0,
             node_id,
             clients: clients.clone(),
             ping_tracker: PingTracker::default(),
+            keep_alive: DEFAULT_KEEP_ALIVE_INTERVAL,
+            trusted: false,
+            tx_limiter: None,
+            shaped: None,
+            tx_limited_once: false,
+            quota_exceeded: false,
+            credit: None,
+            compression: None,
+            notify_unknown: false,
+            unknown_limiter: None,
+            unknown_paused: None,
+            disconnect_hook: None,
+            connected_at: Instant::now(),
+            traffic: Default::default(),
+            overflowed: Default::default(),
+            _task: clients.task_guard(),
         };
 
         let done = CancellationToken::new();
@@ -606,8 +1445,9 @@ mod tests {
         let packet = Packet {
             src: node_id,
             data: Bytes::from(&data[..]),
+            fragment: false,
         };
-        send_queue_s.send(packet.clone()).await?;
+        send_queue_s.try_send(packet.clone())?;
         let frame = recv_frame(FrameType::RecvPacket, &mut io_rw).await?;
         assert_eq!(
             frame,
@@ -619,7 +1459,7 @@ mod tests {
 
         // send disco packet
         println!("  send disco packet");
-        disco_send_queue_s.send(packet.clone()).await?;
+        disco_send_queue_s.try_send(packet.clone())?;
         let frame = recv_frame(FrameType::RecvPacket, &mut io_rw).await?;
         assert_eq!(
             frame,
@@ -631,7 +1471,7 @@ mod tests {
 
         // send peer_gone
         println!("send peer gone");
-        peer_gone_s.send(node_id).await?;
+        control_s.send(Control::PeerGone(node_id)).await?;
         let frame = recv_frame(FrameType::PeerGone, &mut io_rw).await?;
         assert_eq!(frame, Frame::NodeGone { node_id });
 
@@ -677,6 +1517,440 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_client_actor_control_priority() -> TestResult {
+        let (send_queue_s, send_queue_r) = send_queue::channel(10);
+        let (disco_send_queue_s, disco_send_queue_r) = send_queue::channel(10);
+        let (control_s, control_r) = mpsc::channel(10);
+
+        let node_id = SecretKey::generate(rand::thread_rng()).public();
+        let (io, io_rw) = tokio::io::duplex(64 * 1024);
+        let mut io_rw = Framed::new(io_rw, RelayCodec::test());
+        let stream = RelayedStream::relay(MaybeTlsStream::Test(io), RelayCodec::test());
+
+        let actor = Actor {
+            stream: RateLimitedRelayedStream::unlimited(stream),
+            timeout: Duration::from_secs(1),
+            send_queue: send_queue_r,
+            disco_send_queue: disco_send_queue_r,
+            control: control_r,
+            congested: Default::default(),
+            connection_id: 0,
+            node_id,
+            clients: Clients::default(),
+            ping_tracker: PingTracker::default(),
+            keep_alive: DEFAULT_KEEP_ALIVE_INTERVAL,
+            trusted: false,
+            tx_limiter: None,
+            shaped: None,
+            tx_limited_once: false,
+            quota_exceeded: false,
+            credit: None,
+            compression: None,
+            notify_unknown: false,
+            unknown_limiter: None,
+            unknown_paused: None,
+            disconnect_hook: None,
+            connected_at: Instant::now(),
+            traffic: Default::default(),
+            overflowed: Default::default(),
+            _task: Clients::default().task_guard(),
+        };
+
+        // Fill the data lanes before the control message, the actor is not running yet.
+        let packet = Packet {
+            src: node_id,
+            data: Bytes::from_static(b"hello world!"),
+            fragment: false,
+        };
+        for _ in 0..10 {
+            send_queue_s.try_send(packet.clone())?;
+            disco_send_queue_s.try_send(packet.clone())?;
+        }
+        control_s.try_send(Control::PeerGone(node_id))?;
+
+        let done = CancellationToken::new();
+        let handle = tokio::task::spawn(actor.run(done.clone()));
+
+        // The control message overtakes all queued packets.
+        let frame = recv_frame(FrameType::PeerGone, &mut io_rw).await?;
+        assert_eq!(frame, Frame::NodeGone { node_id });
+        for _ in 0..20 {
+            recv_frame(FrameType::RecvPacket, &mut io_rw).await?;
+        }
+
+        done.cancel();
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_actor_disconnect_unflushed() -> TestResult {
+        let (send_queue_s, send_queue_r) = send_queue::channel(10);
+        let (disco_send_queue_s, disco_send_queue_r) = send_queue::channel(10);
+        let (_control_s, control_r) = mpsc::channel(10);
+
+        let node_id = SecretKey::generate(rand::thread_rng()).public();
+        let (io, _io_rw) = tokio::io::duplex(1024);
+        let stream = RelayedStream::relay(MaybeTlsStream::Test(io), RelayCodec::test());
+        let mut actor = Actor {
+            stream: RateLimitedRelayedStream::unlimited(stream),
+            timeout: Duration::from_secs(1),
+            send_queue: send_queue_r,
+            disco_send_queue: disco_send_queue_r,
+            control: control_r,
+            congested: Default::default(),
+            connection_id: 0,
+            node_id,
+            clients: Clients::default(),
+            ping_tracker: PingTracker::default(),
+            keep_alive: DEFAULT_KEEP_ALIVE_INTERVAL,
+            trusted: false,
+            tx_limiter: None,
+            shaped: None,
+            tx_limited_once: false,
+            quota_exceeded: false,
+            credit: None,
+            compression: None,
+            notify_unknown: false,
+            unknown_limiter: None,
+            unknown_paused: None,
+            disconnect_hook: None,
+            connected_at: Instant::now(),
+            traffic: Default::default(),
+            overflowed: Default::default(),
+            _task: Clients::default().task_guard(),
+        };
+
+        let packet = |len| Packet {
+            src: node_id,
+            data: Bytes::from(vec![0u8; len]),
+            fragment: false,
+        };
+        send_queue_s.try_send(packet(100))?;
+        send_queue_s.try_send(packet(200))?;
+        disco_send_queue_s.try_send(packet(50))?;
+
+        let disconnect = actor.disconnect(DisconnectReason::PingTimeout);
+        assert_eq!(disconnect.node_id, node_id);
+        assert_eq!(disconnect.reason, DisconnectReason::PingTimeout);
+        assert_eq!(disconnect.unflushed_packets, 3);
+        assert_eq!(disconnect.unflushed_bytes, 350);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_error_class() -> TestResult {
+        let elapsed = tokio::time::timeout(Duration::ZERO, std::future::pending::<()>())
+            .await
+            .unwrap_err();
+        let timeout = anyhow::Error::from(elapsed).context("write frame");
+        assert_eq!(ErrorClass::of(&timeout), ErrorClass::Timeout);
+
+        let io = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+            .context("read frame");
+        assert_eq!(ErrorClass::of(&io), ErrorClass::Io);
+
+        let invalid = anyhow::anyhow!("invalid forward packet frame length: 3");
+        assert_eq!(ErrorClass::of(&invalid), ErrorClass::Protocol);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_client_actor_tx_rate_limit() -> TestResult {
+        let (send_queue_s, send_queue_r) = send_queue::channel(10);
+        let (_disco_send_queue_s, disco_send_queue_r) = send_queue::channel(10);
+        let (control_s, control_r) = mpsc::channel(10);
+
+        let node_id = SecretKey::generate(rand::thread_rng()).public();
+        let (io, io_rw) = tokio::io::duplex(64 * 1024);
+        let mut io_rw = Framed::new(io_rw, RelayCodec::test());
+        let stream = RelayedStream::relay(MaybeTlsStream::Test(io), RelayCodec::test());
+
+        // One packet of 500 bytes every 500ms.
+        let limiter = rate_limiter(ClientRateLimit {
+            bytes_per_second: 1000.try_into()?,
+            max_burst_bytes: Some(500.try_into()?),
+        });
+        let actor = Actor {
+            stream: RateLimitedRelayedStream::unlimited(stream),
+            timeout: Duration::from_secs(1),
+            send_queue: send_queue_r,
+            disco_send_queue: disco_send_queue_r,
+            control: control_r,
+            congested: Default::default(),
+            connection_id: 0,
+            node_id,
+            clients: Clients::default(),
+            ping_tracker: PingTracker::default(),
+            keep_alive: DEFAULT_KEEP_ALIVE_INTERVAL,
+            trusted: false,
+            tx_limiter: Some(Arc::new(limiter)),
+            shaped: None,
+            tx_limited_once: false,
+            quota_exceeded: false,
+            credit: None,
+            compression: None,
+            notify_unknown: false,
+            unknown_limiter: None,
+            unknown_paused: None,
+            disconnect_hook: None,
+            connected_at: Instant::now(),
+            traffic: Default::default(),
+            overflowed: Default::default(),
+            _task: Clients::default().task_guard(),
+        };
+
+        let packet = Packet {
+            src: node_id,
+            data: Bytes::from(vec![0u8; 500]),
+            fragment: false,
+        };
+        for _ in 0..3 {
+            send_queue_s.try_send(packet.clone())?;
+        }
+        let start = std::time::Instant::now();
+        let done = CancellationToken::new();
+        let handle = tokio::task::spawn(actor.run(done.clone()));
+
+        recv_frame(FrameType::RecvPacket, &mut io_rw).await?;
+        // The control lane is served while the next packet is held back.
+        control_s.send(Control::PeerGone(node_id)).await?;
+        let frame = recv_frame(FrameType::PeerGone, &mut io_rw).await?;
+        assert_eq!(frame, Frame::NodeGone { node_id });
+        for _ in 0..2 {
+            recv_frame(FrameType::RecvPacket, &mut io_rw).await?;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(900));
+
+        done.cancel();
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_client_actor_flow_control() -> TestResult {
+        let (send_queue_s, send_queue_r) = send_queue::channel(10);
+        let (disco_send_queue_s, disco_send_queue_r) = send_queue::channel(10);
+        let (control_s, control_r) = mpsc::channel(10);
+
+        let node_id = SecretKey::generate(rand::thread_rng()).public();
+        let (io, io_rw) = tokio::io::duplex(64 * 1024);
+        let mut io_rw = Framed::new(io_rw, RelayCodec::test());
+        let stream = RelayedStream::relay(MaybeTlsStream::Test(io), RelayCodec::test());
+
+        // The data channel has credit for one more packet.
+        let credit = ChannelCredit {
+            data: 1,
+            ..Default::default()
+        };
+        let actor = Actor {
+            stream: RateLimitedRelayedStream::unlimited(stream),
+            timeout: Duration::from_secs(1),
+            send_queue: send_queue_r,
+            disco_send_queue: disco_send_queue_r,
+            control: control_r,
+            congested: Default::default(),
+            connection_id: 0,
+            node_id,
+            clients: Clients::default(),
+            ping_tracker: PingTracker::default(),
+            keep_alive: DEFAULT_KEEP_ALIVE_INTERVAL,
+            trusted: false,
+            tx_limiter: None,
+            shaped: None,
+            tx_limited_once: false,
+            quota_exceeded: false,
+            credit: Some(credit),
+            compression: None,
+            notify_unknown: false,
+            unknown_limiter: None,
+            unknown_paused: None,
+            disconnect_hook: None,
+            connected_at: Instant::now(),
+            traffic: Default::default(),
+            overflowed: Default::default(),
+            _task: Clients::default().task_guard(),
+        };
+
+        let packet = |data: &'static [u8]| Packet {
+            src: node_id,
+            data: Bytes::from_static(data),
+            fragment: false,
+        };
+        send_queue_s.try_send(packet(b"first"))?;
+        send_queue_s.try_send(packet(b"second"))?;
+        let done = CancellationToken::new();
+        let handle = tokio::task::spawn(actor.run(done.clone()));
+
+        let frame = recv_frame(FrameType::RecvPacket, &mut io_rw).await?;
+        assert_eq!(
+            frame,
+            Frame::RecvPacket {
+                src_key: node_id,
+                content: Bytes::from_static(b"first")
+            }
+        );
+        // The data channel is out of credit, the disco and control channels are not.
+        let mut disco_data = disco::MAGIC.as_bytes().to_vec();
+        disco_data.extend_from_slice(node_id.as_bytes());
+        disco_send_queue_s.try_send(Packet {
+            src: node_id,
+            data: disco_data.clone().into(),
+            fragment: false,
+        })?;
+        let frame = recv_frame(FrameType::RecvPacket, &mut io_rw).await?;
+        assert_eq!(
+            frame,
+            Frame::RecvPacket {
+                src_key: node_id,
+                content: disco_data.into()
+            }
+        );
+        control_s.send(Control::PeerGone(node_id)).await?;
+        let frame = recv_frame(FrameType::PeerGone, &mut io_rw).await?;
+        assert_eq!(frame, Frame::NodeGone { node_id });
+
+        // Granting credit releases the queued packet.
+        io_rw
+            .send(Frame::WindowUpdate {
+                channel: Channel::Data,
+                credit: 1024,
+            })
+            .await?;
+        let frame = recv_frame(FrameType::RecvPacket, &mut io_rw).await?;
+        assert_eq!(
+            frame,
+            Frame::RecvPacket {
+                src_key: node_id,
+                content: Bytes::from_static(b"second")
+            }
+        );
+
+        done.cancel();
+        handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    #[cfg(feature = "payload-compression")]
+    async fn test_client_actor_compression() -> TestResult {
+        let (send_queue_s, send_queue_r) = send_queue::channel(10);
+        let (_disco_send_queue_s, disco_send_queue_r) = send_queue::channel(10);
+        let (_control_s, control_r) = mpsc::channel(10);
+
+        let node_id = SecretKey::generate(rand::thread_rng()).public();
+        let (io, io_rw) = tokio::io::duplex(64 * 1024);
+        let mut io_rw = Framed::new(io_rw, RelayCodec::test());
+        let stream = RelayedStream::relay(MaybeTlsStream::Test(io), RelayCodec::test());
+
+        let traffic = Arc::new(Traffic::default());
+        let actor = Actor {
+            stream: RateLimitedRelayedStream::unlimited(stream),
+            timeout: Duration::from_secs(1),
+            send_queue: send_queue_r,
+            disco_send_queue: disco_send_queue_r,
+            control: control_r,
+            congested: Default::default(),
+            connection_id: 0,
+            node_id,
+            clients: Clients::default(),
+            ping_tracker: PingTracker::default(),
+            keep_alive: DEFAULT_KEEP_ALIVE_INTERVAL,
+            trusted: false,
+            tx_limiter: None,
+            shaped: None,
+            tx_limited_once: false,
+            quota_exceeded: false,
+            credit: None,
+            compression: Some(PayloadCompression::Lz4),
+            notify_unknown: false,
+            unknown_limiter: None,
+            unknown_paused: None,
+            disconnect_hook: None,
+            connected_at: Instant::now(),
+            traffic: traffic.clone(),
+            overflowed: Default::default(),
+            _task: Clients::default().task_guard(),
+        };
+        let done = CancellationToken::new();
+        let handle = tokio::task::spawn(actor.run(done.clone()));
+
+        // Packets which shrink are sent compressed, others as they are.
+        let data = Bytes::from(b"compress me ".repeat(100));
+        for content in [data.clone(), Bytes::from_static(b"tiny")] {
+            send_queue_s.try_send(Packet {
+                src: node_id,
+                data: content,
+                fragment: false,
+            })?;
+        }
+        let frame = recv_frame(FrameType::RecvCompressedPacket, &mut io_rw).await?;
+        let Frame::RecvCompressedPacket {
+            compression,
+            packet,
+            ..
+        } = frame
+        else {
+            panic!("expected a compressed packet, got {frame:?}");
+        };
+        assert_eq!(compression, PayloadCompression::Lz4);
+        assert!(packet.len() < data.len());
+        assert_eq!(compression.decompress(&packet)?, data);
+        let frame = recv_frame(FrameType::RecvPacket, &mut io_rw).await?;
+        assert_eq!(
+            frame,
+            Frame::RecvPacket {
+                src_key: node_id,
+                content: Bytes::from_static(b"tiny")
+            }
+        );
+
+        // Received packets are decompressed, the pong tells they were handled.
+        async fn ping(io_rw: &mut Framed<tokio::io::DuplexStream, RelayCodec>) -> TestResult {
+            io_rw.send(Frame::Ping { data: [1u8; 8] }).await?;
+            recv_frame(FrameType::Pong, io_rw).await?;
+            Ok(())
+        }
+        let dst_key = SecretKey::generate(rand::thread_rng()).public();
+        io_rw
+            .send(Frame::SendCompressedPacket {
+                dst_key,
+                compression: PayloadCompression::Lz4,
+                packet: PayloadCompression::Lz4.compress(&data).expect("compresses"),
+            })
+            .await?;
+        ping(&mut io_rw).await?;
+        assert_eq!(traffic.recv.load(Ordering::Relaxed), data.len() as u64);
+        // Compressions which were not negotiated are ignored.
+        io_rw
+            .send(Frame::SendCompressedPacket {
+                dst_key,
+                compression: PayloadCompression::Zstd,
+                packet: PayloadCompression::Zstd
+                    .compress(&data)
+                    .expect("compresses"),
+            })
+            .await?;
+        ping(&mut io_rw).await?;
+        assert_eq!(traffic.recv.load(Ordering::Relaxed), data.len() as u64);
+
+        // Invalid compressed packets disconnect the client.
+        io_rw
+            .send(Frame::SendCompressedPacket {
+                dst_key,
+                compression: PayloadCompression::Lz4,
+                packet: Bytes::from_static(b"garbage"),
+            })
+            .await?;
+        tokio::time::timeout(Duration::from_secs(1), handle).await??;
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_rate_limit() -> TestResult {
@@ -690,10 +1964,7 @@ mod tests {
         // Build the rate limited stream.
         let (io_read, io_write) = tokio::io::duplex((LIMIT * MAX_FRAMES) as _);
         let mut frame_writer = Framed::new(io_write, RelayCodec::test());
-        let stream = RelayedStream::Relay(Framed::new(
-            MaybeTlsStream::Test(io_read),
-            RelayCodec::test(),
-        ));
+        let stream = RelayedStream::relay(MaybeTlsStream::Test(io_read), RelayCodec::test());
         let mut stream = RateLimitedRelayedStream::new(stream, limiter);
 
         // Prepare a frame to send, assert its size.
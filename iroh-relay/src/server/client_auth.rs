@@ -0,0 +1,80 @@
+//! Authentication of relay clients with TLS client certificates.
+//!
+//! With [`TlsConfig::client_auth`] the HTTPS server only serves connections presenting a
+//! certificate issued by one of the configured authorities.  The verified certificate of
+//! a client is handed to the [`ClientAuthorizer`], which can map it to the device it was
+//! provisioned for.
+//!
+//! [`TlsConfig::client_auth`]: super::TlsConfig::client_auth
+//! [`ClientAuthorizer`]: super::ClientAuthorizer
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::CertificateDer,
+    server::{danger::ClientCertVerifier, WebPkiClientVerifier},
+    RootCertStore,
+};
+
+/// Configuration of the TLS client certificates required by the relay HTTPS server.
+#[derive(Debug, Clone)]
+pub struct ClientAuthConfig {
+    /// The certificates of the authorities the client certificates must be issued by.
+    pub roots: Vec<CertificateDer<'static>>,
+}
+
+impl ClientAuthConfig {
+    /// Returns the verifier of the client certificates, to build the server config with.
+    ///
+    /// Invalid certificates fail the TLS handshake.  Connections without a certificate pass
+    /// it, so the QUIC address discovery sharing the server config keeps working, they are
+    /// closed by the relay server instead.  See
+    /// [`TlsPolicy::mutual_server_config_builder`].
+    ///
+    /// [`TlsPolicy::mutual_server_config_builder`]: super::TlsPolicy::mutual_server_config_builder
+    pub fn verifier(&self, provider: Arc<CryptoProvider>) -> Result<Arc<dyn ClientCertVerifier>> {
+        let mut roots = RootCertStore::empty();
+        for cert in &self.roots {
+            roots
+                .add(cert.clone())
+                .context("invalid client CA certificate")?;
+        }
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+            .allow_unauthenticated()
+            .build()
+            .context("invalid client certificate verifier")
+    }
+}
+
+/// The identity of a relay client, proven by its TLS client certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    /// The certificate of the client.
+    pub end_entity: CertificateDer<'static>,
+    /// The intermediate certificates sent by the client, up to the authority.
+    pub intermediates: Vec<CertificateDer<'static>>,
+}
+
+impl ClientIdentity {
+    /// Returns the identity of the certificate chain a client presented, if any.
+    pub(super) fn from_chain(chain: &[CertificateDer<'_>]) -> Option<Self> {
+        let (end_entity, intermediates) = chain.split_first()?;
+        Some(Self {
+            end_entity: end_entity.clone().into_owned(),
+            intermediates: intermediates
+                .iter()
+                .map(|cert| cert.clone().into_owned())
+                .collect(),
+        })
+    }
+
+    /// Returns the identity of the client of a QUIC connection, if it presented a
+    /// certificate.
+    pub(super) fn from_quic(conn: &quinn::Connection) -> Option<Self> {
+        let identity = conn.peer_identity()?;
+        let chain = identity.downcast_ref::<Vec<CertificateDer<'static>>>()?;
+        Self::from_chain(chain)
+    }
+}
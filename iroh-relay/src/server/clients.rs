@@ -2,7 +2,7 @@
 // Based on tailscale/derp/derp_server.go
 
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashSet},
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
@@ -11,14 +11,114 @@ use std::{
 
 use anyhow::{bail, Result};
 use bytes::Bytes;
-use dashmap::DashMap;
-use iroh_base::NodeId;
-use iroh_metrics::inc;
+use dashmap::{mapref::one::Ref, DashMap, DashSet};
+use iroh_base::{inc, NodeId};
+use serde::Serialize;
 use tokio::sync::mpsc::error::TrySendError;
 use tracing::{debug, trace};
 
-use super::client::{Client, Config};
-use crate::server::metrics::Metrics;
+use super::{
+    client::{Client, Config},
+    mesh::MeshRoutes,
+    quotas::{QuotaConfig, QuotaUsage, Quotas},
+    sessions::{SessionConfig, Sessions},
+    unix_secs,
+    watchdog::{ClientQueues, TaskCounter, TaskGuard},
+    ClientRateLimit,
+};
+use crate::{
+    protos::relay::{ClientSoftware, SendStatus, SessionToken, MAX_FORWARD_HOPS},
+    server::metrics::Metrics,
+};
+
+/// The kinds of packets sent by [`Clients::send_data`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PacketKind {
+    /// A packet sent by a connected client.
+    Packet,
+    /// A fragment of a larger packet sent by a connected client.
+    Fragment,
+    /// A packet forwarded by a trusted client on behalf of another node.
+    Forwarded {
+        /// How many times the packet was forwarded between relay servers so far.
+        hops: u8,
+    },
+}
+
+impl PacketKind {
+    /// The hop count of the packet when forwarding it to a relay of the mesh.
+    ///
+    /// Fragments are only sent to connected clients, they are never forwarded.
+    fn next_hop(self) -> Option<u8> {
+        match self {
+            Self::Packet => Some(1),
+            Self::Fragment => None,
+            Self::Forwarded { hops } => Some(hops.saturating_add(1)),
+        }
+    }
+}
+
+/// What [`Clients::forward_packet`] did with a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ForwardStatus {
+    /// The packet was handled like any other packet to the destination.
+    Sent(SendStatus),
+    /// The packet was forwarded too often and dropped.
+    HopLimitExceeded,
+}
+
+/// A previous key of a client, routed to the client until the rotation expires.
+#[derive(Debug, Clone, Copy)]
+struct Alias {
+    /// The current key of the client.
+    node_id: NodeId,
+    /// The expiry of the rotation, in seconds since the UNIX epoch.
+    expires: u64,
+}
+
+impl Alias {
+    fn is_expired(&self) -> bool {
+        self.expires <= unix_secs()
+    }
+}
+
+/// The number of connected clients per reported software version.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub(super) struct SoftwareVersions {
+    /// The reported versions, ordered by software name and version.
+    pub(super) versions: Vec<SoftwareVersion>,
+    /// The number of clients which did not report their software.
+    pub(super) unreported: usize,
+}
+
+/// The number of connected clients which reported a software version.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(super) struct SoftwareVersion {
+    pub(super) name: String,
+    pub(super) version: String,
+    pub(super) clients: usize,
+}
+
+/// A connected client, as reported by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub(super) struct ClientInfo {
+    pub(super) node_id: String,
+    pub(super) connection_id: u64,
+    /// The protocol of the connection, `relay` or `websocket`.
+    pub(super) protocol: &'static str,
+    /// The seconds since the client connected.
+    pub(super) connected_secs: u64,
+    /// The bytes of the packets relayed to the client.
+    pub(super) bytes_sent: u64,
+    /// The bytes of the packets received from the client.
+    pub(super) bytes_recv: u64,
+    /// The packets to the client dropped as its send queue was full.
+    pub(super) packets_dropped: u64,
+    /// The packets from the client dropped as their destination was not connected.
+    pub(super) packets_to_unknown: u64,
+    /// The software name and version reported by the client.
+    pub(super) software: Option<ClientSoftware>,
+}
 
 /// Manages the connections to all currently connected clients.
 #[derive(Debug, Default, Clone)]
@@ -30,11 +130,41 @@ struct Inner {
     clients: DashMap<NodeId, Client>,
     /// Map of which client has sent where
     sent_to: DashMap<NodeId, HashSet<NodeId>>,
+    /// The trusted clients watching the connections of all clients.
+    watchers: DashSet<NodeId>,
+    /// The previous keys of clients which rotated their key.
+    aliases: DashMap<NodeId, Alias>,
     /// Connection ID Counter
     next_connection_id: AtomicU64,
+    /// Counts the running client actor tasks.
+    client_tasks: TaskCounter,
+    /// Routes packets to the nodes connected to other relays of the mesh.
+    mesh: Option<MeshRoutes>,
+    /// The resumable sessions of the clients, sessions are not supported if `None`.
+    sessions: Option<Sessions>,
+    /// The byte quotas of the nodes, unlimited if `None`.
+    quotas: Option<Quotas>,
 }
 
 impl Clients {
+    /// Creates the clients.
+    ///
+    /// Packets to nodes which are not connected are forwarded over the `mesh`, clients may
+    /// resume their sessions if `sessions` are configured, and the packets of nodes are
+    /// counted against the `quotas`.
+    pub(super) fn new(
+        mesh: Option<MeshRoutes>,
+        sessions: Option<SessionConfig>,
+        quotas: Option<QuotaConfig>,
+    ) -> Self {
+        Self(Arc::new(Inner {
+            mesh,
+            sessions: sessions.map(Sessions::new),
+            quotas: quotas.map(Quotas::new),
+            ..Default::default()
+        }))
+    }
+
     pub async fn shutdown(&self) {
         let keys: Vec<_> = self.0.clients.iter().map(|x| *x.key()).collect();
         trace!("shutting down {} clients", keys.len());
@@ -50,6 +180,23 @@ impl Clients {
         let connection_id = self.get_connection_id();
         trace!(remote_node = node_id.fmt_short(), "registering client");
 
+        // A previous connection of the client might have rotated from another key.
+        self.0.aliases.retain(|_, alias| alias.node_id != node_id);
+        if let Some(rotation) = client_config.key_rotation {
+            debug!(
+                remote_node = node_id.fmt_short(),
+                previous_key = rotation.previous_key.fmt_short(),
+                "routing previous key to rotated client",
+            );
+            self.0.aliases.insert(
+                rotation.previous_key,
+                Alias {
+                    node_id,
+                    expires: rotation.expires,
+                },
+            );
+        }
+
         let client = Client::new(client_config, connection_id, self);
         if let Some(old_client) = self.0.clients.insert(node_id, client) {
             debug!(
@@ -58,6 +205,163 @@ impl Clients {
             );
             old_client.shutdown().await;
         }
+        self.notify_watchers(node_id);
+    }
+
+    /// Resumes the session of the node if the token is valid.
+    ///
+    /// Returns the rate limit granted to the session, see [`Sessions::resume`].
+    pub(super) fn resume_session(
+        &self,
+        node_id: NodeId,
+        token: SessionToken,
+    ) -> Option<Option<ClientRateLimit>> {
+        let rate_limit = self.0.sessions.as_ref()?.resume(node_id, token)?;
+        inc!(Metrics, sessions_resumed);
+        Some(rate_limit)
+    }
+
+    /// Starts a new session of the node, if sessions are supported.
+    pub(super) fn start_session(
+        &self,
+        node_id: NodeId,
+        rate_limit: Option<ClientRateLimit>,
+    ) -> Option<SessionToken> {
+        let sessions = self.0.sessions.as_ref()?;
+        Some(sessions.start(node_id, rate_limit))
+    }
+
+    /// Counts a packet of the node against its quotas, returns whether it may be relayed.
+    pub(super) fn record_quota(&self, node_id: NodeId, bytes: usize) -> bool {
+        match &self.0.quotas {
+            Some(quotas) => quotas.record(node_id, bytes as u64),
+            None => true,
+        }
+    }
+
+    /// The usage of the quotas of all nodes, `None` if there are no quotas.
+    pub(super) fn quota_usage(&self) -> Option<Vec<QuotaUsage>> {
+        self.0.quotas.as_ref().map(Quotas::usage)
+    }
+
+    /// Resets the usage of the quotas of the node, `None` if there are no quotas.
+    ///
+    /// Returns whether the node had used any of its quotas.
+    pub(super) fn reset_quota(&self, node_id: &NodeId) -> Option<bool> {
+        self.0.quotas.as_ref().map(|quotas| quotas.reset(node_id))
+    }
+
+    /// Subscribes a trusted client to the connections of all other clients.
+    ///
+    /// Returns the currently connected clients, which the watcher has to be sent right away.
+    /// It is notified of clients connecting and disconnecting from then on.
+    pub(super) fn watch(&self, watcher: NodeId) -> Vec<NodeId> {
+        self.0.watchers.insert(watcher);
+        self.0
+            .clients
+            .iter()
+            .map(|client| *client.key())
+            .filter(|key| *key != watcher)
+            .collect()
+    }
+
+    /// Notifies all watchers that a client connected.
+    fn notify_watchers(&self, node_id: NodeId) {
+        let watchers: Vec<_> = self.0.watchers.iter().map(|key| *key).collect();
+        for watcher in watchers {
+            if watcher == node_id {
+                continue;
+            }
+            let Some(client) = self.0.clients.get(&watcher) else {
+                continue;
+            };
+            if let Err(err) = client.try_send_peer_present(node_id) {
+                debug!(
+                    dst = watcher.fmt_short(),
+                    "failed to notify watcher of present peer: {err}"
+                );
+            }
+        }
+    }
+
+    /// Counts a running client actor task until the guard is dropped.
+    pub(super) fn task_guard(&self) -> TaskGuard {
+        self.0.client_tasks.guard()
+    }
+
+    /// Returns the number of running client actor tasks.
+    pub(super) fn task_count(&self) -> usize {
+        self.0.client_tasks.get()
+    }
+
+    /// Returns the number of registered clients.
+    pub(super) fn len(&self) -> usize {
+        self.0.clients.len()
+    }
+
+    /// Returns the queue occupancy of all registered clients.
+    pub(super) fn queues(&self) -> Vec<ClientQueues> {
+        self.0
+            .clients
+            .iter()
+            .map(|client| client.queues())
+            .collect()
+    }
+
+    /// Describes all registered clients, ordered by node ID.
+    pub(super) fn infos(&self) -> Vec<ClientInfo> {
+        let mut infos: Vec<_> = self.0.clients.iter().map(|client| client.info()).collect();
+        infos.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+        infos
+    }
+
+    /// Counts the registered clients per reported software version.
+    pub(super) fn software_versions(&self) -> SoftwareVersions {
+        let mut counts = BTreeMap::new();
+        let mut unreported = 0;
+        for client in self.0.clients.iter() {
+            match client.software() {
+                Some(software) => *counts.entry(software.clone()).or_default() += 1,
+                None => unreported += 1,
+            }
+        }
+        let versions = counts
+            .into_iter()
+            .map(|(software, clients)| SoftwareVersion {
+                name: software.name,
+                version: software.version,
+                clients,
+            })
+            .collect();
+        SoftwareVersions {
+            versions,
+            unreported,
+        }
+    }
+
+    /// Returns the client connected with `node_id`, or which rotated from it.
+    ///
+    /// A client connected with the key itself takes precedence over a rotated one.
+    fn get(&self, node_id: &NodeId) -> Option<Ref<'_, NodeId, Client>> {
+        if let Some(client) = self.0.clients.get(node_id) {
+            return Some(client);
+        }
+        let alias = *self.0.aliases.get(node_id)?;
+        if alias.is_expired() {
+            self.0
+                .aliases
+                .remove_if(node_id, |_, alias| alias.is_expired());
+            return None;
+        }
+        self.0.clients.get(&alias.node_id)
+    }
+
+    /// Whether another connection of the node replaced the connection `connection_id`.
+    pub(super) fn is_replaced(&self, node_id: NodeId, connection_id: u64) -> bool {
+        self.0
+            .clients
+            .get(&node_id)
+            .is_some_and(|client| client.connection_id() != connection_id)
     }
 
     fn get_connection_id(&self) -> u64 {
@@ -65,38 +369,117 @@ impl Clients {
     }
 
     /// Removes the client from the map of clients, & sends a notification
-    /// to each client that peers has sent data to, to let them know that
-    /// peer is gone from the network.
+    /// to each client that peers has sent data to, and to all watchers, to let them know
+    /// that peer is gone from the network.
+    ///
+    /// If the client has a session and may `resume` it, the notifications are only sent
+    /// once the grace period of the session passed without the client reconnecting.
     ///
     /// Must be passed a matching connection_id.
-    pub(super) fn unregister(&self, connection_id: u64, node_id: NodeId) {
+    ///
+    /// Returns the removed client, dropping it aborts its actor.
+    pub(super) fn unregister(
+        &self,
+        connection_id: u64,
+        node_id: NodeId,
+        resume: bool,
+    ) -> Option<Client> {
         trace!(
             node_id = node_id.fmt_short(),
             connection_id,
             "unregistering client"
         );
 
-        if let Some((_, client)) = self
+        let (_, client) = self
             .0
             .clients
-            .remove_if(&node_id, |_, c| c.connection_id() == connection_id)
-        {
-            if let Some((_, sent_to)) = self.0.sent_to.remove(&node_id) {
-                for key in sent_to {
-                    match client.try_send_peer_gone(key) {
-                        Ok(_) => {}
-                        Err(TrySendError::Full(_)) => {
-                            debug!(
-                                dst = key.fmt_short(),
-                                "client too busy to receive packet, dropping packet"
-                            );
-                        }
-                        Err(TrySendError::Closed(_)) => {
-                            debug!(
-                                dst = key.fmt_short(),
-                                "can no longer write to client, dropping packet"
-                            );
-                        }
+            .remove_if(&node_id, |_, c| c.connection_id() == connection_id)?;
+        self.0.watchers.remove(&node_id);
+        let mut gone = vec![node_id];
+        self.0.aliases.retain(|previous_key, alias| {
+            if alias.node_id != node_id {
+                return true;
+            }
+            // Peers only knowing the previous key learn that it is gone as well, unless it
+            // is connected itself.
+            if !alias.is_expired() && !self.0.clients.contains_key(previous_key) {
+                gone.push(*previous_key);
+            }
+            false
+        });
+        match (&self.0.sessions, client.session()) {
+            (Some(sessions), Some(token)) if resume => {
+                debug!(
+                    node_id = node_id.fmt_short(),
+                    "deferring gone notifications for the grace period of the session"
+                );
+                let clients = self.clone();
+                let grace_period = sessions.grace_period();
+                tokio::spawn(async move {
+                    tokio::time::sleep(grace_period).await;
+                    clients.expire_session(node_id, token, gone);
+                });
+            }
+            (Some(sessions), Some(token)) => {
+                sessions.end(node_id, token);
+                self.notify_gone(node_id, &gone);
+            }
+            _ => self.notify_gone(node_id, &gone),
+        }
+        Some(client)
+    }
+
+    /// Ends the session once its grace period passed.
+    ///
+    /// The peers are told that the node is gone unless it reconnected in the meantime.
+    fn expire_session(&self, node_id: NodeId, token: SessionToken, gone: Vec<NodeId>) {
+        let Some(sessions) = &self.0.sessions else {
+            return;
+        };
+        if !sessions.end(node_id, token) || self.0.clients.contains_key(&node_id) {
+            trace!(
+                node_id = node_id.fmt_short(),
+                "node returned within grace period"
+            );
+            return;
+        }
+        debug!(node_id = node_id.fmt_short(), "session expired");
+        inc!(Metrics, sessions_expired);
+        self.notify_gone(node_id, &gone);
+    }
+
+    /// Tells the peers `node_id` sent to, and all watchers, that the nodes are gone.
+    ///
+    /// Peers are only told if they accept it, see [`Client::accepts_peer_gone`], watchers
+    /// asked for it.
+    fn notify_gone(&self, node_id: NodeId, gone: &[NodeId]) {
+        let mut notify = self
+            .0
+            .sent_to
+            .remove(&node_id)
+            .map(|(_, sent_to)| sent_to)
+            .unwrap_or_default();
+        notify.retain(|key| self.get(key).is_some_and(|peer| peer.accepts_peer_gone()));
+        notify.extend(self.0.watchers.iter().map(|key| *key));
+        notify.remove(&node_id);
+        for key in notify {
+            let Some(peer) = self.get(&key) else {
+                continue;
+            };
+            for gone in gone {
+                match peer.try_send_peer_gone(*gone) {
+                    Ok(_) => {}
+                    Err(TrySendError::Full(_)) => {
+                        debug!(
+                            dst = key.fmt_short(),
+                            "client too busy to receive packet, dropping packet"
+                        );
+                    }
+                    Err(TrySendError::Closed(_)) => {
+                        debug!(
+                            dst = key.fmt_short(),
+                            "can no longer write to client, dropping packet"
+                        );
                     }
                 }
             }
@@ -104,24 +487,87 @@ impl Clients {
     }
 
     /// Attempt to send a packet to client with [`NodeId`] `dst`.
-    pub(super) fn send_packet(&self, dst: NodeId, data: Bytes, src: NodeId) -> Result<()> {
-        let Some(client) = self.0.clients.get(&dst) else {
-            debug!(dst = dst.fmt_short(), "no connected client, dropped packet");
+    pub(super) fn send_packet(&self, dst: NodeId, data: Bytes, src: NodeId) -> Result<SendStatus> {
+        self.send_data(dst, data, src, PacketKind::Packet)
+    }
+
+    /// Attempt to send a packet forwarded by a trusted client to client with [`NodeId`]
+    /// `dst`.
+    ///
+    /// The `src` is not connected to this server, so it is not tracked for the
+    /// `FrameType::PeerGone` notifications.  If `dst` is connected to another relay of the
+    /// mesh the packet is forwarded again with an incremented hop count.  Packets forwarded
+    /// more than [`MAX_FORWARD_HOPS`] times are dropped, they are most likely looping
+    /// between misconfigured relays.
+    pub(super) fn forward_packet(
+        &self,
+        dst: NodeId,
+        data: Bytes,
+        src: NodeId,
+        hops: u8,
+    ) -> Result<ForwardStatus> {
+        if hops > MAX_FORWARD_HOPS {
+            debug!(
+                src = src.fmt_short(),
+                dst = dst.fmt_short(),
+                hops,
+                "forwarded packet exceeds the hop limit, dropped packet"
+            );
+            inc!(Metrics, forward_loops_dropped);
+            return Ok(ForwardStatus::HopLimitExceeded);
+        }
+        let status = self.send_data(dst, data, src, PacketKind::Forwarded { hops })?;
+        Ok(ForwardStatus::Sent(status))
+    }
+
+    /// Attempt to send a fragment of a larger packet to client with [`NodeId`] `dst`.
+    ///
+    /// The fragment is dropped if the client does not accept fragments.
+    pub(super) fn send_fragment(&self, dst: NodeId, data: Bytes, src: NodeId) -> Result<()> {
+        let accepts_fragments = match self.get(&dst) {
+            Some(client) => client.accepts_fragments(),
+            // Handled like any other packet to an unknown node.
+            None => true,
+        };
+        if !accepts_fragments {
+            debug!(
+                dst = dst.fmt_short(),
+                "client does not accept fragments, dropped fragment"
+            );
             inc!(Metrics, send_packets_dropped);
             return Ok(());
-        };
-        match client.try_send_packet(src, data) {
-            Ok(_) => {
-                // Record sent_to relationship
-                self.0.sent_to.entry(src).or_default().insert(dst);
-                Ok(())
+        }
+        self.send_data(dst, data, src, PacketKind::Fragment)?;
+        Ok(())
+    }
+
+    fn send_data(
+        &self,
+        dst: NodeId,
+        data: Bytes,
+        src: NodeId,
+        kind: PacketKind,
+    ) -> Result<SendStatus> {
+        let Some(client) = self.get(&dst) else {
+            // Packets looping in a misconfigured mesh are dropped by their hop count.
+            let mesh = self.0.mesh.as_ref().filter(|mesh| mesh.knows(&dst));
+            if let Some((mesh, hops)) = mesh.zip(kind.next_hop()) {
+                mesh.forward(dst, data, src, hops)?;
+                return Ok(SendStatus::Queued);
             }
+            debug!(dst = dst.fmt_short(), "no connected client, dropped packet");
+            inc!(Metrics, send_packets_dropped);
+            return Ok(SendStatus::NodeUnknown);
+        };
+        let status = match client.try_send_packet(src, data, kind == PacketKind::Fragment) {
+            Ok(_) => SendStatus::Queued,
             Err(TrySendError::Full(_)) => {
                 debug!(
                     dst = dst.fmt_short(),
-                    "client too busy to receive packet, dropping packet"
+                    "client too busy to receive packet, dropped packet"
                 );
-                bail!("failed to send message: full");
+                inc!(Metrics, send_packets_dropped);
+                client.overflow_status()
             }
             Err(TrySendError::Closed(_)) => {
                 debug!(
@@ -131,31 +577,62 @@ impl Clients {
                 client.start_shutdown();
                 bail!("failed to send message: gone");
             }
+        };
+        if !matches!(kind, PacketKind::Forwarded { .. }) {
+            // Record sent_to relationship
+            self.0.sent_to.entry(src).or_default().insert(dst);
+            if client.note_sender(src, dst) {
+                drop(client);
+                self.send_queue_status(src, dst, false);
+            }
+        }
+        Ok(status)
+    }
+
+    /// Tells the client `src` whether its destination `dst` is ready to receive packets, if
+    /// the client accepts `FrameType::SendQueueStatus` frames.
+    pub(super) fn send_queue_status(&self, src: NodeId, dst: NodeId, ready: bool) {
+        let Some(client) = self.get(&src) else {
+            return;
+        };
+        if !client.accepts_queue_status() {
+            return;
+        }
+        match client.try_send_queue_status(dst, ready) {
+            Ok(()) if ready => inc!(Metrics, send_queue_ready),
+            Ok(()) => inc!(Metrics, send_queue_congested),
+            Err(_) => debug!(
+                src = src.fmt_short(),
+                dst = dst.fmt_short(),
+                "can not notify client of the send queue status"
+            ),
         }
     }
 
     /// Attempt to send a disco packet to client with [`NodeId`] `dst`.
-    pub(super) fn send_disco_packet(&self, dst: NodeId, data: Bytes, src: NodeId) -> Result<()> {
-        let Some(client) = self.0.clients.get(&dst) else {
+    pub(super) fn send_disco_packet(
+        &self,
+        dst: NodeId,
+        data: Bytes,
+        src: NodeId,
+    ) -> Result<SendStatus> {
+        let Some(client) = self.get(&dst) else {
             debug!(
                 dst = dst.fmt_short(),
                 "no connected client, dropped disco packet"
             );
             inc!(Metrics, disco_packets_dropped);
-            return Ok(());
+            return Ok(SendStatus::NodeUnknown);
         };
-        match client.try_send_disco_packet(src, data) {
-            Ok(_) => {
-                // Record sent_to relationship
-                self.0.sent_to.entry(src).or_default().insert(dst);
-                Ok(())
-            }
+        let status = match client.try_send_disco_packet(src, data) {
+            Ok(_) => SendStatus::Queued,
             Err(TrySendError::Full(_)) => {
                 debug!(
                     dst = dst.fmt_short(),
-                    "client too busy to receive disco packet, dropping packet"
+                    "client too busy to receive disco packet, dropped packet"
                 );
-                bail!("failed to send message: full");
+                inc!(Metrics, disco_packets_dropped);
+                client.overflow_status()
             }
             Err(TrySendError::Closed(_)) => {
                 debug!(
@@ -165,7 +642,10 @@ impl Clients {
                 client.start_shutdown();
                 bail!("failed to send message: gone");
             }
-        }
+        };
+        // Record sent_to relationship
+        self.0.sent_to.entry(src).or_default().insert(dst);
+        Ok(status)
     }
 }
 
@@ -175,13 +655,19 @@ mod tests {
 
     use bytes::Bytes;
     use iroh_base::SecretKey;
+    use n0_future::{SinkExt, StreamExt};
     use tokio::io::DuplexStream;
     use tokio_util::codec::{Framed, FramedRead};
 
     use super::*;
     use crate::{
         protos::relay::{recv_frame, Frame, FrameType, RelayCodec},
-        server::streams::{MaybeTlsStream, RelayedStream},
+        server::{
+            keep_alive::DEFAULT_KEEP_ALIVE_INTERVAL,
+            send_queue::{OverflowPolicy, SendQueueConfig},
+            streams::{MaybeTlsStream, RelayedStream},
+            DisconnectHook, DisconnectReason,
+        },
     };
 
     fn test_client_builder(key: NodeId) -> (Config, FramedRead<DuplexStream, RelayCodec>) {
@@ -189,13 +675,28 @@ mod tests {
         (
             Config {
                 node_id: key,
-                stream: RelayedStream::Relay(Framed::new(
-                    MaybeTlsStream::Test(io),
-                    RelayCodec::test(),
-                )),
+                stream: RelayedStream::relay(MaybeTlsStream::Test(io), RelayCodec::test()),
                 write_timeout: Duration::from_secs(1),
-                channel_capacity: 10,
+                send_queue: SendQueueConfig {
+                    depth: 10,
+                    ..Default::default()
+                },
                 rate_limit: None,
+                tx_rate_limit: None,
+                fragments: false,
+                queue_status: false,
+                peer_gone: true,
+                multiplexed: false,
+                compression: None,
+                notify_unknown: false,
+                unknown_limiter: None,
+                keep_alive: DEFAULT_KEEP_ALIVE_INTERVAL,
+                trusted: false,
+                key_rotation: None,
+                software: None,
+                disconnect_hook: None,
+                session: None,
+                ip_permit: None,
             },
             FramedRead::new(test_io, RelayCodec::test()),
         )
@@ -255,4 +756,232 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_send_queue_overflow() -> Result<()> {
+        let src = SecretKey::generate(rand::thread_rng()).public();
+        for (overflow, delivered, overflow_status) in [
+            (
+                OverflowPolicy::DropNewest,
+                Some([0, 1]),
+                SendStatus::Dropped,
+            ),
+            (OverflowPolicy::DropOldest, Some([1, 2]), SendStatus::Queued),
+            (OverflowPolicy::DisconnectClient, None, SendStatus::Dropped),
+        ] {
+            let key = SecretKey::generate(rand::thread_rng()).public();
+            let (mut config, mut rw) = test_client_builder(key);
+            config.send_queue = SendQueueConfig { depth: 2, overflow };
+            let (disconnect_tx, mut disconnect_rx) = tokio::sync::mpsc::unbounded_channel();
+            config.disconnect_hook = Some(DisconnectHook::new(move |disconnect| {
+                disconnect_tx.send(disconnect.reason.clone()).ok();
+            }));
+            let clients = Clients::default();
+            clients.register(config).await;
+
+            // The actor does not run before the test yields, so the third packet overflows.
+            for i in 0..3u8 {
+                let status = clients.send_packet(key, Bytes::from(vec![i]), src)?;
+                let expected = if i < 2 {
+                    SendStatus::Queued
+                } else {
+                    overflow_status
+                };
+                assert_eq!(status, expected);
+            }
+            assert_eq!(clients.get(&key).unwrap().info().packets_dropped, 1);
+
+            match delivered {
+                Some(delivered) => {
+                    for i in delivered {
+                        let frame = recv_frame(FrameType::RecvPacket, &mut rw).await?;
+                        assert_eq!(
+                            frame,
+                            Frame::RecvPacket {
+                                src_key: src,
+                                content: Bytes::from(vec![i]),
+                            }
+                        );
+                    }
+                }
+                None => {
+                    let reason = tokio::time::timeout(Duration::from_secs(1), disconnect_rx.recv())
+                        .await?
+                        .expect("hook dropped");
+                    assert_eq!(reason, DisconnectReason::SendQueueOverflow);
+                }
+            }
+            clients.shutdown().await;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forward_hop_limit() -> Result<()> {
+        let a_key = SecretKey::generate(rand::thread_rng()).public();
+        let remote = SecretKey::generate(rand::thread_rng()).public();
+        let (builder_a, mut a_rw) = test_client_builder(a_key);
+        let clients = Clients::default();
+        clients.register(builder_a).await;
+
+        // Packets within the hop limit are delivered.
+        let status =
+            clients.forward_packet(a_key, Bytes::from_static(b"hi"), remote, MAX_FORWARD_HOPS)?;
+        assert_eq!(status, ForwardStatus::Sent(SendStatus::Queued));
+        let frame = recv_frame(FrameType::RecvPacket, &mut a_rw).await?;
+        assert_eq!(
+            frame,
+            Frame::RecvPacket {
+                src_key: remote,
+                content: Bytes::from_static(b"hi"),
+            }
+        );
+
+        // Packets exceeding it are not, even if the destination is connected.
+        let status = clients.forward_packet(
+            a_key,
+            Bytes::from_static(b"looped"),
+            remote,
+            MAX_FORWARD_HOPS + 1,
+        )?;
+        assert_eq!(status, ForwardStatus::HopLimitExceeded);
+        let recv = recv_frame(FrameType::RecvPacket, &mut a_rw);
+        assert!(tokio::time::timeout(Duration::from_millis(100), recv)
+            .await
+            .is_err());
+
+        clients.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forward_symmetric_mesh_loop() -> Result<()> {
+        // Two relays which both believe the destination is connected to the other one,
+        // forwarding every packet they cannot deliver to their peer.
+        let relays = [Clients::default(), Clients::default()];
+        let src = SecretKey::generate(rand::thread_rng()).public();
+        let dst = SecretKey::generate(rand::thread_rng()).public();
+
+        let mut relay = 0;
+        let mut hops = 1;
+        loop {
+            match relays[relay].forward_packet(dst, Bytes::from_static(b"lost"), src, hops)? {
+                ForwardStatus::Sent(SendStatus::NodeUnknown) => {
+                    relay = 1 - relay;
+                    hops += 1;
+                }
+                ForwardStatus::Sent(status) => panic!("destination is not connected: {status:?}"),
+                ForwardStatus::HopLimitExceeded => break,
+            }
+        }
+        // The packet bounced between the relays until it hit the limit.
+        assert_eq!(hops, MAX_FORWARD_HOPS + 1);
+
+        for relay in relays {
+            relay.shutdown().await;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_closing() -> Result<()> {
+        let a_key = SecretKey::generate(rand::thread_rng()).public();
+        let b_key = SecretKey::generate(rand::thread_rng()).public();
+
+        let (test_io, io) = tokio::io::duplex(1024);
+        let builder_a = Config {
+            node_id: a_key,
+            stream: RelayedStream::relay(MaybeTlsStream::Test(io), RelayCodec::test()),
+            write_timeout: Duration::from_secs(1),
+            send_queue: SendQueueConfig {
+                depth: 10,
+                ..Default::default()
+            },
+            rate_limit: None,
+            tx_rate_limit: None,
+            fragments: false,
+            queue_status: false,
+            peer_gone: true,
+            multiplexed: false,
+            compression: None,
+            notify_unknown: false,
+            unknown_limiter: None,
+            keep_alive: DEFAULT_KEEP_ALIVE_INTERVAL,
+            trusted: false,
+            key_rotation: None,
+            software: None,
+            disconnect_hook: None,
+            session: None,
+            ip_permit: None,
+        };
+        let mut a_rw = Framed::new(test_io, RelayCodec::test());
+        let (builder_b, mut b_rw) = test_client_builder(b_key);
+
+        let clients = Clients::default();
+        clients.register(builder_a).await;
+        clients.register(builder_b).await;
+
+        // a sends to b, so b is told when a is gone
+        clients.send_packet(b_key, Bytes::from_static(b"hello"), a_key)?;
+        recv_frame(FrameType::RecvPacket, &mut b_rw).await?;
+
+        a_rw.send(Frame::Closing).await?;
+        let frame = tokio::time::timeout(
+            Duration::from_millis(500),
+            recv_frame(FrameType::PeerGone, &mut b_rw),
+        )
+        .await??;
+        assert_eq!(frame, Frame::NodeGone { node_id: a_key });
+        assert!(!clients.0.clients.contains_key(&a_key));
+
+        // the server closes the connection once a closes it
+        drop(a_rw);
+        clients.shutdown().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_peer_gone_capability() -> Result<()> {
+        let a_key = SecretKey::generate(rand::thread_rng()).public();
+        let b_key = SecretKey::generate(rand::thread_rng()).public();
+        let c_key = SecretKey::generate(rand::thread_rng()).public();
+        let (builder_a, _a_rw) = test_client_builder(a_key);
+        let (builder_b, mut b_rw) = test_client_builder(b_key);
+        let (mut builder_c, mut c_rw) = test_client_builder(c_key);
+        builder_c.peer_gone = false;
+
+        let clients = Clients::default();
+        clients.register(builder_a).await;
+        clients.register(builder_b).await;
+        clients.register(builder_c).await;
+
+        // a sends to b and c, only b asked to be told when a is gone
+        for dst in [b_key, c_key] {
+            clients.send_packet(dst, Bytes::from_static(b"hello"), a_key)?;
+        }
+        recv_frame(FrameType::RecvPacket, &mut b_rw).await?;
+        recv_frame(FrameType::RecvPacket, &mut c_rw).await?;
+
+        clients
+            .0
+            .clients
+            .get(&a_key)
+            .expect("registered")
+            .start_shutdown();
+        let frame = tokio::time::timeout(
+            Duration::from_millis(500),
+            recv_frame(FrameType::PeerGone, &mut b_rw),
+        )
+        .await??;
+        assert_eq!(frame, Frame::NodeGone { node_id: a_key });
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), c_rw.next())
+                .await
+                .is_err(),
+            "c must not be told that a is gone"
+        );
+
+        clients.shutdown().await;
+        Ok(())
+    }
 }
@@ -0,0 +1,332 @@
+//! Compression of the responses of the custom HTTP routes.
+//!
+//! The encoding is negotiated with the `Accept-Encoding` header of the request.  Only
+//! responses with a compressible content type and of a minimum size are compressed, any
+//! other response is sent as is.  The relay endpoints are never compressed.
+
+use std::io::Write;
+
+use bytes::Bytes;
+use http::{
+    header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY},
+    HeaderValue, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tracing::trace;
+
+/// The default minimum size of compressed responses, in bytes.
+pub const DEFAULT_COMPRESSION_MIN_SIZE: usize = 1024;
+
+/// The brotli quality, trading compression ratio for speed.
+const BROTLI_QUALITY: u32 = 5;
+/// The base two logarithm of the brotli window size.
+const BROTLI_WINDOW: u32 = 22;
+
+/// A compression algorithm for HTTP responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, schemars::JsonSchema)]
+pub enum ContentEncoding {
+    /// Brotli, the `br` content coding.
+    #[serde(rename = "br")]
+    Brotli,
+    /// Gzip, the `gzip` content coding.
+    #[serde(rename = "gzip")]
+    Gzip,
+}
+
+impl ContentEncoding {
+    /// Returns the content coding token of the encoding.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// Compresses the data with this encoding.
+    fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Brotli => {
+                let mut out = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(
+                        &mut out,
+                        4096,
+                        BROTLI_QUALITY,
+                        BROTLI_WINDOW,
+                    );
+                    writer.write_all(data)?;
+                }
+                Ok(out)
+            }
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Configuration for the compression of responses of custom HTTP routes.
+///
+/// Applies to the request handlers added to the server and to the admin API, the relay
+/// endpoints are never compressed.
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    /// The encodings offered to clients, in order of preference.
+    ///
+    /// The encoding is chosen by the quality values of the `Accept-Encoding` header of the
+    /// request, this order breaks ties.
+    pub encodings: Vec<ContentEncoding>,
+    /// Responses smaller than this number of bytes are not compressed.
+    pub min_size: usize,
+    /// The content types of the responses to compress.
+    ///
+    /// Matched against the media type of the `Content-Type` header, ignoring parameters.
+    /// A type ending in `/*`, like `text/*`, matches all its subtypes.
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            encodings: vec![ContentEncoding::Brotli, ContentEncoding::Gzip],
+            min_size: DEFAULT_COMPRESSION_MIN_SIZE,
+            content_types: [
+                "text/*",
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "image/svg+xml",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Compresses the response if the client accepts one of the configured encodings.
+    pub(super) fn compress(
+        &self,
+        accept_encoding: Option<&HeaderValue>,
+        mut response: Response<Bytes>,
+    ) -> Response<Bytes> {
+        if !self.is_compressible(&response) {
+            return response;
+        }
+        // The response depends on the request headers from here on, even if it ends up not
+        // being compressed.
+        response
+            .headers_mut()
+            .append(VARY, HeaderValue::from_static("accept-encoding"));
+        if response.body().len() < self.min_size {
+            return response;
+        }
+        let Some(encoding) = accept_encoding.and_then(|value| self.negotiate(value)) else {
+            return response;
+        };
+        let compressed = match encoding.encode(response.body()) {
+            Ok(compressed) => compressed,
+            Err(err) => {
+                trace!(?encoding, "failed to compress response: {err:#}");
+                return response;
+            }
+        };
+        if compressed.len() >= response.body().len() {
+            return response;
+        }
+        let headers = response.headers_mut();
+        headers.insert(
+            CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+        headers.remove(CONTENT_LENGTH);
+        *response.body_mut() = compressed.into();
+        response
+    }
+
+    /// Whether the status and headers of the response allow compressing it.
+    fn is_compressible(&self, response: &Response<Bytes>) -> bool {
+        let status = response.status();
+        if status.is_informational()
+            || matches!(
+                status,
+                StatusCode::NO_CONTENT | StatusCode::PARTIAL_CONTENT | StatusCode::NOT_MODIFIED
+            )
+        {
+            return false;
+        }
+        let headers = response.headers();
+        if headers.contains_key(CONTENT_ENCODING) {
+            return false;
+        }
+        let Some(media_type) = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|media_type| media_type.trim().to_ascii_lowercase())
+        else {
+            return false;
+        };
+        self.content_types
+            .iter()
+            .any(|pattern| match pattern.strip_suffix("/*") {
+                Some(main_type) => media_type
+                    .split_once('/')
+                    .is_some_and(|(main, _)| main.eq_ignore_ascii_case(main_type)),
+                None => media_type.eq_ignore_ascii_case(pattern),
+            })
+    }
+
+    /// Chooses the configured encoding with the highest quality value accepted by the
+    /// client.
+    fn negotiate(&self, accept_encoding: &HeaderValue) -> Option<ContentEncoding> {
+        let accept_encoding = accept_encoding.to_str().ok()?;
+        let mut wildcard = None;
+        let mut accepted = Vec::new();
+        for item in accept_encoding.split(',') {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or_default().trim();
+            if coding.is_empty() {
+                continue;
+            }
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if coding == "*" {
+                wildcard = Some(quality);
+            } else {
+                accepted.push((coding, quality));
+            }
+        }
+
+        let mut best: Option<(ContentEncoding, f32)> = None;
+        for encoding in &self.encodings {
+            let quality = accepted
+                .iter()
+                .find(|(coding, _)| coding.eq_ignore_ascii_case(encoding.as_str()))
+                .map(|(_, quality)| *quality)
+                .or(wildcard)
+                .unwrap_or(0.0);
+            let better = match best {
+                Some((_, best_quality)) => quality > best_quality,
+                None => quality > 0.0,
+            };
+            if better {
+                best = Some((*encoding, quality));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn response(content_type: &str, len: usize) -> Response<Bytes> {
+        Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .header(CONTENT_LENGTH, len)
+            .body(Bytes::from(
+                "hello relay ".repeat(len / 12 + 1)[..len].to_string(),
+            ))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let config = CompressionConfig::default();
+        let negotiate = |value: &'static str| config.negotiate(&HeaderValue::from_static(value));
+        assert_eq!(
+            negotiate("gzip, deflate, br"),
+            Some(ContentEncoding::Brotli)
+        );
+        assert_eq!(negotiate("gzip"), Some(ContentEncoding::Gzip));
+        assert_eq!(negotiate("br;q=0.5, gzip"), Some(ContentEncoding::Gzip));
+        assert_eq!(negotiate("br;q=0, *"), Some(ContentEncoding::Gzip));
+        assert_eq!(negotiate("*"), Some(ContentEncoding::Brotli));
+        assert_eq!(negotiate("deflate, identity"), None);
+        assert_eq!(negotiate("gzip;q=0"), None);
+        assert_eq!(negotiate(""), None);
+
+        let config = CompressionConfig {
+            encodings: vec![ContentEncoding::Gzip],
+            ..Default::default()
+        };
+        let negotiate = |value: &'static str| config.negotiate(&HeaderValue::from_static(value));
+        assert_eq!(negotiate("br"), None);
+        assert_eq!(negotiate("br, gzip"), Some(ContentEncoding::Gzip));
+    }
+
+    #[test]
+    fn test_compress() {
+        let config = CompressionConfig::default();
+        let original = response("text/html; charset=utf-8", 4096);
+        let body = original.body().clone();
+
+        let br = config.compress(Some(&HeaderValue::from_static("br")), original.clone());
+        assert_eq!(br.headers()[CONTENT_ENCODING], "br");
+        assert_eq!(br.headers()[VARY], "accept-encoding");
+        assert!(!br.headers().contains_key(CONTENT_LENGTH));
+        assert!(br.body().len() < body.len());
+        let mut decoded = Vec::new();
+        brotli::Decompressor::new(&br.body()[..], 4096)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+
+        let gzip = config.compress(Some(&HeaderValue::from_static("gzip")), original.clone());
+        assert_eq!(gzip.headers()[CONTENT_ENCODING], "gzip");
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&gzip.body()[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+
+        // Without an accepted encoding the response is only marked as varying.
+        let plain = config.compress(None, original);
+        assert!(!plain.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(plain.headers()[VARY], "accept-encoding");
+        assert_eq!(plain.body(), &body);
+    }
+
+    #[test]
+    fn test_compress_filters() {
+        let config = CompressionConfig::default();
+        let accept = HeaderValue::from_static("gzip, br");
+        let is_compressed = |response| {
+            config
+                .compress(Some(&accept), response)
+                .headers()
+                .contains_key(CONTENT_ENCODING)
+        };
+
+        assert!(is_compressed(response("application/json", 4096)));
+        assert!(is_compressed(response("TEXT/plain", 4096)));
+        // Too small.
+        assert!(!is_compressed(response("text/plain", 100)));
+        // Not a compressible content type.
+        assert!(!is_compressed(response("image/png", 4096)));
+        assert!(!is_compressed(response("application/jsonx", 4096)));
+        let mut no_content_type = response("text/plain", 4096);
+        no_content_type.headers_mut().remove(CONTENT_TYPE);
+        assert!(!is_compressed(no_content_type));
+        // Already encoded.
+        let mut encoded = response("text/plain", 4096);
+        encoded
+            .headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let encoded = config.compress(Some(&accept), encoded);
+        assert!(!encoded.headers().contains_key(VARY));
+        // Status without content.
+        let mut not_modified = response("text/plain", 4096);
+        *not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+        assert!(!is_compressed(not_modified));
+    }
+}
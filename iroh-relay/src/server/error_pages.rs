@@ -0,0 +1,141 @@
+//! Custom responses for the errors of the relay HTTP server.
+//!
+//! By default the server answers errors with short plaintext bodies.  Deployments which
+//! expose the relay to browsers can replace these with their own pages, or redirect to
+//! another site, using [`ErrorPages`].
+
+use bytes::Bytes;
+use http::{
+    header::{CONTENT_TYPE, LOCATION},
+    response::Builder as ResponseBuilder,
+    HeaderValue, Response, StatusCode,
+};
+use url::Url;
+
+/// The responses served for the errors of the relay HTTP server.
+///
+/// Errors without a configured page keep the default response.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages {
+    /// Served with `404 Not Found` for requests which match no route.
+    pub not_found: Option<ErrorPage>,
+    /// Served with `400 Bad Request` for requests to the relay endpoint which are not a
+    /// valid protocol upgrade, e.g. when opening the relay URL in a browser.
+    pub bad_request: Option<ErrorPage>,
+    /// Served with `503 Service Unavailable` for new relay connections while the server is
+    /// draining, see `POST /admin/drain` in [`AdminConfig`].
+    ///
+    /// [`AdminConfig`]: super::AdminConfig
+    pub unavailable: Option<ErrorPage>,
+}
+
+/// A custom response for an error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorPage {
+    /// Serves a body with the status of the error.
+    ///
+    /// The body is a template: `{status}` is replaced with the status code, `{reason}` with
+    /// its reason phrase and `{path}` with the HTML-escaped path of the request.
+    Template {
+        /// The `Content-Type` of the body.
+        content_type: HeaderValue,
+        /// The template of the body.
+        body: String,
+    },
+    /// Redirects to this URL with `302 Found` instead of serving the error.
+    Redirect(Url),
+}
+
+impl ErrorPage {
+    /// Creates an HTML page from a template.
+    pub fn html(body: impl Into<String>) -> Self {
+        Self::Template {
+            content_type: HeaderValue::from_static("text/html; charset=utf-8"),
+            body: body.into(),
+        }
+    }
+
+    /// Describes the kind of the page for the admin API.
+    pub(super) fn kind(&self) -> &'static str {
+        match self {
+            Self::Template { .. } => "template",
+            Self::Redirect(_) => "redirect",
+        }
+    }
+
+    /// Builds the response for an error with this status.
+    pub(super) fn response(
+        &self,
+        status: StatusCode,
+        path: &str,
+        builder: ResponseBuilder,
+    ) -> http::Result<Response<Bytes>> {
+        match self {
+            Self::Template { content_type, body } => {
+                let body = body
+                    .replace("{status}", status.as_str())
+                    .replace("{reason}", status.canonical_reason().unwrap_or_default())
+                    .replace("{path}", &escape_html(path));
+                builder
+                    .status(status)
+                    .header(CONTENT_TYPE, content_type)
+                    .body(body.into())
+            }
+            Self::Redirect(url) => builder
+                .status(StatusCode::FOUND)
+                .header(LOCATION, url.as_str())
+                .body(Bytes::new()),
+        }
+    }
+}
+
+/// Escapes the characters with a meaning in HTML.
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template() {
+        let page = ErrorPage::html("<h1>{status} {reason}</h1><p>{path}</p>");
+        let res = page
+            .response(
+                StatusCode::NOT_FOUND,
+                "/<script>",
+                ResponseBuilder::new().header("X-Test", "1"),
+            )
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(res.headers()["X-Test"], "1");
+        assert_eq!(
+            res.body(),
+            "<h1>404 Not Found</h1><p>/&lt;script&gt;</p>".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_redirect() {
+        let page = ErrorPage::Redirect("https://example.com/relay".parse().unwrap());
+        let res = page
+            .response(StatusCode::BAD_REQUEST, "/relay", ResponseBuilder::new())
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(res.headers()[LOCATION], "https://example.com/relay");
+        assert!(res.body().is_empty());
+    }
+}
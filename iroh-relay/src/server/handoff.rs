@@ -0,0 +1,396 @@
+//! Upgrades of the relay server binary without closing its listeners.
+//!
+//! The running process starts the new binary with [`upgrade`], which hands over the
+//! listening sockets of its [`Server`] with `SCM_RIGHTS` over a Unix socket.  The new
+//! process picks them up with [`Inherited::from_env`], passes them on in
+//! [`ServerConfig::listeners`] and reports back with [`Inherited::ready`] once its server
+//! is running.  The kernel keeps queueing connections on the shared sockets throughout, so
+//! no connection attempt is refused.
+//!
+//! Afterwards the old process should [`Server::stop_accepting`] and give its clients some
+//! time to reconnect before shutting down.  Live relay connections are not migrated, the
+//! clients reconnect to the new process.
+//!
+//! [`ServerConfig::listeners`]: super::ServerConfig::listeners
+
+use std::{
+    io::{IoSlice, IoSliceMut},
+    os::{
+        fd::{AsFd, BorrowedFd, OwnedFd},
+        unix::fs::{DirBuilderExt, PermissionsExt},
+    },
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use rustix::net::{
+    recvmsg, sendmsg, RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags, SendAncillaryBuffer,
+    SendAncillaryMessage, SendFlags,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, Interest},
+    net::{UnixListener, UnixStream},
+    process::{Child, Command},
+};
+use tracing::{debug, info};
+
+use super::{Listeners, Server};
+
+/// The environment variable with the path of the handoff socket of a new process.
+pub const HANDOFF_SOCKET_ENV: &str = "IROH_RELAY_HANDOFF_SOCKET";
+
+/// The max length of the metadata handed to the new process.
+pub const MAX_METADATA_LEN: usize = 1024 * 1024;
+
+/// The max number of STUN sockets which can be handed over.
+const MAX_STUN_SOCKETS: usize = 16;
+
+/// The max number of sockets in a handoff: the relay, the captive portal and the STUN ones.
+const MAX_SOCKETS: usize = 2 + MAX_STUN_SOCKETS;
+
+/// Identifies the handoff messages, ending with the version of their format.
+const MAGIC: [u8; 5] = *b"IRHO\x01";
+
+/// The length of the header: the magic, whether the relay and captive portal listeners are
+/// included, the number of STUN sockets and the length of the metadata.
+const HEADER_LEN: usize = MAGIC.len() + 3 + 4;
+
+/// Sent by the new process once it is ready.
+const READY: u8 = 1;
+
+/// Closes the received sockets in a new process started by this one, where supported.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const RECV_FLAGS: RecvFlags = RecvFlags::CMSG_CLOEXEC;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const RECV_FLAGS: RecvFlags = RecvFlags::empty();
+
+/// Starts the successor of this process and hands the listening sockets of `server` to it.
+///
+/// Spawns `command` with [`HANDOFF_SOCKET_ENV`] set, sends the [`Server::listeners`] and
+/// `metadata` to it and waits up to `timeout` until it reports being ready.  The metadata is
+/// not interpreted, e.g. the version of the old process or a summary of its clients.
+///
+/// Returns the new process, which keeps running when dropped.  On failure the new process
+/// is killed and `server` keeps running unchanged.
+pub async fn upgrade(
+    server: &Server,
+    mut command: Command,
+    metadata: &[u8],
+    timeout: Duration,
+) -> Result<Child> {
+    ensure!(
+        metadata.len() <= MAX_METADATA_LEN,
+        "handoff metadata too large: {} bytes",
+        metadata.len()
+    );
+    let listeners = server.listeners()?;
+    let dir = std::env::temp_dir().join(format!(
+        "iroh-relay-handoff-{}-{:x}",
+        std::process::id(),
+        rand::random::<u64>()
+    ));
+    let socket = HandoffSocket::bind(dir)?;
+    command.env(HANDOFF_SOCKET_ENV, &socket.path);
+    let mut child = command.spawn().context("failed to start the new process")?;
+    debug!(pid = child.id(), "started the new process");
+
+    let handoff = async {
+        let mut stream = tokio::select! {
+            res = socket.listener.accept() => res?.0,
+            status = child.wait() => bail!("the new process exited: {}", status?),
+        };
+        send(&mut stream, &listeners, metadata).await?;
+        let mut ack = [0u8; 1];
+        stream
+            .read_exact(&mut ack)
+            .await
+            .context("the new process failed before becoming ready")?;
+        ensure!(ack[0] == READY, "invalid handoff response");
+        anyhow::Ok(())
+    };
+    let res = match tokio::time::timeout(timeout, handoff).await {
+        Ok(res) => res,
+        Err(_) => Err(anyhow::anyhow!(
+            "the new process did not become ready in {timeout:?}"
+        )),
+    };
+    match res {
+        Ok(()) => {
+            info!(
+                pid = child.id(),
+                "handed the listeners over to the new process"
+            );
+            Ok(child)
+        }
+        Err(err) => {
+            child.start_kill().ok();
+            Err(err)
+        }
+    }
+}
+
+/// The listening sockets handed over to this process by its predecessor.
+#[derive(Debug)]
+pub struct Inherited {
+    /// The sockets, to be used in [`ServerConfig::listeners`].
+    ///
+    /// [`ServerConfig::listeners`]: super::ServerConfig::listeners
+    pub listeners: Listeners,
+    /// The metadata passed to [`upgrade`] by the predecessor.
+    pub metadata: Vec<u8>,
+    stream: UnixStream,
+}
+
+impl Inherited {
+    /// Receives the sockets if this process was started by [`upgrade`].
+    ///
+    /// Returns `None` if [`HANDOFF_SOCKET_ENV`] is not set.  The variable is left set,
+    /// removing it is not sound once the runtime started threads.  [`upgrade`] sets it for
+    /// the successor of this process, so a stale value is never passed on.
+    pub async fn from_env() -> Result<Option<Self>> {
+        let Some(path) = std::env::var_os(HANDOFF_SOCKET_ENV) else {
+            return Ok(None);
+        };
+        let stream = UnixStream::connect(&path)
+            .await
+            .with_context(|| format!("failed to connect to handoff socket {path:?}"))?;
+        receive(stream).await.map(Some)
+    }
+
+    /// Tells the predecessor that this process is serving on the sockets.
+    pub async fn ready(mut self) -> Result<()> {
+        self.stream
+            .write_all(&[READY])
+            .await
+            .context("failed to report being ready")?;
+        Ok(())
+    }
+}
+
+/// The Unix socket the new process connects to, removed with its directory when dropped.
+///
+/// Anyone connecting to the socket receives the listening sockets, so it is created in a
+/// directory only accessible by the user of this process.
+#[derive(Debug)]
+struct HandoffSocket {
+    listener: UnixListener,
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl HandoffSocket {
+    fn bind(dir: PathBuf) -> Result<Self> {
+        std::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("failed to create handoff directory {}", dir.display()))?;
+        let path = dir.join("handoff.sock");
+        let listener = match UnixListener::bind(&path) {
+            Ok(listener) => listener,
+            Err(err) => {
+                std::fs::remove_dir(&dir).ok();
+                return Err(err)
+                    .with_context(|| format!("failed to bind handoff socket {}", path.display()));
+            }
+        };
+        let socket = Self {
+            listener,
+            dir,
+            path,
+        };
+        std::fs::set_permissions(&socket.path, std::fs::Permissions::from_mode(0o600))
+            .context("failed to restrict the handoff socket")?;
+        Ok(socket)
+    }
+}
+
+impl Drop for HandoffSocket {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+        std::fs::remove_dir(&self.dir).ok();
+    }
+}
+
+/// Sends the sockets and the metadata.
+async fn send(stream: &mut UnixStream, listeners: &Listeners, metadata: &[u8]) -> Result<()> {
+    ensure!(
+        listeners.stun.len() <= MAX_STUN_SOCKETS,
+        "too many STUN sockets to hand over: {}",
+        listeners.stun.len()
+    );
+    let mut header = [0u8; HEADER_LEN];
+    header[..MAGIC.len()].copy_from_slice(&MAGIC);
+    header[MAGIC.len()] = listeners.relay.is_some().into();
+    header[MAGIC.len() + 1] = listeners.http.is_some().into();
+    header[MAGIC.len() + 2] = listeners.stun.len() as u8;
+    header[MAGIC.len() + 3..].copy_from_slice(&(metadata.len() as u32).to_be_bytes());
+
+    let fds: Vec<BorrowedFd<'_>> = listeners
+        .relay
+        .iter()
+        .chain(listeners.http.iter())
+        .map(AsFd::as_fd)
+        .chain(listeners.stun.iter().map(AsFd::as_fd))
+        .collect();
+    let mut space = vec![0; rustix::cmsg_space!(ScmRights(MAX_SOCKETS))];
+    let mut control = SendAncillaryBuffer::new(&mut space);
+    if !fds.is_empty() {
+        ensure!(
+            control.push(SendAncillaryMessage::ScmRights(&fds)),
+            "too many sockets to hand over"
+        );
+    }
+    let sent = stream
+        .async_io(Interest::WRITABLE, || {
+            sendmsg(
+                &*stream,
+                &[IoSlice::new(&header)],
+                &mut control,
+                SendFlags::empty(),
+            )
+            .map_err(Into::into)
+        })
+        .await
+        .context("failed to send the sockets")?;
+    ensure!(sent == HEADER_LEN, "short write of the handoff header");
+    stream.write_all(metadata).await?;
+    Ok(())
+}
+
+/// Receives the sockets and the metadata sent by [`send`].
+async fn receive(mut stream: UnixStream) -> Result<Inherited> {
+    let mut header = [0u8; HEADER_LEN];
+    let mut space = vec![0; rustix::cmsg_space!(ScmRights(MAX_SOCKETS))];
+    let mut control = RecvAncillaryBuffer::new(&mut space);
+    let msg = stream
+        .async_io(Interest::READABLE, || {
+            recvmsg(
+                &stream,
+                &mut [IoSliceMut::new(&mut header)],
+                &mut control,
+                RECV_FLAGS,
+            )
+            .map_err(Into::into)
+        })
+        .await
+        .context("failed to receive the sockets")?;
+    let mut fds: Vec<OwnedFd> = Vec::new();
+    for msg in control.drain() {
+        if let RecvAncillaryMessage::ScmRights(received) = msg {
+            fds.extend(received);
+        }
+    }
+    ensure!(msg.bytes == HEADER_LEN, "truncated handoff header");
+    ensure!(
+        header[..MAGIC.len()] == MAGIC,
+        "invalid handoff header, is the predecessor a different version?"
+    );
+    let has_relay = header[MAGIC.len()] != 0;
+    let has_http = header[MAGIC.len() + 1] != 0;
+    let stun = usize::from(header[MAGIC.len() + 2]);
+    let metadata_len = u32::from_be_bytes(
+        header[MAGIC.len() + 3..]
+            .try_into()
+            .expect("length of 4 bytes"),
+    ) as usize;
+    // Sockets beyond the space of the control buffer are dropped by the kernel.
+    ensure!(
+        fds.len() == usize::from(has_relay) + usize::from(has_http) + stun,
+        "received {} sockets, expected {}",
+        fds.len(),
+        usize::from(has_relay) + usize::from(has_http) + stun,
+    );
+    ensure!(
+        metadata_len <= MAX_METADATA_LEN,
+        "handoff metadata too large: {metadata_len} bytes"
+    );
+
+    let mut fds = fds.into_iter();
+    let listeners = Listeners {
+        relay: has_relay.then(|| fds.next()).flatten().map(Into::into),
+        http: has_http.then(|| fds.next()).flatten().map(Into::into),
+        stun: fds.map(Into::into).collect(),
+    };
+    let mut metadata = vec![0; metadata_len];
+    stream
+        .read_exact(&mut metadata)
+        .await
+        .context("failed to receive the handoff metadata")?;
+    Ok(Inherited {
+        listeners,
+        metadata,
+        stream,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener, UdpSocket};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handoff_socket_private() -> Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("iroh-relay-handoff-{:x}", rand::random::<u64>()));
+        let socket = HandoffSocket::bind(dir.clone())?;
+        let mode = |path: &std::path::Path| -> Result<u32> {
+            Ok(std::fs::metadata(path)?.permissions().mode() & 0o777)
+        };
+        assert_eq!(mode(&dir)?, 0o700);
+        assert_eq!(mode(&socket.path)?, 0o600);
+        let _conn = UnixStream::connect(&socket.path).await?;
+
+        drop(socket);
+        assert!(!dir.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_receive() -> Result<()> {
+        let relay = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let stun = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let relay_addr = relay.local_addr()?;
+        let stun_addr = stun.local_addr()?;
+        let listeners = Listeners {
+            relay: Some(relay),
+            http: None,
+            stun: vec![stun],
+        };
+
+        let (mut a, b) = UnixStream::pair()?;
+        send(&mut a, &listeners, b"v1").await?;
+        drop(listeners);
+        let inherited = receive(b).await?;
+        assert_eq!(inherited.metadata, b"v1");
+        assert!(inherited.listeners.http.is_none());
+        assert_eq!(inherited.listeners.stun.len(), 1);
+        assert_eq!(inherited.listeners.stun[0].local_addr()?, stun_addr);
+
+        // The received listener still accepts connections after the original was closed.
+        let relay = inherited.listeners.relay.as_ref().expect("relay listener");
+        assert_eq!(relay.local_addr()?, relay_addr);
+        let _conn = std::net::TcpStream::connect(relay_addr)?;
+        let (_, peer) = relay.accept()?;
+        assert!(peer.ip().is_loopback());
+
+        inherited.ready().await?;
+        let mut ack = [0u8; 1];
+        a.read_exact(&mut ack).await?;
+        assert_eq!(ack[0], READY);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_receive_invalid() -> Result<()> {
+        let (mut a, b) = UnixStream::pair()?;
+        a.write_all(b"HTTP/1.1 200").await?;
+        let err = receive(b).await.unwrap_err();
+        assert!(
+            err.to_string().contains("invalid handoff header"),
+            "{err:#}"
+        );
+        Ok(())
+    }
+}
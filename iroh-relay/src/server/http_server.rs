@@ -1,45 +1,111 @@
 use std::{
-    collections::HashMap, future::Future, net::SocketAddr, pin::Pin, sync::Arc, time::Duration,
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime},
 };
 
-use anyhow::{bail, ensure, Context as _, Result};
+use anyhow::{anyhow, bail, ensure, Context as _, Result};
 use bytes::Bytes;
 use derive_more::Debug;
-use http::{header::CONNECTION, response::Builder as ResponseBuilder};
+use http::{
+    header::{ACCEPT_ENCODING, AUTHORIZATION, CONNECTION, CONTENT_TYPE, WWW_AUTHENTICATE},
+    response::Builder as ResponseBuilder,
+};
+use http_body_util::BodyExt;
 use hyper::{
-    body::Incoming,
+    body::{Body as _, Incoming},
     header::{HeaderValue, UPGRADE},
     service::Service,
     upgrade::Upgraded,
     HeaderMap, Method, Request, Response, StatusCode,
 };
-use iroh_metrics::inc;
+use ipnet::IpNet;
+use iroh_base::{inc, inc_by, PublicKey};
 use n0_future::{FutureExt, SinkExt};
-use tokio::net::{TcpListener, TcpStream};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
 use tokio_rustls_acme::AcmeAcceptor;
 use tokio_tungstenite::{
     tungstenite::{handshake::derive_accept_key, protocol::Role},
     WebSocketStream,
 };
-use tokio_util::{codec::Framed, sync::CancellationToken, task::AbortOnDropHandle};
+use tokio_util::{sync::CancellationToken, task::AbortOnDropHandle};
 use tracing::{debug, debug_span, error, info, info_span, trace, warn, Instrument};
 
-use super::{clients::Clients, AccessConfig};
+use super::{
+    access_log::{AccessLogger, AccessRecord, PendingAccess},
+    canonical_addr,
+    client_auth::ClientIdentity,
+    clients::Clients,
+    ip_limit::{ClientIpLimit, ClientIpLimiter},
+    keep_alive::{KeepAliveConfig, DEFAULT_KEEP_ALIVE_INTERVAL},
+    mesh::MeshRoutes,
+    proxy_protocol,
+    quotas::QuotaConfig,
+    send_queue::SendQueueConfig,
+    sessions::SessionConfig,
+    unix_secs,
+    unknown_peers::UnknownPeerConfig,
+    watchdog::{TaskCounter, Watchdog, WatchdogReport},
+    AccessConfig, AccessLog, AdminConfig, ClientAuthorizer, CompressionConfig, Decision,
+    DisconnectHook, ErrorPage, ErrorPages, ReloadConfig, WatchdogConfig,
+};
 use crate::{
     defaults::{timeouts::SERVER_WRITE_TIMEOUT, DEFAULT_KEY_CACHE_CAPACITY},
-    http::{Protocol, LEGACY_RELAY_PATH, RELAY_PATH, SUPPORTED_WEBSOCKET_VERSION},
-    protos::relay::{
-        recv_client_key, Frame, RelayCodec, PER_CLIENT_SEND_QUEUE_DEPTH, PROTOCOL_VERSION,
+    http::{
+        Protocol, H2_ALPN, LEGACY_RELAY_PATH, RELAY_ALPN, RELAY_PATH, SUPPORTED_WEBSOCKET_VERSION,
+    },
+    protos::{
+        relay::{
+            recv_client_key, ClientCapabilities, Frame, KeyRotation, MeshKey, RejectReason,
+            RelayCodec, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+        },
+        webtransport::WebTransportStream,
     },
     server::{
         client::Config,
         metrics::Metrics,
         streams::{MaybeTlsStream, RelayedStream},
-        ClientRateLimit,
+        ClientRateLimit, HandshakeLimit,
     },
-    KeyCache,
+    KeyCache, KeyCacheEviction,
 };
 
+/// The path prefix of the admin API.
+const ADMIN_PATH_PREFIX: &str = "/admin/";
+/// The admin API path serving the [`KeyCache`] statistics.
+const ADMIN_KEY_CACHE_PATH: &str = "/admin/key-cache";
+/// The admin API path serving the watchdog reports.
+const ADMIN_WATCHDOG_PATH: &str = "/admin/watchdog";
+/// The admin API path rebinding the listener of the server.
+const ADMIN_LISTENER_PATH: &str = "/admin/listener";
+/// The admin API path serving the effective configuration of the server.
+const ADMIN_CONFIG_PATH: &str = "/admin/config";
+/// The admin API path serving the connected clients.
+const ADMIN_CLIENTS_PATH: &str = "/admin/clients";
+/// The admin API path serving the connected clients per software version.
+const ADMIN_CLIENT_VERSIONS_PATH: &str = "/admin/client-versions";
+/// The admin API path reloading the node list of the access config from its file.
+const ADMIN_ACCESS_RELOAD_PATH: &str = "/admin/access/reload";
+/// The admin API path starting and stopping to drain the server.
+const ADMIN_DRAIN_PATH: &str = "/admin/drain";
+/// The admin API path serving and resetting the usage of the byte quotas.
+const ADMIN_QUOTAS_PATH: &str = "/admin/quotas";
+/// The longest time the previous key of a rotated client is routed to it, whatever expiry
+/// the client asked for.
+const MAX_KEY_ROTATION_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
 type BytesBody = http_body_util::Full<hyper::body::Bytes>;
 type HyperError = Box<dyn std::error::Error + Send + Sync>;
 type HyperResult<T> = std::result::Result<T, HyperError>;
@@ -50,6 +116,11 @@ type HyperHandler = Box<
         + 'static,
 >;
 
+/// Returns the size of the body of the response.
+fn body_len(res: &Response<BytesBody>) -> u64 {
+    res.body().size_hint().exact().unwrap_or_default()
+}
+
 /// Creates a new [`BytesBody`] with no content.
 fn body_empty() -> BytesBody {
     http_body_util::Full::new(hyper::body::Bytes::new())
@@ -60,15 +131,35 @@ fn body_full(content: impl Into<hyper::body::Bytes>) -> BytesBody {
     http_body_util::Full::new(content.into())
 }
 
-fn downcast_upgrade(upgraded: Upgraded) -> Result<(MaybeTlsStream, Bytes)> {
+/// Takes the stream out of an upgraded connection.
+///
+/// The streams of HTTP/2 connections can not be taken out of their connection, they are
+/// served as [`MaybeTlsStream::Multiplexed`].
+fn downcast_upgrade(upgraded: Upgraded) -> (MaybeTlsStream, Bytes) {
     match upgraded.downcast::<hyper_util::rt::TokioIo<MaybeTlsStream>>() {
-        Ok(parts) => Ok((parts.io.into_inner(), parts.read_buf)),
-        Err(_) => {
-            bail!("could not downcast the upgraded connection to MaybeTlsStream")
+        Ok(parts) => (parts.io.into_inner(), parts.read_buf),
+        Err(upgraded) => {
+            let io = hyper_util::rt::TokioIo::new(upgraded);
+            (MaybeTlsStream::Multiplexed(Box::new(io)), Bytes::new())
         }
     }
 }
 
+/// Returns the relay protocol requested by an HTTP/2 extended `CONNECT` request.
+///
+/// The protocol is given by the `:protocol` pseudo-header, which carries the value of the
+/// `Upgrade` header of HTTP/1.1 (RFC 8441).  Returns `None` for other requests.
+fn extended_connect_protocol(req: &Request<Incoming>) -> Option<Option<Protocol>> {
+    if req.method() != Method::CONNECT {
+        return None;
+    }
+    let protocol = req.extensions().get::<hyper::ext::Protocol>()?;
+    let protocol = HeaderValue::from_str(protocol.as_str())
+        .ok()
+        .and_then(|protocol| Protocol::parse_header(&protocol));
+    Some(protocol)
+}
+
 /// The Relay HTTP server.
 ///
 /// A running HTTP server serving the relay endpoint and optionally a number of additional
@@ -81,6 +172,7 @@ pub(super) struct Server {
     addr: SocketAddr,
     http_server_task: AbortOnDropHandle<()>,
     cancel_server_loop: CancellationToken,
+    service: RelayService,
 }
 
 impl Server {
@@ -91,6 +183,7 @@ impl Server {
     pub(super) fn handle(&self) -> ServerHandle {
         ServerHandle {
             cancel_token: self.cancel_server_loop.clone(),
+            service: self.service.clone(),
         }
     }
 
@@ -120,6 +213,7 @@ impl Server {
 #[derive(Debug, Clone)]
 pub(super) struct ServerHandle {
     cancel_token: CancellationToken,
+    service: RelayService,
 }
 
 impl ServerHandle {
@@ -127,6 +221,111 @@ impl ServerHandle {
     pub(super) fn shutdown(&self) {
         self.cancel_token.cancel()
     }
+
+    /// Duplicates the socket of the current listener, `None` once it stopped accepting.
+    pub(super) fn try_clone_listener(&self) -> Result<Option<std::net::TcpListener>> {
+        let Some(listener) = self.service.0.rebind.current() else {
+            return Ok(None);
+        };
+        let socket = socket2::SockRef::from(&*listener).try_clone()?;
+        Ok(Some(socket.into()))
+    }
+
+    /// Returns whether the clients must present a TLS client certificate.
+    pub(super) fn requires_client_certificate(&self) -> bool {
+        self.service.0.client_auth
+    }
+
+    /// Returns the effective configuration, as served by [`ADMIN_CONFIG_PATH`].
+    pub(super) fn effective_config(&self) -> serde_json::Value {
+        self.service.0.effective_config()
+    }
+
+    /// Returns whether the admin API is enabled.
+    pub(super) fn has_admin_api(&self) -> bool {
+        self.service.0.admin.is_some()
+    }
+
+    /// Returns whether new relay connections are refused, see [`ADMIN_DRAIN_PATH`].
+    pub(super) fn is_draining(&self) -> bool {
+        self.service.0.draining.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether requests are recorded in the access log.
+    pub(super) fn has_access_log(&self) -> bool {
+        self.service.0.access_log.is_some()
+    }
+
+    /// Records a request which was not accepted as a relay client, if an access log is
+    /// configured.
+    pub(super) fn log_access(&self, record: AccessRecord) {
+        if let Some(access_log) = &self.service.0.access_log {
+            access_log.log(record);
+        }
+    }
+
+    /// Completes once the server is shut down.
+    pub(super) async fn cancelled(&self) {
+        self.cancel_token.cancelled().await
+    }
+
+    /// Serves a relay client connected by the stream of a WebTransport session.
+    pub(super) async fn accept_webtransport(
+        &self,
+        stream: WebTransportStream,
+        request: ClientRequest,
+    ) -> Result<()> {
+        let _task = self.service.0.connection_tasks.guard();
+        self.service
+            .0
+            .accept(
+                Protocol::WebTransport,
+                MaybeTlsStream::WebTransport(Box::new(stream)),
+                request,
+            )
+            .await
+    }
+
+    /// Closes the listener, the connected clients keep being served.
+    ///
+    /// A listener bound through the admin API afterwards accepts connections again.
+    pub(super) fn stop_accepting(&self) {
+        self.service.0.rebind.close();
+    }
+
+    /// Replaces the rate limits, access configuration, headers and TLS certificates.
+    ///
+    /// The connected clients keep their rate limits, the new ones apply to the clients
+    /// connecting afterwards.  Nothing is replaced if the TLS config can not be.
+    pub(super) fn reload(&self, config: ReloadConfig) -> Result<()> {
+        let inner = &self.service.0;
+        let tls = config
+            .tls_server_config
+            .map(|server_config| inner.renewed_tls_config(server_config))
+            .transpose()?;
+        *inner.rate_limits.write().expect("poisoned") = RateLimits {
+            client_rx: config.client_rx,
+            trusted_client_rx: config.trusted_client_rx,
+            client_tx: config.client_tx,
+        };
+        *inner.access.write().expect("poisoned") = Arc::new(config.access);
+        *inner.headers.extra.write().expect("poisoned") = config.headers;
+        *inner.headers.extra_routes.write().expect("poisoned") = config.route_headers;
+        if let Some(tls) = tls {
+            *inner.tls.write().expect("poisoned") = Some(tls);
+        }
+        info!("reloaded the relay server configuration");
+        Ok(())
+    }
+
+    /// Replaces the TLS certificates only, see [`ServerHandle::reload`].
+    pub(super) fn reload_tls(&self, server_config: rustls::ServerConfig) -> Result<()> {
+        let inner = &self.service.0;
+        let tls = inner.renewed_tls_config(server_config)?;
+        *inner.tls.write().expect("poisoned") = Some(tls);
+        info!("reloaded the TLS certificates");
+        Ok(())
+    }
 }
 
 /// Configuration to use for the TLS connection
@@ -156,16 +355,105 @@ pub(super) struct ServerBuilder {
     /// the relay server, and so must be handled along side requests to the relay endpoint.
     handlers: Handlers,
     /// Headers to use for HTTP responses.
-    headers: HeaderMap,
+    headers: Headers,
     /// Rate-limiting configuration for an individual client connection.
     ///
     /// Rate-limiting is enforced on received traffic from individual clients.  This
     /// configuration applies to a single client connection.
     client_rx_ratelimit: Option<ClientRateLimit>,
+    /// The mesh key authenticating trusted clients, no client is trusted if `None`.
+    mesh_key: Option<MeshKey>,
+    /// The routes to the nodes connected to other relays of the mesh, if any.
+    mesh: Option<MeshRoutes>,
+    /// The session resumption configuration, sessions are not supported if `None`.
+    sessions: Option<SessionConfig>,
+    /// The keep-alive intervals clients can request, they are pinged at the default
+    /// interval if `None`.
+    keep_alive: Option<KeepAliveConfig>,
+    /// Whether clients can request the compression of packet payloads.
+    payload_compression: bool,
+    /// The handling of the packets sent to unknown nodes, silently dropped if `None`.
+    unknown_peers: Option<UnknownPeerConfig>,
+    /// The byte quotas of the nodes, unlimited if `None`.
+    client_quota: Option<QuotaConfig>,
+    /// The queues of the packets sent to each client.
+    send_queue: SendQueueConfig,
+    /// Rate-limiting configuration for a trusted client connection.
+    ///
+    /// Replaces [`Self::client_rx_ratelimit`] for trusted clients.
+    trusted_client_rx_ratelimit: Option<ClientRateLimit>,
+    /// Rate-limiting configuration for the data sent to a client connection.
+    ///
+    /// Summed over all senders, does not apply to trusted clients.
+    client_tx_ratelimit: Option<ClientRateLimit>,
+    /// The limit of connections in the handshake phase, unlimited if `None`.
+    handshake_limit: Option<HandshakeLimit>,
+    /// The limit of open connections.
+    connection_limit: ConnectionLimit,
+    /// The limit of the clients connected from a single source, unlimited if `None`.
+    client_ip_limit: Option<ClientIpLimit>,
     /// The capacity of the key cache.
     key_cache_capacity: usize,
+    /// The eviction policy of the key cache.
+    key_cache_eviction: KeyCacheEviction,
     /// Access config for nodes.
     access: AccessConfig,
+    /// The admin API configuration, the admin API is disabled if `None`.
+    admin: Option<AdminConfig>,
+    /// The watchdog configuration, the watchdog is disabled if `None`.
+    watchdog: Option<WatchdogConfig>,
+    /// The response compression configuration, responses are not compressed if `None`.
+    compression: Option<CompressionConfig>,
+    /// The custom responses for errors.
+    error_pages: ErrorPages,
+    /// The configuration of the services running beside this server.
+    services: ServiceConfig,
+    /// Called whenever a client disconnects.
+    disconnect_hook: Option<DisconnectHook>,
+    /// Decides whether connecting clients may use the relay.
+    authorizer: Option<Arc<dyn ClientAuthorizer>>,
+    /// Whether listeners bound to IPv6 addresses only accept IPv6 connections, the
+    /// operating system default is used if `None`.
+    ipv6_only: Option<bool>,
+    /// The networks of the proxies whose connections start with a PROXY protocol header,
+    /// `None` if the connections do not start with one.
+    proxy_protocol: Option<Vec<IpNet>>,
+    /// Where to write the access records of the requests.
+    access_log: Option<AccessLog>,
+    /// An already bound listener served instead of binding `addr`.
+    listener: Option<std::net::TcpListener>,
+    /// Faults injected into the accepted connections.
+    #[cfg(any(test, feature = "test-utils"))]
+    faults: Option<crate::faults::FaultConfig>,
+}
+
+/// The configuration of the services running beside the relay HTTP(S) server.
+///
+/// Only reported by the `GET /admin/config` endpoint of the admin API.
+#[derive(Debug, Clone, Default, Serialize)]
+pub(super) struct ServiceConfig {
+    /// How the TLS certificates are obtained, `None` when serving plain HTTP.
+    pub(super) tls: Option<TlsMode>,
+    /// The address of the plain HTTP server for captive portal detection, when serving HTTPS.
+    pub(super) http_addr: Option<SocketAddr>,
+    /// The addresses of the STUN server.
+    pub(super) stun_addrs: Vec<SocketAddr>,
+    /// The address of the QUIC server.
+    pub(super) quic_addr: Option<SocketAddr>,
+    /// The address of the metrics server.
+    pub(super) metrics_addr: Option<SocketAddr>,
+}
+
+/// How the TLS certificates of the server are obtained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsMode {
+    /// From Let's Encrypt.
+    LetsEncrypt,
+    /// A static certificate chain.
+    Manual,
+    /// A certificate chain which is reloaded.
+    Reloading,
 }
 
 impl ServerBuilder {
@@ -175,10 +463,37 @@ impl ServerBuilder {
             addr,
             tls_config: None,
             handlers: Default::default(),
-            headers: HeaderMap::new(),
+            headers: Headers::default(),
             client_rx_ratelimit: None,
+            mesh_key: None,
+            mesh: None,
+            sessions: None,
+            keep_alive: None,
+            payload_compression: false,
+            unknown_peers: None,
+            client_quota: None,
+            send_queue: SendQueueConfig::default(),
+            trusted_client_rx_ratelimit: None,
+            client_tx_ratelimit: None,
+            handshake_limit: None,
+            connection_limit: ConnectionLimit::default(),
+            client_ip_limit: None,
             key_cache_capacity: DEFAULT_KEY_CACHE_CAPACITY,
+            key_cache_eviction: KeyCacheEviction::default(),
             access: AccessConfig::Everyone,
+            admin: None,
+            watchdog: None,
+            compression: None,
+            error_pages: ErrorPages::default(),
+            services: ServiceConfig::default(),
+            disconnect_hook: None,
+            authorizer: None,
+            ipv6_only: None,
+            proxy_protocol: None,
+            access_log: None,
+            listener: None,
+            #[cfg(any(test, feature = "test-utils"))]
+            faults: None,
         }
     }
 
@@ -188,6 +503,57 @@ impl ServerBuilder {
         self
     }
 
+    /// Enables the admin API.
+    pub(super) fn admin(mut self, admin: Option<AdminConfig>) -> Self {
+        self.admin = admin;
+        self
+    }
+
+    /// Enables the watchdog.
+    pub(super) fn watchdog(mut self, watchdog: Option<WatchdogConfig>) -> Self {
+        self.watchdog = watchdog;
+        self
+    }
+
+    /// Compresses the responses of the request handlers and the admin API.
+    pub(super) fn compression(mut self, compression: Option<CompressionConfig>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the custom responses for errors.
+    pub(super) fn error_pages(mut self, error_pages: ErrorPages) -> Self {
+        self.error_pages = error_pages;
+        self
+    }
+
+    /// Sets the configuration of the services running beside this server.
+    ///
+    /// Only used to report the effective configuration in the admin API.
+    pub(super) fn services(mut self, services: ServiceConfig) -> Self {
+        self.services = services;
+        self
+    }
+
+    /// Sets the callback invoked whenever a client disconnects.
+    pub(super) fn disconnect_hook(mut self, hook: Option<DisconnectHook>) -> Self {
+        self.disconnect_hook = hook;
+        self
+    }
+
+    /// Sets the authorizer deciding whether connecting clients may use the relay.
+    pub(super) fn authorizer(mut self, authorizer: Option<Arc<dyn ClientAuthorizer>>) -> Self {
+        self.authorizer = authorizer;
+        self
+    }
+
+    /// Injects faults into all accepted connections.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub(super) fn faults(mut self, faults: crate::faults::FaultConfig) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
     /// Serves all requests content using TLS.
     pub(super) fn tls_config(mut self, config: Option<TlsConfig>) -> Self {
         self.tls_config = config;
@@ -203,6 +569,140 @@ impl ServerBuilder {
         self
     }
 
+    /// Sets the mesh key authenticating trusted clients.
+    ///
+    /// Clients proving the knowledge of the key may watch the connections of all clients
+    /// and forward packets on behalf of other nodes.
+    pub(super) fn mesh_key(mut self, mesh_key: Option<MeshKey>) -> Self {
+        self.mesh_key = mesh_key;
+        self
+    }
+
+    /// Forwards packets to nodes connected to other relays of the mesh.
+    pub(super) fn mesh(mut self, mesh: Option<MeshRoutes>) -> Self {
+        self.mesh = mesh;
+        self
+    }
+
+    /// Sets the session resumption configuration.
+    pub(super) fn sessions(mut self, sessions: Option<SessionConfig>) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    /// Sets the keep-alive intervals clients can request.
+    pub(super) fn keep_alive(mut self, keep_alive: Option<KeepAliveConfig>) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Sets whether clients can request the compression of packet payloads.
+    pub(super) fn payload_compression(mut self, enable: bool) -> Self {
+        self.payload_compression = enable;
+        self
+    }
+
+    /// Sets the handling of the packets sent to unknown nodes.
+    pub(super) fn unknown_peers(mut self, unknown_peers: Option<UnknownPeerConfig>) -> Self {
+        self.unknown_peers = unknown_peers;
+        self
+    }
+
+    /// Sets the byte quotas of the nodes.
+    ///
+    /// By default the bytes sent by nodes are not limited, it never applies to trusted
+    /// clients.
+    pub(super) fn client_quota(mut self, quota: Option<QuotaConfig>) -> Self {
+        self.client_quota = quota;
+        self
+    }
+
+    /// Sets the depth of the queues of the packets sent to each client, and what happens
+    /// to the packets sent to a client whose queue is full.
+    pub(super) fn send_queue(mut self, send_queue: SendQueueConfig) -> Self {
+        self.send_queue = send_queue;
+        self
+    }
+
+    /// Sets the rate-limit configuration for incoming data of trusted clients.
+    ///
+    /// By default no rate limit is enforced on trusted clients, regardless of
+    /// [`Self::client_rx_ratelimit`].
+    pub(super) fn trusted_client_rx_ratelimit(mut self, config: ClientRateLimit) -> Self {
+        self.trusted_client_rx_ratelimit = Some(config);
+        self
+    }
+
+    /// Sets the per-client rate-limit configuration for outgoing data.
+    ///
+    /// The data sent to each client connection is rate-limited, summed over all senders.
+    /// By default no rate limit is enforced, it never applies to trusted clients.
+    pub(super) fn client_tx_ratelimit(mut self, config: ClientRateLimit) -> Self {
+        self.client_tx_ratelimit = Some(config);
+        self
+    }
+
+    /// Limits the number of connections in the handshake phase.
+    ///
+    /// By default the handshakes are not limited.
+    pub(super) fn handshake_limit(mut self, limit: Option<HandshakeLimit>) -> Self {
+        self.handshake_limit = limit;
+        self
+    }
+
+    /// Limits the number of open connections.
+    ///
+    /// By default the connections are not limited.
+    pub(super) fn connection_limit(mut self, limit: ConnectionLimit) -> Self {
+        self.connection_limit = limit;
+        self
+    }
+
+    /// Limits the number of clients connected from a single source.
+    ///
+    /// By default the clients are not limited, it never applies to trusted clients.
+    pub(super) fn client_ip_limit(mut self, limit: Option<ClientIpLimit>) -> Self {
+        self.client_ip_limit = limit;
+        self
+    }
+
+    /// Sets `IPV6_V6ONLY` on the listeners bound to IPv6 addresses.
+    ///
+    /// By default the operating system default is used.
+    pub(super) fn ipv6_only(mut self, ipv6_only: Option<bool>) -> Self {
+        self.ipv6_only = ipv6_only;
+        self
+    }
+
+    /// Reads the addresses of the clients from a PROXY protocol header.
+    ///
+    /// Every connection accepted from the `trusted_proxies` networks must start with a
+    /// version 1 or 2 header, sent by the load balancer in front of the server, connections
+    /// without one and connections from other addresses are closed.  The client address of
+    /// the header replaces the address of the load balancer everywhere, in the logs, access
+    /// checks and limits.  Disabled by default.
+    pub(super) fn proxy_protocol(mut self, trusted_proxies: Option<Vec<IpNet>>) -> Self {
+        self.proxy_protocol = trusted_proxies;
+        self
+    }
+
+    /// Writes a structured record of every request to the access log.
+    ///
+    /// Upgrades to the relay protocol are recorded once the client completed its
+    /// handshake, with its node ID.  Disabled by default.
+    pub(super) fn access_log(mut self, access_log: Option<AccessLog>) -> Self {
+        self.access_log = access_log;
+        self
+    }
+
+    /// Serves on an already bound listener instead of binding the address of the server.
+    ///
+    /// Used for listeners handed over by a previous server process.
+    pub(super) fn listener(mut self, listener: Option<std::net::TcpListener>) -> Self {
+        self.listener = listener;
+        self
+    }
+
     /// Adds a custom handler for a specific Method & URI.
     pub(super) fn request_handler(
         mut self,
@@ -214,48 +714,262 @@ impl ServerBuilder {
         self
     }
 
+    /// Adds a custom handler for a specific Method & URI, belonging to a route group.
+    ///
+    /// The responses of the handler carry the headers of the group, if configured using
+    /// [`Self::route_group`].
+    pub(super) fn route_group_handler(
+        mut self,
+        group: RouteGroup,
+        method: Method,
+        uri_path: &'static str,
+        handler: HyperHandler,
+    ) -> Self {
+        self.headers
+            .routes
+            .insert((method.clone(), uri_path), group);
+        self.request_handler(method, uri_path, handler)
+    }
+
     /// Adds HTTP headers to responses.
     pub(super) fn headers(mut self, headers: HeaderMap) -> Self {
         for (k, v) in headers.iter() {
-            self.headers.insert(k.clone(), v.clone());
+            self.headers.default.insert(k.clone(), v.clone());
         }
         self
     }
 
+    /// Sets the HTTP headers of the responses of a route group.
+    ///
+    /// The responses of the group carry these headers instead of the default headers set
+    /// by [`Self::headers`], allowing e.g. to omit headers only suitable for HTML pages.
+    pub(super) fn route_group(mut self, group: RouteGroup, headers: HeaderMap) -> Self {
+        self.headers
+            .groups
+            .entry(group)
+            .or_default()
+            .extend(headers);
+        self
+    }
+
+    /// Sets HTTP headers of all responses, replacing the default and group headers of the
+    /// same name.
+    ///
+    /// These headers can be replaced by [`ServerHandle::reload`].
+    pub(super) fn extra_headers(mut self, headers: HeaderMap) -> Self {
+        *self.headers.extra.get_mut().expect("poisoned") = headers;
+        self
+    }
+
+    /// Sets HTTP headers of the responses of route groups, replacing all other headers of
+    /// the same name.
+    ///
+    /// These headers can be replaced by [`ServerHandle::reload`].
+    pub(super) fn extra_route_headers(mut self, headers: HashMap<RouteGroup, HeaderMap>) -> Self {
+        *self.headers.extra_routes.get_mut().expect("poisoned") = headers;
+        self
+    }
+
     /// Set the capacity of the cache for public keys.
     pub fn key_cache_capacity(mut self, capacity: usize) -> Self {
         self.key_cache_capacity = capacity;
         self
     }
 
+    /// Set the eviction policy of the cache for public keys.
+    pub fn key_cache_eviction(mut self, eviction: KeyCacheEviction) -> Self {
+        self.key_cache_eviction = eviction;
+        self
+    }
+
+    /// Describes the effective configuration for the admin API.
+    ///
+    /// Secrets, like the mesh key and the admin API token, are left out.  The address of
+    /// the relay listener is filled in when serving the request, it changes on rebinds.
+    fn effective_config(&self) -> serde_json::Value {
+        let handshakes = self.handshake_limit.map(|limit| {
+            serde_json::json!({
+                "max_concurrent": limit.max_concurrent,
+                "queue_timeout_ms": limit.queue_timeout.as_millis(),
+            })
+        });
+        let watchdog = self.watchdog.as_ref().map(|watchdog| {
+            serde_json::json!({
+                "interval_secs": watchdog.interval.as_secs(),
+                "max_connection_tasks": watchdog.max_connection_tasks,
+                "max_client_tasks": watchdog.max_client_tasks,
+                "max_orphaned_client_tasks": watchdog.max_orphaned_client_tasks,
+                "max_client_queue_depth": watchdog.max_client_queue_depth,
+                "report_path": watchdog.report_path,
+            })
+        });
+        let compression = self.compression.as_ref().map(|compression| {
+            serde_json::json!({
+                "encodings": compression.encodings,
+                "min_size": compression.min_size,
+                "content_types": compression.content_types,
+            })
+        });
+        serde_json::json!({
+            "listeners": {
+                "relay": null,
+                "http": self.services.http_addr,
+                "stun": self.services.stun_addrs,
+                "quic": self.services.quic_addr,
+                "metrics": self.services.metrics_addr,
+            },
+            "ipv6_only": self.ipv6_only,
+            "proxy_protocol": self.proxy_protocol.is_some(),
+            "trusted_proxies": self.proxy_protocol.iter().flatten().map(ToString::to_string).collect::<Vec<_>>(),
+            "client_auth": self.client_auth(),
+            "access_log": self.access_log.as_ref().map(AccessLog::kind),
+            "tls": self.services.tls,
+            "limits": {
+                "client_rx": rate_limit_json(self.client_rx_ratelimit),
+                "trusted_client_rx": rate_limit_json(self.trusted_client_rx_ratelimit),
+                "client_tx": rate_limit_json(self.client_tx_ratelimit),
+                "client_quota": self.client_quota.map(|quota| {
+                    serde_json::json!({
+                        "daily_bytes": quota.daily_bytes,
+                        "monthly_bytes": quota.monthly_bytes,
+                    })
+                }),
+                "handshakes": handshakes,
+                "connections": {
+                    "max_total": self.connection_limit.max_total,
+                    "max_per_ip": self.connection_limit.max_per_ip,
+                },
+                "clients_per_ip": self.client_ip_limit.as_ref().map(|limit| {
+                    serde_json::json!({
+                        "max_clients": limit.max_clients,
+                        "exempt": limit.exempt.iter().map(ToString::to_string).collect::<Vec<_>>(),
+                    })
+                }),
+                "client_send_queue_depth": self.send_queue.depth,
+                "client_send_queue_overflow": self.send_queue.overflow,
+                "write_timeout_ms": SERVER_WRITE_TIMEOUT.as_millis(),
+            },
+            "key_cache": {
+                "capacity": self.key_cache_capacity,
+                "eviction": self.key_cache_eviction,
+            },
+            "access": access_kind(&self.access),
+            "mesh_key": self.mesh_key.is_some(),
+            "sessions": self.sessions.as_ref().map(|sessions| {
+                serde_json::json!({
+                    "grace_period_ms": sessions.grace_period.as_millis(),
+                })
+            }),
+            "keep_alive": self.keep_alive.as_ref().map(|keep_alive| {
+                serde_json::json!({
+                    "default_interval_ms": keep_alive.default_interval.as_millis(),
+                    "min_interval_ms": keep_alive.min_interval.as_millis(),
+                    "max_interval_ms": keep_alive.max_interval.as_millis(),
+                })
+            }),
+            "payload_compression": self.payload_compression,
+            "unknown_peers": self.unknown_peers.as_ref().map(|unknown_peers| {
+                serde_json::json!({
+                    "notify": unknown_peers.notify,
+                    "max_per_second": unknown_peers.max_per_second,
+                    "max_burst": unknown_peers.max_burst,
+                })
+            }),
+            "watchdog": watchdog,
+            "compression": compression,
+            "error_pages": {
+                "not_found": self.error_pages.not_found.as_ref().map(ErrorPage::kind),
+                "bad_request": self.error_pages.bad_request.as_ref().map(ErrorPage::kind),
+                "unavailable": self.error_pages.unavailable.as_ref().map(ErrorPage::kind),
+            },
+        })
+    }
+
+    /// Whether the TLS acceptor requires client certificates.
+    fn client_auth(&self) -> bool {
+        self.tls_config
+            .as_ref()
+            .is_some_and(|tls| matches!(tls.acceptor, TlsAcceptor::ManualMutual(_)))
+    }
+
     /// Builds and spawns an HTTP(S) Relay Server.
-    pub(super) async fn spawn(self) -> Result<Server> {
+    pub(super) fn spawn(self) -> Result<Server> {
         let cancel_token = CancellationToken::new();
 
-        let service = RelayService::new(
+        if let Some(keep_alive) = &self.keep_alive {
+            keep_alive.validate()?;
+        }
+        if let Some(unknown_peers) = &self.unknown_peers {
+            unknown_peers.validate()?;
+        }
+        self.send_queue.validate()?;
+        let config = self.effective_config();
+        let client_auth = self.client_auth();
+        let http_str = self.tls_config.as_ref().map_or("HTTP/WS", |_| "HTTPS/WSS");
+        let access_log = self.access_log.map(AccessLogger::new).transpose()?;
+        let service = RelayService::builder(
             self.handlers,
             self.headers,
             self.client_rx_ratelimit,
-            KeyCache::new(self.key_cache_capacity),
+            KeyCache::for_server(self.key_cache_capacity, self.key_cache_eviction),
             self.access,
-        );
+            self.admin,
+            self.watchdog,
+        )
+        .with_mesh_key(self.mesh_key, self.trusted_client_rx_ratelimit)
+        .with_clients(self.mesh, self.sessions, self.client_quota)
+        .with_keep_alive(self.keep_alive)
+        .with_payload_compression(self.payload_compression)
+        .with_unknown_peers(self.unknown_peers)
+        .with_send_queue(self.send_queue)
+        .with_tx_rate_limit(self.client_tx_ratelimit)
+        .with_handshake_limit(self.handshake_limit)
+        .with_client_ip_limit(self.client_ip_limit)
+        .with_compression(self.compression)
+        .with_error_pages(self.error_pages)
+        .with_disconnect_hook(self.disconnect_hook)
+        .with_authorizer(self.authorizer)
+        .with_ipv6_only(self.ipv6_only)
+        .with_access_log(access_log)
+        .with_client_auth(client_auth)
+        .with_tls(self.tls_config)
+        .with_config(config);
+        #[cfg(any(test, feature = "test-utils"))]
+        let service = service.with_faults(self.faults);
+        let service = service.build();
+        let watchdog_task = service.0.watchdog.is_some().then(|| {
+            let service = service.clone();
+            AbortOnDropHandle::new(tokio::task::spawn(
+                service
+                    .run_watchdog()
+                    .instrument(info_span!("relay-watchdog")),
+            ))
+        });
 
         let addr = self.addr;
-        let tls_config = self.tls_config;
+        let limiter = ConnectionLimiter::new(self.connection_limit);
+        let trusted_proxies: Option<Arc<[IpNet]>> = self.proxy_protocol.map(Into::into);
 
         // Bind a TCP listener on `addr` and handles content using HTTPS.
-
-        let listener = TcpListener::bind(&addr)
-            .await
-            .with_context(|| format!("failed to bind server socket to {addr}"))?;
+        let listener = match self.listener {
+            Some(listener) => inherit_listener(listener)
+                .with_context(|| format!("failed to use the inherited listener for {addr}"))?,
+            None => bind_listener(addr, self.ipv6_only)
+                .with_context(|| format!("failed to bind server socket to {addr}"))?,
+        };
 
         let addr = listener.local_addr()?;
-        let http_str = tls_config.as_ref().map_or("HTTP/WS", |_| "HTTPS/WSS");
+        let listener = Arc::new(listener);
+        service.0.rebind.set_current(listener.clone());
+        let mut listener = Some(listener);
         info!("[{http_str}] relay: serving on {addr}");
 
+        let handle_service = service.clone();
         let cancel = cancel_token.clone();
         let task = tokio::task::spawn(
             async move {
+                // the watchdog runs as long as the server
+                let _watchdog_task = watchdog_task;
                 // create a join set to track all our connection tasks
                 let mut set = tokio::task::JoinSet::new();
                 loop {
@@ -271,15 +985,68 @@ impl ServerBuilder {
                                 }
                             }
                         }
-                        res = listener.accept() => match res {
+                        _ = service.0.rebind.notified() => {
+                            // Dropping the old listener stops accepting on it, the
+                            // connections it accepted keep being served.
+                            if let Some(new_listener) = service.0.rebind.take() {
+                                info!(
+                                    old = ?listener.as_ref().map(|l| l.local_addr()),
+                                    new = ?new_listener.local_addr(),
+                                    "[{http_str}] relay: rebound listener",
+                                );
+                                listener = Some(new_listener);
+                            } else if service.0.rebind.current().is_none() {
+                                if let Some(old) = listener.take() {
+                                    info!(addr = ?old.local_addr(), "[{http_str}] relay: stopped accepting");
+                                }
+                            }
+                        }
+                        res = accept(listener.as_deref()) => match res {
+                            Ok((stream, peer_addr)) if trusted_proxies.is_some() => {
+                                let peer_addr = canonical_addr(peer_addr);
+                                let trusted = trusted_proxies
+                                    .iter()
+                                    .flat_map(|nets| nets.iter())
+                                    .any(|net| net.contains(&peer_addr.ip()));
+                                if !trusted {
+                                    debug!("closing connection from {peer_addr}: not a trusted proxy");
+                                    inc!(Metrics, proxy_headers_rejected);
+                                    continue;
+                                }
+                                // Counted before the header is read, so connections which
+                                // never send one do not exceed the limit.
+                                let permit = match limiter.as_ref().map(ConnectionLimiter::acquire_pending).transpose() {
+                                    Ok(permit) => permit,
+                                    Err(err) => {
+                                        debug!("closing connection from {peer_addr}: {err:#}");
+                                        inc!(Metrics, connections_rejected);
+                                        continue;
+                                    }
+                                };
+                                let tls_config = service.0.tls_config();
+                                let service = service.clone();
+                                // The header is read by the connection task, to not hold up
+                                // the accept loop.
+                                set.spawn(async move {
+                                    service
+                                        .handle_proxied_connection(stream, peer_addr, tls_config, permit)
+                                        .await
+                                }.instrument(info_span!("proxied", proxy = %peer_addr)));
+                            }
                             Ok((stream, peer_addr)) => {
+                                let peer_addr = canonical_addr(peer_addr);
+                                // Checked before any work is done for the connection, the
+                                // stream is closed when dropped.
+                                let Some(permit) = admit_connection(limiter.as_ref(), peer_addr) else {
+                                    continue;
+                                };
                                 debug!("connection opened from {peer_addr}");
-                                let tls_config = tls_config.clone();
+                                let tls_config = service.0.tls_config();
                                 let service = service.clone();
                                 // spawn a task to handle the connection
                                 set.spawn(async move {
                                     service
-                                        .handle_connection(stream, tls_config)
+                                        .handle_connection(stream, peer_addr, tls_config, permit)
                                         .await
                                 }.instrument(info_span!("conn", peer = %peer_addr)));
                             }
@@ -289,6 +1056,8 @@ impl ServerBuilder {
                         }
                     }
                 }
+                service.0.rebind.close();
+                drop(listener);
                 service.shutdown().await;
                 set.shutdown().await;
                 debug!("server has been shutdown.");
@@ -300,6 +1069,121 @@ impl ServerBuilder {
             addr,
             http_server_task: AbortOnDropHandle::new(task),
             cancel_server_loop: cancel_token,
+            service: handle_service,
+        })
+    }
+}
+
+/// Describes a rate limit for the effective configuration.
+fn rate_limit_json(limit: Option<ClientRateLimit>) -> serde_json::Value {
+    serde_json::json!(limit.map(|limit| {
+        serde_json::json!({
+            "bytes_per_second": limit.bytes_per_second,
+            "max_burst_bytes": limit.max_burst_bytes,
+        })
+    }))
+}
+
+/// Names the kind of an access configuration for the effective configuration.
+fn access_kind(access: &AccessConfig) -> &'static str {
+    match access {
+        AccessConfig::Everyone => "everyone",
+        AccessConfig::Restricted(_) => "restricted",
+        AccessConfig::Allowlist(_) => "allowlist",
+        AccessConfig::Denylist(_) => "denylist",
+    }
+}
+
+/// What a connecting client is known by before its handshake, for the [`ClientAuthorizer`].
+#[derive(Debug, Clone)]
+pub(super) struct ClientRequest {
+    /// The address of the peer of the connection.
+    pub(super) remote_addr: SocketAddr,
+    /// The headers of the request which upgraded the connection, if any.
+    pub(super) headers: HeaderMap,
+    /// The access record of the connection, written once the handshake completed.
+    pub(super) access: Option<PendingAccess>,
+    /// The verified TLS client certificate of the connection, if any.
+    pub(super) identity: Option<ClientIdentity>,
+}
+
+impl ClientRequest {
+    /// Returns the request of a client speaking the relay protocol right away.
+    fn direct(remote_addr: SocketAddr) -> Self {
+        Self {
+            remote_addr,
+            headers: HeaderMap::new(),
+            access: None,
+            identity: None,
+        }
+    }
+
+    /// Returns the request of a client upgrading an HTTP request.
+    fn upgrade(req: &mut Request<Incoming>) -> Self {
+        let RemoteAddr(remote_addr) = *req
+            .extensions()
+            .get()
+            .expect("requests carry the remote address");
+        Self {
+            remote_addr,
+            headers: req.headers().clone(),
+            access: req.extensions_mut().remove(),
+            identity: req.extensions_mut().remove(),
+        }
+    }
+
+    /// Defers the access record of the request until the client completed its handshake.
+    fn defer_access(&mut self, response: &mut Response<BytesBody>) {
+        if let Some(access) = &mut self.access {
+            access.respond(response.status(), body_len(response));
+            response.extensions_mut().insert(AccessDeferred);
+        }
+    }
+}
+
+/// Marks the responses whose access record is written by the upgraded connection.
+#[derive(Debug, Clone, Copy)]
+struct AccessDeferred;
+
+/// The address of the peer of the connection a request was received on.
+#[derive(Debug, Clone, Copy)]
+struct RemoteAddr(SocketAddr);
+
+/// Serves the requests of a connection, passing its remote address on to the relay handlers.
+#[derive(Debug, Clone)]
+struct ConnectionService {
+    service: RelayService,
+    remote_addr: SocketAddr,
+    /// The verified TLS client certificate of the connection.
+    identity: Option<ClientIdentity>,
+}
+
+impl Service<Request<Incoming>> for ConnectionService {
+    type Response = Response<BytesBody>;
+    type Error = HyperError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, mut req: Request<Incoming>) -> Self::Future {
+        req.extensions_mut().insert(RemoteAddr(self.remote_addr));
+        if let Some(identity) = &self.identity {
+            req.extensions_mut().insert(identity.clone());
+        }
+        if self.service.0.access_log.is_none() {
+            return self.service.call(req);
+        }
+        let mut access = PendingAccess::new(&req, self.remote_addr);
+        req.extensions_mut().insert(access.clone());
+        let service = self.service.clone();
+        let res = self.service.call(req);
+        Box::pin(async move {
+            let res = res.await?;
+            if let Some(access_log) = &service.0.access_log {
+                if res.extensions().get::<AccessDeferred>().is_none() {
+                    access.respond(res.status(), body_len(&res));
+                    access_log.log(access.finish(None));
+                }
+            }
+            Ok(res)
         })
     }
 }
@@ -311,85 +1195,326 @@ struct RelayService(Arc<Inner>);
 #[derive(Debug)]
 struct Inner {
     handlers: Handlers,
-    headers: HeaderMap,
+    headers: Headers,
     clients: Clients,
     write_timeout: Duration,
-    rate_limit: Option<ClientRateLimit>,
+    /// The rate limits of connecting clients.
+    rate_limits: RwLock<RateLimits>,
+    /// The mesh key authenticating trusted clients.
+    mesh_key: Option<MeshKey>,
+    /// Limits the connections in the handshake phase.
+    handshakes: Option<HandshakeLimiter>,
+    /// Limits the clients connected from a single source.
+    client_ips: Option<ClientIpLimiter>,
+    /// Compression of the responses of the request handlers and the admin API.
+    compression: Option<CompressionConfig>,
+    /// The custom responses for errors.
+    error_pages: ErrorPages,
+    /// Whether new relay connections are refused, see [`ADMIN_DRAIN_PATH`].
+    draining: AtomicBool,
+    /// The effective configuration served by the admin API.
+    config: serde_json::Value,
+    /// Called whenever a client disconnects.
+    disconnect_hook: Option<DisconnectHook>,
+    /// Decides whether connecting clients may use the relay.
+    authorizer: Option<Arc<dyn ClientAuthorizer>>,
+    /// Records the requests, if an access log is configured.
+    access_log: Option<AccessLogger>,
+    /// Whether the clients must present a TLS client certificate.
+    client_auth: bool,
+    /// The keep-alive intervals clients can request.
+    keep_alive: Option<KeepAliveConfig>,
+    /// Whether clients can request the compression of packet payloads.
+    payload_compression: bool,
+    /// The handling of the packets sent to unknown nodes, silently dropped if `None`.
+    unknown_peers: Option<UnknownPeerConfig>,
+    /// The queues of the packets sent to each client.
+    send_queue: SendQueueConfig,
     key_cache: KeyCache,
-    access: AccessConfig,
+    access: RwLock<Arc<AccessConfig>>,
+    /// The TLS configuration of the accepted connections, HTTP is served if `None`.
+    tls: RwLock<Option<TlsConfig>>,
+    admin: Option<AdminConfig>,
+    watchdog: Option<Watchdog>,
+    /// Counts the running connection tasks.
+    connection_tasks: TaskCounter,
+    /// Hands a new listener bound by the admin API to the accept loop.
+    rebind: Rebind,
+    /// Faults injected into the accepted connections.
+    #[cfg(any(test, feature = "test-utils"))]
+    faults: Option<crate::faults::FaultConfig>,
 }
 
-impl RelayService {
-    /// Upgrades the HTTP connection to the relay protocol, runs relay client.
-    fn call_client_conn(
-        &self,
-        mut req: Request<Incoming>,
-    ) -> Pin<Box<dyn Future<Output = Result<Response<BytesBody>, hyper::Error>> + Send>> {
-        // TODO: soooo much cloning. See if there is an alternative
-        let this = self.clone();
-        let mut builder = Response::builder();
-        for (key, value) in self.0.headers.iter() {
-            builder = builder.header(key, value);
-        }
-
-        async move {
-            {
-                // Send a 400 to any request that doesn't have an `Upgrade` header.
-                let Some(protocol) = req.headers().get(UPGRADE).and_then(Protocol::parse_header)
-                else {
-                    return Ok(builder
-                        .status(StatusCode::BAD_REQUEST)
-                        .body(body_empty())
-                        .expect("valid body"));
-                };
+/// The rate limits of the clients, applied when they connect.
+#[derive(Debug, Default, Clone, Copy)]
+struct RateLimits {
+    /// The rate limit of untrusted clients.
+    client_rx: Option<ClientRateLimit>,
+    /// The rate limit of trusted clients.
+    trusted_client_rx: Option<ClientRateLimit>,
+    /// The rate limit of the data sent to untrusted clients.
+    client_tx: Option<ClientRateLimit>,
+}
 
-                let websocket_headers = if protocol == Protocol::Websocket {
-                    let Some(key) = req.headers().get("Sec-WebSocket-Key").cloned() else {
+/// A group of routes of the Relay HTTP(S) server, whose responses share HTTP headers.
+///
+/// See [`RelayConfig::route_headers`].
+///
+/// [`RelayConfig::route_headers`]: super::RelayConfig::route_headers
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum RouteGroup {
+    /// The HTML pages, like the index page, and the `robots.txt` file.
+    Pages,
+    /// The relay endpoints, including `CONNECT` tunnels.
+    Relay,
+    /// The latency probe endpoints, like [`RELAY_PROBE_PATH`].
+    ///
+    /// [`RELAY_PROBE_PATH`]: crate::http::RELAY_PROBE_PATH
+    Probes,
+    /// The error responses to requests which match no route.
+    Errors,
+}
+
+/// The HTTP headers of the responses, per route group.
+#[derive(Debug, Default)]
+struct Headers {
+    /// The headers of the routes which are not in a configured group.
+    default: HeaderMap,
+    groups: HashMap<RouteGroup, HeaderMap>,
+    /// The groups of the custom routes.
+    routes: HashMap<(Method, &'static str), RouteGroup>,
+    /// The headers of all responses, replacing the headers of the groups with the same name.
+    ///
+    /// Unlike the headers of the groups these can be replaced by [`ServerHandle::reload`].
+    extra: RwLock<HeaderMap>,
+    /// The headers of the responses of a group, replacing all other headers with the same
+    /// name.
+    extra_routes: RwLock<HashMap<RouteGroup, HeaderMap>>,
+}
+
+impl Headers {
+    /// Starts a response with the headers of a route group, or the default headers.
+    fn response(&self, group: Option<&RouteGroup>) -> ResponseBuilder {
+        let headers = group
+            .and_then(|group| self.groups.get(group))
+            .unwrap_or(&self.default);
+        let extra = self.extra.read().expect("poisoned");
+        let extra_routes = self.extra_routes.read().expect("poisoned");
+        let empty = HeaderMap::new();
+        let route = group
+            .and_then(|group| extra_routes.get(group))
+            .unwrap_or(&empty);
+        let mut response = Response::builder();
+        for (key, value) in headers
+            .iter()
+            .filter(|(key, _)| !extra.contains_key(*key) && !route.contains_key(*key))
+        {
+            response = response.header(key.clone(), value.clone());
+        }
+        for (key, value) in extra.iter().filter(|(key, _)| !route.contains_key(*key)) {
+            response = response.header(key.clone(), value.clone());
+        }
+        for (key, value) in route.iter() {
+            response = response.header(key.clone(), value.clone());
+        }
+        response
+    }
+
+    /// Starts a response of a custom route.
+    fn route_response(&self, method: &Method, path: &str) -> ResponseBuilder {
+        self.response(self.routes.get(&(method.clone(), path)))
+    }
+}
+
+/// Hands over a new listener to the accept loop of the server.
+#[derive(Debug, Default)]
+struct Rebind {
+    listener: std::sync::Mutex<Option<Arc<TcpListener>>>,
+    notify: tokio::sync::Notify,
+    /// The current listener, `None` once the server stopped accepting.
+    current: std::sync::Mutex<Option<Arc<TcpListener>>>,
+    /// The `IPV6_V6ONLY` setting for new listeners.
+    ipv6_only: Option<bool>,
+}
+
+impl Rebind {
+    /// Replaces the listener of the accept loop.
+    fn replace(&self, listener: TcpListener) {
+        let listener = Arc::new(listener);
+        self.set_current(listener.clone());
+        *self.listener.lock().expect("poisoned") = Some(listener);
+        self.notify.notify_one();
+    }
+
+    /// Makes the accept loop drop its listener without a replacement.
+    fn close(&self) {
+        self.current.lock().expect("poisoned").take();
+        self.listener.lock().expect("poisoned").take();
+        self.notify.notify_one();
+    }
+
+    fn set_current(&self, listener: Arc<TcpListener>) {
+        *self.current.lock().expect("poisoned") = Some(listener);
+    }
+
+    fn current(&self) -> Option<Arc<TcpListener>> {
+        self.current.lock().expect("poisoned").clone()
+    }
+
+    /// Returns the address of the current listener.
+    fn addr(&self) -> Option<SocketAddr> {
+        self.current()
+            .and_then(|listener| listener.local_addr().ok())
+    }
+
+    /// Waits until a new listener is available.
+    async fn notified(&self) {
+        self.notify.notified().await
+    }
+
+    fn take(&self) -> Option<Arc<TcpListener>> {
+        self.listener.lock().expect("poisoned").take()
+    }
+}
+
+/// Accepts a connection on the listener, never completes without one.
+async fn accept(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+impl RelayService {
+    /// Upgrades the HTTP connection to the relay protocol, runs relay client.
+    fn call_client_conn(
+        &self,
+        mut req: Request<Incoming>,
+    ) -> Pin<Box<dyn Future<Output = Result<Response<BytesBody>, hyper::Error>> + Send>> {
+        // TODO: soooo much cloning. See if there is an alternative
+        let this = self.clone();
+        let mut builder = self.0.headers.response(Some(&RouteGroup::Relay));
+
+        async move {
+            {
+                // Send a 503 to new clients while draining.
+                if this.0.draining.load(Ordering::Relaxed) {
+                    return Ok(this.0.error_response(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        req.uri().path(),
+                        builder,
+                    ));
+                }
+
+                // Send a 400 to any request that doesn't have an `Upgrade` header, or a
+                // `:protocol` on HTTP/2.
+                let extended_connect = extended_connect_protocol(&req);
+                let protocol = match extended_connect {
+                    Some(protocol) => protocol,
+                    None => req.headers().get(UPGRADE).and_then(Protocol::parse_header),
+                };
+                let Some(protocol) = protocol else {
+                    return Ok(this.0.error_response(
+                        StatusCode::BAD_REQUEST,
+                        req.uri().path(),
+                        builder,
+                    ));
+                };
+
+                // Websockets over HTTP/2 have no key to derive the accept key from.
+                let websocket_key = if protocol == Protocol::Websocket {
+                    let key = req.headers().get("Sec-WebSocket-Key").cloned();
+                    if key.is_none() && extended_connect.is_none() {
                         warn!("missing header Sec-WebSocket-Key for websocket relay protocol");
-                        return Ok(builder
-                            .status(StatusCode::BAD_REQUEST)
-                            .body(body_empty())
-                            .expect("valid body"));
-                    };
+                        return Ok(this.0.error_response(
+                            StatusCode::BAD_REQUEST,
+                            req.uri().path(),
+                            builder,
+                        ));
+                    }
 
                     let Some(version) = req.headers().get("Sec-WebSocket-Version").cloned() else {
                         warn!("missing header Sec-WebSocket-Version for websocket relay protocol");
-                        return Ok(builder
-                            .status(StatusCode::BAD_REQUEST)
-                            .body(body_empty())
-                            .expect("valid body"));
+                        return Ok(this.0.error_response(
+                            StatusCode::BAD_REQUEST,
+                            req.uri().path(),
+                            builder,
+                        ));
                     };
 
                     if version.as_bytes() != SUPPORTED_WEBSOCKET_VERSION.as_bytes() {
                         warn!("invalid header Sec-WebSocket-Version: {:?}", version);
-                        return Ok(builder
-                            .status(StatusCode::BAD_REQUEST)
+                        return Ok(this.0.error_response(
+                            StatusCode::BAD_REQUEST,
+                            req.uri().path(),
                             // It's convention to send back the version(s) we *do* support
-                            .header("Sec-WebSocket-Version", SUPPORTED_WEBSOCKET_VERSION)
-                            .body(body_empty())
-                            .expect("valid body"));
+                            builder.header("Sec-WebSocket-Version", SUPPORTED_WEBSOCKET_VERSION),
+                        ));
                     }
 
-                    Some((key, version))
+                    key
                 } else {
                     None
                 };
 
                 debug!(?protocol, "upgrading connection");
+                let mut request = ClientRequest::upgrade(&mut req);
+
+                let mut response = if extended_connect.is_some() {
+                    // On HTTP/2 the stream of the request is the upgraded connection once it
+                    // is confirmed with a 200.
+                    builder
+                        .status(StatusCode::OK)
+                        .body(body_empty())
+                        .expect("valid body")
+                } else {
+                    // Now return a 101 Response saying we agree to the upgrade to the
+                    // HTTP_UPGRADE_PROTOCOL
+                    builder = builder
+                        .status(StatusCode::SWITCHING_PROTOCOLS)
+                        .header(UPGRADE, HeaderValue::from_static(protocol.upgrade_header()));
+
+                    if let Some(key) = websocket_key {
+                        builder
+                            .header("Sec-WebSocket-Accept", &derive_accept_key(key.as_bytes()))
+                            .header(CONNECTION, "upgrade")
+                            .body(body_full("switching to websocket protocol"))
+                            .expect("valid body")
+                    } else {
+                        builder.body(body_empty()).expect("valid body")
+                    }
+                };
+                request.defer_access(&mut response);
 
                 // Setup a future that will eventually receive the upgraded
                 // connection and talk a new protocol, and spawn the future
                 // into the runtime.
                 //
-                // Note: This can't possibly be fulfilled until the 101 response
+                // Note: This can't possibly be fulfilled until the response
                 // is returned below, so it's better to spawn this future instead
                 // waiting for it to complete to then return a response.
                 tokio::task::spawn(
                     async move {
                         match hyper::upgrade::on(&mut req).await {
                             Ok(upgraded) => {
-                                if let Err(err) =
-                                    this.0.relay_connection_handler(protocol, upgraded).await
+                                if let Err(err) = this
+                                    .0
+                                    .relay_connection_handler(protocol, upgraded, request)
+                                    .await
                                 {
                                     warn!(
                                         ?protocol,
@@ -405,22 +1530,63 @@ impl RelayService {
                     .instrument(debug_span!("handler")),
                 );
 
-                // Now return a 101 Response saying we agree to the upgrade to the
-                // HTTP_UPGRADE_PROTOCOL
-                builder = builder
-                    .status(StatusCode::SWITCHING_PROTOCOLS)
-                    .header(UPGRADE, HeaderValue::from_static(protocol.upgrade_header()));
-
-                if let Some((key, _version)) = websocket_headers {
-                    Ok(builder
-                        .header("Sec-WebSocket-Accept", &derive_accept_key(key.as_bytes()))
-                        .header(CONNECTION, "upgrade")
-                        .body(body_full("switching to websocket protocol"))
-                        .expect("valid body"))
-                } else {
-                    Ok(builder.body(body_empty()).expect("valid body"))
-                }
+                Ok(response)
+            }
+        }
+        .boxed()
+    }
+
+    /// Accepts an HTTP `CONNECT` tunnel and runs the relay protocol over it.
+    ///
+    /// This is an alternative to the `Upgrade` handshake for networks where middleboxes
+    /// strip the `Upgrade` header.  The tunnel always speaks [`Protocol::Relay`], the
+    /// request target is not used as the tunnel always ends at this server.
+    fn call_client_tunnel(
+        &self,
+        mut req: Request<Incoming>,
+    ) -> Pin<Box<dyn Future<Output = Result<Response<BytesBody>, hyper::Error>> + Send>> {
+        let this = self.clone();
+        let builder = self.0.headers.response(Some(&RouteGroup::Relay));
+
+        async move {
+            if this.0.draining.load(Ordering::Relaxed) {
+                return Ok(this.0.error_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    req.uri().path(),
+                    builder,
+                ));
             }
+            debug!(target = %req.uri(), "accepting CONNECT tunnel");
+            let mut request = ClientRequest::upgrade(&mut req);
+            let mut response = builder
+                .status(StatusCode::OK)
+                .body(body_empty())
+                .expect("valid body");
+            request.defer_access(&mut response);
+
+            // As with the upgrade, the tunnel is only available once the response below
+            // has been sent.
+            tokio::task::spawn(
+                async move {
+                    match hyper::upgrade::on(&mut req).await {
+                        Ok(upgraded) => {
+                            if let Err(err) = this
+                                .0
+                                .relay_connection_handler(Protocol::Relay, upgraded, request)
+                                .await
+                            {
+                                warn!("error accepting tunneled connection: {err:#}");
+                            } else {
+                                debug!("tunneled connection completed");
+                            };
+                        }
+                        Err(err) => warn!("CONNECT tunnel error: {err:#}"),
+                    }
+                }
+                .instrument(debug_span!("tunnel-handler")),
+            );
+
+            Ok(response)
         }
         .boxed()
     }
@@ -432,48 +1598,346 @@ impl Service<Request<Incoming>> for RelayService {
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn call(&self, req: Request<Incoming>) -> Self::Future {
-        // Create a client if the request hits the relay endpoint.
+        // Create a client if the request hits the relay endpoint, on HTTP/2 with an
+        // extended CONNECT.
         if matches!(
             (req.method(), req.uri().path()),
             (&hyper::Method::GET, LEGACY_RELAY_PATH | RELAY_PATH)
-        ) {
+        ) || (extended_connect_protocol(&req).is_some()
+            && matches!(req.uri().path(), LEGACY_RELAY_PATH | RELAY_PATH))
+        {
             let this = self.clone();
             return Box::pin(async move { this.call_client_conn(req).await.map_err(Into::into) });
         }
+        // Create a client if the request opens a CONNECT tunnel to the relay.
+        if req.method() == hyper::Method::CONNECT {
+            let this = self.clone();
+            return Box::pin(async move { this.call_client_tunnel(req).await.map_err(Into::into) });
+        }
         // Otherwise handle the relay connection as normal.
 
+        let accept_encoding = req.headers().get(ACCEPT_ENCODING).cloned();
+        if let Some(admin) = &self.0.admin {
+            if req.uri().path().starts_with(ADMIN_PATH_PREFIX) {
+                let res = self.0.admin_fn(admin, req, self.0.default_response());
+                let this = self.clone();
+                return Box::pin(async move { this.compress(accept_encoding, res?).await });
+            }
+        }
+
         // Check all other possible endpoints.
         let uri = req.uri().clone();
         if let Some(res) = self.0.handlers.get(&(req.method().clone(), uri.path())) {
-            let f = res(req, self.0.default_response());
-            return Box::pin(async move { f });
+            let response = self.0.headers.route_response(req.method(), uri.path());
+            let f = res(req, response);
+            let this = self.clone();
+            return Box::pin(async move { this.compress(accept_encoding, f?).await });
         }
         // Otherwise return 404
-        let res = self.0.not_found_fn(req, self.0.default_response());
+        let res = self.0.not_found_fn(req);
         Box::pin(async move { res })
     }
 }
 
+impl RelayService {
+    /// Compresses the response if configured and accepted by the client.
+    async fn compress(
+        &self,
+        accept_encoding: Option<HeaderValue>,
+        response: Response<BytesBody>,
+    ) -> HyperResult<Response<BytesBody>> {
+        let Some(compression) = &self.0.compression else {
+            return Ok(response);
+        };
+        let (parts, body) = response.into_parts();
+        let body = match body.collect().await {
+            Ok(body) => body.to_bytes(),
+            Err(never) => match never {},
+        };
+        let response =
+            compression.compress(accept_encoding.as_ref(), Response::from_parts(parts, body));
+        Ok(response.map(body_full))
+    }
+}
+
 impl Inner {
+    /// The current access configuration.
+    fn access(&self) -> Arc<AccessConfig> {
+        self.access.read().expect("poisoned").clone()
+    }
+
+    /// The current TLS configuration, for a newly accepted connection.
+    fn tls_config(&self) -> Option<TlsConfig> {
+        self.tls.read().expect("poisoned").clone()
+    }
+
+    /// Builds the TLS configuration replacing the current one, with new certificates.
+    ///
+    /// The ALPN protocols and the kind of the acceptor are kept.
+    fn renewed_tls_config(&self, mut server_config: rustls::ServerConfig) -> Result<TlsConfig> {
+        let current = self.tls_config().context("the server does not serve TLS")?;
+        server_config.alpn_protocols = current.config.alpn_protocols.clone();
+        let config = Arc::new(server_config);
+        let acceptor = tokio_rustls::TlsAcceptor::from(config.clone());
+        let acceptor = match current.acceptor {
+            TlsAcceptor::LetsEncrypt(_) => bail!("the certificates are managed by Let's Encrypt"),
+            TlsAcceptor::Manual(_) => TlsAcceptor::Manual(acceptor),
+            TlsAcceptor::ManualMutual(_) => TlsAcceptor::ManualMutual(acceptor),
+        };
+        Ok(TlsConfig { config, acceptor })
+    }
+
     fn default_response(&self) -> ResponseBuilder {
-        let mut response = Response::builder();
-        for (key, value) in self.headers.iter() {
-            response = response.header(key.clone(), value.clone());
+        self.headers.response(None)
+    }
+
+    /// The effective configuration, with the address the relay listener is bound to now.
+    fn effective_config(&self) -> serde_json::Value {
+        let mut config = self.config.clone();
+        config["listeners"]["relay"] = serde_json::json!(self.rebind.addr());
+        // These may have been replaced by reloads.
+        let rate_limits = *self.rate_limits.read().expect("poisoned");
+        config["limits"]["client_rx"] = rate_limit_json(rate_limits.client_rx);
+        config["limits"]["trusted_client_rx"] = rate_limit_json(rate_limits.trusted_client_rx);
+        config["limits"]["client_tx"] = rate_limit_json(rate_limits.client_tx);
+        config["access"] = access_kind(&self.access()).into();
+        config
+    }
+
+    fn not_found_fn(&self, req: Request<Incoming>) -> HyperResult<Response<BytesBody>> {
+        let res = self.headers.response(Some(&RouteGroup::Errors));
+        HyperResult::Ok(self.error_response(StatusCode::NOT_FOUND, req.uri().path(), res))
+    }
+
+    /// Builds the response for an error, serving the custom page if one is configured.
+    fn error_response(
+        &self,
+        status: StatusCode,
+        path: &str,
+        res: ResponseBuilder,
+    ) -> Response<BytesBody> {
+        let page = match status {
+            StatusCode::NOT_FOUND => self.error_pages.not_found.as_ref(),
+            StatusCode::BAD_REQUEST => self.error_pages.bad_request.as_ref(),
+            StatusCode::SERVICE_UNAVAILABLE => self.error_pages.unavailable.as_ref(),
+            _ => None,
+        };
+        match page {
+            Some(page) => page
+                .response(status, path, res)
+                .expect("valid body")
+                .map(body_full),
+            None => {
+                let body = match status {
+                    StatusCode::NOT_FOUND => body_full("Not Found"),
+                    _ => body_empty(),
+                };
+                res.status(status).body(body).expect("valid body")
+            }
         }
-        response
     }
 
-    fn not_found_fn(
+    /// Serves the admin API, see [`AdminConfig`].
+    fn admin_fn(
         &self,
-        _req: Request<Incoming>,
-        mut res: ResponseBuilder,
+        admin: &AdminConfig,
+        req: Request<Incoming>,
+        res: ResponseBuilder,
     ) -> HyperResult<Response<BytesBody>> {
-        for (k, v) in self.headers.iter() {
-            res = res.header(k.clone(), v.clone());
+        let authorized = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "))
+            .is_some_and(|token| constant_time_eq(token, admin.bearer_token.as_bytes()));
+        if !authorized {
+            warn!(path = %req.uri().path(), "unauthorized admin API request");
+            let r = res
+                .status(StatusCode::UNAUTHORIZED)
+                .header(WWW_AUTHENTICATE, "Bearer")
+                .body(body_empty())?;
+            return Ok(r);
+        }
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, ADMIN_KEY_CACHE_PATH) => {
+                let body = serde_json::to_vec(&self.key_cache.stats())?;
+                let r = res
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body_full(body))?;
+                Ok(r)
+            }
+            (&Method::GET, ADMIN_WATCHDOG_PATH) => {
+                let Some(watchdog) = &self.watchdog else {
+                    return self.not_found_fn(req);
+                };
+                let body = serde_json::to_vec(&serde_json::json!({
+                    "current": watchdog.evaluate(self.watchdog_sample()),
+                    "last_alarm": watchdog.last_alarm(),
+                }))?;
+                let r = res
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body_full(body))?;
+                Ok(r)
+            }
+            (&Method::POST, ADMIN_LISTENER_PATH) => {
+                let addr = req.uri().query().and_then(|query| {
+                    url::form_urlencoded::parse(query.as_bytes())
+                        .find(|(key, _)| key == "addr")
+                        .map(|(_, value)| value.parse::<SocketAddr>())
+                });
+                let addr = match addr {
+                    Some(Ok(addr)) => addr,
+                    Some(Err(err)) => {
+                        let r = res
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(body_full(format!("invalid addr: {err}")))?;
+                        return Ok(r);
+                    }
+                    None => {
+                        let r = res
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(body_full("missing addr query parameter"))?;
+                        return Ok(r);
+                    }
+                };
+                let listener = match bind_listener(addr, self.rebind.ipv6_only) {
+                    Ok(listener) => listener,
+                    Err(err) => {
+                        warn!(%addr, "failed to rebind listener: {err:#}");
+                        let r = res
+                            .status(StatusCode::CONFLICT)
+                            .body(body_full(format!("failed to bind {addr}: {err:#}")))?;
+                        return Ok(r);
+                    }
+                };
+                let addr = listener.local_addr()?;
+                self.rebind.replace(listener);
+                let body = serde_json::to_vec(&serde_json::json!({ "addr": addr }))?;
+                let r = res
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body_full(body))?;
+                Ok(r)
+            }
+            (&Method::GET, ADMIN_CLIENTS_PATH) => {
+                let clients = self.clients.infos();
+                let body = serde_json::to_vec(&serde_json::json!({ "clients": clients }))?;
+                let r = res
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body_full(body))?;
+                Ok(r)
+            }
+            (&Method::GET, ADMIN_QUOTAS_PATH) => {
+                let Some(nodes) = self.clients.quota_usage() else {
+                    return self.not_found_fn(req);
+                };
+                let body = serde_json::to_vec(&serde_json::json!({ "nodes": nodes }))?;
+                let r = res
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body_full(body))?;
+                Ok(r)
+            }
+            (&Method::DELETE, ADMIN_QUOTAS_PATH) => {
+                let node = req.uri().query().and_then(|query| {
+                    url::form_urlencoded::parse(query.as_bytes())
+                        .find(|(key, _)| key == "node")
+                        .map(|(_, value)| value.parse::<PublicKey>())
+                });
+                let node = match node {
+                    Some(Ok(node)) => node,
+                    Some(Err(err)) => {
+                        let r = res
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(body_full(format!("invalid node: {err}")))?;
+                        return Ok(r);
+                    }
+                    None => {
+                        let r = res
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(body_full("missing node query parameter"))?;
+                        return Ok(r);
+                    }
+                };
+                let Some(reset) = self.clients.reset_quota(&node) else {
+                    return self.not_found_fn(req);
+                };
+                info!(node = node.fmt_short(), "reset byte quota usage");
+                let body = serde_json::to_vec(&serde_json::json!({ "reset": reset }))?;
+                let r = res
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body_full(body))?;
+                Ok(r)
+            }
+            (&Method::GET, ADMIN_CLIENT_VERSIONS_PATH) => {
+                let body = serde_json::to_vec(&self.clients.software_versions())?;
+                let r = res
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body_full(body))?;
+                Ok(r)
+            }
+            (&Method::POST, ADMIN_ACCESS_RELOAD_PATH) => {
+                let access = self.access();
+                let Some(list) = access.node_list().filter(|list| list.path().is_some()) else {
+                    let r = res
+                        .status(StatusCode::CONFLICT)
+                        .body(body_full("access is not configured from a node list file"))?;
+                    return Ok(r);
+                };
+                let r = match list.reload() {
+                    Ok(nodes) => {
+                        let body = serde_json::to_vec(&serde_json::json!({ "nodes": nodes }))?;
+                        res.status(StatusCode::OK)
+                            .header(CONTENT_TYPE, "application/json")
+                            .body(body_full(body))?
+                    }
+                    Err(err) => {
+                        warn!("failed to reload node list: {err:#}");
+                        res.status(StatusCode::UNPROCESSABLE_ENTITY)
+                            .body(body_full(format!("{err:#}")))?
+                    }
+                };
+                Ok(r)
+            }
+            (&Method::POST | &Method::DELETE, ADMIN_DRAIN_PATH) => {
+                let draining = req.method() == Method::POST;
+                if self.draining.swap(draining, Ordering::Relaxed) != draining {
+                    info!(draining, "relay draining changed");
+                }
+                let body = serde_json::to_vec(&serde_json::json!({
+                    "draining": draining,
+                    "clients": self.clients.len(),
+                }))?;
+                let r = res
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body_full(body))?;
+                Ok(r)
+            }
+            (&Method::GET, ADMIN_CONFIG_PATH) => {
+                let body = serde_json::to_vec(&self.effective_config())?;
+                let r = res
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body_full(body))?;
+                Ok(r)
+            }
+            _ => self.not_found_fn(req),
         }
-        let body = body_full("Not Found");
-        let r = res.status(StatusCode::NOT_FOUND).body(body)?;
-        HyperResult::Ok(r)
+    }
+
+    /// Samples the running tasks and client queues for the watchdog.
+    fn watchdog_sample(&self) -> WatchdogReport {
+        WatchdogReport::new(
+            self.connection_tasks.get(),
+            self.clients.task_count(),
+            self.clients.len(),
+            self.clients.queues(),
+        )
     }
 
     /// The server HTTP handler to do HTTP upgrades.
@@ -481,16 +1945,21 @@ impl Inner {
     /// This handler runs while doing the connection upgrade handshake.  Once the connection
     /// is upgraded it sends the stream to the relay server which takes it over.  After
     /// having sent off the connection this handler returns.
-    async fn relay_connection_handler(&self, protocol: Protocol, upgraded: Upgraded) -> Result<()> {
+    async fn relay_connection_handler(
+        &self,
+        protocol: Protocol,
+        upgraded: Upgraded,
+        request: ClientRequest,
+    ) -> Result<()> {
         debug!(?protocol, "relay_connection upgraded");
-        let (io, read_buf) = downcast_upgrade(upgraded)?;
+        let (io, read_buf) = downcast_upgrade(upgraded);
         ensure!(
             read_buf.is_empty(),
             "can not deal with buffered data yet: {:?}",
             read_buf
         );
 
-        self.accept(protocol, io).await
+        self.accept(protocol, io, request).await
     }
 
     /// Adds a new connection to the server and serves it.
@@ -503,110 +1972,854 @@ impl Inner {
     ///
     /// [`AsyncRead`]: tokio::io::AsyncRead
     /// [`AsyncWrite`]: tokio::io::AsyncWrite
-    async fn accept(&self, protocol: Protocol, io: MaybeTlsStream) -> Result<()> {
+    async fn accept(
+        &self,
+        protocol: Protocol,
+        io: MaybeTlsStream,
+        mut request: ClientRequest,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let access = request.access.take();
+        let res = self.handshake(protocol, io, request).await;
+        if let (Some(access_log), Some(access)) = (&self.access_log, access) {
+            access_log.log(access.finish(res.as_ref().ok().copied()));
+        }
+        match (protocol, &res) {
+            (Protocol::Relay, Ok(_)) => {
+                inc_by!(
+                    Metrics,
+                    relay_handshake_ms,
+                    start.elapsed().as_millis() as u64
+                );
+            }
+            (Protocol::Websocket, Ok(_)) => {
+                inc_by!(
+                    Metrics,
+                    websocket_handshake_ms,
+                    start.elapsed().as_millis() as u64
+                );
+            }
+            (Protocol::WebTransport, Ok(_)) => {
+                inc_by!(
+                    Metrics,
+                    webtransport_handshake_ms,
+                    start.elapsed().as_millis() as u64
+                );
+            }
+            (Protocol::Relay, Err(_)) => inc!(Metrics, relay_handshake_errors),
+            (Protocol::Websocket, Err(_)) => inc!(Metrics, websocket_handshake_errors),
+            (Protocol::WebTransport, Err(_)) => inc!(Metrics, webtransport_handshake_errors),
+        }
+        res.map(|_| ())
+    }
+
+    /// Runs the handshake of a new connection and registers the client.
+    ///
+    /// Returns the node ID of the registered client.
+    async fn handshake(
+        &self,
+        protocol: Protocol,
+        io: MaybeTlsStream,
+        mut request: ClientRequest,
+    ) -> Result<PublicKey> {
         trace!(?protocol, "accept: start");
+        // Direct framing has no HTTP request to refuse, and upgrades may have been accepted
+        // just before draining started.
+        ensure!(
+            !self.draining.load(Ordering::Relaxed),
+            "not accepting clients while draining"
+        );
+        let handshake = self.handshake_permit().await?;
         let mut io = match protocol {
             Protocol::Relay => {
                 inc!(Metrics, relay_accepts);
-                RelayedStream::Relay(Framed::new(io, RelayCodec::new(self.key_cache.clone())))
+                RelayedStream::relay(io, RelayCodec::new(self.key_cache.clone()))
             }
             Protocol::Websocket => {
                 inc!(Metrics, websocket_accepts);
-                RelayedStream::Ws(
+                RelayedStream::ws(
                     WebSocketStream::from_raw_socket(io, Role::Server, None).await,
                     self.key_cache.clone(),
                 )
             }
+            Protocol::WebTransport => {
+                inc!(Metrics, webtransport_accepts);
+                RelayedStream::relay(io, RelayCodec::new(self.key_cache.clone()))
+            }
         };
         trace!("accept: recv client key");
-        let (client_key, info) = recv_client_key(&mut io)
+        let (client_key, info, capabilities, software) = recv_client_key(&mut io)
             .await
             .context("unable to receive client information")?;
 
-        trace!("accept: checking access: {:?}", self.access);
-        if !self.access.is_allowed(client_key).await {
-            io.send(Frame::Health {
-                problem: Bytes::from_static(b"not authenticated"),
+        // Checked before anything is set up for the client, such as its session.
+        if !info.version_supported() {
+            io.send(Frame::Error {
+                reason: RejectReason::VersionUnsupported {
+                    min: MIN_PROTOCOL_VERSION,
+                    max: PROTOCOL_VERSION,
+                },
             })
             .await?;
             io.flush().await?;
 
-            bail!("client is not authenticated: {}", client_key);
-        }
-
-        if info.version != PROTOCOL_VERSION {
             bail!(
-                "unexpected client version {}, expected {}",
+                "unexpected client version {}, expected {} to {}",
                 info.version,
+                MIN_PROTOCOL_VERSION,
                 PROTOCOL_VERSION
             );
         }
 
+        let access = self.access();
+        trace!("accept: checking access: {:?}", access);
+        if !access.is_allowed(client_key).await {
+            reject_client(&mut io, "not authenticated", RejectReason::Banned).await?;
+            bail!("client is not authenticated: {}", client_key);
+        }
+
+        let trusted = match (&self.mesh_key, &capabilities.mesh_proof) {
+            (Some(mesh_key), Some(proof)) => {
+                let trusted = mesh_key.verify(&client_key, proof);
+                if !trusted {
+                    debug!("accept: invalid mesh proof, treating client as untrusted");
+                }
+                trusted
+            }
+            _ => false,
+        };
+
+        let ip_permit = match self.client_ips.as_ref().filter(|_| !trusted) {
+            Some(limiter) => match limiter.acquire(request.remote_addr.ip(), client_key) {
+                Ok(permit) => permit,
+                Err(err) => {
+                    inc!(Metrics, clients_ip_limited);
+                    reject_client(
+                        &mut io,
+                        "too many clients from this address",
+                        RejectReason::TooManyClients,
+                    )
+                    .await?;
+                    bail!("client is refused: {client_key}: {err:#}");
+                }
+            },
+            None => None,
+        };
+
+        let authorized = match &self.authorizer {
+            Some(authorizer) => {
+                trace!("accept: authorizing client");
+                if let Some(token) = &capabilities.auth_token {
+                    if !request.headers.contains_key(AUTHORIZATION) {
+                        match HeaderValue::from_str(&format!("Bearer {token}")) {
+                            Ok(value) => {
+                                request.headers.insert(AUTHORIZATION, value);
+                            }
+                            Err(_) => debug!("accept: ignoring invalid auth token"),
+                        }
+                    }
+                }
+                let decision = match &request.identity {
+                    Some(identity) => authorizer.authorize_certified(
+                        client_key,
+                        request.remote_addr,
+                        &request.headers,
+                        identity,
+                    ),
+                    None => authorizer.authorize(client_key, request.remote_addr, &request.headers),
+                };
+                let decision = tokio::time::timeout(AUTHORIZE_TIMEOUT, decision)
+                    .await
+                    .with_context(|| format!("authorizing client {client_key} timed out"))?;
+                match decision {
+                    Decision::Accept => None,
+                    Decision::AcceptWithRateLimit(rate_limit) => Some(rate_limit),
+                    Decision::Reject { reason } => {
+                        inc!(Metrics, clients_unauthorized);
+                        reject_client(
+                            &mut io,
+                            reason.clone(),
+                            RejectReason::Unauthorized {
+                                reason: reason.clone(),
+                            },
+                        )
+                        .await?;
+                        bail!("client is not authorized: {client_key}: {reason}");
+                    }
+                }
+            }
+            None => None,
+        };
+
+        // Resumed sessions are authorized like new ones, so revoked clients lose access.  The
+        // session keeps the rate limit granted before, unless the authorizer grants a new one.
+        let resumed = match capabilities.session_token {
+            Some(token) if capabilities.sessions => self.clients.resume_session(client_key, token),
+            _ => None,
+        };
+        let rate_limit = match resumed {
+            Some(previous) => {
+                debug!("accept: resumed session");
+                authorized.or(previous)
+            }
+            None => authorized,
+        };
+        let session = capabilities
+            .sessions
+            .then(|| self.clients.start_session(client_key, rate_limit))
+            .flatten();
+
+        // TLS already protects the frames, checksums are only used on the plain paths.
+        let checksums = capabilities.frame_checksums && !io.is_tls();
+        if checksums {
+            debug!("accept: enabling frame checksums");
+            io.enable_checksums();
+            // The first checksummed frame acknowledges the checksums to the client.
+            io.send(Frame::KeepAlive).await?;
+        }
+
+        let key_rotation = match capabilities.key_rotation {
+            Some(rotation) => self.verify_key_rotation(client_key, rotation).await,
+            None => None,
+        };
+
+        let (keep_alive, negotiated_keep_alive) = match &self.keep_alive {
+            Some(config) => config.negotiate(capabilities.keep_alive),
+            None => (DEFAULT_KEEP_ALIVE_INTERVAL, None),
+        };
+
+        let compression = capabilities
+            .compression
+            .filter(|c| self.payload_compression && c.is_supported());
+        if let Some(compression) = compression {
+            debug!(?compression, "accept: enabling payload compression");
+        }
+        let notify_unknown = capabilities.unknown_peers
+            && self
+                .unknown_peers
+                .as_ref()
+                .is_some_and(|config| config.notify);
+
+        if capabilities.fragments
+            || capabilities.send_acks
+            || capabilities.queue_status
+            || capabilities.mesh_proof.is_some()
+            || capabilities.key_rotation.is_some()
+            || session.is_some()
+            || negotiated_keep_alive.is_some()
+            || compression.is_some()
+            || notify_unknown
+            || capabilities.time.is_some()
+            || capabilities.peer_gone
+        {
+            debug!(?capabilities, "accept: acknowledging capabilities");
+            let accepted = ClientCapabilities {
+                frame_checksums: checksums,
+                fragments: capabilities.fragments,
+                send_acks: capabilities.send_acks,
+                // Echoing the proof tells the client that it is trusted.
+                mesh_proof: capabilities.mesh_proof.filter(|_| trusted),
+                // Echoing the rotation tells the client that its previous key is routed.
+                key_rotation,
+                queue_status: capabilities.queue_status,
+                sessions: session.is_some(),
+                session_token: session,
+                keep_alive: negotiated_keep_alive,
+                compression,
+                unknown_peers: notify_unknown,
+                // Answering with the server time lets the client detect clock skew.
+                time: capabilities.time.map(|_| {
+                    SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .map_or(0, |since| since.as_millis() as u64)
+                }),
+                auth_token: None,
+                peer_gone: capabilities.peer_gone,
+            };
+            io.send(Frame::Capabilities {
+                capabilities: accepted,
+            })
+            .await?;
+        }
+
         trace!("accept: build client conn");
+        let rate_limits = *self.rate_limits.read().expect("poisoned");
         let client_conn_builder = Config {
             node_id: client_key,
             stream: io,
             write_timeout: self.write_timeout,
-            channel_capacity: PER_CLIENT_SEND_QUEUE_DEPTH,
-            rate_limit: self.rate_limit,
+            send_queue: self.send_queue.clone(),
+            rate_limit: match rate_limit {
+                Some(rate_limit) => Some(rate_limit),
+                None if trusted => rate_limits.trusted_client_rx,
+                None => rate_limits.client_rx,
+            },
+            tx_rate_limit: rate_limits.client_tx.filter(|_| !trusted),
+            fragments: capabilities.fragments,
+            queue_status: capabilities.queue_status,
+            peer_gone: capabilities.peer_gone,
+            multiplexed: info.multiplexed(),
+            compression,
+            notify_unknown,
+            unknown_limiter: self
+                .unknown_peers
+                .as_ref()
+                .filter(|_| !trusted)
+                .and_then(UnknownPeerConfig::limiter),
+            keep_alive,
+            trusted,
+            key_rotation,
+            software,
+            disconnect_hook: self.disconnect_hook.clone(),
+            session,
+            ip_permit,
         };
         trace!("accept: create client");
         inc!(Metrics, accepts);
+        match &client_conn_builder.software {
+            Some(software) => {
+                debug!(name = %software.name, version = %software.version, "accept: client software");
+                inc!(Metrics, client_software_reported);
+            }
+            None => inc!(Metrics, client_software_unreported),
+        }
         let node_id = client_conn_builder.node_id;
         trace!(node_id = node_id.fmt_short(), "create client");
 
         // build and register client, starting up read & write loops for the client
         // connection
+        drop(handshake);
         self.clients.register(client_conn_builder).await;
-        Ok(())
+        Ok(node_id)
     }
 }
 
-/// TLS Certificate Authority acceptor.
-#[derive(Clone, derive_more::Debug)]
-pub(super) enum TlsAcceptor {
-    /// Uses Let's Encrypt as the Certificate Authority. This is used in production.
-    LetsEncrypt(#[debug("tokio_rustls_acme::AcmeAcceptor")] AcmeAcceptor),
-    /// Manually added tls acceptor. Generally used for tests or for when we've passed in
-    /// a certificate via a file.
-    Manual(#[debug("tokio_rustls::TlsAcceptor")] tokio_rustls::TlsAcceptor),
-}
+impl Inner {
+    /// Waits for a slot of the handshake phase, if handshakes are limited.
+    async fn handshake_permit(&self) -> Result<Option<OwnedSemaphorePermit>> {
+        match &self.handshakes {
+            Some(handshakes) => handshakes.acquire().await.map(Some),
+            None => Ok(None),
+        }
+    }
 
-impl RelayService {
-    fn new(
-        handlers: Handlers,
-        headers: HeaderMap,
-        rate_limit: Option<ClientRateLimit>,
-        key_cache: KeyCache,
-        access: AccessConfig,
-    ) -> Self {
-        Self(Arc::new(Inner {
-            handlers,
-            headers,
-            clients: Clients::default(),
-            write_timeout: SERVER_WRITE_TIMEOUT,
-            rate_limit,
-            key_cache,
-            access,
-        }))
+    /// Returns the rotation if it is valid and the previous key is allowed to connect.
+    async fn verify_key_rotation(
+        &self,
+        client_key: PublicKey,
+        rotation: KeyRotation,
+    ) -> Option<KeyRotation> {
+        let now = unix_secs();
+        if !rotation.verify(&client_key, now) {
+            debug!("accept: invalid or expired key rotation, ignoring it");
+            return None;
+        }
+        // A previous key might have been banned for being compromised.
+        if !self.access().is_allowed(rotation.previous_key).await {
+            debug!("accept: previous key is not allowed, ignoring key rotation");
+            return None;
+        }
+        inc!(Metrics, key_rotations);
+        // The signed expiry is kept as is, only the time the previous key is routed for is
+        // shortened.
+        let max_expires = now.saturating_add(MAX_KEY_ROTATION_WINDOW.as_secs());
+        Some(KeyRotation {
+            expires: rotation.expires.min(max_expires),
+            ..rotation
+        })
     }
+}
 
-    async fn shutdown(&self) {
-        self.0.clients.shutdown().await;
+/// Limits the number of connections in the handshake phase.
+#[derive(Debug, Clone)]
+struct HandshakeLimiter {
+    slots: Arc<Semaphore>,
+    queue_timeout: Duration,
+}
+
+impl HandshakeLimiter {
+    fn new(limit: HandshakeLimit) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(limit.max_concurrent.get())),
+            queue_timeout: limit.queue_timeout,
+        }
     }
 
-    /// Handle the incoming connection.
+    /// Waits for a free slot, at most for the queue timeout.
     ///
-    /// If a `tls_config` is given, will serve the connection using HTTPS.
-    async fn handle_connection(self, stream: TcpStream, tls_config: Option<TlsConfig>) {
+    /// The slot is freed when the permit is dropped.
+    async fn acquire(&self) -> Result<OwnedSemaphorePermit> {
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+        inc!(Metrics, handshakes_queued);
+        match tokio::time::timeout(self.queue_timeout, self.slots.clone().acquire_owned()).await {
+            Ok(permit) => Ok(permit.expect("semaphore never closed")),
+            Err(_) => {
+                inc!(Metrics, handshakes_rejected);
+                bail!("too many concurrent handshakes");
+            }
+        }
+    }
+}
+
+/// How long the [`ClientAuthorizer`] may take to decide about a client.
+///
+/// Clients which are not decided about in time are disconnected without a rejection, so
+/// they try again.
+const AUTHORIZE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Rejects a client during the handshake.
+///
+/// The health frame with the `problem` is kept for clients that do not know the error
+/// frame.
+async fn reject_client(
+    io: &mut RelayedStream,
+    problem: impl Into<Bytes>,
+    reason: RejectReason,
+) -> Result<()> {
+    io.send(Frame::Health {
+        problem: problem.into(),
+    })
+    .await?;
+    io.send(Frame::Error { reason }).await?;
+    io.flush().await?;
+    Ok(())
+}
+
+/// Limit of the open connections of the server.
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct ConnectionLimit {
+    /// Max number of open connections, unlimited if `None`.
+    pub(super) max_total: Option<NonZeroUsize>,
+    /// Max number of open connections from a single IP address, unlimited if `None`.
+    pub(super) max_per_ip: Option<NonZeroUsize>,
+}
+
+/// Counts the open connections to enforce a [`ConnectionLimit`].
+#[derive(Debug, Clone)]
+struct ConnectionLimiter(Arc<ConnectionLimiterInner>);
+
+#[derive(Debug)]
+struct ConnectionLimiterInner {
+    limit: ConnectionLimit,
+    counts: Mutex<ConnectionCounts>,
+}
+
+#[derive(Debug, Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl ConnectionLimiter {
+    /// Creates a limiter, `None` if the connections are not limited.
+    fn new(limit: ConnectionLimit) -> Option<Self> {
+        if limit.max_total.is_none() && limit.max_per_ip.is_none() {
+            return None;
+        }
+        Some(Self(Arc::new(ConnectionLimiterInner {
+            limit,
+            counts: Default::default(),
+        })))
+    }
+
+    /// Counts a new connection from `ip`, failing if it exceeds the limit.
+    ///
+    /// The connection is counted until the permit is dropped.
+    fn acquire(&self, ip: IpAddr) -> Result<ConnectionPermit> {
+        let mut permit = self.acquire_pending()?;
+        permit.assign(ip)?;
+        Ok(permit)
+    }
+
+    /// Counts a new connection whose client address is not known yet, failing if it
+    /// exceeds the total limit.
+    ///
+    /// The address is checked against the limit per IP address with
+    /// [`ConnectionPermit::assign`].
+    fn acquire_pending(&self) -> Result<ConnectionPermit> {
+        let mut counts = self.0.counts.lock().expect("poisoned");
+        if let Some(max_total) = self.0.limit.max_total {
+            ensure!(counts.total < max_total.get(), "too many connections");
+        }
+        counts.total += 1;
+        Ok(ConnectionPermit {
+            limiter: self.clone(),
+            ip: None,
+        })
+    }
+}
+
+/// Counts a new connection from `addr`, `None` if it exceeds the limit and must be closed.
+fn admit_connection(
+    limiter: Option<&ConnectionLimiter>,
+    addr: SocketAddr,
+) -> Option<Option<ConnectionPermit>> {
+    match limiter.map(|l| l.acquire(addr.ip())).transpose() {
+        Ok(permit) => Some(permit),
+        Err(err) => {
+            debug!("closing connection from {addr}: {err:#}");
+            inc!(Metrics, connections_rejected);
+            None
+        }
+    }
+}
+
+/// A connection counted by the [`ConnectionLimiter`].
+#[derive(Debug)]
+pub(crate) struct ConnectionPermit {
+    limiter: ConnectionLimiter,
+    /// The client address, `None` until assigned.
+    ip: Option<IpAddr>,
+}
+
+impl ConnectionPermit {
+    /// Counts the connection for the client address `ip`, failing if it exceeds the limit
+    /// per IP address.
+    fn assign(&mut self, ip: IpAddr) -> Result<()> {
+        debug_assert!(self.ip.is_none(), "permit assigned twice");
+        let mut counts = self.limiter.0.counts.lock().expect("poisoned");
+        let per_ip = counts.per_ip.get(&ip).copied().unwrap_or_default();
+        if let Some(max_per_ip) = self.limiter.0.limit.max_per_ip {
+            ensure!(per_ip < max_per_ip.get(), "too many connections from {ip}");
+        }
+        counts.per_ip.insert(ip, per_ip + 1);
+        self.ip = Some(ip);
+        Ok(())
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.0.counts.lock().expect("poisoned");
+        counts.total -= 1;
+        let Some(ip) = self.ip else {
+            return;
+        };
+        if let std::collections::hash_map::Entry::Occupied(mut entry) = counts.per_ip.entry(ip) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+/// Binds a listener without blocking, for use from synchronous request handlers.
+///
+/// Sets `IPV6_V6ONLY` for IPv6 addresses if `ipv6_only` is not `None`.
+pub(super) fn bind_listener(addr: SocketAddr, ipv6_only: Option<bool>) -> Result<TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    if let (SocketAddr::V6(_), Some(ipv6_only)) = (addr, ipv6_only) {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    // Like the standard library, allow rebinding while old connections are in TIME_WAIT.
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Serves on a listener bound by another process, see [`ServerBuilder::listener`].
+pub(super) fn inherit_listener(listener: std::net::TcpListener) -> Result<TcpListener> {
+    listener.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(listener)?)
+}
+
+/// Compares two byte strings in constant time, only leaking their lengths.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// TLS Certificate Authority acceptor.
+#[derive(Clone, derive_more::Debug)]
+pub(super) enum TlsAcceptor {
+    /// Uses Let's Encrypt as the Certificate Authority. This is used in production.
+    LetsEncrypt(#[debug("tokio_rustls_acme::AcmeAcceptor")] AcmeAcceptor),
+    /// Manually added tls acceptor. Generally used for tests or for when we've passed in
+    /// a certificate via a file.
+    Manual(#[debug("tokio_rustls::TlsAcceptor")] tokio_rustls::TlsAcceptor),
+    /// Like [`TlsAcceptor::Manual`], additionally requiring a client certificate.
+    ///
+    /// The certificate is verified by the server config of the acceptor, connections
+    /// without one are closed after the TLS handshake.
+    ManualMutual(#[debug("tokio_rustls::TlsAcceptor")] tokio_rustls::TlsAcceptor),
+}
+
+impl RelayService {
+    /// Starts configuring a service, see [`RelayServiceBuilder`].
+    fn builder(
+        handlers: Handlers,
+        headers: Headers,
+        rate_limit: Option<ClientRateLimit>,
+        key_cache: KeyCache,
+        access: AccessConfig,
+        admin: Option<AdminConfig>,
+        watchdog: Option<WatchdogConfig>,
+    ) -> RelayServiceBuilder {
+        RelayServiceBuilder(Inner {
+            handlers,
+            headers,
+            clients: Clients::default(),
+            write_timeout: SERVER_WRITE_TIMEOUT,
+            rate_limits: RwLock::new(RateLimits {
+                client_rx: rate_limit,
+                ..Default::default()
+            }),
+            mesh_key: None,
+            handshakes: None,
+            client_ips: None,
+            compression: None,
+            error_pages: ErrorPages::default(),
+            draining: AtomicBool::new(false),
+            config: serde_json::Value::Null,
+            disconnect_hook: None,
+            authorizer: None,
+            access_log: None,
+            client_auth: false,
+            keep_alive: None,
+            payload_compression: false,
+            unknown_peers: None,
+            send_queue: SendQueueConfig::default(),
+            key_cache,
+            access: RwLock::new(Arc::new(access)),
+            tls: RwLock::new(None),
+            admin,
+            watchdog: watchdog.map(Watchdog::new),
+            connection_tasks: TaskCounter::default(),
+            rebind: Rebind::default(),
+            #[cfg(any(test, feature = "test-utils"))]
+            faults: None,
+        })
+    }
+}
+
+/// Configures a [`RelayService`] before it is shared by the connections.
+#[derive(Debug)]
+struct RelayServiceBuilder(Inner);
+
+impl RelayServiceBuilder {
+    /// Trusts the clients proving the knowledge of the mesh key.
+    fn with_mesh_key(
+        mut self,
+        mesh_key: Option<MeshKey>,
+        trusted_rate_limit: Option<ClientRateLimit>,
+    ) -> Self {
+        let inner = &mut self.0;
+        inner.mesh_key = mesh_key;
+        inner
+            .rate_limits
+            .get_mut()
+            .expect("poisoned")
+            .trusted_client_rx = trusted_rate_limit;
+        self
+    }
+
+    /// Forwards packets to the nodes connected to other relays of the mesh, resumes the
+    /// sessions of clients and enforces the byte quotas of the nodes.
+    fn with_clients(
+        mut self,
+        mesh: Option<MeshRoutes>,
+        sessions: Option<SessionConfig>,
+        quotas: Option<QuotaConfig>,
+    ) -> Self {
+        self.0.clients = Clients::new(mesh, sessions, quotas);
+        self
+    }
+
+    /// Limits the rate of the data sent to untrusted clients.
+    fn with_tx_rate_limit(mut self, tx_rate_limit: Option<ClientRateLimit>) -> Self {
+        self.0.rate_limits.get_mut().expect("poisoned").client_tx = tx_rate_limit;
+        self
+    }
+
+    /// Serves the accepted connections using TLS.
+    fn with_tls(mut self, tls: Option<TlsConfig>) -> Self {
+        *self.0.tls.get_mut().expect("poisoned") = tls;
+        self
+    }
+
+    /// Limits the number of connections in the handshake phase.
+    fn with_handshake_limit(mut self, limit: Option<HandshakeLimit>) -> Self {
+        self.0.handshakes = limit.map(HandshakeLimiter::new);
+        self
+    }
+
+    /// Limits the number of untrusted clients connected from a single source.
+    fn with_client_ip_limit(mut self, limit: Option<ClientIpLimit>) -> Self {
+        self.0.client_ips = limit.map(ClientIpLimiter::new);
+        self
+    }
+
+    /// Sets `IPV6_V6ONLY` on the listeners bound by the admin API.
+    fn with_ipv6_only(mut self, ipv6_only: Option<bool>) -> Self {
+        self.0.rebind.ipv6_only = ipv6_only;
+        self
+    }
+
+    /// Compresses the responses of the request handlers and the admin API.
+    fn with_compression(mut self, compression: Option<CompressionConfig>) -> Self {
+        self.0.compression = compression;
+        self
+    }
+
+    /// Serves custom responses for errors.
+    fn with_error_pages(mut self, error_pages: ErrorPages) -> Self {
+        self.0.error_pages = error_pages;
+        self
+    }
+
+    /// Lets clients request keep-alive intervals within the configured bounds.
+    fn with_keep_alive(mut self, keep_alive: Option<KeepAliveConfig>) -> Self {
+        self.0.keep_alive = keep_alive;
+        self
+    }
+
+    /// Lets clients request the compression of packet payloads.
+    fn with_payload_compression(mut self, enable: bool) -> Self {
+        self.0.payload_compression = enable;
+        self
+    }
+
+    /// Sets the handling of the packets sent to unknown nodes.
+    fn with_unknown_peers(mut self, unknown_peers: Option<UnknownPeerConfig>) -> Self {
+        self.0.unknown_peers = unknown_peers;
+        self
+    }
+
+    /// Sets the queues of the packets sent to each client.
+    fn with_send_queue(mut self, send_queue: SendQueueConfig) -> Self {
+        self.0.send_queue = send_queue;
+        self
+    }
+
+    /// Calls the hook whenever a client disconnects.
+    fn with_disconnect_hook(mut self, hook: Option<DisconnectHook>) -> Self {
+        self.0.disconnect_hook = hook;
+        self
+    }
+
+    /// Asks the authorizer whether connecting clients may use the relay.
+    fn with_authorizer(mut self, authorizer: Option<Arc<dyn ClientAuthorizer>>) -> Self {
+        self.0.authorizer = authorizer;
+        self
+    }
+
+    /// Requires the clients to present a TLS client certificate.
+    fn with_client_auth(mut self, client_auth: bool) -> Self {
+        self.0.client_auth = client_auth;
+        self
+    }
+
+    /// Records the requests in the access log.
+    fn with_access_log(mut self, access_log: Option<AccessLogger>) -> Self {
+        self.0.access_log = access_log;
+        self
+    }
+
+    /// Sets the effective configuration served by the admin API.
+    fn with_config(mut self, config: serde_json::Value) -> Self {
+        self.0.config = config;
+        self
+    }
+
+    /// Injects faults into the connections served by this service.
+    #[cfg(any(test, feature = "test-utils"))]
+    fn with_faults(mut self, faults: Option<crate::faults::FaultConfig>) -> Self {
+        self.0.faults = faults;
+        self
+    }
+
+    /// Builds the service, ready to be shared.
+    fn build(self) -> RelayService {
+        RelayService(Arc::new(self.0))
+    }
+}
+
+impl RelayService {
+    async fn shutdown(&self) {
+        self.0.clients.shutdown().await;
+    }
+
+    /// Periodically checks the server with the watchdog, if enabled.
+    async fn run_watchdog(self) {
+        let Some(watchdog) = &self.0.watchdog else {
+            return;
+        };
+        let mut interval = tokio::time::interval(watchdog.interval());
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            watchdog.check(self.0.watchdog_sample()).await;
+        }
+    }
+
+    /// Handles a connection starting with a PROXY protocol header.
+    ///
+    /// The connection is handled as if it was accepted from the client address of the
+    /// header, the address of the proxy is used for connections of the proxy itself.  The
+    /// `permit` is assigned to the client address once the header is read.
+    async fn handle_proxied_connection(
+        self,
+        mut stream: TcpStream,
+        proxy_addr: SocketAddr,
+        tls_config: Option<TlsConfig>,
+        mut permit: Option<ConnectionPermit>,
+    ) {
+        let header = tokio::time::timeout(
+            proxy_protocol::HEADER_TIMEOUT,
+            proxy_protocol::read_header(&mut stream),
+        )
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("timed out")));
+        let remote_addr = match header {
+            Ok(client_addr) => client_addr.map_or(proxy_addr, canonical_addr),
+            Err(err) => {
+                debug!("closing connection: invalid PROXY protocol header: {err:#}");
+                inc!(Metrics, proxy_headers_rejected);
+                return;
+            }
+        };
+        if let Some(Err(err)) = permit.as_mut().map(|p| p.assign(remote_addr.ip())) {
+            debug!("closing connection from {remote_addr}: {err:#}");
+            inc!(Metrics, connections_rejected);
+            return;
+        }
+        debug!("connection opened from {remote_addr}");
+        self.handle_connection(stream, remote_addr, tls_config, permit)
+            .instrument(info_span!("conn", peer = %remote_addr))
+            .await
+    }
+
+    /// Handle the incoming connection.
+    ///
+    /// If a `tls_config` is given, will serve the connection using HTTPS.  The `permit` of
+    /// the connection limit is held as long as the stream is open.
+    async fn handle_connection(
+        self,
+        stream: TcpStream,
+        remote_addr: SocketAddr,
+        tls_config: Option<TlsConfig>,
+        permit: Option<ConnectionPermit>,
+    ) {
+        let _task = self.0.connection_tasks.guard();
         let res = match tls_config {
             Some(tls_config) => {
                 debug!("HTTPS: serve connection");
-                self.tls_serve_connection(stream, tls_config).await
+                self.tls_serve_connection(stream, remote_addr, tls_config, permit)
+                    .await
             }
             None => {
                 debug!("HTTP: serve connection");
-                self.serve_connection(MaybeTlsStream::Plain(stream)).await
+                let io = MaybeTlsStream::Plain(stream).with_permit(permit);
+                self.serve_connection(io, remote_addr).await
             }
         };
         match res {
@@ -623,8 +2836,22 @@ impl RelayService {
     }
 
     /// Serve the tls connection
-    async fn tls_serve_connection(self, stream: TcpStream, tls_config: TlsConfig) -> Result<()> {
+    async fn tls_serve_connection(
+        self,
+        stream: TcpStream,
+        remote_addr: SocketAddr,
+        tls_config: TlsConfig,
+        permit: Option<ConnectionPermit>,
+    ) -> Result<()> {
         let TlsConfig { acceptor, config } = tls_config;
+        let mutual = matches!(acceptor, TlsAcceptor::ManualMutual(_));
+        let handshake = match self.0.handshake_permit().await {
+            Ok(handshake) => handshake,
+            Err(err) => {
+                debug!("TLS: closing connection: {err:#}");
+                return Ok(());
+            }
+        };
         match acceptor {
             TlsAcceptor::LetsEncrypt(a) => match a.accept(stream).await? {
                 None => {
@@ -636,33 +2863,81 @@ impl RelayService {
                         .into_stream(config)
                         .await
                         .context("TLS[acme] handshake")?;
-                    self.serve_connection(MaybeTlsStream::Tls(tls_stream))
-                        .await
-                        .context("TLS[acme] serve connection")?;
+                    drop(handshake);
+                    self.serve_connection(
+                        MaybeTlsStream::Tls(tls_stream).with_permit(permit),
+                        remote_addr,
+                    )
+                    .await
+                    .context("TLS[acme] serve connection")?;
                 }
             },
-            TlsAcceptor::Manual(a) => {
+            TlsAcceptor::Manual(a) | TlsAcceptor::ManualMutual(a) => {
                 debug!("TLS[manual]: accept");
                 let tls_stream = tokio::time::timeout(Duration::from_secs(30), a.accept(stream))
                     .await
                     .context("TLS[manual] timeout")?
                     .context("TLS[manual] accept")?;
+                drop(handshake);
+                if mutual && tls_stream.get_ref().1.peer_certificates().is_none() {
+                    debug!("TLS[manual]: closing connection without client certificate");
+                    inc!(Metrics, client_certificates_missing);
+                    return Ok(());
+                }
 
-                self.serve_connection(MaybeTlsStream::Tls(tls_stream))
-                    .await
-                    .context("TLS[manual] serve connection")?;
+                self.serve_connection(
+                    MaybeTlsStream::Tls(tls_stream).with_permit(permit),
+                    remote_addr,
+                )
+                .await
+                .context("TLS[manual] serve connection")?;
             }
         }
         Ok(())
     }
 
     /// Wrapper for the actual http connection (with upgrades)
-    async fn serve_connection<I>(self, io: I) -> Result<()>
-    where
-        I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync + 'static,
-    {
+    ///
+    /// Connections which negotiated the [`RELAY_ALPN`] use direct framing, they speak the
+    /// relay protocol right away without an HTTP upgrade.
+    async fn serve_connection(self, io: MaybeTlsStream, remote_addr: SocketAddr) -> Result<()> {
+        #[cfg(any(test, feature = "test-utils"))]
+        let io = match &self.0.faults {
+            Some(faults) => MaybeTlsStream::Faulty(Box::new(crate::faults::FaultyStream::new(
+                io,
+                faults.clone(),
+            ))),
+            None => io,
+        };
+        let identity = io.peer_certificates().and_then(ClientIdentity::from_chain);
+        if io.alpn_protocol() == Some(RELAY_ALPN) {
+            debug!("serving relay client with direct framing");
+            let mut request = ClientRequest::direct(remote_addr);
+            request.identity = identity;
+            if self.0.access_log.is_some() {
+                request.access = Some(PendingAccess::direct(remote_addr));
+            }
+            return self.0.accept(Protocol::Relay, io, request).await;
+        }
+        let service = ConnectionService {
+            service: self,
+            remote_addr,
+            identity,
+        };
+        if io.alpn_protocol() == Some(H2_ALPN) {
+            debug!("serving HTTP/2 connection");
+            hyper::server::conn::http2::Builder::new(hyper_util::rt::TokioExecutor::new())
+                .enable_connect_protocol()
+                .serve_connection(hyper_util::rt::TokioIo::new(io), service)
+                .await?;
+            return Ok(());
+        }
+        debug!(
+            alpn = ?io.alpn_protocol().map(String::from_utf8_lossy),
+            "serving HTTP connection"
+        );
         hyper::server::conn::http1::Builder::new()
-            .serve_connection(hyper_util::rt::TokioIo::new(io), self)
+            .serve_connection(hyper_util::rt::TokioIo::new(io), service)
             .with_upgrades()
             .await?;
         Ok(())
@@ -698,233 +2973,3529 @@ impl std::ops::DerefMut for Handlers {
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{
+        collections::BTreeSet,
+        num::NonZeroU32,
+        sync::{atomic::Ordering, Arc},
+    };
 
     use anyhow::Result;
     use bytes::Bytes;
-    use iroh_base::{PublicKey, SecretKey};
+    use http::header::{CONTENT_ENCODING, LOCATION, VARY};
+    use iroh_base::{NodeId, RelayUrl, SecretKey};
     use n0_future::{SinkExt, StreamExt};
     use reqwest::Url;
+    use tokio_util::codec::Framed;
     use tracing::info;
     use tracing_test::traced_test;
 
     use super::*;
     use crate::{
         client::{
-            conn::{Conn, ReceivedMessage, SendMessage},
+            conn::{Conn, ConnSendError, ReceivedMessage, SendMessage},
             streams::MaybeTlsStreamChained,
-            Client, ClientBuilder,
+            Client, ClientBuilder, ConnectionRejected, ConnectivityCheckConfig, ConnectivityEvent,
+            DropReason, NegotiatedKeepAlive, PingKeepaliveConfig, SampledFrame, TelemetryConfig,
         },
         dns::DnsResolver,
+        faults::FaultConfig,
+        protos::relay::{send_client_key, ClientInfo, SendStatus, CHANNEL_WINDOW},
+        server::{NodeList, DEFAULT_SEND_QUEUE_DEPTH},
     };
 
-    pub(crate) fn make_tls_config() -> TlsConfig {
-        let subject_alt_names = vec!["localhost".to_string()];
+    /// Returns the request of a client connected over an in-memory pipe.
+    fn test_request() -> ClientRequest {
+        ClientRequest::direct((std::net::Ipv4Addr::LOCALHOST, 0).into())
+    }
+
+    pub(crate) fn make_tls_config() -> TlsConfig {
+        make_tls_config_with_alpns(Vec::new())
+    }
+
+    fn make_tls_config_with_alpns(alpn_protocols: Vec<Vec<u8>>) -> TlsConfig {
+        let subject_alt_names = vec!["localhost".to_string()];
+
+        let cert = rcgen::generate_simple_self_signed(subject_alt_names).unwrap();
+        let rustls_certificate = cert.cert.der().clone();
+        let rustls_key = rustls::pki_types::PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+        let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .expect("protocols supported by ring")
+        .with_no_client_auth()
+        .with_single_cert(vec![(rustls_certificate)], rustls_key.into())
+        .expect("cert is right");
+        config.alpn_protocols = alpn_protocols;
+
+        let config = Arc::new(config);
+        let acceptor = tokio_rustls::TlsAcceptor::from(config.clone());
+
+        TlsConfig {
+            config,
+            acceptor: TlsAcceptor::Manual(acceptor),
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_http_clients_and_server() -> Result<()> {
+        let a_key = SecretKey::generate(rand::thread_rng());
+        let b_key = SecretKey::generate(rand::thread_rng());
+
+        // start server
+        let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap()).spawn()?;
+
+        let addr = server.addr();
+
+        // get dial info
+        let port = addr.port();
+        let addr = {
+            if let std::net::IpAddr::V4(ipv4_addr) = addr.ip() {
+                ipv4_addr
+            } else {
+                anyhow::bail!("cannot get ipv4 addr from socket addr {addr:?}");
+            }
+        };
+        info!("addr: {addr}:{port}");
+        let relay_addr: Url = format!("http://{addr}:{port}").parse().unwrap();
+
+        // create clients
+        let (a_key, mut client_a) = create_test_client(a_key, relay_addr.clone()).await?;
+        info!("created client {a_key:?}");
+        let (b_key, mut client_b) = create_test_client(b_key, relay_addr).await?;
+        info!("created client {b_key:?}");
+
+        info!("ping a");
+        client_a.send(SendMessage::Ping([1u8; 8])).await?;
+        let pong = client_a.next().await.context("eos")??;
+        assert!(matches!(pong, ReceivedMessage::Pong(_)));
+
+        info!("ping b");
+        client_b.send(SendMessage::Ping([2u8; 8])).await?;
+        let pong = client_b.next().await.context("eos")??;
+        assert!(matches!(pong, ReceivedMessage::Pong(_)));
+
+        info!("sending message from a to b");
+        let msg = Bytes::from_static(b"hi there, client b!");
+        client_a
+            .send(SendMessage::SendPacket(b_key, msg.clone()))
+            .await?;
+        info!("waiting for message from a on b");
+        let (got_key, got_msg) =
+            process_msg(client_b.next().await).expect("expected message from client_a");
+        assert_eq!(a_key, got_key);
+        assert_eq!(msg, got_msg);
+
+        info!("sending message from b to a");
+        let msg = Bytes::from_static(b"right back at ya, client b!");
+        client_b
+            .send(SendMessage::SendPacket(a_key, msg.clone()))
+            .await?;
+        info!("waiting for message b on a");
+        let (got_key, got_msg) =
+            process_msg(client_a.next().await).expect("expected message from client_b");
+        assert_eq!(b_key, got_key);
+        assert_eq!(msg, got_msg);
+
+        client_a.close().await?;
+        client_b.close().await?;
+        server.shutdown();
+
+        Ok(())
+    }
+
+    async fn create_test_client(key: SecretKey, server_url: Url) -> Result<(PublicKey, Client)> {
+        let public_key = key.public();
+        let client =
+            ClientBuilder::new(server_url, key, DnsResolver::new()).insecure_skip_cert_verify(true);
+        let client = client.connect().await?;
+
+        Ok((public_key, client))
+    }
+
+    /// Connects a client and waits for the server to complete the handshake.
+    ///
+    /// The acknowledgement of the capabilities, like the session token, is received before
+    /// the pong.
+    async fn connect_acked(builder: ClientBuilder) -> Result<Client> {
+        let mut client = builder.connect().await?;
+        client.send(SendMessage::Ping([1u8; 8])).await?;
+        let pong = client.next().await.context("eos")??;
+        assert!(matches!(pong, ReceivedMessage::Pong(data) if data == [1u8; 8]));
+        Ok(client)
+    }
+
+    fn process_msg(msg: Option<Result<ReceivedMessage>>) -> Option<(PublicKey, Bytes)> {
+        match msg {
+            Some(Err(e)) => {
+                info!("client `recv` error {e}");
+                None
+            }
+            Some(Ok(msg)) => {
+                info!("got message on: {msg:?}");
+                if let ReceivedMessage::ReceivedPacket {
+                    remote_node_id: source,
+                    data,
+                } = msg
+                {
+                    Some((source, data))
+                } else {
+                    None
+                }
+            }
+            None => {
+                info!("client end of stream");
+                None
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_https_clients_and_server() -> Result<()> {
+        let a_key = SecretKey::generate(rand::thread_rng());
+        let b_key = SecretKey::generate(rand::thread_rng());
+
+        // create tls_config
+        let tls_config = make_tls_config();
+
+        // start server
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .tls_config(Some(tls_config))
+            .spawn()?;
+
+        let addr = server.addr();
+
+        // get dial info
+        let port = addr.port();
+        let addr = {
+            if let std::net::IpAddr::V4(ipv4_addr) = addr.ip() {
+                ipv4_addr
+            } else {
+                anyhow::bail!("cannot get ipv4 addr from socket addr {addr:?}");
+            }
+        };
+        info!("Relay listening on: {addr}:{port}");
+
+        let url: Url = format!("https://localhost:{port}").parse().unwrap();
+
+        // create clients
+        let (a_key, mut client_a) = create_test_client(a_key, url.clone()).await?;
+        info!("created client {a_key:?}");
+        let (b_key, mut client_b) = create_test_client(b_key, url).await?;
+        info!("created client {b_key:?}");
+
+        info!("ping a");
+        client_a.send(SendMessage::Ping([1u8; 8])).await?;
+        let pong = client_a.next().await.context("eos")??;
+        assert!(matches!(pong, ReceivedMessage::Pong(_)));
+
+        info!("ping b");
+        client_b.send(SendMessage::Ping([2u8; 8])).await?;
+        let pong = client_b.next().await.context("eos")??;
+        assert!(matches!(pong, ReceivedMessage::Pong(_)));
+
+        info!("sending message from a to b");
+        let msg = Bytes::from_static(b"hi there, client b!");
+        client_a
+            .send(SendMessage::SendPacket(b_key, msg.clone()))
+            .await?;
+        info!("waiting for message from a on b");
+        let (got_key, got_msg) =
+            process_msg(client_b.next().await).expect("expected message from client_a");
+        assert_eq!(a_key, got_key);
+        assert_eq!(msg, got_msg);
+
+        info!("sending message from b to a");
+        let msg = Bytes::from_static(b"right back at ya, client b!");
+        client_b
+            .send(SendMessage::SendPacket(a_key, msg.clone()))
+            .await?;
+        info!("waiting for message b on a");
+        let (got_key, got_msg) =
+            process_msg(client_a.next().await).expect("expected message from client_b");
+        assert_eq!(b_key, got_key);
+        assert_eq!(msg, got_msg);
+
+        server.shutdown();
+        server.task_handle().await?;
+        client_a.close().await?;
+        client_b.close().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_http2_relay_connections() -> Result<()> {
+        use http_body_util::Empty;
+        use hyper_util::rt::{TokioExecutor, TokioIo};
+
+        let tls_config = make_tls_config_with_alpns(vec![H2_ALPN.to_vec()]);
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .tls_config(Some(tls_config))
+            .spawn()?;
+        let port = server.addr().port();
+
+        let mut config = crate::client::make_dangerous_client_config();
+        config.alpn_protocols = vec![H2_ALPN.to_vec()];
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let stream = TcpStream::connect(server.addr()).await?;
+        let server_name = rustls::pki_types::ServerName::try_from("localhost")?;
+        let stream = connector.connect(server_name, stream).await?;
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(H2_ALPN));
+        let (mut sender, conn) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await?;
+        let _conn_task = AbortOnDropHandle::new(tokio::task::spawn(conn));
+
+        // A normal request, after which the server settings are known.
+        let res = sender
+            .send_request(
+                Request::get(format!("https://localhost:{port}/")).body(Empty::<Bytes>::new())?,
+            )
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        async fn connect(
+            sender: &mut hyper::client::conn::http2::SendRequest<Empty<Bytes>>,
+            port: u16,
+            key: &SecretKey,
+        ) -> Result<Conn> {
+            let mut req = Request::connect(format!("https://localhost:{port}{RELAY_PATH}"))
+                .body(Empty::<Bytes>::new())?;
+            req.extensions_mut()
+                .insert(hyper::ext::Protocol::from_static(
+                    Protocol::Relay.upgrade_header(),
+                ));
+            let res = sender.send_request(req).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            let mut upgraded = TokioIo::new(hyper::upgrade::on(res).await?);
+            let (client, mut bridge) = tokio::io::duplex(64 * 1024);
+            tokio::task::spawn(async move {
+                tokio::io::copy_bidirectional(&mut upgraded, &mut bridge)
+                    .await
+                    .ok();
+            });
+            make_test_client(client, key).await
+        }
+
+        // Both relay connections share the HTTP/2 connection.
+        let a_key = SecretKey::generate(rand::thread_rng());
+        let b_key = SecretKey::generate(rand::thread_rng());
+        let mut client_a = connect(&mut sender, port, &a_key).await?;
+        let mut client_b = connect(&mut sender, port, &b_key).await?;
+
+        let msg = Bytes::from_static(b"hello over h2");
+        let mut result = None;
+        // The server may not have registered b yet.
+        for _ in 0..10 {
+            client_a
+                .send(SendMessage::SendPacket(b_key.public(), msg.clone()))
+                .await?;
+            match tokio::time::timeout(Duration::from_millis(500), client_b.next()).await {
+                Ok(res) => {
+                    result = process_msg(res);
+                    break;
+                }
+                Err(_) => continue,
+            }
+        }
+        let (got_key, got_msg) = result.context("expected message from client a")?;
+        assert_eq!(got_key, a_key.public());
+        assert_eq!(got_msg, msg);
+
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_http_client_fronting_domain() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap()).spawn()?;
+        // The relay hostname does not resolve, only the fronting domain is dialed.
+        let url: Url = format!("http://relay.invalid:{}", server.addr().port())
+            .parse()
+            .unwrap();
+
+        let key = SecretKey::generate(rand::thread_rng());
+        let mut client = ClientBuilder::new(url, key, DnsResolver::new())
+            .fronting_domain("127.0.0.1")
+            .connect()
+            .await?;
+        client.send(SendMessage::Ping([1u8; 8])).await?;
+        let pong = client.next().await.context("eos")??;
+        assert!(matches!(pong, ReceivedMessage::Pong(_)));
+
+        client.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_http_client_connect_tunnel() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap()).spawn()?;
+        let url: Url = format!("http://127.0.0.1:{}", server.addr().port())
+            .parse()
+            .unwrap();
+
+        let key = SecretKey::generate(rand::thread_rng());
+        let mut client = ClientBuilder::new(url, key, DnsResolver::new())
+            .http_connect_tunnel(true)
+            .connect()
+            .await?;
+        client.send(SendMessage::Ping([1u8; 8])).await?;
+        let pong = client.next().await.context("eos")??;
+        assert!(matches!(pong, ReceivedMessage::Pong(_)));
+
+        client.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+
+        Ok(())
+    }
+
+    /// Runs a proxy tunnelling connections by SOCKS5 or by HTTP `CONNECT`.
+    ///
+    /// The proxy requires the username `user` and the password `p@ss`.
+    async fn spawn_test_proxy(socks: bool) -> Result<(SocketAddr, tokio::task::JoinHandle<()>)> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        async fn socks5_handshake(stream: &mut tokio::net::TcpStream) -> Result<(String, u16)> {
+            let mut header = [0u8; 2];
+            stream.read_exact(&mut header).await?;
+            let mut methods = vec![0u8; header[1] as usize];
+            stream.read_exact(&mut methods).await?;
+            ensure!(methods.contains(&2), "no password offered");
+            stream.write_all(&[5, 2]).await?;
+            let mut buf = [0u8; 2];
+            stream.read_exact(&mut buf).await?;
+            let mut user = vec![0u8; buf[1] as usize];
+            stream.read_exact(&mut user).await?;
+            let mut pass = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut pass).await?;
+            let ok = user == b"user" && pass == b"p@ss";
+            stream.write_all(&[1, if ok { 0 } else { 1 }]).await?;
+            ensure!(ok, "wrong credentials");
+
+            let mut request = [0u8; 4];
+            stream.read_exact(&mut request).await?;
+            let host = match request[3] {
+                1 => {
+                    let mut ip = [0u8; 4];
+                    stream.read_exact(&mut ip).await?;
+                    std::net::Ipv4Addr::from(ip).to_string()
+                }
+                3 => {
+                    let mut host = vec![0u8; stream.read_u8().await? as usize];
+                    stream.read_exact(&mut host).await?;
+                    String::from_utf8(host)?
+                }
+                atyp => bail!("unexpected address type {atyp}"),
+            };
+            let port = stream.read_u16().await?;
+            stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+            Ok((host, port))
+        }
+
+        async fn http_connect(stream: &mut tokio::net::TcpStream) -> Result<(String, u16)> {
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await?);
+            }
+            let request = String::from_utf8(request)?.to_lowercase();
+            // base64 of `user:p@ss`
+            ensure!(
+                request.contains("proxy-authorization: basic dxnlcjpwqhnz"),
+                "wrong credentials"
+            );
+            let target = request
+                .strip_prefix("connect ")
+                .and_then(|r| r.split_whitespace().next())
+                .context("not a CONNECT request")?;
+            let (host, port) = target.rsplit_once(':').context("no port")?;
+            let (host, port) = (host.to_string(), port.parse()?);
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await?;
+            Ok((host, port))
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let task = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let target = match socks {
+                        true => socks5_handshake(&mut stream).await,
+                        false => http_connect(&mut stream).await,
+                    };
+                    let target = match target {
+                        Ok(target) => target,
+                        Err(err) => {
+                            info!("proxy refused tunnel: {err:#}");
+                            return;
+                        }
+                    };
+                    if let Ok(mut upstream) = tokio::net::TcpStream::connect(target).await {
+                        tokio::io::copy_bidirectional(&mut stream, &mut upstream)
+                            .await
+                            .ok();
+                    }
+                });
+            }
+        });
+        Ok((addr, task))
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_http_client_proxies() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap()).spawn()?;
+        let port = server.addr().port();
+        let (socks_addr, socks_task) = spawn_test_proxy(true).await?;
+        let (http_addr, http_task) = spawn_test_proxy(false).await?;
+
+        // With `socks5h` the proxy resolves the host name.
+        for (relay_url, proxy_url) in [
+            (
+                format!("http://127.0.0.1:{port}"),
+                format!("socks5://user:p%40ss@{socks_addr}"),
+            ),
+            (
+                format!("http://localhost:{port}"),
+                format!("socks5h://user:p%40ss@{socks_addr}"),
+            ),
+            (
+                format!("http://127.0.0.1:{port}"),
+                format!("http://user:p%40ss@{http_addr}"),
+            ),
+        ] {
+            info!(%proxy_url, "connecting through proxy");
+            let key = SecretKey::generate(rand::thread_rng());
+            let mut client = ClientBuilder::new(relay_url.parse::<Url>()?, key, DnsResolver::new())
+                .proxy_url(proxy_url.parse()?)
+                .connect()
+                .await?;
+            client.send(SendMessage::Ping([1u8; 8])).await?;
+            let pong = client.next().await.context("eos")??;
+            assert!(matches!(pong, ReceivedMessage::Pong(_)));
+            client.close().await?;
+        }
+
+        // Wrong credentials are refused.
+        for proxy_addr in [socks_addr, http_addr] {
+            let scheme = if proxy_addr == socks_addr {
+                "socks5"
+            } else {
+                "http"
+            };
+            let key = SecretKey::generate(rand::thread_rng());
+            let res = ClientBuilder::new(
+                format!("http://127.0.0.1:{port}").parse::<Url>()?,
+                key,
+                DnsResolver::new(),
+            )
+            .proxy_url(format!("{scheme}://user:wrong@{proxy_addr}").parse()?)
+            .connect()
+            .await;
+            assert!(res.is_err());
+        }
+
+        // WebSocket connections do not bypass the proxy.
+        let key = SecretKey::generate(rand::thread_rng());
+        let res = ClientBuilder::new(
+            format!("http://127.0.0.1:{port}").parse::<Url>()?,
+            key,
+            DnsResolver::new(),
+        )
+        .protocol(Protocol::Websocket)
+        .proxy_url(format!("socks5://user:p%40ss@{socks_addr}").parse()?)
+        .connect()
+        .await;
+        assert!(res.is_err());
+
+        socks_task.abort();
+        http_task.abort();
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_http_client_telemetry_sampling() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap()).spawn()?;
+        let url: Url = format!("http://127.0.0.1:{}", server.addr().port())
+            .parse()
+            .unwrap();
+
+        let key = SecretKey::generate(rand::thread_rng());
+        let client = ClientBuilder::new(url, key, DnsResolver::new())
+            .telemetry_sampling(TelemetryConfig {
+                sample_every: 2.try_into().unwrap(),
+                capacity: 8.try_into().unwrap(),
+            })
+            .connect()
+            .await?;
+        let telemetry = client.telemetry().context("telemetry enabled")?.clone();
+        let (mut stream, mut sink) = client.split();
+        for i in 0..4 {
+            sink.send(SendMessage::Ping([i; 8])).await?;
+            let pong = stream.next().await.context("eos")??;
+            assert!(matches!(pong, ReceivedMessage::Pong(data) if data == [i; 8]));
+        }
+
+        let samples = telemetry.samples();
+        assert_eq!(samples.iter().map(|s| s.seq).collect::<Vec<_>>(), [0, 2]);
+        for sample in samples {
+            assert_eq!(sample.frame, SampledFrame::Ping);
+            assert!(sample.write_time.is_some());
+            assert!(sample.pong_time.is_some());
+        }
+
+        server.shutdown();
+        server.task_handle().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_route_group_headers() -> Result<()> {
+        fn ok_handler(
+            _req: Request<Incoming>,
+            res: ResponseBuilder,
+        ) -> HyperResult<Response<BytesBody>> {
+            Ok(res.status(StatusCode::OK).body(body_empty())?)
+        }
+        fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static(value));
+            headers
+        }
+
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .headers(headers("x-default", "1"))
+            .route_group(RouteGroup::Probes, headers("x-probe", "1"))
+            .route_group(RouteGroup::Errors, headers("x-error", "1"))
+            .route_group(RouteGroup::Relay, headers("x-relay", "1"))
+            .extra_headers(headers("x-extra", "1"))
+            .extra_route_headers(HashMap::from([(
+                RouteGroup::Errors,
+                headers("x-extra", "errors"),
+            )]))
+            .request_handler(Method::GET, "/", Box::new(ok_handler))
+            .route_group_handler(
+                RouteGroup::Probes,
+                Method::GET,
+                "/ping",
+                Box::new(ok_handler),
+            )
+            .route_group_handler(
+                RouteGroup::Pages,
+                Method::GET,
+                "/other",
+                Box::new(ok_handler),
+            )
+            .spawn()?;
+        let http = reqwest::Client::new();
+        let get = |path: &'static str| {
+            http.get(format!("http://127.0.0.1:{}{path}", server.addr().port()))
+                .send()
+        };
+
+        let res = get("/").await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().contains_key("x-default"));
+        assert!(!res.headers().contains_key("x-probe"));
+
+        let res = get("/ping").await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().contains_key("x-probe"));
+        assert!(!res.headers().contains_key("x-default"));
+
+        // A group without configured headers uses the default headers.
+        let res = get("/other").await?;
+        assert!(res.headers().contains_key("x-default"));
+
+        assert_eq!(res.headers()["x-extra"], "1");
+
+        // The extra headers of a group replace the extra headers of all responses.
+        let res = get("/nope").await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.headers().get_all("x-error").iter().count(), 1);
+        assert!(!res.headers().contains_key("x-default"));
+        assert_eq!(res.headers().get_all("x-extra").iter().count(), 1);
+        assert_eq!(res.headers()["x-extra"], "errors");
+
+        // Without an upgrade header the relay endpoint responds with an error.
+        let res = get(RELAY_PATH).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(res.headers().contains_key("x-relay"));
+        assert!(!res.headers().contains_key("x-default"));
+
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_response_compression() -> Result<()> {
+        fn page_handler(
+            _req: Request<Incoming>,
+            res: ResponseBuilder,
+        ) -> HyperResult<Response<BytesBody>> {
+            Ok(res
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "text/html")
+                .body(body_full("<p>status</p>".repeat(200)))?)
+        }
+
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .compression(Some(CompressionConfig::default()))
+            .request_handler(Method::GET, "/status", Box::new(page_handler))
+            .spawn()?;
+        let http = reqwest::Client::new();
+        let get = |accept_encoding: &'static str| {
+            http.get(format!("http://127.0.0.1:{}/status", server.addr().port()))
+                .header(ACCEPT_ENCODING, accept_encoding)
+                .send()
+        };
+
+        let res = get("gzip;q=0.8, br").await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_ENCODING], "br");
+        assert_eq!(res.headers()[VARY], "accept-encoding");
+        let body = res.bytes().await?;
+        let mut decoded = String::new();
+        std::io::Read::read_to_string(
+            &mut brotli::Decompressor::new(&body[..], 4096),
+            &mut decoded,
+        )?;
+        assert_eq!(decoded, "<p>status</p>".repeat(200));
+
+        let res = get("identity").await?;
+        assert!(!res.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(res.text().await?, "<p>status</p>".repeat(200));
+
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_admin_key_cache() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .admin(Some(AdminConfig {
+                bearer_token: "secret".to_string(),
+            }))
+            .spawn()?;
+        let url = format!(
+            "http://127.0.0.1:{}{ADMIN_KEY_CACHE_PATH}",
+            server.addr().port()
+        );
+        let http = reqwest::Client::new();
+
+        let res = http.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = http.get(&url).bearer_auth("wrong").send().await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // Connecting a client parses its key through the cache.
+        let key = SecretKey::generate(rand::thread_rng());
+        let relay_url: Url = format!("http://127.0.0.1:{}", server.addr().port()).parse()?;
+        let mut client = ClientBuilder::new(relay_url, key, DnsResolver::new())
+            .connect()
+            .await?;
+        client.send(SendMessage::Ping([1u8; 8])).await?;
+        client.next().await.context("eos")??;
+
+        let res = http.get(&url).bearer_auth("secret").send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let stats: serde_json::Value = serde_json::from_str(&res.text().await?)?;
+        assert_eq!(stats["capacity"], DEFAULT_KEY_CACHE_CAPACITY);
+        assert_eq!(stats["misses"], 1);
+
+        let res = http
+            .get(format!(
+                "http://127.0.0.1:{}/admin/nope",
+                server.addr().port()
+            ))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        client.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+
+        // Without an admin config, the admin API does not exist.
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap()).spawn()?;
+        let url = format!(
+            "http://127.0.0.1:{}{ADMIN_KEY_CACHE_PATH}",
+            server.addr().port()
+        );
+        let res = http.get(&url).bearer_auth("secret").send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        server.shutdown();
+        server.task_handle().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_admin_client_versions() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .admin(Some(AdminConfig {
+                bearer_token: "secret".to_string(),
+            }))
+            .spawn()?;
+        let relay_url: Url = format!("http://127.0.0.1:{}", server.addr().port()).parse()?;
+
+        let mut clients = Vec::new();
+        for software in [
+            None,
+            Some(("iroh", "0.31.0")),
+            Some(("iroh", "0.32.0")),
+            Some(("iroh", "0.32.0")),
+        ] {
+            let key = SecretKey::generate(rand::thread_rng());
+            let mut client = ClientBuilder::new(relay_url.clone(), key, DnsResolver::new())
+                .client_software(software.map(|(name, version)| (name.into(), version.into())))
+                .connect()
+                .await?;
+            // The pong is only sent once the client is registered.
+            client.send(SendMessage::Ping([1u8; 8])).await?;
+            client.next().await.context("eos")??;
+            clients.push(client);
+        }
+
+        let res = reqwest::Client::new()
+            .get(format!(
+                "http://{}{ADMIN_CLIENT_VERSIONS_PATH}",
+                server.addr()
+            ))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let versions: serde_json::Value = serde_json::from_str(&res.text().await?)?;
+        assert_eq!(
+            versions,
+            serde_json::json!({
+                "versions": [
+                    { "name": "iroh", "version": "0.31.0", "clients": 1 },
+                    { "name": "iroh", "version": "0.32.0", "clients": 2 },
+                ],
+                "unreported": 1,
+            })
+        );
+
+        for mut client in clients {
+            client.close().await?;
+        }
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_admin_access_reload() -> Result<()> {
+        let a_key = SecretKey::generate(rand::thread_rng());
+        let b_key = SecretKey::generate(rand::thread_rng());
+        let path =
+            std::env::temp_dir().join(format!("iroh-relay-allowlist-{}", rand::random::<u64>()));
+        std::fs::write(&path, format!("{}\n", a_key.public()))?;
+
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .admin(Some(AdminConfig {
+                bearer_token: "secret".to_string(),
+            }))
+            .access(AccessConfig::Allowlist(NodeList::load(&path)?))
+            .spawn()?;
+        let relay_url: Url = format!("http://127.0.0.1:{}", server.addr().port()).parse()?;
+
+        // Whether the client is registered, or rejected by the allowlist.
+        let registers = |key: SecretKey| {
+            let relay_url = relay_url.clone();
+            async move {
+                let mut client = ClientBuilder::new(relay_url, key, DnsResolver::new())
+                    .connect()
+                    .await?;
+                client.send(SendMessage::Ping([1u8; 8])).await?;
+                let registered = match client.next().await.context("eos")?? {
+                    ReceivedMessage::Pong(_) => true,
+                    ReceivedMessage::Health { problem } => {
+                        assert_eq!(problem.as_deref(), Some("not authenticated"));
+                        false
+                    }
+                    msg => anyhow::bail!("unexpected message {msg:?}"),
+                };
+                client.close().await.ok();
+                anyhow::Ok(registered)
+            }
+        };
+        assert!(registers(a_key.clone()).await?);
+        assert!(!registers(b_key.clone()).await?);
+
+        let url = format!("http://{}{ADMIN_ACCESS_RELOAD_PATH}", server.addr());
+        std::fs::write(&path, format!("{}\n", b_key.public()))?;
+        let res = reqwest::Client::new()
+            .post(&url)
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&res.text().await?)?;
+        assert_eq!(body, serde_json::json!({ "nodes": 1 }));
+        assert!(!registers(a_key).await?);
+        assert!(registers(b_key.clone()).await?);
+
+        // An invalid file is rejected and keeps the loaded nodes.
+        std::fs::write(&path, "invalid\n")?;
+        let res = reqwest::Client::new()
+            .post(&url)
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(registers(b_key).await?);
+
+        server.shutdown();
+        server.task_handle().await?;
+        std::fs::remove_file(&path)?;
+
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .admin(Some(AdminConfig {
+                bearer_token: "secret".to_string(),
+            }))
+            .spawn()?;
+        let res = reqwest::Client::new()
+            .post(format!(
+                "http://{}{ADMIN_ACCESS_RELOAD_PATH}",
+                server.addr()
+            ))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_reload() -> Result<()> {
+        fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static(value));
+            headers
+        }
+        fn server_config() -> rustls::ServerConfig {
+            let TlsConfig { config, acceptor } = make_tls_config();
+            drop(acceptor);
+            Arc::into_inner(config).expect("not shared")
+        }
+
+        let a_key = SecretKey::generate(rand::thread_rng());
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .headers(headers("x-default", "1"))
+            .extra_headers(headers("x-extra", "1"))
+            .spawn()?;
+        let handle = server.handle();
+        let relay_url: Url = format!("http://127.0.0.1:{}", server.addr().port()).parse()?;
+        let get = || reqwest::get(format!("http://{}/", server.addr()));
+        let res = get().await?;
+        assert_eq!(res.headers()["x-default"], "1");
+        assert_eq!(res.headers()["x-extra"], "1");
+
+        // The connected client is not disconnected by the new access config.
+        let mut client = ClientBuilder::new(relay_url.clone(), a_key.clone(), DnsResolver::new())
+            .connect()
+            .await?;
+        client.send(SendMessage::Ping([1u8; 8])).await?;
+        assert!(matches!(
+            client.next().await.context("eos")??,
+            ReceivedMessage::Pong(_)
+        ));
+        handle.reload(ReloadConfig {
+            client_rx: Some(ClientRateLimit {
+                bytes_per_second: NonZeroU32::new(1000).unwrap(),
+                max_burst_bytes: None,
+            }),
+            trusted_client_rx: None,
+            client_tx: None,
+            access: AccessConfig::Denylist(NodeList::new([a_key.public()])),
+            headers: headers("x-default", "2"),
+            route_headers: HashMap::new(),
+            tls_server_config: None,
+        })?;
+        client.send(SendMessage::Ping([1u8; 8])).await?;
+        assert!(matches!(
+            client.next().await.context("eos")??,
+            ReceivedMessage::Pong(_)
+        ));
+        client.close().await?;
+
+        let mut client = ClientBuilder::new(relay_url, a_key, DnsResolver::new())
+            .connect()
+            .await?;
+        client.send(SendMessage::Ping([1u8; 8])).await?;
+        assert!(matches!(
+            client.next().await.context("eos")??,
+            ReceivedMessage::Health { .. }
+        ));
+        client.close().await.ok();
+
+        // The extra headers replace the default header of the same name.
+        let res = get().await?;
+        assert_eq!(res.headers().get_all("x-default").iter().count(), 1);
+        assert_eq!(res.headers()["x-default"], "2");
+        assert!(!res.headers().contains_key("x-extra"));
+        let config = handle.effective_config();
+        assert_eq!(config["limits"]["client_rx"]["bytes_per_second"], 1000);
+        assert_eq!(config["access"], "denylist");
+
+        // Nothing is replaced if the certificates can not be.
+        let err = handle
+            .reload(ReloadConfig {
+                client_rx: None,
+                trusted_client_rx: None,
+                client_tx: None,
+                access: AccessConfig::Everyone,
+                headers: HeaderMap::new(),
+                route_headers: HashMap::new(),
+                tls_server_config: Some(server_config()),
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "the server does not serve TLS");
+        assert_eq!(get().await?.headers()["x-default"], "2");
+        assert_eq!(handle.effective_config()["access"], "denylist");
+        server.shutdown();
+        server.task_handle().await?;
+
+        // New certificates are served to new connections, with the same ALPN protocols.
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .tls_config(Some(make_tls_config_with_alpns(vec![H2_ALPN.to_vec()])))
+            .spawn()?;
+        let served_cert = || async {
+            let mut config = crate::client::make_dangerous_client_config();
+            config.alpn_protocols = vec![H2_ALPN.to_vec()];
+            let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+            let stream = TcpStream::connect(server.addr()).await?;
+            let server_name = rustls::pki_types::ServerName::try_from("localhost")?;
+            let stream = connector.connect(server_name, stream).await?;
+            let (_, conn) = stream.get_ref();
+            assert_eq!(conn.alpn_protocol(), Some(H2_ALPN));
+            let certs = conn.peer_certificates().context("no certificates")?;
+            anyhow::Ok(certs[0].clone())
+        };
+        let cert = served_cert().await?;
+        server.handle().reload(ReloadConfig {
+            client_rx: None,
+            trusted_client_rx: None,
+            client_tx: None,
+            access: AccessConfig::Everyone,
+            headers: HeaderMap::new(),
+            route_headers: HashMap::new(),
+            tls_server_config: Some(server_config()),
+        })?;
+        let reloaded = served_cert().await?;
+        assert_ne!(reloaded, cert);
+        server.handle().reload_tls(server_config())?;
+        assert_ne!(served_cert().await?, reloaded);
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_drain_direct_framing() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .tls_config(Some(make_tls_config_with_alpns(vec![RELAY_ALPN.to_vec()])))
+            .spawn()?;
+        let relay_url: Url = format!("https://localhost:{}", server.addr().port()).parse()?;
+        let key = SecretKey::generate(rand::thread_rng());
+        let connect = || async {
+            let mut client = ClientBuilder::new(relay_url.clone(), key.clone(), DnsResolver::new())
+                .insecure_skip_cert_verify(true)
+                .connect()
+                .await?;
+            client.send(SendMessage::Ping([1u8; 8])).await?;
+            client.next().await.context("eos")?
+        };
+
+        server
+            .handle()
+            .service
+            .0
+            .draining
+            .store(true, Ordering::Relaxed);
+        assert!(connect().await.is_err(), "connected while draining");
+        assert!(logs_contain("serving relay client with direct framing"));
+
+        server
+            .handle()
+            .service
+            .0
+            .draining
+            .store(false, Ordering::Relaxed);
+        assert!(matches!(connect().await?, ReceivedMessage::Pong(_)));
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_error_pages() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .admin(Some(AdminConfig {
+                bearer_token: "secret".to_string(),
+            }))
+            .error_pages(ErrorPages {
+                not_found: Some(ErrorPage::html("<p>{status} {reason}: {path}</p>")),
+                bad_request: Some(ErrorPage::Redirect("https://example.com/".parse()?)),
+                unavailable: Some(ErrorPage::html("<p>{status} draining</p>")),
+            })
+            .spawn()?;
+        let base = format!("http://{}", server.addr());
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+
+        let res = http.get(format!("{base}/missing")).send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(res.text().await?, "<p>404 Not Found: /missing</p>");
+
+        // Opening the relay endpoint without an upgrade is a bad request.
+        let res = http.get(format!("{base}{RELAY_PATH}")).send().await?;
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(res.headers()[LOCATION], "https://example.com/");
+
+        let res = http
+            .post(format!("{base}{ADMIN_DRAIN_PATH}"))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&res.text().await?)?;
+        assert_eq!(body, serde_json::json!({ "draining": true, "clients": 0 }));
+
+        let res = http.get(format!("{base}{RELAY_PATH}")).send().await?;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.text().await?, "<p>503 draining</p>");
+        let relay_url: Url = base.parse()?;
+        let key = SecretKey::generate(rand::thread_rng());
+        let res = ClientBuilder::new(relay_url.clone(), key.clone(), DnsResolver::new())
+            .connect()
+            .await;
+        assert!(res.is_err(), "connected while draining");
+
+        let res = http
+            .delete(format!("{base}{ADMIN_DRAIN_PATH}"))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        let body: serde_json::Value = serde_json::from_str(&res.text().await?)?;
+        assert_eq!(body["draining"], false);
+        let mut client = ClientBuilder::new(relay_url, key, DnsResolver::new())
+            .connect()
+            .await?;
+        client.send(SendMessage::Ping([1u8; 8])).await?;
+        client.next().await.context("eos")??;
+
+        client.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_admin_clients() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .admin(Some(AdminConfig {
+                bearer_token: "secret".to_string(),
+            }))
+            .spawn()?;
+        let relay_url: Url = format!("http://127.0.0.1:{}", server.addr().port()).parse()?;
+
+        let key_a = SecretKey::generate(rand::thread_rng());
+        let mut client_a = ClientBuilder::new(relay_url.clone(), key_a.clone(), DnsResolver::new())
+            .client_software(Some(("iroh".into(), "0.32.0".into())))
+            .connect()
+            .await?;
+        let key_b = SecretKey::generate(rand::thread_rng());
+        let mut client_b = ClientBuilder::new(relay_url, key_b.clone(), DnsResolver::new())
+            .protocol(Protocol::Websocket)
+            .connect()
+            .await?;
+        // The pong is only sent once the client is registered.
+        client_b.send(SendMessage::Ping([1u8; 8])).await?;
+        client_b.next().await.context("eos")??;
+
+        let msg = Bytes::from_static(b"hello");
+        client_a
+            .send(SendMessage::SendPacket(key_b.public(), msg.clone()))
+            .await?;
+        let received = tokio::time::timeout(Duration::from_secs(5), client_b.next())
+            .await?
+            .context("eos")??;
+        assert!(matches!(received, ReceivedMessage::ReceivedPacket { .. }));
+
+        let res = reqwest::Client::new()
+            .get(format!("http://{}{ADMIN_CLIENTS_PATH}", server.addr()))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&res.text().await?)?;
+        let clients = body["clients"].as_array().context("clients")?;
+        assert_eq!(clients.len(), 2);
+        let client = |key: &SecretKey| {
+            clients
+                .iter()
+                .find(|client| client["node_id"] == key.public().to_string())
+                .expect("client listed")
+        };
+        let info_a = client(&key_a);
+        assert_eq!(info_a["protocol"], "relay");
+        assert_eq!(info_a["bytes_recv"], msg.len());
+        assert_eq!(info_a["bytes_sent"], 0);
+        assert_eq!(info_a["software"]["version"], "0.32.0");
+        assert_eq!(info_a["connected_secs"], 0);
+        let info_b = client(&key_b);
+        assert_eq!(info_b["protocol"], "websocket");
+        assert_eq!(info_b["bytes_sent"], msg.len());
+        assert_eq!(info_b["bytes_recv"], 0);
+
+        client_a.close().await?;
+        client_b.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_client_quota() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .admin(Some(AdminConfig {
+                bearer_token: "secret".to_string(),
+            }))
+            .client_quota(Some(QuotaConfig {
+                daily_bytes: Some(10.try_into().unwrap()),
+                monthly_bytes: None,
+            }))
+            .spawn()?;
+        let relay_url: Url = format!("http://127.0.0.1:{}", server.addr().port()).parse()?;
+
+        let key_a = SecretKey::generate(rand::thread_rng());
+        let mut client_a = ClientBuilder::new(relay_url.clone(), key_a.clone(), DnsResolver::new())
+            .connect()
+            .await?;
+        let key_b = SecretKey::generate(rand::thread_rng());
+        let mut client_b = ClientBuilder::new(relay_url, key_b.clone(), DnsResolver::new())
+            .connect()
+            .await?;
+        // The pong is only sent once the client is registered.
+        client_b.send(SendMessage::Ping([1u8; 8])).await?;
+        client_b.next().await.context("eos")??;
+
+        // The packet using up the quota is relayed, the next one dropped.
+        let msg = Bytes::from_static(b"hello world");
+        for _ in 0..2 {
+            client_a
+                .send(SendMessage::SendPacket(key_b.public(), msg.clone()))
+                .await?;
+        }
+        let health = client_a.next().await.context("eos")??;
+        assert!(
+            matches!(&health, ReceivedMessage::Health { problem: Some(problem) } if problem.contains("quota")),
+            "{health:?}"
+        );
+        let received = client_b.next().await.context("eos")??;
+        assert!(matches!(received, ReceivedMessage::ReceivedPacket { .. }));
+        client_b.send(SendMessage::Ping([2u8; 8])).await?;
+        let pong = client_b.next().await.context("eos")??;
+        assert!(matches!(pong, ReceivedMessage::Pong(data) if data == [2u8; 8]));
+
+        let admin_url = format!("http://{}{ADMIN_QUOTAS_PATH}", server.addr());
+        let res = reqwest::Client::new()
+            .get(&admin_url)
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&res.text().await?)?;
+        assert_eq!(
+            body["nodes"],
+            serde_json::json!([{
+                "node_id": key_a.public().to_string(),
+                "daily_bytes": msg.len(),
+                "monthly_bytes": msg.len(),
+                "exceeded": true,
+            }])
+        );
+
+        // Resetting the usage lifts the quota right away.
+        let res = reqwest::Client::new()
+            .delete(format!("{admin_url}?node={}", key_a.public()))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        client_a
+            .send(SendMessage::SendPacket(key_b.public(), msg.clone()))
+            .await?;
+        let received = client_b.next().await.context("eos")??;
+        assert!(matches!(received, ReceivedMessage::ReceivedPacket { .. }));
+
+        client_a.close().await?;
+        client_b.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_admin_watchdog() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .admin(Some(AdminConfig {
+                bearer_token: "secret".to_string(),
+            }))
+            .watchdog(Some(WatchdogConfig {
+                interval: Duration::from_millis(10),
+                max_client_tasks: Some(0),
+                ..Default::default()
+            }))
+            .spawn()?;
+        let url = format!(
+            "http://127.0.0.1:{}{ADMIN_WATCHDOG_PATH}",
+            server.addr().port()
+        );
+        let http = reqwest::Client::new();
+
+        let key = SecretKey::generate(rand::thread_rng());
+        let relay_url: Url = format!("http://127.0.0.1:{}", server.addr().port()).parse()?;
+        let mut client = ClientBuilder::new(relay_url, key, DnsResolver::new())
+            .connect()
+            .await?;
+        client.send(SendMessage::Ping([1u8; 8])).await?;
+        client.next().await.context("eos")??;
+
+        let report = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let res = http.get(&url).bearer_auth("secret").send().await?;
+                assert_eq!(res.status(), StatusCode::OK);
+                let report: serde_json::Value = serde_json::from_str(&res.text().await?)?;
+                if !report["last_alarm"].is_null() {
+                    break anyhow::Ok(report);
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await??;
+        let current = &report["current"];
+        assert_eq!(current["client_tasks"], 1);
+        assert_eq!(current["registered_clients"], 1);
+        assert_eq!(current["orphaned_client_tasks"], 0);
+        assert_eq!(current["alarms"][0], "client_tasks 1 exceeds 0");
+        assert_eq!(report["last_alarm"]["alarms"], current["alarms"]);
+
+        client.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+
+        // Without a watchdog config, the endpoint does not exist.
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .admin(Some(AdminConfig {
+                bearer_token: "secret".to_string(),
+            }))
+            .spawn()?;
+        let url = format!(
+            "http://127.0.0.1:{}{ADMIN_WATCHDOG_PATH}",
+            server.addr().port()
+        );
+        let res = http.get(&url).bearer_auth("secret").send().await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        server.shutdown();
+        server.task_handle().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_bind_listener_ipv6_only() -> Result<()> {
+        let addr: SocketAddr = "[::]:0".parse().unwrap();
+
+        let listener = bind_listener(addr, Some(false))?;
+        let port = listener.local_addr()?.port();
+        let (_stream, (_, peer_addr)) =
+            tokio::try_join!(TcpStream::connect(("127.0.0.1", port)), listener.accept())?;
+        assert!(
+            matches!(peer_addr.ip(), std::net::IpAddr::V6(ip) if ip.to_ipv4_mapped().is_some())
+        );
+        assert_eq!(
+            canonical_addr(peer_addr).ip(),
+            std::net::Ipv4Addr::LOCALHOST
+        );
+
+        let listener = bind_listener(addr, Some(true))?;
+        let port = listener.local_addr()?.port();
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+        TcpStream::connect(("::1", port)).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_admin_listener_rebind() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .admin(Some(AdminConfig {
+                bearer_token: "secret".to_string(),
+            }))
+            .spawn()?;
+        let old_addr = server.addr();
+        let http = reqwest::Client::new();
+
+        // A client connected to the old listener.
+        let relay_url: Url = format!("http://{old_addr}").parse()?;
+        let mut client_a = ClientBuilder::new(
+            relay_url,
+            SecretKey::generate(rand::thread_rng()),
+            DnsResolver::new(),
+        )
+        .connect()
+        .await?;
+
+        let url = format!("http://{old_addr}{ADMIN_LISTENER_PATH}");
+        let res = http.post(&url).bearer_auth("secret").send().await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = http
+            .post(format!("{url}?addr=nope"))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = http
+            .post(format!("{url}?addr={old_addr}"))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let res = http
+            .post(format!("{url}?addr=127.0.0.1:0"))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&res.text().await?)?;
+        let new_addr: SocketAddr = body["addr"].as_str().context("addr")?.parse()?;
+        assert_ne!(new_addr, old_addr);
+
+        // New clients connect to the new listener.
+        let relay_url: Url = format!("http://{new_addr}").parse()?;
+        let key_b = SecretKey::generate(rand::thread_rng());
+        let mut client_b = ClientBuilder::new(relay_url, key_b.clone(), DnsResolver::new())
+            .connect()
+            .await?;
+        client_b.send(SendMessage::Ping([1u8; 8])).await?;
+        client_b.next().await.context("eos")??;
+
+        // The client of the old listener keeps being served.
+        let msg = Bytes::from_static(b"still here");
+        client_a
+            .send(SendMessage::SendPacket(key_b.public(), msg.clone()))
+            .await?;
+        let received = tokio::time::timeout(Duration::from_secs(5), client_b.next())
+            .await?
+            .context("eos")??;
+        let ReceivedMessage::ReceivedPacket { data, .. } = received else {
+            bail!("unexpected message {received:?}");
+        };
+        assert_eq!(data, msg);
+
+        // The old listener no longer accepts connections.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while TcpStream::connect(old_addr).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        client_a.close().await?;
+        client_b.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_admin_config() -> Result<()> {
+        let mesh_key = MeshKey::generate();
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .admin(Some(AdminConfig {
+                bearer_token: "secret".to_string(),
+            }))
+            .mesh_key(Some(mesh_key))
+            .client_rx_ratelimit(ClientRateLimit {
+                bytes_per_second: 1024.try_into().unwrap(),
+                max_burst_bytes: None,
+            })
+            .handshake_limit(Some(HandshakeLimit {
+                max_concurrent: 8.try_into().unwrap(),
+                queue_timeout: Duration::from_millis(250),
+            }))
+            .connection_limit(ConnectionLimit {
+                max_total: None,
+                max_per_ip: Some(16.try_into().unwrap()),
+            })
+            .services(ServiceConfig {
+                tls: Some(TlsMode::Manual),
+                stun_addrs: vec!["127.0.0.1:3478".parse().unwrap()],
+                ..Default::default()
+            })
+            .spawn()?;
+        let url = format!("http://{}{ADMIN_CONFIG_PATH}", server.addr());
+        let http = reqwest::Client::new();
+
+        let res = http.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = http.get(&url).bearer_auth("secret").send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.text().await?;
+        assert!(!body.contains("secret"));
+        let config: serde_json::Value = serde_json::from_str(&body)?;
+        assert_eq!(config["listeners"]["relay"], server.addr().to_string());
+        assert_eq!(config["listeners"]["stun"][0], "127.0.0.1:3478");
+        assert_eq!(config["listeners"]["quic"], serde_json::Value::Null);
+        assert_eq!(config["tls"], "manual");
+        assert_eq!(config["limits"]["client_rx"]["bytes_per_second"], 1024);
+        assert_eq!(config["limits"]["client_tx"], serde_json::Value::Null);
+        assert_eq!(config["limits"]["handshakes"]["max_concurrent"], 8);
+        assert_eq!(config["limits"]["handshakes"]["queue_timeout_ms"], 250);
+        assert_eq!(
+            config["limits"]["connections"]["max_total"],
+            serde_json::Value::Null
+        );
+        assert_eq!(config["limits"]["connections"]["max_per_ip"], 16);
+        assert_eq!(config["key_cache"]["capacity"], DEFAULT_KEY_CACHE_CAPACITY);
+        assert_eq!(config["key_cache"]["eviction"], "lru");
+        assert_eq!(config["access"], "everyone");
+        assert_eq!(config["mesh_key"], true);
+        assert_eq!(config["watchdog"], serde_json::Value::Null);
+        assert_eq!(config["error_pages"]["not_found"], serde_json::Value::Null);
+
+        // The listener address follows rebinds.
+        let res = http
+            .post(format!(
+                "http://{}{ADMIN_LISTENER_PATH}?addr=127.0.0.1:0",
+                server.addr()
+            ))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&res.text().await?)?;
+        let new_addr = body["addr"].as_str().context("addr")?;
+        let res = http
+            .get(format!("http://{new_addr}{ADMIN_CONFIG_PATH}"))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        let config: serde_json::Value = serde_json::from_str(&res.text().await?)?;
+        assert_eq!(config["listeners"]["relay"], new_addr);
+
+        server.shutdown();
+        server.task_handle().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_faulty_connections() -> Result<()> {
+        let server_faults = FaultConfig::default();
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .faults(server_faults.clone())
+            .spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        let key_a = SecretKey::generate(rand::thread_rng());
+        let mut client_a = ClientBuilder::new(relay_url.clone(), key_a.clone(), DnsResolver::new())
+            .connect()
+            .await?;
+        let client_faults = FaultConfig {
+            latency: Some(Duration::from_millis(1)),
+            max_write_len: Some(1),
+            ..Default::default()
+        };
+        let mut client_b = ClientBuilder::new(
+            relay_url,
+            SecretKey::generate(rand::thread_rng()),
+            DnsResolver::new(),
+        )
+        .faults(client_faults.clone())
+        .connect()
+        .await?;
+
+        // Partial writes and latency must not corrupt frames.
+        let msg = Bytes::from_static(b"written one byte at a time");
+        client_b
+            .send(SendMessage::SendPacket(key_a.public(), msg.clone()))
+            .await?;
+        let received = tokio::time::timeout(Duration::from_secs(5), client_a.next())
+            .await?
+            .context("eos")??;
+        let ReceivedMessage::ReceivedPacket { data, .. } = received else {
+            bail!("unexpected message {received:?}");
+        };
+        assert_eq!(data, msg);
+
+        // A stalled server does not answer pings until resumed.
+        server_faults.handle.stall();
+        client_a.send(SendMessage::Ping([1u8; 8])).await?;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), client_a.next())
+                .await
+                .is_err()
+        );
+        server_faults.handle.resume();
+        let received = tokio::time::timeout(Duration::from_secs(5), client_a.next())
+            .await?
+            .context("eos")??;
+        assert!(matches!(received, ReceivedMessage::Pong(data) if data == [1u8; 8]));
+
+        // A disconnected client fails to send.
+        client_faults.handle.disconnect();
+        assert!(client_b.send(SendMessage::Ping([2u8; 8])).await.is_err());
+
+        client_a.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_ping_keepalive() -> Result<()> {
+        let server_faults = FaultConfig::default();
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .faults(server_faults.clone())
+            .spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        let client = ClientBuilder::new(
+            relay_url,
+            SecretKey::generate(rand::thread_rng()),
+            DnsResolver::new(),
+        )
+        .ping_keepalive(PingKeepaliveConfig {
+            interval: Duration::from_millis(50),
+            max_unanswered: NonZeroU32::new(2).unwrap(),
+        })
+        .connect()
+        .await?;
+        // The pings are sent by the stream half.
+        let (mut stream, _sink) = client.split();
+
+        // The server answers the pings, their pongs are not yielded.
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), stream.next())
+                .await
+                .is_err()
+        );
+
+        server_faults.handle.stall();
+        let lost = tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await?
+            .context("eos")??;
+        assert!(matches!(
+            lost,
+            ReceivedMessage::ConnectionLost {
+                unanswered_pings: 2
+            }
+        ));
+        assert!(stream.next().await.is_none());
+
+        server_faults.handle.resume();
+        server.shutdown();
+        server.task_handle().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_frame_checksums() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap()).spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        for protocol in [Protocol::Relay, Protocol::Websocket] {
+            info!(?protocol, "testing frame checksums");
+            let key_a = SecretKey::generate(rand::thread_rng());
+            let key_b = SecretKey::generate(rand::thread_rng());
+            let mut clients = Vec::new();
+            for key in [&key_a, &key_b] {
+                let mut client =
+                    ClientBuilder::new(relay_url.clone(), key.clone(), DnsResolver::new())
+                        .protocol(protocol)
+                        .frame_checksums(true)
+                        .connect()
+                        .await?;
+                // The server acknowledges the checksums with a checksummed keep alive.
+                let ack = client.next().await.context("eos")??;
+                assert!(matches!(ack, ReceivedMessage::KeepAlive));
+                clients.push(client);
+            }
+            let [mut client_a, mut client_b] = clients.try_into().expect("two clients");
+
+            // Large enough to use vectored writes without checksums.
+            let msg = Bytes::from(vec![42u8; 2048]);
+            client_a
+                .send(SendMessage::SendPacket(key_b.public(), msg.clone()))
+                .await?;
+            let received = client_b.next().await.context("eos")??;
+            let ReceivedMessage::ReceivedPacket {
+                remote_node_id,
+                data,
+            } = received
+            else {
+                bail!("unexpected message {received:?}");
+            };
+            assert_eq!(remote_node_id, key_a.public());
+            assert_eq!(data, msg);
+
+            client_a.close().await?;
+            client_b.close().await?;
+        }
+
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_fragmentation() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap()).spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        for protocol in [Protocol::Relay, Protocol::Websocket] {
+            info!(?protocol, "testing fragmentation");
+            let key_a = SecretKey::generate(rand::thread_rng());
+            let key_b = SecretKey::generate(rand::thread_rng());
+            let key_c = SecretKey::generate(rand::thread_rng());
+            let mut clients = Vec::new();
+            for (key, fragmentation) in [(&key_a, true), (&key_b, true), (&key_c, false)] {
+                let builder =
+                    ClientBuilder::new(relay_url.clone(), key.clone(), DnsResolver::new())
+                        .protocol(protocol)
+                        .fragmentation(fragmentation);
+                clients.push(connect_acked(builder).await?);
+            }
+            let [mut client_a, mut client_b, mut client_c] =
+                clients.try_into().expect("three clients");
+
+            let msg: Bytes = (0..3 * crate::MAX_PACKET_SIZE)
+                .map(|i| i as u8)
+                .collect::<Vec<_>>()
+                .into();
+            client_a
+                .send(SendMessage::SendPacket(key_b.public(), msg.clone()))
+                .await?;
+            let received = client_b.next().await.context("eos")??;
+            let ReceivedMessage::ReceivedPacket {
+                remote_node_id,
+                data,
+            } = received
+            else {
+                bail!("unexpected message {received:?}");
+            };
+            assert_eq!(remote_node_id, key_a.public());
+            assert_eq!(data, msg);
+
+            // The fragments are dropped for clients which did not enable fragmentation.
+            let small = Bytes::from_static(b"small");
+            client_a
+                .send(SendMessage::SendPacket(key_c.public(), msg.clone()))
+                .await?;
+            client_a
+                .send(SendMessage::SendPacket(key_c.public(), small.clone()))
+                .await?;
+            let received = client_c.next().await.context("eos")??;
+            let ReceivedMessage::ReceivedPacket { data, .. } = received else {
+                bail!("unexpected message {received:?}");
+            };
+            assert_eq!(data, small);
+
+            // Without fragmentation large packets can not be sent.
+            assert!(client_c
+                .send(SendMessage::SendPacket(key_a.public(), msg))
+                .await
+                .is_err());
+
+            client_a.close().await?;
+            client_b.close().await?;
+        }
+
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_acks() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap()).spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        for protocol in [Protocol::Relay, Protocol::Websocket] {
+            info!(?protocol, "testing send acks");
+            let key_a = SecretKey::generate(rand::thread_rng());
+            let key_b = SecretKey::generate(rand::thread_rng());
+            let mut clients = Vec::new();
+            for (key, send_acks) in [(&key_a, true), (&key_b, false)] {
+                let builder =
+                    ClientBuilder::new(relay_url.clone(), key.clone(), DnsResolver::new())
+                        .protocol(protocol)
+                        .send_acks(send_acks);
+                clients.push(connect_acked(builder).await?);
+            }
+            let [mut client_a, mut client_b] = clients.try_into().expect("two clients");
+
+            let msg = Bytes::from_static(b"hello");
+            let id = client_a.send_acked(key_b.public(), msg.clone()).await?;
+            let ack = client_a.next().await.context("eos")??;
+            assert!(
+                matches!(ack, ReceivedMessage::SendAck { id: acked, status: SendStatus::Queued } if acked == id),
+                "{ack:?}"
+            );
+            let received = client_b.next().await.context("eos")??;
+            assert!(
+                matches!(received, ReceivedMessage::ReceivedPacket { data, .. } if data == msg)
+            );
+
+            let unknown = SecretKey::generate(rand::thread_rng()).public();
+            let next_id = client_a.send_acked(unknown, msg.clone()).await?;
+            assert_ne!(next_id, id);
+            let ack = client_a.next().await.context("eos")??;
+            assert!(
+                matches!(ack, ReceivedMessage::SendAck { id: acked, status: SendStatus::NodeUnknown } if acked == next_id),
+                "{ack:?}"
+            );
+
+            // Without requesting acknowledgements acknowledged sends fail.
+            assert!(client_b.send_acked(key_a.public(), msg).await.is_err());
+
+            client_a.close().await?;
+        }
+
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_queue_status() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap()).spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        let key_a = SecretKey::generate(rand::thread_rng());
+        let key_b = SecretKey::generate(rand::thread_rng());
+        let mut clients = Vec::new();
+        for (key, queue_status) in [(&key_a, true), (&key_b, false)] {
+            let builder = ClientBuilder::new(relay_url.clone(), key.clone(), DnsResolver::new())
+                .send_queue_status(queue_status);
+            clients.push(connect_acked(builder).await?);
+        }
+        let [client_a, mut client_b] = clients.try_into().expect("two clients");
+        let (mut stream_a, mut sink_a) = client_a.split();
+        let (status_s, mut status_r) = tokio::sync::mpsc::channel(16);
+        let reader = tokio::spawn(async move {
+            while let Some(Ok(msg)) = stream_a.next().await {
+                if let ReceivedMessage::SendQueueStatus { dst, ready } = msg {
+                    status_s.send((dst, ready)).await.ok();
+                }
+            }
+        });
+
+        // Client b does not read, so its send queue fills up once its socket blocks.
+        let packet = Bytes::from(vec![0u8; 60 * 1024]);
+        let dst = key_b.public();
+        let mut sent = 0;
+        while sink_a.is_send_ready(&dst) {
+            assert!(sent < DEFAULT_SEND_QUEUE_DEPTH * 8, "never congested");
+            sink_a
+                .send(SendMessage::SendPacket(dst, packet.clone()))
+                .await?;
+            sent += 1;
+            tokio::task::yield_now().await;
+        }
+        let status = status_r.recv().await.context("no status")?;
+        assert_eq!(status, (dst, false));
+
+        // Reading the queued packets lets the queue drain below the low watermark.
+        let status = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                tokio::select! {
+                    status = status_r.recv() => break status.context("no status"),
+                    received = client_b.next() => {
+                        let received = received.context("eos")??;
+                        assert!(matches!(received, ReceivedMessage::ReceivedPacket { .. }));
+                    }
+                }
+            }
+        })
+        .await??;
+        assert_eq!(status, (dst, true));
+        assert!(sink_a.is_send_ready(&dst));
+
+        sink_a.close().await?;
+        reader.await?;
+        client_b.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_mesh_key_trusted_clients() -> Result<()> {
+        let mesh_key = MeshKey::generate();
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .mesh_key(Some(mesh_key.clone()))
+            .spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        let key_a = SecretKey::generate(rand::thread_rng());
+        let mut client_a = ClientBuilder::new(relay_url.clone(), key_a.clone(), DnsResolver::new())
+            .connect()
+            .await?;
+
+        let key_watcher = SecretKey::generate(rand::thread_rng());
+        let mut watcher = connect_acked(
+            ClientBuilder::new(relay_url.clone(), key_watcher, DnsResolver::new())
+                .mesh_key(mesh_key),
+        )
+        .await?;
+        watcher.send(SendMessage::WatchConns).await?;
+        let present = watcher.next().await.context("eos")??;
+        assert!(
+            matches!(present, ReceivedMessage::PeerPresent(node_id) if node_id == key_a.public()),
+            "{present:?}"
+        );
+
+        // A client with another mesh key is not trusted, but still connects.
+        let key_untrusted = SecretKey::generate(rand::thread_rng());
+        let mut untrusted = connect_acked(
+            ClientBuilder::new(relay_url.clone(), key_untrusted.clone(), DnsResolver::new())
+                .mesh_key(MeshKey::generate()),
+        )
+        .await?;
+        assert!(untrusted.send(SendMessage::WatchConns).await.is_err());
+        let present = watcher.next().await.context("eos")??;
+        assert!(
+            matches!(present, ReceivedMessage::PeerPresent(node_id) if node_id == key_untrusted.public()),
+            "{present:?}"
+        );
+
+        // Trusted clients forward packets on behalf of other nodes.
+        let remote = SecretKey::generate(rand::thread_rng()).public();
+        let msg = Bytes::from_static(b"forwarded");
+        watcher
+            .send(SendMessage::ForwardPacket {
+                src: remote,
+                dst: key_a.public(),
+                hops: 1,
+                packet: msg.clone(),
+            })
+            .await?;
+        let received = client_a.next().await.context("eos")??;
+        assert!(
+            matches!(&received, ReceivedMessage::ReceivedPacket { remote_node_id, data } if *remote_node_id == remote && *data == msg),
+            "{received:?}"
+        );
+
+        client_a.close().await?;
+        let gone = watcher.next().await.context("eos")??;
+        assert!(
+            matches!(gone, ReceivedMessage::NodeGone(node_id) if node_id == key_a.public()),
+            "{gone:?}"
+        );
+
+        untrusted.close().await?;
+        watcher.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_watch_conns_more_than_queued() -> Result<()> {
+        let mesh_key = MeshKey::generate();
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .mesh_key(Some(mesh_key.clone()))
+            .send_queue(SendQueueConfig {
+                depth: 2,
+                ..Default::default()
+            })
+            .spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        let mut clients = Vec::new();
+        let mut keys = BTreeSet::new();
+        for _ in 0..5 {
+            let key = SecretKey::generate(rand::thread_rng());
+            keys.insert(key.public());
+            // Make sure the client is registered before watching.
+            let builder = ClientBuilder::new(relay_url.clone(), key, DnsResolver::new());
+            clients.push(connect_acked(builder).await?);
+        }
+
+        let key_watcher = SecretKey::generate(rand::thread_rng());
+        let mut watcher = connect_acked(
+            ClientBuilder::new(relay_url, key_watcher, DnsResolver::new()).mesh_key(mesh_key),
+        )
+        .await?;
+        watcher.send(SendMessage::WatchConns).await?;
+        // All connected clients are reported, even more than fit into the queue.
+        let mut present = BTreeSet::new();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while present.len() < keys.len() {
+                match watcher.next().await.context("eos")?? {
+                    ReceivedMessage::PeerPresent(node_id) => {
+                        present.insert(node_id);
+                    }
+                    msg => trace!(?msg, "ignoring message"),
+                }
+            }
+            anyhow::Ok(())
+        })
+        .await??;
+        assert_eq!(present, keys);
+
+        for mut client in clients {
+            client.close().await?;
+        }
+        watcher.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_client_authorizer() -> Result<()> {
+        /// Accepts only the allowed node, with a rate limit, from local HTTP upgrades.
+        #[derive(derive_more::Debug)]
+        struct Authorizer {
+            allowed: NodeId,
+        }
+
+        impl ClientAuthorizer for Authorizer {
+            fn authorize(
+                &self,
+                node_id: NodeId,
+                remote_addr: SocketAddr,
+                headers: &HeaderMap,
+            ) -> n0_future::future::Boxed<Decision> {
+                let decision = if !remote_addr.ip().is_loopback() || !headers.contains_key(UPGRADE)
+                {
+                    Decision::Reject {
+                        reason: "unexpected request".to_string(),
+                    }
+                } else if node_id == self.allowed {
+                    Decision::AcceptWithRateLimit(ClientRateLimit {
+                        bytes_per_second: 1_000_000.try_into().unwrap(),
+                        max_burst_bytes: None,
+                    })
+                } else {
+                    Decision::Reject {
+                        reason: "no token".to_string(),
+                    }
+                };
+                Box::pin(async move { decision })
+            }
+        }
+
+        let key_a = SecretKey::generate(rand::thread_rng());
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .authorizer(Some(Arc::new(Authorizer {
+                allowed: key_a.public(),
+            })))
+            .spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        let mut client_a = ClientBuilder::new(relay_url.clone(), key_a, DnsResolver::new())
+            .connect()
+            .await?;
+        client_a.send(SendMessage::Ping([1u8; 8])).await?;
+        let pong = client_a.next().await.context("eos")??;
+        assert!(matches!(pong, ReceivedMessage::Pong(data) if data == [1u8; 8]));
+
+        let key_b = SecretKey::generate(rand::thread_rng());
+        let mut client_b = ClientBuilder::new(relay_url, key_b, DnsResolver::new())
+            .connect()
+            .await?;
+        let health = client_b.next().await.context("eos")??;
+        assert!(
+            matches!(&health, ReceivedMessage::Health { problem } if problem.as_deref() == Some("no token")),
+            "{health:?}"
+        );
+        let err = client_b.next().await.context("eos")?.unwrap_err();
+        let rejected = err
+            .downcast_ref::<ConnectionRejected>()
+            .expect("rejection error");
+        assert_eq!(
+            rejected.reason(),
+            &RejectReason::Unauthorized {
+                reason: "no token".to_string()
+            }
+        );
+
+        client_a.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_client_authorizer_token() -> Result<()> {
+        /// Accepts clients presenting the token, never decides for the blocked node.
+        #[derive(derive_more::Debug)]
+        struct Authorizer {
+            blocked: NodeId,
+        }
+
+        impl ClientAuthorizer for Authorizer {
+            fn authorize(
+                &self,
+                node_id: NodeId,
+                _remote_addr: SocketAddr,
+                headers: &HeaderMap,
+            ) -> n0_future::future::Boxed<Decision> {
+                if node_id == self.blocked {
+                    return Box::pin(std::future::pending());
+                }
+                let decision = match headers.get(AUTHORIZATION) {
+                    Some(value) if value == "Bearer secret" => Decision::Accept,
+                    _ => Decision::Reject {
+                        reason: "no token".to_string(),
+                    },
+                };
+                Box::pin(async move { decision })
+            }
+        }
+
+        // Direct framing carries no HTTP headers, the token comes with the handshake.
+        let blocked = SecretKey::generate(rand::thread_rng());
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .tls_config(Some(make_tls_config_with_alpns(vec![RELAY_ALPN.to_vec()])))
+            .authorizer(Some(Arc::new(Authorizer {
+                blocked: blocked.public(),
+            })))
+            .spawn()?;
+        let relay_url: Url = format!("https://localhost:{}", server.addr().port()).parse()?;
+        let builder = |key: SecretKey| {
+            ClientBuilder::new(relay_url.clone(), key, DnsResolver::new())
+                .insecure_skip_cert_verify(true)
+        };
+
+        let mut client_a = builder(SecretKey::generate(rand::thread_rng()))
+            .auth_token("secret")
+            .connect()
+            .await?;
+        client_a.send(SendMessage::Ping([1u8; 8])).await?;
+        let pong = client_a.next().await.context("eos")??;
+        assert!(matches!(pong, ReceivedMessage::Pong(data) if data == [1u8; 8]));
+        assert!(logs_contain("serving relay client with direct framing"));
+
+        let mut client_b = builder(SecretKey::generate(rand::thread_rng()))
+            .connect()
+            .await?;
+        let health = client_b.next().await.context("eos")??;
+        assert!(
+            matches!(&health, ReceivedMessage::Health { problem } if problem.as_deref() == Some("no token")),
+            "{health:?}"
+        );
+
+        // Authorizers which never decide are given up on.
+        let (client, server_io) = tokio::io::duplex(1024);
+        let service = server.handle().service.clone();
+        let accept = tokio::spawn(async move {
+            service
+                .0
+                .accept(
+                    Protocol::Relay,
+                    MaybeTlsStream::Test(server_io),
+                    test_request(),
+                )
+                .await
+        });
+        let _client = make_test_client(client, &blocked).await?;
+        tokio::time::pause();
+        let err = accept.await?.unwrap_err();
+        assert!(format!("{err:#}").contains("timed out"), "{err:#}");
+
+        client_a.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_client_certificates() -> Result<()> {
+        use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+        use crate::server::{ClientAuthConfig, ClientIdentity, TlsPolicy};
+
+        /// Accepts only clients with the provisioned certificate.
+        #[derive(derive_more::Debug)]
+        struct Authorizer {
+            #[debug(skip)]
+            provisioned: rustls::pki_types::CertificateDer<'static>,
+        }
+
+        impl ClientAuthorizer for Authorizer {
+            fn authorize(
+                &self,
+                _node_id: NodeId,
+                _remote_addr: SocketAddr,
+                _headers: &HeaderMap,
+            ) -> n0_future::future::Boxed<Decision> {
+                Box::pin(async move {
+                    Decision::Reject {
+                        reason: "no certificate".to_string(),
+                    }
+                })
+            }
+
+            fn authorize_certified(
+                &self,
+                _node_id: NodeId,
+                _remote_addr: SocketAddr,
+                _headers: &HeaderMap,
+                identity: &ClientIdentity,
+            ) -> n0_future::future::Boxed<Decision> {
+                let decision = if identity.end_entity == self.provisioned {
+                    Decision::Accept
+                } else {
+                    Decision::Reject {
+                        reason: "unknown device".to_string(),
+                    }
+                };
+                Box::pin(async move { decision })
+            }
+        }
+
+        let mut ca_params = CertificateParams::new(Vec::new())?;
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate()?;
+        let ca = ca_params.self_signed(&ca_key)?;
+        let server_key = KeyPair::generate()?;
+        let server_cert = CertificateParams::new(vec!["localhost".to_string()])?.signed_by(
+            &server_key,
+            &ca,
+            &ca_key,
+        )?;
+        let client_key = KeyPair::generate()?;
+        let client_cert = CertificateParams::new(vec!["device".to_string()])?.signed_by(
+            &client_key,
+            &ca,
+            &ca_key,
+        )?;
+
+        let provider = rustls::crypto::ring::default_provider();
+        let client_auth = ClientAuthConfig {
+            roots: vec![ca.der().clone()],
+        };
+        let config = TlsPolicy::default()
+            .mutual_server_config_builder(provider.clone(), &client_auth)?
+            .with_single_cert(
+                vec![server_cert.der().clone()],
+                rustls::pki_types::PrivatePkcs8KeyDer::from(server_key.serialize_der()).into(),
+            )?;
+        let config = Arc::new(config);
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .tls_config(Some(TlsConfig {
+                config: config.clone(),
+                acceptor: TlsAcceptor::ManualMutual(config.into()),
+            }))
+            .authorizer(Some(Arc::new(Authorizer {
+                provisioned: client_cert.der().clone(),
+            })))
+            .spawn()?;
+        assert!(server.handle().requires_client_certificate());
+        let url: Url = format!("https://localhost:{}", server.addr().port()).parse()?;
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca.der().clone())?;
+        let client_config = |cert: Option<(&rcgen::Certificate, &KeyPair)>| {
+            let builder = rustls::ClientConfig::builder_with_provider(Arc::new(provider.clone()))
+                .with_safe_default_protocol_versions()
+                .expect("protocols supported by ring")
+                .with_root_certificates(roots.clone());
+            let config = match cert {
+                Some((cert, key)) => builder
+                    .with_client_auth_cert(
+                        vec![cert.der().clone()],
+                        rustls::pki_types::PrivatePkcs8KeyDer::from(key.serialize_der()).into(),
+                    )
+                    .expect("valid client certificate"),
+                None => builder.with_no_client_auth(),
+            };
+            Arc::new(config)
+        };
+
+        // The provisioned certificate is accepted.
+        let key = SecretKey::generate(rand::thread_rng());
+        let mut client = ClientBuilder::new(url.clone(), key, DnsResolver::new())
+            .rustls_config(client_config(Some((&client_cert, &client_key))))
+            .connect()
+            .await?;
+        client.send(SendMessage::Ping([1u8; 8])).await?;
+        let pong = client.next().await.context("eos")??;
+        assert!(matches!(pong, ReceivedMessage::Pong(data) if data == [1u8; 8]));
+
+        // Clients without a certificate are not served.
+        let key = SecretKey::generate(rand::thread_rng());
+        let res = ClientBuilder::new(url.clone(), key, DnsResolver::new())
+            .rustls_config(client_config(None))
+            .connect()
+            .await;
+        assert!(res.is_err());
+
+        // Other certificates of the authority are up to the authorizer.
+        let other_key = KeyPair::generate()?;
+        let other_cert = CertificateParams::new(vec!["other".to_string()])?
+            .signed_by(&other_key, &ca, &ca_key)?;
+        let key = SecretKey::generate(rand::thread_rng());
+        let mut other = ClientBuilder::new(url, key, DnsResolver::new())
+            .rustls_config(client_config(Some((&other_cert, &other_key))))
+            .connect()
+            .await?;
+        let health = other.next().await.context("eos")??;
+        assert!(
+            matches!(&health, ReceivedMessage::Health { problem } if problem.as_deref() == Some("unknown device")),
+            "{health:?}"
+        );
+
+        client.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_client_ip_limit() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .client_ip_limit(Some(ClientIpLimit {
+                max_clients: 1.try_into()?,
+                exempt: Vec::new(),
+            }))
+            .spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        let key_a = SecretKey::generate(rand::thread_rng());
+        let client_a = connect_acked(ClientBuilder::new(
+            relay_url.clone(),
+            key_a.clone(),
+            DnsResolver::new(),
+        ))
+        .await?;
+        // The same node reconnecting replaces its previous connection.
+        let mut client_a = {
+            let reconnected = connect_acked(ClientBuilder::new(
+                relay_url.clone(),
+                key_a.clone(),
+                DnsResolver::new(),
+            ))
+            .await?;
+            drop(client_a);
+            reconnected
+        };
+
+        let key_b = SecretKey::generate(rand::thread_rng());
+        let mut client_b = ClientBuilder::new(relay_url.clone(), key_b.clone(), DnsResolver::new())
+            .connect()
+            .await?;
+        let health = client_b.next().await.context("eos")??;
+        assert!(
+            matches!(health, ReceivedMessage::Health { .. }),
+            "{health:?}"
+        );
+        let err = client_b.next().await.context("eos")?.unwrap_err();
+        let rejected = err
+            .downcast_ref::<ConnectionRejected>()
+            .expect("rejection error");
+        assert_eq!(rejected.reason(), &RejectReason::TooManyClients);
+
+        // The slot is free once the first client disconnected.
+        client_a.close().await?;
+        let mut client_b = connect_acked(ClientBuilder::new(
+            relay_url.clone(),
+            key_b.clone(),
+            DnsResolver::new(),
+        ))
+        .await?;
+
+        client_b.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_keep_alive_negotiation() -> Result<()> {
+        let keep_alive = KeepAliveConfig {
+            default_interval: Duration::from_millis(150),
+            min_interval: Duration::from_millis(100),
+            max_interval: Duration::from_millis(200),
+        };
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .keep_alive(Some(keep_alive))
+            .spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        // The requested interval is clamped to the bounds of the server.
+        let key = SecretKey::generate(rand::thread_rng());
+        let mut client = ClientBuilder::new(relay_url.clone(), key, DnsResolver::new())
+            .keep_alive_interval(Duration::from_secs(60))
+            .connect()
+            .await?;
+        let negotiated = client.next().await.context("eos")??;
+        let ReceivedMessage::KeepAliveNegotiated(negotiated) = negotiated else {
+            bail!("expected the negotiated keep-alive, got {negotiated:?}");
+        };
+        assert_eq!(
+            negotiated,
+            NegotiatedKeepAlive {
+                interval: Duration::from_millis(200),
+                min_interval: Duration::from_millis(100),
+                max_interval: Duration::from_millis(200),
+            }
+        );
+        // The server pings at the picked interval.
+        let ping = tokio::time::timeout(Duration::from_secs(2), client.next())
+            .await?
+            .context("eos")??;
+        assert!(matches!(ping, ReceivedMessage::Ping(_)), "{ping:?}");
+
+        // Clients not requesting an interval are not told about it.
+        let key = SecretKey::generate(rand::thread_rng());
+        let mut other = ClientBuilder::new(relay_url, key, DnsResolver::new())
+            .connect()
+            .await?;
+        other.send(SendMessage::Ping([1u8; 8])).await?;
+        let pong = other.next().await.context("eos")??;
+        assert!(
+            matches!(pong, ReceivedMessage::Pong(data) if data == [1u8; 8]),
+            "{pong:?}"
+        );
+
+        client.close().await?;
+        other.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_session_resumption() -> Result<()> {
+        let grace_period = Duration::from_millis(300);
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .sessions(Some(SessionConfig { grace_period }))
+            .spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        let key_peer = SecretKey::generate(rand::thread_rng());
+        let mut peer = connect_acked(ClientBuilder::new(
+            relay_url.clone(),
+            key_peer.clone(),
+            DnsResolver::new(),
+        ))
+        .await?;
+
+        let key_a = SecretKey::generate(rand::thread_rng());
+        let builder_a = ClientBuilder::new(relay_url, key_a.clone(), DnsResolver::new())
+            .session_resumption(true);
+        let mut client_a = connect_acked(builder_a.clone()).await?;
+        let msg = Bytes::from_static(b"hello");
+        client_a
+            .send(SendMessage::SendPacket(key_peer.public(), msg.clone()))
+            .await?;
+        let received = peer.next().await.context("eos")??;
+        assert!(
+            matches!(&received, ReceivedMessage::ReceivedPacket { remote_node_id, data } if *remote_node_id == key_a.public() && *data == msg),
+            "{received:?}"
+        );
+
+        // Reconnecting within the grace period hides the broken connection from the peer.
+        drop(client_a);
+        let client_a = connect_acked(builder_a.clone()).await?;
+        let msg = tokio::time::timeout(grace_period * 2, peer.next()).await;
+        assert!(msg.is_err(), "{msg:?}");
+
+        // Without reconnecting, the peer learns that the client is gone after the grace
+        // period.
+        drop(client_a);
+        let gone = tokio::time::timeout(grace_period * 2, peer.next())
+            .await?
+            .context("eos")??;
+        assert!(
+            matches!(gone, ReceivedMessage::NodeGone(node_id) if node_id == key_a.public()),
+            "{gone:?}"
+        );
+
+        peer.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_session_resumption_authorized() -> Result<()> {
+        /// Accepts clients until they are revoked.
+        #[derive(derive_more::Debug, Default)]
+        struct Authorizer {
+            revoked: AtomicBool,
+        }
+
+        impl ClientAuthorizer for Authorizer {
+            fn authorize(
+                &self,
+                _node_id: NodeId,
+                _remote_addr: SocketAddr,
+                _headers: &HeaderMap,
+            ) -> n0_future::future::Boxed<Decision> {
+                let decision = if self.revoked.load(Ordering::Relaxed) {
+                    Decision::Reject {
+                        reason: "revoked".to_string(),
+                    }
+                } else {
+                    Decision::Accept
+                };
+                Box::pin(async move { decision })
+            }
+        }
+
+        let authorizer = Arc::new(Authorizer::default());
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .sessions(Some(SessionConfig::default()))
+            .authorizer(Some(authorizer.clone()))
+            .spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+        let builder = ClientBuilder::new(
+            relay_url,
+            SecretKey::generate(rand::thread_rng()),
+            DnsResolver::new(),
+        )
+        .session_resumption(true);
+
+        let client = connect_acked(builder.clone()).await?;
+        drop(client);
+        let client = connect_acked(builder.clone()).await?;
+        assert!(logs_contain("accept: resumed session"));
+
+        // A revoked client cannot resume its session.
+        authorizer.revoked.store(true, Ordering::Relaxed);
+        drop(client);
+        let mut client = builder.connect().await?;
+        let health = client.next().await.context("eos")??;
+        assert!(
+            matches!(&health, ReceivedMessage::Health { problem } if problem.as_deref() == Some("revoked")),
+            "{health:?}"
+        );
+
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_unsupported_version_keeps_session() -> Result<()> {
+        let service = RelayService::builder(
+            Default::default(),
+            Default::default(),
+            None,
+            KeyCache::test(),
+            AccessConfig::Everyone,
+            None,
+            None,
+        )
+        .with_clients(None, Some(SessionConfig::default()), None)
+        .build();
+        let key = SecretKey::generate(rand::thread_rng());
+        let token = service
+            .0
+            .clients
+            .start_session(key.public(), None)
+            .context("sessions")?;
+
+        let (client, server_io) = tokio::io::duplex(1024);
+        let s = service.clone();
+        let accept = tokio::spawn(async move {
+            s.0.accept(
+                Protocol::Relay,
+                MaybeTlsStream::Test(server_io),
+                test_request(),
+            )
+            .await
+        });
+        let mut client = Framed::new(client, RelayCodec::new(KeyCache::test()));
+        let capabilities = ClientCapabilities {
+            sessions: true,
+            ..Default::default()
+        };
+        send_client_key(
+            (&mut client).sink_map_err(ConnSendError::from),
+            &key,
+            &ClientInfo { version: 1 },
+            &capabilities,
+            None,
+        )
+        .await?;
+        let frame = client.next().await.context("eos")??;
+        assert!(
+            matches!(
+                frame,
+                Frame::Error {
+                    reason: RejectReason::VersionUnsupported { .. }
+                }
+            ),
+            "{frame:?}"
+        );
+        assert!(accept.await?.is_err());
+
+        // The rejected client did not replace the session.
+        assert!(service
+            .0
+            .clients
+            .resume_session(key.public(), token)
+            .is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_key_rotation() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap()).spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        let key_peer = SecretKey::generate(rand::thread_rng());
+        let mut peer = connect_acked(
+            ClientBuilder::new(relay_url.clone(), key_peer.clone(), DnsResolver::new())
+                .send_acks(true),
+        )
+        .await?;
+
+        let previous_key = SecretKey::generate(rand::thread_rng());
+        let key_rotated = SecretKey::generate(rand::thread_rng());
+        let mut rotated = connect_acked(
+            ClientBuilder::new(relay_url.clone(), key_rotated.clone(), DnsResolver::new())
+                .key_rotation(
+                    previous_key.clone(),
+                    SystemTime::now() + Duration::from_secs(3600),
+                ),
+        )
+        .await?;
+
+        // Packets to the previous key reach the rotated client.
+        let msg = Bytes::from_static(b"hello");
+        let id = peer.send_acked(previous_key.public(), msg.clone()).await?;
+        let ack = peer.next().await.context("eos")??;
+        assert!(
+            matches!(ack, ReceivedMessage::SendAck { id: acked, status: SendStatus::Queued } if acked == id),
+            "{ack:?}"
+        );
+        let received = rotated.next().await.context("eos")??;
+        assert!(
+            matches!(&received, ReceivedMessage::ReceivedPacket { remote_node_id, data } if *remote_node_id == key_peer.public() && *data == msg),
+            "{received:?}"
+        );
+
+        // Once the rotated client is gone, so are both of its keys.
+        rotated
+            .send(SendMessage::SendPacket(key_peer.public(), msg.clone()))
+            .await?;
+        peer.next().await.context("eos")??;
+        rotated.close().await?;
+        let mut gone = Vec::new();
+        for _ in 0..2 {
+            match peer.next().await.context("eos")?? {
+                ReceivedMessage::NodeGone(node_id) => gone.push(node_id),
+                msg => bail!("unexpected message {msg:?}"),
+            }
+        }
+        gone.sort();
+        let mut expected = vec![key_rotated.public(), previous_key.public()];
+        expected.sort();
+        assert_eq!(gone, expected);
+
+        // Expired rotations are ignored.
+        let previous_key = SecretKey::generate(rand::thread_rng());
+        let mut expired = connect_acked(
+            ClientBuilder::new(
+                relay_url.clone(),
+                SecretKey::generate(rand::thread_rng()),
+                DnsResolver::new(),
+            )
+            .key_rotation(previous_key.clone(), SystemTime::UNIX_EPOCH),
+        )
+        .await?;
+        let id = peer.send_acked(previous_key.public(), msg).await?;
+        let ack = peer.next().await.context("eos")??;
+        assert!(
+            matches!(ack, ReceivedMessage::SendAck { id: acked, status: SendStatus::NodeUnknown } if acked == id),
+            "{ack:?}"
+        );
+
+        expired.close().await?;
+        peer.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation_window() {
+        let service = RelayService::builder(
+            Default::default(),
+            Default::default(),
+            None,
+            KeyCache::test(),
+            AccessConfig::Everyone,
+            None,
+            None,
+        )
+        .build();
+        let previous_key = SecretKey::generate(rand::thread_rng());
+        let key = SecretKey::generate(rand::thread_rng()).public();
+        let window = MAX_KEY_ROTATION_WINDOW.as_secs();
+
+        // Short windows are kept as they are.
+        let expires = unix_secs() + 60;
+        let rotation = KeyRotation::new(&previous_key, &key, expires);
+        let verified = service.0.verify_key_rotation(key, rotation).await.unwrap();
+        assert_eq!(verified.expires, expires);
+
+        // Longer ones are capped.
+        let start = unix_secs();
+        let rotation = KeyRotation::new(&previous_key, &key, u64::MAX);
+        let verified = service.0.verify_key_rotation(key, rotation).await.unwrap();
+        assert!(verified.expires >= start + window);
+        assert!(verified.expires <= unix_secs() + window);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_handshake_limit() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .handshake_limit(Some(HandshakeLimit {
+                max_concurrent: 1.try_into()?,
+                queue_timeout: Duration::from_millis(200),
+            }))
+            .spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        // Upgrade a connection, but never send the client key, keeping the only slot.
+        let mut stalled = TcpStream::connect(server.addr()).await?;
+        let request = format!(
+            "GET {RELAY_PATH} HTTP/1.1\r\nHost: localhost\r\nUpgrade: {}\r\nConnection: Upgrade\r\n\r\n",
+            Protocol::Relay.upgrade_header()
+        );
+        stalled.write_all(request.as_bytes()).await?;
+        let mut buf = [0u8; 1024];
+        let len = stalled.read(&mut buf).await?;
+        assert!(String::from_utf8_lossy(&buf[..len]).contains("101"));
+
+        async fn ping(client: &mut Client) -> Result<()> {
+            client.send(SendMessage::Ping([1u8; 8])).await?;
+            match tokio::time::timeout(Duration::from_secs(5), client.next()).await? {
+                Some(Ok(ReceivedMessage::Pong(_))) => Ok(()),
+                msg => bail!("no pong: {msg:?}"),
+            }
+        }
+
+        // The next handshake times out in the queue and the connection is closed.
+        let key = SecretKey::generate(rand::thread_rng());
+        let builder = ClientBuilder::new(relay_url, key, DnsResolver::new());
+        if let Ok(mut client) = builder.clone().connect().await {
+            assert!(ping(&mut client).await.is_err());
+        }
+        assert!(logs_contain("too many concurrent handshakes"));
+
+        // The slot is freed once the stalled connection is gone.
+        drop(stalled);
+        let mut client = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(client) = builder.clone().connect().await {
+                    return client;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await?;
+        ping(&mut client).await?;
+
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_connection_limit() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .connection_limit(ConnectionLimit {
+                max_total: None,
+                max_per_ip: Some(1.try_into()?),
+            })
+            .spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        // The upgraded relay connection keeps the only slot of the address.
+        let key = SecretKey::generate(rand::thread_rng());
+        let mut client = ClientBuilder::new(relay_url, key, DnsResolver::new())
+            .connect()
+            .await?;
+        client.send(SendMessage::Ping([1u8; 8])).await?;
+        match tokio::time::timeout(Duration::from_secs(5), client.next()).await? {
+            Some(Ok(ReceivedMessage::Pong(_))) => {}
+            msg => bail!("no pong: {msg:?}"),
+        }
+
+        async fn get_root(addr: SocketAddr) -> Result<String> {
+            let mut stream = TcpStream::connect(addr).await?;
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await?;
+            let mut response = Vec::new();
+            tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+                .await??;
+            Ok(String::from_utf8_lossy(&response).into_owned())
+        }
+
+        // Further connections from the address are closed without a response.
+        assert_eq!(get_root(server.addr()).await.unwrap_or_default(), "");
+        assert!(logs_contain("too many connections from 127.0.0.1"));
+
+        // The slot is freed once the relay connection is gone.
+        drop(client);
+        let response = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match get_root(server.addr()).await {
+                    Ok(response) if !response.is_empty() => return response,
+                    _ => tokio::time::sleep(Duration::from_millis(50)).await,
+                }
+            }
+        })
+        .await?;
+        assert!(response.starts_with("HTTP/1.1 404"));
+
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_proxy_protocol() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .proxy_protocol(Some(vec!["127.0.0.0/8".parse()?]))
+            .connection_limit(ConnectionLimit {
+                max_total: Some(3.try_into()?),
+                max_per_ip: Some(1.try_into()?),
+            })
+            .spawn()?;
+
+        async fn get_root(addr: SocketAddr, header: &[u8]) -> Result<String> {
+            let mut stream = TcpStream::connect(addr).await?;
+            stream.write_all(header).await?;
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await?;
+            let mut response = Vec::new();
+            tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+                .await??;
+            Ok(String::from_utf8_lossy(&response).into_owned())
+        }
+
+        let response = get_root(server.addr(), b"PROXY UNKNOWN\r\n").await?;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+        assert_eq!(get_root(server.addr(), b"").await.unwrap_or_default(), "");
+        assert!(logs_contain("invalid PROXY protocol header"));
+
+        // The connections are limited by the client address of the header.
+        let header = b"PROXY TCP4 192.0.2.1 127.0.0.1 4000 443\r\n";
+        let mut open = TcpStream::connect(server.addr()).await?;
+        open.write_all(header).await?;
+        let response = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let response = get_root(server.addr(), header).await;
+                match response.unwrap_or_default() {
+                    response if response.is_empty() => return response,
+                    _ => tokio::time::sleep(Duration::from_millis(50)).await,
+                }
+            }
+        })
+        .await?;
+        assert_eq!(response, "");
+        assert!(logs_contain("too many connections from 192.0.2.1"));
+        let response = get_root(
+            server.addr(),
+            b"PROXY TCP4 192.0.2.2 127.0.0.1 4000 443\r\n",
+        )
+        .await?;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+
+        // Connections count towards the total limit before they sent their header.
+        let _pending = TcpStream::connect(server.addr()).await?;
+        let _pending2 = TcpStream::connect(server.addr()).await?;
+        let header = b"PROXY TCP4 192.0.2.3 127.0.0.1 4000 443\r\n";
+        let response = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let response = get_root(server.addr(), header).await;
+                match response.unwrap_or_default() {
+                    response if response.is_empty() => return response,
+                    _ => tokio::time::sleep(Duration::from_millis(50)).await,
+                }
+            }
+        })
+        .await?;
+        assert_eq!(response, "");
+        assert!(!logs_contain("too many connections from 192.0.2.3"));
+        drop(open);
+        server.shutdown();
+        server.task_handle().await?;
+
+        // Only the trusted proxies may send headers.
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .proxy_protocol(Some(vec!["192.0.2.0/24".parse()?]))
+            .spawn()?;
+        assert_eq!(
+            get_root(server.addr(), b"PROXY UNKNOWN\r\n")
+                .await
+                .unwrap_or_default(),
+            ""
+        );
+        assert!(logs_contain("not a trusted proxy"));
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_access_log() -> Result<()> {
+        let (records_tx, mut records) = tokio::sync::mpsc::channel(16);
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .access_log(Some(AccessLog::Channel(records_tx)))
+            .spawn()?;
+        let url: Url = format!("http://127.0.0.1:{}", server.addr().port()).parse()?;
+
+        let response = reqwest::get(url.join("/missing")?).await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let record = records.recv().await.context("no record")?;
+        assert_eq!(record.method, Method::GET);
+        assert_eq!(record.path, "/missing");
+        assert_eq!(record.status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            record.peer.ip(),
+            IpAddr::from(std::net::Ipv4Addr::LOCALHOST)
+        );
+        assert_eq!(record.node_id, None);
+        assert_eq!(record.bytes, b"Not Found".len() as u64);
+
+        // The upgrade is recorded once the client is registered, with its node ID.
+        let (node_id, mut client) =
+            create_test_client(SecretKey::generate(rand::thread_rng()), url).await?;
+        let record = tokio::time::timeout(Duration::from_secs(5), records.recv())
+            .await?
+            .context("no record")?;
+        assert_eq!(record.path, RELAY_PATH);
+        assert_eq!(record.status, StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(record.node_id, Some(node_id));
+        assert!(records.try_recv().is_err());
+
+        client.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_access_log_direct_framing() -> Result<()> {
+        let (records_tx, mut records) = tokio::sync::mpsc::channel(16);
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .tls_config(Some(make_tls_config_with_alpns(vec![RELAY_ALPN.to_vec()])))
+            .access_log(Some(AccessLog::Channel(records_tx)))
+            .spawn()?;
+        let relay_url: Url = format!("https://localhost:{}", server.addr().port()).parse()?;
+
+        let key = SecretKey::generate(rand::thread_rng());
+        let mut client = ClientBuilder::new(relay_url, key.clone(), DnsResolver::new())
+            .insecure_skip_cert_verify(true)
+            .connect()
+            .await?;
+        let record = tokio::time::timeout(Duration::from_secs(5), records.recv())
+            .await?
+            .context("no record")?;
+        assert!(logs_contain("serving relay client with direct framing"));
+        assert_eq!(record.method, Method::CONNECT);
+        assert_eq!(record.path, RELAY_PATH);
+        assert_eq!(record.status, StatusCode::OK);
+        assert_eq!(record.node_id, Some(key.public()));
+
+        client.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_connection_limiter() {
+        let limiter = ConnectionLimiter::new(ConnectionLimit {
+            max_total: Some(3.try_into().unwrap()),
+            max_per_ip: Some(2.try_into().unwrap()),
+        })
+        .unwrap();
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let a1 = limiter.acquire(a).unwrap();
+        let a2 = limiter.acquire(a).unwrap();
+        assert!(limiter.acquire(a).is_err());
+        let _b1 = limiter.acquire(b).unwrap();
+        assert!(limiter.acquire(b).is_err(), "total limit");
+
+        drop(a1);
+        let _b2 = limiter.acquire(b).unwrap();
+        assert_eq!(limiter.0.counts.lock().unwrap().per_ip[&b], 2);
+
+        // Pending connections count towards the total, and the limit per IP once assigned.
+        drop(a2);
+        let mut pending = limiter.acquire_pending().unwrap();
+        assert!(limiter.acquire_pending().is_err(), "total limit");
+        assert!(pending.assign(b).is_err());
+        drop(pending);
+        let mut pending = limiter.acquire_pending().unwrap();
+        pending.assign(a).unwrap();
+        assert_eq!(limiter.0.counts.lock().unwrap().per_ip[&a], 1);
+        drop(pending);
+        assert!(!limiter.0.counts.lock().unwrap().per_ip.contains_key(&a));
+
+        assert!(ConnectionLimiter::new(ConnectionLimit::default()).is_none());
+    }
+
+    /// Waits for the connectivity event, skipping the others.
+    async fn wait_for(
+        events: &mut n0_future::boxed::BoxStream<ConnectivityEvent>,
+        expected: ConnectivityEvent,
+    ) -> Result<()> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(event) = events.next().await {
+                info!(?event, "connectivity event");
+                if event == expected {
+                    return Ok(());
+                }
+            }
+            bail!("events ended");
+        })
+        .await?
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_connectivity_checker() -> Result<()> {
+        let faults = crate::faults::FaultConfig::default();
+        let handle = faults.handle.clone();
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .faults(faults)
+            .spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        let key = SecretKey::generate(rand::thread_rng());
+        let config = ConnectivityCheckConfig {
+            interval: Duration::from_millis(100),
+            timeout: Duration::from_millis(100),
+            max_failures: 2.try_into()?,
+            max_backoff: Duration::from_millis(400),
+        };
+        let mut client = ClientBuilder::new(relay_url, key, DnsResolver::new())
+            .connect_checked(config)
+            .await?;
+        let mut events = client.events();
+
+        // The server silently stops responding, like a middlebox dropping the connection.
+        handle.stall();
+        wait_for(&mut events, ConnectivityEvent::Degraded { failures: 1 }).await?;
+        wait_for(&mut events, ConnectivityEvent::Degraded { failures: 2 }).await?;
+
+        handle.resume();
+        wait_for(
+            &mut events,
+            ConnectivityEvent::Recovered { reconnected: true },
+        )
+        .await?;
+
+        // The new connection is used transparently.
+        client.send(SendMessage::Ping([7u8; 8])).await?;
+        let pong = tokio::time::timeout(Duration::from_secs(5), client.next())
+            .await?
+            .context("eos")?;
+        assert!(matches!(pong, ReceivedMessage::Pong(data) if data == [7u8; 8]));
+
+        client.close().await;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_connectivity_failover() -> Result<()> {
+        // The preferred server is not running yet.
+        let preferred_addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let preferred_url: RelayUrl = format!("http://{preferred_addr}").parse()?;
+        let mut fallback = ServerBuilder::new("127.0.0.1:0".parse().unwrap()).spawn()?;
+        let fallback_url: RelayUrl = format!("http://{}", fallback.addr()).parse()?;
+
+        let key = SecretKey::generate(rand::thread_rng());
+        let config = ConnectivityCheckConfig {
+            interval: Duration::from_millis(100),
+            timeout: Duration::from_millis(500),
+            max_failures: 2.try_into()?,
+            max_backoff: Duration::from_millis(200),
+        };
+        let mut client = ClientBuilder::new(preferred_url.clone(), key, DnsResolver::new())
+            .failover_urls([fallback_url.clone()])
+            .connect_checked(config)
+            .await?;
+        assert_eq!(client.relay_url(), fallback_url);
+        let mut events = client.events();
 
-        let cert = rcgen::generate_simple_self_signed(subject_alt_names).unwrap();
-        let rustls_certificate = cert.cert.der().clone();
-        let rustls_key = rustls::pki_types::PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
-        let config = rustls::ServerConfig::builder_with_provider(Arc::new(
-            rustls::crypto::ring::default_provider(),
-        ))
-        .with_safe_default_protocol_versions()
-        .expect("protocols supported by ring")
-        .with_no_client_auth()
-        .with_single_cert(vec![(rustls_certificate)], rustls_key.into())
-        .expect("cert is right");
+        // Once the preferred server is reachable the client switches back to it.
+        let mut preferred = ServerBuilder::new(preferred_addr).spawn()?;
+        wait_for(&mut events, ConnectivityEvent::Switched { index: 0 }).await?;
+        assert_eq!(client.relay_url(), preferred_url);
+        client.send(SendMessage::Ping([7u8; 8])).await?;
+        let pong = tokio::time::timeout(Duration::from_secs(5), client.next())
+            .await?
+            .context("eos")?;
+        assert!(matches!(pong, ReceivedMessage::Pong(data) if data == [7u8; 8]));
 
-        let config = Arc::new(config);
-        let acceptor = tokio_rustls::TlsAcceptor::from(config.clone());
+        // When the preferred server goes away, the client fails over again.
+        preferred.shutdown();
+        preferred.task_handle().await?;
+        wait_for(&mut events, ConnectivityEvent::Switched { index: 1 }).await?;
+        assert_eq!(client.relay_url(), fallback_url);
 
-        TlsConfig {
-            config,
-            acceptor: TlsAcceptor::Manual(acceptor),
-        }
+        client.close().await;
+        fallback.shutdown();
+        fallback.task_handle().await?;
+        Ok(())
     }
 
     #[tokio::test]
     #[traced_test]
-    async fn test_http_clients_and_server() -> Result<()> {
-        let a_key = SecretKey::generate(rand::thread_rng());
-        let b_key = SecretKey::generate(rand::thread_rng());
+    async fn test_dropped_frames() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap()).spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
 
-        // start server
-        let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
-            .spawn()
+        let key_a = SecretKey::generate(rand::thread_rng());
+        let key_b = SecretKey::generate(rand::thread_rng());
+        let mut client_a = ClientBuilder::new(relay_url.clone(), key_a.clone(), DnsResolver::new())
+            .connect()
             .await?;
+        let client_b = ClientBuilder::new(relay_url, key_b.clone(), DnsResolver::new())
+            .connect_checked(ConnectivityCheckConfig::default())
+            .await?;
+        let mut drops = client_b.dropped_frames().drops();
 
-        let addr = server.addr();
-
-        // get dial info
-        let port = addr.port();
-        let addr = {
-            if let std::net::IpAddr::V4(ipv4_addr) = addr.ip() {
-                ipv4_addr
-            } else {
-                anyhow::bail!("cannot get ipv4 addr from socket addr {addr:?}");
+        // Client b never polls its stream, once its queue is full packets are dropped.
+        let msg = Bytes::from_static(b"hello");
+        let drop = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                tokio::select! {
+                    biased;
+                    drop = drops.next() => return anyhow::Ok(drop.context("eos")?),
+                    res = client_a.send(SendMessage::SendPacket(key_b.public(), msg.clone())) => res?,
+                }
             }
-        };
-        info!("addr: {addr}:{port}");
-        let relay_addr: Url = format!("http://{addr}:{port}").parse().unwrap();
-
-        // create clients
-        let (a_key, mut client_a) = create_test_client(a_key, relay_addr.clone()).await?;
-        info!("created client {a_key:?}");
-        let (b_key, mut client_b) = create_test_client(b_key, relay_addr).await?;
-        info!("created client {b_key:?}");
+        })
+        .await??;
+        assert_eq!(drop.src, Some(key_a.public()));
+        assert_eq!(drop.reason, DropReason::QueueFull);
+        let dropped = client_b.dropped_frames();
+        assert!(dropped.count(DropReason::QueueFull) >= 1);
+        assert_eq!(dropped.by_sender()[0].0, key_a.public());
 
-        info!("ping a");
-        client_a.send(SendMessage::Ping([1u8; 8])).await?;
-        let pong = client_a.next().await.context("eos")??;
-        assert!(matches!(pong, ReceivedMessage::Pong(_)));
+        client_a.close().await?;
+        client_b.close().await;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
 
-        info!("ping b");
-        client_b.send(SendMessage::Ping([2u8; 8])).await?;
-        let pong = client_b.next().await.context("eos")??;
-        assert!(matches!(pong, ReceivedMessage::Pong(_)));
+    #[tokio::test]
+    #[traced_test]
+    async fn test_client_latency() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap()).spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
 
-        info!("sending message from a to b");
-        let msg = Bytes::from_static(b"hi there, client b!");
-        client_a
-            .send(SendMessage::SendPacket(b_key, msg.clone()))
+        let key_a = SecretKey::generate(rand::thread_rng());
+        let key_b = SecretKey::generate(rand::thread_rng());
+        let mut client_a = ClientBuilder::new(relay_url.clone(), key_a.clone(), DnsResolver::new())
+            .connect()
             .await?;
-        info!("waiting for message from a on b");
-        let (got_key, got_msg) =
-            process_msg(client_b.next().await).expect("expected message from client_a");
-        assert_eq!(a_key, got_key);
-        assert_eq!(msg, got_msg);
+        let mut client_b = ClientBuilder::new(relay_url, key_b, DnsResolver::new())
+            .latency_probing(Duration::from_millis(50))
+            .connect()
+            .await?;
+        let mut samples = client_a.latency_stream();
+        assert_eq!(client_a.last_latency(), None);
 
-        info!("sending message from b to a");
-        let msg = Bytes::from_static(b"right back at ya, client b!");
+        // Packets received while measuring are still delivered, the pong is not.
+        let msg = Bytes::from_static(b"hello");
         client_b
-            .send(SendMessage::SendPacket(a_key, msg.clone()))
+            .send(SendMessage::SendPacket(key_a.public(), msg.clone()))
             .await?;
-        info!("waiting for message b on a");
-        let (got_key, got_msg) =
-            process_msg(client_a.next().await).expect("expected message from client_b");
-        assert_eq!(b_key, got_key);
-        assert_eq!(msg, got_msg);
+        let rtt = tokio::time::timeout(Duration::from_secs(5), client_a.latency()).await??;
+        assert_eq!(client_a.last_latency(), Some(rtt));
+        assert_eq!(samples.next().await, Some(rtt));
+        let received = tokio::time::timeout(Duration::from_secs(5), client_a.next())
+            .await?
+            .context("eos")??;
+        assert!(
+            matches!(received, ReceivedMessage::ReceivedPacket { ref data, .. } if *data == msg),
+            "{received:?}"
+        );
+
+        // Split clients probe periodically, and on request while the stream is polled.
+        let mut samples = client_b.latency_stream();
+        let (mut stream, sink) = client_b.split();
+        let measure = async {
+            samples.next().await.context("no sample")?;
+            samples.next().await.context("no sample")?;
+            sink.latency().await
+        };
+        let rtt = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::select! {
+                rtt = measure => rtt,
+                msg = stream.next() => bail!("unexpected message {msg:?}"),
+            }
+        })
+        .await??;
+        assert!(stream.last_latency().is_some());
+        assert!(rtt < Duration::from_secs(5));
 
         client_a.close().await?;
-        client_b.close().await?;
         server.shutdown();
-
+        server.task_handle().await?;
+        // Measurements fail once the connection is closed.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while stream.next().await.is_some() {}
+        })
+        .await?;
+        assert!(sink.latency().await.is_err());
         Ok(())
     }
 
-    async fn create_test_client(key: SecretKey, server_url: Url) -> Result<(PublicKey, Client)> {
-        let public_key = key.public();
-        let client =
-            ClientBuilder::new(server_url, key, DnsResolver::new()).insecure_skip_cert_verify(true);
-        let client = client.connect().await?;
+    #[tokio::test]
+    #[traced_test]
+    async fn test_clock_skew() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap()).spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
 
-        Ok((public_key, client))
+        let key = SecretKey::generate(rand::thread_rng());
+        let mut client = ClientBuilder::new(relay_url, key, DnsResolver::new())
+            .connect()
+            .await?;
+        assert_eq!(client.clock_skew(), None);
+
+        // The capabilities with the server time are received before the pong.
+        tokio::time::timeout(Duration::from_secs(5), client.latency()).await??;
+        let skew = client.clock_skew().context("no clock skew")?;
+        // Both share the clock of the host.
+        assert!(!skew.exceeds(Duration::from_secs(1)), "{skew:?}");
+
+        let (stream, _sink) = client.split();
+        assert_eq!(stream.clock_skew(), Some(skew));
+
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
     }
 
-    fn process_msg(msg: Option<Result<ReceivedMessage>>) -> Option<(PublicKey, Bytes)> {
-        match msg {
-            Some(Err(e)) => {
-                info!("client `recv` error {e}");
-                None
-            }
-            Some(Ok(msg)) => {
-                info!("got message on: {msg:?}");
-                if let ReceivedMessage::ReceivedPacket {
-                    remote_node_id: source,
-                    data,
-                } = msg
-                {
-                    Some((source, data))
-                } else {
-                    None
-                }
+    #[tokio::test]
+    #[traced_test]
+    async fn test_multiplexed_channels() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap()).spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        let key_a = SecretKey::generate(rand::thread_rng());
+        let key_b = SecretKey::generate(rand::thread_rng());
+        let mut client_a = ClientBuilder::new(relay_url.clone(), key_a.clone(), DnsResolver::new())
+            .multiplexed_channels(true)
+            .connect()
+            .await?;
+        // Clients of protocol version 3 keep working.
+        let mut client_b = ClientBuilder::new(relay_url, key_b.clone(), DnsResolver::new())
+            .connect()
+            .await?;
+
+        // More than the initial window is received, as the client grants more credit.
+        let msg = Bytes::from(vec![42u8; 1000]);
+        let batches = 2 * CHANNEL_WINDOW as usize / (10 * msg.len());
+        for _ in 0..batches {
+            for _ in 0..10 {
+                client_b
+                    .send(SendMessage::SendPacket(key_a.public(), msg.clone()))
+                    .await?;
             }
-            None => {
-                info!("client end of stream");
-                None
+            for _ in 0..10 {
+                let received = tokio::time::timeout(Duration::from_secs(5), client_a.next())
+                    .await?
+                    .context("eos")??;
+                assert!(
+                    matches!(received, ReceivedMessage::ReceivedPacket { ref data, .. } if *data == msg),
+                    "{received:?}"
+                );
             }
         }
+
+        client_a
+            .send(SendMessage::SendPacket(key_b.public(), msg.clone()))
+            .await?;
+        let received = tokio::time::timeout(Duration::from_secs(5), client_b.next())
+            .await?
+            .context("eos")??;
+        assert!(
+            matches!(received, ReceivedMessage::ReceivedPacket { ref data, .. } if *data == msg),
+            "{received:?}"
+        );
+
+        client_a.close().await?;
+        client_b.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
     }
 
     #[tokio::test]
     #[traced_test]
-    async fn test_https_clients_and_server() -> Result<()> {
-        let a_key = SecretKey::generate(rand::thread_rng());
-        let b_key = SecretKey::generate(rand::thread_rng());
-
-        // create tls_config
-        let tls_config = make_tls_config();
+    #[cfg(feature = "payload-compression")]
+    async fn test_payload_compression() -> Result<()> {
+        use crate::protos::relay::PayloadCompression;
 
-        // start server
         let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
-            .tls_config(Some(tls_config))
-            .spawn()
+            .payload_compression(true)
+            .spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
+
+        async fn relay(from: &mut Client, to: &mut Client, to_key: PublicKey) -> Result<()> {
+            for msg in [
+                Bytes::from("relayed packets compress well ".repeat(150)),
+                Bytes::from_static(b"small"),
+            ] {
+                from.send(SendMessage::SendPacket(to_key, msg.clone()))
+                    .await?;
+                let received = tokio::time::timeout(Duration::from_secs(5), to.next())
+                    .await?
+                    .context("eos")??;
+                assert!(
+                    matches!(received, ReceivedMessage::ReceivedPacket { ref data, .. } if *data == msg),
+                    "{received:?}"
+                );
+            }
+            Ok(())
+        }
+
+        let key_a = SecretKey::generate(rand::thread_rng());
+        let key_b = SecretKey::generate(rand::thread_rng());
+        let key_c = SecretKey::generate(rand::thread_rng());
+        let mut client_a = ClientBuilder::new(relay_url.clone(), key_a.clone(), DnsResolver::new())
+            .payload_compression(PayloadCompression::Lz4)
+            .connect()
+            .await?;
+        let mut client_b = ClientBuilder::new(relay_url.clone(), key_b.clone(), DnsResolver::new())
+            .payload_compression(PayloadCompression::Zstd)
+            .connect()
+            .await?;
+        // Clients not requesting compression keep working.
+        let mut client_c = ClientBuilder::new(relay_url, key_c.clone(), DnsResolver::new())
+            .connect()
             .await?;
 
-        let addr = server.addr();
+        assert!(logs_contain(
+            "accept: enabling payload compression compression=Lz4"
+        ));
+        assert!(logs_contain(
+            "accept: enabling payload compression compression=Zstd"
+        ));
 
-        // get dial info
-        let port = addr.port();
-        let addr = {
-            if let std::net::IpAddr::V4(ipv4_addr) = addr.ip() {
-                ipv4_addr
-            } else {
-                anyhow::bail!("cannot get ipv4 addr from socket addr {addr:?}");
-            }
-        };
-        info!("Relay listening on: {addr}:{port}");
+        relay(&mut client_a, &mut client_b, key_b.public()).await?;
+        relay(&mut client_b, &mut client_a, key_a.public()).await?;
+        relay(&mut client_a, &mut client_c, key_c.public()).await?;
+        relay(&mut client_c, &mut client_b, key_b.public()).await?;
 
-        let url: Url = format!("https://localhost:{port}").parse().unwrap();
+        client_a.close().await?;
+        client_b.close().await?;
+        client_c.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
 
-        // create clients
-        let (a_key, mut client_a) = create_test_client(a_key, url.clone()).await?;
-        info!("created client {a_key:?}");
-        let (b_key, mut client_b) = create_test_client(b_key, url).await?;
-        info!("created client {b_key:?}");
+    #[tokio::test]
+    #[traced_test]
+    async fn test_unknown_peers() -> Result<()> {
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .unknown_peers(Some(UnknownPeerConfig {
+                notify: true,
+                max_per_second: NonZeroU32::new(1),
+                max_burst: NonZeroU32::new(2),
+            }))
+            .spawn()?;
+        let relay_url: Url = format!("http://{}", server.addr()).parse()?;
 
-        info!("ping a");
-        client_a.send(SendMessage::Ping([1u8; 8])).await?;
-        let pong = client_a.next().await.context("eos")??;
-        assert!(matches!(pong, ReceivedMessage::Pong(_)));
+        let key_a = SecretKey::generate(rand::thread_rng());
+        let key_b = SecretKey::generate(rand::thread_rng());
+        let unknown = SecretKey::generate(rand::thread_rng()).public();
+        let mut client_a = ClientBuilder::new(relay_url.clone(), key_a.clone(), DnsResolver::new())
+            .unknown_peer_notifications(true)
+            .connect()
+            .await?;
+        let mut client_b = ClientBuilder::new(relay_url, key_b.clone(), DnsResolver::new())
+            .connect()
+            .await?;
 
-        info!("ping b");
-        client_b.send(SendMessage::Ping([2u8; 8])).await?;
-        let pong = client_b.next().await.context("eos")??;
-        assert!(matches!(pong, ReceivedMessage::Pong(_)));
+        // Every packet to an unknown node is notified, even once the reads are paused.
+        for _ in 0..3 {
+            client_a
+                .send(SendMessage::SendPacket(unknown, Bytes::from_static(b"hi")))
+                .await?;
+            let received = tokio::time::timeout(Duration::from_secs(5), client_a.next())
+                .await?
+                .context("eos")??;
+            assert!(
+                matches!(received, ReceivedMessage::UnknownPeer(dst) if dst == unknown),
+                "{received:?}"
+            );
+        }
+        assert!(logs_contain("too many packets to unknown nodes"));
 
-        info!("sending message from a to b");
-        let msg = Bytes::from_static(b"hi there, client b!");
+        // Clients not requesting notifications are not notified.
+        client_b
+            .send(SendMessage::SendPacket(unknown, Bytes::from_static(b"hi")))
+            .await?;
+        // The packets of the paused client are relayed once the rate allows it again.
+        let msg = Bytes::from_static(b"after the pause");
         client_a
-            .send(SendMessage::SendPacket(b_key, msg.clone()))
+            .send(SendMessage::SendPacket(key_b.public(), msg.clone()))
             .await?;
-        info!("waiting for message from a on b");
-        let (got_key, got_msg) =
-            process_msg(client_b.next().await).expect("expected message from client_a");
-        assert_eq!(a_key, got_key);
-        assert_eq!(msg, got_msg);
+        let received = tokio::time::timeout(Duration::from_secs(5), client_b.next())
+            .await?
+            .context("eos")??;
+        assert!(
+            matches!(received, ReceivedMessage::ReceivedPacket { ref data, .. } if *data == msg),
+            "{received:?}"
+        );
 
-        info!("sending message from b to a");
-        let msg = Bytes::from_static(b"right back at ya, client b!");
-        client_b
-            .send(SendMessage::SendPacket(a_key, msg.clone()))
+        client_a.close().await?;
+        client_b.close().await?;
+        server.shutdown();
+        server.task_handle().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_https_client_custom_rustls_config() -> Result<()> {
+        let tls_config = make_tls_config();
+        let mut server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .tls_config(Some(tls_config))
+            .spawn()?;
+        let url: Url = format!("https://localhost:{}", server.addr().port())
+            .parse()
+            .unwrap();
+
+        // The default config does not trust the self-signed certificate.
+        let key = SecretKey::generate(rand::thread_rng());
+        let res = ClientBuilder::new(url.clone(), key, DnsResolver::new())
+            .connect()
+            .await;
+        assert!(res.is_err());
+
+        // A custom config can.
+        let key = SecretKey::generate(rand::thread_rng());
+        let rustls_config = Arc::new(crate::client::make_dangerous_client_config());
+        let mut client = ClientBuilder::new(url, key, DnsResolver::new())
+            .rustls_config(rustls_config)
+            .connect()
             .await?;
-        info!("waiting for message b on a");
-        let (got_key, got_msg) =
-            process_msg(client_a.next().await).expect("expected message from client_b");
-        assert_eq!(b_key, got_key);
-        assert_eq!(msg, got_msg);
+        client.send(SendMessage::Ping([1u8; 8])).await?;
+        let pong = client.next().await.context("eos")??;
+        assert!(matches!(pong, ReceivedMessage::Pong(_)));
 
+        client.close().await?;
         server.shutdown();
         server.task_handle().await?;
-        client_a.close().await?;
-        client_b.close().await?;
 
         Ok(())
     }
 
     async fn make_test_client(client: tokio::io::DuplexStream, key: &SecretKey) -> Result<Conn> {
         let client = MaybeTlsStreamChained::Mem(client);
-        let client = Conn::new_relay(client, KeyCache::test(), key).await?;
+        let client = Conn::new_relay(
+            client,
+            KeyCache::test(),
+            key,
+            Default::default(),
+            None,
+            None,
+            false,
+        )
+        .await?;
         Ok(client)
     }
 
@@ -932,21 +6503,24 @@ mod tests {
     #[traced_test]
     async fn test_server_basic() -> Result<()> {
         info!("Create the server.");
-        let service = RelayService::new(
+        let service = RelayService::builder(
             Default::default(),
             Default::default(),
             None,
             KeyCache::test(),
             AccessConfig::Everyone,
-        );
+            None,
+            None,
+        )
+        .build();
 
         info!("Create client A and connect it to the server.");
         let key_a = SecretKey::generate(rand::thread_rng());
         let public_key_a = key_a.public();
-        let (client_a, rw_a) = tokio::io::duplex(10);
+        let (client_a, rw_a) = tokio::io::duplex(1024);
         let s = service.clone();
         let handler_task = tokio::spawn(async move {
-            s.0.accept(Protocol::Relay, MaybeTlsStream::Test(rw_a))
+            s.0.accept(Protocol::Relay, MaybeTlsStream::Test(rw_a), test_request())
                 .await
         });
         let mut client_a = make_test_client(client_a, &key_a).await?;
@@ -955,10 +6529,10 @@ mod tests {
         info!("Create client B and connect it to the server.");
         let key_b = SecretKey::generate(rand::thread_rng());
         let public_key_b = key_b.public();
-        let (client_b, rw_b) = tokio::io::duplex(10);
+        let (client_b, rw_b) = tokio::io::duplex(1024);
         let s = service.clone();
         let handler_task = tokio::spawn(async move {
-            s.0.accept(Protocol::Relay, MaybeTlsStream::Test(rw_b))
+            s.0.accept(Protocol::Relay, MaybeTlsStream::Test(rw_b), test_request())
                 .await
         });
         let mut client_b = make_test_client(client_b, &key_b).await?;
@@ -1019,21 +6593,24 @@ mod tests {
     #[tokio::test]
     async fn test_server_replace_client() -> Result<()> {
         info!("Create the server.");
-        let service = RelayService::new(
+        let service = RelayService::builder(
             Default::default(),
             Default::default(),
             None,
             KeyCache::test(),
             AccessConfig::Everyone,
-        );
+            None,
+            None,
+        )
+        .build();
 
         info!("Create client A and connect it to the server.");
         let key_a = SecretKey::generate(rand::thread_rng());
         let public_key_a = key_a.public();
-        let (client_a, rw_a) = tokio::io::duplex(10);
+        let (client_a, rw_a) = tokio::io::duplex(1024);
         let s = service.clone();
         let handler_task = tokio::spawn(async move {
-            s.0.accept(Protocol::Relay, MaybeTlsStream::Test(rw_a))
+            s.0.accept(Protocol::Relay, MaybeTlsStream::Test(rw_a), test_request())
                 .await
         });
         let mut client_a = make_test_client(client_a, &key_a).await?;
@@ -1042,10 +6619,10 @@ mod tests {
         info!("Create client B and connect it to the server.");
         let key_b = SecretKey::generate(rand::thread_rng());
         let public_key_b = key_b.public();
-        let (client_b, rw_b) = tokio::io::duplex(10);
+        let (client_b, rw_b) = tokio::io::duplex(1024);
         let s = service.clone();
         let handler_task = tokio::spawn(async move {
-            s.0.accept(Protocol::Relay, MaybeTlsStream::Test(rw_b))
+            s.0.accept(Protocol::Relay, MaybeTlsStream::Test(rw_b), test_request())
                 .await
         });
         let mut client_b = make_test_client(client_b, &key_b).await?;
@@ -1088,11 +6665,15 @@ mod tests {
         }
 
         info!("Create client B and connect it to the server");
-        let (new_client_b, new_rw_b) = tokio::io::duplex(10);
+        let (new_client_b, new_rw_b) = tokio::io::duplex(1024);
         let s = service.clone();
         let handler_task = tokio::spawn(async move {
-            s.0.accept(Protocol::Relay, MaybeTlsStream::Test(new_rw_b))
-                .await
+            s.0.accept(
+                Protocol::Relay,
+                MaybeTlsStream::Test(new_rw_b),
+                test_request(),
+            )
+            .await
         });
         let mut new_client_b = make_test_client(new_client_b, &key_b).await?;
         handler_task.await??;
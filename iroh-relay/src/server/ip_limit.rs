@@ -0,0 +1,174 @@
+//! Limit of the clients connected from a single source address.
+//!
+//! A single host must not occupy all client slots of the server, so the distinct nodes
+//! connected from each source are counted during the relay handshake.  A source is an IPv4
+//! address, or an IPv6 /64 prefix as hosts usually get a whole prefix to pick addresses
+//! from.  Networks sharing a few addresses between many hosts, like carrier-grade NATs,
+//! can be exempt from the limit.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{ensure, Result};
+use ipnet::IpNet;
+use iroh_base::NodeId;
+
+/// The length of the IPv6 prefixes counted as a single source.
+const IPV6_SOURCE_PREFIX_LEN: u32 = 64;
+
+/// Limit of the clients connected at the same time from a single source.
+#[derive(Debug, Clone)]
+pub struct ClientIpLimit {
+    /// Max number of distinct nodes connected at the same time from a single IPv4 address,
+    /// or a single IPv6 /64 prefix.
+    ///
+    /// A node reconnecting while its previous connection is still open is counted once.
+    pub max_clients: NonZeroUsize,
+    /// The networks whose clients are not limited.
+    pub exempt: Vec<IpNet>,
+}
+
+/// Counts the clients connected from each source to enforce a [`ClientIpLimit`].
+#[derive(Debug, Clone)]
+pub(super) struct ClientIpLimiter(Arc<LimiterInner>);
+
+#[derive(Debug)]
+struct LimiterInner {
+    limit: ClientIpLimit,
+    /// The connections of every node, per source.
+    sources: Mutex<HashMap<IpAddr, HashMap<NodeId, usize>>>,
+}
+
+impl ClientIpLimiter {
+    pub(super) fn new(limit: ClientIpLimit) -> Self {
+        Self(Arc::new(LimiterInner {
+            limit,
+            sources: Default::default(),
+        }))
+    }
+
+    /// Counts a client connecting from `ip`, failing if its source has too many clients.
+    ///
+    /// Returns `None` for exempt addresses.  The client is counted until the permit is
+    /// dropped.
+    pub(super) fn acquire(&self, ip: IpAddr, node_id: NodeId) -> Result<Option<ClientIpPermit>> {
+        let ip = ip.to_canonical();
+        if self.0.limit.exempt.iter().any(|net| net.contains(&ip)) {
+            return Ok(None);
+        }
+        let source = source(ip);
+        let mut sources = self.0.sources.lock().expect("poisoned");
+        let nodes = sources.entry(source).or_default();
+        ensure!(
+            nodes.contains_key(&node_id) || nodes.len() < self.0.limit.max_clients.get(),
+            "too many clients from {source}"
+        );
+        *nodes.entry(node_id).or_default() += 1;
+        Ok(Some(ClientIpPermit {
+            limiter: self.clone(),
+            source,
+            node_id,
+        }))
+    }
+}
+
+/// The source a client connecting from `ip` is counted for.
+///
+/// IPv4-mapped IPv6 addresses count as their IPv4 address rather than sharing one /64.
+fn source(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        ip @ IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX << (128 - IPV6_SOURCE_PREFIX_LEN);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+/// A client counted by the [`ClientIpLimiter`].
+#[derive(Debug)]
+pub(super) struct ClientIpPermit {
+    limiter: ClientIpLimiter,
+    source: IpAddr,
+    node_id: NodeId,
+}
+
+impl Drop for ClientIpPermit {
+    fn drop(&mut self) {
+        let mut sources = self.limiter.0.sources.lock().expect("poisoned");
+        let Some(nodes) = sources.get_mut(&self.source) else {
+            return;
+        };
+        if let Some(count) = nodes.get_mut(&self.node_id) {
+            *count -= 1;
+            if *count == 0 {
+                nodes.remove(&self.node_id);
+            }
+        }
+        if nodes.is_empty() {
+            sources.remove(&self.source);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::SecretKey;
+
+    use super::*;
+
+    #[test]
+    fn test_client_ip_limiter() -> Result<()> {
+        let limiter = ClientIpLimiter::new(ClientIpLimit {
+            max_clients: 2.try_into()?,
+            exempt: vec!["100.64.0.0/10".parse()?],
+        });
+        let node = || SecretKey::generate(rand::thread_rng()).public();
+        let (a, b, c) = (node(), node(), node());
+
+        // The addresses of an IPv6 /64 prefix are a single source.
+        let first = limiter.acquire("2001:db8::1".parse()?, a)?;
+        let second = limiter.acquire("2001:db8::2".parse()?, b)?;
+        assert!(limiter.acquire("2001:db8::3".parse()?, c).is_err());
+        assert!(limiter.acquire("2001:db8:0:1::1".parse()?, c)?.is_some());
+        // A node reconnecting is counted once.
+        let reconnect = limiter.acquire("2001:db8::1".parse()?, a)?;
+        drop(first);
+        assert!(limiter.acquire("2001:db8::3".parse()?, c).is_err());
+        drop(reconnect);
+        assert!(limiter.acquire("2001:db8::3".parse()?, c)?.is_some());
+        drop(second);
+
+        assert!(limiter.acquire("192.0.2.1".parse()?, a)?.is_some());
+        for _ in 0..3 {
+            assert!(limiter.acquire("100.64.1.1".parse()?, node())?.is_none());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_ip_limiter_ipv4_mapped() -> Result<()> {
+        let limiter = ClientIpLimiter::new(ClientIpLimit {
+            max_clients: 1.try_into()?,
+            exempt: vec!["100.64.0.0/10".parse()?],
+        });
+        let node = || SecretKey::generate(rand::thread_rng()).public();
+
+        // Different IPv4 clients on a dual-stack socket are separate sources.
+        let _first = limiter.acquire("::ffff:192.0.2.1".parse()?, node())?;
+        assert!(limiter
+            .acquire("::ffff:192.0.2.2".parse()?, node())?
+            .is_some());
+        // The same client is counted once however its address is written.
+        assert!(limiter.acquire("192.0.2.1".parse()?, node()).is_err());
+        // Exemptions apply to the mapped address.
+        assert!(limiter
+            .acquire("::ffff:100.64.1.1".parse()?, node())?
+            .is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+//! Negotiation of the interval the server pings idle clients at.
+//!
+//! A client can request a keep-alive interval in its capabilities, e.g. a shorter one to
+//! keep the mapping of a NAT with a short timeout alive, or a longer one to save the
+//! battery of a mobile device.  The server clamps the requested interval to the bounds it
+//! is configured with and answers with the picked interval and the bounds.  Clients not
+//! requesting an interval are pinged at the default interval.
+
+use std::time::Duration;
+
+use anyhow::{ensure, Result};
+
+use crate::protos::relay::KeepAliveInterval;
+
+/// The default interval the server pings idle clients at.
+///
+/// Clients are pinged a bit later, with some jitter so not all pings are sent at the same
+/// time.
+///
+/// This is less than the QUIC idle timeout of 30 seconds, so the connections to clients
+/// relaying over QUIC do not time out.
+pub const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Configuration of the keep-alive intervals clients can request.
+#[derive(Debug, Clone)]
+pub struct KeepAliveConfig {
+    /// The interval clients not requesting one are pinged at.
+    pub default_interval: Duration,
+    /// The shortest interval a client can request.
+    pub min_interval: Duration,
+    /// The longest interval a client can request.
+    pub max_interval: Duration,
+}
+
+impl Default for KeepAliveConfig {
+    fn default() -> Self {
+        Self {
+            default_interval: DEFAULT_KEEP_ALIVE_INTERVAL,
+            min_interval: Duration::from_secs(5),
+            max_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl KeepAliveConfig {
+    pub(super) fn validate(&self) -> Result<()> {
+        ensure!(
+            !self.min_interval.is_zero(),
+            "the minimum keep-alive interval must not be zero"
+        );
+        ensure!(
+            self.min_interval <= self.default_interval
+                && self.default_interval <= self.max_interval,
+            "the default keep-alive interval {:?} must be within {:?} and {:?}",
+            self.default_interval,
+            self.min_interval,
+            self.max_interval
+        );
+        Ok(())
+    }
+
+    /// Returns the interval to ping a client at, and the answer to its request.
+    ///
+    /// Clients not requesting an interval get no answer.
+    pub(super) fn negotiate(
+        &self,
+        requested: Option<KeepAliveInterval>,
+    ) -> (Duration, Option<KeepAliveInterval>) {
+        let Some(requested) = requested else {
+            return (self.default_interval, None);
+        };
+        let interval = requested
+            .interval()
+            .clamp(self.min_interval, self.max_interval);
+        let picked = KeepAliveInterval::picked(interval, self.min_interval, self.max_interval);
+        (interval, Some(picked))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let config = KeepAliveConfig::default();
+        config.validate().unwrap();
+        assert_eq!(config.negotiate(None), (DEFAULT_KEEP_ALIVE_INTERVAL, None));
+
+        for (requested, picked) in [(1, 5), (20, 20), (600, 60)] {
+            let requested = KeepAliveInterval::request(Duration::from_secs(requested));
+            let (interval, answer) = config.negotiate(Some(requested));
+            assert_eq!(interval, Duration::from_secs(picked));
+            let answer = answer.unwrap();
+            assert_eq!(answer.interval(), interval);
+            assert_eq!((answer.min_ms, answer.max_ms), (5_000, 60_000));
+        }
+
+        let invalid = KeepAliveConfig {
+            default_interval: Duration::from_secs(90),
+            ..Default::default()
+        };
+        assert!(invalid.validate().is_err());
+    }
+}
@@ -0,0 +1,344 @@
+//! Forwarding of packets between the relay servers of a mesh.
+//!
+//! Every relay of a mesh connects to each of its peers as a trusted client, see
+//! [`RelayConfig::mesh_key`], and watches which nodes are connected there.  Packets from
+//! local clients to nodes which are not connected locally are forwarded to the peer the
+//! node is connected to, which delivers them like its own packets.  Forwarded packets are
+//! never forwarded again, so every relay of the mesh has to list all others as peers.
+//!
+//! [`RelayConfig::mesh_key`]: crate::server::RelayConfig::mesh_key
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use dashmap::DashMap;
+use iroh_base::{inc, NodeId, RelayUrl, SecretKey};
+use n0_future::{SinkExt, StreamExt};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinSet,
+};
+use tracing::{debug, info, info_span, trace, warn, Instrument};
+
+use super::{http_server::ServerHandle, metrics::Metrics};
+use crate::{
+    client::{ClientBuilder, ReceivedMessage, SendMessage},
+    dns::DnsResolver,
+    protos::relay::MeshKey,
+};
+
+/// The number of packets queued for a peer before further packets are dropped.
+const PEER_QUEUE_DEPTH: usize = 512;
+/// The delay before reconnecting to a peer after the first failure.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// The longest delay between attempts to reconnect to a peer.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// How long a peer may take to acknowledge the mesh key once connected.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Configuration of the relay mesh.
+///
+/// The relays of a mesh share the same [`RelayConfig::mesh_key`], which is required to
+/// join a mesh.
+///
+/// [`RelayConfig::mesh_key`]: crate::server::RelayConfig::mesh_key
+#[derive(Debug, Clone, Default)]
+pub struct MeshConfig {
+    /// The URLs of the other relays of the mesh.
+    pub peers: Vec<RelayUrl>,
+}
+
+/// A packet to forward to a peer.
+#[derive(Debug)]
+struct Forward {
+    src: NodeId,
+    dst: NodeId,
+    /// The hop count sent to the peer, see [`MAX_FORWARD_HOPS`].
+    ///
+    /// [`MAX_FORWARD_HOPS`]: crate::protos::relay::MAX_FORWARD_HOPS
+    hops: u8,
+    packet: Bytes,
+}
+
+/// Which peer relay the nodes not connected to this relay are connected to.
+#[derive(Debug, Clone)]
+pub(super) struct MeshRoutes(Arc<RoutesInner>);
+
+#[derive(Debug)]
+struct RoutesInner {
+    /// The index of the peer each remote node is connected to.
+    nodes: DashMap<NodeId, usize>,
+    /// The queues of the packets to forward to each peer.
+    peers: Vec<mpsc::Sender<Forward>>,
+}
+
+impl MeshRoutes {
+    /// Whether the node is connected to a peer relay.
+    pub(super) fn knows(&self, node_id: &NodeId) -> bool {
+        self.0.nodes.contains_key(node_id)
+    }
+
+    /// Forwards a packet to the peer relay `dst` is connected to.
+    ///
+    /// The `hops` are 1 for packets of local clients, forwarded packets count the relays
+    /// they were forwarded between.
+    pub(super) fn forward(&self, dst: NodeId, packet: Bytes, src: NodeId, hops: u8) -> Result<()> {
+        let peer = *self
+            .0
+            .nodes
+            .get(&dst)
+            .context("no peer relay for the node")?;
+        match self.0.peers[peer].try_send(Forward {
+            src,
+            dst,
+            hops,
+            packet,
+        }) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                debug!(dst = dst.fmt_short(), "mesh peer too busy, dropping packet");
+                inc!(Metrics, mesh_packets_dropped);
+                bail!("failed to forward packet: full");
+            }
+            Err(TrySendError::Closed(_)) => bail!("failed to forward packet: mesh closed"),
+        }
+    }
+
+    fn insert(&self, node_id: NodeId, peer: usize) {
+        trace!(node = node_id.fmt_short(), peer, "node present at peer");
+        self.0.nodes.insert(node_id, peer);
+    }
+
+    /// Removes the node, unless it moved to another peer in the meantime.
+    fn remove(&self, node_id: NodeId, peer: usize) {
+        trace!(node = node_id.fmt_short(), peer, "node gone from peer");
+        self.0.nodes.remove_if(&node_id, |_, p| *p == peer);
+    }
+
+    /// Removes all nodes connected to the peer.
+    fn remove_peer(&self, peer: usize) {
+        self.0.nodes.retain(|_, p| *p != peer);
+    }
+}
+
+/// Maintains the connections to the peers of the mesh.
+#[derive(Debug)]
+pub(super) struct Mesh {
+    routes: MeshRoutes,
+    peers: Vec<(RelayUrl, mpsc::Receiver<Forward>)>,
+    mesh_key: MeshKey,
+    /// The key the relay connects to its peers with.
+    secret_key: SecretKey,
+}
+
+impl Mesh {
+    /// Creates the mesh and the routes for the relay server to forward packets with.
+    pub(super) fn new(config: MeshConfig, mesh_key: MeshKey) -> (MeshRoutes, Self) {
+        let (senders, peers) = config
+            .peers
+            .into_iter()
+            .map(|url| {
+                let (tx, rx) = mpsc::channel(PEER_QUEUE_DEPTH);
+                (tx, (url, rx))
+            })
+            .unzip();
+        let routes = MeshRoutes(Arc::new(RoutesInner {
+            nodes: DashMap::new(),
+            peers: senders,
+        }));
+        let mesh = Self {
+            routes: routes.clone(),
+            peers,
+            mesh_key,
+            secret_key: SecretKey::generate(rand::rngs::OsRng),
+        };
+        (routes, mesh)
+    }
+
+    /// Connects to the peers until the relay server shuts down.
+    pub(super) async fn run(self, relay: ServerHandle) -> Result<()> {
+        info!(
+            peers = self.peers.len(),
+            node = self.secret_key.public().fmt_short(),
+            "relay mesh: connecting to peers"
+        );
+        let dns_resolver = DnsResolver::new();
+        let mut tasks = JoinSet::new();
+        for (index, (url, queue)) in self.peers.into_iter().enumerate() {
+            let builder =
+                ClientBuilder::new(url.clone(), self.secret_key.clone(), dns_resolver.clone())
+                    .mesh_key(self.mesh_key.clone());
+            tasks.spawn(
+                run_peer(index, builder, queue, self.routes.clone())
+                    .instrument(info_span!("mesh-peer", %url)),
+            );
+        }
+        relay.cancelled().await;
+        tasks.shutdown().await;
+        debug!("relay mesh has been shutdown.");
+        Ok(())
+    }
+}
+
+/// Keeps a connection to a peer, reconnecting with a backoff.
+async fn run_peer(
+    index: usize,
+    builder: ClientBuilder,
+    mut queue: mpsc::Receiver<Forward>,
+    routes: MeshRoutes,
+) {
+    let mut delay = MIN_RECONNECT_DELAY;
+    loop {
+        match builder.connect().await {
+            Ok(client) => {
+                debug!("connected");
+                inc!(Metrics, mesh_connects);
+                delay = MIN_RECONNECT_DELAY;
+                if let Err(err) = serve_peer(index, client, &mut queue, &routes).await {
+                    warn!("connection failed: {err:#}");
+                }
+                routes.remove_peer(index);
+            }
+            Err(err) => warn!("failed to connect: {err:#}"),
+        }
+        // Packets queued while disconnected are stale by the time it reconnects.
+        while queue.try_recv().is_ok() {
+            inc!(Metrics, mesh_packets_dropped);
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Tracks the nodes connected to the peer and forwards the queued packets to it.
+async fn serve_peer(
+    index: usize,
+    client: crate::client::Client,
+    queue: &mut mpsc::Receiver<Forward>,
+    routes: &MeshRoutes,
+) -> Result<()> {
+    let (mut stream, mut sink) = client.split();
+    // The peer acknowledges the mesh key before answering the ping.
+    sink.send(SendMessage::Ping(*b"meshpeer")).await?;
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        loop {
+            match stream.next().await.context("connection closed")?? {
+                ReceivedMessage::Pong(_) => return anyhow::Ok(()),
+                msg => trace!(?msg, "ignoring message"),
+            }
+        }
+    })
+    .await
+    .context("timeout waiting for the peer to acknowledge the mesh key")??;
+    sink.send(SendMessage::WatchConns)
+        .await
+        .context("the peer does not accept the mesh key")?;
+    loop {
+        tokio::select! {
+            msg = stream.next() => match msg.context("connection closed")?? {
+                ReceivedMessage::PeerPresent(node_id) => routes.insert(node_id, index),
+                ReceivedMessage::NodeGone(node_id) => routes.remove(node_id, index),
+                ReceivedMessage::Ping(data) => sink.send(SendMessage::Pong(data)).await?,
+                msg => trace!(?msg, "ignoring message"),
+            },
+            Some(Forward { src, dst, hops, packet }) = queue.recv() => {
+                sink.send(SendMessage::ForwardPacket {
+                    src,
+                    dst,
+                    hops,
+                    packet,
+                })
+                .await?;
+                inc!(Metrics, mesh_packets_forwarded);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        protos::relay::{SendStatus, MAX_FORWARD_HOPS},
+        server::clients::{Clients, ForwardStatus},
+    };
+
+    #[test]
+    fn test_routes() -> Result<()> {
+        let config = MeshConfig {
+            peers: vec!["https://a.example".parse()?, "https://b.example".parse()?],
+        };
+        let (routes, mut mesh) = Mesh::new(config, MeshKey::generate());
+        let node = SecretKey::generate(rand::thread_rng()).public();
+        let src = SecretKey::generate(rand::thread_rng()).public();
+        assert!(!routes.knows(&node));
+        assert!(routes
+            .forward(node, Bytes::from_static(b"lost"), src, 1)
+            .is_err());
+
+        routes.insert(node, 0);
+        routes.forward(node, Bytes::from_static(b"hi"), src, 1)?;
+        let forward = mesh.peers[0].1.try_recv()?;
+        assert_eq!((forward.src, forward.dst), (src, node));
+
+        // The node moved to the second peer before the first one noticed it is gone.
+        routes.insert(node, 1);
+        routes.remove(node, 0);
+        assert!(routes.knows(&node));
+        routes.forward(node, Bytes::from_static(b"hi"), src, 1)?;
+        assert!(mesh.peers[1].1.try_recv().is_ok());
+
+        routes.remove_peer(1);
+        assert!(!routes.knows(&node));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_symmetric_mesh_loop() -> Result<()> {
+        // Two meshed relays which both believe the node is connected to the other one.
+        let relay = |peer: &str| -> Result<_> {
+            let config = MeshConfig {
+                peers: vec![peer.parse()?],
+            };
+            let (routes, mesh) = Mesh::new(config, MeshKey::generate());
+            let clients = Clients::new(Some(routes.clone()), None, None);
+            Ok((routes, mesh, clients))
+        };
+        let mut relays = [relay("https://b.example")?, relay("https://a.example")?];
+        let node = SecretKey::generate(rand::thread_rng()).public();
+        let src = SecretKey::generate(rand::thread_rng()).public();
+        for (routes, ..) in &relays {
+            routes.insert(node, 0);
+        }
+
+        // A packet of a local client is forwarded to the peer, which forwards it back.
+        let status = relays[0]
+            .2
+            .send_packet(node, Bytes::from_static(b"lost"), src)?;
+        assert_eq!(status, SendStatus::Queued);
+        let mut from = 0;
+        let status = loop {
+            let forward = relays[from].1.peers[0].1.try_recv()?;
+            assert_eq!((forward.src, forward.dst), (src, node));
+            from = 1 - from;
+            match relays[from]
+                .2
+                .forward_packet(node, forward.packet, src, forward.hops)?
+            {
+                ForwardStatus::Sent(SendStatus::Queued) => {}
+                status => break (forward.hops, status),
+            }
+        };
+        // The packet bounced between the relays until it exceeded the hop limit.
+        assert_eq!(
+            status,
+            (MAX_FORWARD_HOPS + 1, ForwardStatus::HopLimitExceeded)
+        );
+        for (_, mesh, clients) in &mut relays {
+            assert!(mesh.peers[0].1.try_recv().is_err());
+            clients.shutdown().await;
+        }
+        Ok(())
+    }
+}
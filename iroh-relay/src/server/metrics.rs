@@ -41,27 +41,161 @@ pub struct Metrics {
     pub sent_pong: Counter,
     /// Number of `FrameType::Unknown` received
     pub unknown_frames: Counter,
+    /// Number of `FrameType::ForwardPacket`s dropped for exceeding the hop limit
+    pub forward_loops_dropped: Counter,
+    /// Number of packets forwarded to the relay of the mesh their destination is connected to
+    pub mesh_packets_forwarded: Counter,
+    /// Number of packets dropped instead of forwarding them to a relay of the mesh
+    pub mesh_packets_dropped: Counter,
+    /// Number of connections established to the relays of the mesh
+    pub mesh_connects: Counter,
+    /// Number of `FrameType::SendQueueStatus`s sent telling that a destination is congested
+    pub send_queue_congested: Counter,
+    /// Number of `FrameType::SendQueueStatus`s sent telling that a destination is ready again
+    pub send_queue_ready: Counter,
+    /// Number of packets dropped as the send queue of their destination was full
+    pub send_queue_overflows: Counter,
+    /// Number of clients disconnected for overflowing their send queue
+    pub send_queue_overflow_disconnects: Counter,
+    /// Number of `FrameType::UnknownPeer`s sent telling that a destination is not connected
+    pub unknown_peer_notifications: Counter,
+    /// Number of times the reads from a client were paused for sending to unknown nodes
+    pub unknown_peers_ratelimited: Counter,
 
     /// Number of frames received from client connection which have been rate-limited.
     pub frames_rx_ratelimited_total: Counter,
     /// Number of client connections which have had any frames rate-limited.
     pub conns_rx_ratelimited_total: Counter,
+    /// Number of packets to a client connection which have been held back by the rate limit.
+    pub frames_tx_ratelimited_total: Counter,
+    /// Number of client connections which have had any packets held back by the rate limit.
+    pub conns_tx_ratelimited_total: Counter,
+    /// Number of packets from client connections which have been dropped by the byte quota.
+    pub frames_quota_dropped_total: Counter,
+    /// Number of times a client connection had packets dropped after using up its quota.
+    pub conns_quota_exceeded_total: Counter,
+    /// Number of connections which waited for a free slot of the handshake phase.
+    pub handshakes_queued: Counter,
+    /// Number of connections closed as no slot of the handshake phase became free in time.
+    pub handshakes_rejected: Counter,
+    /// Number of connections closed right after accepting them, for exceeding a connection limit.
+    pub connections_rejected: Counter,
+    /// Number of connections closed for a missing or invalid PROXY protocol header.
+    pub proxy_headers_rejected: Counter,
+    /// Number of connections closed for presenting no TLS client certificate.
+    pub client_certificates_missing: Counter,
+    /// Number of access log records dropped because the log did not keep up.
+    pub access_records_dropped: Counter,
 
     /*
      * Metrics about peers
      */
     /// Number of connections we have accepted
     pub accepts: Counter,
+    /// Number of clients refused by the authorizer
+    pub clients_unauthorized: Counter,
+    /// Number of clients refused as too many clients are connected from their address
+    pub clients_ip_limited: Counter,
+    /// Number of clients which resumed their session after reconnecting
+    pub sessions_resumed: Counter,
+    /// Number of sessions which ended as the client did not reconnect within the grace period
+    pub sessions_expired: Counter,
     /// Number of connections we have removed because of an error
     pub disconnects: Counter,
+    /// Number of clients that announced they are disconnecting
+    pub graceful_disconnects: Counter,
 
     /// Number of unique client keys per day
     pub unique_client_keys: Counter,
 
+    /// Number of accepted key rotations
+    pub key_rotations: Counter,
+
+    /// Number of accepted clients which reported their software version
+    pub client_software_reported: Counter,
+    /// Number of accepted clients which did not report their software version
+    pub client_software_unreported: Counter,
+
     /// Number of accepted websocket connections
     pub websocket_accepts: Counter,
     /// Number of accepted 'iroh derp http' connection upgrades
     pub relay_accepts: Counter,
+    /// Number of accepted WebTransport relay streams
+    pub webtransport_accepts: Counter,
+
+    /*
+     * Metrics about the protocols of client connections
+     */
+    /// Frames sent over the relay framing
+    pub relay_frames_sent: Counter,
+    /// Frames received over the relay framing
+    pub relay_frames_recv: Counter,
+    /// Bytes of the frames sent over the relay framing, excluding the frame headers
+    pub relay_frame_bytes_sent: Counter,
+    /// Bytes of the frames received over the relay framing, excluding the frame headers
+    pub relay_frame_bytes_recv: Counter,
+    /// Milliseconds spent in completed handshakes over the relay framing
+    pub relay_handshake_ms: Counter,
+    /// Handshakes over the relay framing which failed
+    pub relay_handshake_errors: Counter,
+    /// Connections over the relay framing closed by an IO error
+    pub relay_io_errors: Counter,
+    /// Connections over the relay framing closed by a write or ping timeout
+    pub relay_timeouts: Counter,
+    /// Connections over the relay framing closed by a protocol violation
+    pub relay_protocol_errors: Counter,
+
+    /// Frames sent over websockets
+    pub websocket_frames_sent: Counter,
+    /// Frames received over websockets
+    pub websocket_frames_recv: Counter,
+    /// Bytes of the frames sent over websockets, excluding the frame headers
+    pub websocket_frame_bytes_sent: Counter,
+    /// Bytes of the frames received over websockets, excluding the frame headers
+    pub websocket_frame_bytes_recv: Counter,
+    /// Milliseconds spent in completed handshakes over websockets
+    pub websocket_handshake_ms: Counter,
+    /// Handshakes over websockets which failed
+    pub websocket_handshake_errors: Counter,
+    /// Connections over websockets closed by an IO error
+    pub websocket_io_errors: Counter,
+    /// Connections over websockets closed by a write or ping timeout
+    pub websocket_timeouts: Counter,
+    /// Connections over websockets closed by a protocol violation
+    pub websocket_protocol_errors: Counter,
+
+    /// Frames sent over WebTransport
+    pub webtransport_frames_sent: Counter,
+    /// Frames received over WebTransport
+    pub webtransport_frames_recv: Counter,
+    /// Bytes of the frames sent over WebTransport, excluding the frame headers
+    pub webtransport_frame_bytes_sent: Counter,
+    /// Bytes of the frames received over WebTransport, excluding the frame headers
+    pub webtransport_frame_bytes_recv: Counter,
+    /// Milliseconds spent in completed handshakes over WebTransport
+    pub webtransport_handshake_ms: Counter,
+    /// Handshakes over WebTransport which failed
+    pub webtransport_handshake_errors: Counter,
+    /// Connections over WebTransport closed by an IO error
+    pub webtransport_io_errors: Counter,
+    /// Connections over WebTransport closed by a write or ping timeout
+    pub webtransport_timeouts: Counter,
+    /// Connections over WebTransport closed by a protocol violation
+    pub webtransport_protocol_errors: Counter,
+    /// WebTransport session requests which were rejected
+    pub webtransport_sessions_rejected: Counter,
+
+    /*
+     * Metrics about the key cache
+     */
+    /// Number of public keys found in the key cache
+    pub key_cache_hits: Counter,
+    /// Number of public keys not found in the key cache
+    pub key_cache_misses: Counter,
+    /// Number of public keys inserted into the key cache
+    pub key_cache_inserts: Counter,
+    /// Number of public keys evicted from the key cache
+    pub key_cache_evictions: Counter,
     // TODO: enable when we can have multiple connections for one node id
     // pub duplicate_client_keys: Counter,
     // pub duplicate_client_conns: Counter,
@@ -96,23 +230,189 @@ impl Default for Metrics {
             got_ping: Counter::new("Number of times the server has received a Ping from a client."),
             sent_pong: Counter::new("Number of times the server has sent a Pong to a client."),
             unknown_frames: Counter::new("Number of unknown frames sent to this server."),
+            forward_loops_dropped: Counter::new(
+                "Number of forwarded packets dropped as they exceeded the hop limit, likely looping in the mesh.",
+            ),
+            mesh_packets_forwarded: Counter::new(
+                "Number of packets forwarded to the relay of the mesh their destination is connected to.",
+            ),
+            mesh_packets_dropped: Counter::new(
+                "Number of packets dropped as the relay of the mesh they were forwarded to was busy or disconnected.",
+            ),
+            mesh_connects: Counter::new("Number of connections established to relays of the mesh."),
+            send_queue_congested: Counter::new(
+                "Number of times a sender was told that the send queue of its destination is congested.",
+            ),
+            send_queue_ready: Counter::new(
+                "Number of times a sender was told that the send queue of its destination drained.",
+            ),
+            send_queue_overflows: Counter::new(
+                "Number of packets dropped as the send queue of their destination was full.",
+            ),
+            send_queue_overflow_disconnects: Counter::new(
+                "Number of clients disconnected for overflowing their send queue.",
+            ),
+            unknown_peer_notifications: Counter::new(
+                "Number of times a sender was told that the destination of its packet is not connected.",
+            ),
+            unknown_peers_ratelimited: Counter::new(
+                "Number of times the reads from a client were paused for sending to unknown nodes.",
+            ),
             frames_rx_ratelimited_total: Counter::new(
                 "Number of frames received from client connection which have been rate-limited.",
             ),
             conns_rx_ratelimited_total: Counter::new(
                 "Number of client connections which have had any frames rate-limited.",
             ),
+            frames_tx_ratelimited_total: Counter::new(
+                "Number of packets to client connections which have been held back by the rate limit.",
+            ),
+            conns_tx_ratelimited_total: Counter::new(
+                "Number of client connections which have had any packets held back by the rate limit.",
+            ),
+            frames_quota_dropped_total: Counter::new(
+                "Number of packets from client connections which have been dropped by the byte quota.",
+            ),
+            conns_quota_exceeded_total: Counter::new(
+                "Number of times a client connection had packets dropped after using up its quota.",
+            ),
+            handshakes_queued: Counter::new(
+                "Number of connections which waited for a free slot of the handshake phase.",
+            ),
+            handshakes_rejected: Counter::new(
+                "Number of connections closed as no slot of the handshake phase became free in time.",
+            ),
+            connections_rejected: Counter::new(
+                "Number of connections closed right after accepting them, for exceeding a connection limit.",
+            ),
+            proxy_headers_rejected: Counter::new(
+                "Number of connections closed for a missing or invalid PROXY protocol header.",
+            ),
+            client_certificates_missing: Counter::new(
+                "Number of connections closed for presenting no TLS client certificate.",
+            ),
+            access_records_dropped: Counter::new(
+                "Number of access log records dropped because the log did not keep up.",
+            ),
 
             /*
              * Metrics about peers
              */
             accepts: Counter::new("Number of times this server has accepted a connection."),
+            clients_unauthorized: Counter::new("Number of clients refused by the authorizer."),
+            clients_ip_limited: Counter::new(
+                "Number of clients refused as too many clients are connected from their address.",
+            ),
+            sessions_resumed: Counter::new(
+                "Number of clients which resumed their session after reconnecting.",
+            ),
+            sessions_expired: Counter::new(
+                "Number of sessions which ended as the client did not reconnect within the grace period.",
+            ),
             disconnects: Counter::new("Number of clients that have then disconnected."),
+            graceful_disconnects: Counter::new(
+                "Number of clients that announced they are disconnecting.",
+            ),
 
             unique_client_keys: Counter::new("Number of unique client keys per day."),
 
+            key_rotations: Counter::new(
+                "Number of clients connecting with an accepted rotation from a previous key.",
+            ),
+            client_software_reported: Counter::new(
+                "Number of accepted clients which reported a valid software name and version.",
+            ),
+            client_software_unreported: Counter::new(
+                "Number of accepted clients which did not report their software name and version.",
+            ),
+
             websocket_accepts: Counter::new("Number of accepted websocket connections"),
             relay_accepts: Counter::new("Number of accepted 'iroh derp http' connection upgrades"),
+            webtransport_accepts: Counter::new("Number of accepted WebTransport relay streams"),
+
+            /*
+             * Metrics about the protocols of client connections
+             */
+            relay_frames_sent: Counter::new("Number of frames sent over the relay framing."),
+            relay_frames_recv: Counter::new("Number of frames received over the relay framing."),
+            relay_frame_bytes_sent: Counter::new(
+                "Number of bytes of the frames sent over the relay framing.",
+            ),
+            relay_frame_bytes_recv: Counter::new(
+                "Number of bytes of the frames received over the relay framing.",
+            ),
+            relay_handshake_ms: Counter::new(
+                "Milliseconds spent in completed handshakes over the relay framing.",
+            ),
+            relay_handshake_errors: Counter::new(
+                "Number of failed handshakes over the relay framing.",
+            ),
+            relay_io_errors: Counter::new(
+                "Number of connections over the relay framing closed by an IO error.",
+            ),
+            relay_timeouts: Counter::new(
+                "Number of connections over the relay framing closed by a timeout.",
+            ),
+            relay_protocol_errors: Counter::new(
+                "Number of connections over the relay framing closed by a protocol violation.",
+            ),
+            websocket_frames_sent: Counter::new("Number of frames sent over websockets."),
+            websocket_frames_recv: Counter::new("Number of frames received over websockets."),
+            websocket_frame_bytes_sent: Counter::new(
+                "Number of bytes of the frames sent over websockets.",
+            ),
+            websocket_frame_bytes_recv: Counter::new(
+                "Number of bytes of the frames received over websockets.",
+            ),
+            websocket_handshake_ms: Counter::new(
+                "Milliseconds spent in completed handshakes over websockets.",
+            ),
+            websocket_handshake_errors: Counter::new(
+                "Number of failed handshakes over websockets.",
+            ),
+            websocket_io_errors: Counter::new(
+                "Number of connections over websockets closed by an IO error.",
+            ),
+            websocket_timeouts: Counter::new(
+                "Number of connections over websockets closed by a timeout.",
+            ),
+            websocket_protocol_errors: Counter::new(
+                "Number of connections over websockets closed by a protocol violation.",
+            ),
+            webtransport_frames_sent: Counter::new("Number of frames sent over WebTransport."),
+            webtransport_frames_recv: Counter::new("Number of frames received over WebTransport."),
+            webtransport_frame_bytes_sent: Counter::new(
+                "Number of bytes of the frames sent over WebTransport.",
+            ),
+            webtransport_frame_bytes_recv: Counter::new(
+                "Number of bytes of the frames received over WebTransport.",
+            ),
+            webtransport_handshake_ms: Counter::new(
+                "Milliseconds spent in completed handshakes over WebTransport.",
+            ),
+            webtransport_handshake_errors: Counter::new(
+                "Number of failed handshakes over WebTransport.",
+            ),
+            webtransport_io_errors: Counter::new(
+                "Number of connections over WebTransport closed by an IO error.",
+            ),
+            webtransport_timeouts: Counter::new(
+                "Number of connections over WebTransport closed by a timeout.",
+            ),
+            webtransport_protocol_errors: Counter::new(
+                "Number of connections over WebTransport closed by a protocol violation.",
+            ),
+            webtransport_sessions_rejected: Counter::new(
+                "Number of rejected WebTransport session requests.",
+            ),
+
+            /*
+             * Metrics about the key cache
+             */
+            key_cache_hits: Counter::new("Number of public keys found in the key cache."),
+            key_cache_misses: Counter::new("Number of public keys not found in the key cache."),
+            key_cache_inserts: Counter::new("Number of public keys inserted into the key cache."),
+            key_cache_evictions: Counter::new("Number of public keys evicted from the key cache."),
             // TODO: enable when we can have multiple connections for one node id
             // pub duplicate_client_keys: Counter::new("Number of duplicate client keys."),
             // pub duplicate_client_conns: Counter::new("Number of duplicate client connections."),
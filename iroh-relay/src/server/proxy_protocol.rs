@@ -0,0 +1,182 @@
+//! The PROXY protocol, passing the client address on from a load balancer.
+//!
+//! Load balancers like HAProxy or AWS NLBs forwarding the TCP connections of the relay
+//! terminate them, so the server only sees the addresses of the load balancer.  With the
+//! PROXY protocol enabled the load balancer sends a header with the address of the client
+//! before any data of the connection, see
+//! <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.  Both the text header of
+//! version 1 and the binary header of version 2 are accepted.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How long the load balancer may take to send the header.
+pub(super) const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The signature starting a version 2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// The max length of a version 1 header, including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Reads the PROXY protocol header from the start of the stream.
+///
+/// Returns the address of the client, or `None` if the header carries no address, as is
+/// the case for the health checks of load balancers.  Nothing after the header is read.
+pub(super) async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>> {
+    // The shortest header, `PROXY UNKNOWN\r\n`, has 15 bytes.
+    let mut prefix = [0u8; 5];
+    stream.read_exact(&mut prefix).await?;
+    if &prefix == b"PROXY" {
+        read_v1(stream).await
+    } else if prefix == V2_SIGNATURE[..5] {
+        read_v2(stream).await
+    } else {
+        bail!("no PROXY protocol header");
+    }
+}
+
+/// Reads the rest of a version 1 header, after `PROXY`.
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    // Read byte by byte, to not consume any data after the header.
+    let mut line = Vec::with_capacity(V1_MAX_LEN);
+    line.extend_from_slice(b"PROXY");
+    while !line.ends_with(b"\r\n") {
+        ensure!(line.len() < V1_MAX_LEN, "PROXY protocol header too long");
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).context("invalid header")?;
+    let mut fields = line.split(' ');
+    ensure!(fields.next() == Some("PROXY"), "invalid header");
+    match fields.next() {
+        Some("TCP4") | Some("TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        protocol => bail!("unsupported protocol {protocol:?}"),
+    }
+    let (Some(src_ip), Some(_dst_ip), Some(src_port), Some(_dst_port), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        bail!("invalid header: {line}");
+    };
+    let ip: IpAddr = src_ip.parse().context("invalid source address")?;
+    let port: u16 = src_port.parse().context("invalid source port")?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// Reads the rest of a version 2 header, after the first bytes of the signature.
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    let mut header = [0u8; 11];
+    stream.read_exact(&mut header).await?;
+    ensure!(header[..7] == V2_SIGNATURE[5..], "invalid header signature");
+    let version_command = header[7];
+    let family = header[8];
+    let len = u16::from_be_bytes([header[9], header[10]]) as usize;
+    ensure!(version_command >> 4 == 2, "unsupported version");
+    let mut addrs = vec![0u8; len];
+    stream.read_exact(&mut addrs).await?;
+
+    match version_command & 0x0f {
+        // LOCAL: connections of the proxy itself, e.g. health checks.
+        0x0 => return Ok(None),
+        0x1 => {}
+        command => bail!("unsupported command {command}"),
+    }
+    // Any type-length-values after the addresses are ignored.
+    match family >> 4 {
+        0x1 => {
+            ensure!(addrs.len() >= 12, "header too short");
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addrs[..4]).expect("checked length"));
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        0x2 => {
+            ensure!(addrs.len() >= 36, "header too short");
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addrs[..16]).expect("checked length"));
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // UNSPEC, or unix sockets which have no address to use.
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(mut input: &[u8]) -> Result<(Option<SocketAddr>, Vec<u8>)> {
+        let addr = read_header(&mut input).await?;
+        Ok((addr, input.to_vec()))
+    }
+
+    #[tokio::test]
+    async fn test_read_v1_header() -> Result<()> {
+        let (addr, rest) = parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET").await?;
+        assert_eq!(addr, Some("192.0.2.1:56324".parse()?));
+        assert_eq!(rest, b"GET");
+        let (addr, _) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n").await?;
+        assert_eq!(addr, Some("[2001:db8::1]:4000".parse()?));
+        let (addr, rest) = parse(b"PROXY UNKNOWN\r\n\x16").await?;
+        assert_eq!(addr, None);
+        assert_eq!(rest, b"\x16");
+
+        assert!(parse(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n")
+            .await
+            .is_err());
+        assert!(parse(&[b"PROXY TCP4 ".as_slice(), &[b'1'; 120]].concat())
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_header() -> Result<()> {
+        let header = |command: u8, family: u8, addrs: &[u8]| {
+            let mut header = V2_SIGNATURE.to_vec();
+            header.extend([0x20 | command, family]);
+            header.extend((addrs.len() as u16).to_be_bytes());
+            header.extend(addrs);
+            header
+        };
+
+        // IPv4 with a trailing type-length-value.
+        let mut input = header(
+            1,
+            0x11,
+            &[
+                192, 0, 2, 1, 198, 51, 100, 1, 0x1f, 0x90, 0x01, 0xbb, 0x04, 0x00, 0x01, 0xff,
+            ],
+        );
+        input.extend(b"\x16\x03");
+        let (addr, rest) = parse(&input).await?;
+        assert_eq!(addr, Some("192.0.2.1:8080".parse()?));
+        assert_eq!(rest, b"\x16\x03");
+
+        let mut addrs = Vec::new();
+        addrs.extend("2001:db8::1".parse::<Ipv6Addr>()?.octets());
+        addrs.extend("2001:db8::2".parse::<Ipv6Addr>()?.octets());
+        addrs.extend([0x0f, 0xa0, 0x01, 0xbb]);
+        let (addr, _) = parse(&header(1, 0x21, &addrs)).await?;
+        assert_eq!(addr, Some("[2001:db8::1]:4000".parse()?));
+
+        // LOCAL connections of the proxy carry no address.
+        let (addr, rest) = parse(&[header(0, 0x00, &[]), b"GET".to_vec()].concat()).await?;
+        assert_eq!(addr, None);
+        assert_eq!(rest, b"GET");
+
+        assert!(parse(&header(1, 0x11, &[192, 0, 2, 1])).await.is_err());
+        assert!(parse(&header(2, 0x11, &[0; 12])).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+//! Byte quotas of the nodes relaying through the server.
+//!
+//! The bytes of the packets every node sends through the relay are counted over a rolling
+//! day and a rolling month, across all its connections.  Once a node exceeded one of its
+//! quotas, the server drops its packets until enough of its traffic left the window.  Only
+//! the packets a node sends count against its quotas, so other nodes can not exhaust them,
+//! and disco packets are neither counted nor dropped, so nodes over their quota can still
+//! establish direct connections.
+
+use std::{
+    num::NonZeroU64,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use iroh_base::NodeId;
+use serde::Serialize;
+use tokio::time::Instant;
+use tracing::debug;
+
+/// The length of the buckets of the daily window.
+const HOUR: Duration = Duration::from_secs(60 * 60);
+/// The length of the buckets of the monthly window.
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+/// The number of hourly buckets of the daily window.
+const DAY_BUCKETS: usize = 24;
+/// The number of daily buckets of the monthly window.
+const MONTH_BUCKETS: usize = 30;
+
+/// Byte quotas of every node.
+///
+/// The windows roll over hourly for the daily quota and daily for the monthly quota, so a
+/// node exceeding its quota is held back for at most an hour, or a day, longer than the
+/// exact window.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuotaConfig {
+    /// Max number of bytes a node may send within 24 hours.  Unlimited if not set.
+    pub daily_bytes: Option<NonZeroU64>,
+    /// Max number of bytes a node may send within 30 days.  Unlimited if not set.
+    pub monthly_bytes: Option<NonZeroU64>,
+}
+
+/// The bytes sent by a node within the windows of the quotas, as reported by the admin API.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub(super) struct QuotaUsage {
+    pub(super) node_id: String,
+    /// The bytes sent within the last 24 hours.
+    pub(super) daily_bytes: u64,
+    /// The bytes sent within the last 30 days.
+    pub(super) monthly_bytes: u64,
+    /// Whether the packets of the node are dropped.
+    pub(super) exceeded: bool,
+}
+
+/// Counts the bytes sent by every node against its quotas.
+#[derive(Debug)]
+pub(super) struct Quotas {
+    config: QuotaConfig,
+    /// The start of the first bucket of the windows.
+    start: Instant,
+    usage: DashMap<NodeId, Usage>,
+    /// The last hour the nodes without any traffic in the monthly window were pruned.
+    pruned: AtomicU64,
+}
+
+impl Quotas {
+    pub(super) fn new(config: QuotaConfig) -> Self {
+        Self {
+            config,
+            start: Instant::now(),
+            usage: DashMap::new(),
+            pruned: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts the bytes of a packet sent by the node, returns whether it may be relayed.
+    ///
+    /// Packets are relayed until the node used up one of its quotas, the packet doing so
+    /// included.  The packets of a node over its quota are dropped and not counted.
+    pub(super) fn record(&self, node_id: NodeId, bytes: u64) -> bool {
+        self.record_at(node_id, bytes, self.start.elapsed())
+    }
+
+    fn record_at(&self, node_id: NodeId, bytes: u64, elapsed: Duration) -> bool {
+        let now = Buckets::at(elapsed);
+        self.prune(now);
+        let mut usage = self.usage.entry(node_id).or_default();
+        if self.exhausted(usage.day.total(now.hour), usage.month.total(now.day)) {
+            return false;
+        }
+        usage.day.add(now.hour, bytes);
+        usage.month.add(now.day, bytes);
+        if self.exhausted(usage.day.total(now.hour), usage.month.total(now.day)) {
+            debug!(node = node_id.fmt_short(), "node used up its byte quota");
+        }
+        true
+    }
+
+    /// Whether the bytes sent use up one of the quotas.
+    fn exhausted(&self, daily: u64, monthly: u64) -> bool {
+        let exhausted =
+            |used: u64, quota: Option<NonZeroU64>| quota.is_some_and(|quota| used >= quota.get());
+        exhausted(daily, self.config.daily_bytes) || exhausted(monthly, self.config.monthly_bytes)
+    }
+
+    /// The usage of all nodes which sent packets within the monthly window.
+    pub(super) fn usage(&self) -> Vec<QuotaUsage> {
+        self.usage_at(self.start.elapsed())
+    }
+
+    fn usage_at(&self, elapsed: Duration) -> Vec<QuotaUsage> {
+        let now = Buckets::at(elapsed);
+        let mut usage: Vec<_> = self
+            .usage
+            .iter()
+            .map(|entry| {
+                let daily_bytes = entry.day.total(now.hour);
+                let monthly_bytes = entry.month.total(now.day);
+                QuotaUsage {
+                    node_id: entry.key().to_string(),
+                    daily_bytes,
+                    monthly_bytes,
+                    exceeded: self.exhausted(daily_bytes, monthly_bytes),
+                }
+            })
+            .filter(|usage| usage.monthly_bytes > 0)
+            .collect();
+        usage.sort_by_key(|usage| std::cmp::Reverse(usage.monthly_bytes));
+        usage
+    }
+
+    /// Forgets the bytes sent by the node, returns whether it sent any.
+    pub(super) fn reset(&self, node_id: &NodeId) -> bool {
+        self.usage.remove(node_id).is_some()
+    }
+
+    /// Removes the nodes without any traffic in the monthly window, at most once an hour.
+    fn prune(&self, now: Buckets) {
+        if self.pruned.swap(now.hour, Ordering::Relaxed) == now.hour {
+            return;
+        }
+        self.usage.retain(|_, usage| usage.month.total(now.day) > 0);
+    }
+}
+
+/// The indices of the current buckets of the windows, counted from [`Quotas::start`].
+#[derive(Debug, Clone, Copy)]
+struct Buckets {
+    hour: u64,
+    day: u64,
+}
+
+impl Buckets {
+    fn at(elapsed: Duration) -> Self {
+        Self {
+            hour: elapsed.as_secs() / HOUR.as_secs(),
+            day: elapsed.as_secs() / DAY.as_secs(),
+        }
+    }
+}
+
+/// The bytes sent by a node.
+#[derive(Debug, Default)]
+struct Usage {
+    day: Window<DAY_BUCKETS>,
+    month: Window<MONTH_BUCKETS>,
+}
+
+/// A rolling window of `N` buckets counting bytes.
+#[derive(Debug)]
+struct Window<const N: usize> {
+    /// The index of the newest bucket.
+    newest: u64,
+    buckets: [u64; N],
+}
+
+impl<const N: usize> Default for Window<N> {
+    fn default() -> Self {
+        Self {
+            newest: 0,
+            buckets: [0; N],
+        }
+    }
+}
+
+impl<const N: usize> Window<N> {
+    /// Adds the bytes to the bucket, clearing the buckets which left the window.
+    fn add(&mut self, bucket: u64, bytes: u64) {
+        let stale = bucket.saturating_sub(self.newest).min(N as u64);
+        for index in 1..=stale {
+            self.buckets[((self.newest + index) % N as u64) as usize] = 0;
+        }
+        self.newest = self.newest.max(bucket);
+        self.buckets[(bucket % N as u64) as usize] += bytes;
+    }
+
+    /// The bytes within the window ending with the bucket.
+    fn total(&self, bucket: u64) -> u64 {
+        (0..N as u64)
+            .filter_map(|age| self.newest.checked_sub(age))
+            .filter(|index| index + N as u64 > bucket)
+            .map(|index| self.buckets[(index % N as u64) as usize])
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::SecretKey;
+
+    use super::*;
+
+    #[test]
+    fn test_window() {
+        let mut window = Window::<3>::default();
+        window.add(0, 1);
+        window.add(1, 10);
+        window.add(2, 100);
+        assert_eq!(window.total(2), 111);
+        assert_eq!(window.total(3), 110);
+        window.add(4, 1000);
+        assert_eq!(window.total(4), 1100);
+        assert_eq!(window.total(6), 1000);
+        assert_eq!(window.total(7), 0);
+    }
+
+    #[test]
+    fn test_quotas() {
+        let quotas = Quotas::new(QuotaConfig {
+            daily_bytes: Some(NonZeroU64::new(100).unwrap()),
+            monthly_bytes: Some(NonZeroU64::new(250).unwrap()),
+        });
+        let node = SecretKey::generate(rand::thread_rng()).public();
+        let other = SecretKey::generate(rand::thread_rng()).public();
+
+        assert!(quotas.record_at(node, 60, Duration::ZERO));
+        // The packet using up the quota is still relayed.
+        assert!(quotas.record_at(node, 50, HOUR));
+        assert!(!quotas.record_at(node, 1, HOUR));
+        assert!(quotas.record_at(other, 10, HOUR));
+
+        // The daily quota frees up once the first bytes left the window.
+        assert!(!quotas.record_at(node, 1, DAY - Duration::from_secs(1)));
+        assert!(quotas.record_at(node, 60, DAY));
+        assert!(quotas.record_at(node, 80, DAY * 2));
+        // The monthly quota is used up, even though the daily one is not.
+        assert!(!quotas.record_at(node, 1, DAY * 3));
+
+        let usage = quotas.usage_at(DAY * 3);
+        assert_eq!(usage.len(), 2);
+        assert_eq!(usage[0].node_id, node.to_string());
+        assert!(usage[0].exceeded);
+        assert!(!usage[1].exceeded);
+        assert!(quotas.reset(&node));
+        assert!(quotas.record_at(node, 1, DAY * 3));
+    }
+}
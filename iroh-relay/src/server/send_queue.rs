@@ -0,0 +1,268 @@
+//! The queues of the packets sent to each client.
+//!
+//! The packets for a client are queued until its actor writes them to the connection.  A
+//! client which reads slower than its peers send fills its queue, the [`OverflowPolicy`]
+//! decides what happens to the packets sent to it then.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc::error::TrySendError, Notify};
+
+/// The default number of packets queued for a client.
+pub const DEFAULT_SEND_QUEUE_DEPTH: usize = 512;
+
+/// What happens to a packet sent to a client whose send queue is full.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Drops the packet.
+    #[default]
+    DropNewest,
+    /// Drops the oldest queued packet to make room for the packet.
+    ///
+    /// This favours fresh packets, which are more useful to latency sensitive traffic
+    /// than packets which waited in the queue.
+    DropOldest,
+    /// Drops the packet and disconnects the client, as it can not keep up with its peers.
+    DisconnectClient,
+}
+
+/// Configuration of the queues of the packets sent to each client.
+#[derive(Debug, Clone)]
+pub struct SendQueueConfig {
+    /// The number of packets queued for a client.
+    ///
+    /// Regular and disco packets are queued separately, each queue holds this many.
+    pub depth: usize,
+    /// What happens to the packets sent to a client whose queue is full.
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for SendQueueConfig {
+    fn default() -> Self {
+        Self {
+            depth: DEFAULT_SEND_QUEUE_DEPTH,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+impl SendQueueConfig {
+    pub(super) fn validate(&self) -> Result<()> {
+        ensure!(self.depth > 0, "the send queue depth must not be zero");
+        Ok(())
+    }
+}
+
+/// Creates a send queue holding up to `capacity` items.
+///
+/// Unlike a [`tokio::sync::mpsc`] channel the sender can make room for an item by dropping
+/// the oldest queued one.
+pub(super) fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(capacity),
+            closed: false,
+        }),
+        notify: Notify::new(),
+        capacity,
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+#[derive(Debug)]
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    /// Wakes the receiver once an item is queued or the queue is closed.
+    notify: Notify,
+    capacity: usize,
+}
+
+#[derive(Debug)]
+struct State<T> {
+    items: VecDeque<T>,
+    /// Whether the sender or the receiver is gone.
+    closed: bool,
+}
+
+impl<T> Shared<T> {
+    fn state(&self) -> std::sync::MutexGuard<'_, State<T>> {
+        self.state.lock().expect("poisoned")
+    }
+
+    fn close(&self) {
+        self.state().closed = true;
+        self.notify.notify_one();
+    }
+}
+
+/// The sending half of a send queue, closes the queue when dropped.
+#[derive(Debug)]
+pub(super) struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Queues the item, failing if the queue is full or closed.
+    pub(super) fn try_send(&self, item: T) -> Result<(), TrySendError<T>> {
+        let mut state = self.shared.state();
+        if state.closed {
+            return Err(TrySendError::Closed(item));
+        }
+        if state.items.len() >= self.shared.capacity {
+            return Err(TrySendError::Full(item));
+        }
+        state.items.push_back(item);
+        drop(state);
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+
+    /// Queues the item, dropping the oldest queued item if the queue is full.
+    ///
+    /// Returns the dropped item, or the item itself if the queue is closed.
+    pub(super) fn send_evicting(&self, item: T) -> Result<Option<T>, T> {
+        let mut state = self.shared.state();
+        if state.closed {
+            return Err(item);
+        }
+        let evicted = if state.items.len() >= self.shared.capacity {
+            state.items.pop_front()
+        } else {
+            None
+        };
+        state.items.push_back(item);
+        drop(state);
+        self.shared.notify.notify_one();
+        Ok(evicted)
+    }
+
+    /// The maximum number of queued items.
+    pub(super) fn max_capacity(&self) -> usize {
+        self.shared.capacity
+    }
+
+    /// The number of items which can be queued before the queue is full.
+    pub(super) fn capacity(&self) -> usize {
+        self.shared
+            .capacity
+            .saturating_sub(self.shared.state().items.len())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+/// The receiving half of a send queue, closes the queue when dropped.
+#[derive(Debug)]
+pub(super) struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Receives the next item, `None` once the queue is closed and drained.
+    ///
+    /// Cancel safe, an item is only removed from the queue when it is returned.
+    pub(super) async fn recv(&mut self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.shared.state();
+                if let Some(item) = state.items.pop_front() {
+                    return Some(item);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            // A notification sent since the state was checked is kept as a permit.
+            self.shared.notify.notified().await;
+        }
+    }
+
+    /// Receives the next item if one is queued.
+    pub(super) fn try_recv(&mut self) -> Option<T> {
+        self.shared.state().items.pop_front()
+    }
+
+    /// Closes the queue, no further items are queued.  Queued items can still be received.
+    pub(super) fn close(&mut self) {
+        self.shared.close();
+    }
+
+    /// The number of queued items.
+    pub(super) fn len(&self) -> usize {
+        self.shared.state().items.len()
+    }
+
+    /// The maximum number of queued items.
+    pub(super) fn max_capacity(&self) -> usize {
+        self.shared.capacity
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_send_queue() {
+        let (tx, mut rx) = channel(2);
+        tx.try_send(1).unwrap();
+        tx.try_send(2).unwrap();
+        assert!(matches!(tx.try_send(3), Err(TrySendError::Full(3))));
+        assert_eq!(tx.capacity(), 0);
+
+        // Evicting makes room for the newest item.
+        assert_eq!(tx.send_evicting(4), Ok(Some(1)));
+        assert_eq!(rx.len(), 2);
+        assert_eq!(rx.recv().await, Some(2));
+        assert_eq!(tx.send_evicting(5), Ok(None));
+        assert_eq!(rx.recv().await, Some(4));
+        assert_eq!(rx.recv().await, Some(5));
+
+        // The receiver is woken by items queued while it waits.
+        let recv = tokio::spawn(async move {
+            let item = rx.recv().await;
+            (item, rx)
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        tx.try_send(6).unwrap();
+        let (item, mut rx) = recv.await.unwrap();
+        assert_eq!(item, Some(6));
+
+        // Queued items are still received after the queue is closed.
+        tx.try_send(7).unwrap();
+        rx.close();
+        assert!(matches!(tx.try_send(8), Err(TrySendError::Closed(8))));
+        assert_eq!(tx.send_evicting(9), Err(9));
+        assert_eq!(rx.recv().await, Some(7));
+        assert_eq!(rx.recv().await, None);
+
+        let (tx, mut rx) = channel::<u8>(2);
+        drop(tx);
+        assert_eq!(rx.recv().await, None);
+    }
+}
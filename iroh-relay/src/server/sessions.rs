@@ -0,0 +1,134 @@
+//! Resumption of the sessions of clients reconnecting after a brief disconnect.
+//!
+//! Every client requesting it is issued a [`SessionToken`] when it connects.  Reconnecting
+//! with the token resumes its session: the client is authorized again, but keeps the rate
+//! limit it was granted unless the authorizer grants a new one.  When the connection of a client with a session breaks, its
+//! peers are only told that it is gone once the grace period passed without the client
+//! reconnecting, which hides brief connectivity problems from them.
+
+use std::time::Duration;
+
+use dashmap::DashMap;
+use iroh_base::NodeId;
+use tracing::trace;
+
+use super::ClientRateLimit;
+use crate::protos::relay::SessionToken;
+
+/// The default of [`SessionConfig::grace_period`].
+pub const DEFAULT_SESSION_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Configuration of the session resumption.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// How long a session can be resumed after the connection of the client broke.
+    ///
+    /// The peers of the client are told that it is gone once it did not reconnect within
+    /// this period.
+    pub grace_period: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            grace_period: DEFAULT_SESSION_GRACE_PERIOD,
+        }
+    }
+}
+
+/// The sessions of the clients, connected or within their grace period.
+#[derive(Debug)]
+pub(super) struct Sessions {
+    grace_period: Duration,
+    sessions: DashMap<NodeId, Session>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Session {
+    token: SessionToken,
+    /// The rate limit granted by the authorizer.
+    rate_limit: Option<ClientRateLimit>,
+}
+
+impl Sessions {
+    pub(super) fn new(config: SessionConfig) -> Self {
+        Self {
+            grace_period: config.grace_period,
+            sessions: DashMap::new(),
+        }
+    }
+
+    pub(super) fn grace_period(&self) -> Duration {
+        self.grace_period
+    }
+
+    /// Resumes the session of the node if the token is valid.
+    ///
+    /// Returns the rate limit granted to the session.  A session is resumed at most once,
+    /// the client is issued a new token with [`Sessions::start`].
+    pub(super) fn resume(
+        &self,
+        node_id: NodeId,
+        token: SessionToken,
+    ) -> Option<Option<ClientRateLimit>> {
+        let (_, session) = self
+            .sessions
+            .remove_if(&node_id, |_, session| session.token == token)?;
+        trace!(node = node_id.fmt_short(), "resuming session");
+        Some(session.rate_limit)
+    }
+
+    /// Starts a new session of the node, replacing its previous one.
+    pub(super) fn start(
+        &self,
+        node_id: NodeId,
+        rate_limit: Option<ClientRateLimit>,
+    ) -> SessionToken {
+        let token = SessionToken::generate();
+        self.sessions.insert(node_id, Session { token, rate_limit });
+        token
+    }
+
+    /// Ends the session of the node, unless it was replaced by a new one.
+    ///
+    /// Returns whether the session was ended.
+    pub(super) fn end(&self, node_id: NodeId, token: SessionToken) -> bool {
+        self.sessions
+            .remove_if(&node_id, |_, session| session.token == token)
+            .is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::SecretKey;
+
+    use super::*;
+
+    #[test]
+    fn test_sessions() {
+        let sessions = Sessions::new(SessionConfig::default());
+        let node = SecretKey::generate(rand::thread_rng()).public();
+        let rate_limit = ClientRateLimit {
+            bytes_per_second: 1000.try_into().unwrap(),
+            max_burst_bytes: None,
+        };
+        assert!(sessions.resume(node, SessionToken::generate()).is_none());
+
+        let first = sessions.start(node, Some(rate_limit));
+        assert!(sessions.resume(node, SessionToken::generate()).is_none());
+        let resumed = sessions.resume(node, first).expect("valid token");
+        assert_eq!(
+            resumed.map(|limit| limit.bytes_per_second),
+            Some(rate_limit.bytes_per_second)
+        );
+        // Tokens are only valid once.
+        assert!(sessions.resume(node, first).is_none());
+
+        let second = sessions.start(node, None);
+        let third = sessions.start(node, None);
+        assert!(!sessions.end(node, second));
+        assert!(sessions.end(node, third));
+        assert!(sessions.resume(node, third).is_none());
+    }
+}
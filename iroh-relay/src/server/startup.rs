@@ -0,0 +1,110 @@
+//! The report describing how a [`Server`](super::Server) started.
+//!
+//! The report is logged once the server started and is available from
+//! [`Server::startup_report`](super::Server::startup_report), so orchestration can verify a
+//! deployment without parsing the logs.
+
+use std::{fmt::Write, net::SocketAddr};
+
+use rustls::pki_types::CertificateDer;
+use serde::Serialize;
+
+pub use super::http_server::TlsMode;
+
+/// Describes the services of a started [`Server`](super::Server).
+#[derive(Debug, Clone, Serialize)]
+pub struct StartupReport {
+    /// The addresses the services are bound to.
+    pub listeners: ListenerAddrs,
+    /// The TLS setup of the relay server, `None` when serving plain HTTP.
+    pub tls: Option<TlsReport>,
+    /// The services and features which are enabled.
+    pub features: Features,
+    /// The effective limits of the relay server, `null` if the relay server is disabled.
+    ///
+    /// The same limits as reported by the `GET /admin/config` endpoint of the admin API.
+    pub limits: serde_json::Value,
+}
+
+/// The addresses the services of a server are bound to.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ListenerAddrs {
+    /// The address of the HTTP server.
+    ///
+    /// This serves the relay itself unless TLS is configured, then it only serves the
+    /// captive portal detection.
+    pub http: Option<SocketAddr>,
+    /// The address of the HTTPS server, if TLS is configured.
+    pub https: Option<SocketAddr>,
+    /// The addresses of the STUN server.
+    pub stun: Vec<SocketAddr>,
+    /// The address of the QUIC address discovery server.
+    pub quic: Option<SocketAddr>,
+    /// The address of the WebTransport endpoint.
+    pub webtransport: Option<SocketAddr>,
+    /// The address the Prometheus metrics are served on.
+    pub metrics: Option<SocketAddr>,
+}
+
+/// The TLS setup of a relay server.
+#[derive(Debug, Clone, Serialize)]
+pub struct TlsReport {
+    /// How the certificates are obtained.
+    pub mode: TlsMode,
+    /// The SHA-256 fingerprints of the certificate chain, leaf first.
+    ///
+    /// Only known for [`TlsMode::Manual`], the other certificates are obtained after the
+    /// server started.
+    pub cert_fingerprints: Vec<String>,
+    /// Whether the clients must present a TLS client certificate.
+    pub client_auth: bool,
+}
+
+/// The services and features enabled on a server.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Features {
+    /// Whether the relay server is running.
+    pub relay: bool,
+    /// Whether the STUN server is running.
+    pub stun: bool,
+    /// Whether the captive portal detection is served.
+    pub captive_portal: bool,
+    /// Whether the admin API is enabled.
+    pub admin_api: bool,
+    /// Whether the QUIC address discovery server is running.
+    pub quic: bool,
+    /// Whether the relay is served over WebTransport.
+    pub webtransport: bool,
+    /// Whether the Prometheus metrics are served.
+    pub metrics: bool,
+    /// Whether the relay server is part of a mesh.
+    pub mesh: bool,
+}
+
+/// Returns the SHA-256 fingerprint of a certificate, as colon separated hex bytes.
+pub(super) fn cert_fingerprint(cert: &CertificateDer<'_>) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert);
+    let mut fingerprint = String::with_capacity(digest.as_ref().len() * 3);
+    for (i, byte) in digest.as_ref().iter().enumerate() {
+        if i > 0 {
+            fingerprint.push(':');
+        }
+        write!(fingerprint, "{byte:02X}").expect("writing to a string");
+    }
+    fingerprint
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cert_fingerprint() {
+        let cert = CertificateDer::from(b"not a certificate".to_vec());
+        assert_eq!(
+            cert_fingerprint(&cert),
+            "47:20:9C:9B:7A:F8:39:DE:69:E9:A9:CD:62:5E:91:82:\
+             C1:AD:63:DA:E7:9E:D8:8A:2D:D6:80:FE:34:21:86:20"
+        );
+    }
+}
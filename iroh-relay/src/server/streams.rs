@@ -1,28 +1,287 @@
 //! Streams used in the server-side implementation of iroh relays.
 
 use std::{
+    io::IoSlice,
     pin::Pin,
-    task::{Context, Poll},
+    task::{ready, Context, Poll},
 };
 
 use anyhow::Result;
+use bytes::Bytes;
+use iroh_base::{inc, inc_by, NodeId, PublicKey};
 use n0_future::{Sink, Stream};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_tungstenite::{tungstenite, WebSocketStream};
 use tokio_util::codec::Framed;
 
 use crate::{
-    protos::relay::{Frame, RelayCodec},
+    http::Protocol,
+    protos::{
+        relay::{Frame, FrameType, RelayCodec, RECV_HEADER_LEN},
+        webtransport::WebTransportStream,
+    },
+    server::{http_server::ConnectionPermit, metrics::Metrics},
     KeyCache,
 };
 
+/// Packets of at least this size are written using vectored IO.
+///
+/// Smaller packets are copied into the write buffer, which allows to write several of them
+/// at once.
+const VECTORED_WRITE_MIN_LEN: usize = 512;
+
+/// Length of the longest websocket frame header, of a message longer than 64 KiB.
+const MAX_WS_HEADER_LEN: usize = 10;
+
+/// Length of the longest header of a [`VectoredWrite`], the websocket frame header
+/// followed by the frame type and the source key.
+const MAX_VECTORED_HEADER_LEN: usize = MAX_WS_HEADER_LEN + 1 + PublicKey::LENGTH;
+
 /// A Stream and Sink for [`Frame`]s connected to a single relay client.
 ///
 /// The stream receives message from the client while the sink sends them to the client.
 #[derive(Debug)]
 pub(crate) enum RelayedStream {
-    Relay(Framed<MaybeTlsStream, RelayCodec>),
-    Ws(WebSocketStream<MaybeTlsStream>, KeyCache),
+    /// The relay framing, over [`Protocol::Relay`] or [`Protocol::WebTransport`].
+    Relay {
+        framed: Framed<MaybeTlsStream, RelayCodec>,
+        /// A packet being written directly to the stream, bypassing the write buffer.
+        pending: Option<VectoredWrite>,
+    },
+    Ws {
+        stream: WebSocketStream<MaybeTlsStream>,
+        key_cache: KeyCache,
+        /// Whether sent frames are checksummed.
+        checksums: bool,
+        /// Whether the client sent a checksummed frame.
+        peer_checksums: bool,
+        /// A packet being written directly to the stream as a websocket message, bypassing
+        /// the write buffer of the websocket.
+        pending: Option<VectoredWrite>,
+    },
+}
+
+/// A `FrameType::RecvPacket` or `FrameType::RecvFragment` frame which is written using
+/// vectored IO.
+///
+/// This avoids copying the packet content into the write buffer of the [`Framed`] or the
+/// [`WebSocketStream`], so a forwarded packet is never copied after it was received.
+#[derive(Debug)]
+pub(crate) struct VectoredWrite {
+    header: [u8; MAX_VECTORED_HEADER_LEN],
+    header_len: usize,
+    content: Bytes,
+    /// Number of bytes already written, of the header followed by the content.
+    written: usize,
+}
+
+impl VectoredWrite {
+    /// Writes the frame in the relay framing.
+    fn relay(frame_type: FrameType, src_key: &NodeId, content: Bytes) -> std::io::Result<Self> {
+        let relay_header = RelayCodec::encode_recv_header(frame_type, src_key, content.len())?;
+        let mut header = [0u8; MAX_VECTORED_HEADER_LEN];
+        header[..RECV_HEADER_LEN].copy_from_slice(&relay_header);
+        Ok(Self {
+            header,
+            header_len: RECV_HEADER_LEN,
+            content,
+            written: 0,
+        })
+    }
+
+    /// Writes the frame as a binary websocket message.
+    ///
+    /// The message is a single unmasked frame, as sent by servers.
+    fn ws(frame_type: FrameType, src_key: &NodeId, content: Bytes) -> Self {
+        let payload_len = 1 + PublicKey::LENGTH + content.len();
+        let mut header = [0u8; MAX_VECTORED_HEADER_LEN];
+        // The FIN bit and the binary opcode.
+        header[0] = 0x82;
+        let mut header_len = match u16::try_from(payload_len) {
+            Ok(len @ 0..=125) => {
+                header[1] = len as u8;
+                2
+            }
+            Ok(len) => {
+                header[1] = 126;
+                header[2..4].copy_from_slice(&len.to_be_bytes());
+                4
+            }
+            Err(_) => {
+                header[1] = 127;
+                header[2..10].copy_from_slice(&(payload_len as u64).to_be_bytes());
+                MAX_WS_HEADER_LEN
+            }
+        };
+        header[header_len] = frame_type.into();
+        header_len += 1;
+        header[header_len..header_len + PublicKey::LENGTH].copy_from_slice(src_key.as_bytes());
+        header_len += PublicKey::LENGTH;
+        Self {
+            header,
+            header_len,
+            content,
+            written: 0,
+        }
+    }
+
+    /// Returns the parts of the frame if its content is written using vectored IO.
+    fn split(frame: &Frame) -> Option<(FrameType, NodeId, Bytes)> {
+        match frame {
+            Frame::RecvPacket { src_key, content } if content.len() >= VECTORED_WRITE_MIN_LEN => {
+                Some((FrameType::RecvPacket, *src_key, content.clone()))
+            }
+            Frame::RecvFragment { src_key, fragment }
+                if fragment.len() >= VECTORED_WRITE_MIN_LEN =>
+            {
+                Some((FrameType::RecvFragment, *src_key, fragment.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    fn is_started(&self) -> bool {
+        self.written > 0
+    }
+
+    fn is_done(&self) -> bool {
+        self.written == self.header_len + self.content.len()
+    }
+
+    fn slices(&self) -> [IoSlice<'_>; 2] {
+        let header_written = self.written.min(self.header_len);
+        let content_written = self.written - header_written;
+        [
+            IoSlice::new(&self.header[header_written..self.header_len]),
+            IoSlice::new(&self.content[content_written..]),
+        ]
+    }
+
+    /// Writes the remainder of the frame to `io`.
+    fn poll_write(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut io: Pin<&mut MaybeTlsStream>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        while !self.is_done() {
+            let n = ready!(io.as_mut().poll_write_vectored(cx, &self.slices()))?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl RelayedStream {
+    /// Creates a stream using the relay framing.
+    pub(crate) fn relay(io: MaybeTlsStream, codec: RelayCodec) -> Self {
+        Self::Relay {
+            framed: Framed::new(io, codec),
+            pending: None,
+        }
+    }
+
+    /// Creates a stream using websocket messages as framing.
+    pub(crate) fn ws(stream: WebSocketStream<MaybeTlsStream>, key_cache: KeyCache) -> Self {
+        Self::Ws {
+            stream,
+            key_cache,
+            checksums: false,
+            peer_checksums: false,
+            pending: None,
+        }
+    }
+
+    /// Returns the protocol of the connection.
+    pub(crate) fn protocol(&self) -> Protocol {
+        match self {
+            Self::Relay { framed, .. } => match framed.get_ref().is_webtransport() {
+                true => Protocol::WebTransport,
+                false => Protocol::Relay,
+            },
+            Self::Ws { .. } => Protocol::Websocket,
+        }
+    }
+
+    /// Counts a frame in the metrics of the protocol.
+    fn record_frame(&self, frame: &Frame, sent: bool) {
+        let len = frame.len() as u64;
+        match (self.protocol(), sent) {
+            (Protocol::Relay, true) => {
+                inc!(Metrics, relay_frames_sent);
+                inc_by!(Metrics, relay_frame_bytes_sent, len);
+            }
+            (Protocol::Relay, false) => {
+                inc!(Metrics, relay_frames_recv);
+                inc_by!(Metrics, relay_frame_bytes_recv, len);
+            }
+            (Protocol::Websocket, true) => {
+                inc!(Metrics, websocket_frames_sent);
+                inc_by!(Metrics, websocket_frame_bytes_sent, len);
+            }
+            (Protocol::Websocket, false) => {
+                inc!(Metrics, websocket_frames_recv);
+                inc_by!(Metrics, websocket_frame_bytes_recv, len);
+            }
+            (Protocol::WebTransport, true) => {
+                inc!(Metrics, webtransport_frames_sent);
+                inc_by!(Metrics, webtransport_frame_bytes_sent, len);
+            }
+            (Protocol::WebTransport, false) => {
+                inc!(Metrics, webtransport_frames_recv);
+                inc_by!(Metrics, webtransport_frame_bytes_recv, len);
+            }
+        }
+    }
+
+    /// Returns whether the underlying connection uses TLS.
+    pub(crate) fn is_tls(&self) -> bool {
+        match self {
+            Self::Relay { framed, .. } => framed.get_ref().is_tls(),
+            Self::Ws { stream, .. } => stream.get_ref().is_tls(),
+        }
+    }
+
+    /// Checksums all further sent frames.
+    pub(crate) fn enable_checksums(&mut self) {
+        match self {
+            Self::Relay { framed, .. } => framed.codec_mut().enable_checksums(),
+            Self::Ws { checksums, .. } => *checksums = true,
+        }
+    }
+
+    /// Writes the pending vectored write, if any.
+    ///
+    /// Anything remaining in the write buffer is written first, to keep the frames in order.
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        match self {
+            Self::Relay { framed, pending } => {
+                let Some(write) = pending else {
+                    return Poll::Ready(Ok(()));
+                };
+                if !write.is_started() && !framed.write_buffer().is_empty() {
+                    ready!(Pin::new(&mut *framed).poll_flush(cx))?;
+                }
+                ready!(write.poll_write(cx, Pin::new(framed.get_mut())))?;
+                *pending = None;
+            }
+            Self::Ws {
+                stream, pending, ..
+            } => {
+                let Some(write) = pending else {
+                    return Poll::Ready(Ok(()));
+                };
+                if !write.is_started() {
+                    ready!(Pin::new(&mut *stream).poll_flush(cx)).map_err(tung_to_io_err)?;
+                }
+                ready!(write.poll_write(cx, Pin::new(stream.get_mut())))?;
+                *pending = None;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
 }
 
 fn tung_to_io_err(e: tungstenite::Error) -> std::io::Error {
@@ -36,54 +295,136 @@ impl Sink<Frame> for RelayedStream {
     type Error = std::io::Error;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_write_pending(cx))?;
         match *self {
-            Self::Relay(ref mut framed) => Pin::new(framed).poll_ready(cx),
-            Self::Ws(ref mut ws, _) => Pin::new(ws).poll_ready(cx).map_err(tung_to_io_err),
+            Self::Relay { ref mut framed, .. } => Pin::new(framed).poll_ready(cx),
+            Self::Ws { ref mut stream, .. } => {
+                Pin::new(stream).poll_ready(cx).map_err(tung_to_io_err)
+            }
         }
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: Frame) -> Result<(), Self::Error> {
+        self.record_frame(&item, true);
         match *self {
-            Self::Relay(ref mut framed) => Pin::new(framed).start_send(item),
-            Self::Ws(ref mut ws, _) => Pin::new(ws)
-                .start_send(tungstenite::Message::Binary(item.encode_for_ws_msg()))
-                .map_err(tung_to_io_err),
+            Self::Relay {
+                ref mut framed,
+                ref mut pending,
+            } => {
+                check_not_pending(pending)?;
+                // The vectored write does not support checksums.
+                if framed.codec().checksums() {
+                    return Pin::new(framed).start_send(item);
+                }
+                match VectoredWrite::split(&item) {
+                    Some((frame_type, src_key, content)) => {
+                        *pending = Some(VectoredWrite::relay(frame_type, &src_key, content)?);
+                        Ok(())
+                    }
+                    None => Pin::new(framed).start_send(item),
+                }
+            }
+            Self::Ws {
+                ref mut stream,
+                checksums,
+                ref mut pending,
+                ..
+            } => {
+                check_not_pending(pending)?;
+                if !checksums {
+                    if let Some((frame_type, src_key, content)) = VectoredWrite::split(&item) {
+                        *pending = Some(VectoredWrite::ws(frame_type, &src_key, content));
+                        return Ok(());
+                    }
+                }
+                Pin::new(stream)
+                    .start_send(tungstenite::Message::Binary(
+                        item.encode_for_ws_msg(checksums),
+                    ))
+                    .map_err(tung_to_io_err)
+            }
         }
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_write_pending(cx))?;
         match *self {
-            Self::Relay(ref mut framed) => Pin::new(framed).poll_flush(cx),
-            Self::Ws(ref mut ws, _) => Pin::new(ws).poll_flush(cx).map_err(tung_to_io_err),
+            Self::Relay { ref mut framed, .. } => Pin::new(framed).poll_flush(cx),
+            Self::Ws { ref mut stream, .. } => {
+                Pin::new(stream).poll_flush(cx).map_err(tung_to_io_err)
+            }
         }
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_write_pending(cx))?;
         match *self {
-            Self::Relay(ref mut framed) => Pin::new(framed).poll_close(cx),
-            Self::Ws(ref mut ws, _) => Pin::new(ws).poll_close(cx).map_err(tung_to_io_err),
+            Self::Relay { ref mut framed, .. } => Pin::new(framed).poll_close(cx),
+            Self::Ws { ref mut stream, .. } => {
+                Pin::new(stream).poll_close(cx).map_err(tung_to_io_err)
+            }
         }
     }
 }
 
+/// Errors if a vectored write is still pending, which `poll_ready` completes.
+fn check_not_pending(pending: &Option<VectoredWrite>) -> Result<(), std::io::Error> {
+    match pending {
+        Some(_) => Err(std::io::Error::other(
+            "start_send called without poll_ready",
+        )),
+        None => Ok(()),
+    }
+}
+
 impl Stream for RelayedStream {
     type Item = anyhow::Result<Frame>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let frame = ready!(self.poll_next_frame(cx));
+        if let Some(Ok(ref frame)) = frame {
+            self.record_frame(frame, false);
+        }
+        Poll::Ready(frame)
+    }
+}
+
+impl RelayedStream {
+    fn poll_next_frame(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Frame>>> {
         match *self {
-            Self::Relay(ref mut framed) => Pin::new(framed).poll_next(cx),
-            Self::Ws(ref mut ws, ref cache) => match Pin::new(ws).poll_next(cx) {
-                Poll::Ready(Some(Ok(tungstenite::Message::Binary(vec)))) => {
-                    Poll::Ready(Some(Frame::decode_from_ws_msg(vec, cache)))
+            Self::Relay { ref mut framed, .. } => Pin::new(framed).poll_next(cx),
+            Self::Ws {
+                ref mut stream,
+                ref key_cache,
+                ref mut checksums,
+                ref mut peer_checksums,
+                ref mut pending,
+            } => {
+                // Reading may write replies to websocket pings, which must not end up in
+                // the middle of a partially written message.
+                if let Some(write) = pending.as_mut().filter(|write| write.is_started()) {
+                    ready!(write.poll_write(cx, Pin::new(stream.get_mut())))?;
+                    *pending = None;
                 }
-                Poll::Ready(Some(Ok(msg))) => {
-                    tracing::warn!(?msg, "Got websocket message of unsupported type, skipping.");
-                    Poll::Pending
+                match Pin::new(stream).poll_next(cx) {
+                    Poll::Ready(Some(Ok(tungstenite::Message::Binary(vec)))) => {
+                        let frame = Frame::decode_from_ws_msg(vec, key_cache, peer_checksums);
+                        // The peer supports checksums, protect our frames as well.
+                        *checksums |= *peer_checksums;
+                        Poll::Ready(Some(frame))
+                    }
+                    Poll::Ready(Some(Ok(msg))) => {
+                        tracing::warn!(
+                            ?msg,
+                            "Got websocket message of unsupported type, skipping."
+                        );
+                        Poll::Pending
+                    }
+                    Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
+                    Poll::Ready(None) => Poll::Ready(None),
+                    Poll::Pending => Poll::Pending,
                 }
-                Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
-                Poll::Ready(None) => Poll::Ready(None),
-                Poll::Pending => Poll::Pending,
-            },
+            }
         }
     }
 }
@@ -97,9 +438,96 @@ pub enum MaybeTlsStream {
     Plain(tokio::net::TcpStream),
     /// A Tls wrapped [`tokio::net::TcpStream`]
     Tls(tokio_rustls::server::TlsStream<tokio::net::TcpStream>),
+    /// A stream of an HTTP/2 connection, upgraded with an extended `CONNECT` request.
+    Multiplexed(Box<hyper_util::rt::TokioIo<hyper::upgrade::Upgraded>>),
+    /// The relay stream of a WebTransport session.
+    WebTransport(Box<WebTransportStream>),
+    /// A stream counted by the connection limit of the server, which holds the permit.
+    Limited {
+        /// The counted stream.
+        stream: Box<MaybeTlsStream>,
+        /// The permit freeing the slot of the connection when dropped.
+        _permit: ConnectionPermit,
+    },
     /// An in-memory bidirectional pipe.
     #[cfg(test)]
     Test(tokio::io::DuplexStream),
+    /// A stream injecting faults.
+    #[cfg(any(test, feature = "test-utils"))]
+    Faulty(Box<crate::faults::FaultyStream<MaybeTlsStream>>),
+}
+
+impl MaybeTlsStream {
+    /// Holds the connection limit permit, if any, for as long as the stream is open.
+    pub(crate) fn with_permit(self, permit: Option<ConnectionPermit>) -> Self {
+        match permit {
+            Some(permit) => MaybeTlsStream::Limited {
+                stream: Box::new(self),
+                _permit: permit,
+            },
+            None => self,
+        }
+    }
+
+    /// Returns whether the stream is the relay stream of a WebTransport session.
+    fn is_webtransport(&self) -> bool {
+        match self {
+            MaybeTlsStream::WebTransport(_) => true,
+            MaybeTlsStream::Limited { stream, .. } => stream.is_webtransport(),
+            #[cfg(any(test, feature = "test-utils"))]
+            MaybeTlsStream::Faulty(s) => s.get_ref().is_webtransport(),
+            _ => false,
+        }
+    }
+
+    /// Returns whether the stream uses TLS.
+    pub(crate) fn is_tls(&self) -> bool {
+        match self {
+            MaybeTlsStream::Plain(_) => false,
+            MaybeTlsStream::Tls(_) => true,
+            // HTTP/2 is only negotiated with TLS.
+            MaybeTlsStream::Multiplexed(_) => true,
+            // QUIC always uses TLS.
+            MaybeTlsStream::WebTransport(_) => true,
+            MaybeTlsStream::Limited { stream, .. } => stream.is_tls(),
+            #[cfg(test)]
+            MaybeTlsStream::Test(_) => false,
+            #[cfg(any(test, feature = "test-utils"))]
+            MaybeTlsStream::Faulty(s) => s.get_ref().is_tls(),
+        }
+    }
+
+    /// Returns the certificate chain the client presented during the TLS handshake, if any.
+    pub(crate) fn peer_certificates(
+        &self,
+    ) -> Option<&[rustls::pki_types::CertificateDer<'static>]> {
+        match self {
+            MaybeTlsStream::Plain(_) => None,
+            MaybeTlsStream::Tls(s) => s.get_ref().1.peer_certificates(),
+            MaybeTlsStream::Multiplexed(_) => None,
+            MaybeTlsStream::WebTransport(_) => None,
+            MaybeTlsStream::Limited { stream, .. } => stream.peer_certificates(),
+            #[cfg(test)]
+            MaybeTlsStream::Test(_) => None,
+            #[cfg(any(test, feature = "test-utils"))]
+            MaybeTlsStream::Faulty(s) => s.get_ref().peer_certificates(),
+        }
+    }
+
+    /// Returns the ALPN protocol negotiated during the TLS handshake, if any.
+    pub(crate) fn alpn_protocol(&self) -> Option<&[u8]> {
+        match self {
+            MaybeTlsStream::Plain(_) => None,
+            MaybeTlsStream::Tls(s) => s.get_ref().1.alpn_protocol(),
+            MaybeTlsStream::Multiplexed(_) => None,
+            MaybeTlsStream::WebTransport(_) => None,
+            MaybeTlsStream::Limited { stream, .. } => stream.alpn_protocol(),
+            #[cfg(test)]
+            MaybeTlsStream::Test(_) => None,
+            #[cfg(any(test, feature = "test-utils"))]
+            MaybeTlsStream::Faulty(s) => s.get_ref().alpn_protocol(),
+        }
+    }
 }
 
 impl AsyncRead for MaybeTlsStream {
@@ -111,8 +539,15 @@ impl AsyncRead for MaybeTlsStream {
         match &mut *self {
             MaybeTlsStream::Plain(ref mut s) => Pin::new(s).poll_read(cx, buf),
             MaybeTlsStream::Tls(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            MaybeTlsStream::Multiplexed(ref mut s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+            MaybeTlsStream::WebTransport(ref mut s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+            MaybeTlsStream::Limited { ref mut stream, .. } => {
+                Pin::new(stream.as_mut()).poll_read(cx, buf)
+            }
             #[cfg(test)]
             MaybeTlsStream::Test(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(any(test, feature = "test-utils"))]
+            MaybeTlsStream::Faulty(ref mut s) => Pin::new(s.as_mut()).poll_read(cx, buf),
         }
     }
 }
@@ -125,8 +560,15 @@ impl AsyncWrite for MaybeTlsStream {
         match &mut *self {
             MaybeTlsStream::Plain(ref mut s) => Pin::new(s).poll_flush(cx),
             MaybeTlsStream::Tls(ref mut s) => Pin::new(s).poll_flush(cx),
+            MaybeTlsStream::Multiplexed(ref mut s) => Pin::new(s.as_mut()).poll_flush(cx),
+            MaybeTlsStream::WebTransport(ref mut s) => Pin::new(s.as_mut()).poll_flush(cx),
+            MaybeTlsStream::Limited { ref mut stream, .. } => {
+                Pin::new(stream.as_mut()).poll_flush(cx)
+            }
             #[cfg(test)]
             MaybeTlsStream::Test(ref mut s) => Pin::new(s).poll_flush(cx),
+            #[cfg(any(test, feature = "test-utils"))]
+            MaybeTlsStream::Faulty(ref mut s) => Pin::new(s.as_mut()).poll_flush(cx),
         }
     }
 
@@ -137,8 +579,15 @@ impl AsyncWrite for MaybeTlsStream {
         match &mut *self {
             MaybeTlsStream::Plain(ref mut s) => Pin::new(s).poll_shutdown(cx),
             MaybeTlsStream::Tls(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            MaybeTlsStream::Multiplexed(ref mut s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+            MaybeTlsStream::WebTransport(ref mut s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+            MaybeTlsStream::Limited { ref mut stream, .. } => {
+                Pin::new(stream.as_mut()).poll_shutdown(cx)
+            }
             #[cfg(test)]
             MaybeTlsStream::Test(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(any(test, feature = "test-utils"))]
+            MaybeTlsStream::Faulty(ref mut s) => Pin::new(s.as_mut()).poll_shutdown(cx),
         }
     }
 
@@ -150,8 +599,15 @@ impl AsyncWrite for MaybeTlsStream {
         match &mut *self {
             MaybeTlsStream::Plain(ref mut s) => Pin::new(s).poll_write(cx, buf),
             MaybeTlsStream::Tls(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            MaybeTlsStream::Multiplexed(ref mut s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+            MaybeTlsStream::WebTransport(ref mut s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+            MaybeTlsStream::Limited { ref mut stream, .. } => {
+                Pin::new(stream.as_mut()).poll_write(cx, buf)
+            }
             #[cfg(test)]
             MaybeTlsStream::Test(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(any(test, feature = "test-utils"))]
+            MaybeTlsStream::Faulty(ref mut s) => Pin::new(s.as_mut()).poll_write(cx, buf),
         }
     }
 
@@ -163,8 +619,137 @@ impl AsyncWrite for MaybeTlsStream {
         match &mut *self {
             MaybeTlsStream::Plain(ref mut s) => Pin::new(s).poll_write_vectored(cx, bufs),
             MaybeTlsStream::Tls(ref mut s) => Pin::new(s).poll_write_vectored(cx, bufs),
+            MaybeTlsStream::Multiplexed(ref mut s) => {
+                Pin::new(s.as_mut()).poll_write_vectored(cx, bufs)
+            }
+            MaybeTlsStream::WebTransport(ref mut s) => {
+                Pin::new(s.as_mut()).poll_write_vectored(cx, bufs)
+            }
+            MaybeTlsStream::Limited { ref mut stream, .. } => {
+                Pin::new(stream.as_mut()).poll_write_vectored(cx, bufs)
+            }
             #[cfg(test)]
             MaybeTlsStream::Test(ref mut s) => Pin::new(s).poll_write_vectored(cx, bufs),
+            #[cfg(any(test, feature = "test-utils"))]
+            MaybeTlsStream::Faulty(ref mut s) => Pin::new(s.as_mut()).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            MaybeTlsStream::Plain(s) => s.is_write_vectored(),
+            MaybeTlsStream::Tls(s) => s.is_write_vectored(),
+            MaybeTlsStream::Multiplexed(s) => s.is_write_vectored(),
+            MaybeTlsStream::WebTransport(s) => s.is_write_vectored(),
+            MaybeTlsStream::Limited { stream, .. } => stream.is_write_vectored(),
+            #[cfg(test)]
+            MaybeTlsStream::Test(s) => s.is_write_vectored(),
+            #[cfg(any(test, feature = "test-utils"))]
+            MaybeTlsStream::Faulty(s) => s.is_write_vectored(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context as _;
+    use iroh_base::SecretKey;
+    use n0_future::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+    use tokio_util::codec::FramedRead;
+
+    use super::*;
+    use crate::protos::relay::MAX_PACKET_SIZE;
+
+    #[tokio::test]
+    async fn test_relay_vectored_writes_keep_order() -> Result<()> {
+        let (io, io_rw) = tokio::io::duplex(1024);
+        let mut stream = RelayedStream::relay(MaybeTlsStream::Test(io), RelayCodec::test());
+        let mut reader = FramedRead::new(io_rw, RelayCodec::test());
+
+        let src_key = SecretKey::generate(rand::thread_rng()).public();
+        let frames: Vec<_> = [10, VECTORED_WRITE_MIN_LEN, 20, 30, 4000, 5000, 40]
+            .into_iter()
+            .enumerate()
+            .map(|(i, len)| Frame::RecvPacket {
+                src_key,
+                content: vec![i as u8; len].into(),
+            })
+            .collect();
+
+        let expected = frames.clone();
+        let writer = tokio::spawn(async move {
+            for frame in frames {
+                stream.feed(frame).await?;
+            }
+            stream.flush().await?;
+            anyhow::Ok(stream)
+        });
+        for frame in expected {
+            assert_eq!(reader.next().await.unwrap()?, frame);
+        }
+        let mut stream = writer.await??;
+        stream.close().await?;
+        assert!(reader.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ws_vectored_writes_keep_order() -> Result<()> {
+        let (io, io_rw) = tokio::io::duplex(1024);
+        let stream =
+            WebSocketStream::from_raw_socket(MaybeTlsStream::Test(io), Role::Server, None).await;
+        let mut stream = RelayedStream::ws(stream, KeyCache::test());
+        let mut reader = WebSocketStream::from_raw_socket(io_rw, Role::Client, None).await;
+
+        let src_key = SecretKey::generate(rand::thread_rng()).public();
+        let frames: Vec<_> = [
+            10,
+            VECTORED_WRITE_MIN_LEN,
+            20,
+            4000,
+            MAX_PACKET_SIZE,
+            40,
+            5000,
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, len)| match i % 3 {
+            2 => Frame::RecvFragment {
+                src_key,
+                fragment: vec![i as u8; len].into(),
+            },
+            _ => Frame::RecvPacket {
+                src_key,
+                content: vec![i as u8; len].into(),
+            },
+        })
+        .collect();
+
+        let expected = frames.clone();
+        let writer = tokio::spawn(async move {
+            for frame in frames {
+                stream.feed(frame).await?;
+            }
+            stream.flush().await?;
+            anyhow::Ok(stream)
+        });
+        for frame in expected {
+            let msg = reader.next().await.context("eos")??;
+            let tungstenite::Message::Binary(msg) = msg else {
+                anyhow::bail!("unexpected message: {msg:?}");
+            };
+            assert_eq!(
+                Frame::decode_from_ws_msg(msg, &KeyCache::test(), &mut false)?,
+                frame
+            );
         }
+        let mut stream = writer.await??;
+        stream.close().await?;
+        assert!(matches!(
+            reader.next().await,
+            Some(Ok(tungstenite::Message::Close(_)))
+        ));
+        Ok(())
     }
 }
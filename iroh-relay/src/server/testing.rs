@@ -1,9 +1,7 @@
 //! Exposes functions to quickly configure a server suitable for testing.
 use std::net::Ipv4Addr;
 
-use super::{
-    AccessConfig, CertConfig, QuicConfig, RelayConfig, ServerConfig, StunConfig, TlsConfig,
-};
+use super::{CertConfig, QuicConfig, RelayConfig, ServerConfig, StunConfig, TlsConfig};
 
 /// Creates a [`StunConfig`] suitable for testing.
 ///
@@ -11,6 +9,7 @@ use super::{
 pub fn stun_config() -> StunConfig {
     StunConfig {
         bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+        additional_bind_addrs: Vec::new(),
     }
 }
 
@@ -55,6 +54,10 @@ pub fn tls_config() -> TlsConfig<()> {
         cert: CertConfig::<(), ()>::Manual { certs },
         https_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
         quic_bind_addr: (Ipv4Addr::UNSPECIFIED, 0).into(),
+        ech_config_list: None,
+        http2: false,
+        webtransport: false,
+        client_auth: None,
     }
 }
 
@@ -65,11 +68,9 @@ pub fn tls_config() -> TlsConfig<()> {
 /// - Uses default limits.
 pub fn relay_config() -> RelayConfig<()> {
     RelayConfig {
-        http_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
         tls: Some(tls_config()),
-        limits: Default::default(),
         key_cache_capacity: Some(1024),
-        access: AccessConfig::Everyone,
+        ..RelayConfig::new((Ipv4Addr::LOCALHOST, 0).into())
     }
 }
 
@@ -97,6 +98,7 @@ pub fn server_config() -> ServerConfig<()> {
         stun: Some(stun_config()),
         quic: Some(quic_config()),
         #[cfg(feature = "metrics")]
-        metrics_addr: None,
+        metrics: Default::default(),
+        listeners: Default::default(),
     }
 }
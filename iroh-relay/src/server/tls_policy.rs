@@ -0,0 +1,209 @@
+//! Restrictions of the TLS listener to protocol versions, cipher suites and curves.
+//!
+//! The defaults of rustls are a sound choice, these restrictions exist for deployments with
+//! compliance requirements, e.g. to only accept TLS 1.3.  They are applied to the
+//! [`CryptoProvider`] the server config is built with, so they also apply to the QUIC
+//! endpoints sharing the config.
+
+use anyhow::{bail, Context, Result};
+use rustls::{
+    crypto::CryptoProvider, server::WantsServerCert, CipherSuite, ConfigBuilder, NamedGroup,
+    ServerConfig, SupportedProtocolVersion, WantsVerifier,
+};
+use serde::{Deserialize, Serialize};
+
+use super::ClientAuthConfig;
+
+/// A TLS protocol version.
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    schemars::JsonSchema,
+)]
+pub enum TlsVersion {
+    /// TLS 1.2.
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    /// TLS 1.3.
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    /// Returns the rustls protocol version.
+    fn supported(&self) -> &'static SupportedProtocolVersion {
+        match self {
+            Self::Tls12 => &rustls::version::TLS12,
+            Self::Tls13 => &rustls::version::TLS13,
+        }
+    }
+}
+
+/// The protocol versions, cipher suites and key exchange groups the TLS listener accepts.
+///
+/// The default accepts everything the crypto provider supports, with its preferences.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TlsPolicy {
+    /// The lowest protocol version accepted.
+    pub min_version: TlsVersion,
+    /// The cipher suites accepted, in order of preference.
+    ///
+    /// All suites of the crypto provider if empty.  Suites of protocol versions below
+    /// [`TlsPolicy::min_version`] are never negotiated.
+    pub cipher_suites: Vec<CipherSuite>,
+    /// The key exchange groups accepted, in order of preference.
+    ///
+    /// All groups of the crypto provider if empty.
+    pub kx_groups: Vec<NamedGroup>,
+}
+
+impl TlsPolicy {
+    /// Restricts the cipher suites and key exchange groups of the provider.
+    ///
+    /// Fails if the provider does not support one of the configured ones.
+    pub fn restrict(&self, mut provider: CryptoProvider) -> Result<CryptoProvider> {
+        if !self.cipher_suites.is_empty() {
+            provider.cipher_suites = self
+                .cipher_suites
+                .iter()
+                .map(|name| {
+                    provider
+                        .cipher_suites
+                        .iter()
+                        .find(|suite| suite.suite() == *name)
+                        .copied()
+                        .with_context(|| format!("unsupported cipher suite {name:?}"))
+                })
+                .collect::<Result<_>>()?;
+        }
+        if !self.kx_groups.is_empty() {
+            provider.kx_groups = self
+                .kx_groups
+                .iter()
+                .map(|name| {
+                    provider
+                        .kx_groups
+                        .iter()
+                        .find(|group| group.name() == *name)
+                        .copied()
+                        .with_context(|| format!("unsupported key exchange group {name:?}"))
+                })
+                .collect::<Result<_>>()?;
+        }
+        Ok(provider)
+    }
+
+    /// Returns the protocol versions accepted.
+    pub fn protocol_versions(&self) -> Vec<&'static SupportedProtocolVersion> {
+        [TlsVersion::Tls13, TlsVersion::Tls12]
+            .into_iter()
+            .filter(|version| *version >= self.min_version)
+            .map(|version| version.supported())
+            .collect()
+    }
+
+    /// Starts building a server config following this policy, without client auth.
+    ///
+    /// Fails if the restrictions leave no cipher suite for the accepted protocol versions.
+    pub fn server_config_builder(
+        &self,
+        provider: CryptoProvider,
+    ) -> Result<ConfigBuilder<ServerConfig, WantsServerCert>> {
+        Ok(self.builder(provider)?.with_no_client_auth())
+    }
+
+    /// Starts building a server config following this policy, verifying client
+    /// certificates.
+    ///
+    /// Fails like [`TlsPolicy::server_config_builder`], or if the roots of the client
+    /// authorities are invalid.
+    pub fn mutual_server_config_builder(
+        &self,
+        provider: CryptoProvider,
+        client_auth: &ClientAuthConfig,
+    ) -> Result<ConfigBuilder<ServerConfig, WantsServerCert>> {
+        let builder = self.builder(provider)?;
+        let verifier = client_auth.verifier(builder.crypto_provider().clone())?;
+        Ok(builder.with_client_cert_verifier(verifier))
+    }
+
+    fn builder(
+        &self,
+        provider: CryptoProvider,
+    ) -> Result<ConfigBuilder<ServerConfig, WantsVerifier>> {
+        let provider = self.restrict(provider)?;
+        let versions = self.protocol_versions();
+        if !provider
+            .cipher_suites
+            .iter()
+            .any(|suite| versions.contains(&suite.version()))
+        {
+            bail!("no cipher suite left for the accepted protocol versions");
+        }
+        ServerConfig::builder_with_provider(provider.into())
+            .with_protocol_versions(&versions)
+            .context("invalid TLS policy")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::crypto::ring::default_provider;
+
+    use super::*;
+
+    #[test]
+    fn test_default_policy() -> Result<()> {
+        let provider = TlsPolicy::default().restrict(default_provider())?;
+        assert_eq!(
+            provider.cipher_suites.len(),
+            default_provider().cipher_suites.len()
+        );
+        assert_eq!(TlsPolicy::default().protocol_versions().len(), 2);
+        TlsPolicy::default().server_config_builder(default_provider())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_restricted_policy() -> Result<()> {
+        let policy = TlsPolicy {
+            min_version: TlsVersion::Tls13,
+            cipher_suites: vec![
+                CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+                CipherSuite::TLS13_AES_256_GCM_SHA384,
+            ],
+            kx_groups: vec![NamedGroup::X25519],
+        };
+        assert_eq!(policy.protocol_versions(), [&rustls::version::TLS13]);
+        let provider = policy.restrict(default_provider())?;
+        let suites: Vec<_> = provider.cipher_suites.iter().map(|s| s.suite()).collect();
+        assert_eq!(suites, policy.cipher_suites);
+        let groups: Vec<_> = provider.kx_groups.iter().map(|g| g.name()).collect();
+        assert_eq!(groups, policy.kx_groups);
+        policy.server_config_builder(default_provider())?;
+
+        // TLS 1.3 only, with TLS 1.2 suites only.
+        let policy = TlsPolicy {
+            min_version: TlsVersion::Tls13,
+            cipher_suites: vec![CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256],
+            kx_groups: Vec::new(),
+        };
+        assert!(policy.server_config_builder(default_provider()).is_err());
+
+        let policy = TlsPolicy {
+            kx_groups: vec![NamedGroup::FFDHE2048],
+            ..Default::default()
+        };
+        assert!(policy.restrict(default_provider()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+//! Handling of the packets sent to nodes not connected to the server.
+//!
+//! Such packets are dropped.  Clients requesting it are told about it with an
+//! `UnknownPeer` frame for every dropped packet, which helps debugging and lets them stop
+//! sending to a peer that is gone.  The packets are counted per client, and clients which
+//! keep sending to unknown nodes can be rate limited: once they exceed the configured
+//! rate of packets to unknown nodes, the server stops reading from their connection until
+//! the rate allows another one.
+
+use std::num::NonZeroU32;
+
+use anyhow::{ensure, Result};
+
+/// Configuration of the handling of packets sent to unknown nodes.
+#[derive(Debug, Clone)]
+pub struct UnknownPeerConfig {
+    /// Whether clients requesting it are notified of the packets dropped for an unknown
+    /// destination.
+    pub notify: bool,
+    /// The number of packets per second a client may send to unknown nodes before it is
+    /// rate limited, unlimited if `None`.
+    pub max_per_second: Option<NonZeroU32>,
+    /// The number of packets a client may send to unknown nodes in a burst, defaults to
+    /// [`UnknownPeerConfig::max_per_second`].
+    pub max_burst: Option<NonZeroU32>,
+}
+
+impl Default for UnknownPeerConfig {
+    fn default() -> Self {
+        Self {
+            notify: true,
+            max_per_second: None,
+            max_burst: None,
+        }
+    }
+}
+
+impl UnknownPeerConfig {
+    pub(super) fn validate(&self) -> Result<()> {
+        ensure!(
+            self.max_burst.is_none() || self.max_per_second.is_some(),
+            "a burst of packets to unknown nodes requires a rate"
+        );
+        Ok(())
+    }
+
+    /// Builds the rate limiter of the packets a client sends to unknown nodes, if limited.
+    pub(super) fn limiter(&self) -> Option<governor::DefaultDirectRateLimiter> {
+        let mut quota = governor::Quota::per_second(self.max_per_second?);
+        if let Some(max_burst) = self.max_burst {
+            quota = quota.allow_burst(max_burst);
+        }
+        Some(governor::RateLimiter::direct(quota))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limiter() {
+        let config = UnknownPeerConfig::default();
+        config.validate().unwrap();
+        assert!(config.limiter().is_none());
+
+        let config = UnknownPeerConfig {
+            max_per_second: NonZeroU32::new(1),
+            max_burst: NonZeroU32::new(3),
+            ..Default::default()
+        };
+        config.validate().unwrap();
+        let limiter = config.limiter().unwrap();
+        for _ in 0..3 {
+            assert!(limiter.check().is_ok());
+        }
+        assert!(limiter.check().is_err());
+
+        let invalid = UnknownPeerConfig {
+            max_burst: NonZeroU32::new(3),
+            ..Default::default()
+        };
+        assert!(invalid.validate().is_err());
+    }
+}
@@ -0,0 +1,307 @@
+//! A watchdog sampling the health of the relay server.
+//!
+//! The watchdog periodically samples the number of running tasks and the occupancy of the
+//! per-client queues.  When a threshold is crossed it logs a structured warning and keeps a
+//! diagnostic report, which is served by the admin API and can be written to a file.  This
+//! catches leaks like orphaned client tasks before they exhaust the server.
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+use tracing::warn;
+
+/// The default interval between watchdog samples.
+pub const DEFAULT_WATCHDOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The maximum number of clients listed in a [`WatchdogReport`].
+const MAX_REPORTED_CLIENTS: usize = 32;
+
+/// Configuration of the relay server watchdog.
+///
+/// Thresholds which are `None` are not checked.
+#[derive(Debug, Clone)]
+pub struct WatchdogConfig {
+    /// How often the server is sampled.
+    pub interval: Duration,
+    /// Maximum number of tasks serving HTTP connections.
+    pub max_connection_tasks: Option<usize>,
+    /// Maximum number of tasks serving relay clients.
+    pub max_client_tasks: Option<usize>,
+    /// Maximum number of client tasks which do not belong to a registered client.
+    ///
+    /// Outside of short windows while clients connect and disconnect, such tasks are
+    /// leaked.
+    pub max_orphaned_client_tasks: Option<usize>,
+    /// Maximum number of items queued for a single client.
+    pub max_client_queue_depth: Option<usize>,
+    /// File to write the diagnostic report to, as JSON, when a threshold is crossed.
+    pub report_path: Option<PathBuf>,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_WATCHDOG_INTERVAL,
+            max_connection_tasks: None,
+            max_client_tasks: None,
+            max_orphaned_client_tasks: Some(16),
+            max_client_queue_depth: None,
+            report_path: None,
+        }
+    }
+}
+
+/// A diagnostic report of a watchdog sample.
+#[derive(Debug, Clone, Default, Serialize)]
+pub(super) struct WatchdogReport {
+    /// When the sample was taken, in seconds since the UNIX epoch.
+    pub(super) timestamp: u64,
+    /// Number of tasks serving HTTP connections.
+    pub(super) connection_tasks: usize,
+    /// Number of tasks serving relay clients.
+    pub(super) client_tasks: usize,
+    /// Number of registered relay clients.
+    pub(super) registered_clients: usize,
+    /// Number of client tasks which do not belong to a registered client.
+    pub(super) orphaned_client_tasks: usize,
+    /// The clients with the most queued items, most queued first.
+    pub(super) busiest_clients: Vec<ClientQueues>,
+    /// The thresholds which were crossed.
+    pub(super) alarms: Vec<String>,
+}
+
+/// The occupancy of the queues of a single client.
+#[derive(Debug, Clone, Serialize)]
+pub(super) struct ClientQueues {
+    pub(super) node_id: String,
+    pub(super) connection_id: u64,
+    pub(super) send_queue: usize,
+    pub(super) disco_send_queue: usize,
+    pub(super) control_queue: usize,
+}
+
+impl ClientQueues {
+    fn depth(&self) -> usize {
+        self.send_queue + self.disco_send_queue + self.control_queue
+    }
+}
+
+impl WatchdogReport {
+    /// Creates a report from a sample of the server.
+    pub(super) fn new(
+        connection_tasks: usize,
+        client_tasks: usize,
+        registered_clients: usize,
+        mut clients: Vec<ClientQueues>,
+    ) -> Self {
+        clients.retain(|client| client.depth() > 0);
+        clients.sort_unstable_by_key(|client| std::cmp::Reverse(client.depth()));
+        clients.truncate(MAX_REPORTED_CLIENTS);
+        Self {
+            timestamp: super::unix_secs(),
+            connection_tasks,
+            client_tasks,
+            registered_clients,
+            orphaned_client_tasks: client_tasks.saturating_sub(registered_clients),
+            busiest_clients: clients,
+            alarms: Vec::new(),
+        }
+    }
+
+    fn max_client_queue_depth(&self) -> usize {
+        self.busiest_clients.first().map_or(0, ClientQueues::depth)
+    }
+}
+
+/// The watchdog of a relay server, see the [module docs](self).
+#[derive(Debug)]
+pub(super) struct Watchdog {
+    config: WatchdogConfig,
+    /// The last report which crossed a threshold.
+    last_alarm: Mutex<Option<WatchdogReport>>,
+}
+
+impl Watchdog {
+    pub(super) fn new(config: WatchdogConfig) -> Self {
+        Self {
+            config,
+            last_alarm: Mutex::new(None),
+        }
+    }
+
+    pub(super) fn interval(&self) -> Duration {
+        self.config.interval
+    }
+
+    /// Returns the last report which crossed a threshold.
+    pub(super) fn last_alarm(&self) -> Option<WatchdogReport> {
+        self.last_alarm.lock().expect("poisoned").clone()
+    }
+
+    /// Evaluates a sample against the thresholds, recording the crossed ones in the report.
+    pub(super) fn evaluate(&self, mut report: WatchdogReport) -> WatchdogReport {
+        let checks = [
+            (
+                "connection_tasks",
+                report.connection_tasks,
+                self.config.max_connection_tasks,
+            ),
+            (
+                "client_tasks",
+                report.client_tasks,
+                self.config.max_client_tasks,
+            ),
+            (
+                "orphaned_client_tasks",
+                report.orphaned_client_tasks,
+                self.config.max_orphaned_client_tasks,
+            ),
+            (
+                "client_queue_depth",
+                report.max_client_queue_depth(),
+                self.config.max_client_queue_depth,
+            ),
+        ];
+        report.alarms = checks
+            .into_iter()
+            .filter_map(|(name, value, max)| {
+                let max = max?;
+                (value > max).then(|| format!("{name} {value} exceeds {max}"))
+            })
+            .collect();
+        report
+    }
+
+    /// Checks a sample against the thresholds, returning it with the crossed thresholds.
+    ///
+    /// If a threshold is crossed a warning is logged and the report is kept as
+    /// [`Self::last_alarm`] and written to the configured report file.
+    pub(super) async fn check(&self, report: WatchdogReport) -> WatchdogReport {
+        let report = self.evaluate(report);
+        if report.alarms.is_empty() {
+            return report;
+        }
+
+        warn!(
+            connection_tasks = report.connection_tasks,
+            client_tasks = report.client_tasks,
+            registered_clients = report.registered_clients,
+            orphaned_client_tasks = report.orphaned_client_tasks,
+            max_client_queue_depth = report.max_client_queue_depth(),
+            alarms = ?report.alarms,
+            "relay server watchdog threshold crossed",
+        );
+        if let Some(path) = &self.config.report_path {
+            let res = match serde_json::to_vec_pretty(&report) {
+                Ok(json) => tokio::fs::write(path, json).await.map_err(Into::into),
+                Err(err) => Err(anyhow::Error::from(err)),
+            };
+            if let Err(err) = res {
+                warn!(path = %path.display(), "failed to write watchdog report: {err:#}");
+            }
+        }
+        *self.last_alarm.lock().expect("poisoned") = Some(report.clone());
+        report
+    }
+}
+
+/// Counts running tasks, each task holds a [`TaskGuard`] while it runs.
+#[derive(Debug, Clone, Default)]
+pub(super) struct TaskCounter(Arc<AtomicUsize>);
+
+impl TaskCounter {
+    /// Counts a task until the returned guard is dropped.
+    pub(super) fn guard(&self) -> TaskGuard {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        TaskGuard(self.0.clone())
+    }
+
+    /// Returns the number of running tasks.
+    pub(super) fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Guard counting a running task in a [`TaskCounter`].
+#[derive(Debug)]
+pub(super) struct TaskGuard(Arc<AtomicUsize>);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queues(connection_id: u64, send_queue: usize) -> ClientQueues {
+        ClientQueues {
+            node_id: format!("node{connection_id}"),
+            connection_id,
+            send_queue,
+            disco_send_queue: 0,
+            control_queue: 0,
+        }
+    }
+
+    #[test]
+    fn test_task_counter() {
+        let counter = TaskCounter::default();
+        let a = counter.guard();
+        let b = counter.guard();
+        assert_eq!(counter.get(), 2);
+        drop(a);
+        assert_eq!(counter.get(), 1);
+        drop(b);
+        assert_eq!(counter.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_watchdog_check() {
+        let report_path = std::env::temp_dir().join(format!(
+            "iroh-relay-watchdog-{}.json",
+            rand::random::<u64>()
+        ));
+        let watchdog = Watchdog::new(WatchdogConfig {
+            max_client_queue_depth: Some(5),
+            max_orphaned_client_tasks: Some(1),
+            report_path: Some(report_path.clone()),
+            ..Default::default()
+        });
+
+        let report = WatchdogReport::new(3, 3, 3, vec![queues(0, 0), queues(1, 5)]);
+        let report = watchdog.check(report).await;
+        assert!(report.alarms.is_empty());
+        assert_eq!(report.busiest_clients.len(), 1);
+        assert!(watchdog.last_alarm().is_none());
+        assert!(!report_path.exists());
+
+        let report = WatchdogReport::new(3, 5, 3, vec![queues(0, 2), queues(1, 8)]);
+        let report = watchdog.check(report).await;
+        assert_eq!(
+            report.alarms,
+            [
+                "orphaned_client_tasks 2 exceeds 1",
+                "client_queue_depth 8 exceeds 5"
+            ]
+        );
+        assert_eq!(report.busiest_clients[0].connection_id, 1);
+        let last_alarm = watchdog.last_alarm().expect("alarm");
+        assert_eq!(last_alarm.alarms, report.alarms);
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&report_path).unwrap()).unwrap();
+        assert_eq!(json["orphaned_client_tasks"], 2);
+        assert_eq!(json["busiest_clients"][0]["send_queue"], 8);
+        std::fs::remove_file(report_path).unwrap();
+    }
+}
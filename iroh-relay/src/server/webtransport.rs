@@ -0,0 +1,345 @@
+//! Serves relay connections over WebTransport sessions, see [`TlsConfig::webtransport`].
+//!
+//! The QUIC endpoint listens on the UDP port of the HTTPS server.  Each connection carries
+//! one session, established with a `CONNECT` request for [`RELAY_PATH`], whose first
+//! WebTransport stream is handed to the relay server.  The connection is closed once the
+//! session ends.
+//!
+//! [`TlsConfig::webtransport`]: crate::server::TlsConfig::webtransport
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use iroh_base::inc;
+use quinn::{crypto::rustls::QuicServerConfig, ConnectionError, RecvStream, SendStream};
+use tokio::{sync::oneshot, task::JoinSet};
+use tracing::{debug, info, info_span, trace, Instrument};
+
+use super::{
+    access_log::PendingAccess,
+    canonical_addr,
+    client_auth::ClientIdentity,
+    http_server::{ClientRequest, ServerHandle},
+    metrics::Metrics,
+};
+use crate::{
+    http::{Protocol, H3_ALPN, RELAY_PATH},
+    protos::webtransport::{
+        self, field, headers_frame, read_headers, read_varint, WebTransportStream,
+        DRAFT02_RESPONSE_HEADER, H3_NO_ERROR, H3_REQUEST_REJECTED, WEBTRANSPORT_STREAM,
+    },
+};
+
+/// How long a client may take to send a request, or to open the relay stream.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The number of streams a client may open concurrently in each direction.
+///
+/// Besides the control streams only the `CONNECT` request and the relay stream are used.
+const MAX_CONCURRENT_STREAMS: u8 = 4;
+
+/// Binds the QUIC endpoint for WebTransport sessions, see [`server_config`].
+pub(super) fn bind(addr: SocketAddr, tls_config: rustls::ServerConfig) -> Result<quinn::Endpoint> {
+    let endpoint = quinn::Endpoint::server(server_config(tls_config)?, addr)
+        .with_context(|| format!("failed to bind WebTransport endpoint on {addr}"))?;
+    info!(addr = ?endpoint.local_addr(), "WebTransport relay: serving");
+    Ok(endpoint)
+}
+
+/// Builds the QUIC server config for WebTransport sessions.
+///
+/// The TLS config must support TLS 1.3, its ALPN protocols are replaced by [`H3_ALPN`].
+pub(super) fn server_config(mut tls_config: rustls::ServerConfig) -> Result<quinn::ServerConfig> {
+    tls_config.alpn_protocols = vec![H3_ALPN.to_vec()];
+    let crypto = QuicServerConfig::try_from(tls_config).context("TLS 1.3 is required for QUIC")?;
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    Arc::get_mut(&mut server_config.transport)
+        .expect("not used yet")
+        .max_concurrent_bidi_streams(MAX_CONCURRENT_STREAMS.into())
+        .max_concurrent_uni_streams(MAX_CONCURRENT_STREAMS.into());
+    Ok(server_config)
+}
+
+/// Accepts connections on the endpoint until the relay server shuts down.
+pub(super) async fn serve(endpoint: quinn::Endpoint, relay: ServerHandle) -> Result<()> {
+    let mut set = JoinSet::new();
+    loop {
+        tokio::select! {
+            biased;
+            _ = relay.cancelled() => break,
+            Some(res) = set.join_next() => {
+                if let Err(err) = res {
+                    if err.is_panic() {
+                        panic!("task panicked: {err:#?}");
+                    }
+                }
+            }
+            incoming = endpoint.accept() => {
+                let Some(incoming) = incoming else {
+                    break;
+                };
+                let remote_addr = canonical_addr(incoming.remote_address());
+                let relay = relay.clone();
+                set.spawn(
+                    async move {
+                        if let Err(err) = handle_connection(incoming, relay).await {
+                            debug!("connection failed: {err:#}");
+                        }
+                    }
+                    .instrument(info_span!("webtransport-conn", %remote_addr)),
+                );
+            }
+        }
+    }
+    endpoint.close(H3_NO_ERROR, b"shutdown");
+    set.shutdown().await;
+    endpoint.wait_idle().await;
+    debug!("WebTransport endpoint has been shutdown.");
+    Ok(())
+}
+
+async fn handle_connection(incoming: quinn::Incoming, relay: ServerHandle) -> Result<()> {
+    let conn = incoming.await?;
+    debug!("established");
+    // The settings of clients are of no interest, they only open streams.
+    let (settings_tx, _settings_rx) = oneshot::channel();
+    let res = tokio::select! {
+        res = webtransport::run_control_streams(conn.clone(), settings_tx) => res,
+        res = serve_session(&conn, &relay) => res,
+    };
+    match res {
+        Err(err) => match err.downcast_ref::<ConnectionError>() {
+            Some(ConnectionError::ApplicationClosed(_)) => Ok(()),
+            _ => Err(err),
+        },
+        Ok(()) => {
+            conn.close(H3_NO_ERROR, b"");
+            Ok(())
+        }
+    }
+}
+
+/// Serves the session of a connection, returns once it ended.
+async fn serve_session(conn: &quinn::Connection, relay: &ServerHandle) -> Result<()> {
+    let remote_addr = canonical_addr(conn.remote_address());
+    let (session_id, mut connect_stream, headers, access) = loop {
+        let (send, recv) = conn.accept_bi().await?;
+        let session_id = u64::from(send.id());
+        let session = accept_session(send, recv, remote_addr, relay);
+        match tokio::time::timeout(REQUEST_TIMEOUT, session).await {
+            Ok(Ok(Some((connect_stream, headers, access)))) => {
+                break (session_id, connect_stream, headers, access)
+            }
+            Ok(Ok(None)) => inc!(Metrics, webtransport_sessions_rejected),
+            Ok(Err(err)) => {
+                inc!(Metrics, webtransport_sessions_rejected);
+                debug!("invalid session request: {err:#}");
+            }
+            Err(_) => {
+                inc!(Metrics, webtransport_sessions_rejected);
+                debug!("session request timed out");
+            }
+        }
+    };
+    debug!(session_id, "session established");
+
+    let res = async {
+        let stream = tokio::time::timeout(REQUEST_TIMEOUT, accept_relay_stream(conn, session_id))
+            .await
+            .context("timeout waiting for the relay stream")??;
+        let identity = ClientIdentity::from_quic(conn);
+        if identity.is_none() && relay.requires_client_certificate() {
+            inc!(Metrics, client_certificates_missing);
+            bail!("no client certificate");
+        }
+        Ok((stream, identity))
+    };
+    let (stream, identity) = match res.await {
+        Ok(res) => res,
+        Err(err) => {
+            // The session was accepted, but the client never became a relay client.
+            if let Some(access) = access {
+                relay.log_access(access.finish(None));
+            }
+            return Err(err);
+        }
+    };
+    let request = ClientRequest {
+        remote_addr,
+        headers,
+        access,
+        identity,
+    };
+    relay.accept_webtransport(stream, request).await?;
+
+    // Once the relay stream is served, the session ends with its `CONNECT` stream.
+    let mut sink = tokio::io::sink();
+    let closed = tokio::io::copy(&mut connect_stream.1, &mut sink);
+    tokio::pin!(closed);
+    loop {
+        tokio::select! {
+            res = &mut closed => {
+                trace!(?res, "CONNECT stream closed");
+                return Ok(());
+            }
+            res = conn.accept_bi() => {
+                let (send, recv) = res?;
+                reject(send, recv);
+            }
+        }
+    }
+}
+
+/// The `CONNECT` stream, the headers and the pending access record of an accepted session.
+type AcceptedSession = ((SendStream, RecvStream), HeaderMap, Option<PendingAccess>);
+
+/// Answers a request for a session.
+///
+/// Returns the `CONNECT` stream and the headers of the request if it was accepted.  Refused
+/// requests are written to the access log right away, the record of an accepted one is
+/// completed once the client finished its relay handshake.
+async fn accept_session(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    remote_addr: SocketAddr,
+    relay: &ServerHandle,
+) -> Result<Option<AcceptedSession>> {
+    let first_type = read_varint(&mut recv).await?;
+    let fields = read_headers(&mut recv, Some(first_type)).await?;
+    let status = session_status(&fields, relay.is_draining());
+    trace!(status, "session request");
+    let access = relay.has_access_log().then(|| {
+        let method = field(&fields, ":method").and_then(|m| Method::from_bytes(m).ok());
+        let path = field(&fields, ":path").unwrap_or_default();
+        let mut access = PendingAccess::request(
+            method.unwrap_or(Method::CONNECT),
+            String::from_utf8_lossy(path).into_owned(),
+            remote_addr,
+        );
+        let status = StatusCode::from_bytes(status.as_bytes()).unwrap_or_default();
+        access.respond(status, 0);
+        access
+    });
+    let mut response = vec![(":status", status)];
+    if status == "200" {
+        response.push(DRAFT02_RESPONSE_HEADER);
+    }
+    send.write_all(&headers_frame(&response)).await?;
+    match status {
+        "200" => Ok(Some(((send, recv), request_headers(&fields), access))),
+        _ => {
+            send.finish().ok();
+            if let Some(access) = access {
+                relay.log_access(access.finish(None));
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Returns the response status of a session request.
+fn session_status(fields: &[webtransport::qpack::Field], draining: bool) -> &'static str {
+    let method = field(fields, ":method");
+    let protocol = field(fields, ":protocol");
+    if method != Some(b"CONNECT")
+        || protocol != Some(Protocol::WebTransport.upgrade_header().as_bytes())
+        || field(fields, ":scheme") != Some(b"https")
+        || field(fields, ":authority").is_none()
+    {
+        return "400";
+    }
+    let path = field(fields, ":path").unwrap_or_default();
+    let path = path.split(|&b| b == b'?').next().unwrap_or_default();
+    if path != RELAY_PATH.as_bytes() {
+        return "404";
+    }
+    // Like the HTTP server, refuse new clients while draining.
+    if draining {
+        return "503";
+    }
+    "200"
+}
+
+/// Returns the regular headers of a request, skipping the pseudo-headers and invalid ones.
+fn request_headers(fields: &[webtransport::qpack::Field]) -> HeaderMap {
+    fields
+        .iter()
+        .filter(|(name, _)| !name.starts_with(b":"))
+        .filter_map(|(name, value)| {
+            let name = HeaderName::from_bytes(name).ok()?;
+            let value = HeaderValue::from_bytes(value).ok()?;
+            Some((name, value))
+        })
+        .collect()
+}
+
+/// Accepts the relay stream of a session, rejecting other streams.
+async fn accept_relay_stream(
+    conn: &quinn::Connection,
+    session_id: u64,
+) -> Result<WebTransportStream> {
+    loop {
+        let (send, mut recv) = conn.accept_bi().await?;
+        let signal = read_varint(&mut recv).await?;
+        if signal == WEBTRANSPORT_STREAM && read_varint(&mut recv).await? == session_id {
+            return Ok(WebTransportStream::accepted(send, recv));
+        }
+        debug!(signal, "rejecting stream");
+        reject(send, recv);
+    }
+}
+
+/// Rejects a stream which is not part of the session.
+fn reject(mut send: SendStream, mut recv: RecvStream) {
+    send.reset(H3_REQUEST_REJECTED).ok();
+    recv.stop(H3_REQUEST_REJECTED).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(fields: &[(&str, &str)]) -> Vec<webtransport::qpack::Field> {
+        fields
+            .iter()
+            .map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn test_session_status() {
+        let request = [
+            (":method", "CONNECT"),
+            (":scheme", "https"),
+            (":authority", "relay.example"),
+            (":path", "/relay?client=1"),
+            (":protocol", "webtransport"),
+        ];
+        assert_eq!(session_status(&fields(&request), false), "200");
+        assert_eq!(session_status(&fields(&request), true), "503");
+
+        let mut wrong_path = request;
+        wrong_path[3] = (":path", "/derp");
+        assert_eq!(session_status(&fields(&wrong_path), false), "404");
+
+        let mut websocket = request;
+        websocket[4] = (":protocol", "websocket");
+        assert_eq!(session_status(&fields(&websocket), false), "400");
+
+        assert_eq!(session_status(&fields(&request[..4]), false), "400");
+    }
+
+    #[test]
+    fn test_request_headers() {
+        let request = [
+            (":method", "CONNECT"),
+            (":path", "/relay"),
+            ("authorization", "Bearer token"),
+            ("bad header", "x"),
+        ];
+        let headers = request_headers(&fields(&request));
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["authorization"], "Bearer token");
+    }
+}
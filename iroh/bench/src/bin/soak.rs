@@ -0,0 +1,16 @@
+use clap::Parser;
+use iroh_bench::{configure_tracing_subscriber, soak};
+
+fn main() {
+    let opt = soak::Opt::parse();
+    configure_tracing_subscriber();
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build runtime");
+    if let Err(e) = rt.block_on(soak::run(opt)) {
+        eprintln!("failed: {e:#}");
+        std::process::exit(1);
+    }
+}
@@ -18,6 +18,8 @@ pub mod iroh;
 #[cfg(not(any(target_os = "freebsd", target_os = "openbsd", target_os = "netbsd")))]
 pub mod quinn;
 pub mod s2n;
+#[cfg(feature = "soak")]
+pub mod soak;
 pub mod stats;
 
 #[derive(Parser, Debug, Clone, Copy)]
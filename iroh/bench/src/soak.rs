@@ -0,0 +1,675 @@
+//! A soak test for release qualification.
+//!
+//! Runs relay servers, a DNS server and many endpoints on localhost.  The endpoints find
+//! each other through the DNS server and continuously send each other messages, which the
+//! receivers record and acknowledge.  Meanwhile faults are injected periodically:
+//!
+//! - a relay server is killed and restarted on the same address after a while,
+//! - some endpoints are told that their network changed,
+//! - an endpoint is restarted with a new key.
+//!
+//! At the end the invariants are checked: each acknowledged message was recorded by its
+//! receiver with the same content, and no endpoint was unable to reach another one for
+//! longer than the reconnect limit.
+
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hasher},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use clap::{Parser, ValueEnum};
+use iroh::{
+    discovery::{dns::DnsDiscovery, pkarr::PkarrPublisher, ConcurrentDiscovery},
+    dns::DnsResolver,
+    endpoint::{Connection, Incoming, PathSelection, RecvStream, SendStream},
+    Endpoint, NodeId, RelayMap, RelayMode, RelayNode, RelayUrl, SecretKey,
+};
+use iroh_relay::server::{self as relay, ServerConfig};
+use rand::{seq::SliceRandom, Rng};
+use tokio::{sync::mpsc, task::JoinSet};
+use tracing::{debug, info, warn, Instrument};
+use url::Url;
+
+/// The ALPN of the messages exchanged by the endpoints.
+const ALPN: &[u8] = b"n0/iroh-soak/0";
+/// The origin of the node records served by the DNS server.
+const ORIGIN: &str = "irohdns.example";
+/// The length of the header of a message: the sender index and the sequence number.
+const HEADER_LEN: usize = 12;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(name = "iroh-soak")]
+pub struct Opt {
+    /// The number of endpoints exchanging messages.
+    #[clap(long, default_value_t = 24)]
+    pub endpoints: usize,
+    /// The number of relay servers.
+    #[clap(long, default_value_t = 2)]
+    pub relays: usize,
+    /// How long to run the soak test, in seconds.
+    #[clap(long, default_value_t = 600)]
+    pub duration_secs: u64,
+    /// The faults to inject.
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [Chaos::KillRelay, Chaos::FlapNetwork, Chaos::RotateKey],
+    )]
+    pub chaos: Vec<Chaos>,
+    /// Seconds between two faults.
+    #[clap(long, default_value_t = 20)]
+    pub chaos_interval_secs: u64,
+    /// How long a killed relay server stays down, in seconds.
+    #[clap(long, default_value_t = 5)]
+    pub relay_downtime_secs: u64,
+    /// How long a flapping endpoint stays without network, in seconds.
+    #[clap(long, default_value_t = 5)]
+    pub network_downtime_secs: u64,
+    /// The longest an endpoint may be unable to reach another one, in seconds.
+    #[clap(long, default_value_t = 30)]
+    pub max_reconnect_secs: u64,
+    /// Milliseconds between the messages sent by each endpoint.
+    #[clap(long, default_value_t = 250)]
+    pub message_interval_ms: u64,
+    /// Seconds to wait for the acknowledgement of a message.
+    #[clap(long, default_value_t = 10)]
+    pub message_timeout_secs: u64,
+    /// Number of bytes in each message.
+    ///
+    /// This can use SI prefixes for sizes. E.g. 1M will send 1MiB.
+    #[clap(long, default_value = "16k", value_parser = crate::parse_byte_size)]
+    pub message_size: u64,
+    /// Let the endpoints connect directly, by default all traffic is relayed.
+    #[clap(long)]
+    pub direct: bool,
+}
+
+/// A fault injected by the soak test.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chaos {
+    /// Kills a relay server and restarts it on the same address.
+    KillRelay,
+    /// Takes the network interfaces of a quarter of the endpoints away and gives them back.
+    ///
+    /// While offline the endpoints neither send nor receive anything, once back their
+    /// sockets are rebound and they reconnect to the relays.
+    FlapNetwork,
+    /// Restarts an endpoint with a new key.
+    RotateKey,
+}
+
+/// Runs the soak test, failing if an invariant is violated.
+pub async fn run(opt: Opt) -> Result<()> {
+    ensure!(opt.endpoints >= 2, "at least two endpoints are needed");
+    ensure!(opt.relays >= 1, "at least one relay is needed");
+
+    let (dns_server, nameserver, pkarr_url) = spawn_dns_server().await?;
+    info!(%nameserver, %pkarr_url, "dns server running");
+    let mut relays = Vec::with_capacity(opt.relays);
+    for _ in 0..opt.relays {
+        let relay = Relay::spawn().await?;
+        info!(url = %relay.url, "relay server running");
+        relays.push(relay);
+    }
+    let relay_map = RelayMap::from_nodes(relays.iter().map(|relay| RelayNode {
+        url: relay.url.clone(),
+        stun_only: false,
+        stun_port: 0,
+        quic: None,
+    }))?;
+
+    let env = Arc::new(Env {
+        ledger: Ledger::new(opt.max_reconnect_secs),
+        roster: RwLock::new(vec![None; opt.endpoints]),
+        opt,
+        relay_map,
+        nameserver,
+        pkarr_url,
+    });
+    let mut nodes = JoinSet::new();
+    let mut commands = Vec::with_capacity(env.opt.endpoints);
+    for index in 0..env.opt.endpoints {
+        let (tx, rx) = mpsc::channel(8);
+        commands.push(tx);
+        nodes.spawn(
+            run_node(index, env.clone(), rx).instrument(tracing::error_span!("node", index)),
+        );
+    }
+
+    let chaos = Chaos::run_all(&env, &mut relays, &commands);
+    let duration = Duration::from_secs(env.opt.duration_secs);
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => (),
+        res = chaos => res?,
+        Some(res) = nodes.join_next() => {
+            res?.context("endpoint failed")?;
+            bail!("endpoint stopped");
+        }
+    }
+
+    info!("soak test finished, shutting down");
+    drop(commands);
+    while let Some(res) = nodes.join_next().await {
+        if let Err(err) = res? {
+            warn!("endpoint failed to shut down: {err:#}");
+        }
+    }
+    for mut relay in relays {
+        relay.kill().await?;
+    }
+    dns_server.shutdown().await?;
+
+    env.ledger.report()
+}
+
+/// The state shared by the endpoints.
+#[derive(Debug)]
+struct Env {
+    opt: Opt,
+    relay_map: RelayMap,
+    nameserver: SocketAddr,
+    pkarr_url: Url,
+    /// The current node ID of each endpoint, `None` while it is restarting.
+    roster: RwLock<Vec<Option<NodeId>>>,
+    ledger: Ledger,
+}
+
+impl Env {
+    /// Picks another endpoint to send a message to.
+    fn pick_peer(&self, index: usize) -> Option<(usize, NodeId)> {
+        let roster = self.roster.read().expect("poisoned");
+        let mut peer = rand::thread_rng().gen_range(0..roster.len() - 1);
+        if peer >= index {
+            peer += 1;
+        }
+        roster[peer].map(|node_id| (peer, node_id))
+    }
+}
+
+/// Runs the DNS server the endpoints publish to and resolve each other with.
+///
+/// Returns the server, the address of the nameserver and the URL of the pkarr relay.
+async fn spawn_dns_server() -> Result<(iroh_dns_server::server::Server, SocketAddr, Url)> {
+    use iroh_dns_server::{
+        config::{Config, MetricsConfig},
+        http::{HttpConfig, RateLimitConfig},
+        server::Server,
+        ZoneStore,
+    };
+
+    let mut config = Config::default();
+    config.dns.port = 0;
+    config.dns.bind_addr = Some(Ipv4Addr::LOCALHOST.into());
+    config.http = Some(HttpConfig {
+        port: 0,
+        bind_addr: Some(Ipv4Addr::LOCALHOST.into()),
+    });
+    config.https = None;
+    config.metrics = Some(MetricsConfig::disabled());
+    // All endpoints publish from localhost.
+    config.pkarr_put_rate_limit = RateLimitConfig::Disabled;
+
+    let store = ZoneStore::in_memory(Default::default())?;
+    let server = Server::spawn(config, store).await?;
+    let http_addr = server.http_addr().context("http server not running")?;
+    let pkarr_url = format!("http://{http_addr}/pkarr").parse()?;
+    let dns_addr = server.dns_addr();
+    Ok((server, dns_addr, pkarr_url))
+}
+
+/// A relay server which can be killed and restarted on the same address.
+#[derive(Debug)]
+struct Relay {
+    url: RelayUrl,
+    https_addr: SocketAddr,
+    server: Option<relay::Server>,
+}
+
+impl Relay {
+    /// Runs a relay server on a free port.
+    async fn spawn() -> Result<Self> {
+        let server = Self::spawn_server((Ipv4Addr::LOCALHOST, 0).into()).await?;
+        let https_addr = server.https_addr().context("https not running")?;
+        Ok(Self {
+            url: format!("https://{https_addr}").parse()?,
+            https_addr,
+            server: Some(server),
+        })
+    }
+
+    async fn spawn_server(https_addr: SocketAddr) -> Result<relay::Server> {
+        let mut relay_config = relay::testing::relay_config();
+        if let Some(tls) = relay_config.tls.as_mut() {
+            tls.https_bind_addr = https_addr;
+        }
+        let config = ServerConfig {
+            relay: Some(relay_config),
+            ..Default::default()
+        };
+        relay::Server::spawn(config).await
+    }
+
+    /// Kills the relay server, if it is running.
+    async fn kill(&mut self) -> Result<()> {
+        if let Some(server) = self.server.take() {
+            server.shutdown().await?;
+        }
+        Ok(())
+    }
+
+    /// Restarts the killed relay server on its previous address.
+    async fn restart(&mut self) -> Result<()> {
+        let server = Self::spawn_server(self.https_addr)
+            .await
+            .with_context(|| format!("failed to restart relay {}", self.url))?;
+        self.server = Some(server);
+        Ok(())
+    }
+}
+
+/// A command for an endpoint from the chaos loop.
+#[derive(Debug, Clone, Copy)]
+enum Command {
+    FlapNetwork,
+    RotateKey,
+}
+
+impl Chaos {
+    /// Injects the configured faults until the soak test ends.
+    async fn run_all(
+        env: &Env,
+        relays: &mut [Relay],
+        commands: &[mpsc::Sender<Command>],
+    ) -> Result<()> {
+        if env.opt.chaos.is_empty() {
+            return std::future::pending().await;
+        }
+        let mut interval = tokio::time::interval(Duration::from_secs(env.opt.chaos_interval_secs));
+        // The first tick completes immediately, let the endpoints start up first.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let chaos = *env
+                .opt
+                .chaos
+                .choose(&mut rand::thread_rng())
+                .expect("not empty");
+            chaos.inject(env, relays, commands).await?;
+        }
+    }
+
+    async fn inject(
+        self,
+        env: &Env,
+        relays: &mut [Relay],
+        commands: &[mpsc::Sender<Command>],
+    ) -> Result<()> {
+        let faults = &env.ledger.faults;
+        match self {
+            Self::KillRelay => {
+                let relay = relays
+                    .choose_mut(&mut rand::thread_rng())
+                    .expect("not empty");
+                info!(url = %relay.url, "chaos: killing relay");
+                relay.kill().await?;
+                faults.relay_kills.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_secs(env.opt.relay_downtime_secs)).await;
+                info!(url = %relay.url, "chaos: restarting relay");
+                relay.restart().await?;
+            }
+            Self::FlapNetwork => {
+                let count = (commands.len() / 4).max(1);
+                for (index, commands) in commands
+                    .iter()
+                    .enumerate()
+                    .collect::<Vec<_>>()
+                    .choose_multiple(&mut rand::thread_rng(), count)
+                {
+                    info!(index, "chaos: taking the network away");
+                    commands.send(Command::FlapNetwork).await.ok();
+                    faults.network_flaps.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Self::RotateKey => {
+                let index = rand::thread_rng().gen_range(0..commands.len());
+                info!(index, "chaos: rotating key");
+                commands[index].send(Command::RotateKey).await.ok();
+                faults.key_rotations.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs an endpoint, restarting it with a new key when rotating keys.
+///
+/// Returns once the command channel is closed.
+async fn run_node(
+    index: usize,
+    env: Arc<Env>,
+    mut commands: mpsc::Receiver<Command>,
+) -> Result<()> {
+    loop {
+        let secret_key = SecretKey::generate(rand::thread_rng());
+        let endpoint = bind_endpoint(&env, secret_key).await?;
+        endpoint.home_relay().initialized().await?;
+        info!(node_id = %endpoint.node_id().fmt_short(), "endpoint running");
+        env.roster.write().expect("poisoned")[index] = Some(endpoint.node_id());
+
+        let mut tasks = JoinSet::new();
+        tasks.spawn(accept_loop(index, endpoint.clone(), env.clone()).in_current_span());
+        tasks.spawn(send_loop(index, endpoint.clone(), env.clone()).in_current_span());
+        let rotate = loop {
+            match commands.recv().await {
+                Some(Command::FlapNetwork) => {
+                    endpoint.set_offline(true).await;
+                    tokio::time::sleep(Duration::from_secs(env.opt.network_downtime_secs)).await;
+                    info!(index, "chaos: giving the network back");
+                    endpoint.set_offline(false).await;
+                }
+                Some(Command::RotateKey) => break true,
+                None => break false,
+            }
+        };
+
+        env.roster.write().expect("poisoned")[index] = None;
+        tasks.shutdown().await;
+        endpoint.close().await;
+        if !rotate {
+            return Ok(());
+        }
+    }
+}
+
+async fn bind_endpoint(env: &Env, secret_key: SecretKey) -> Result<Endpoint> {
+    let discovery = ConcurrentDiscovery::from_services(vec![
+        Box::new(DnsDiscovery::new(ORIGIN.to_string())),
+        Box::new(PkarrPublisher::new(
+            secret_key.clone(),
+            env.pkarr_url.clone(),
+        )),
+    ]);
+    let path_selection = match env.opt.direct {
+        true => PathSelection::default(),
+        false => PathSelection::RelayOnly,
+    };
+    Endpoint::builder()
+        .secret_key(secret_key)
+        .alpns(vec![ALPN.to_vec()])
+        .relay_mode(RelayMode::Custom(env.relay_map.clone()))
+        .insecure_skip_relay_cert_verify(true)
+        .path_selection(path_selection)
+        .discovery(Box::new(discovery))
+        .dns_resolver(DnsResolver::with_nameserver(env.nameserver))
+        .bind()
+        .await
+}
+
+/// Records and acknowledges the messages sent to the endpoint.
+async fn accept_loop(index: usize, endpoint: Endpoint, env: Arc<Env>) {
+    let mut connections = JoinSet::new();
+    while let Some(incoming) = endpoint.accept().await {
+        connections.spawn(handle_connection(index, incoming, env.clone()).in_current_span());
+        while connections.try_join_next().is_some() {}
+    }
+}
+
+async fn handle_connection(index: usize, incoming: Incoming, env: Arc<Env>) -> Result<()> {
+    let connection = incoming.await?;
+    loop {
+        let (send, recv) = connection.accept_bi().await?;
+        if let Err(err) = receive_message(index, &env, send, recv).await {
+            debug!("failed to receive message: {err:#}");
+        }
+    }
+}
+
+async fn receive_message(
+    index: usize,
+    env: &Env,
+    mut send: SendStream,
+    mut recv: RecvStream,
+) -> Result<()> {
+    let limit = HEADER_LEN + env.opt.message_size as usize;
+    let message = recv.read_to_end(limit).await?;
+    ensure!(message.len() >= HEADER_LEN, "message too short");
+    let sender = u32::from_be_bytes(message[..4].try_into().expect("length checked"));
+    let seq = u64::from_be_bytes(message[4..HEADER_LEN].try_into().expect("length checked"));
+    let digest = digest(&message[HEADER_LEN..]);
+    env.ledger.record(index, sender as usize, seq, digest);
+    send.write_all(&digest.to_be_bytes()).await?;
+    send.finish()?;
+    // Keep the stream until the sender read the acknowledgement.
+    send.stopped().await.ok();
+    Ok(())
+}
+
+/// Sends messages to random other endpoints.
+async fn send_loop(index: usize, endpoint: Endpoint, env: Arc<Env>) {
+    let mut connections: HashMap<NodeId, Connection> = HashMap::new();
+    let mut interval = tokio::time::interval(Duration::from_millis(env.opt.message_interval_ms));
+    let timeout = Duration::from_secs(env.opt.message_timeout_secs);
+    loop {
+        interval.tick().await;
+        let Some((peer, node_id)) = env.pick_peer(index) else {
+            continue;
+        };
+        let seq = env.ledger.next_seq(index);
+        let mut payload = vec![0u8; env.opt.message_size as usize];
+        rand::thread_rng().fill(&mut payload[..]);
+        let res = tokio::time::timeout(
+            timeout,
+            send_message(&endpoint, &mut connections, node_id, index, seq, &payload),
+        )
+        .await;
+        match res {
+            Ok(Ok(())) => env.ledger.acknowledged(index, peer, seq, digest(&payload)),
+            Ok(Err(err)) => {
+                debug!(peer, "failed to send message: {err:#}");
+                connections.remove(&node_id);
+                env.ledger.failed(index, peer);
+            }
+            Err(_) => {
+                debug!(peer, "timed out sending message");
+                connections.remove(&node_id);
+                env.ledger.failed(index, peer);
+            }
+        }
+    }
+}
+
+async fn send_message(
+    endpoint: &Endpoint,
+    connections: &mut HashMap<NodeId, Connection>,
+    node_id: NodeId,
+    index: usize,
+    seq: u64,
+    payload: &[u8],
+) -> Result<()> {
+    let connection = match connections.get(&node_id) {
+        Some(connection) => connection.clone(),
+        None => {
+            let connection = endpoint.connect(node_id, ALPN).await?;
+            connections.insert(node_id, connection.clone());
+            connection
+        }
+    };
+    let (mut send, mut recv) = connection.open_bi().await?;
+    let mut header = [0u8; HEADER_LEN];
+    header[..4].copy_from_slice(&(index as u32).to_be_bytes());
+    header[4..].copy_from_slice(&seq.to_be_bytes());
+    send.write_all(&header).await?;
+    send.write_all(payload).await?;
+    send.finish()?;
+    let ack = recv.read_to_end(8).await?;
+    ensure!(
+        ack == digest(payload).to_be_bytes(),
+        "acknowledged with the wrong digest"
+    );
+    Ok(())
+}
+
+fn digest(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(data);
+    hasher.finish()
+}
+
+/// The record of the messages and faults of the soak test.
+#[derive(Debug)]
+struct Ledger {
+    max_reconnect: Duration,
+    /// The next sequence number of each sender.
+    seqs: Mutex<HashMap<usize, u64>>,
+    /// The digests of the messages recorded by the receivers, by receiver, sender and
+    /// sequence number.
+    received: Mutex<HashMap<(usize, usize, u64), u64>>,
+    /// The acknowledged messages, by receiver, sender and sequence number.
+    acknowledged: Mutex<HashMap<(usize, usize, u64), u64>>,
+    /// When each sender started failing to reach each receiver.
+    outages: Mutex<HashMap<(usize, usize), Instant>>,
+    /// The longest time a sender failed to reach a receiver, with the sender and receiver.
+    longest_outage: Mutex<Option<(Duration, usize, usize)>>,
+    /// The number of outages longer than the reconnect limit.
+    violations: AtomicU64,
+    failed: AtomicU64,
+    faults: Faults,
+}
+
+/// The number of injected faults.
+#[derive(Debug, Default)]
+struct Faults {
+    relay_kills: AtomicU64,
+    network_flaps: AtomicU64,
+    key_rotations: AtomicU64,
+}
+
+impl Ledger {
+    fn new(max_reconnect_secs: u64) -> Self {
+        Self {
+            max_reconnect: Duration::from_secs(max_reconnect_secs),
+            seqs: Default::default(),
+            received: Default::default(),
+            acknowledged: Default::default(),
+            outages: Default::default(),
+            longest_outage: Default::default(),
+            violations: Default::default(),
+            failed: Default::default(),
+            faults: Default::default(),
+        }
+    }
+
+    /// Returns the next sequence number of a sender, unique across key rotations.
+    fn next_seq(&self, sender: usize) -> u64 {
+        let mut seqs = self.seqs.lock().expect("poisoned");
+        let seq = seqs.entry(sender).or_default();
+        *seq += 1;
+        *seq
+    }
+
+    /// Records a message received by an endpoint.
+    fn record(&self, receiver: usize, sender: usize, seq: u64, digest: u64) {
+        self.received
+            .lock()
+            .expect("poisoned")
+            .insert((receiver, sender, seq), digest);
+    }
+
+    /// Records a message acknowledged by its receiver, ending an outage between them.
+    fn acknowledged(&self, sender: usize, receiver: usize, seq: u64, digest: u64) {
+        self.acknowledged
+            .lock()
+            .expect("poisoned")
+            .insert((receiver, sender, seq), digest);
+        let since = self
+            .outages
+            .lock()
+            .expect("poisoned")
+            .remove(&(sender, receiver));
+        if let Some(since) = since {
+            let outage = since.elapsed();
+            info!(sender, receiver, ?outage, "reconnected");
+            self.note_outage(outage, sender, receiver);
+        }
+    }
+
+    /// Records a message which was not acknowledged, starting an outage if none is ongoing.
+    fn failed(&self, sender: usize, receiver: usize) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        self.outages
+            .lock()
+            .expect("poisoned")
+            .entry((sender, receiver))
+            .or_insert_with(Instant::now);
+    }
+
+    fn note_outage(&self, outage: Duration, sender: usize, receiver: usize) {
+        if outage > self.max_reconnect {
+            warn!(
+                sender,
+                receiver,
+                ?outage,
+                "outage exceeds {:?}",
+                self.max_reconnect
+            );
+            self.violations.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut longest = self.longest_outage.lock().expect("poisoned");
+        if longest.is_none_or(|(longest, _, _)| longest < outage) {
+            *longest = Some((outage, sender, receiver));
+        }
+    }
+
+    /// Prints the results and checks the invariants.
+    fn report(&self) -> Result<()> {
+        // Outages still ongoing at the end count as well.
+        let ongoing: Vec<_> = self.outages.lock().expect("poisoned").drain().collect();
+        for ((sender, receiver), since) in ongoing {
+            self.note_outage(since.elapsed(), sender, receiver);
+        }
+
+        let received = self.received.lock().expect("poisoned");
+        let acknowledged = self.acknowledged.lock().expect("poisoned");
+        let lost = acknowledged
+            .iter()
+            .filter(|(key, digest)| received.get(key) != Some(digest))
+            .count();
+        let failed = self.failed.load(Ordering::Relaxed);
+        let violations = self.violations.load(Ordering::Relaxed);
+        println!(
+            "messages: {} acknowledged, {} received, {failed} failed",
+            acknowledged.len(),
+            received.len()
+        );
+        println!(
+            "faults: {} relay kills, {} network flaps, {} key rotations",
+            self.faults.relay_kills.load(Ordering::Relaxed),
+            self.faults.network_flaps.load(Ordering::Relaxed),
+            self.faults.key_rotations.load(Ordering::Relaxed),
+        );
+        if let Some((outage, sender, receiver)) = *self.longest_outage.lock().expect("poisoned") {
+            println!("longest outage: {outage:?} from endpoint {sender} to {receiver}");
+        }
+
+        ensure!(!acknowledged.is_empty(), "no message was acknowledged");
+        ensure!(
+            lost == 0,
+            "{lost} acknowledged messages were not recorded by their receiver"
+        );
+        ensure!(
+            violations == 0,
+            "{violations} outages exceeded {:?}",
+            self.max_reconnect
+        );
+        println!("all invariants hold");
+        Ok(())
+    }
+}
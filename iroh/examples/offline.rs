@@ -0,0 +1,70 @@
+//! Connects two endpoints by their node IDs without any n0 infrastructure.
+//!
+//! Both endpoints publish their direct addresses to an in-process pkarr relay and resolve
+//! each other from the in-process DNS server, so this runs without network access.
+//!
+//! ## Usage
+//!
+//!     cargo run --example offline --features=discovery-test-server
+
+use anyhow::Result;
+use iroh::{discovery::test_server::DnsPkarrServer, Endpoint, RelayMode, SecretKey};
+use n0_future::time::Duration;
+
+const ALPN: &[u8] = b"iroh-example/offline/0";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let server = DnsPkarrServer::run().await?;
+
+    let listener = endpoint(&server).await?;
+    let connector = endpoint(&server).await?;
+
+    // Wait until the listener published its addresses.
+    server
+        .on_node(&listener.node_id(), Duration::from_secs(10))
+        .await?;
+
+    let accept = tokio::spawn({
+        let listener = listener.clone();
+        async move {
+            let conn = listener
+                .accept()
+                .await
+                .ok_or_else(|| anyhow::anyhow!("endpoint closed"))?
+                .await?;
+            let (mut send, mut recv) = conn.accept_bi().await?;
+            let msg = recv.read_to_end(1000).await?;
+            send.write_all(&msg).await?;
+            send.finish()?;
+            conn.closed().await;
+            anyhow::Ok(())
+        }
+    });
+
+    // Only the node ID is known, the addresses are discovered.
+    let conn = connector.connect(listener.node_id(), ALPN).await?;
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_all(b"hello offline").await?;
+    send.finish()?;
+    let response = recv.read_to_end(1000).await?;
+    println!("echoed: {}", String::from_utf8_lossy(&response));
+    conn.close(0u32.into(), b"bye");
+    accept.await??;
+
+    connector.close().await;
+    listener.close().await;
+    Ok(())
+}
+
+async fn endpoint(server: &DnsPkarrServer) -> Result<Endpoint> {
+    let secret_key = SecretKey::generate(rand::rngs::OsRng);
+    Endpoint::builder()
+        .secret_key(secret_key.clone())
+        .alpns(vec![ALPN.to_vec()])
+        .relay_mode(RelayMode::Disabled)
+        .discovery(server.discovery(secret_key))
+        .dns_resolver(server.dns_resolver())
+        .bind()
+        .await
+}
@@ -40,8 +40,15 @@
 //! - The [`DhtDiscovery`] also uses the [`pkarr`] system but can also publish and lookup
 //!   records to/from the Mainline DHT.
 //!
+//! For examples and tests running offline, the `discovery-test-server` feature provides an
+//! in-process DNS server and pkarr relay in the `test_server` module.
+//!
 //! To use multiple discovery systems simultaneously use [`ConcurrentDiscovery`] which will
-//! perform lookups to all discovery systems at the same time.
+//! perform lookups to all discovery systems at the same time, skipping systems which failed
+//! repeatedly for a while.
+//!
+//! An [`Endpoint`] limits how many resolutions run at the same time and queues the rest, see
+//! [`DEFAULT_MAX_CONCURRENT_DISCOVERY`] and [`Builder::max_concurrent_discovery`].
 //!
 //! # Examples
 //!
@@ -95,6 +102,7 @@
 //!
 //! [`RelayUrl`]: crate::RelayUrl
 //! [`Builder::discovery`]: crate::endpoint::Builder::discovery
+//! [`Builder::max_concurrent_discovery`]: crate::endpoint::Builder::max_concurrent_discovery
 //! [`DnsDiscovery`]: dns::DnsDiscovery
 //! [Number 0]: https://n0.computer
 //! [`PkarrResolver`]: pkarr::PkarrResolver
@@ -104,19 +112,27 @@
 //! [`LocalSwarmDiscovery`]: local_swarm_discovery::LocalSwarmDiscovery
 //! [`StaticProvider`]: static_provider::StaticProvider
 
-use std::{collections::BTreeSet, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{BTreeSet, VecDeque},
+    net::SocketAddr,
+    num::{NonZeroU32, NonZeroUsize},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
 
 use anyhow::{anyhow, ensure, Result};
-use iroh_base::{NodeAddr, NodeId, RelayUrl};
+use iroh_base::{inc, inc_by, NodeAddr, NodeId, RelayUrl};
 use n0_future::{
-    stream::{Boxed as BoxStream, StreamExt},
+    future::Boxed as BoxFuture,
+    stream::{Boxed as BoxStream, Stream, StreamExt},
     task::{self, AbortOnDropHandle},
-    time::{self, Duration},
+    time::{self, Duration, Instant},
 };
 use tokio::sync::oneshot;
 use tracing::{debug, error_span, warn, Instrument};
 
-use crate::Endpoint;
+use crate::{magicsock::Metrics as MagicsockMetrics, Endpoint};
 
 pub mod dns;
 
@@ -124,6 +140,8 @@ pub mod dns;
 pub mod local_swarm_discovery;
 pub mod pkarr;
 pub mod static_provider;
+#[cfg(any(test, feature = "discovery-test-server"))]
+pub mod test_server;
 
 /// Node discovery for [`super::Endpoint`].
 ///
@@ -188,6 +206,19 @@ pub trait Discovery: std::fmt::Debug + Send + Sync {
     fn subscribe(&self) -> Option<BoxStream<DiscoveryItem>> {
         None
     }
+
+    /// Removes the published addressing information from the discovery mechanism.
+    ///
+    /// Called by [`Endpoint::shutdown`] so that other nodes stop dialing a node which
+    /// is going away.  The returned future completes once the removal is done, the
+    /// endpoint waits for it at most until the shutdown deadline.  After unpublishing
+    /// the service should ignore further calls to [`Discovery::publish`].
+    ///
+    /// Discovery systems without published state, or whose published records expire
+    /// by themselves, do not need to implement this method.
+    fn unpublish(&self) -> Option<BoxFuture<Result<()>>> {
+        None
+    }
 }
 
 impl<T: Discovery> Discovery for Arc<T> {}
@@ -211,9 +242,18 @@ pub struct DiscoveryItem {
 /// A discovery service that combines multiple discovery sources.
 ///
 /// The discovery services will resolve concurrently.
+///
+/// The health of each service is tracked: a lookup succeeds once the service yields an
+/// address, and fails if it yields an error or ends without any address.  A service failing
+/// too many lookups in a row is demoted for a cooldown period, see [`DemotionConfig`].
+/// Demoted services are only queried if none of the healthy services found an address for
+/// the node, once all of them ended, or if there are no healthy services at all.
+/// The tracked state is available from [`ConcurrentDiscovery::health`].
 #[derive(Debug, Default)]
 pub struct ConcurrentDiscovery {
-    services: Vec<Box<dyn Discovery>>,
+    services: Vec<Arc<dyn Discovery>>,
+    health: DiscoveryHealth,
+    demotion: DemotionConfig,
 }
 
 impl ConcurrentDiscovery {
@@ -224,12 +264,51 @@ impl ConcurrentDiscovery {
 
     /// Creates a new [`ConcurrentDiscovery`].
     pub fn from_services(services: Vec<Box<dyn Discovery>>) -> Self {
-        Self { services }
+        services.into()
     }
 
     /// Adds a [`Discovery`] service.
     pub fn add(&mut self, service: impl Discovery + 'static) {
-        self.services.push(Box::new(service));
+        self.services.push(Arc::new(service));
+        self.health.push();
+    }
+
+    /// Sets when failing services are demoted.
+    pub fn with_demotion(mut self, demotion: DemotionConfig) -> Self {
+        self.demotion = demotion;
+        self
+    }
+
+    /// Returns a handle to the health of the discovery services.
+    ///
+    /// The handle stays valid after the [`ConcurrentDiscovery`] was passed to the
+    /// [`Endpoint`].
+    pub fn health(&self) -> DiscoveryHealth {
+        self.health.clone()
+    }
+
+    /// Resolves with the services at `indices`, recording the outcome in the health.
+    fn resolve_tracked(
+        &self,
+        indices: &[usize],
+        endpoint: &Endpoint,
+        node_id: NodeId,
+    ) -> Vec<BoxStream<Result<DiscoveryItem>>> {
+        indices
+            .iter()
+            .filter_map(|&index| {
+                let stream = self.services[index].resolve(endpoint.clone(), node_id)?;
+                let stream = TrackedStream {
+                    inner: stream,
+                    health: self.health.clone(),
+                    demotion: self.demotion.clone(),
+                    index,
+                    started: Instant::now(),
+                    done: false,
+                };
+                Some(stream.boxed())
+            })
+            .collect()
     }
 }
 
@@ -238,8 +317,16 @@ where
     T: IntoIterator<Item = Box<dyn Discovery>>,
 {
     fn from(iter: T) -> Self {
-        let services = iter.into_iter().collect::<Vec<_>>();
-        Self { services }
+        let services = iter.into_iter().map(Arc::from).collect::<Vec<_>>();
+        let health = DiscoveryHealth::default();
+        for _ in &services {
+            health.push();
+        }
+        Self {
+            services,
+            health,
+            demotion: DemotionConfig::default(),
+        }
     }
 }
 
@@ -255,13 +342,33 @@ impl Discovery for ConcurrentDiscovery {
         endpoint: Endpoint,
         node_id: NodeId,
     ) -> Option<BoxStream<Result<DiscoveryItem>>> {
-        let streams = self
-            .services
-            .iter()
-            .filter_map(|service| service.resolve(endpoint.clone(), node_id));
-
-        let streams = n0_future::MergeBounded::from_iter(streams);
-        Some(Box::pin(streams))
+        let demoted = self.health.demoted(Instant::now());
+        let (demoted, healthy): (Vec<usize>, Vec<usize>) =
+            (0..self.services.len()).partition(|index| demoted[*index]);
+        let streams = self.resolve_tracked(&healthy, &endpoint, node_id);
+        if streams.is_empty() {
+            // Fall back to the demoted services rather than not resolving at all.
+            let streams = self.resolve_tracked(&demoted, &endpoint, node_id);
+            return Some(Box::pin(n0_future::MergeBounded::from_iter(streams)));
+        }
+        let streams = Box::pin(n0_future::MergeBounded::from_iter(streams));
+        if demoted.is_empty() {
+            return Some(streams);
+        }
+        let this = Self {
+            services: self.services.clone(),
+            health: self.health.clone(),
+            demotion: self.demotion.clone(),
+        };
+        let fallback = move || {
+            let streams = this.resolve_tracked(&demoted, &endpoint, node_id);
+            Box::pin(n0_future::MergeBounded::from_iter(streams)) as BoxStream<_>
+        };
+        Some(Box::pin(FallbackStream {
+            inner: streams,
+            found: false,
+            fallback: Some(Box::new(fallback)),
+        }))
     }
 
     fn subscribe(&self) -> Option<BoxStream<DiscoveryItem>> {
@@ -275,6 +382,353 @@ impl Discovery for ConcurrentDiscovery {
         let streams = n0_future::MergeBounded::from_iter(streams);
         Some(Box::pin(streams))
     }
+
+    fn unpublish(&self) -> Option<BoxFuture<Result<()>>> {
+        let futs = self
+            .services
+            .iter()
+            .filter_map(|service| service.unpublish())
+            .collect::<Vec<_>>();
+        if futs.is_empty() {
+            return None;
+        }
+        let fut = async move {
+            // Unpublish from all services, even if some of them fail.
+            let mut res = Ok(());
+            for r in n0_future::join_all(futs).await {
+                if let Err(err) = r {
+                    res = Err(err);
+                }
+            }
+            res
+        };
+        Some(Box::pin(fut))
+    }
+}
+
+/// Configuration of the demotion of failing services of a [`ConcurrentDiscovery`].
+#[derive(Debug, Clone)]
+pub struct DemotionConfig {
+    /// The number of consecutive failed lookups after which a service is demoted.
+    pub failure_threshold: NonZeroU32,
+    /// How long a demoted service is not queried.
+    ///
+    /// Once the cooldown is over the service is queried again, and demoted again right away
+    /// if that lookup fails as well.
+    pub cooldown: Duration,
+}
+
+impl Default for DemotionConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: NonZeroU32::new(3).expect("non-zero"),
+            cooldown: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The health of the services of a [`ConcurrentDiscovery`].
+///
+/// Created with [`ConcurrentDiscovery::health`].
+#[derive(Debug, Clone, Default)]
+pub struct DiscoveryHealth(Arc<Mutex<Vec<HealthState>>>);
+
+/// The health of a single service of a [`ConcurrentDiscovery`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceHealth {
+    /// The position of the service in the [`ConcurrentDiscovery`].
+    pub index: usize,
+    /// The provenance of the last address found by the service, if any.
+    pub provenance: Option<&'static str>,
+    /// The number of successful lookups.
+    pub successes: u64,
+    /// The number of failed lookups.
+    pub failures: u64,
+    /// The number of failed lookups since the last successful one.
+    pub consecutive_failures: u32,
+    /// The smoothed time until the service found the first address.
+    pub latency: Option<Duration>,
+    /// The remaining cooldown if the service is demoted.
+    pub demoted_for: Option<Duration>,
+}
+
+impl ServiceHealth {
+    /// Whether the service is currently demoted.
+    pub fn is_demoted(&self) -> bool {
+        self.demoted_for.is_some()
+    }
+}
+
+#[derive(Debug, Default)]
+struct HealthState {
+    provenance: Option<&'static str>,
+    successes: u64,
+    failures: u64,
+    consecutive_failures: u32,
+    latency: Option<Duration>,
+    demoted_until: Option<Instant>,
+}
+
+impl DiscoveryHealth {
+    /// Returns the health of all services, in the order they were added.
+    pub fn services(&self) -> Vec<ServiceHealth> {
+        let now = Instant::now();
+        let state = self.0.lock().expect("poisoned");
+        state
+            .iter()
+            .enumerate()
+            .map(|(index, state)| ServiceHealth {
+                index,
+                provenance: state.provenance,
+                successes: state.successes,
+                failures: state.failures,
+                consecutive_failures: state.consecutive_failures,
+                latency: state.latency,
+                demoted_for: state
+                    .demoted_until
+                    .filter(|until| *until > now)
+                    .map(|until| until - now),
+            })
+            .collect()
+    }
+
+    fn push(&self) {
+        self.0
+            .lock()
+            .expect("poisoned")
+            .push(HealthState::default());
+    }
+
+    /// Returns which services are demoted at `now`.
+    fn demoted(&self, now: Instant) -> Vec<bool> {
+        let state = self.0.lock().expect("poisoned");
+        state
+            .iter()
+            .map(|state| state.demoted_until.is_some_and(|until| until > now))
+            .collect()
+    }
+
+    fn record_success(&self, index: usize, provenance: &'static str, latency: Duration) {
+        let mut state = self.0.lock().expect("poisoned");
+        let state = &mut state[index];
+        state.provenance = Some(provenance);
+        state.successes += 1;
+        state.consecutive_failures = 0;
+        state.demoted_until = None;
+        // Exponentially weighted moving average, like the smoothed RTT of TCP.
+        state.latency = Some(match state.latency {
+            Some(avg) => (avg * 7 + latency) / 8,
+            None => latency,
+        });
+    }
+
+    fn record_failure(&self, index: usize, demotion: &DemotionConfig) {
+        let mut state = self.0.lock().expect("poisoned");
+        let state = &mut state[index];
+        state.failures += 1;
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        if state.consecutive_failures >= demotion.failure_threshold.get() {
+            debug!(
+                index,
+                failures = state.consecutive_failures,
+                cooldown = ?demotion.cooldown,
+                "demoting discovery service"
+            );
+            state.demoted_until = Some(Instant::now() + demotion.cooldown);
+        }
+    }
+}
+
+/// A resolve stream of a service, recording the outcome in the [`DiscoveryHealth`].
+///
+/// Only the first item counts, a stream dropped before yielding anything is not recorded.
+struct TrackedStream {
+    inner: BoxStream<Result<DiscoveryItem>>,
+    health: DiscoveryHealth,
+    demotion: DemotionConfig,
+    index: usize,
+    started: Instant,
+    done: bool,
+}
+
+impl Stream for TrackedStream {
+    type Item = Result<DiscoveryItem>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let res = std::task::ready!(self.inner.poll_next(cx));
+        if !self.done {
+            self.done = true;
+            match &res {
+                Some(Ok(item)) => {
+                    let latency = self.started.elapsed();
+                    self.health
+                        .record_success(self.index, item.provenance, latency);
+                }
+                Some(Err(_)) | None => self.health.record_failure(self.index, &self.demotion),
+            }
+        }
+        Poll::Ready(res)
+    }
+}
+
+/// A resolve stream of the healthy services, followed by the demoted services if the
+/// healthy ones did not find any address.
+struct FallbackStream {
+    inner: BoxStream<Result<DiscoveryItem>>,
+    found: bool,
+    fallback: Option<Box<dyn FnOnce() -> BoxStream<Result<DiscoveryItem>> + Send>>,
+}
+
+impl Stream for FallbackStream {
+    type Item = Result<DiscoveryItem>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match std::task::ready!(self.inner.poll_next(cx)) {
+                Some(item) => {
+                    self.found |= item.is_ok();
+                    return Poll::Ready(Some(item));
+                }
+                None => match self.fallback.take() {
+                    Some(fallback) if !self.found => {
+                        debug!("falling back to demoted discovery services");
+                        self.inner = fallback();
+                    }
+                    _ => return Poll::Ready(None),
+                },
+            }
+        }
+    }
+}
+
+/// The default of [`Builder::max_concurrent_discovery`], 16 resolutions.
+///
+/// The limit keeps dialing many nodes at once from flooding the DNS servers and pkarr
+/// relays.  A resolution usually holds its slot for a single DNS or pkarr lookup, so this
+/// keeps up with dozens of dials per second while bursts of hundreds of dials are queued
+/// rather than sent to the discovery services all at once.  Endpoints dialing many nodes at once, e.g.
+/// on startup, may want to raise it.
+///
+/// [`Builder::max_concurrent_discovery`]: crate::endpoint::Builder::max_concurrent_discovery
+pub const DEFAULT_MAX_CONCURRENT_DISCOVERY: NonZeroUsize = match NonZeroUsize::new(16) {
+    Some(max) => max,
+    None => panic!("non-zero"),
+};
+
+/// The priority of a discovery resolution waiting in the [`DiscoveryQueue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Priority {
+    /// A dial is waiting for the result, as there is no other address for the node.
+    Interactive,
+    /// The node has addresses already, the resolution only looks for better ones.
+    Background,
+}
+
+/// Limits the number of concurrent discovery resolutions of an endpoint.
+///
+/// Resolutions beyond the limit wait for a [`DiscoveryPermit`], interactive ones before
+/// background ones and otherwise in the order they were queued.
+#[derive(Debug, Clone)]
+pub(crate) struct DiscoveryQueue(Arc<Mutex<QueueState>>);
+
+#[derive(Debug)]
+struct QueueState {
+    max_concurrent: usize,
+    running: usize,
+    interactive: VecDeque<oneshot::Sender<DiscoveryPermit>>,
+    background: VecDeque<oneshot::Sender<DiscoveryPermit>>,
+}
+
+/// Allows a discovery resolution to run, released when dropped.
+#[derive(Debug)]
+pub(crate) struct DiscoveryPermit(Option<DiscoveryQueue>);
+
+impl DiscoveryQueue {
+    pub(crate) fn new(max_concurrent: NonZeroUsize) -> Self {
+        Self(Arc::new(Mutex::new(QueueState {
+            max_concurrent: max_concurrent.get(),
+            running: 0,
+            interactive: VecDeque::new(),
+            background: VecDeque::new(),
+        })))
+    }
+
+    /// Waits until the resolution may run.
+    pub(crate) async fn acquire(&self, priority: Priority) -> DiscoveryPermit {
+        match priority {
+            Priority::Interactive => inc!(MagicsockMetrics, discovery_interactive),
+            Priority::Background => inc!(MagicsockMetrics, discovery_background),
+        }
+        let rx = {
+            let mut state = self.0.lock().expect("poisoned");
+            if state.running < state.max_concurrent {
+                state.running += 1;
+                return DiscoveryPermit(Some(self.clone()));
+            }
+            let (tx, rx) = oneshot::channel();
+            match priority {
+                Priority::Interactive => state.interactive.push_back(tx),
+                Priority::Background => state.background.push_back(tx),
+            }
+            debug!(
+                ?priority,
+                max_concurrent = state.max_concurrent,
+                waiting = state.interactive.len() + state.background.len(),
+                "discovery resolution queued, too many are running"
+            );
+            rx
+        };
+        inc!(MagicsockMetrics, discovery_queued);
+        let start = Instant::now();
+        let permit = rx.await.expect("the queue keeps the senders");
+        inc_by!(
+            MagicsockMetrics,
+            discovery_queue_wait_ms,
+            start.elapsed().as_millis() as u64
+        );
+        permit
+    }
+
+    /// Returns the number of running and of waiting resolutions.
+    #[cfg(test)]
+    fn len(&self) -> (usize, usize) {
+        let state = self.0.lock().expect("poisoned");
+        (
+            state.running,
+            state.interactive.len() + state.background.len(),
+        )
+    }
+}
+
+impl Drop for DiscoveryPermit {
+    fn drop(&mut self) {
+        let Some(queue) = self.0.take() else {
+            return;
+        };
+        // Hand the slot over to the next waiter, skipping those which gave up.
+        let mut permit = DiscoveryPermit(Some(queue.clone()));
+        loop {
+            let next = {
+                let mut state = queue.0.lock().expect("poisoned");
+                let next = state
+                    .interactive
+                    .pop_front()
+                    .or_else(|| state.background.pop_front());
+                if next.is_none() {
+                    state.running -= 1;
+                }
+                next
+            };
+            let Some(next) = next else {
+                permit.0 = None;
+                return;
+            };
+            match next.send(permit) {
+                Ok(()) => return,
+                Err(returned) => permit = returned,
+            }
+        }
+    }
 }
 
 /// Maximum duration since the last control or data message received from an endpoint to make us
@@ -294,9 +748,10 @@ impl DiscoveryTask {
         let (on_first_tx, on_first_rx) = oneshot::channel();
         let me = ep.node_id();
         let task = task::spawn(
-            async move { Self::run(ep, node_id, on_first_tx).await }.instrument(
-                error_span!("discovery", me = %me.fmt_short(), node = %node_id.fmt_short()),
-            ),
+            async move { Self::run(ep, node_id, Priority::Interactive, on_first_tx).await }
+                .instrument(
+                    error_span!("discovery", me = %me.fmt_short(), node = %node_id.fmt_short()),
+                ),
         );
         Ok(Self {
             task: AbortOnDropHandle::new(task),
@@ -336,7 +791,7 @@ impl DiscoveryTask {
                         return;
                     }
                 }
-                Self::run(ep, node_id, on_first_tx).await
+                Self::run(ep, node_id, Priority::Background, on_first_tx).await
             }
             .instrument(
                 error_span!("discovery", me = %me.fmt_short(), node = %node_id.fmt_short()),
@@ -389,7 +844,17 @@ impl DiscoveryTask {
         }
     }
 
-    async fn run(ep: Endpoint, node_id: NodeId, on_first_tx: oneshot::Sender<Result<()>>) {
+    /// Runs the resolution once the [`DiscoveryQueue`] of the endpoint permits it.
+    ///
+    /// The permit is released once the first address is found, the resolution keeps
+    /// running for further addresses without counting towards the limit.
+    async fn run(
+        ep: Endpoint,
+        node_id: NodeId,
+        priority: Priority,
+        on_first_tx: oneshot::Sender<Result<()>>,
+    ) {
+        let mut permit = Some(ep.discovery_queue().acquire(priority).await);
         let mut stream = match Self::create_stream(&ep, node_id) {
             Ok(stream) => stream,
             Err(err) => {
@@ -408,6 +873,7 @@ impl DiscoveryTask {
                     }
                     debug!(provenance = %r.provenance, addr = ?r.node_addr, "discovery: new address found");
                     ep.add_node_addr_with_source(r.node_addr, r.provenance).ok();
+                    permit.take();
                     if let Some(tx) = on_first_tx.take() {
                         tx.send(Ok(())).ok();
                     }
@@ -747,6 +1213,275 @@ mod tests {
 
         Ok(())
     }
+
+    /// A discovery resolving every node immediately, or failing to.
+    #[derive(Debug, Clone)]
+    struct CountingDiscovery {
+        fail: Arc<std::sync::atomic::AtomicBool>,
+        calls: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl CountingDiscovery {
+        fn new(fail: bool) -> Self {
+            Self {
+                fail: Arc::new(fail.into()),
+                calls: Default::default(),
+            }
+        }
+
+        fn set_fail(&self, fail: bool) {
+            self.fail.store(fail, std::sync::atomic::Ordering::Relaxed);
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(std::sync::atomic::Ordering::Relaxed)
+        }
+    }
+
+    impl Discovery for CountingDiscovery {
+        fn resolve(
+            &self,
+            _endpoint: Endpoint,
+            node_id: NodeId,
+        ) -> Option<BoxStream<Result<DiscoveryItem>>> {
+            self.calls
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let item = match self.fail.load(std::sync::atomic::Ordering::Relaxed) {
+                true => Err(anyhow!("lookup failed")),
+                false => Ok(DiscoveryItem {
+                    node_addr: NodeAddr::new(node_id),
+                    provenance: "counting",
+                    last_updated: None,
+                }),
+            };
+            Some(n0_future::stream::once(item).boxed())
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_concurrent_discovery_demotion() -> TestResult {
+        let failing = CountingDiscovery::new(true);
+        let working = CountingDiscovery::new(false);
+        let mut disco = ConcurrentDiscovery::empty().with_demotion(DemotionConfig {
+            failure_threshold: NonZeroU32::new(2).unwrap(),
+            cooldown: Duration::from_millis(500),
+        });
+        disco.add(failing.clone());
+        disco.add(working.clone());
+        let health = disco.health();
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let node_id = SecretKey::generate(rand::thread_rng()).public();
+        let resolve = || {
+            let stream = disco.resolve(ep.clone(), node_id).unwrap();
+            stream.collect::<Vec<_>>()
+        };
+
+        for _ in 0..2 {
+            assert_eq!(resolve().await.len(), 2);
+        }
+        let [failing_health, working_health] = &health.services()[..] else {
+            panic!("expected two services");
+        };
+        assert_eq!(failing_health.failures, 2);
+        assert!(failing_health.is_demoted());
+        assert_eq!(failing_health.provenance, None);
+        assert_eq!(working_health.successes, 2);
+        assert_eq!(working_health.provenance, Some("counting"));
+        assert!(working_health.latency.is_some());
+        assert!(!working_health.is_demoted());
+
+        // The demoted service is skipped.
+        let items = resolve().await;
+        assert_eq!(items.len(), 1);
+        assert!(items[0].is_ok());
+        assert_eq!(failing.calls(), 2);
+
+        // It is queried again after the cooldown, and demoted again on failure.
+        time::sleep(Duration::from_millis(600)).await;
+        assert!(!health.services()[0].is_demoted());
+        assert_eq!(resolve().await.len(), 2);
+        assert_eq!(failing.calls(), 3);
+        assert!(health.services()[0].is_demoted());
+        assert_eq!(health.services()[0].consecutive_failures, 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_concurrent_discovery_demoted_fallback() -> TestResult {
+        let failing = CountingDiscovery::new(true);
+        let disco = ConcurrentDiscovery::from_services(vec![Box::new(failing.clone())])
+            .with_demotion(DemotionConfig {
+                failure_threshold: NonZeroU32::new(1).unwrap(),
+                cooldown: Duration::from_secs(60),
+            });
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let node_id = SecretKey::generate(rand::thread_rng()).public();
+
+        for calls in 1..=2 {
+            let items = disco
+                .resolve(ep.clone(), node_id)
+                .unwrap()
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(items.len(), 1);
+            assert_eq!(failing.calls(), calls);
+            assert!(disco.health().services()[0].is_demoted());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_concurrent_discovery_demoted_after_healthy() -> TestResult {
+        let demoted = CountingDiscovery::new(true);
+        let healthy = CountingDiscovery::new(false);
+        let mut disco = ConcurrentDiscovery::empty().with_demotion(DemotionConfig {
+            failure_threshold: NonZeroU32::new(1).unwrap(),
+            cooldown: Duration::from_secs(60),
+        });
+        disco.add(demoted.clone());
+        disco.add(healthy.clone());
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let node_id = SecretKey::generate(rand::thread_rng()).public();
+        let resolve = || {
+            let stream = disco.resolve(ep.clone(), node_id).unwrap();
+            stream.collect::<Vec<_>>()
+        };
+
+        assert_eq!(resolve().await.len(), 2);
+        assert!(disco.health().services()[0].is_demoted());
+
+        // The demoted service is queried once the healthy one failed.
+        healthy.set_fail(true);
+        demoted.set_fail(false);
+        let items = resolve().await;
+        assert_eq!(items.len(), 2);
+        assert!(items[0].is_err());
+        assert!(items[1].is_ok());
+        assert_eq!(demoted.calls(), 2);
+        assert!(!disco.health().services()[0].is_demoted());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_discovery_queue_priority() -> TestResult {
+        let queue = DiscoveryQueue::new(NonZeroUsize::new(1).unwrap());
+        let permit = queue.acquire(Priority::Background).await;
+        let (order_s, mut order_r) = tokio::sync::mpsc::unbounded_channel();
+        let mut tasks = Vec::new();
+        for (name, priority) in [
+            ("background", Priority::Background),
+            ("interactive", Priority::Interactive),
+        ] {
+            let task_queue = queue.clone();
+            let order_s = order_s.clone();
+            tasks.push(AbortOnDropHandle::new(task::spawn(async move {
+                let _permit = task_queue.acquire(priority).await;
+                order_s.send(name).unwrap();
+            })));
+            while queue.len().1 < tasks.len() {
+                time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+        assert_eq!(queue.len(), (1, 2));
+
+        // The interactive resolution queued last runs first.
+        drop(permit);
+        assert_eq!(order_r.recv().await, Some("interactive"));
+        assert_eq!(order_r.recv().await, Some("background"));
+        for task in tasks {
+            task.await?;
+        }
+        assert_eq!(queue.len(), (0, 0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_discovery_queue_cancelled_waiter() -> TestResult {
+        let queue = DiscoveryQueue::new(NonZeroUsize::new(1).unwrap());
+        let permit = queue.acquire(Priority::Interactive).await;
+        let waiter = task::spawn({
+            let queue = queue.clone();
+            async move { queue.acquire(Priority::Interactive).await }
+        });
+        while queue.len().1 == 0 {
+            time::sleep(Duration::from_millis(1)).await;
+        }
+        waiter.abort();
+        assert!(waiter.await.is_err());
+
+        // The slot is not handed to the cancelled waiter.
+        drop(permit);
+        assert_eq!(queue.len(), (0, 0));
+        let _permit =
+            time::timeout(Duration::from_secs(1), queue.acquire(Priority::Background)).await?;
+        assert_eq!(queue.len(), (1, 0));
+        Ok(())
+    }
+
+    /// A discovery which never finds anything.
+    #[derive(Debug)]
+    struct PendingDiscovery;
+
+    impl Discovery for PendingDiscovery {
+        fn resolve(
+            &self,
+            _endpoint: Endpoint,
+            _node_id: NodeId,
+        ) -> Option<BoxStream<Result<DiscoveryItem>>> {
+            Some(Box::pin(n0_future::stream::pending()))
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_endpoint_max_concurrent_discovery() -> TestResult {
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .discovery(Box::new(PendingDiscovery))
+            .max_concurrent_discovery(NonZeroUsize::new(1).unwrap())
+            .bind()
+            .await?;
+        let dials = (0..3)
+            .map(|_| {
+                let ep = ep.clone();
+                let node_id = SecretKey::generate(rand::thread_rng()).public();
+                AbortOnDropHandle::new(task::spawn(async move {
+                    ep.connect(node_id, TEST_ALPN).await.ok();
+                }))
+            })
+            .collect::<Vec<_>>();
+        time::timeout(Duration::from_secs(5), async {
+            while ep.discovery_queue().len() != (1, 2) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        // Giving up on the dials releases their resolutions.
+        drop(dials);
+        time::timeout(Duration::from_secs(5), async {
+            while ep.discovery_queue().len() != (0, 0) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        Ok(())
+    }
 }
 
 /// This module contains end-to-end tests for DNS node discovery.
@@ -764,10 +1499,12 @@ mod test_dns_pkarr {
 
     use crate::{
         discovery::pkarr::PkarrPublisher,
-        dns::{node_info::NodeInfo, DnsResolver},
-        test_utils::{
-            dns_server::run_dns_server, pkarr_dns_state::State, run_relay_server, DnsPkarrServer,
+        discovery::test_server::{
+            dns_server::run_dns_server, pkarr_dns_state::State, DnsPkarrServer,
         },
+        discovery::Discovery,
+        dns::{node_info::NodeInfo, DnsResolver},
+        test_utils::run_relay_server,
         Endpoint, RelayMode,
     };
 
@@ -829,6 +1566,34 @@ mod test_dns_pkarr {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn pkarr_unpublish() -> Result<()> {
+        let origin = "testdns.example".to_string();
+        let dns_pkarr_server = DnsPkarrServer::run_with_origin(origin.clone()).await?;
+
+        let secret_key = SecretKey::generate(rand::thread_rng());
+        let node_id = secret_key.public();
+        let relay_url = Some("https://relay.example".parse().unwrap());
+
+        let publisher = PkarrPublisher::new(secret_key, dns_pkarr_server.pkarr_url.clone());
+        publisher.update_addr_info(relay_url.as_ref(), &Default::default());
+        dns_pkarr_server.on_node(&node_id, PUBLISH_TIMEOUT).await?;
+        let resolver = dns_pkarr_server.dns_resolver();
+        assert!(resolver.lookup_node_by_id(&node_id, &origin).await.is_ok());
+
+        publisher.unpublish().expect("pkarr unpublishes").await?;
+        // A new resolver, to not hit the cache of the previous lookup.
+        let resolver = dns_pkarr_server.dns_resolver();
+        assert!(resolver.lookup_node_by_id(&node_id, &origin).await.is_err());
+
+        // Publishing again after unpublishing is ignored.
+        publisher.update_addr_info(relay_url.as_ref(), &Default::default());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(resolver.lookup_node_by_id(&node_id, &origin).await.is_err());
+        Ok(())
+    }
+
     const TEST_ALPN: &[u8] = b"TEST";
 
     #[tokio::test]
@@ -49,7 +49,7 @@ use std::{collections::BTreeSet, net::SocketAddr, sync::Arc};
 use anyhow::{anyhow, bail, Result};
 use iroh_base::{NodeId, RelayUrl, SecretKey};
 use n0_future::{
-    boxed::BoxStream,
+    boxed::{BoxFuture, BoxStream},
     task::{self, AbortOnDropHandle},
     time::{self, Duration, Instant},
 };
@@ -116,7 +116,9 @@ pub const DEFAULT_REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 5);
 pub struct PkarrPublisher {
     node_id: NodeId,
     watchable: Watchable<Option<NodeInfo>>,
-    _drop_guard: Arc<AbortOnDropHandle<()>>,
+    #[debug(skip)]
+    service: PublisherService,
+    publish_task: Arc<AbortOnDropHandle<()>>,
 }
 
 impl PkarrPublisher {
@@ -160,13 +162,15 @@ impl PkarrPublisher {
         };
         let join_handle = task::spawn(
             service
+                .clone()
                 .run()
                 .instrument(error_span!("pkarr_publish", me=%node_id.fmt_short())),
         );
         Self {
             watchable,
             node_id,
-            _drop_guard: Arc::new(AbortOnDropHandle::new(join_handle)),
+            service,
+            publish_task: Arc::new(AbortOnDropHandle::new(join_handle)),
         }
     }
 
@@ -210,6 +214,13 @@ impl Discovery for PkarrPublisher {
     fn publish(&self, url: Option<&RelayUrl>, addrs: &BTreeSet<SocketAddr>) {
         self.update_addr_info(url, addrs);
     }
+
+    fn unpublish(&self) -> Option<BoxFuture<Result<()>>> {
+        // Stop (re)publishing the node info before replacing it.
+        self.publish_task.abort();
+        let service = self.service.clone();
+        Some(Box::pin(async move { service.unpublish().await }))
+    }
 }
 
 /// Publish node info to a pkarr relay.
@@ -278,6 +289,18 @@ impl PublisherService {
         self.pkarr_client.publish(&signed_packet).await?;
         Ok(())
     }
+
+    /// Replaces the published node info with a packet without any records.
+    async fn unpublish(&self) -> Result<()> {
+        info!(
+            pkarr_relay = %self.pkarr_client.pkarr_relay_url,
+            "Unpublish node info from pkarr"
+        );
+        let info = NodeInfo::new(self.secret_key.public(), None, Default::default());
+        let signed_packet = info.to_pkarr_signed_packet(&self.secret_key, self.ttl)?;
+        self.pkarr_client.publish(&signed_packet).await?;
+        Ok(())
+    }
 }
 
 /// Resolver of node discovery information from a [pkarr] relay.
@@ -398,10 +421,10 @@ impl PkarrRelayClient {
             .await?;
 
         if !response.status().is_success() {
-            bail!(format!(
-                "Publish request failed with status {}",
-                response.status()
-            ))
+            let status = response.status();
+            // The body may tell why the publish was rejected.
+            let body = response.text().await.unwrap_or_default();
+            bail!("Publish request failed with status {status}: {body}")
         }
 
         Ok(())
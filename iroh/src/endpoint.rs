@@ -16,30 +16,40 @@ use std::{
     collections::BTreeSet,
     future::{Future, IntoFuture},
     net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    num::NonZeroUsize,
+    ops::RangeInclusive,
+    path::PathBuf,
     pin::Pin,
     sync::Arc,
     task::Poll,
 };
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use iroh_base::{NodeAddr, NodeId, RelayUrl, SecretKey};
 use iroh_relay::RelayMap;
-use n0_future::time::Duration;
+use n0_future::{
+    task::AbortOnDropHandle,
+    time::{self, Duration, Instant},
+};
 use pin_project::pin_project;
 use tracing::{debug, instrument, trace, warn};
 use url::Url;
 
 use crate::{
     discovery::{
-        dns::DnsDiscovery, pkarr::PkarrPublisher, ConcurrentDiscovery, Discovery, DiscoveryTask,
+        dns::DnsDiscovery, pkarr::PkarrPublisher, ConcurrentDiscovery, Discovery, DiscoveryQueue,
+        DiscoveryTask, DEFAULT_MAX_CONCURRENT_DISCOVERY,
     },
     dns::DnsResolver,
-    magicsock::{self, Handle, NodeIdMappedAddr},
+    magicsock::{self, Handle, NodeIdMappedAddr, PortSelection, ReportCache},
     tls,
     watchable::Watcher,
 };
 
+mod connection_id;
+mod pool;
 mod rtt_actor;
+mod send_queue;
 
 // Missing still: SendDatagram and ConnectionClose::frame_type's Type.
 pub use quinn::{
@@ -58,9 +68,13 @@ pub use quinn_proto::{
     FrameStats, PathStats, TransportError, TransportErrorCode, UdpStats, Written,
 };
 
-use self::rtt_actor::RttMessage;
+pub use self::connection_id::{ConnectionIdConfig, DEFAULT_CONNECTION_ID_LEN};
+pub use self::send_queue::{SendQueue, TrackedSendStream};
+use self::{rtt_actor::RttMessage, send_queue::SendQueueState};
 pub use super::magicsock::{
-    ConnectionType, ControlMsg, DirectAddr, DirectAddrInfo, DirectAddrType, RemoteInfo, Source,
+    ClearReason, ConnectionType, ControlMsg, DirectAddr, DirectAddrInfo, DirectAddrType,
+    PathCandidate, PathInfo, PathTimers, PathTransition, Reachability, RemoteInfo, SelectedPath,
+    Source, TransitionReason, ValidationStatus,
 };
 
 /// The delay to fall back to discovery when direct addresses fail.
@@ -70,8 +84,18 @@ pub use super::magicsock::{
 /// is still no connection the configured [`Discovery`] will be used however.
 const DISCOVERY_WAIT_PERIOD: Duration = Duration::from_millis(500);
 
+/// How long [`Endpoint::shutdown`] waits for the close frames of the connections it closes.
+const SHUTDOWN_CLOSE_TIMEOUT: Duration = Duration::from_millis(500);
+
 type DiscoveryBuilder = Box<dyn FnOnce(&SecretKey) -> Option<Box<dyn Discovery>> + Send + Sync>;
 
+type AcceptPolicy = Arc<dyn Fn(&Connection) -> bool + Send + Sync>;
+
+pub use crate::tls::certificate::MAX_PEER_TOKEN_SIZE;
+
+/// The error code connections refused by the [`Builder::accept_policy`] are closed with.
+pub const ACCEPT_POLICY_REFUSED: VarInt = VarInt::from_u32(403);
+
 /// Defines the mode of path selection for all traffic flowing through
 /// the endpoint.
 #[cfg(any(test, feature = "test-utils"))]
@@ -96,6 +120,7 @@ pub struct Builder {
     relay_mode: RelayMode,
     alpn_protocols: Vec<Vec<u8>>,
     transport_config: quinn::TransportConfig,
+    connection_ids: ConnectionIdConfig,
     keylog: bool,
     #[debug(skip)]
     discovery: Vec<DiscoveryBuilder>,
@@ -107,8 +132,18 @@ pub struct Builder {
     insecure_skip_relay_cert_verify: bool,
     addr_v4: Option<SocketAddrV4>,
     addr_v6: Option<SocketAddrV6>,
+    port_selection: PortSelection,
+    port_state: Option<PathBuf>,
+    net_report_cache: Option<ReportCache>,
+    static_direct_addrs: Vec<SocketAddr>,
+    assume_reachable: bool,
     #[cfg(any(test, feature = "test-utils"))]
     path_selection: PathSelection,
+    #[debug(skip)]
+    accept_policy: Option<AcceptPolicy>,
+    max_concurrent_discovery: NonZeroUsize,
+    #[cfg(feature = "metrics")]
+    metrics: crate::metrics::MetricsExporter,
 }
 
 impl Default for Builder {
@@ -120,6 +155,7 @@ impl Default for Builder {
             relay_mode: default_relay_mode(),
             alpn_protocols: Default::default(),
             transport_config,
+            connection_ids: Default::default(),
             keylog: Default::default(),
             discovery: Default::default(),
             proxy_url: None,
@@ -129,8 +165,17 @@ impl Default for Builder {
             insecure_skip_relay_cert_verify: false,
             addr_v4: None,
             addr_v6: None,
+            port_selection: PortSelection::default(),
+            port_state: None,
+            net_report_cache: None,
+            static_direct_addrs: Vec::new(),
+            assume_reachable: false,
             #[cfg(any(test, feature = "test-utils"))]
             path_selection: PathSelection::default(),
+            accept_policy: None,
+            max_concurrent_discovery: DEFAULT_MAX_CONCURRENT_DISCOVERY,
+            #[cfg(feature = "metrics")]
+            metrics: Default::default(),
         }
     }
 }
@@ -151,6 +196,7 @@ impl Builder {
             transport_config: Arc::new(self.transport_config),
             keylog: self.keylog,
             secret_key: secret_key.clone(),
+            accept_policy: self.accept_policy,
         };
         let dns_resolver = self.dns_resolver.unwrap_or_default();
         let discovery = self
@@ -168,19 +214,30 @@ impl Builder {
         let msock_opts = magicsock::Options {
             addr_v4: self.addr_v4,
             addr_v6: self.addr_v6,
+            port_selection: self.port_selection,
+            port_state: self.port_state,
+            net_report_cache: self.net_report_cache,
             secret_key,
             relay_map,
             node_map: self.node_map,
             discovery,
             proxy_url: self.proxy_url,
+            static_direct_addrs: self.static_direct_addrs,
+            assume_reachable: self.assume_reachable,
             dns_resolver,
             server_config,
+            connection_ids: self.connection_ids,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: self.insecure_skip_relay_cert_verify,
             #[cfg(any(test, feature = "test-utils"))]
             path_selection: self.path_selection,
         };
-        Endpoint::bind(static_config, msock_opts).await
+        let discovery_queue = DiscoveryQueue::new(self.max_concurrent_discovery);
+        #[cfg(feature = "metrics")]
+        let metrics_exporter = crate::metrics::spawn_exporter(self.metrics)?;
+        #[cfg(not(feature = "metrics"))]
+        let metrics_exporter = None;
+        Endpoint::bind(static_config, msock_opts, discovery_queue, metrics_exporter).await
     }
 
     // # The very common methods everyone basically needs.
@@ -207,6 +264,78 @@ impl Builder {
         self
     }
 
+    /// Binds the UDP sockets to exactly this port.
+    ///
+    /// Unlike the port of [`Builder::bind_addr_v4`], this does not fall back to a random
+    /// port: [`Builder::bind`] fails if the port is already in use.  The IPv6 socket is
+    /// bound to the same port, and not bound at all if it is in use.  The IP addresses of
+    /// the bind addresses are still used.
+    pub fn bind_port(mut self, port: u16) -> Self {
+        self.port_selection = PortSelection::Port(port);
+        self
+    }
+
+    /// Binds the UDP sockets to the first free port of the range.
+    ///
+    /// Ports in use are skipped, [`Builder::bind`] fails if all of them are.  The IPv6
+    /// socket prefers the same port as the IPv4 socket.  This is useful when a firewall
+    /// only passes traffic to a range of ports opened for the endpoint.
+    pub fn bind_port_range(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.port_selection = PortSelection::Range(ports);
+        self
+    }
+
+    /// Persists the bound port to a file, to bind the same port again after a restart.
+    ///
+    /// The port stored in the file is preferred over other free ports, if it is allowed by
+    /// [`Builder::bind_port_range`].  The file is created if missing, and updated whenever
+    /// the endpoint has to bind a different port.
+    pub fn port_state_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.port_state = Some(path.into());
+        self
+    }
+
+    /// Caches the last net report in a file, to use it right away after a restart.
+    ///
+    /// Until the first net report is done, which can take a few seconds, the endpoint has
+    /// no home relay and does not know whether UDP works.  With the cache the endpoint
+    /// starts from the report of the previous run, if it is at most `max_age` old, so
+    /// short-lived processes can connect right away.  The network is still probed in the
+    /// background, and the cache updated with every new report.
+    ///
+    /// The addresses discovered by STUN are not cached, they are specific to the bound
+    /// socket.
+    pub fn net_report_cache(mut self, path: impl Into<PathBuf>, max_age: Duration) -> Self {
+        self.net_report_cache = Some(ReportCache::new(path.into(), max_age));
+        self
+    }
+
+    /// Sets direct addresses to advertise in addition to the discovered ones.
+    ///
+    /// This is useful when the endpoint is reachable on addresses it can not discover
+    /// itself, e.g. when a port of a public IP address is forwarded to the bound port.
+    /// These addresses are reported with [`DirectAddrType::Static`] by
+    /// [`Endpoint::direct_addresses`], and are advertised to other nodes before any
+    /// discovered address.
+    pub fn static_direct_addrs(mut self, addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+        self.static_direct_addrs = addrs.into_iter().collect();
+        self
+    }
+
+    /// Assumes other nodes can reach this endpoint on its direct addresses.
+    ///
+    /// Nodes connecting to this endpoint ask it to send pings to their addresses, to punch
+    /// a hole through the NATs and firewalls in front of this endpoint.  For a server with
+    /// a public IP address or a port forwarded using [`Builder::static_direct_addrs`] this
+    /// is not needed: the connecting nodes reach the endpoint directly.  Setting this skips
+    /// sending these pings.
+    ///
+    /// Outgoing connections still use hole punching.  Defaults to `false`.
+    pub fn assume_reachable(mut self, assume_reachable: bool) -> Self {
+        self.assume_reachable = assume_reachable;
+        self
+    }
+
     /// Sets a secret key to authenticate with other peers.
     ///
     /// This secret key's public key will be the [`PublicKey`] of this endpoint and thus
@@ -268,6 +397,9 @@ impl Builder {
     /// If no discovery service is set, connecting to a node without providing its
     /// direct addresses or relay URLs will fail.
     ///
+    /// The number of concurrent resolutions is limited, see
+    /// [`Builder::max_concurrent_discovery`].
+    ///
     /// See the documentation of the [`Discovery`] trait for details.
     pub fn discovery(mut self, discovery: Box<dyn Discovery>) -> Self {
         self.discovery.clear();
@@ -367,6 +499,41 @@ impl Builder {
         self
     }
 
+    /// Sets how many discovery resolutions may run at the same time.
+    ///
+    /// Further resolutions are queued, those of dials waiting for an address before those
+    /// looking for better addresses of nodes which are dialable already.  A resolution
+    /// releases its slot once it found the first address.
+    ///
+    /// Defaults to [`DEFAULT_MAX_CONCURRENT_DISCOVERY`], which explains the choice of the
+    /// limit.  Queued resolutions are counted in the `discovery_queued` metric and their
+    /// wait in `discovery_queue_wait_ms`.
+    pub fn max_concurrent_discovery(mut self, max: NonZeroUsize) -> Self {
+        self.max_concurrent_discovery = max;
+        self
+    }
+
+    /// Sets where the endpoint metrics are exported to.
+    ///
+    /// [`MetricsExporter::Prometheus`] sets up the process-wide metrics registry of
+    /// [`iroh_metrics`] with the endpoint metrics, if it is not set up already, and serves
+    /// them until the endpoint is closed.  [`MetricsExporter::Backend`] installs the
+    /// process-wide metrics backend, binding fails if a different backend is installed
+    /// already.  The port mapping metrics are recorded by the `portmapper` crate itself, they
+    /// are not reported to a backend.
+    ///
+    /// Defaults to [`MetricsExporter::Disabled`], in which case the metrics are only
+    /// recorded if the application sets up the registry or the backend itself.
+    ///
+    /// [`MetricsExporter::Disabled`]: crate::metrics::MetricsExporter::Disabled
+    /// [`MetricsExporter::Prometheus`]: crate::metrics::MetricsExporter::Prometheus
+    /// [`MetricsExporter::Backend`]: crate::metrics::MetricsExporter::Backend
+    #[cfg(feature = "metrics")]
+    pub fn metrics(mut self, exporter: crate::metrics::MetricsExporter) -> Self {
+        self.metrics = exporter;
+        self
+    }
+
     /// Optionally set a list of known nodes.
     pub fn known_nodes(mut self, nodes: Vec<NodeAddr>) -> Self {
         self.node_map = Some(nodes);
@@ -391,6 +558,19 @@ impl Builder {
         self
     }
 
+    /// Sets how the QUIC connections of this endpoint issue connection IDs.
+    ///
+    /// The connection IDs are sent in the clear and allow observers to link the packets of
+    /// a connection.  Use [`ConnectionIdConfig::privacy`] to use longer connection IDs which
+    /// are rotated regularly and whenever the path to a remote node changes.
+    ///
+    /// Binding fails if the configuration is invalid.  Defaults to 8 byte connection IDs
+    /// which are never rotated on their own.
+    pub fn connection_ids(mut self, config: ConnectionIdConfig) -> Self {
+        self.connection_ids = config;
+        self
+    }
+
     /// Optionally sets a custom DNS resolver to use for this endpoint.
     ///
     /// The DNS resolver is used to resolve relay hostnames, and node addresses if
@@ -406,6 +586,10 @@ impl Builder {
     }
 
     /// Sets an explicit proxy url to proxy all HTTP(S) traffic through.
+    ///
+    /// Besides HTTP proxies, SOCKS5 proxies are supported with the `socks5` and `socks5h`
+    /// schemes, the latter letting the proxy resolve host names.  Credentials are taken
+    /// from the URL.
     pub fn proxy_url(mut self, url: Url) -> Self {
         self.proxy_url.replace(url);
         self
@@ -434,6 +618,25 @@ impl Builder {
         self
     }
 
+    /// Sets a policy deciding whether to keep accepted connections.
+    ///
+    /// The policy is called once the handshake of an incoming connection completed, before
+    /// any stream is accepted.  It can inspect the authenticated [`Connection::remote_node_id`],
+    /// the [`Connection::alpn`] and the [`Connection::peer_token`] presented by the dialer, to
+    /// implement e.g. invite tokens without an extra round trip.  Refused connections are
+    /// closed with the [`ACCEPT_POLICY_REFUSED`] error code and awaiting them fails with
+    /// [`ConnectionError::LocallyClosed`].
+    ///
+    /// With a policy set, [`Connecting::into_0rtt`] always fails as the peer is not
+    /// authenticated before the handshake completed.
+    pub fn accept_policy<F>(mut self, policy: F) -> Self
+    where
+        F: Fn(&Connection) -> bool + Send + Sync + 'static,
+    {
+        self.accept_policy = Some(Arc::new(policy));
+        self
+    }
+
     /// Skip verification of SSL certificates from relay servers
     ///
     /// May only be used in tests.
@@ -453,11 +656,13 @@ impl Builder {
 }
 
 /// Configuration for a [`quinn::Endpoint`] that cannot be changed at runtime.
-#[derive(Debug)]
+#[derive(derive_more::Debug)]
 struct StaticConfig {
     secret_key: SecretKey,
     transport_config: Arc<quinn::TransportConfig>,
     keylog: bool,
+    #[debug(skip)]
+    accept_policy: Option<AcceptPolicy>,
 }
 
 impl StaticConfig {
@@ -520,6 +725,12 @@ pub struct Endpoint {
     msock: Handle,
     rtt_actor: Arc<rtt_actor::RttHandle>,
     static_config: Arc<StaticConfig>,
+    /// The connections dialed by [`Endpoint::connect_reuse`].
+    connection_pool: Arc<pool::ConnectionPool>,
+    /// Limits the concurrent discovery resolutions, see [`Builder::max_concurrent_discovery`].
+    discovery_queue: DiscoveryQueue,
+    /// The metrics exporter task, see [`Builder::metrics`].
+    metrics_exporter: Option<Arc<AbortOnDropHandle<()>>>,
 }
 
 impl Endpoint {
@@ -539,14 +750,25 @@ impl Endpoint {
     /// This is for internal use, the public interface is the [`Builder`] obtained from
     /// [Self::builder]. See the methods on the builder for documentation of the parameters.
     #[instrument("ep", skip_all, fields(me = %static_config.secret_key.public().fmt_short()))]
-    async fn bind(static_config: StaticConfig, msock_opts: magicsock::Options) -> Result<Self> {
+    async fn bind(
+        static_config: StaticConfig,
+        msock_opts: magicsock::Options,
+        discovery_queue: DiscoveryQueue,
+        metrics_exporter: Option<AbortOnDropHandle<()>>,
+    ) -> Result<Self> {
+        let rotate_connection_ids = msock_opts.connection_ids.rotate_on_path_change;
         let msock = magicsock::MagicSock::spawn(msock_opts).await?;
         trace!("created magicsock");
         debug!(version = env!("CARGO_PKG_VERSION"), "iroh Endpoint created");
         let ep = Self {
             msock: msock.clone(),
-            rtt_actor: Arc::new(rtt_actor::RttHandle::new()),
+            rtt_actor: Arc::new(rtt_actor::RttHandle::new(
+                rotate_connection_ids.then(|| msock.clone()),
+            )),
             static_config: Arc::new(static_config),
+            connection_pool: Default::default(),
+            discovery_queue,
+            metrics_exporter: metrics_exporter.map(Arc::new),
         };
         Ok(ep)
     }
@@ -604,7 +826,74 @@ impl Endpoint {
         alpn: &[u8],
         transport_config: Arc<TransportConfig>,
     ) -> Result<Connection> {
-        let node_addr: NodeAddr = node_addr.into();
+        self.connect_inner(node_addr.into(), alpn, transport_config, None)
+            .await
+    }
+
+    /// Connects to a remote [`Endpoint`], presenting a token to it.
+    ///
+    /// Like [`Endpoint::connect`], but the `token` is carried in the TLS certificate of
+    /// this endpoint, signed by its [`SecretKey`].  The remote endpoint can inspect it using
+    /// [`Connection::peer_token`], e.g. from its [`Builder::accept_policy`], before
+    /// accepting any streams.
+    ///
+    /// The TLS 1.3 handshake encrypts the certificate, so on-path observers can not see the
+    /// token.  It is however visible to the remote endpoint whether or not it accepts the
+    /// connection, so it must only contain what the remote node may learn.
+    /// It can be at most [`MAX_PEER_TOKEN_SIZE`] bytes long.
+    pub async fn connect_with_token(
+        &self,
+        node_addr: impl Into<NodeAddr>,
+        alpn: &[u8],
+        token: &[u8],
+    ) -> Result<Connection> {
+        ensure!(
+            token.len() <= MAX_PEER_TOKEN_SIZE,
+            "peer token too large: {} > {MAX_PEER_TOKEN_SIZE} bytes",
+            token.len()
+        );
+        self.connect_inner(
+            node_addr.into(),
+            alpn,
+            self.static_config.transport_config.clone(),
+            Some(token),
+        )
+        .await
+    }
+
+    /// Connects to a remote [`Endpoint`], reusing the existing connection if possible.
+    ///
+    /// Returns the connection previously dialed by this method for the same `node_id` and
+    /// `alpn` if it is still healthy, otherwise dials a new connection like
+    /// [`Endpoint::connect`] and keeps it for reuse.  Concurrent calls for the same node and
+    /// ALPN share a single dial and its result, if it fails they all fail.  This keeps applications from accumulating duplicate
+    /// connections to the same node.
+    ///
+    /// The connection is checked for staleness before it is handed out: once it is closed,
+    /// by either side or by the idle timeout, a new connection is dialed.  Only connections
+    /// dialed by this method are reused, connections from [`Endpoint::connect`] and accepted
+    /// connections are not.
+    ///
+    /// A reused connection is shared by all callers, closing it closes it for all of them.
+    /// Prefer finishing the streams opened on it, and only close the connection once done
+    /// with the remote node.
+    pub async fn connect_reuse(&self, node_id: NodeId, alpn: &[u8]) -> Result<Connection> {
+        let ep = self.clone();
+        let dial_alpn = alpn.to_vec();
+        self.connection_pool
+            .get_or_dial(node_id, alpn, || async move {
+                ep.connect(node_id, &dial_alpn).await
+            })
+            .await
+    }
+
+    async fn connect_inner(
+        &self,
+        node_addr: NodeAddr,
+        alpn: &[u8],
+        transport_config: Arc<TransportConfig>,
+        token: Option<&[u8]>,
+    ) -> Result<Connection> {
         tracing::Span::current().record("remote", node_addr.node_id.fmt_short());
         // Connecting to ourselves is not supported.
         if node_addr.node_id == self.node_id() {
@@ -641,7 +930,7 @@ impl Endpoint {
 
         // Start connecting via quinn. This will time out after 10 seconds if no reachable
         // address is available.
-        self.connect_quinn(node_id, alpn, addr, transport_config)
+        self.connect_quinn(node_id, alpn, addr, transport_config, token)
             .await
     }
 
@@ -660,6 +949,7 @@ impl Endpoint {
         alpn: &[u8],
         addr: NodeIdMappedAddr,
         transport_config: Arc<TransportConfig>,
+        token: Option<&[u8]>,
     ) -> Result<Connection> {
         debug!("Attempting connection...");
         let client_config = {
@@ -668,6 +958,7 @@ impl Endpoint {
                 &self.static_config.secret_key,
                 Some(node_id),
                 alpn_protocols,
+                token,
                 self.static_config.keylog,
             )?;
             let mut client_config = quinn::ClientConfig::new(Arc::new(quic_client_config));
@@ -695,7 +986,7 @@ impl Endpoint {
             warn!("rtt-actor not reachable: {err:#}");
         }
         debug!("Connection established");
-        Ok(Connection { inner: connection })
+        Ok(Connection::new(connection))
     }
 
     /// Accepts an incoming connection on the endpoint.
@@ -842,6 +1133,35 @@ impl Endpoint {
         self.msock.home_relay()
     }
 
+    /// Returns the relay servers this endpoint may use as its home relay.
+    ///
+    /// This is the [`RelayMap`] of the [`RelayMode`] this endpoint was bound with, unless
+    /// replaced with [`Endpoint::set_relay_map`].
+    pub fn relay_map(&self) -> RelayMap {
+        self.msock.relay_map()
+    }
+
+    /// Replaces the relay servers this endpoint may use as its home relay.
+    ///
+    /// The [`Endpoint::home_relay`] is selected again right away, e.g. moving to another
+    /// relay of the same [`RelayRegion`] after its weights changed:
+    ///
+    /// ```no_run
+    /// # async fn wrapper(ep: iroh::Endpoint, url: iroh::RelayUrl) -> anyhow::Result<()> {
+    /// let mut relay_map = ep.relay_map();
+    /// relay_map.set_weight(&url, 0)?;
+    /// ep.set_relay_map(relay_map);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Relay servers of other nodes are still dialed when connecting to them.
+    ///
+    /// [`RelayRegion`]: crate::RelayRegion
+    pub fn set_relay_map(&self, relay_map: RelayMap) {
+        self.msock.set_relay_map(relay_map);
+    }
+
     /// Returns a [`Watcher`] for the direct addresses of this [`Endpoint`].
     ///
     /// The direct addresses of the [`Endpoint`] are those that could be used by other
@@ -861,6 +1181,10 @@ impl Endpoint {
     /// will always return [`Some`] set of direct addresses immediately, which are the most
     /// recently discovered direct addresses.
     ///
+    /// Each [`DirectAddr`] records where it was found in [`DirectAddr::typ`] and how likely
+    /// other nodes can reach it in [`DirectAddr::reachability`].  Addresses configured using
+    /// [`Builder::static_direct_addrs`] are always included.
+    ///
     /// # Examples
     ///
     /// To get the first set of direct addresses use [`Watcher::initialized`]:
@@ -952,6 +1276,31 @@ impl Endpoint {
         self.msock.conn_type(node_id)
     }
 
+    /// Returns the state of the path selection towards a remote node.
+    ///
+    /// The [`PathInfo`] shows which paths are used to send to the node, why they were
+    /// selected, and the state of each direct path which could be selected instead.  This
+    /// answers questions like "why is this node on the relay?".
+    ///
+    /// Returns `None` if we do not have any address information for the given `node_id`.
+    pub fn path_info(&self, node_id: NodeId) -> Option<PathInfo> {
+        self.msock.path_info(node_id)
+    }
+
+    /// Returns a [`Watcher`] for the changes of the paths used to send to a remote node.
+    ///
+    /// Each [`PathTransition`] carries the reason of the change.  Like for
+    /// [`Endpoint::conn_type`], transitions happening before the stream of the watcher is
+    /// polled are not all yielded, only the last one is.  The current value is `None` until
+    /// the paths changed for the first time.
+    ///
+    /// # Errors
+    ///
+    /// Will error if we do not have any address information for the given `node_id`.
+    pub fn path_transitions(&self, node_id: NodeId) -> Result<Watcher<Option<PathTransition>>> {
+        self.msock.path_transitions(node_id)
+    }
+
     /// Returns the DNS resolver used in this [`Endpoint`].
     ///
     /// See [`Builder::dns_resolver`].
@@ -966,6 +1315,10 @@ impl Endpoint {
         self.msock.discovery()
     }
 
+    pub(crate) fn discovery_queue(&self) -> &DiscoveryQueue {
+        &self.discovery_queue
+    }
+
     // # Methods for less common state updates.
 
     /// Notifies the system of potential network changes.
@@ -982,6 +1335,18 @@ impl Endpoint {
         self.msock.network_change().await;
     }
 
+    /// Simulates losing all network interfaces, or getting them back.
+    ///
+    /// While offline, all datagrams sent or received over UDP and the relays are dropped,
+    /// as if the interfaces were gone.  Coming back online is handled as a major network
+    /// change, rebinding the sockets and reconnecting to the relays.
+    ///
+    /// Only for tests.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub async fn set_offline(&self, offline: bool) {
+        self.msock.set_offline(offline).await;
+    }
+
     // # Methods for terminating the endpoint.
 
     /// Closes the QUIC endpoint and the magic socket.
@@ -990,7 +1355,8 @@ impl Endpoint {
     /// of `0` and an empty reason.  Though it is best practice to close those
     /// explicitly before with a custom error code and reason.
     ///
-    /// This will not wait for the [`quinn::Endpoint`] to drain connections.
+    /// This will not wait for the [`quinn::Endpoint`] to drain connections.  The last net
+    /// report is written to the [`Builder::net_report_cache`] before the endpoint is closed.
     ///
     /// To ensure no data is lost, design protocols so that the last *sender*
     /// of data in the protocol calls [`Connection::closed`], and `await`s until
@@ -1007,9 +1373,69 @@ impl Endpoint {
         }
 
         tracing::debug!("Connections closed");
+        self.connection_pool.clear();
+        if let Some(exporter) = &self.metrics_exporter {
+            exporter.abort();
+        }
         self.msock.close().await;
     }
 
+    /// Shuts the endpoint down gracefully, giving open connections until `deadline` to finish.
+    ///
+    /// This is meant for restarting nodes which serve other nodes without failing their
+    /// requests:
+    ///
+    /// - New incoming connections are no longer accepted.
+    /// - The addressing information of this node is removed from the discovery services,
+    ///   see [`Discovery::unpublish`], so other nodes stop dialing it.
+    /// - Open connections are waited for until they are closed, by either side, or the
+    ///   `deadline` is reached.  Pooled connections of [`Endpoint::connect_reuse`] close
+    ///   once they have no streams open anymore.
+    /// - Connections still open at the deadline are closed, sending the remote nodes a
+    ///   close frame with an error code of `0` and the reason `shutdown`.
+    ///
+    /// Finally the endpoint is closed as with [`Endpoint::close`], which also flushes the
+    /// persisted state.
+    pub async fn shutdown(&self, deadline: Instant) {
+        if self.is_closed() {
+            return;
+        }
+
+        debug!("Shutting down");
+        self.msock.endpoint().set_server_config(None);
+        self.connection_pool.clear();
+        let unpublish = async {
+            let Some(fut) = self.discovery().and_then(|discovery| discovery.unpublish()) else {
+                return;
+            };
+            if let Err(err) = fut.await {
+                warn!("failed to unpublish from discovery: {err:#}");
+            }
+        };
+        let idle = self.msock.endpoint().wait_idle();
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let (unpublished, idle) = n0_future::future::zip(
+            time::timeout(timeout, unpublish),
+            time::timeout(timeout, idle),
+        )
+        .await;
+        if unpublished.is_err() {
+            warn!("unpublishing from discovery did not finish before the deadline");
+        }
+        if idle.is_err() {
+            debug!(
+                connections = self.msock.endpoint().open_connections(),
+                "closing connections still open at the deadline"
+            );
+            self.msock.endpoint().close(0u16.into(), b"shutdown");
+            // Give the close frames a moment to be sent before the sockets go away.
+            time::timeout(SHUTDOWN_CLOSE_TIMEOUT, self.msock.endpoint().wait_idle())
+                .await
+                .ok();
+        }
+        self.close().await;
+    }
+
     /// Check if this endpoint is still alive, or already closed.
     pub fn is_closed(&self) -> bool {
         self.msock.is_closed()
@@ -1087,6 +1513,22 @@ impl Endpoint {
     pub(crate) fn endpoint(&self) -> &quinn::Endpoint {
         self.msock.endpoint()
     }
+
+    /// Closes an accepted connection if the [`Builder::accept_policy`] refuses it.
+    fn apply_accept_policy(&self, conn: Connection) -> Result<Connection, ConnectionError> {
+        if let Some(policy) = &self.static_config.accept_policy {
+            if !policy(&conn) {
+                debug!(
+                    remote = ?conn.remote_node_id().ok().map(|id| id.fmt_short()),
+                    "connection refused by accept policy"
+                );
+                conn.close(ACCEPT_POLICY_REFUSED, b"refused by accept policy");
+                return Err(ConnectionError::LocallyClosed);
+            }
+        }
+        try_send_rtt_msg(&conn, self);
+        Ok(conn)
+    }
 }
 
 /// Future produced by [`Endpoint::accept`].
@@ -1110,6 +1552,7 @@ impl Future for Accept<'_> {
             Poll::Ready(Some(inner)) => Poll::Ready(Some(Incoming {
                 inner,
                 ep: this.ep.clone(),
+                received_at: Instant::now(),
             })),
         }
     }
@@ -1121,6 +1564,7 @@ impl Future for Accept<'_> {
 pub struct Incoming {
     inner: quinn::Incoming,
     ep: Endpoint,
+    received_at: Instant,
 }
 
 impl Incoming {
@@ -1134,9 +1578,11 @@ impl Incoming {
     /// Thus it is common to simply log the errors here and accept them as something which
     /// can happen.
     pub fn accept(self) -> Result<Connecting, ConnectionError> {
+        let start = self.accept_start();
         self.inner.accept().map(|conn| Connecting {
             inner: conn,
             ep: self.ep,
+            start,
         })
     }
 
@@ -1149,11 +1595,13 @@ impl Incoming {
         self,
         server_config: Arc<ServerConfig>,
     ) -> Result<Connecting, ConnectionError> {
+        let start = self.accept_start();
         self.inner
             .accept_with(server_config)
             .map(|conn| Connecting {
                 inner: conn,
                 ep: self.ep,
+                start,
             })
     }
 
@@ -1194,6 +1642,14 @@ impl Incoming {
     pub fn remote_address_validated(&self) -> bool {
         self.inner.remote_address_validated()
     }
+
+    fn accept_start(&self) -> AcceptStart {
+        AcceptStart {
+            remote_address: self.inner.remote_address(),
+            received_at: self.received_at,
+            accepted_at: Instant::now(),
+        }
+    }
 }
 
 impl IntoFuture for Incoming {
@@ -1201,9 +1657,11 @@ impl IntoFuture for Incoming {
     type IntoFuture = IncomingFuture;
 
     fn into_future(self) -> Self::IntoFuture {
+        let start = self.accept_start();
         IncomingFuture {
             inner: self.inner.into_future(),
             ep: self.ep,
+            start,
         }
     }
 }
@@ -1215,6 +1673,7 @@ pub struct IncomingFuture {
     #[pin]
     inner: quinn::IncomingFuture,
     ep: Endpoint,
+    start: AcceptStart,
 }
 
 impl Future for IncomingFuture {
@@ -1226,9 +1685,8 @@ impl Future for IncomingFuture {
             Poll::Pending => Poll::Pending,
             Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
             Poll::Ready(Ok(inner)) => {
-                let conn = Connection { inner };
-                try_send_rtt_msg(&conn, this.ep);
-                Poll::Ready(Ok(conn))
+                let conn = Connection::accepted(inner, this.ep, *this.start);
+                Poll::Ready(this.ep.apply_accept_policy(conn))
             }
         }
     }
@@ -1241,18 +1699,28 @@ pub struct Connecting {
     #[pin]
     inner: quinn::Connecting,
     ep: Endpoint,
+    start: AcceptStart,
 }
 
 impl Connecting {
     /// Convert into a 0-RTT or 0.5-RTT connection at the cost of weakened security.
+    ///
+    /// Always fails if a [`Builder::accept_policy`] is set.
     pub fn into_0rtt(self) -> Result<(Connection, ZeroRttAccepted), Self> {
+        if self.ep.static_config.accept_policy.is_some() {
+            return Err(self);
+        }
         match self.inner.into_0rtt() {
             Ok((inner, zrtt_accepted)) => {
-                let conn = Connection { inner };
+                let conn = Connection::accepted(inner, &self.ep, self.start);
                 try_send_rtt_msg(&conn, &self.ep);
                 Ok((conn, zrtt_accepted))
             }
-            Err(inner) => Err(Self { inner, ep: self.ep }),
+            Err(inner) => Err(Self {
+                inner,
+                ep: self.ep,
+                start: self.start,
+            }),
         }
     }
 
@@ -1285,9 +1753,8 @@ impl Future for Connecting {
             Poll::Pending => Poll::Pending,
             Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
             Poll::Ready(Ok(inner)) => {
-                let conn = Connection { inner };
-                try_send_rtt_msg(&conn, this.ep);
-                Poll::Ready(Ok(conn))
+                let conn = Connection::accepted(inner, this.ep, *this.start);
+                Poll::Ready(this.ep.apply_accept_policy(conn))
             }
         }
     }
@@ -1306,15 +1773,41 @@ impl Future for Connecting {
 /// connection without losing application data.
 ///
 /// May be cloned to obtain another handle to the same connection.
-// This has repr(transparent) as it opens the door to potentially allow casting it to a
-// quinn::Connection in the future.  Right now however that'd be iroh_quinn::Connection.
 #[derive(Debug, Clone)]
-#[repr(transparent)]
 pub struct Connection {
     inner: quinn::Connection,
+    /// Shared by all clones of the connection.
+    state: Arc<ConnectionState>,
+}
+
+/// The state kept for a [`Connection`] besides the QUIC connection itself.
+#[derive(Debug, Default)]
+struct ConnectionState {
+    /// Only set for connections accepted by this endpoint.
+    handshake_info: Option<HandshakeInfo>,
+    /// The data queued on the tracked send streams.
+    send_queue: Arc<SendQueueState>,
 }
 
 impl Connection {
+    fn new(inner: quinn::Connection) -> Self {
+        Self {
+            inner,
+            state: Default::default(),
+        }
+    }
+
+    /// Wraps a connection accepted by `ep`, recording how it was established.
+    fn accepted(inner: quinn::Connection, ep: &Endpoint, start: AcceptStart) -> Self {
+        let mut conn = Self::new(inner);
+        let handshake_info = HandshakeInfo::new(&conn, ep, start);
+        conn.state = Arc::new(ConnectionState {
+            handshake_info: Some(handshake_info),
+            send_queue: Default::default(),
+        });
+        conn
+    }
+
     /// Initiates a new outgoing unidirectional stream.
     ///
     /// Streams are cheap and instantaneous to open unless blocked by flow control. As a
@@ -1475,6 +1968,26 @@ impl Connection {
         self.inner.stats()
     }
 
+    /// Returns a snapshot of the data queued for sending on this connection.
+    ///
+    /// Only the writes made through streams wrapped by [`Connection::track_send_stream`]
+    /// are accounted.
+    pub fn send_queue(&self) -> SendQueue {
+        let sent_bytes = self.inner.stats().udp_tx.bytes;
+        let datagram_buffer_space = self.inner.datagram_send_buffer_space();
+        self.state
+            .send_queue
+            .snapshot(sent_bytes, datagram_buffer_space)
+    }
+
+    /// Wraps a send stream of this connection, accounting its writes in
+    /// [`Connection::send_queue`].
+    pub fn track_send_stream(&self, stream: SendStream) -> TrackedSendStream {
+        let send_queue = &self.state.send_queue;
+        send_queue.start(self.inner.stats().udp_tx.bytes);
+        TrackedSendStream::new(stream, send_queue.clone())
+    }
+
     /// Current state of the congestion control algorithm, for debugging purposes.
     #[inline]
     pub fn congestion_state(&self) -> Box<dyn quinn_proto::congestion::Controller> {
@@ -1544,6 +2057,23 @@ impl Connection {
         }
     }
 
+    /// Returns the token the remote node presented when connecting.
+    ///
+    /// The token is authenticated, it is signed by the remote node as returned by
+    /// [`Connection::remote_node_id`].  Returns `None` if the remote node did not present
+    /// a token, see [`Endpoint::connect_with_token`].
+    pub fn peer_token(&self) -> Option<Vec<u8>> {
+        let certs = self
+            .peer_identity()?
+            .downcast::<Vec<rustls::pki_types::CertificateDer>>()
+            .ok()?;
+        let [cert] = &certs[..] else {
+            return None;
+        };
+        let cert = tls::certificate::parse(cert).ok()?;
+        cert.peer_token().map(|token| token.to_vec())
+    }
+
     /// A stable identifier for this connection.
     ///
     /// Peer addresses and connection IDs can change, but this value will remain fixed for
@@ -1553,6 +2083,18 @@ impl Connection {
         self.inner.stable_id()
     }
 
+    /// Returns how this connection was established, if it was accepted by this endpoint.
+    ///
+    /// The [`HandshakeInfo`] records the path the connection arrived on, whether the
+    /// remote [`NodeId`] was verified and how long the handshake took.  It is captured
+    /// once the handshake completes and does not change afterwards, use
+    /// [`Endpoint::conn_type`] to observe later path changes.
+    ///
+    /// Returns `None` for connections created using [`Endpoint::connect`].
+    pub fn handshake_info(&self) -> Option<HandshakeInfo> {
+        self.state.handshake_info.clone()
+    }
+
     /// Derives keying material from this connection's TLS session secrets.
     ///
     /// When both peers call this method with the same `label` and `context`
@@ -1596,6 +2138,132 @@ impl Connection {
     }
 }
 
+/// Information about how an accepted [`Connection`] was established.
+///
+/// See [`Connection::handshake_info`].
+#[derive(Debug, Clone)]
+pub struct HandshakeInfo {
+    path: ConnectionType,
+    node_id: NodeIdVerification,
+    timing: HandshakeTiming,
+}
+
+impl HandshakeInfo {
+    fn new(conn: &Connection, ep: &Endpoint, start: AcceptStart) -> Self {
+        let established_at = Instant::now();
+        // The node the magicsock delivered the packets from, as known from the disco and
+        // relay layers.  The TLS certificate is what actually authenticates the node.
+        let claimed = ep.msock.node_id_for_mapped_addr(start.remote_address);
+        let node_id = match (claimed, conn.remote_node_id().ok()) {
+            (Some(claimed), Some(authenticated)) if claimed == authenticated => {
+                NodeIdVerification::Verified(authenticated)
+            }
+            (Some(claimed), Some(authenticated)) => NodeIdVerification::Mismatch {
+                claimed,
+                authenticated,
+            },
+            (None, Some(authenticated)) => NodeIdVerification::Unmapped(authenticated),
+            (_, None) => NodeIdVerification::Unavailable,
+        };
+        let path = node_id
+            .node_id()
+            .or(claimed)
+            .and_then(|node_id| ep.conn_type(node_id).ok())
+            .and_then(|watcher| watcher.get().ok())
+            .unwrap_or_default();
+        Self {
+            path,
+            node_id,
+            timing: HandshakeTiming {
+                accept_delay: start.accepted_at.duration_since(start.received_at),
+                handshake: established_at.duration_since(start.accepted_at),
+            },
+        }
+    }
+
+    /// Returns the path the connection arrived on.
+    ///
+    /// This is either the relay server or the direct address the remote node was
+    /// reachable on when the handshake completed.
+    pub fn path(&self) -> &ConnectionType {
+        &self.path
+    }
+
+    /// Returns the verification status of the remote [`NodeId`].
+    pub fn node_id(&self) -> &NodeIdVerification {
+        &self.node_id
+    }
+
+    /// Returns the timing breakdown of the handshake.
+    pub fn timing(&self) -> &HandshakeTiming {
+        &self.timing
+    }
+}
+
+/// The verification status of the remote [`NodeId`] of an accepted connection.
+///
+/// The TLS handshake authenticates the [`NodeId`] presented in the peer's certificate.
+/// Separately the magicsock knows which node it received the packets from, based on the
+/// relay server or the disco messages exchanged for direct paths.  A mismatch between the
+/// two means the remote node is not who the lower layers think it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeIdVerification {
+    /// The [`NodeId`] was authenticated and matches the node the packets arrived from.
+    Verified(NodeId),
+    /// The authenticated [`NodeId`] differs from the node the packets arrived from.
+    Mismatch {
+        /// The node the magicsock associated with the packets.
+        claimed: NodeId,
+        /// The node authenticated by the TLS handshake.
+        authenticated: NodeId,
+    },
+    /// The [`NodeId`] was authenticated but the sending node is not in the node map.
+    Unmapped(NodeId),
+    /// No peer certificate was available, e.g. for a 0.5-RTT connection.
+    Unavailable,
+}
+
+impl NodeIdVerification {
+    /// Returns the [`NodeId`] authenticated by the TLS handshake, if any.
+    pub fn node_id(&self) -> Option<NodeId> {
+        match self {
+            Self::Verified(node_id) | Self::Unmapped(node_id) => Some(*node_id),
+            Self::Mismatch { authenticated, .. } => Some(*authenticated),
+            Self::Unavailable => None,
+        }
+    }
+
+    /// Whether the [`NodeId`] was authenticated and matches the node the packets arrived from.
+    pub fn is_verified(&self) -> bool {
+        matches!(self, Self::Verified(_))
+    }
+}
+
+/// Timing breakdown of the handshake of an accepted connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeTiming {
+    /// Time between the endpoint receiving the connection attempt and the application
+    /// accepting it.
+    pub accept_delay: Duration,
+    /// Time between the application accepting the connection and the handshake completing.
+    pub handshake: Duration,
+}
+
+impl HandshakeTiming {
+    /// Returns the total time from receiving the connection attempt until it was established.
+    pub fn total(&self) -> Duration {
+        self.accept_delay + self.handshake
+    }
+}
+
+/// Bookkeeping needed to build the [`HandshakeInfo`] of an accepted connection.
+#[derive(Debug, Clone, Copy)]
+struct AcceptStart {
+    remote_address: SocketAddr,
+    received_at: Instant,
+    accepted_at: Instant,
+}
+
 /// Try send a message to the rtt-actor.
 ///
 /// If we can't notify the actor that will impact performance a little, but we can still
@@ -1721,8 +2389,8 @@ mod tests {
 
     use std::time::Instant;
 
-    use n0_future::StreamExt;
-    use rand::SeedableRng;
+    use n0_future::{time, StreamExt};
+    use rand::{Rng, SeedableRng};
     use tracing::{error_span, info, info_span, Instrument};
     use tracing_test::traced_test;
 
@@ -1731,6 +2399,143 @@ mod tests {
 
     const TEST_ALPN: &[u8] = b"n0/iroh/test";
 
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Binds two endpoints on a new test relay server, the first one accepting [`TEST_ALPN`].
+    ///
+    /// The builders are adjusted by `config1` and `config2`.  Returns the endpoints, the relay
+    /// address of the first one and the relay server, which runs until dropped.
+    async fn endpoint_pair(
+        config1: impl FnOnce(Builder) -> Builder,
+        config2: impl FnOnce(Builder) -> Builder,
+    ) -> (Endpoint, Endpoint, NodeAddr, iroh_relay::server::Server) {
+        let (relay_map, relay_url, relay) = run_relay_server().await.unwrap();
+        let builder = || {
+            Endpoint::builder()
+                .insecure_skip_relay_cert_verify(true)
+                .relay_mode(RelayMode::Custom(relay_map.clone()))
+        };
+        let ep1 = config1(builder().alpns(vec![TEST_ALPN.to_vec()]))
+            .bind()
+            .await
+            .unwrap();
+        let ep2 = config2(builder()).bind().await.unwrap();
+        let ep1_nodeaddr = NodeAddr::new(ep1.node_id()).with_relay_url(relay_url);
+        (ep1, ep2, ep1_nodeaddr, relay)
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_set_relay_map() -> testresult::TestResult {
+        let (relay_map_a, relay_url_a, _guard_a) = run_relay_server().await?;
+        let (_, relay_url_b, _guard_b) = run_relay_server().await?;
+        let ep = Endpoint::builder()
+            .insecure_skip_relay_cert_verify(true)
+            .relay_mode(RelayMode::Custom(relay_map_a.clone()))
+            .bind()
+            .await?;
+        let home = time::timeout(Duration::from_secs(10), ep.home_relay().initialized()).await??;
+        assert_eq!(home, relay_url_a);
+
+        // Drain a in favour of b, in a region probed through the STUN server of a.
+        let mut region = crate::RelayRegion::new(
+            "test",
+            [relay_url_a.clone(), relay_url_b.clone()].map(crate::WeightedRelay::new),
+        );
+        region.stun_port = relay_map_a.get_node(&relay_url_a).unwrap().stun_port;
+        let mut relay_map = RelayMap::from_regions([region])?;
+        relay_map.set_weight(&relay_url_a, 0)?;
+        ep.set_relay_map(relay_map.clone());
+        assert_eq!(ep.relay_map(), relay_map);
+
+        let mut home = ep.home_relay().stream();
+        time::timeout(Duration::from_secs(10), async {
+            while home.next().await != Some(Some(relay_url_b.clone())) {}
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// A metrics backend broadcasting the incremented counters.
+    #[derive(Debug)]
+    struct BroadcastBackend(tokio::sync::broadcast::Sender<(&'static str, &'static str)>);
+
+    /// The backend is installed for the whole process, so all tests share this one.
+    static METRICS_BACKEND: std::sync::LazyLock<Arc<BroadcastBackend>> =
+        std::sync::LazyLock::new(|| {
+            Arc::new(BroadcastBackend(tokio::sync::broadcast::channel(4096).0))
+        });
+
+    /// Returns an exporter to the shared test backend and a receiver of its counters.
+    fn metrics_backend() -> (
+        crate::metrics::MetricsExporter,
+        tokio::sync::broadcast::Receiver<(&'static str, &'static str)>,
+    ) {
+        let rx = METRICS_BACKEND.0.subscribe();
+        (
+            crate::metrics::MetricsExporter::Backend(METRICS_BACKEND.clone()),
+            rx,
+        )
+    }
+
+    /// Waits until the counter `name` of the metric `group` was incremented.
+    async fn counter_incremented(
+        rx: &mut tokio::sync::broadcast::Receiver<(&'static str, &'static str)>,
+        group: &str,
+        name: &str,
+    ) {
+        use tokio::sync::broadcast::error::RecvError;
+
+        loop {
+            match rx.recv().await {
+                Ok(counter) if counter == (group, name) => return,
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => panic!("metrics backend dropped"),
+            }
+        }
+    }
+
+    impl crate::metrics::MetricsBackend for BroadcastBackend {
+        fn increment_counter(
+            &self,
+            group: &'static str,
+            name: &'static str,
+            _help: &'static str,
+            _by: u64,
+        ) {
+            self.0.send((group, name)).ok();
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_metrics_backend() -> testresult::TestResult {
+        use crate::metrics::MetricsExporter;
+
+        let (exporter, mut rx) = metrics_backend();
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .metrics(exporter)
+            .bind()
+            .await?;
+        time::timeout(
+            Duration::from_secs(5),
+            counter_incremented(&mut rx, "magicsock", "re_stun_calls"),
+        )
+        .await?;
+        ep.close().await;
+
+        // The backend is installed for the process, a different one is refused.
+        let (other, _rx) = tokio::sync::broadcast::channel(1);
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .metrics(MetricsExporter::backend(BroadcastBackend(other)))
+            .bind()
+            .await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_connect_self() {
@@ -2165,6 +2970,484 @@ mod tests {
         r2.expect("ep2 timeout").unwrap();
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_assume_reachable_direct() {
+        let (exporter, mut rx) = metrics_backend();
+        let (ep1, ep2, _, _relay) = endpoint_pair(
+            |builder| builder.assume_reachable(true).metrics(exporter),
+            |builder| builder,
+        )
+        .await;
+        let ep1_nodeaddr = ep1.node_addr().await.unwrap();
+        let ep1_nodeid = ep1.node_id();
+
+        let accept = tokio::spawn(async move {
+            let conn = ep1.accept().await.unwrap().await.unwrap();
+            (ep1, conn)
+        });
+        // Without hole punching from ep1, ep2 still reaches it on its direct addresses.
+        let _conn = tokio::time::timeout(TIMEOUT, ep2.connect(ep1_nodeaddr, TEST_ALPN))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut conn_type = ep2.conn_type(ep1_nodeid).unwrap().stream();
+        tokio::time::timeout(TIMEOUT, async {
+            while let Some(conn_type) = conn_type.next().await {
+                if matches!(conn_type, ConnectionType::Direct(_)) {
+                    return;
+                }
+            }
+            panic!("conn_type stream ended before `ConnectionType::Direct`");
+        })
+        .await
+        .unwrap();
+        let (_ep1, _conn) = tokio::time::timeout(TIMEOUT, accept)
+            .await
+            .unwrap()
+            .unwrap();
+        // ep1 answered the call-me-maybe of ep2 without hole punching.
+        tokio::time::timeout(
+            TIMEOUT,
+            counter_incremented(&mut rx, "magicsock", "skipped_disco_ping"),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_accept_handshake_info() {
+        let (ep1, ep2, ep1_nodeaddr, _relay) = endpoint_pair(|b| b, |b| b).await;
+        let ep2_nodeid = ep2.node_id();
+
+        const ACCEPT_DELAY: Duration = Duration::from_millis(100);
+
+        let accept = tokio::spawn(async move {
+            let incoming = ep1.accept().await.unwrap();
+            // Hold the connection attempt so the accept delay has a known lower bound.
+            tokio::time::sleep(ACCEPT_DELAY).await;
+            let handshake_start = Instant::now();
+            let conn = incoming.accept().unwrap().await.unwrap();
+            let handshake_elapsed = handshake_start.elapsed();
+            let info = conn.handshake_info().unwrap();
+            (ep1, conn, info, handshake_elapsed)
+        });
+        let connect_start = Instant::now();
+        let conn = tokio::time::timeout(TIMEOUT, ep2.connect(ep1_nodeaddr, TEST_ALPN))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(conn.handshake_info().is_none());
+
+        let (_ep1, _conn, info, handshake_elapsed) = tokio::time::timeout(TIMEOUT, accept)
+            .await
+            .unwrap()
+            .unwrap();
+        let connect_elapsed = connect_start.elapsed();
+        assert_eq!(info.node_id(), &NodeIdVerification::Verified(ep2_nodeid));
+        assert!(info.node_id().is_verified());
+        assert_ne!(info.path(), &ConnectionType::None);
+        let timing = info.timing();
+        assert!(timing.accept_delay >= ACCEPT_DELAY, "{timing:?}");
+        assert!(timing.handshake <= handshake_elapsed, "{timing:?}");
+        assert!(timing.total() <= connect_elapsed, "{timing:?}");
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_connect_reuse() {
+        let (ep1, ep2, ep1_nodeaddr, _relay) = endpoint_pair(|b| b, |b| b).await;
+        let ep1_nodeid = ep1.node_id();
+        ep2.add_node_addr(ep1_nodeaddr).unwrap();
+
+        let (accepted_tx, mut accepted_rx) = tokio::sync::mpsc::unbounded_channel();
+        let _accept = n0_future::task::AbortOnDropHandle::new(tokio::spawn(async move {
+            while let Some(incoming) = ep1.accept().await {
+                let conn = incoming.await.unwrap();
+                accepted_tx.send(conn).unwrap();
+            }
+        }));
+
+        // Concurrent calls share a single connection.
+        let (conn_a, conn_b) = tokio::time::timeout(TIMEOUT, async {
+            tokio::join!(
+                ep2.connect_reuse(ep1_nodeid, TEST_ALPN),
+                ep2.connect_reuse(ep1_nodeid, TEST_ALPN),
+            )
+        })
+        .await
+        .unwrap();
+        let (conn_a, conn_b) = (conn_a.unwrap(), conn_b.unwrap());
+        assert_eq!(conn_a.stable_id(), conn_b.stable_id());
+        let _accepted = tokio::time::timeout(TIMEOUT, accepted_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let conn_c = ep2.connect_reuse(ep1_nodeid, TEST_ALPN).await.unwrap();
+        assert_eq!(conn_a.stable_id(), conn_c.stable_id());
+        assert!(accepted_rx.try_recv().is_err());
+
+        // A closed connection is stale and replaced.
+        conn_a.close(0u32.into(), b"done");
+        let conn_d = tokio::time::timeout(TIMEOUT, ep2.connect_reuse(ep1_nodeid, TEST_ALPN))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(conn_a.stable_id(), conn_d.stable_id());
+        assert!(conn_d.close_reason().is_none());
+        let _accepted = tokio::time::timeout(TIMEOUT, accepted_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Failed dials are not kept, the slots of closed connections are pruned.
+        let unknown = SecretKey::generate(rand::thread_rng()).public();
+        assert!(ep2.connect_reuse(unknown, TEST_ALPN).await.is_err());
+        assert_eq!(ep2.connection_pool.len(), 1);
+        conn_d.close(0u32.into(), b"done");
+        assert!(ep2.connect_reuse(unknown, TEST_ALPN).await.is_err());
+        assert_eq!(ep2.connection_pool.len(), 0);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_shutdown() {
+        let connect = || async {
+            let (ep1, ep2, ep1_nodeaddr, relay) = endpoint_pair(|b| b, |b| b).await;
+            let (conn1, conn2) = tokio::time::timeout(TIMEOUT, async {
+                tokio::join!(
+                    async { ep1.accept().await.unwrap().await.unwrap() },
+                    async { ep2.connect(ep1_nodeaddr, TEST_ALPN).await.unwrap() },
+                )
+            })
+            .await
+            .unwrap();
+            (ep1, ep2, conn1, conn2, relay)
+        };
+
+        // Shutdown waits for the open connection to be closed by the remote.
+        let (ep1, _ep2, _conn1, conn2, _relay) = connect().await;
+        let shutdown = tokio::spawn({
+            let ep1 = ep1.clone();
+            async move { ep1.shutdown(time::Instant::now() + TIMEOUT).await }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!shutdown.is_finished());
+        assert!(!ep1.is_closed());
+        conn2.close(0u32.into(), b"done");
+        tokio::time::timeout(TIMEOUT, shutdown)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(ep1.is_closed());
+
+        // Connections still open at the deadline are closed.
+        let (ep1, _ep2, _conn1, conn2, _relay) = connect().await;
+        let start = time::Instant::now();
+        ep1.shutdown(start + Duration::from_millis(200)).await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(ep1.is_closed());
+        let err = tokio::time::timeout(TIMEOUT, conn2.closed()).await.unwrap();
+        let ConnectionError::ApplicationClosed(close) = err else {
+            panic!("unexpected close: {err:?}");
+        };
+        assert_eq!(close.error_code, 0u32.into());
+        assert_eq!(&close.reason[..], b"shutdown");
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_send_queue() {
+        const WINDOW: u32 = 16 * 1024;
+        let mut transport_config = TransportConfig::default();
+        transport_config
+            .stream_receive_window(VarInt::from_u32(WINDOW))
+            .receive_window(VarInt::from_u32(WINDOW));
+        let (ep1, ep2, ep1_nodeaddr, _relay) =
+            endpoint_pair(|b| b.transport_config(transport_config), |b| b).await;
+
+        let accept = tokio::spawn(async move {
+            let conn = ep1.accept().await.unwrap().await.unwrap();
+            (ep1, conn)
+        });
+        let conn = tokio::time::timeout(TIMEOUT, ep2.connect(ep1_nodeaddr, TEST_ALPN))
+            .await
+            .unwrap()
+            .unwrap();
+        let (_ep1, remote) = tokio::time::timeout(TIMEOUT, accept)
+            .await
+            .unwrap()
+            .unwrap();
+        let queue = conn.send_queue();
+        assert_eq!(queue.unsent_bytes(), 0);
+        assert_eq!(queue.blocked_streams, 0);
+        assert!(queue.datagram_buffer_space > 0);
+
+        // Writing more than the remote allows blocks the stream with the excess queued.
+        let data = vec![42u8; 4 * WINDOW as usize];
+        let mut stream = conn.track_send_stream(conn.open_uni().await.unwrap());
+        let write = tokio::spawn({
+            let data = data.clone();
+            async move {
+                stream.write_all(&data).await.unwrap();
+                stream.finish().unwrap();
+                stream
+            }
+        });
+        tokio::time::timeout(TIMEOUT, async {
+            while conn.send_queue().blocked_streams == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let queue = conn.send_queue();
+        assert!(queue.queued_bytes > 0);
+        // Only a window of the data was sent, the rest waits in the queue.
+        assert!(queue.unsent_bytes() >= data.len() as u64 - 2 * WINDOW as u64);
+        assert!(queue.unsent_bytes() <= data.len() as u64);
+
+        // Reading on the remote drains the queue.
+        let mut recv = remote.accept_uni().await.unwrap();
+        let received = recv.read_to_end(data.len()).await.unwrap();
+        assert_eq!(received, data);
+        let _stream = write.await.unwrap();
+        let queue = conn.send_queue();
+        assert_eq!(queue.unsent_bytes(), 0);
+        assert_eq!(queue.blocked_streams, 0);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_connection_ids() {
+        let res = Endpoint::builder()
+            .connection_ids(ConnectionIdConfig {
+                len: 32,
+                ..Default::default()
+            })
+            .bind()
+            .await;
+        assert!(res.is_err());
+
+        let (ep1, ep2, ep1_nodeaddr, _relay) = endpoint_pair(
+            |b| b.connection_ids(ConnectionIdConfig::privacy()),
+            |b| {
+                b.connection_ids(ConnectionIdConfig {
+                    len: 12,
+                    rotate_on_path_change: true,
+                    ..Default::default()
+                })
+            },
+        )
+        .await;
+
+        let accept = tokio::spawn(async move {
+            let conn = ep1.accept().await.unwrap().await.unwrap();
+            let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+            let msg = recv.read_to_end(100).await.unwrap();
+            send.write_all(&msg).await.unwrap();
+            send.finish().unwrap();
+            conn.closed().await;
+        });
+        let conn = tokio::time::timeout(TIMEOUT, ep2.connect(ep1_nodeaddr, TEST_ALPN))
+            .await
+            .unwrap()
+            .unwrap();
+        let (mut send, mut recv) = conn.open_bi().await.unwrap();
+        send.write_all(b"hello").await.unwrap();
+        send.finish().unwrap();
+        let msg = tokio::time::timeout(TIMEOUT, recv.read_to_end(100))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg, b"hello");
+
+        // The connection starts on the relay and then switches to the direct path, which
+        // retires the connection ID used on the relay.
+        tokio::time::timeout(TIMEOUT, async {
+            while conn.stats().frame_tx.retire_connection_id == 0 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("connection IDs not rotated");
+        conn.close(0u32.into(), b"done");
+        tokio::time::timeout(TIMEOUT, accept)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn endpoint_accept_policy_peer_token() {
+        let (ep1, ep2, ep1_nodeaddr, _relay) = endpoint_pair(
+            |b| b.accept_policy(|conn| conn.peer_token().as_deref() == Some(b"invite")),
+            |b| b,
+        )
+        .await;
+
+        let accept = tokio::spawn(async move {
+            let first = ep1.accept().await.unwrap().await;
+            let second = ep1.accept().await.unwrap().await;
+            (ep1, first, second)
+        });
+
+        let too_large = vec![0u8; MAX_PEER_TOKEN_SIZE + 1];
+        assert!(ep2
+            .connect_with_token(ep1_nodeaddr.clone(), TEST_ALPN, &too_large)
+            .await
+            .is_err());
+
+        let conn = tokio::time::timeout(
+            TIMEOUT,
+            ep2.connect_with_token(ep1_nodeaddr.clone(), TEST_ALPN, b"invite"),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        // The accepting side did not present a token.
+        assert_eq!(conn.peer_token(), None);
+
+        // Without the token the connection is closed before any stream is accepted.
+        let refused = tokio::time::timeout(TIMEOUT, ep2.connect(ep1_nodeaddr, TEST_ALPN))
+            .await
+            .unwrap()
+            .unwrap();
+        let err = tokio::time::timeout(TIMEOUT, refused.closed())
+            .await
+            .unwrap();
+        match err {
+            ConnectionError::ApplicationClosed(close) => {
+                assert_eq!(close.error_code, ACCEPT_POLICY_REFUSED);
+            }
+            err => panic!("unexpected close: {err:?}"),
+        }
+
+        let (_ep1, first, second) = tokio::time::timeout(TIMEOUT, accept)
+            .await
+            .unwrap()
+            .unwrap();
+        let first = first.unwrap();
+        assert_eq!(first.peer_token().as_deref(), Some(&b"invite"[..]));
+        assert_eq!(first.remote_node_id().unwrap(), ep2.node_id());
+        assert!(matches!(second, Err(ConnectionError::LocallyClosed)));
+        drop(conn);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_bind_port_range() {
+        let start = rand::thread_rng().gen_range(20_000..60_000);
+        let ports = start..=start + 3;
+        let dir = std::env::temp_dir().join(format!("iroh-test-{}", rand::random::<u64>()));
+        let state = dir.join("port");
+        let bind = |state: Option<&std::path::Path>| {
+            let mut builder = Endpoint::builder()
+                .relay_mode(RelayMode::Disabled)
+                .bind_port_range(ports.clone());
+            if let Some(state) = state {
+                builder = builder.port_state_path(state);
+            }
+            builder.bind()
+        };
+
+        // The persisted port is preferred.
+        std::fs::create_dir_all(&dir).unwrap();
+        let port = start + 2;
+        std::fs::write(&state, port.to_string()).unwrap();
+        let ep1 = bind(Some(&state)).await.unwrap();
+        assert_eq!(ep1.bound_sockets().0.port(), port);
+
+        // A taken port is skipped, and binding exactly it fails.
+        let ep2 = bind(None).await.unwrap();
+        assert!(ports.contains(&ep2.bound_sockets().0.port()));
+        assert_ne!(ep2.bound_sockets().0.port(), port);
+        assert!(Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind_port(port)
+            .bind()
+            .await
+            .is_err());
+
+        // A different port is persisted if the persisted one is taken.
+        let ep3 = bind(Some(&state)).await.unwrap();
+        let other = ep3.bound_sockets().0.port();
+        assert!(ports.contains(&other));
+        assert_ne!(other, port);
+        assert_eq!(std::fs::read_to_string(&state).unwrap(), other.to_string());
+
+        for ep in [ep1, ep2, ep3] {
+            ep.close().await;
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_net_report_cache() {
+        let (relay_map, relay_url, _guard) = run_relay_server().await.unwrap();
+        let dir = std::env::temp_dir().join(format!("iroh-test-{}", rand::random::<u64>()));
+        let cache = dir.join("net-report.json");
+        let bind = || {
+            Endpoint::builder()
+                .relay_mode(RelayMode::Custom(relay_map.clone()))
+                .insecure_skip_relay_cert_verify(true)
+                .net_report_cache(&cache, Duration::from_secs(60))
+                .bind()
+        };
+
+        // The report is cached once the probes are done, at the latest when closing.
+        let ep = bind().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), ep.home_relay().initialized())
+            .await
+            .unwrap()
+            .unwrap();
+        ep.close().await;
+        let content = std::fs::read_to_string(&cache).unwrap();
+        assert!(content.contains(relay_url.as_str()));
+
+        // A restarted endpoint uses the cached home relay.
+        let ep = bind().await.unwrap();
+        let home_relay =
+            tokio::time::timeout(Duration::from_secs(10), ep.home_relay().initialized())
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(home_relay, relay_url);
+        ep.close().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_static_direct_addrs() {
+        let static_addr: SocketAddr = "203.0.113.7:4433".parse().unwrap();
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .static_direct_addrs([static_addr])
+            .bind()
+            .await
+            .unwrap();
+
+        let addrs =
+            tokio::time::timeout(Duration::from_secs(10), ep.direct_addresses().initialized())
+                .await
+                .unwrap()
+                .unwrap();
+        let configured = addrs
+            .iter()
+            .find(|addr| addr.addr == static_addr)
+            .expect("static address advertised");
+        assert_eq!(configured.typ, DirectAddrType::Static);
+        assert_eq!(configured.reachability(), Reachability::High);
+        assert!(addrs
+            .iter()
+            .filter(|addr| addr.typ == DirectAddrType::Local)
+            .all(|addr| addr.reachability() <= Reachability::Medium));
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_direct_addresses_no_stun_relay() {
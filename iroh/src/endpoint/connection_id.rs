@@ -0,0 +1,151 @@
+//! How the QUIC connections of an [`Endpoint`] identify themselves on the wire.
+//!
+//! Every QUIC packet carries the connection ID chosen by its receiver.  Observers on the
+//! network can use them to link the packets of a connection, even when it moves to a new
+//! path.  Longer and more frequently rotated connection IDs make this harder, at the cost
+//! of a few bytes per packet and some more frames to issue new IDs.
+//!
+//! [`Endpoint`]: super::Endpoint
+
+use std::time::Duration;
+
+use anyhow::{ensure, Result};
+use quinn_proto::{
+    ConnectionIdGenerator, HashedConnectionIdGenerator, RandomConnectionIdGenerator,
+};
+
+/// The connection ID length used by default, as in QUIC implementations generally.
+pub const DEFAULT_CONNECTION_ID_LEN: usize = 8;
+
+/// The shortest connection ID length allowed.
+///
+/// Connection IDs route the packets of all connections sharing the socket, too short IDs
+/// are likely to collide.
+const MIN_CONNECTION_ID_LEN: usize = 4;
+
+/// The longest connection ID length allowed by QUIC version 1.
+const MAX_CONNECTION_ID_LEN: usize = 20;
+
+/// Configures the connection IDs issued by an [`Endpoint`].
+///
+/// See [`Builder::connection_ids`].
+///
+/// [`Endpoint`]: super::Endpoint
+/// [`Builder::connection_ids`]: super::Builder::connection_ids
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionIdConfig {
+    /// The length of the connection IDs in bytes, between 4 and 20.
+    ///
+    /// Defaults to [`DEFAULT_CONNECTION_ID_LEN`].
+    pub len: usize,
+    /// How long a connection ID is used before it is retired and the peer is given a new one.
+    ///
+    /// Defaults to `None`, the connection IDs are used until the connection migrates.
+    pub lifetime: Option<Duration>,
+    /// Whether the connections switch to a new connection ID when the path to the remote
+    /// node changes.
+    ///
+    /// Connections are not aware of iroh switching between direct paths and relays, so by
+    /// default they keep using the same connection ID on every path, which links the paths
+    /// for an observer seeing several of them.  quinn can only rotate the connection IDs of
+    /// all connections at once, so the path of any connection changing rotates the IDs of
+    /// all connections of the endpoint.  Defaults to `false`.
+    pub rotate_on_path_change: bool,
+}
+
+impl Default for ConnectionIdConfig {
+    fn default() -> Self {
+        Self {
+            len: DEFAULT_CONNECTION_ID_LEN,
+            lifetime: None,
+            rotate_on_path_change: false,
+        }
+    }
+}
+
+impl ConnectionIdConfig {
+    /// Returns a configuration making it hard to link the packets of a connection.
+    ///
+    /// The connection IDs are 16 bytes long, rotated every minute and on every change of
+    /// the path to the remote node.
+    pub fn privacy() -> Self {
+        Self {
+            len: 16,
+            lifetime: Some(Duration::from_secs(60)),
+            rotate_on_path_change: true,
+        }
+    }
+
+    /// Checks the configuration can be used by the QUIC stack.
+    pub(crate) fn validate(&self) -> Result<()> {
+        ensure!(
+            (MIN_CONNECTION_ID_LEN..=MAX_CONNECTION_ID_LEN).contains(&self.len),
+            "connection ID length must be between {MIN_CONNECTION_ID_LEN} and \
+             {MAX_CONNECTION_ID_LEN} bytes, not {}",
+            self.len
+        );
+        if let Some(lifetime) = self.lifetime {
+            ensure!(
+                !lifetime.is_zero(),
+                "connection ID lifetime must not be zero"
+            );
+        }
+        Ok(())
+    }
+
+    /// Configures the QUIC endpoint to issue connection IDs as configured.
+    pub(crate) fn apply(&self, endpoint_config: &mut quinn::EndpointConfig) {
+        let config = self.clone();
+        endpoint_config.cid_generator(move || config.generator());
+    }
+
+    fn generator(&self) -> Box<dyn ConnectionIdGenerator> {
+        // The hashed generator is the default of quinn, it supports only the default length.
+        if self.len == DEFAULT_CONNECTION_ID_LEN {
+            let mut generator = HashedConnectionIdGenerator::new();
+            if let Some(lifetime) = self.lifetime {
+                generator.set_lifetime(lifetime);
+            }
+            Box::new(generator)
+        } else {
+            let mut generator = RandomConnectionIdGenerator::new(self.len);
+            if let Some(lifetime) = self.lifetime {
+                generator.set_lifetime(lifetime);
+            }
+            Box::new(generator)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_id_config() {
+        let config = ConnectionIdConfig::default();
+        assert!(config.validate().is_ok());
+        let generator = config.generator();
+        assert_eq!(generator.cid_len(), DEFAULT_CONNECTION_ID_LEN);
+        assert_eq!(generator.cid_lifetime(), None);
+
+        let config = ConnectionIdConfig::privacy();
+        assert!(config.validate().is_ok());
+        let generator = config.generator();
+        assert_eq!(generator.cid_len(), 16);
+        assert_eq!(generator.cid_lifetime(), Some(Duration::from_secs(60)));
+
+        for len in [0, 3, 21] {
+            let config = ConnectionIdConfig {
+                len,
+                ..Default::default()
+            };
+            assert!(config.validate().is_err(), "length {len}");
+        }
+        let config = ConnectionIdConfig {
+            lifetime: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
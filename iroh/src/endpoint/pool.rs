@@ -0,0 +1,169 @@
+//! Reuse of connections dialed with [`Endpoint::connect_reuse`].
+//!
+//! [`Endpoint::connect_reuse`]: super::Endpoint::connect_reuse
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use futures_util::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+use iroh_base::NodeId;
+use tracing::trace;
+
+use super::Connection;
+
+/// A dial shared by all callers waiting for it, its error is shared as well.
+type Dial = Shared<BoxFuture<'static, Result<Connection, Arc<anyhow::Error>>>>;
+
+/// The connection to a remote node for one ALPN.
+#[derive(Debug)]
+enum Slot {
+    Dialing(Dial),
+    Connected(Connection),
+}
+
+/// The connections dialed for reuse, one per remote node and ALPN.
+#[derive(Debug, Default)]
+pub(super) struct ConnectionPool {
+    slots: Mutex<HashMap<(NodeId, Vec<u8>), Slot>>,
+}
+
+impl ConnectionPool {
+    /// Returns the healthy connection to `node_id` for `alpn`, or dials a new one.
+    ///
+    /// Concurrent calls for the same node and ALPN wait for a single dial, rather than
+    /// each dialing a connection of their own, and all get its result: if the dial fails
+    /// they all fail with its error.  The slots of stale connections and abandoned dials are
+    /// pruned on every call.
+    pub(super) async fn get_or_dial<F>(
+        &self,
+        node_id: NodeId,
+        alpn: &[u8],
+        dial: impl FnOnce() -> F,
+    ) -> Result<Connection>
+    where
+        F: Future<Output = Result<Connection>> + Send + 'static,
+    {
+        let key = (node_id, alpn.to_vec());
+        let shared = {
+            let mut slots = self.slots.lock().expect("poisoned");
+            slots.retain(|_, slot| !is_unused(slot));
+            match slots.get(&key) {
+                Some(Slot::Connected(conn)) if is_healthy(conn) => {
+                    trace!(remote = %node_id.fmt_short(), "reusing connection");
+                    return Ok(conn.clone());
+                }
+                Some(Slot::Dialing(shared)) => {
+                    trace!(remote = %node_id.fmt_short(), "waiting for dial");
+                    shared.clone()
+                }
+                slot => {
+                    if slot.is_some() {
+                        trace!(remote = %node_id.fmt_short(), "pooled connection is stale, dialing");
+                    }
+                    let shared = dial().map(|res| res.map_err(Arc::new)).boxed().shared();
+                    slots.insert(key.clone(), Slot::Dialing(shared.clone()));
+                    shared
+                }
+            }
+        };
+        let res = shared.clone().await;
+
+        // The first caller to finish stores the result, unless the pool was cleared.
+        let mut slots = self.slots.lock().expect("poisoned");
+        if let Some(Slot::Dialing(dialing)) = slots.get(&key) {
+            if dialing.ptr_eq(&shared) {
+                match &res {
+                    Ok(conn) => {
+                        slots.insert(key, Slot::Connected(conn.clone()));
+                    }
+                    // Do not keep a failed dial around, the next call dials again.
+                    Err(_) => {
+                        slots.remove(&key);
+                    }
+                }
+            }
+        }
+        res.map_err(|err| anyhow!("{err:#}"))
+    }
+
+    /// Forgets all connections.
+    pub(super) fn clear(&self) {
+        self.slots.lock().expect("poisoned").clear();
+    }
+
+    /// The number of slots, including those not yet pruned.
+    #[cfg(test)]
+    pub(super) fn len(&self) -> usize {
+        self.slots.lock().expect("poisoned").len()
+    }
+}
+
+/// Whether a slot can be pruned: its dial was abandoned by all callers, or its connection
+/// is stale.
+///
+/// Dials are only cloned while the pool is locked, so a dial which is not shared while the
+/// pool is locked stays abandoned.
+fn is_unused(slot: &Slot) -> bool {
+    match slot {
+        Slot::Dialing(shared) => shared.strong_count() == Some(1),
+        Slot::Connected(conn) => !is_healthy(conn),
+    }
+}
+
+/// Whether a pooled connection may still be handed out.
+///
+/// A connection is stale once it is closed, by either side or by an idle timeout.
+fn is_healthy(conn: &Connection) -> bool {
+    conn.close_reason().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use iroh_base::SecretKey;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_failed_dial_shared() {
+        let pool = ConnectionPool::default();
+        let unreachable = SecretKey::generate(rand::thread_rng()).public();
+        let dials = Arc::new(AtomicUsize::new(0));
+        let dial = || {
+            let dials = dials.clone();
+            async move {
+                let n = dials.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Err(anyhow!("no addressing information for dial {n}"))
+            }
+        };
+
+        let results = futures_util::future::join_all(
+            (0..4).map(|_| pool.get_or_dial(unreachable, b"alpn", dial)),
+        )
+        .await;
+        assert_eq!(dials.load(Ordering::SeqCst), 1);
+        for res in results {
+            assert_eq!(
+                res.unwrap_err().to_string(),
+                "no addressing information for dial 0"
+            );
+        }
+
+        // The failed dial is not kept, the next call dials again.
+        assert_eq!(pool.len(), 0);
+        assert!(pool.get_or_dial(unreachable, b"alpn", dial).await.is_err());
+        assert_eq!(dials.load(Ordering::SeqCst), 2);
+    }
+}
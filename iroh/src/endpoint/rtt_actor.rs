@@ -2,16 +2,20 @@
 
 use std::{pin::Pin, task::Poll};
 
-use iroh_base::NodeId;
-use iroh_metrics::inc;
+use futures_util::FutureExt;
+use iroh_base::{inc, NodeId};
 use n0_future::{
     task::{self, AbortOnDropHandle},
     MergeUnbounded, Stream, StreamExt,
 };
 use tokio::sync::mpsc;
-use tracing::{debug, info_span, Instrument};
+use tracing::{debug, info_span, trace, warn, Instrument};
 
-use crate::{magicsock::ConnectionType, metrics::MagicsockMetrics, watchable::WatcherStream};
+use crate::{
+    magicsock::{self, ConnectionType},
+    metrics::MagicsockMetrics,
+    watchable::WatcherStream,
+};
 
 #[derive(Debug)]
 pub(super) struct RttHandle {
@@ -21,9 +25,12 @@ pub(super) struct RttHandle {
 }
 
 impl RttHandle {
-    pub(super) fn new() -> Self {
+    /// Creates the actor, which rotates the connection IDs on path changes if given the
+    /// magic socket to do so.
+    pub(super) fn new(cid_rotation: Option<magicsock::Handle>) -> Self {
         let mut actor = RttActor {
             connection_events: Default::default(),
+            cid_rotation,
         };
         let (msg_tx, msg_rx) = mpsc::channel(16);
         let handle = task::spawn(
@@ -62,6 +69,12 @@ struct RttActor {
     /// Stream of connection type changes.
     #[debug("MergeUnbounded<WatcherStream<ConnectionType>>")]
     connection_events: MergeUnbounded<MappedStream>,
+    /// The magic socket to rotate the connection IDs of, if they rotate on path changes.
+    ///
+    /// See [`ConnectionIdConfig::rotate_on_path_change`].
+    ///
+    /// [`ConnectionIdConfig::rotate_on_path_change`]: super::ConnectionIdConfig::rotate_on_path_change
+    cid_rotation: Option<magicsock::Handle>,
 }
 
 #[derive(Debug)]
@@ -73,10 +86,13 @@ struct MappedStream {
     /// This an indiciator of whether this connection was direct before.
     /// This helps establish metrics on number of connections that became direct.
     was_direct_before: bool,
+    /// Whether the initial connection type was seen, any later one is a path change.
+    had_conn_type: bool,
 }
 
 impl Stream for MappedStream {
-    type Item = ConnectionType;
+    /// The node whose connection changed to a new path.
+    type Item = Option<NodeId>;
 
     /// Performs the congestion controller reset for a magic socket path change.
     ///
@@ -102,7 +118,8 @@ impl Stream for MappedStream {
                         inc!(MagicsockMetrics, connection_became_direct);
                     }
                 }
-                Poll::Ready(Some(new_conn_type))
+                let had_conn_type = std::mem::replace(&mut self.had_conn_type, true);
+                Poll::Ready(Some(had_conn_type.then_some(self.node_id)))
             }
             Poll::Ready(None) => Poll::Ready(None),
             Poll::Pending => Poll::Pending,
@@ -124,7 +141,11 @@ impl RttActor {
                         None => break,
                     }
                 }
-                _item = self.connection_events.next(), if !self.connection_events.is_empty() => {}
+                Some(changed) = self.connection_events.next(), if !self.connection_events.is_empty() => {
+                    if let Some(node_id) = changed {
+                        self.handle_path_changed(node_id);
+                    }
+                }
             }
         }
         debug!("rtt-actor finished");
@@ -155,7 +176,28 @@ impl RttActor {
             connection,
             node_id,
             was_direct_before: false,
+            had_conn_type: false,
         });
         inc!(MagicsockMetrics, connection_handshake_success);
     }
+
+    /// Rotates the connection IDs once the path of a connection changed.
+    ///
+    /// quinn only sees the stable mapped addresses of the nodes, so it never notices a path
+    /// change itself.  Switching to a new connection ID keeps observers from linking the
+    /// paths.  Several connections changing their paths at once, e.g. after the local
+    /// network changed, only rotate the connection IDs once.
+    fn handle_path_changed(&mut self, node_id: NodeId) {
+        let Some(msock) = &self.cid_rotation else {
+            return;
+        };
+        let mut nodes = vec![node_id];
+        while let Some(Some(changed)) = self.connection_events.next().now_or_never() {
+            nodes.extend(changed);
+        }
+        trace!(nodes = ?nodes.iter().map(|n| n.fmt_short()).collect::<Vec<_>>(), "path changed, rotating connection IDs");
+        if let Err(err) = msock.rotate_connection_ids() {
+            warn!("failed to rotate connection IDs: {err:#}");
+        }
+    }
 }
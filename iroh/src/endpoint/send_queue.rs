@@ -0,0 +1,208 @@
+//! Accounting of the data queued on the send streams of a [`Connection`].
+//!
+//! The QUIC stack does not expose how much data is waiting to be sent.  Instead the writes
+//! made through a [`TrackedSendStream`] are accounted while they wait for the stream to
+//! accept the data, which happens once flow control of the remote and congestion control
+//! allow it.  The data accepted into the send buffers of the streams is compared with the
+//! bytes the connection sent since, from its statistics, to estimate how much of it still
+//! waits to be sent.
+//!
+//! [`Connection`]: super::Connection
+
+use std::{
+    future::poll_fn,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    task::Poll,
+};
+
+use super::{SendStream, WriteError};
+
+/// The data queued on the tracked send streams of a connection.
+#[derive(Debug, Default)]
+pub(super) struct SendQueueState {
+    queued_bytes: AtomicU64,
+    blocked_streams: AtomicUsize,
+    /// Bytes accepted by the tracked streams, in total.
+    accepted_bytes: AtomicU64,
+    /// The bytes the connection sent when the first stream was tracked.
+    sent_bytes_at_start: OnceLock<u64>,
+}
+
+impl SendQueueState {
+    /// Starts accounting, given the bytes sent by the connection so far.
+    pub(super) fn start(&self, sent_bytes: u64) {
+        self.sent_bytes_at_start.get_or_init(|| sent_bytes);
+    }
+
+    /// Takes a snapshot, given the bytes sent by the connection so far.
+    pub(super) fn snapshot(&self, sent_bytes: u64, datagram_buffer_space: usize) -> SendQueue {
+        let sent_since_start = self
+            .sent_bytes_at_start
+            .get()
+            .map_or(0, |start| sent_bytes.saturating_sub(*start));
+        SendQueue {
+            queued_bytes: self.queued_bytes.load(Ordering::Relaxed),
+            buffered_bytes: self
+                .accepted_bytes
+                .load(Ordering::Relaxed)
+                .saturating_sub(sent_since_start),
+            blocked_streams: self.blocked_streams.load(Ordering::Relaxed),
+            datagram_buffer_space,
+        }
+    }
+}
+
+/// A snapshot of the data queued for sending on a [`Connection`].
+///
+/// Applications can use it to shed load, e.g. dropping stale frames, rather than writing
+/// more data into a backlogged connection.  Only writes made through a
+/// [`TrackedSendStream`] are accounted.
+///
+/// See [`Connection::send_queue`].
+///
+/// [`Connection`]: super::Connection
+/// [`Connection::send_queue`]: super::Connection::send_queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendQueue {
+    /// Bytes passed to pending writes which the streams did not accept yet.
+    pub queued_bytes: u64,
+    /// Bytes accepted into the send buffers of the streams which were not sent yet.
+    ///
+    /// Estimated from the bytes the connection sent since the first stream was tracked.
+    /// Those include packet headers, retransmissions and the data of untracked streams, so
+    /// this can be lower than the actual amount.
+    pub buffered_bytes: u64,
+    /// Number of streams with a write blocked on flow control or congestion control.
+    pub blocked_streams: usize,
+    /// Bytes of datagrams which can be queued before the oldest queued datagrams are dropped.
+    pub datagram_buffer_space: usize,
+}
+
+impl SendQueue {
+    /// Bytes written to the tracked streams which were not sent yet, queued or buffered.
+    pub fn unsent_bytes(&self) -> u64 {
+        self.queued_bytes + self.buffered_bytes
+    }
+}
+
+/// A [`SendStream`] whose writes are accounted in the [`SendQueue`] of its connection.
+///
+/// Created by [`Connection::track_send_stream`].  Dereferences to the [`SendStream`],
+/// writes made on it directly are not accounted.
+///
+/// [`Connection::track_send_stream`]: super::Connection::track_send_stream
+#[derive(Debug)]
+pub struct TrackedSendStream {
+    inner: SendStream,
+    state: Arc<SendQueueState>,
+}
+
+impl TrackedSendStream {
+    pub(super) fn new(inner: SendStream, state: Arc<SendQueueState>) -> Self {
+        Self { inner, state }
+    }
+
+    /// Writes bytes to the stream, returning how many bytes were written.
+    ///
+    /// See [`SendStream::write`].
+    pub async fn write(&mut self, buf: &[u8]) -> Result<usize, WriteError> {
+        let mut pending = Pending::new(&self.state, buf.len());
+        let inner = &mut self.inner;
+        let n = poll_fn(|cx| pending.poll(Pin::new(&mut *inner).poll_write(cx, buf))).await?;
+        pending.written(n);
+        Ok(n)
+    }
+
+    /// Writes all bytes to the stream.
+    ///
+    /// See [`SendStream::write_all`].
+    pub async fn write_all(&mut self, mut buf: &[u8]) -> Result<(), WriteError> {
+        let mut pending = Pending::new(&self.state, buf.len());
+        let inner = &mut self.inner;
+        while !buf.is_empty() {
+            let n = poll_fn(|cx| pending.poll(Pin::new(&mut *inner).poll_write(cx, buf))).await?;
+            pending.written(n);
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+
+    /// Returns the underlying [`SendStream`].
+    pub fn into_inner(self) -> SendStream {
+        self.inner
+    }
+}
+
+impl Deref for TrackedSendStream {
+    type Target = SendStream;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for TrackedSendStream {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+/// The bytes of a write not yet accepted by the stream.
+///
+/// Removed from the queue once dropped, also when the write is cancelled.
+#[derive(Debug)]
+struct Pending<'a> {
+    state: &'a SendQueueState,
+    bytes: u64,
+    blocked: bool,
+}
+
+impl<'a> Pending<'a> {
+    fn new(state: &'a SendQueueState, bytes: usize) -> Self {
+        let bytes = bytes as u64;
+        state.queued_bytes.fetch_add(bytes, Ordering::Relaxed);
+        Self {
+            state,
+            bytes,
+            blocked: false,
+        }
+    }
+
+    /// Marks the stream as blocked while polling the write returns pending.
+    fn poll<T>(&mut self, poll: Poll<T>) -> Poll<T> {
+        let blocked = poll.is_pending();
+        if blocked != self.blocked {
+            self.blocked = blocked;
+            if blocked {
+                self.state.blocked_streams.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.state.blocked_streams.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        poll
+    }
+
+    /// Moves the bytes accepted by the stream from the queue to the send buffer.
+    fn written(&mut self, n: usize) {
+        let n = (n as u64).min(self.bytes);
+        self.bytes -= n;
+        self.state.queued_bytes.fetch_sub(n, Ordering::Relaxed);
+        self.state.accepted_bytes.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        self.state
+            .queued_bytes
+            .fetch_sub(self.bytes, Ordering::Relaxed);
+        if self.blocked {
+            self.state.blocked_streams.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
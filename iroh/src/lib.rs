@@ -252,7 +252,7 @@ pub use endpoint::{Endpoint, RelayMode};
 pub use iroh_base::{
     KeyParsingError, NodeAddr, NodeId, PublicKey, RelayUrl, RelayUrlParseError, SecretKey,
 };
-pub use iroh_relay::{RelayMap, RelayNode};
+pub use iroh_relay::{RelayMap, RelayNode, RelayRegion, WeightedRelay};
 
 #[cfg(any(test, feature = "test-utils"))]
 pub mod test_utils;
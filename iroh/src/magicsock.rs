@@ -20,6 +20,7 @@ use std::{
     fmt::Display,
     io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    path::PathBuf,
     pin::Pin,
     sync::{
         atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering},
@@ -33,8 +34,7 @@ use atomic_waker::AtomicWaker;
 use bytes::Bytes;
 use concurrent_queue::ConcurrentQueue;
 use data_encoding::HEXLOWER;
-use iroh_base::{NodeAddr, NodeId, PublicKey, RelayUrl, SecretKey};
-use iroh_metrics::{inc, inc_by};
+use iroh_base::{inc, inc_by, NodeAddr, NodeId, PublicKey, RelayUrl, SecretKey};
 use iroh_relay::{protos::stun, RelayMap};
 use n0_future::{
     boxed::BoxStream,
@@ -45,6 +45,7 @@ use n0_future::{
     FutureExt, StreamExt,
 };
 use net_report::{IpMappedAddr, IpMappedAddresses, QuicConfig, MAPPED_ADDR_PORT};
+use netwatch::ip::is_unicast_link_local;
 use netwatch::{interfaces, ip::LocalAddresses, netmon, UdpSocket};
 use quinn::{AsyncUdpSocket, ServerConfig};
 use rand::{seq::SliceRandom, Rng, SeedableRng};
@@ -61,6 +62,7 @@ use url::Url;
 use self::{
     metrics::Metrics as MagicsockMetrics,
     node_map::{NodeMap, PingAction, PingRole, SendPing},
+    port_selection::PortState,
     relay_actor::{RelayActor, RelayActorMessage, RelayRecvDatagram},
     udp_conn::UdpConn,
 };
@@ -71,12 +73,15 @@ use crate::{
     disco::{self, CallMeMaybe, SendAddr},
     discovery::{Discovery, DiscoveryItem},
     dns::DnsResolver,
+    endpoint::ConnectionIdConfig,
     key::{public_ed_box, secret_ed_box, DecryptionError, SharedSecret},
     watchable::{Watchable, Watcher},
 };
 
 mod metrics;
+mod net_report_cache;
 mod node_map;
+mod port_selection;
 mod relay_actor;
 mod udp_conn;
 
@@ -84,8 +89,12 @@ pub use node_map::Source;
 
 pub use self::{
     metrics::Metrics,
-    node_map::{ConnectionType, ControlMsg, DirectAddrInfo, RemoteInfo},
+    node_map::{
+        ClearReason, ConnectionType, ControlMsg, DirectAddrInfo, PathCandidate, PathInfo,
+        PathTimers, PathTransition, RemoteInfo, SelectedPath, TransitionReason, ValidationStatus,
+    },
 };
+pub(crate) use self::{net_report_cache::ReportCache, port_selection::PortSelection};
 
 /// How long we consider a STUN-derived endpoint valid for. UDP NAT mappings typically
 /// expire at 30 seconds, so this is a few seconds shy of that.
@@ -105,6 +114,15 @@ pub(crate) struct Options {
     /// If set to `None` it will choose a random port and listen on `[::]:0`.
     pub(crate) addr_v6: Option<SocketAddrV6>,
 
+    /// The ports the sockets may be bound to.
+    pub(crate) port_selection: PortSelection,
+
+    /// The file persisting the bound port, to bind it again after a restart.
+    pub(crate) port_state: Option<PathBuf>,
+
+    /// The file caching the last net report, to use it right away after a restart.
+    pub(crate) net_report_cache: Option<ReportCache>,
+
     /// Secret key for this node.
     pub(crate) secret_key: SecretKey,
 
@@ -126,9 +144,19 @@ pub(crate) struct Options {
     /// Proxy configuration.
     pub(crate) proxy_url: Option<Url>,
 
+    /// Direct addresses to advertise in addition to the discovered ones.
+    pub(crate) static_direct_addrs: Vec<SocketAddr>,
+
+    /// Whether to assume other nodes can reach us on our direct addresses, skipping hole
+    /// punching towards nodes calling us.
+    pub(crate) assume_reachable: bool,
+
     /// ServerConfig for the internal QUIC endpoint
     pub(crate) server_config: ServerConfig,
 
+    /// How the QUIC endpoint issues connection IDs.
+    pub(crate) connection_ids: ConnectionIdConfig,
+
     /// Skip verification of SSL certificates from relay servers
     ///
     /// May only be used in tests.
@@ -148,13 +176,19 @@ impl Default for Options {
         Options {
             addr_v4: None,
             addr_v6: None,
+            port_selection: PortSelection::default(),
+            port_state: None,
+            net_report_cache: None,
             secret_key,
             relay_map: RelayMap::empty(),
             node_map: None,
             discovery: None,
             proxy_url: None,
+            static_direct_addrs: Vec::new(),
+            assume_reachable: false,
             dns_resolver: DnsResolver::new(),
             server_config,
+            connection_ids: Default::default(),
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: false,
             #[cfg(any(test, feature = "test-utils"))]
@@ -240,7 +274,7 @@ pub(crate) struct MagicSock {
     ipv6_reported: Arc<AtomicBool>,
 
     /// None (or zero nodes) means relay is disabled.
-    relay_map: RelayMap,
+    relay_map: Watchable<RelayMap>,
     /// Nearest relay node ID; 0 means none/unknown.
     my_relay: Watchable<Option<RelayUrl>>,
     /// Tracks the networkmap node entity for each node discovery key.
@@ -264,6 +298,10 @@ pub(crate) struct MagicSock {
 
     /// Our discovered direct addresses.
     direct_addrs: DiscoveredDirectAddrs,
+    /// The configured direct addresses, always part of [`Self::direct_addrs`].
+    static_direct_addrs: Vec<SocketAddr>,
+    /// Whether nodes calling us are expected to reach us without hole punching.
+    assume_reachable: bool,
 
     /// List of CallMeMaybe disco messages that should be sent out after the next endpoint update
     /// completes
@@ -277,6 +315,11 @@ pub(crate) struct MagicSock {
     /// May only be used in tests.
     #[cfg(any(test, feature = "test-utils"))]
     insecure_skip_relay_cert_verify: bool,
+
+    /// Whether the loss of all network interfaces is simulated, see
+    /// [`MagicSock::set_offline`].
+    #[cfg(any(test, feature = "test-utils"))]
+    offline: AtomicBool,
 }
 
 impl MagicSock {
@@ -362,6 +405,21 @@ impl MagicSock {
         self.my_relay.watch()
     }
 
+    /// Returns the relay servers which may be used as home relay.
+    pub(crate) fn relay_map(&self) -> RelayMap {
+        self.relay_map.get()
+    }
+
+    /// Replaces the relay servers which may be used as home relay.
+    ///
+    /// The home relay is selected again with the next net report, which is started right
+    /// away.
+    pub(crate) fn set_relay_map(&self, relay_map: RelayMap) {
+        if self.relay_map.set(relay_map).is_ok() {
+            self.re_stun("relay-map-changed");
+        }
+    }
+
     /// Returns a [`Watcher`] that reports the [`ConnectionType`] we have to the
     /// given `node_id`.
     ///
@@ -376,11 +434,36 @@ impl MagicSock {
         self.node_map.conn_type(node_id)
     }
 
+    /// Returns the state of the path selection towards the node.
+    pub(crate) fn path_info(&self, node_id: NodeId) -> Option<PathInfo> {
+        self.node_map.path_info(node_id)
+    }
+
+    /// Returns a [`Watcher`] for the changes of the paths selected towards the node.
+    pub(crate) fn path_transitions(
+        &self,
+        node_id: NodeId,
+    ) -> Result<Watcher<Option<PathTransition>>> {
+        self.node_map.path_transitions(node_id)
+    }
+
     /// Returns the socket address which can be used by the QUIC layer to dial this node.
     pub(crate) fn get_mapping_addr(&self, node_id: NodeId) -> Option<NodeIdMappedAddr> {
         self.node_map.get_quic_mapped_addr_for_node_key(node_id)
     }
 
+    /// Returns the [`NodeId`] of the node the QUIC layer reaches on the given address.
+    ///
+    /// This is the reverse of [`MagicSock::get_mapping_addr`]: the QUIC layer only ever
+    /// sees [`NodeIdMappedAddr`]s and this maps them back to the node they belong to.
+    pub(crate) fn node_id_for_mapped_addr(&self, addr: SocketAddr) -> Option<NodeId> {
+        let SocketAddr::V6(addr) = addr else {
+            return None;
+        };
+        let addr = NodeIdMappedAddr::try_from(*addr.ip()).ok()?;
+        self.node_map.node_id_for_quic_mapped_addr(addr)
+    }
+
     /// Add addresses for a node to the magic socket's addresbook.
     #[instrument(skip_all, fields(me = %self.me))]
     pub fn add_node_addr(&self, mut addr: NodeAddr, source: node_map::Source) -> Result<()> {
@@ -434,7 +517,7 @@ impl MagicSock {
             .ok();
     }
 
-    #[cfg(test)]
+    #[cfg(any(test, feature = "test-utils"))]
     async fn force_network_change(&self, is_major: bool) {
         self.actor_sender
             .send(ActorMessage::ForceNetworkChange(is_major))
@@ -442,6 +525,47 @@ impl MagicSock {
             .ok();
     }
 
+    /// Simulates losing all network interfaces, or getting them back.
+    ///
+    /// While offline all datagrams are dropped, sent or received, over UDP and the relays
+    /// alike.  Coming back online is a major network change: the sockets are rebound and
+    /// the relay connections are re-established.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub(crate) async fn set_offline(&self, offline: bool) {
+        let was_offline = self.offline.swap(offline, Ordering::Relaxed);
+        if was_offline && !offline {
+            self.force_network_change(true).await;
+        }
+    }
+
+    /// Whether the loss of all network interfaces is simulated.
+    fn is_offline(&self) -> bool {
+        #[cfg(any(test, feature = "test-utils"))]
+        return self.offline.load(Ordering::Relaxed);
+        #[cfg(not(any(test, feature = "test-utils")))]
+        false
+    }
+
+    /// Receives and drops all datagrams ready while offline.
+    fn drain_offline(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        metas: &mut [quinn_udp::RecvMeta],
+    ) {
+        while let Poll::Ready(Ok(n)) = self.pconn4.poll_recv(cx, bufs, metas) {
+            trace!(count = n, "offline: dropping received UDP datagrams");
+        }
+        if let Some(ref pconn) = self.pconn6 {
+            while let Poll::Ready(Ok(n)) = pconn.poll_recv(cx, bufs, metas) {
+                trace!(count = n, "offline: dropping received UDP datagrams");
+            }
+        }
+        while let Poll::Ready(Ok(_)) = self.relay_datagram_recv_queue.poll_recv(cx) {
+            trace!("offline: dropping received relay datagram");
+        }
+    }
+
     #[cfg_attr(windows, allow(dead_code))]
     fn normalized_local_addr(&self) -> io::Result<SocketAddr> {
         let (v4, v6) = self.local_addr();
@@ -662,6 +786,10 @@ impl MagicSock {
         node: NodeId,
         contents: RelayContents,
     ) -> io::Result<()> {
+        if self.is_offline() {
+            trace!(node = %node.fmt_short(), relay_url = %url, "offline: dropping relay datagram");
+            return Ok(());
+        }
         trace!(
             node = %node.fmt_short(),
             relay_url = %url,
@@ -700,6 +828,10 @@ impl MagicSock {
     }
 
     fn try_send_udp(&self, addr: SocketAddr, transmit: &quinn_udp::Transmit) -> io::Result<()> {
+        if self.is_offline() {
+            trace!(%addr, "offline: dropping UDP datagram");
+            return Ok(());
+        }
         let conn = self.conn_for_addr(addr)?;
         conn.try_send(transmit)?;
         let total_bytes: u64 = transmit.contents.len() as u64;
@@ -734,6 +866,10 @@ impl MagicSock {
         if self.is_closed() {
             return Poll::Pending;
         }
+        if self.is_offline() {
+            self.drain_offline(cx, bufs, metas);
+            return Poll::Pending;
+        }
 
         // Three macros to help polling: they return if they get a result, execution
         // continues if they were Pending and we need to poll others (or finally return
@@ -1139,6 +1275,13 @@ impl MagicSock {
                             warn!("Unexpected CallMeMaybe as response of handling a CallMeMaybe");
                         }
                         PingAction::SendPing(ping) => {
+                            if self.assume_reachable && !ping.dst.is_relay() {
+                                // The node reaches us on our advertised addresses, our
+                                // pings would only punch holes in our own firewall.
+                                trace!(dst = %ping.dst, "assuming reachable, skip hole punching");
+                                inc!(MagicsockMetrics, skipped_disco_ping);
+                                continue;
+                            }
                             self.send_ping_queued(ping);
                         }
                     }
@@ -1617,13 +1760,19 @@ impl Handle {
         let Options {
             addr_v4,
             addr_v6,
+            port_selection,
+            port_state,
+            net_report_cache,
             secret_key,
             relay_map,
             node_map,
             discovery,
             dns_resolver,
             proxy_url,
+            static_direct_addrs,
+            assume_reachable,
             server_config,
+            connection_ids,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify,
             #[cfg(any(test, feature = "test-utils"))]
@@ -1632,8 +1781,20 @@ impl Handle {
 
         let relay_datagram_recv_queue = Arc::new(RelayDatagramRecvQueue::new());
 
-        let (pconn4, pconn6) = bind(addr_v4, addr_v6)?;
+        port_selection.validate()?;
+        connection_ids.validate()?;
+        let port_state = port_state.map(PortState::new);
+        let persisted_port = match port_state {
+            Some(ref state) => state.load().await,
+            None => None,
+        };
+        let (pconn4, pconn6) = bind(addr_v4, addr_v6, &port_selection, persisted_port)?;
         let port = pconn4.port();
+        if let Some(ref state) = port_state {
+            if let Err(err) = state.store(port, persisted_port).await {
+                warn!("{err:#}");
+            }
+        }
 
         // NOTE: we can end up with a zero port if `std::net::UdpSocket::socket_addr` fails
         match port.try_into() {
@@ -1652,6 +1813,14 @@ impl Handle {
             dns_resolver.clone(),
             Some(ip_mapped_addrs.clone()),
         )?;
+        let cached_report = match net_report_cache {
+            Some(ref cache) => cache.load().await,
+            None => None,
+        };
+        if let Some(ref report) = cached_report {
+            // The first probes are incremental, as if the cached report was the last one.
+            net_reporter.seed_report(report.clone()).await?;
+        }
 
         let pconn4_sock = pconn4.as_socket();
         let pconn6_sock = pconn6.as_ref().map(|p| p.as_socket());
@@ -1684,7 +1853,7 @@ impl Handle {
             poll_recv_counter: AtomicUsize::new(0),
             actor_sender: actor_sender.clone(),
             ipv6_reported: Arc::new(AtomicBool::new(false)),
-            relay_map,
+            relay_map: Watchable::new(relay_map),
             my_relay: Default::default(),
             net_reporter: net_reporter.addr(),
             pconn4,
@@ -1695,11 +1864,15 @@ impl Handle {
             udp_disco_sender,
             discovery,
             direct_addrs: Default::default(),
+            static_direct_addrs,
+            assume_reachable,
             pending_call_me_maybes: Default::default(),
             direct_addr_update_state: DirectAddrUpdateState::new(),
             dns_resolver,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify,
+            #[cfg(any(test, feature = "test-utils"))]
+            offline: AtomicBool::new(false),
         });
 
         let mut endpoint_config = quinn::EndpointConfig::default();
@@ -1709,6 +1882,7 @@ impl Handle {
         // through to quinn. We set the first byte of the packet to zero, which makes quinn ignore
         // the packet if grease_quic_bit is set to false.
         endpoint_config.grease_quic_bit(false);
+        connection_ids.apply(&mut endpoint_config);
 
         let endpoint = quinn::Endpoint::new_with_abstract_socket(
             endpoint_config,
@@ -1766,7 +1940,7 @@ impl Handle {
 
         actor_tasks.spawn(
             async move {
-                let actor = Actor {
+                let mut actor = Actor {
                     msg_receiver: actor_receiver,
                     msg_sender: actor_sender,
                     relay_actor_sender,
@@ -1781,7 +1955,13 @@ impl Handle {
                     net_reporter,
                     network_monitor,
                     net_report_config,
+                    net_report_cache,
+                    net_report_store: None,
                 };
+                // Use the cached report until the first probes are done.
+                if let Some(report) = cached_report {
+                    actor.handle_net_report_report(Some(report)).await;
+                }
 
                 if let Err(err) = actor.run().await {
                     warn!("relay handler errored: {:?}", err);
@@ -1804,6 +1984,15 @@ impl Handle {
         &self.endpoint
     }
 
+    /// Makes the connections switch to new connection IDs.
+    ///
+    /// quinn only rotates the connection IDs when the socket is rebound, so this rebinds
+    /// the endpoint to the same socket.  This rotates the IDs of all connections of the
+    /// endpoint, quinn has no way to rotate those of a single connection.
+    pub(crate) fn rotate_connection_ids(&self) -> io::Result<()> {
+        self.endpoint.rebind_abstract(self.msock.clone())
+    }
+
     /// Closes the connection.
     ///
     /// Only the first close does anything. Any later closes return nil.
@@ -2177,7 +2366,7 @@ enum ActorMessage {
     EndpointPingExpired(usize, stun_rs::TransactionId),
     NetReport(Result<Option<Arc<net_report::Report>>>, &'static str),
     NetworkChange,
-    #[cfg(test)]
+    #[cfg(any(test, feature = "test-utils"))]
     ForceNetworkChange(bool),
 }
 
@@ -2211,6 +2400,11 @@ struct Actor {
     net_reporter: net_report::Client,
 
     network_monitor: netmon::Monitor,
+
+    /// The file caching the last net report.
+    net_report_cache: Option<ReportCache>,
+    /// The write of the last net report to the cache, flushed on shutdown.
+    net_report_store: Option<task::JoinHandle<()>>,
 }
 
 impl Actor {
@@ -2378,6 +2572,9 @@ impl Actor {
                 self.msock.node_map.notify_shutdown();
                 self.port_mapper.deactivate();
                 self.relay_actor_cancel_token.cancel();
+                if let Some(store) = self.net_report_store.take() {
+                    store.await.ok();
+                }
 
                 debug!("shutdown complete");
                 return true;
@@ -2388,6 +2585,20 @@ impl Actor {
             ActorMessage::NetReport(report, why) => {
                 match report {
                     Ok(report) => {
+                        if let (Some(cache), Some(report)) = (&self.net_report_cache, &report) {
+                            let cache = cache.clone();
+                            let report = report.clone();
+                            // Writes one after the other, so the last report is kept.
+                            let previous = self.net_report_store.take();
+                            self.net_report_store = Some(task::spawn(async move {
+                                if let Some(previous) = previous {
+                                    previous.await.ok();
+                                }
+                                if let Err(err) = cache.store(&report).await {
+                                    warn!("{err:#}");
+                                }
+                            }));
+                        }
                         self.handle_net_report_report(report).await;
                     }
                     Err(err) => {
@@ -2402,7 +2613,7 @@ impl Actor {
             ActorMessage::NetworkChange => {
                 self.network_monitor.network_change().await.ok();
             }
-            #[cfg(test)]
+            #[cfg(any(test, feature = "test-utils"))]
             ActorMessage::ForceNetworkChange(is_major) => {
                 self.handle_network_change(is_major).await;
             }
@@ -2432,6 +2643,7 @@ impl Actor {
     /// Updates the [`DiscoveredDirectAddrs`] of this [`MagicSock`] with the current set of
     /// direct addresses from:
     ///
+    /// - The statically configured addresses.
     /// - The portmapper.
     /// - A net_report report.
     /// - The local interfaces IP addresses.
@@ -2443,6 +2655,11 @@ impl Actor {
         // DirectAddr from each entry.
         let mut addrs: BTreeMap<SocketAddr, DirectAddrType> = BTreeMap::new();
 
+        // The configured addresses take precedence over any discovered source.
+        for addr in &self.msock.static_direct_addrs {
+            addrs.entry(*addr).or_insert(DirectAddrType::Static);
+        }
+
         // First add PortMapper provided addresses.
         let maybe_port_mapped = *portmap_watcher.borrow();
         if let Some(portmap_ext) = maybe_port_mapped.map(SocketAddr::V4) {
@@ -2452,6 +2669,12 @@ impl Actor {
             self.set_net_info_have_port_map();
         }
 
+        // Whether the NAT uses a different mapping for each destination, which makes the
+        // STUN addresses unlikely to work for other nodes.
+        let mapping_varies_by_dest_ip = net_report_report
+            .as_ref()
+            .and_then(|report| report.mapping_varies_by_dest_ip);
+
         // Next add STUN addresses from the net_report report.
         if let Some(net_report_report) = net_report_report {
             if let Some(global_v4) = net_report_report.global_v4 {
@@ -2553,6 +2776,11 @@ impl Actor {
                         .map(|(addr, typ)| DirectAddr {
                             addr: *addr,
                             typ: *typ,
+                            reachability: Reachability::estimate(
+                                *addr,
+                                *typ,
+                                mapping_varies_by_dest_ip,
+                            ),
                         })
                         .collect(),
                 );
@@ -2614,7 +2842,8 @@ impl Actor {
             debug!("skipping net_report, socket is shutting down");
             return;
         }
-        if self.msock.relay_map.is_empty() {
+        let relay_map = self.msock.relay_map();
+        if relay_map.is_empty() {
             debug!("skipping net_report, empty RelayMap");
             self.msg_sender
                 .send(ActorMessage::NetReport(Ok(None), why))
@@ -2623,7 +2852,6 @@ impl Actor {
             return;
         }
 
-        let relay_map = self.msock.relay_map.clone();
         let opts = self.net_report_config.clone();
 
         debug!("requesting net_report report");
@@ -2689,9 +2917,16 @@ impl Actor {
                     .insert(format!("{rid}-v6"), d.as_secs_f64());
             }
 
+            // Within a region the weights decide which relay is used.  The report might
+            // also name a relay removed from the map since.
+            let relay_map = self.msock.relay_map();
+            ni.preferred_relay = ni
+                .preferred_relay
+                .and_then(|url| relay_map.select(&url, &self.msock.public_key()));
+
             if ni.preferred_relay.is_none() {
                 // Perhaps UDP is blocked. Pick a deterministic but arbitrary one.
-                ni.preferred_relay = self.pick_relay_fallback();
+                ni.preferred_relay = self.pick_relay_fallback(&relay_map);
             }
 
             if !self.set_nearest_relay(ni.preferred_relay.clone()) {
@@ -2733,7 +2968,7 @@ impl Actor {
     /// latency checks aren't working.
     ///
     /// If no the [`RelayMap`] is empty, returns `0`.
-    fn pick_relay_fallback(&self) -> Option<RelayUrl> {
+    fn pick_relay_fallback(&self, relay_map: &RelayMap) -> Option<RelayUrl> {
         // TODO: figure out which relay node most of our nodes are using,
         // and use that region as our fallback.
         //
@@ -2745,13 +2980,17 @@ impl Actor {
         // We used to do the above for legacy clients, but never updated it for disco.
 
         let my_relay = self.msock.my_relay();
-        if my_relay.is_some() {
+        if my_relay
+            .as_ref()
+            .is_some_and(|url| relay_map.contains_node(url))
+        {
             return my_relay;
         }
 
-        let ids = self.msock.relay_map.urls().collect::<Vec<_>>();
+        let ids = relay_map.urls().collect::<Vec<_>>();
         let mut rng = rand::rngs::StdRng::seed_from_u64(0);
-        ids.choose(&mut rng).map(|c| (*c).clone())
+        ids.choose(&mut rng)
+            .and_then(|url| relay_map.select(url, &self.msock.public_key()))
     }
 
     /// Resets the preferred address for all nodes.
@@ -2807,19 +3046,35 @@ fn new_re_stun_timer(initial_delay: bool) -> time::Interval {
     }
 }
 
-/// Initial connection setup.
+/// Binds the IPv4 and IPv6 sockets to the ports of the selection.
+///
+/// The IPv4 socket prefers the port of its bind address, then the persisted port.  The
+/// IPv6 socket prefers the port after the IPv4 one, or the same port if the ports are
+/// restricted, so both can be opened in a firewall alike.
 fn bind(
     addr_v4: Option<SocketAddrV4>,
     addr_v6: Option<SocketAddrV6>,
+    ports: &PortSelection,
+    persisted_port: Option<u16>,
 ) -> Result<(UdpConn, Option<UdpConn>)> {
     let addr_v4 = addr_v4.unwrap_or_else(|| SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
-    let pconn4 = UdpConn::bind(SocketAddr::V4(addr_v4)).context("bind IPv4 failed")?;
+    let ports4 = ports.candidates([addr_v4.port(), persisted_port.unwrap_or_default()]);
+    let pconn4 = UdpConn::bind(SocketAddr::V4(addr_v4), &ports4).context("bind IPv4 failed")?;
 
     let ip4_port = pconn4.local_addr()?.port();
-    let ip6_port = ip4_port.checked_add(1).unwrap_or(ip4_port - 1);
-    let addr_v6 =
-        addr_v6.unwrap_or_else(|| SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, ip6_port, 0, 0));
-    let pconn6 = match UdpConn::bind(SocketAddr::V6(addr_v6)) {
+    let ip6_port = match ports {
+        PortSelection::Any => ip4_port.checked_add(1).unwrap_or(ip4_port - 1),
+        PortSelection::Port(_) | PortSelection::Range(_) => ip4_port,
+    };
+    let (addr_v6, ip6_port) = match addr_v6 {
+        Some(addr) => (addr, addr.port()),
+        None => (
+            SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, ip6_port, 0, 0),
+            ip6_port,
+        ),
+    };
+    let ports6 = ports.candidates([ip6_port]);
+    let pconn6 = match UdpConn::bind(SocketAddr::V6(addr_v6), &ports6) {
         Ok(conn) => Some(conn),
         Err(err) => {
             info!("bind ignoring IPv6 bind failure: {:?}", err);
@@ -2892,14 +3147,11 @@ impl DiscoveredDirectAddrs {
         }
     }
 
+    /// Creates the call-me-maybe message, with the most reachable addresses first.
     fn to_call_me_maybe_message(&self) -> disco::CallMeMaybe {
-        let my_numbers = self
-            .addrs
-            .get()
-            .unwrap_or_default()
-            .into_iter()
-            .map(|da| da.addr)
-            .collect();
+        let mut addrs: Vec<_> = self.addrs.get().unwrap_or_default().into_iter().collect();
+        addrs.sort_by_key(|da| std::cmp::Reverse(da.reachability));
+        let my_numbers = addrs.into_iter().map(|da| da.addr).collect();
         disco::CallMeMaybe { my_numbers }
     }
 }
@@ -3025,6 +3277,15 @@ pub struct DirectAddr {
     pub addr: SocketAddr,
     /// The origin of this direct address.
     pub typ: DirectAddrType,
+    /// How likely other nodes can reach this direct address.
+    reachability: Reachability,
+}
+
+impl DirectAddr {
+    /// How likely other nodes can reach this direct address.
+    pub fn reachability(&self) -> Reachability {
+        self.reachability
+    }
 }
 
 /// The type of direct address.
@@ -3032,6 +3293,7 @@ pub struct DirectAddr {
 /// These are the various sources or origins from which an iroh node might have found a
 /// possible [`DirectAddr`].
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[non_exhaustive]
 pub enum DirectAddrType {
     /// Not yet determined..
     Unknown,
@@ -3054,6 +3316,10 @@ pub enum DirectAddrType {
     /// configure the router to forward this port to the iroh node.  This indicates a
     /// situation like this, which still uses STUN to discover the public address.
     Stun4LocalPort,
+    /// An address configured using [`Builder::static_direct_addrs`].
+    ///
+    /// [`Builder::static_direct_addrs`]: crate::endpoint::Builder::static_direct_addrs
+    Static,
 }
 
 impl Display for DirectAddrType {
@@ -3064,6 +3330,80 @@ impl Display for DirectAddrType {
             DirectAddrType::Stun => write!(f, "stun"),
             DirectAddrType::Portmapped => write!(f, "portmap"),
             DirectAddrType::Stun4LocalPort => write!(f, "stun4localport"),
+            DirectAddrType::Static => write!(f, "static"),
+        }
+    }
+}
+
+/// The confidence that other nodes can reach a [`DirectAddr`].
+///
+/// This is an estimate from the [`DirectAddrType`] and the address itself, it is not
+/// verified by any probing.  Ordered from the least to the most confident.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum Reachability {
+    /// Only reachable from the local network, if at all.
+    ///
+    /// Private and link-local addresses, and addresses discovered while behind a NAT
+    /// mapping each destination differently.
+    Low,
+    /// Reachable if no firewall blocks it, or after hole punching.
+    ///
+    /// Public addresses bound locally and STUN'ed addresses of an endpoint independent NAT.
+    Medium,
+    /// Reachable from the internet.
+    ///
+    /// Port mapped and statically configured addresses.
+    High,
+}
+
+impl Reachability {
+    fn estimate(
+        addr: SocketAddr,
+        typ: DirectAddrType,
+        mapping_varies_by_dest_ip: Option<bool>,
+    ) -> Self {
+        match typ {
+            DirectAddrType::Static | DirectAddrType::Portmapped => Self::High,
+            DirectAddrType::Stun => match mapping_varies_by_dest_ip {
+                Some(true) => Self::Low,
+                Some(false) | None => Self::Medium,
+            },
+            DirectAddrType::Local if is_public_ip(addr.ip()) => Self::Medium,
+            DirectAddrType::Local | DirectAddrType::Stun4LocalPort | DirectAddrType::Unknown => {
+                Self::Low
+            }
+        }
+    }
+}
+
+impl Display for Reachability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reachability::Low => write!(f, "low"),
+            Reachability::Medium => write!(f, "medium"),
+            Reachability::High => write!(f, "high"),
+        }
+    }
+}
+
+/// Whether the IP address might be reachable from the internet.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                // Shared address space for carrier grade NAT, 100.64.0.0/10.
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || is_unicast_link_local(ip)
+                // Unique local addresses, fc00::/7.
+                || ip.segments()[0] & 0xfe00 == 0xfc00)
         }
     }
 }
@@ -3501,6 +3841,38 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_offline() -> testresult::TestResult {
+        let m1 = MagicStack::new(RelayMode::Disabled).await?;
+        let m2 = MagicStack::new(RelayMode::Disabled).await?;
+        let _guard = mesh_stacks(vec![m1.clone(), m2.clone()]).await?;
+        let _handle = AbortOnDropHandle::new(tokio::spawn({
+            let endpoint = m2.endpoint.clone();
+            async move {
+                while let Some(incoming) = endpoint.accept().await {
+                    if let Ok(conn) = incoming.await {
+                        conn.closed().await;
+                    }
+                }
+            }
+        }));
+        let addr = m2.endpoint.node_addr().await?;
+
+        // Nothing gets through without network.
+        m1.endpoint.set_offline(true).await;
+        let connect = m1.endpoint.connect(addr.clone(), ALPN);
+        assert!(time::timeout(Duration::from_secs(2), connect)
+            .await
+            .is_err());
+
+        m1.endpoint.set_offline(false).await;
+        let conn =
+            time::timeout(Duration::from_secs(10), m1.endpoint.connect(addr, ALPN)).await??;
+        conn.close(0u32.into(), b"done");
+        Ok(())
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     #[traced_test]
     async fn test_two_devices_roundtrip_network_change() -> Result<()> {
@@ -3653,7 +4025,7 @@ mod tests {
             )?;
 
             let quic_client_config =
-                tls::make_client_config(&key, None, vec![ALPN.to_vec()], false)?;
+                tls::make_client_config(&key, None, vec![ALPN.to_vec()], None, false)?;
             let mut client_config = quinn::ClientConfig::new(Arc::new(quic_client_config));
             let mut transport_config = quinn::TransportConfig::default();
             transport_config.max_idle_timeout(Some(Duration::from_secs(10).try_into().unwrap()));
@@ -3785,7 +4157,7 @@ mod tests {
     async fn test_two_devices_roundtrip_quinn_rebinding_conn() -> Result<()> {
         fn make_conn(addr: SocketAddr) -> anyhow::Result<quinn::Endpoint> {
             let key = SecretKey::generate(rand::thread_rng());
-            let conn = UdpConn::bind(addr)?;
+            let conn = UdpConn::bind(addr, &[addr.port()])?;
 
             let quic_server_config = tls::make_server_config(&key, vec![ALPN.to_vec()], false)?;
             let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(quic_server_config));
@@ -3801,7 +4173,7 @@ mod tests {
             )?;
 
             let quic_client_config =
-                tls::make_client_config(&key, None, vec![ALPN.to_vec()], false)?;
+                tls::make_client_config(&key, None, vec![ALPN.to_vec()], None, false)?;
             let mut client_config = quinn::ClientConfig::new(Arc::new(quic_client_config));
             let mut transport_config = quinn::TransportConfig::default();
             transport_config.max_idle_timeout(Some(Duration::from_secs(10).try_into().unwrap()));
@@ -3988,6 +4360,93 @@ mod tests {
         assert_eq!(eps0, eps1);
     }
 
+    #[test]
+    fn test_call_me_maybe_order() {
+        let direct_addrs = DiscoveredDirectAddrs::default();
+        let addr = |addr: &str, typ, reachability| DirectAddr {
+            addr: addr.parse().unwrap(),
+            typ,
+            reachability,
+        };
+        direct_addrs.update(BTreeSet::from([
+            addr("10.0.0.1:1234", DirectAddrType::Local, Reachability::Low),
+            addr(
+                "198.51.100.1:1234",
+                DirectAddrType::Stun,
+                Reachability::Medium,
+            ),
+            addr(
+                "203.0.113.7:4433",
+                DirectAddrType::Static,
+                Reachability::High,
+            ),
+        ]));
+        let msg = direct_addrs.to_call_me_maybe_message();
+        assert_eq!(
+            msg.my_numbers,
+            vec![
+                "203.0.113.7:4433".parse().unwrap(),
+                "198.51.100.1:1234".parse().unwrap(),
+                "10.0.0.1:1234".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reachability_estimate() {
+        let estimate = |addr: &str, typ, mapping_varies| {
+            Reachability::estimate(addr.parse().unwrap(), typ, mapping_varies)
+        };
+        assert_eq!(
+            estimate("192.168.1.2:1234", DirectAddrType::Local, None),
+            Reachability::Low
+        );
+        assert_eq!(
+            estimate("100.100.1.2:1234", DirectAddrType::Local, None),
+            Reachability::Low
+        );
+        assert_eq!(
+            estimate("[fd00::1]:1234", DirectAddrType::Local, None),
+            Reachability::Low
+        );
+        assert_eq!(
+            estimate("[fe80::1]:1234", DirectAddrType::Local, None),
+            Reachability::Low
+        );
+        assert_eq!(
+            estimate("203.0.113.7:1234", DirectAddrType::Local, None),
+            Reachability::Medium
+        );
+        assert_eq!(
+            estimate("[2001:db8::1]:1234", DirectAddrType::Local, None),
+            Reachability::Medium
+        );
+        assert_eq!(
+            estimate("203.0.113.7:1234", DirectAddrType::Stun, Some(false)),
+            Reachability::Medium
+        );
+        assert_eq!(
+            estimate("203.0.113.7:1234", DirectAddrType::Stun, Some(true)),
+            Reachability::Low
+        );
+        assert_eq!(
+            estimate(
+                "203.0.113.7:1234",
+                DirectAddrType::Stun4LocalPort,
+                Some(true)
+            ),
+            Reachability::Low
+        );
+        assert_eq!(
+            estimate("203.0.113.7:1234", DirectAddrType::Portmapped, None),
+            Reachability::High
+        );
+        assert_eq!(
+            estimate("192.168.1.2:1234", DirectAddrType::Static, None),
+            Reachability::High
+        );
+    }
+
     #[tokio::test]
     async fn test_watch_home_relay() {
         // use an empty relay map to get full control of the changes during the test
@@ -4036,13 +4495,19 @@ mod tests {
         let opts = Options {
             addr_v4: None,
             addr_v6: None,
+            port_selection: PortSelection::default(),
+            port_state: None,
+            net_report_cache: None,
             secret_key: secret_key.clone(),
             relay_map: RelayMap::empty(),
             node_map: None,
             discovery: None,
             dns_resolver,
             proxy_url: None,
+            static_direct_addrs: Vec::new(),
+            assume_reachable: false,
             server_config,
+            connection_ids: Default::default(),
             insecure_skip_relay_cert_verify: true,
             path_selection: PathSelection::default(),
         };
@@ -4089,7 +4554,7 @@ mod tests {
     ) -> Result<quinn::Connection> {
         let alpns = vec![ALPN.to_vec()];
         let quic_client_config =
-            tls::make_client_config(&ep_secret_key, Some(node_id), alpns, true)?;
+            tls::make_client_config(&ep_secret_key, Some(node_id), alpns, None, true)?;
         let mut client_config = quinn::ClientConfig::new(Arc::new(quic_client_config));
         client_config.transport_config(transport_config);
         let connect = ep.connect_with(client_config, mapped_addr.socket_addr(), "localhost")?;
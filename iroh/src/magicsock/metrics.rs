@@ -21,6 +21,8 @@ pub struct Metrics {
     pub send_data: Counter,
     pub send_data_network_down: Counter,
     pub recv_data_relay: Counter,
+    /// Number of packets received from a relay but dropped because the receive queue was full.
+    pub recv_data_relay_dropped: Counter,
     pub recv_data_ipv4: Counter,
     pub recv_data_ipv6: Counter,
     /// Number of QUIC datagrams received.
@@ -34,6 +36,8 @@ pub struct Metrics {
     pub sent_disco_udp: Counter,
     pub sent_disco_relay: Counter,
     pub sent_disco_ping: Counter,
+    /// Number of hole punching pings skipped as other nodes are assumed to reach us.
+    pub skipped_disco_ping: Counter,
     pub sent_disco_pong: Counter,
     pub sent_disco_call_me_maybe: Counter,
     pub recv_disco_bad_key: Counter,
@@ -79,6 +83,15 @@ pub struct Metrics {
     pub connection_handshake_success: Counter,
     /// Number of connections with a successful handshake that became direct.
     pub connection_became_direct: Counter,
+
+    /// Number of discovery resolutions for dials waiting on them.
+    pub discovery_interactive: Counter,
+    /// Number of discovery resolutions for nodes with known addresses.
+    pub discovery_background: Counter,
+    /// Number of discovery resolutions which waited for others to finish.
+    pub discovery_queued: Counter,
+    /// Total time discovery resolutions waited for others to finish, in milliseconds.
+    pub discovery_queue_wait_ms: Counter,
 }
 
 impl Default for Metrics {
@@ -100,6 +113,7 @@ impl Default for Metrics {
             send_data: Counter::new("send_data"),
             send_data_network_down: Counter::new("send_data_network_down"),
             recv_data_relay: Counter::new("recv_data_relay"),
+            recv_data_relay_dropped: Counter::new("recv_data_relay_dropped"),
             recv_data_ipv4: Counter::new("recv_data_ipv4"),
             recv_data_ipv6: Counter::new("recv_data_ipv6"),
             recv_datagrams: Counter::new("recv_datagrams"),
@@ -111,6 +125,7 @@ impl Default for Metrics {
             sent_disco_udp: Counter::new("disco_sent_udp"),
             sent_disco_relay: Counter::new("disco_sent_relay"),
             sent_disco_ping: Counter::new("disco_sent_ping"),
+            skipped_disco_ping: Counter::new("disco_skipped_ping"),
             sent_disco_pong: Counter::new("disco_sent_pong"),
             sent_disco_call_me_maybe: Counter::new("disco_sent_callmemaybe"),
             recv_disco_bad_key: Counter::new("disco_recv_bad_key"),
@@ -149,6 +164,11 @@ impl Default for Metrics {
 
             connection_handshake_success: Counter::new("connection_handshake_success"),
             connection_became_direct: Counter::new("connection_became_direct"),
+
+            discovery_interactive: Counter::new("discovery_interactive"),
+            discovery_background: Counter::new("discovery_background"),
+            discovery_queued: Counter::new("discovery_queued"),
+            discovery_queue_wait_ms: Counter::new("discovery_queue_wait_ms"),
         }
     }
 }
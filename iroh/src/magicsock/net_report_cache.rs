@@ -0,0 +1,208 @@
+//! Persistence of the last net report across restarts.
+//!
+//! A net report takes a probe cycle of up to a few seconds, during which the endpoint has
+//! no home relay.  Short-lived processes pay this on every start, so the last report can
+//! be cached in a file and used right away, if it is fresh enough.  The network is still
+//! probed in the background, and the cache updated with the new report.
+
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use iroh_base::RelayUrl;
+use net_report::Report;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use super::port_selection::write_atomic;
+
+/// A file caching the last net report.
+#[derive(Debug, Clone)]
+pub(crate) struct ReportCache {
+    path: PathBuf,
+    /// The age after which a cached report is not used anymore.
+    max_age: Duration,
+}
+
+/// The part of a [`Report`] worth caching.
+///
+/// The addresses discovered by STUN are not cached, they depend on the NAT mapping of the
+/// bound socket.
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedReport {
+    /// When the report was created, in seconds since the unix epoch.
+    created: u64,
+    udp: bool,
+    ipv4: bool,
+    ipv6: bool,
+    ipv4_can_send: bool,
+    ipv6_can_send: bool,
+    os_has_ipv6: bool,
+    mapping_varies_by_dest_ip: Option<bool>,
+    mapping_varies_by_dest_ipv6: Option<bool>,
+    hair_pinning: Option<bool>,
+    captive_portal: Option<bool>,
+    preferred_relay: Option<RelayUrl>,
+    relay_latency: Vec<(RelayUrl, Duration)>,
+    relay_v4_latency: Vec<(RelayUrl, Duration)>,
+    relay_v6_latency: Vec<(RelayUrl, Duration)>,
+}
+
+impl CachedReport {
+    fn new(report: &Report, created: SystemTime) -> Self {
+        let latencies = |l: &net_report::RelayLatencies| {
+            l.iter()
+                .map(|(url, latency)| (url.clone(), latency))
+                .collect()
+        };
+        Self {
+            created: created
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            udp: report.udp,
+            ipv4: report.ipv4,
+            ipv6: report.ipv6,
+            ipv4_can_send: report.ipv4_can_send,
+            ipv6_can_send: report.ipv6_can_send,
+            os_has_ipv6: report.os_has_ipv6,
+            mapping_varies_by_dest_ip: report.mapping_varies_by_dest_ip,
+            mapping_varies_by_dest_ipv6: report.mapping_varies_by_dest_ipv6,
+            hair_pinning: report.hair_pinning,
+            captive_portal: report.captive_portal,
+            preferred_relay: report.preferred_relay.clone(),
+            relay_latency: latencies(&report.relay_latency),
+            relay_v4_latency: latencies(&report.relay_v4_latency),
+            relay_v6_latency: latencies(&report.relay_v6_latency),
+        }
+    }
+
+    /// The age of the report, `None` if it was created in the future.
+    fn age(&self, now: SystemTime) -> Option<Duration> {
+        let created = UNIX_EPOCH + Duration::from_secs(self.created);
+        now.duration_since(created).ok()
+    }
+
+    fn into_report(self) -> Report {
+        Report {
+            udp: self.udp,
+            ipv4: self.ipv4,
+            ipv6: self.ipv6,
+            ipv4_can_send: self.ipv4_can_send,
+            ipv6_can_send: self.ipv6_can_send,
+            os_has_ipv6: self.os_has_ipv6,
+            mapping_varies_by_dest_ip: self.mapping_varies_by_dest_ip,
+            mapping_varies_by_dest_ipv6: self.mapping_varies_by_dest_ipv6,
+            hair_pinning: self.hair_pinning,
+            captive_portal: self.captive_portal,
+            preferred_relay: self.preferred_relay,
+            relay_latency: self.relay_latency.into_iter().collect(),
+            relay_v4_latency: self.relay_v4_latency.into_iter().collect(),
+            relay_v6_latency: self.relay_v6_latency.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl ReportCache {
+    pub(crate) fn new(path: PathBuf, max_age: Duration) -> Self {
+        Self { path, max_age }
+    }
+
+    /// Reads the cached report, if it is fresh enough.
+    ///
+    /// An unreadable cache is ignored: it is overwritten with the next report.
+    pub(super) async fn load(&self) -> Option<Arc<Report>> {
+        let content = match tokio::fs::read(&self.path).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(err) => {
+                warn!(path = %self.path.display(), "failed to read cached net report: {err}");
+                return None;
+            }
+        };
+        let cached: CachedReport = match serde_json::from_slice(&content) {
+            Ok(cached) => cached,
+            Err(err) => {
+                warn!(path = %self.path.display(), "invalid cached net report: {err}");
+                return None;
+            }
+        };
+        match cached.age(SystemTime::now()) {
+            Some(age) if age <= self.max_age => {
+                debug!(path = %self.path.display(), ?age, "using cached net report");
+                Some(Arc::new(cached.into_report()))
+            }
+            age => {
+                debug!(path = %self.path.display(), ?age, "cached net report is stale");
+                None
+            }
+        }
+    }
+
+    /// Caches the report.
+    pub(super) async fn store(&self, report: &Report) -> Result<()> {
+        let cached = CachedReport::new(report, SystemTime::now());
+        let content = serde_json::to_vec(&cached)?;
+        if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        write_atomic(&self.path, &content)
+            .await
+            .with_context(|| format!("failed to cache net report to {}", self.path.display()))?;
+        debug!(path = %self.path.display(), "cached net report");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_report_cache() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("iroh-net-report-{}", rand::random::<u64>()));
+        let path = dir.join("net-report.json");
+        let cache = ReportCache::new(path.clone(), Duration::from_secs(60));
+        assert!(cache.load().await.is_none());
+
+        let url: RelayUrl = "https://relay.example.com".parse()?;
+        let report = Report {
+            udp: true,
+            ipv4: true,
+            mapping_varies_by_dest_ip: Some(true),
+            preferred_relay: Some(url.clone()),
+            relay_latency: [(url.clone(), Duration::from_millis(20))]
+                .into_iter()
+                .collect(),
+            global_v4: Some("1.2.3.4:5678".parse()?),
+            ..Default::default()
+        };
+        cache.store(&report).await?;
+        let cached = cache.load().await.context("no cached report")?;
+        // The STUN addresses are not cached.
+        assert_eq!(
+            *cached,
+            Report {
+                global_v4: None,
+                ..report.clone()
+            }
+        );
+
+        // Stale and future reports are not used.
+        let mut stale = CachedReport::new(&report, SystemTime::now() - Duration::from_secs(120));
+        tokio::fs::write(&path, serde_json::to_vec(&stale)?).await?;
+        assert!(cache.load().await.is_none());
+        stale.created += 3600;
+        tokio::fs::write(&path, serde_json::to_vec(&stale)?).await?;
+        assert!(cache.load().await.is_none());
+
+        tokio::fs::write(&path, "garbage").await?;
+        assert!(cache.load().await.is_none());
+        tokio::fs::remove_dir_all(dir).await?;
+        Ok(())
+    }
+}
@@ -5,17 +5,13 @@ use std::{
     sync::Mutex,
 };
 
-use iroh_base::{NodeAddr, NodeId, PublicKey, RelayUrl};
-use iroh_metrics::inc;
+use iroh_base::{inc, NodeAddr, NodeId, PublicKey, RelayUrl};
 use n0_future::time::Instant;
 use serde::{Deserialize, Serialize};
 use stun_rs::TransactionId;
 use tracing::{debug, info, instrument, trace, warn};
 
-use self::{
-    best_addr::ClearReason,
-    node_state::{NodeState, Options, PingHandled},
-};
+use self::node_state::{NodeState, Options, PingHandled};
 use super::{
     metrics::Metrics as MagicsockMetrics, ActorMessage, DiscoMessageSource, NodeIdMappedAddr,
 };
@@ -28,11 +24,17 @@ use crate::{
 
 mod best_addr;
 mod node_state;
+mod path_info;
 mod path_state;
 mod udp_paths;
 
+pub use best_addr::ClearReason;
 pub use node_state::{ConnectionType, ControlMsg, DirectAddrInfo, RemoteInfo};
 pub(super) use node_state::{DiscoPingPurpose, PingAction, PingRole, SendPing};
+pub use path_info::{
+    PathCandidate, PathInfo, PathTimers, PathTransition, SelectedPath, TransitionReason,
+    ValidationStatus,
+};
 
 /// Number of nodes that are inactive for which we keep info about. This limit is enforced
 /// periodically via [`NodeMap::prune_inactive`].
@@ -212,6 +214,14 @@ impl NodeMap {
             .map(|ep| *ep.quic_mapped_addr())
     }
 
+    pub(super) fn node_id_for_quic_mapped_addr(&self, addr: NodeIdMappedAddr) -> Option<NodeId> {
+        self.inner
+            .lock()
+            .expect("poisoned")
+            .get(NodeStateKey::NodeIdMappedAddr(addr))
+            .map(|ep| *ep.public_key())
+    }
+
     /// Insert a received ping into the node map, and return whether a ping with this tx_id was already
     /// received.
     pub(super) fn handle_ping(
@@ -314,6 +324,36 @@ impl NodeMap {
         self.inner.lock().expect("poisoned").remote_info(node_id)
     }
 
+    /// Returns the state of the path selection towards the node.
+    pub(super) fn path_info(&self, node_id: NodeId) -> Option<PathInfo> {
+        self.inner
+            .lock()
+            .expect("poisoned")
+            .get(NodeStateKey::NodeId(node_id))
+            .map(|ns| ns.path_info(Instant::now()))
+    }
+
+    /// Returns a [`Watcher`] for the changes of the paths selected towards the node.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if there is not an entry in the [`NodeMap`] for
+    /// the `node_id`
+    pub(super) fn path_transitions(
+        &self,
+        node_id: NodeId,
+    ) -> anyhow::Result<Watcher<Option<PathTransition>>> {
+        match self
+            .inner
+            .lock()
+            .expect("poisoned")
+            .get(NodeStateKey::NodeId(node_id))
+        {
+            Some(ns) => Ok(ns.path_transitions()),
+            None => anyhow::bail!("No endpoint for {node_id:?} found"),
+        }
+    }
+
     /// Prunes nodes without recent activity so that at most [`MAX_INACTIVE_NODES`] are kept.
     pub(super) fn prune_inactive(&self) {
         self.inner.lock().expect("poisoned").prune_inactive();
@@ -9,7 +9,11 @@ use tracing::{debug, info};
 const TRUST_UDP_ADDR_DURATION: Duration = Duration::from_millis(6500);
 
 #[derive(Debug, Default)]
-pub(super) struct BestAddr(Option<BestAddrInner>);
+pub(super) struct BestAddr {
+    inner: Option<BestAddrInner>,
+    /// The last change of the best address, until taken by [`BestAddr::take_change`].
+    change: Option<Change>,
+}
 
 #[derive(Debug)]
 struct BestAddrInner {
@@ -55,14 +59,31 @@ pub(super) enum State<'a> {
     Empty,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// The reason a direct path stopped being used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ClearReason {
+    /// The state of the node was reset.
     Reset,
+    /// The path was pruned for not being used for a while.
     Inactive,
+    /// A ping sent on the path was not answered in time.
     PongTimeout,
+    /// The address of the path turned out to be one of our own local addresses.
     MatchesOurLocalAddr,
 }
 
+/// A change of the best address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Change {
+    /// A new address was selected, `candidate` if it was not confirmed by a pong but chosen
+    /// among the candidates.
+    Selected { candidate: bool },
+    /// The address was cleared.
+    Cleared(ClearReason),
+    /// The trust in the address was revoked.
+    TrustCleared,
+}
+
 impl BestAddr {
     #[cfg(test)]
     pub fn from_parts(
@@ -76,18 +97,22 @@ impl BestAddr {
             confirmed_at,
             trust_until: Some(trust_until),
         };
-        Self(Some(inner))
+        Self {
+            inner: Some(inner),
+            change: None,
+        }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.0.is_none()
+        self.inner.is_none()
     }
 
     /// Unconditionally clears the best address.
     pub fn clear(&mut self, reason: ClearReason, has_relay: bool) {
-        let old = self.0.take();
+        let old = self.inner.take();
         if let Some(old_addr) = old.as_ref().map(BestAddrInner::addr) {
             info!(?reason, ?has_relay, %old_addr, "clearing best_addr");
+            self.change = Some(Change::Cleared(reason));
         }
     }
 
@@ -99,13 +124,14 @@ impl BestAddr {
     }
 
     pub fn clear_trust(&mut self, why: &'static str) {
-        if let Some(state) = self.0.as_mut() {
+        if let Some(state) = self.inner.as_mut() {
             info!(
                 %why,
                 prev_trust_until = ?state.trust_until,
                 "clearing best_addr trust",
             );
             state.trust_until = None;
+            self.change = Some(Change::TrustCleared);
         }
     }
 
@@ -116,7 +142,7 @@ impl BestAddr {
         source: Source,
         confirmed_at: Instant,
     ) {
-        match self.0.as_mut() {
+        match self.inner.as_mut() {
             None => {
                 self.insert(addr, latency, source, confirmed_at);
             }
@@ -134,7 +160,7 @@ impl BestAddr {
 
     /// Reset the expiry, if the passed in addr matches the currently used one.
     pub fn reconfirm_if_used(&mut self, addr: SocketAddr, source: Source, confirmed_at: Instant) {
-        if let Some(state) = self.0.as_mut() {
+        if let Some(state) = self.inner.as_mut() {
             if state.addr.addr == addr {
                 state.confirmed_at = confirmed_at;
                 state.trust_until = Some(source.trust_until(confirmed_at));
@@ -152,7 +178,7 @@ impl BestAddr {
         let trust_until = source.trust_until(confirmed_at);
 
         if self
-            .0
+            .inner
             .as_ref()
             .map(|prev| prev.addr.addr == addr)
             .unwrap_or_default()
@@ -170,17 +196,20 @@ impl BestAddr {
                trust_for = ?trust_until.duration_since(Instant::now()),
                "selecting new direct path for node"
             );
+            self.change = Some(Change::Selected {
+                candidate: matches!(source, Source::BestCandidate),
+            });
         }
         let inner = BestAddrInner {
             addr: AddrLatency { addr, latency },
             trust_until: Some(trust_until),
             confirmed_at,
         };
-        self.0 = Some(inner);
+        self.inner = Some(inner);
     }
 
     pub fn state(&self, now: Instant) -> State {
-        match &self.0 {
+        match &self.inner {
             None => State::Empty,
             Some(state) => match state.trust_until {
                 Some(expiry) if now < expiry => State::Valid(&state.addr),
@@ -190,7 +219,21 @@ impl BestAddr {
     }
 
     pub fn addr(&self) -> Option<SocketAddr> {
-        self.0.as_ref().map(BestAddrInner::addr)
+        self.inner.as_ref().map(BestAddrInner::addr)
+    }
+
+    /// Returns how long the address is still trusted, `None` if it is not.
+    pub fn trusted_for(&self, now: Instant) -> Option<Duration> {
+        self.inner
+            .as_ref()
+            .and_then(|state| state.trust_until)
+            .and_then(|trust_until| trust_until.checked_duration_since(now))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Returns the last change of the address since this was last called.
+    pub fn take_change(&mut self) -> Option<Change> {
+        self.change.take()
     }
 }
 
@@ -5,8 +5,7 @@ use std::{
 };
 
 use data_encoding::HEXLOWER;
-use iroh_base::{NodeAddr, NodeId, PublicKey, RelayUrl};
-use iroh_metrics::inc;
+use iroh_base::{inc, NodeAddr, NodeId, PublicKey, RelayUrl};
 use iroh_relay::protos::stun;
 use n0_future::{
     task::{self, AbortOnDropHandle},
@@ -19,6 +18,10 @@ use tracing::{debug, event, info, instrument, trace, warn, Level};
 
 use super::{
     best_addr::{self, ClearReason, Source as BestAddrSource},
+    path_info::{
+        PathCandidate, PathInfo, PathTimers, PathTransition, SelectedPath, TransitionReason,
+        ValidationStatus,
+    },
     path_state::{summarize_node_paths, PathState},
     udp_paths::{NodeUdpPaths, UdpSendAddr},
     IpPort, Source,
@@ -140,6 +143,11 @@ pub(super) struct NodeState {
     ///
     /// Used for metric reporting.
     has_been_direct: bool,
+    /// The paths currently used to send to the node.
+    selected_path: SelectedPath,
+    /// The last change of the [`NodeState::selected_path`], and when it happened.
+    path_transition: Watchable<Option<PathTransition>>,
+    last_transition_at: Option<Instant>,
     /// Configuration for what path selection to use
     #[cfg(any(test, feature = "test-utils"))]
     path_selection: PathSelection,
@@ -185,6 +193,9 @@ impl NodeState {
             last_call_me_maybe: None,
             conn_type: Watchable::new(ConnectionType::None),
             has_been_direct: false,
+            selected_path: SelectedPath::None,
+            path_transition: Watchable::new(None),
+            last_transition_at: None,
             #[cfg(any(test, feature = "test-utils"))]
             path_selection: options.path_selection,
         }
@@ -206,6 +217,97 @@ impl NodeState {
         self.conn_type.watch()
     }
 
+    pub(super) fn path_transitions(&self) -> Watcher<Option<PathTransition>> {
+        self.path_transition.watch()
+    }
+
+    /// Returns the state of the path selection towards this node.
+    pub(super) fn path_info(&self, now: Instant) -> PathInfo {
+        let elapsed = |instant: Instant| now.duration_since(instant);
+        let candidates = self
+            .udp_paths
+            .paths
+            .iter()
+            .map(|(ipp, state)| {
+                let addr = SocketAddr::from(*ipp);
+                let pending = self
+                    .sent_pings
+                    .values()
+                    .any(|ping| ping.to == SendAddr::Udp(addr));
+                let status = if state.recent_pong.is_some() {
+                    ValidationStatus::Validated
+                } else if pending {
+                    ValidationStatus::Pending
+                } else if state.failed_pings > 0 {
+                    ValidationStatus::Unreachable
+                } else {
+                    ValidationStatus::Untested
+                };
+                PathCandidate {
+                    addr,
+                    status,
+                    latency: state.latency(),
+                    last_ping: state.last_ping.map(elapsed),
+                    last_pong: state.recent_pong.as_ref().map(|pong| elapsed(pong.pong_at)),
+                }
+            })
+            .collect();
+        PathInfo {
+            node_id: self.node_id,
+            selected: self.selected_path.clone(),
+            last_transition: self.path_transition.get(),
+            candidates,
+            relay_url: self.relay_url(),
+            timers: PathTimers {
+                trusted_for: self.udp_paths.best_addr.trusted_for(now),
+                since_transition: self.last_transition_at.map(elapsed),
+                since_full_ping: self.last_full_ping.map(elapsed),
+                since_call_me_maybe: self.last_call_me_maybe.map(elapsed),
+            },
+        }
+    }
+
+    /// Records the paths selected to send to the node, notifying a change.
+    fn set_selected_path(&mut self, selected: SelectedPath, now: Instant) {
+        if selected == self.selected_path {
+            return;
+        }
+        let change = self.udp_paths.best_addr.take_change();
+        let reason = match (&selected, change) {
+            (SelectedPath::Direct(_), Some(best_addr::Change::Selected { candidate: true })) => {
+                TransitionReason::BestCandidate
+            }
+            (SelectedPath::Direct(_), _) => TransitionReason::PongReceived,
+            (SelectedPath::Outdated(..), Some(best_addr::Change::TrustCleared)) => {
+                TransitionReason::ConnectivityChange
+            }
+            (SelectedPath::Outdated(..), _) => TransitionReason::TrustExpired,
+            (_, Some(best_addr::Change::Cleared(reason))) => TransitionReason::PathCleared(reason),
+            (SelectedPath::Unconfirmed(..), _) => TransitionReason::NewCandidate,
+            (SelectedPath::Relay(_) | SelectedPath::None, _) => match self.selected_path {
+                SelectedPath::Relay(_) => TransitionReason::RelayChanged,
+                _ => TransitionReason::NoDirectPath,
+            },
+        };
+        let from = std::mem::replace(&mut self.selected_path, selected.clone());
+        event!(
+            target: "iroh::_events::path::changed",
+            Level::DEBUG,
+            remote_node = %self.node_id.fmt_short(),
+            ?from,
+            to = ?selected,
+            ?reason,
+        );
+        self.last_transition_at = Some(now);
+        self.path_transition
+            .set(Some(PathTransition {
+                from,
+                to: selected,
+                reason,
+            }))
+            .ok();
+    }
+
     /// Returns info about this node.
     pub(super) fn info(&self, now: Instant) -> RemoteInfo {
         let conn_type = self.conn_type.get();
@@ -287,28 +389,37 @@ impl NodeState {
             debug!("in `RelayOnly` mode, giving the relay address as the only viable address for this endpoint");
             return (None, self.relay_url());
         }
-        let (best_addr, relay_url) = match self.udp_paths.send_addr(*now, have_ipv6) {
+        let (best_addr, relay_url, selected) = match self.udp_paths.send_addr(*now, have_ipv6) {
             UdpSendAddr::Valid(addr) => {
                 // If we have a valid address we use it.
                 trace!(%addr, "UdpSendAddr is valid, use it");
-                (Some(addr), None)
+                (Some(addr), None, SelectedPath::Direct(addr))
             }
             UdpSendAddr::Outdated(addr) => {
                 // If the address is outdated we use it, but send via relay at the same time.
                 // We also send disco pings so that it will become valid again if it still
                 // works (i.e. we don't need to holepunch again).
                 trace!(%addr, "UdpSendAddr is outdated, use it together with relay");
-                (Some(addr), self.relay_url())
+                let relay_url = self.relay_url();
+                let selected = SelectedPath::Outdated(addr, relay_url.clone());
+                (Some(addr), relay_url, selected)
             }
             UdpSendAddr::Unconfirmed(addr) => {
                 trace!(%addr, "UdpSendAddr is unconfirmed, use it together with relay");
-                (Some(addr), self.relay_url())
+                let relay_url = self.relay_url();
+                let selected = SelectedPath::Unconfirmed(addr, relay_url.clone());
+                (Some(addr), relay_url, selected)
             }
             UdpSendAddr::None => {
                 trace!("No UdpSendAddr, use relay");
-                (None, self.relay_url())
+                let relay_url = self.relay_url();
+                let selected = relay_url
+                    .clone()
+                    .map_or(SelectedPath::None, SelectedPath::Relay);
+                (None, relay_url, selected)
             }
         };
+        self.set_selected_path(selected, *now);
         let typ = match (best_addr, relay_url.clone()) {
             (Some(best_addr), Some(relay_url)) => ConnectionType::Mixed(best_addr, relay_url),
             (Some(best_addr), None) => ConnectionType::Direct(best_addr),
@@ -438,6 +549,7 @@ impl NodeState {
                             // pong.  Both are used to select this path again, but we know
                             // it's not a usable path now.
                             path_state.recent_pong = None;
+                            path_state.failed_pings += 1;
                             self.udp_paths.best_addr.clear_if_equals(
                                 addr,
                                 ClearReason::PongTimeout,
@@ -1507,6 +1619,9 @@ mod tests {
                     last_call_me_maybe: None,
                     conn_type: Watchable::new(ConnectionType::Direct(ip_port.into())),
                     has_been_direct: true,
+                    selected_path: SelectedPath::None,
+                    path_transition: Watchable::new(None),
+                    last_transition_at: None,
                     #[cfg(any(test, feature = "test-utils"))]
                     path_selection: PathSelection::default(),
                 },
@@ -1529,6 +1644,9 @@ mod tests {
                 last_call_me_maybe: None,
                 conn_type: Watchable::new(ConnectionType::Relay(send_addr.clone())),
                 has_been_direct: false,
+                selected_path: SelectedPath::None,
+                path_transition: Watchable::new(None),
+                last_transition_at: None,
                 #[cfg(any(test, feature = "test-utils"))]
                 path_selection: PathSelection::default(),
             }
@@ -1558,6 +1676,9 @@ mod tests {
                 last_call_me_maybe: None,
                 conn_type: Watchable::new(ConnectionType::Relay(send_addr.clone())),
                 has_been_direct: false,
+                selected_path: SelectedPath::None,
+                path_transition: Watchable::new(None),
+                last_transition_at: None,
                 #[cfg(any(test, feature = "test-utils"))]
                 path_selection: PathSelection::default(),
             }
@@ -1600,6 +1721,9 @@ mod tests {
                         send_addr.clone(),
                     )),
                     has_been_direct: false,
+                    selected_path: SelectedPath::None,
+                    path_transition: Watchable::new(None),
+                    last_transition_at: None,
                     #[cfg(any(test, feature = "test-utils"))]
                     path_selection: PathSelection::default(),
                 },
@@ -1738,4 +1862,63 @@ mod tests {
         // number of pings as direct addresses in the call-me-maybe.
         assert_eq!(ping_messages.len(), my_numbers_count as usize);
     }
+
+    #[test]
+    fn test_path_transitions() {
+        let key = SecretKey::generate(rand::thread_rng());
+        let relay_url: RelayUrl = "https://relay.example.com".parse().unwrap();
+        let opts = Options {
+            node_id: key.public(),
+            relay_url: Some(relay_url.clone()),
+            active: true,
+            source: crate::magicsock::Source::NamedApp {
+                name: "test".into(),
+            },
+            path_selection: PathSelection::default(),
+        };
+        let mut ep = NodeState::new(0, opts);
+        let transitions = ep.path_transitions();
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1000);
+        let now = Instant::now();
+
+        let expect = |ep: &mut NodeState, selected: SelectedPath, reason| {
+            ep.set_selected_path(selected.clone(), now);
+            let transition = transitions.get().unwrap().expect("transition");
+            assert_eq!(transition.to, selected);
+            assert_eq!(transition.reason, reason);
+        };
+        expect(
+            &mut ep,
+            SelectedPath::Unconfirmed(addr, Some(relay_url.clone())),
+            TransitionReason::NewCandidate,
+        );
+        ep.udp_paths.best_addr.insert_if_better_or_reconfirm(
+            addr,
+            Duration::from_millis(10),
+            best_addr::Source::ReceivedPong,
+            now,
+        );
+        expect(
+            &mut ep,
+            SelectedPath::Direct(addr),
+            TransitionReason::PongReceived,
+        );
+        ep.udp_paths.best_addr.clear_trust("test");
+        expect(
+            &mut ep,
+            SelectedPath::Outdated(addr, Some(relay_url.clone())),
+            TransitionReason::ConnectivityChange,
+        );
+        ep.udp_paths.best_addr.clear(ClearReason::PongTimeout, true);
+        expect(
+            &mut ep,
+            SelectedPath::Relay(relay_url.clone()),
+            TransitionReason::PathCleared(ClearReason::PongTimeout),
+        );
+
+        let info = ep.path_info(now);
+        assert_eq!(info.selected, SelectedPath::Relay(relay_url));
+        assert_eq!(info.timers.trusted_for, None);
+        assert_eq!(info.timers.since_transition, Some(Duration::ZERO));
+    }
 }
@@ -0,0 +1,131 @@
+//! The observable state of the path selection towards a remote node.
+//!
+//! The [`NodeState`] decides on every send which paths to use: a direct UDP path once one
+//! is validated by a pong, the relay while none is, or both while a direct path is being
+//! tried or has not been confirmed for a while.  These types expose that decision and why
+//! it was made, to answer questions like "why is this node on the relay?".
+//!
+//! [`NodeState`]: super::node_state::NodeState
+
+use std::net::SocketAddr;
+
+use iroh_base::{NodeId, RelayUrl};
+use n0_future::time::Duration;
+
+use super::best_addr::ClearReason;
+
+/// The state of the path selection towards a remote node.
+///
+/// This is a snapshot, see [`Endpoint::path_info`] and [`Endpoint::path_transitions`].
+///
+/// [`Endpoint::path_info`]: crate::endpoint::Endpoint::path_info
+/// [`Endpoint::path_transitions`]: crate::endpoint::Endpoint::path_transitions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathInfo {
+    /// The remote node.
+    pub node_id: NodeId,
+    /// The paths currently used to send to the node.
+    pub selected: SelectedPath,
+    /// The last change of the selected paths, `None` if they never changed.
+    pub last_transition: Option<PathTransition>,
+    /// The direct paths which are candidates for the selection.
+    pub candidates: Vec<PathCandidate>,
+    /// The relay server of the node, if known.
+    pub relay_url: Option<RelayUrl>,
+    /// The timers driving the path selection.
+    pub timers: PathTimers,
+}
+
+/// The paths used to send to a remote node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelectedPath {
+    /// A validated direct path is used on its own.
+    Direct(SocketAddr),
+    /// A direct path which was not confirmed for a while is used together with the relay.
+    ///
+    /// The path is pinged to validate it again.
+    Outdated(SocketAddr, Option<RelayUrl>),
+    /// A direct path which was never validated is tried together with the relay.
+    Unconfirmed(SocketAddr, Option<RelayUrl>),
+    /// Only the relay is used, as no direct path is known.
+    Relay(RelayUrl),
+    /// No path to the node is known.
+    None,
+}
+
+/// A change of the paths used to send to a remote node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathTransition {
+    /// The paths used before.
+    pub from: SelectedPath,
+    /// The paths used since.
+    pub to: SelectedPath,
+    /// Why the selection changed.
+    pub reason: TransitionReason,
+}
+
+/// Why the paths used to send to a remote node changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TransitionReason {
+    /// A pong validated the direct path.
+    PongReceived,
+    /// The validated direct path with the lowest latency was chosen, with no path selected.
+    BestCandidate,
+    /// A direct path is tried, before any was validated.
+    NewCandidate,
+    /// The direct path was not confirmed by a pong for too long.
+    TrustExpired,
+    /// The trust in the direct path was revoked after a change of the local network.
+    ConnectivityChange,
+    /// The direct path was cleared.
+    PathCleared(ClearReason),
+    /// The relay of the node changed, or the node has no relay anymore.
+    RelayChanged,
+    /// No direct path to the node is known.
+    NoDirectPath,
+}
+
+/// A direct path to a remote node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathCandidate {
+    /// The UDP address of the path.
+    pub addr: SocketAddr,
+    /// Whether the path is known to work.
+    pub status: ValidationStatus,
+    /// The latency measured by the most recent pong.
+    pub latency: Option<Duration>,
+    /// Time elapsed since the last ping was sent on the path.
+    pub last_ping: Option<Duration>,
+    /// Time elapsed since the most recent pong was received on the path.
+    pub last_pong: Option<Duration>,
+}
+
+/// Whether a direct path is known to work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationStatus {
+    /// No ping was sent on the path yet.
+    Untested,
+    /// A ping was sent on the path, its pong is pending.
+    Pending,
+    /// A pong was received on the path.
+    Validated,
+    /// The last pings sent on the path were not answered.
+    Unreachable,
+}
+
+/// The timers driving the path selection towards a remote node.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathTimers {
+    /// How long the selected direct path is trusted without a new pong.
+    ///
+    /// Once this runs out the path is [`SelectedPath::Outdated`].  `None` if no direct path
+    /// is trusted.
+    pub trusted_for: Option<Duration>,
+    /// Time elapsed since the last transition of the selected paths.
+    pub since_transition: Option<Duration>,
+    /// Time elapsed since all direct paths were last pinged.
+    pub since_full_ping: Option<Duration>,
+    /// Time elapsed since the last call-me-maybe was sent, asking the node to ping back.
+    pub since_call_me_maybe: Option<Duration>,
+}
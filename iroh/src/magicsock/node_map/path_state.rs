@@ -49,6 +49,8 @@ pub(super) struct PathState {
     /// Previous replies are cleared when they are no longer relevant to determine whether
     /// this path can still be used to reach the remote node.
     pub(super) recent_pong: Option<PongReply>,
+    /// The number of pings which timed out without any sign of life, since the last pong.
+    pub(super) failed_pings: usize,
     /// When the last payload data was **received** via this path.
     ///
     /// This excludes DISCO messages.
@@ -72,6 +74,7 @@ impl PathState {
             last_got_ping: None,
             call_me_maybe_time: None,
             recent_pong: None,
+            failed_pings: 0,
             last_payload_msg: None,
             sources,
         }
@@ -99,6 +102,7 @@ impl PathState {
             last_got_ping: None,
             call_me_maybe_time: None,
             recent_pong: None,
+            failed_pings: 0,
             last_payload_msg: Some(now),
             sources,
         }
@@ -129,6 +133,7 @@ impl PathState {
             }
         }
         self.recent_pong = Some(r);
+        self.failed_pings = 0;
     }
 
     #[cfg(test)]
@@ -140,6 +145,7 @@ impl PathState {
             last_got_ping: None,
             call_me_maybe_time: None,
             recent_pong: Some(r),
+            failed_pings: 0,
             last_payload_msg: None,
             sources: HashMap::new(),
         }
@@ -281,6 +287,7 @@ impl PathState {
         self.last_got_ping = None;
         self.call_me_maybe_time = None;
         self.recent_pong = None;
+        self.failed_pings = 0;
     }
 
     fn summary(&self, mut w: impl std::fmt::Write) -> std::fmt::Result {
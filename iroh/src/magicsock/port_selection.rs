@@ -0,0 +1,163 @@
+//! Selection of the ports the UDP sockets are bound to.
+//!
+//! Firewalls often only pass traffic to a few ports opened for the endpoint, so the sockets
+//! can be bound to an exact port, or to the first free port of a range.  The port chosen
+//! can be persisted to a file, to bind the same port again after a restart.
+
+use std::{
+    collections::HashSet,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context, Result};
+use tracing::{debug, warn};
+
+/// The ports the UDP sockets of an endpoint may be bound to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) enum PortSelection {
+    /// Binds the ports of the bind addresses, or random ports if they are taken or `0`.
+    #[default]
+    Any,
+    /// Binds exactly this port, failing if it is taken.
+    Port(u16),
+    /// Binds the first free port of the range, failing if all of them are taken.
+    Range(RangeInclusive<u16>),
+}
+
+impl PortSelection {
+    /// Checks the selection contains at least one port to bind.
+    pub(super) fn validate(&self) -> Result<()> {
+        match self {
+            Self::Any => {}
+            Self::Port(port) => ensure!(*port != 0, "port to bind must not be 0"),
+            Self::Range(range) => ensure!(
+                !range.is_empty() && *range.start() != 0,
+                "invalid port range {}-{}",
+                range.start(),
+                range.end()
+            ),
+        }
+        Ok(())
+    }
+
+    /// Whether the port may be bound.
+    fn allows(&self, port: u16) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Port(selected) => port == *selected,
+            Self::Range(range) => range.contains(&port),
+        }
+    }
+
+    /// The ports to try binding, in order.
+    ///
+    /// The `preferred` ports are tried first if they are allowed, a `0` in the list lets
+    /// the operating system choose a random port.
+    pub(super) fn candidates(&self, preferred: impl IntoIterator<Item = u16>) -> Vec<u16> {
+        let mut ports: Vec<u16> = preferred
+            .into_iter()
+            .filter(|port| *port != 0 && self.allows(*port))
+            .collect();
+        match self {
+            Self::Any => ports.push(0),
+            Self::Port(port) => ports.push(*port),
+            Self::Range(range) => ports.extend(range.clone()),
+        }
+        let mut seen = HashSet::new();
+        ports.retain(|port| seen.insert(*port));
+        ports
+    }
+}
+
+/// A file persisting the port the IPv4 socket was bound to.
+#[derive(Debug, Clone)]
+pub(super) struct PortState {
+    path: PathBuf,
+}
+
+impl PortState {
+    pub(super) fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Reads the persisted port, if any.
+    ///
+    /// An unreadable state is ignored: the port is chosen anew and persisted again.
+    pub(super) async fn load(&self) -> Option<u16> {
+        match tokio::fs::read_to_string(&self.path).await {
+            Ok(content) => match content.trim().parse::<u16>() {
+                Ok(port) => Some(port),
+                Err(err) => {
+                    warn!(path = %self.path.display(), "invalid persisted port: {err}");
+                    None
+                }
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => {
+                warn!(path = %self.path.display(), "failed to read persisted port: {err}");
+                None
+            }
+        }
+    }
+
+    /// Persists the port, unless it is already persisted.
+    pub(super) async fn store(&self, port: u16, persisted: Option<u16>) -> Result<()> {
+        if persisted == Some(port) || port == 0 {
+            return Ok(());
+        }
+        if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        write_atomic(&self.path, port.to_string().as_bytes())
+            .await
+            .with_context(|| format!("failed to persist port to {}", self.path.display()))?;
+        debug!(path = %self.path.display(), port, "persisted bound port");
+        Ok(())
+    }
+}
+
+/// Writes the file through a temporary file, so a crash never leaves a partial write.
+pub(super) async fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tokio::fs::write(&tmp, content).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port_candidates() {
+        assert_eq!(PortSelection::Any.candidates([]), vec![0]);
+        assert_eq!(PortSelection::Any.candidates([0, 4000]), vec![4000, 0]);
+        assert_eq!(PortSelection::Port(4000).candidates([5000]), vec![4000]);
+        assert_eq!(
+            PortSelection::Range(4000..=4003).candidates([4002, 5000]),
+            vec![4002, 4000, 4001, 4003]
+        );
+
+        assert!(PortSelection::Port(0).validate().is_err());
+        #[allow(clippy::reversed_empty_ranges)]
+        let empty = PortSelection::Range(4001..=4000);
+        assert!(empty.validate().is_err());
+        assert!(PortSelection::Range(4000..=4000).validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_port_state() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("iroh-port-state-{}", rand::random::<u64>()));
+        let state = PortState::new(dir.join("port"));
+        assert_eq!(state.load().await, None);
+        state.store(4000, None).await?;
+        assert_eq!(state.load().await, Some(4000));
+
+        tokio::fs::write(dir.join("port"), "garbage").await?;
+        assert_eq!(state.load().await, None);
+        tokio::fs::remove_dir_all(dir).await?;
+        Ok(())
+    }
+}
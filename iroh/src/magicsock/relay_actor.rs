@@ -40,11 +40,10 @@ use std::{
 use anyhow::{anyhow, Result};
 use backoff::exponential::{ExponentialBackoff, ExponentialBackoffBuilder};
 use bytes::{Bytes, BytesMut};
-use iroh_base::{NodeId, PublicKey, RelayUrl, SecretKey};
-use iroh_metrics::{inc, inc_by};
+use iroh_base::{inc, inc_by, NodeId, PublicKey, RelayUrl, SecretKey};
 use iroh_relay::{
     self as relay,
-    client::{Client, ReceivedMessage, SendMessage},
+    client::{Client, ConnectionRejected, DropReason, DroppedFrames, ReceivedMessage, SendMessage},
     PingTracker, MAX_PACKET_SIZE,
 };
 use n0_future::{
@@ -249,7 +248,11 @@ impl ActiveRelayActor {
             insecure_skip_cert_verify,
         } = opts;
         let mut builder = relay::client::ClientBuilder::new(url, secret_key, dns_resolver)
-            .address_family_selector(move || prefer_ipv6.load(Ordering::Relaxed));
+            .address_family_selector(move || prefer_ipv6.load(Ordering::Relaxed))
+            .client_software(Some((
+                env!("CARGO_PKG_NAME").to_string(),
+                env!("CARGO_PKG_VERSION").to_string(),
+            )));
         if let Some(proxy_url) = proxy_url {
             builder = builder.proxy_url(proxy_url);
         }
@@ -275,6 +278,11 @@ impl ActiveRelayActor {
             {
                 Ok(_) => break,
                 Err(err) => {
+                    if let Some(rejected) = err.downcast_ref::<ConnectionRejected>() {
+                        // Reconnecting would only be rejected again.
+                        warn!("Relay server rejected the connection: {rejected}");
+                        break;
+                    }
                     debug!("Connection to relay server lost: {err:#}");
                     continue;
                 }
@@ -437,6 +445,7 @@ impl ActiveRelayActor {
         let (mut client_stream, mut client_sink) = client.split();
 
         let mut state = ConnectedRelayState {
+            dropped_frames: client_stream.dropped_frames().clone(),
             ping_tracker: PingTracker::default(),
             nodes_present: BTreeSet::new(),
             last_packet_src: None,
@@ -559,7 +568,7 @@ impl ActiveRelayActor {
                             // reset the ping timer, we have just received a message
                             ping_interval.reset();
                         },
-                        Err(err) => break Err(anyhow!("Client stream read error: {err:#}")),
+                        Err(err) => break Err(err.context("Client stream read error")),
                     }
                 }
                 _ = &mut self.inactive_timeout, if !self.is_home_relay => {
@@ -599,6 +608,10 @@ impl ActiveRelayActor {
                     };
                     if let Err(err) = self.relay_datagrams_recv.try_send(datagram) {
                         warn!("Dropping received relay packet: {err:#}");
+                        inc!(MagicsockMetrics, recv_data_relay_dropped);
+                        state
+                            .dropped_frames
+                            .record(Some(remote_node_id), DropReason::QueueFull);
                     }
                 }
             }
@@ -620,6 +633,12 @@ impl ActiveRelayActor {
                 state.ping_tracker.pong_received(data)
             }
             ReceivedMessage::KeepAlive
+            | ReceivedMessage::SendAck { .. }
+            | ReceivedMessage::PeerPresent(_)
+            | ReceivedMessage::SendQueueStatus { .. }
+            | ReceivedMessage::UnknownPeer(_)
+            | ReceivedMessage::KeepAliveNegotiated(_)
+            | ReceivedMessage::ConnectionLost { .. }
             | ReceivedMessage::Health { .. }
             | ReceivedMessage::ServerRestarting { .. } => trace!("Ignoring {msg:?}"),
         }
@@ -702,6 +721,8 @@ impl ActiveRelayActor {
 /// [`ActiveRelayActor::run_sending`].
 #[derive(Debug)]
 struct ConnectedRelayState {
+    /// Where the received packets dropped because magicsock did not keep up are recorded.
+    dropped_frames: DroppedFrames,
     /// Tracks pings we have sent, awaits pong replies.
     ping_tracker: PingTracker,
     /// Nodes which are reachable via this relay server.
@@ -28,8 +28,9 @@ impl UdpConn {
         &self.io
     }
 
-    pub(super) fn bind(addr: SocketAddr) -> anyhow::Result<Self> {
-        let sock = bind(addr)?;
+    /// Binds the first of the ports which is free, with `0` choosing a random port.
+    pub(super) fn bind(addr: SocketAddr, ports: &[u16]) -> anyhow::Result<Self> {
+        let sock = bind(addr, ports)?;
 
         Ok(Self { io: Arc::new(sock) })
     }
@@ -80,25 +81,10 @@ impl AsyncUdpSocket for UdpConn {
     }
 }
 
-fn bind(mut addr: SocketAddr) -> anyhow::Result<UdpSocket> {
-    debug!(%addr, "binding");
+fn bind(mut addr: SocketAddr, ports: &[u16]) -> anyhow::Result<UdpSocket> {
+    debug!(%addr, ?ports, "binding");
 
-    // Build a list of preferred ports.
-    // - Best is the port that the user requested.
-    // - Second best is the port that is currently in use.
-    // - If those fail, fall back to 0.
-
-    let mut ports = Vec::new();
-    if addr.port() != 0 {
-        ports.push(addr.port());
-    }
-    // Backup port
-    ports.push(0);
-    // Remove duplicates. (All duplicates are consecutive.)
-    ports.dedup();
-    debug!(?ports, "candidate ports");
-
-    for port in &ports {
+    for port in ports {
         addr.set_port(*port);
         match UdpSocket::bind_full(addr) {
             Ok(pconn) => {
@@ -113,7 +99,7 @@ fn bind(mut addr: SocketAddr) -> anyhow::Result<UdpSocket> {
         }
     }
 
-    // Failed to bind, including on port 0 (!).
+    // Failed to bind, including on port 0 if it was a candidate (!).
     bail!("failed to bind any ports on {:?} (tried {:?})", addr, ports);
 }
 
@@ -154,7 +140,8 @@ mod tests {
             Arc::new(quinn::TokioRuntime),
         )?;
 
-        let quic_client_config = tls::make_client_config(&key, None, vec![ALPN.to_vec()], false)?;
+        let quic_client_config =
+            tls::make_client_config(&key, None, vec![ALPN.to_vec()], None, false)?;
         let client_config = quinn::ClientConfig::new(Arc::new(quic_client_config));
         quic_ep.set_default_client_config(client_config);
         Ok((quic_ep, key))
@@ -176,10 +163,10 @@ mod tests {
     }
 
     async fn rebinding_conn_send_recv(network: IpFamily) -> Result<()> {
-        let m1 = UdpConn::bind(SocketAddr::new(network.unspecified_addr(), 0))?;
+        let m1 = UdpConn::bind(SocketAddr::new(network.unspecified_addr(), 0), &[0])?;
         let (m1, _m1_key) = wrap_socket(m1)?;
 
-        let m2 = UdpConn::bind(SocketAddr::new(network.unspecified_addr(), 0))?;
+        let m2 = UdpConn::bind(SocketAddr::new(network.unspecified_addr(), 0), &[0])?;
         let (m2, _m2_key) = wrap_socket(m2)?;
 
         let m1_addr = SocketAddr::new(network.local_addr(), m1.local_addr()?.port());
@@ -4,4 +4,42 @@ pub use iroh_relay::server::Metrics as RelayMetrics;
 pub use net_report::Metrics as NetReportMetrics;
 pub use portmapper::Metrics as PortmapMetrics;
 
+#[cfg(feature = "metrics")]
+pub use iroh_relay::metrics::{MetricsBackend, MetricsExporter};
+
 pub use crate::magicsock::Metrics as MagicsockMetrics;
+
+/// Installs the metrics backend, or sets up the metrics registry with the endpoint metrics
+/// and spawns the Prometheus exporter.
+///
+/// Returns `None` if there is no exporter to run.
+#[cfg(feature = "metrics")]
+pub(crate) fn spawn_exporter(
+    exporter: MetricsExporter,
+) -> std::io::Result<Option<n0_future::task::AbortOnDropHandle<()>>> {
+    use iroh_metrics::core::{Core, Metric};
+    use tracing::{debug, info_span, warn, Instrument};
+
+    exporter.install_backend()?;
+    if exporter.prometheus_addr().is_none() {
+        return Ok(None);
+    }
+    if Core::try_init(|reg, metrics| {
+        metrics.insert(MagicsockMetrics::new(reg));
+        metrics.insert(NetReportMetrics::new(reg));
+        metrics.insert(PortmapMetrics::new(reg));
+    })
+    .is_err()
+    {
+        debug!("metrics registry already set up");
+    }
+    let task = tokio::spawn(
+        async move {
+            if let Err(err) = exporter.run().await {
+                warn!("metrics exporter failed: {err:#}");
+            }
+        }
+        .instrument(info_span!("metrics-exporter")),
+    );
+    Ok(Some(n0_future::task::AbortOnDropHandle::new(task)))
+}
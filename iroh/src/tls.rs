@@ -27,6 +27,9 @@ pub enum CreateConfigError {
 
 /// Create a TLS client configuration.
 ///
+/// If *token* is set, it is presented to the server in the certificate, see
+/// [`certificate::generate_with_token`].
+///
 /// If *keylog* is `true` this will enable logging of the pre-master key to the file in the
 /// `SSLKEYLOGFILE` environment variable.  This can be used to inspect the traffic for
 /// debugging purposes.
@@ -34,9 +37,10 @@ pub fn make_client_config(
     secret_key: &SecretKey,
     remote_peer_id: Option<PublicKey>,
     alpn_protocols: Vec<Vec<u8>>,
+    token: Option<&[u8]>,
     keylog: bool,
 ) -> Result<QuicClientConfig, CreateConfigError> {
-    let (certificate, secret_key) = certificate::generate(secret_key)?;
+    let (certificate, secret_key) = certificate::generate_with_token(secret_key, token)?;
 
     let cert_resolver = Arc::new(
         AlwaysResolvesCert::new(certificate, &secret_key)
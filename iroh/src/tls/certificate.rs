@@ -23,6 +23,19 @@ const P2P_EXT_OID: [u64; 9] = [1, 3, 6, 1, 4, 1, 53594, 1, 1];
 /// in possession of the private host key at the time the certificate was signed.
 const P2P_SIGNING_PREFIX: [u8; 21] = *b"libp2p-tls-handshake:";
 
+/// The iroh Peer Token Extension is a X.509 extension carrying an application defined
+/// token of the peer.
+///
+/// It is not critical, so implementations not knowing about it ignore it.
+const PEER_TOKEN_EXT_OID: [u64; 10] = [1, 3, 6, 1, 4, 1, 53594, 1, 1, 1];
+
+/// The peer signs the concatenation of the string `iroh-peer-token:`, the public key
+/// of the certificate and the token, using its private host key.
+const PEER_TOKEN_SIGNING_PREFIX: [u8; 16] = *b"iroh-peer-token:";
+
+/// The maximum size of a peer token, in bytes.
+pub const MAX_PEER_TOKEN_SIZE: usize = 1024;
+
 // Certificates MUST use the NamedCurve encoding for elliptic curve parameters.
 // Similarly, hash functions with an output length less than 256 bits MUST NOT be used.
 static P2P_SIGNATURE_ALGORITHM: &rcgen::SignatureAlgorithm = &rcgen::PKCS_ECDSA_P256_SHA256;
@@ -74,6 +87,14 @@ struct SignedKey<'a> {
     signature: OctetStringRef<'a>,
 }
 
+/// The token and the signature are ASN.1-encoded into the SignedToken data structure,
+/// which is carried in the iroh Peer Token Extension.
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+struct SignedToken<'a> {
+    token: OctetStringRef<'a>,
+    signature: OctetStringRef<'a>,
+}
+
 /// Generates a self-signed TLS certificate that includes a libp2p-specific
 /// certificate extension containing the public key of the given secret key.
 pub fn generate(
@@ -84,6 +105,21 @@ pub fn generate(
         rustls::pki_types::PrivateKeyDer<'static>,
     ),
     GenError,
+> {
+    generate_with_token(identity_secret_key, None)
+}
+
+/// Like [`generate`], additionally carrying the peer `token` in the iroh Peer Token
+/// Extension if set.
+pub fn generate_with_token(
+    identity_secret_key: &SecretKey,
+    token: Option<&[u8]>,
+) -> Result<
+    (
+        rustls::pki_types::CertificateDer<'static>,
+        rustls::pki_types::PrivateKeyDer<'static>,
+    ),
+    GenError,
 > {
     // SecretKey used to sign the certificate.
     // SHOULD NOT be related to the host's key.
@@ -101,6 +137,13 @@ pub fn generate(
             identity_secret_key,
             &certificate_keypair,
         )?);
+        if let Some(token) = token {
+            params.custom_extensions.push(make_token_extension(
+                identity_secret_key,
+                &certificate_keypair,
+                token,
+            )?);
+        }
         params
             .self_signed(&certificate_keypair)
             .expect("self signed certificate to be generated")
@@ -132,6 +175,16 @@ pub struct P2pCertificate<'a> {
     /// * the public host key
     /// * a signature performed using the private host key
     extension: P2pExtension,
+    /// The iroh Peer Token Extension, if present.
+    token: Option<TokenExtension>,
+}
+
+/// The contents of the iroh Peer Token Extension, containing the token and a signature
+/// performed using the private host key.
+#[derive(Debug)]
+pub struct TokenExtension {
+    token: Vec<u8>,
+    signature: Signature,
 }
 
 /// The contents of the specific libp2p extension, containing the public host key
@@ -170,7 +223,11 @@ fn parse_unverified(der_input: &[u8]) -> Result<P2pCertificate, webpki::Error> {
     let p2p_ext_oid = der_parser::oid::Oid::from(&P2P_EXT_OID)
         .expect("This is a valid OID of p2p extension; qed");
 
+    let token_ext_oid = der_parser::oid::Oid::from(&PEER_TOKEN_EXT_OID)
+        .expect("This is a valid OID of the token extension; qed");
+
     let mut libp2p_extension = None;
+    let mut token_extension = None;
 
     for ext in x509.extensions() {
         let oid = &ext.oid;
@@ -196,6 +253,25 @@ fn parse_unverified(der_input: &[u8]) -> Result<P2pCertificate, webpki::Error> {
             continue;
         }
 
+        if oid == &token_ext_oid {
+            if token_extension.is_some() {
+                return Err(webpki::Error::BadDer);
+            }
+            let signed_token = SignedToken::from_der(ext.value)
+                .map_err(|_| webpki::Error::ExtensionValueInvalid)?;
+            let token = signed_token.token.as_bytes();
+            if token.len() > MAX_PEER_TOKEN_SIZE {
+                return Err(webpki::Error::ExtensionValueInvalid);
+            }
+            let signature = Signature::from_slice(signed_token.signature.as_bytes())
+                .map_err(|_| webpki::Error::ExtensionValueInvalid)?;
+            token_extension = Some(TokenExtension {
+                token: token.to_vec(),
+                signature,
+            });
+            continue;
+        }
+
         if ext.critical {
             // Endpoints MUST abort the connection attempt if the certificate
             // contains critical extensions that the endpoint does not understand.
@@ -212,6 +288,7 @@ fn parse_unverified(der_input: &[u8]) -> Result<P2pCertificate, webpki::Error> {
     let certificate = P2pCertificate {
         certificate: x509,
         extension,
+        token: token_extension,
     };
 
     Ok(certificate)
@@ -233,11 +310,10 @@ fn make_libp2p_extension(
     };
 
     let public_key = identity_secret_key.public();
-    let public_key_ref = OctetStringRef::new(&public_key.as_bytes()[..])
-        .map_err(|_| rcgen::Error::CouldNotParseKeyPair)?;
+    let public_key_ref =
+        OctetStringRef::new(&public_key.as_bytes()[..]).map_err(extension_der_error)?;
     let signature = signature.to_bytes();
-    let signature_ref =
-        OctetStringRef::new(&signature).map_err(|_| rcgen::Error::CouldNotParseCertificate)?;
+    let signature_ref = OctetStringRef::new(&signature).map_err(extension_der_error)?;
     let key = SignedKey {
         public_key: public_key_ref,
         signature: signature_ref,
@@ -253,12 +329,59 @@ fn make_libp2p_extension(
     Ok(ext)
 }
 
+fn make_token_extension(
+    identity_secret_key: &SecretKey,
+    certificate_keypair: &rcgen::KeyPair,
+    token: &[u8],
+) -> Result<rcgen::CustomExtension, rcgen::Error> {
+    let signature = {
+        let mut msg = vec![];
+        msg.extend(PEER_TOKEN_SIGNING_PREFIX);
+        msg.extend(certificate_keypair.public_key_der());
+        msg.extend(token);
+
+        identity_secret_key.sign(&msg)
+    };
+
+    let token_ref = OctetStringRef::new(token).map_err(extension_der_error)?;
+    let signature = signature.to_bytes();
+    let signature_ref = OctetStringRef::new(&signature).map_err(extension_der_error)?;
+    let signed_token = SignedToken {
+        token: token_ref,
+        signature: signature_ref,
+    };
+
+    let mut extension_content = Vec::new();
+    signed_token
+        .encode_to_vec(&mut extension_content)
+        .expect("vec");
+
+    // Not critical, peers not knowing about tokens ignore it.
+    let mut ext = rcgen::CustomExtension::from_oid_content(&PEER_TOKEN_EXT_OID, extension_content);
+    ext.set_criticality(false);
+
+    Ok(ext)
+}
+
+/// Maps a failure to DER encode the value of one of our certificate extensions.
+///
+/// rcgen has no error for invalid custom extensions, the closest is the certificate being
+/// unparsable.
+fn extension_der_error(_: der::Error) -> rcgen::Error {
+    rcgen::Error::CouldNotParseCertificate
+}
+
 impl P2pCertificate<'_> {
     /// The [`PublicKey`] of the remote peer.
     pub fn peer_id(&self) -> PublicKey {
         self.extension.public_key
     }
 
+    /// The token of the remote peer, if it sent one.
+    pub fn peer_token(&self) -> Option<&[u8]> {
+        self.token.as_ref().map(|ext| &ext.token[..])
+    }
+
     /// Verify the `signature` of the `message` signed by the secret key corresponding to the public key stored
     /// in the certificate.
     pub fn verify_signature(
@@ -376,6 +499,22 @@ impl P2pCertificate<'_> {
             return Err(Error::UnknownIssuer);
         }
 
+        // The token must be signed by the same private host key.
+        if let Some(ext) = &self.token {
+            let mut msg = vec![];
+            msg.extend(PEER_TOKEN_SIGNING_PREFIX);
+            msg.extend(subject_pki);
+            msg.extend(&ext.token);
+            if self
+                .extension
+                .public_key
+                .verify(&msg, &ext.signature)
+                .is_err()
+            {
+                return Err(Error::ExtensionValueInvalid);
+            }
+        }
+
         Ok(())
     }
 
@@ -441,4 +580,37 @@ mod tests {
         assert!(parsed_cert.verify().is_ok());
         assert_eq!(secret_key.public(), parsed_cert.extension.public_key);
     }
+
+    #[test]
+    fn peer_token() {
+        let secret_key = SecretKey::generate(rand::thread_rng());
+
+        let (cert, _) = generate(&secret_key).unwrap();
+        assert_eq!(parse(&cert).unwrap().peer_token(), None);
+
+        let (cert, _) = generate_with_token(&secret_key, Some(b"invite")).unwrap();
+        let parsed_cert = parse(&cert).unwrap();
+        assert_eq!(parsed_cert.peer_id(), secret_key.public());
+        assert_eq!(parsed_cert.peer_token(), Some(&b"invite"[..]));
+    }
+
+    #[test]
+    fn peer_token_signed_by_other_key() {
+        let secret_key = SecretKey::generate(rand::thread_rng());
+        let other_key = SecretKey::generate(rand::thread_rng());
+
+        let keypair = rcgen::KeyPair::generate_for(P2P_SIGNATURE_ALGORITHM).unwrap();
+        let mut params = rcgen::CertificateParams::default();
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params
+            .custom_extensions
+            .push(make_libp2p_extension(&secret_key, &keypair).unwrap());
+        params
+            .custom_extensions
+            .push(make_token_extension(&other_key, &keypair, b"invite").unwrap());
+        let cert = params.self_signed(&keypair).unwrap();
+
+        assert!(parse_unverified(cert.der()).is_ok());
+        assert!(parse(cert.der()).is_err());
+    }
 }